@@ -0,0 +1,384 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info};
+
+use crate::{Bridge, BridgeConfig, BridgeError};
+
+#[cfg(target_os = "linux")]
+pub mod socketcan;
+
+/// Byte order of the address and value carried in each CAN frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CanByteOrder {
+    BigEndian,
+    LittleEndian,
+}
+
+impl CanByteOrder {
+    fn encode(self, value: u32) -> [u8; 4] {
+        match self {
+            CanByteOrder::BigEndian => value.to_be_bytes(),
+            CanByteOrder::LittleEndian => value.to_le_bytes(),
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        match self {
+            CanByteOrder::BigEndian => u32::from_be_bytes(bytes),
+            CanByteOrder::LittleEndian => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+#[derive(Clone)]
+/// Describes a connection to a device over a CAN bus. Note that not all
+/// platforms support CAN connections.
+pub struct CanBridge {
+    interface: String,
+    tx_id: u32,
+    rx_id: u32,
+    extended_ids: bool,
+    byte_order: CanByteOrder,
+    write_ack: bool,
+    timeout: Duration,
+}
+
+/// A builder to create a connection to a target via a CAN bus, using a
+/// Linux SocketCAN interface such as `can0`.
+///
+/// Requests are sent with one arbitration ID, and the target answers with
+/// another. A write is a single eight-byte frame holding the address and
+/// then the value. Once the write completes, the target answers with a
+/// four-byte frame holding the address. A read is a four-byte frame
+/// holding the address, which the target answers with an eight-byte frame
+/// holding the address and then the value. Both are big-endian unless
+/// changed with `byte_order()`. Answers for a different address are ignored,
+/// so a late answer to a request that timed out can't be mistaken for the
+/// answer to the next one.
+///
+/// ```no_run
+/// use wishbone_bridge::CanBridge;
+/// let bridge = CanBridge::new("can0").tx_id(0x123).rx_id(0x124).create().unwrap();
+/// ```
+impl CanBridge {
+    /// Create a new CanBridge on the SocketCAN interface `interface`. Requests
+    /// are sent with the standard ID `0x5b0` and answers are expected on `0x5b8`.
+    pub fn new(interface: &str) -> Self {
+        CanBridge {
+            interface: interface.to_owned(),
+            tx_id: 0x5b0,
+            rx_id: 0x5b8,
+            extended_ids: false,
+            byte_order: CanByteOrder::BigEndian,
+            write_ack: true,
+            timeout: Duration::from_millis(250),
+        }
+    }
+
+    /// Specify the arbitration ID that requests are sent with.
+    pub fn tx_id(&mut self, id: u32) -> &mut CanBridge {
+        self.tx_id = id;
+        self
+    }
+
+    /// Specify the arbitration ID that the target answers with.
+    pub fn rx_id(&mut self, id: u32) -> &mut CanBridge {
+        self.rx_id = id;
+        self
+    }
+
+    /// Use 29-bit extended IDs rather than 11-bit standard IDs.
+    pub fn extended_ids(&mut self, extended: bool) -> &mut CanBridge {
+        self.extended_ids = extended;
+        self
+    }
+
+    /// Specify the byte order of the address and value in each frame.
+    pub fn byte_order(&mut self, byte_order: CanByteOrder) -> &mut CanBridge {
+        self.byte_order = byte_order;
+        self
+    }
+
+    /// Wait for the target to acknowledge each write. Defaults to `true`.
+    /// Targets that don't acknowledge writes are faster, but writes to them
+    /// may be lost without any error being reported.
+    pub fn write_ack(&mut self, write_ack: bool) -> &mut CanBridge {
+        self.write_ack = write_ack;
+        self
+    }
+
+    /// Specify how long to wait for the target to answer. Defaults to 250 ms.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut CanBridge {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Create a `Bridge` struct based on the current configuration.
+    /// This will return an error on platforms that do not support CAN.
+    pub fn create(&self) -> Result<Bridge, BridgeError> {
+        Bridge::new(BridgeConfig::CanBridge(self.clone()))
+    }
+}
+
+/// A way of sending and receiving raw CAN frames.
+trait CanTransport {
+    /// Send a frame carrying `data` with the request ID.
+    fn send(&mut self, data: &[u8]) -> Result<(), BridgeError>;
+
+    /// Wait for a frame carrying the answer ID, and return its data.
+    fn recv(&mut self) -> Result<Vec<u8>, BridgeError>;
+}
+
+fn open_transport(cfg: &CanBridge) -> Result<Box<dyn CanTransport>, BridgeError> {
+    let id_limit = if cfg.extended_ids { 0x1fff_ffff } else { 0x7ff };
+    if cfg.tx_id > id_limit || cfg.rx_id > id_limit {
+        return Err(BridgeError::InvalidAddress);
+    }
+    #[cfg(target_os = "linux")]
+    return Ok(Box::new(socketcan::SocketCan::open(
+        &cfg.interface,
+        cfg.tx_id,
+        cfg.rx_id,
+        cfg.extended_ids,
+        cfg.timeout,
+    )?));
+    #[cfg(not(target_os = "linux"))]
+    Err(BridgeError::ProtocolNotSupported)
+}
+
+#[derive(Clone)]
+pub struct CanBridgeInner {
+    main_tx: Sender<ConnectThreadRequests>,
+    main_rx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+    mutex: Arc<Mutex<()>>,
+}
+
+enum ConnectThreadRequests {
+    Exit,
+    Connect,
+    Poke(u32 /* addr */, u32 /* val */),
+    Peek(u32 /* addr */),
+}
+
+#[derive(Debug)]
+enum ConnectThreadResponses {
+    Connected(Result<(), BridgeError>),
+    PeekResult(Result<u32, BridgeError>),
+    PokeResult(Result<(), BridgeError>),
+}
+
+impl CanBridgeInner {
+    pub fn new(cfg: &CanBridge) -> Result<Self, BridgeError> {
+        // Try to open the interface first, just to make sure we can.
+        open_transport(cfg)?;
+
+        let (main_tx, thread_rx) = channel();
+        let cv = Arc::new((Mutex::new(None), Condvar::new()));
+
+        let thr_cv = cv.clone();
+        let thr_cfg = cfg.clone();
+        thread::spawn(move || Self::can_connect_thread(thr_cv, thread_rx, thr_cfg));
+
+        Ok(CanBridgeInner {
+            main_tx,
+            main_rx: cv,
+            mutex: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn can_connect_thread(
+        tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+        rx: Receiver<ConnectThreadRequests>,
+        cfg: CanBridge,
+    ) {
+        use ConnectThreadRequests::*;
+        use ConnectThreadResponses::*;
+        let (response, cvar) = &*tx;
+
+        let mut print_waiting_message = true;
+        loop {
+            match open_transport(&cfg) {
+                Ok(mut transport) => {
+                    info!(
+                        "opened can bridge on {} with ids 0x{:x}/0x{:x}",
+                        cfg.interface, cfg.tx_id, cfg.rx_id
+                    );
+                    print_waiting_message = true;
+                    let mut keep_going = true;
+                    while keep_going {
+                        match rx.recv() {
+                            Err(_) => {
+                                error!("connection closed");
+                                return;
+                            }
+                            Ok(Exit) => {
+                                debug!("can_connect_thread requested exit");
+                                return;
+                            }
+                            Ok(Connect) => {
+                                *response.lock().unwrap() = Some(Connected(Ok(())));
+                                cvar.notify_one();
+                            }
+                            Ok(Peek(addr)) => {
+                                let result = Self::do_peek(&mut *transport, &cfg, addr);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PeekResult(result));
+                                cvar.notify_one();
+                            }
+                            Ok(Poke(addr, val)) => {
+                                let result = Self::do_poke(&mut *transport, &cfg, addr, val);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PokeResult(result));
+                                cvar.notify_one();
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    if print_waiting_message {
+                        info!("waiting for can interface {}: {}", cfg.interface, e);
+                        print_waiting_message = false;
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_millis(500));
+
+            // Respond to any messages in the buffer with NotConnected.  As soon
+            // as the channel is empty, loop back to the start of this function.
+            loop {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("main thread disconnected"),
+                    Ok(m) => {
+                        let reply = match m {
+                            Exit => {
+                                debug!("main thread requested exit");
+                                return;
+                            }
+                            Connect => Connected(Err(BridgeError::NotConnected)),
+                            Peek(_addr) => PeekResult(Err(BridgeError::NotConnected)),
+                            Poke(_addr, _val) => PokeResult(Err(BridgeError::NotConnected)),
+                        };
+                        *response.lock().unwrap() = Some(reply);
+                        cvar.notify_one();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait for an answer of `len` bytes that starts with `addr`.
+    fn wait_reply(
+        transport: &mut dyn CanTransport,
+        cfg: &CanBridge,
+        addr: u32,
+        len: usize,
+    ) -> Result<Vec<u8>, BridgeError> {
+        let deadline = Instant::now() + cfg.timeout;
+        let addr = cfg.byte_order.encode(addr);
+        loop {
+            let frame = transport.recv()?;
+            if frame.len() == len && frame[..4] == addr {
+                return Ok(frame);
+            }
+            debug!("ignoring unexpected can frame {:02x?}", frame);
+            if Instant::now() > deadline {
+                return Err(BridgeError::Timeout);
+            }
+        }
+    }
+
+    fn do_poke(
+        transport: &mut dyn CanTransport,
+        cfg: &CanBridge,
+        addr: u32,
+        value: u32,
+    ) -> Result<(), BridgeError> {
+        debug!("poke: writing 0x{:08x} to 0x{:08x}", value, addr);
+        let mut frame = cfg.byte_order.encode(addr).to_vec();
+        frame.extend_from_slice(&cfg.byte_order.encode(value));
+        transport.send(&frame)?;
+        if cfg.write_ack {
+            Self::wait_reply(transport, cfg, addr, 4)?;
+        }
+        Ok(())
+    }
+
+    fn do_peek(
+        transport: &mut dyn CanTransport,
+        cfg: &CanBridge,
+        addr: u32,
+    ) -> Result<u32, BridgeError> {
+        transport.send(&cfg.byte_order.encode(addr))?;
+        let frame = Self::wait_reply(transport, cfg, addr, 8)?;
+        let value = cfg.byte_order.decode(&frame[4..]);
+        debug!("peek: value 0x{:08x} at addr 0x{:08x}", value, addr);
+        Ok(value)
+    }
+
+    fn request(&self, req: ConnectThreadRequests) -> ConnectThreadResponses {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(req)
+            .expect("Unable to send request to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        _mtx.take().unwrap()
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
+    /// Block until the CAN interface has been opened.
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        loop {
+            if let ConnectThreadResponses::Connected(Ok(())) =
+                self.request(ConnectThreadRequests::Connect)
+            {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        match self.request(ConnectThreadRequests::Poke(addr, value)) {
+            ConnectThreadResponses::PokeResult(r) => r,
+            e => {
+                error!("unexpected bridge poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        match self.request(ConnectThreadRequests::Peek(addr)) {
+            ConnectThreadResponses::PeekResult(r) => r,
+            e => {
+                error!("unexpected bridge peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+}
+
+impl Drop for CanBridgeInner {
+    fn drop(&mut self) {
+        // If this is the last reference to the bridge, tell the control thread
+        // to exit.
+        if Arc::strong_count(&self.mutex) + Arc::weak_count(&self.mutex) <= 1 {
+            let (lock, _cvar) = &*self.main_rx;
+            let mut _mtx = lock.lock().unwrap();
+            self.main_tx.send(ConnectThreadRequests::Exit).ok();
+        }
+    }
+}
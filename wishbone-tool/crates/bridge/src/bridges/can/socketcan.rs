@@ -0,0 +1,133 @@
+extern crate libc;
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use super::CanTransport;
+use crate::sys::{self, check};
+use crate::BridgeError;
+
+const FRAME_SIZE: usize = mem::size_of::<libc::can_frame>();
+
+/// A raw CAN socket on a Linux SocketCAN interface such as `can0`. Only
+/// frames carrying the reply ID are received.
+pub struct SocketCan {
+    socket: File,
+    tx_id: u32,
+}
+
+fn setsockopt<T>(
+    socket: &File,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    })?;
+    Ok(())
+}
+
+impl SocketCan {
+    pub fn open(
+        interface: &str,
+        tx_id: u32,
+        rx_id: u32,
+        extended_ids: bool,
+        timeout: Duration,
+    ) -> Result<SocketCan, BridgeError> {
+        let name = CString::new(interface).map_err(|_| BridgeError::InvalidAddress)?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let socket: File = sys::socket(libc::PF_CAN, libc::SOCK_RAW, libc::CAN_RAW)?;
+
+        // Match the whole ID, whether it's extended, and that it isn't a
+        // remote frame, so nothing else on the bus gets through.
+        let (id_flag, id_mask) = if extended_ids {
+            (libc::CAN_EFF_FLAG, libc::CAN_EFF_MASK)
+        } else {
+            (0, libc::CAN_SFF_MASK)
+        };
+        let filter = libc::can_filter {
+            can_id: rx_id | id_flag,
+            can_mask: id_mask | libc::CAN_EFF_FLAG | libc::CAN_RTR_FLAG,
+        };
+        setsockopt(&socket, libc::SOL_CAN_RAW, libc::CAN_RAW_FILTER, &filter)?;
+
+        let timeval = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        setsockopt(&socket, libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeval)?;
+
+        let mut addr: libc::sockaddr_can = unsafe { mem::zeroed() };
+        addr.can_family = libc::AF_CAN as libc::sa_family_t;
+        addr.can_ifindex = ifindex as libc::c_int;
+        check(unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_can as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(SocketCan {
+            socket,
+            tx_id: tx_id | id_flag,
+        })
+    }
+}
+
+impl CanTransport for SocketCan {
+    fn send(&mut self, data: &[u8]) -> Result<(), BridgeError> {
+        let mut frame: libc::can_frame = unsafe { mem::zeroed() };
+        frame.can_id = self.tx_id;
+        frame.can_dlc = data.len() as u8;
+        frame.data[..data.len()].copy_from_slice(data);
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&frame as *const libc::can_frame as *const u8, FRAME_SIZE)
+        };
+        let written = self.socket.write(bytes)?;
+        if written != FRAME_SIZE {
+            return Err(BridgeError::LengthError(FRAME_SIZE, written));
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, BridgeError> {
+        let mut frame: libc::can_frame = unsafe { mem::zeroed() };
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut frame as *mut libc::can_frame as *mut u8,
+                FRAME_SIZE,
+            )
+        };
+        let read = match self.socket.read(bytes) {
+            Ok(read) => read,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(BridgeError::Timeout)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if read != FRAME_SIZE {
+            return Err(BridgeError::LengthError(FRAME_SIZE, read));
+        }
+        let len = (frame.can_dlc as usize).min(frame.data.len());
+        Ok(frame.data[..len].to_vec())
+    }
+}
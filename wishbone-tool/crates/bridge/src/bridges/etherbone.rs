@@ -0,0 +1,238 @@
+extern crate byteorder;
+
+#[cfg(feature = "sim")]
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::BridgeError;
+
+/// Flag in the Etherbone header asking the device to answer a probe
+#[cfg(feature = "ethernet")]
+pub(crate) const EB_PROBE_FLAG: u8 = 0x01;
+
+/// Flag in the Etherbone header indicating an answer to a probe
+#[cfg(feature = "ethernet")]
+pub(crate) const EB_PROBE_RESPONSE: u8 = 0x02;
+
+/// Flag in the Etherbone header telling the device that a packet has no
+/// reads in it, so it needn't send anything back
+#[cfg(feature = "ethernet")]
+pub(crate) const EB_NO_READS: u8 = 0x04;
+
+/// Record flag asking for reads to come from the device's config space
+/// rather than from the bus
+#[cfg(feature = "ethernet")]
+const EB_READ_CONFIG: u8 = 0x40;
+
+/// Width field meaning 32-bit addresses and 32-bit data
+pub(crate) const EB_WIDTH_32: u8 = 0x44;
+
+/// A single record can carry at most 255 reads or 255 writes
+pub(crate) const MAX_RECORD_OPS: usize = 255;
+
+pub(crate) fn eb_header(flags: u8) -> Vec<u8> {
+    vec![
+        0x4e,         // Magic byte 0
+        0x6f,         // Magic byte 1
+        0x10 | flags, // Version 1
+        EB_WIDTH_32,  // Address is 32-bits, port is 32-bits
+        0,            // Padding
+        0,            // Padding
+        0,            // Padding
+        0,            // Padding
+    ]
+}
+
+/// Build a packet writing `values` to consecutive addresses starting at `addr`.
+pub(crate) fn eb_write_packet(addr: u32, values: &[u32], byte_enable: u8) -> Vec<u8> {
+    let mut packet = eb_header(0);
+    for (i, chunk) in values.chunks(MAX_RECORD_OPS).enumerate() {
+        eb_write_record(
+            &mut packet,
+            addr + (i * MAX_RECORD_OPS * 4) as u32,
+            chunk,
+            byte_enable,
+        );
+    }
+    packet
+}
+
+/// Add a record to `packet` writing up to `MAX_RECORD_OPS` values to
+/// consecutive addresses starting at `addr`.
+pub(crate) fn eb_write_record(packet: &mut Vec<u8>, addr: u32, values: &[u32], byte_enable: u8) {
+    packet.extend_from_slice(&[
+        0,                  // No Wishbone flags are set (cyc, wca, wff, etc.)
+        byte_enable,        // Byte enable
+        values.len() as u8, // Write count
+        0,                  // Read count
+    ]);
+    let mut word = [0; 4];
+    BigEndian::write_u32(&mut word, addr);
+    packet.extend_from_slice(&word);
+    for value in values {
+        BigEndian::write_u32(&mut word, *value);
+        packet.extend_from_slice(&word);
+    }
+}
+
+/// Build a packet reading the error status register at the start of the
+/// device's config space. The reply only comes once everything sent before
+/// it has been handled, so this confirms that writes have landed without
+/// touching the bus.
+#[cfg(feature = "ethernet")]
+pub(crate) fn eb_barrier_packet() -> Vec<u8> {
+    let mut packet = eb_header(0);
+    packet.extend_from_slice(&[
+        EB_READ_CONFIG, // Read from config space
+        0x0f,           // Byte enable
+        0,              // Write count
+        1,              // Read count
+    ]);
+    // Base return address
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    // Address of the error status register
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    packet
+}
+
+/// Build a packet reading `count` consecutive words starting at `addr`.
+pub(crate) fn eb_read_packet(addr: u32, count: usize) -> Vec<u8> {
+    let mut packet = eb_header(0);
+    let mut word = [0; 4];
+    let mut offset = 0;
+    while offset < count {
+        let chunk = (count - offset).min(MAX_RECORD_OPS);
+        packet.extend_from_slice(&[
+            0,           // No Wishbone flags are set (cyc, wca, wff, etc.)
+            0x0f,        // Byte enable
+            0,           // Write count
+            chunk as u8, // Read count
+        ]);
+        // Base return address
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        for i in 0..chunk {
+            BigEndian::write_u32(&mut word, addr + ((offset + i) * 4) as u32);
+            packet.extend_from_slice(&word);
+        }
+        offset += chunk;
+    }
+    packet
+}
+
+/// Pull the values out of the write records a device sends in reply to reads.
+pub(crate) fn eb_parse_read_response(packet: &[u8]) -> Result<Vec<u32>, BridgeError> {
+    if packet.len() < 8 || packet[0] != 0x4e || packet[1] != 0x6f {
+        return Err(BridgeError::WrongResponse);
+    }
+    let mut values = vec![];
+    let mut offset = 8;
+    while offset + 8 <= packet.len() {
+        let wcount = packet[offset + 2] as usize;
+        let rcount = packet[offset + 3] as usize;
+        // Skip the record header and base write address
+        offset += 8;
+        let end = offset + 4 * (wcount + rcount);
+        if end > packet.len() {
+            return Err(BridgeError::LengthError(end, packet.len()));
+        }
+        for i in 0..wcount {
+            values.push(BigEndian::read_u32(&packet[offset + 4 * i..]));
+        }
+        offset = end;
+    }
+    Ok(values)
+}
+
+/// Write `values` to consecutive addresses over a stream connection such as
+/// TCP, sending one record per packet.
+#[cfg(feature = "sim")]
+pub(crate) fn stream_write<S: Write>(
+    stream: &mut S,
+    addr: u32,
+    values: &[u32],
+    byte_enable: u8,
+) -> Result<(), BridgeError> {
+    for (i, chunk) in values.chunks(MAX_RECORD_OPS).enumerate() {
+        let packet = eb_write_packet(addr + (i * MAX_RECORD_OPS * 4) as u32, chunk, byte_enable);
+        stream.write_all(&packet)?;
+    }
+    Ok(())
+}
+
+/// Read `count` consecutive words over a stream connection, sending one
+/// record per packet.
+#[cfg(feature = "sim")]
+pub(crate) fn stream_read<S: Read + Write>(
+    stream: &mut S,
+    addr: u32,
+    count: usize,
+) -> Result<Vec<u32>, BridgeError> {
+    let mut values = Vec::with_capacity(count);
+    while values.len() < count {
+        let chunk = (count - values.len()).min(MAX_RECORD_OPS);
+        let packet = eb_read_packet(addr + (values.len() * 4) as u32, chunk);
+        stream.write_all(&packet)?;
+        // Each read record comes back as a write record of the same size.
+        let mut buffer = vec![0; packet.len()];
+        stream.read_exact(&mut buffer)?;
+        let received = eb_parse_read_response(&buffer)?;
+        if received.len() != chunk {
+            return Err(BridgeError::LengthError(received.len() * 4, chunk * 4));
+        }
+        values.extend(received);
+    }
+    Ok(values)
+}
+
+/// Read `len` bytes starting at the word-aligned address `addr`.
+#[cfg(feature = "sim")]
+pub(crate) fn stream_burst_read<S: Read + Write>(
+    stream: &mut S,
+    addr: u32,
+    len: u32,
+) -> Result<Vec<u8>, BridgeError> {
+    if addr & 3 != 0 {
+        return Err(BridgeError::InvalidAddress);
+    }
+    let words = (len as usize).div_ceil(4);
+    let mut data: Vec<u8> = stream_read(stream, addr, words)?
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect();
+    data.truncate(len as usize);
+    Ok(data)
+}
+
+/// Write `data` starting at the word-aligned address `addr`. Any trailing
+/// bytes are written using only the byte lanes they cover.
+#[cfg(feature = "sim")]
+pub(crate) fn stream_burst_write<S: Write>(
+    stream: &mut S,
+    addr: u32,
+    data: &[u8],
+) -> Result<(), BridgeError> {
+    if addr & 3 != 0 {
+        return Err(BridgeError::InvalidAddress);
+    }
+    let whole = data.len() & !3;
+    let values: Vec<u32> = data[..whole]
+        .chunks(4)
+        .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        .collect();
+    stream_write(stream, addr, &values, 0x0f)?;
+
+    let tail = &data[whole..];
+    if !tail.is_empty() {
+        let mut word = [0; 4];
+        word[..tail.len()].copy_from_slice(tail);
+        let byte_enable = (1u8 << tail.len()) - 1;
+        stream_write(
+            stream,
+            addr + whole as u32,
+            &[u32::from_le_bytes(word)],
+            byte_enable,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,1267 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, info};
+
+#[cfg(feature = "tls")]
+mod tls;
+mod websocket;
+#[cfg(feature = "tls")]
+use tls::{TlsOptions, TlsStream};
+use websocket::WebSocket;
+
+use super::etherbone::{
+    eb_barrier_packet, eb_header, eb_parse_read_response, eb_read_packet, eb_write_packet,
+    eb_write_record, EB_NO_READS, EB_PROBE_FLAG, EB_PROBE_RESPONSE, EB_WIDTH_32, MAX_RECORD_OPS,
+};
+use crate::stats::Operation;
+use crate::trace::{word, TraceReplay, TraceSettings, TraceTransport, Tracer};
+use crate::{Bridge, BridgeConfig, BridgeError};
+
+/// Bytes of IPv4 and UDP header that come out of each frame's MTU
+const UDP_IP_OVERHEAD: usize = 28;
+
+/// The largest UDP payload that every IPv4 host is required to accept. This
+/// is used for devices that don't answer probes.
+const SAFE_UDP_PAYLOAD: usize = 508;
+
+/// How long to wait for each probe to be answered
+const PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long fast writes may wait for more to join them before being sent
+const FAST_WRITE_IDLE: Duration = Duration::from_millis(2);
+
+#[derive(Clone, Copy, PartialEq)]
+/// Indicates which Ethernet protocol to use for Wishbone when connecting
+/// via a network.
+pub enum EthernetBridgeProtocol {
+    /// A persistent TCP connection, also known as "Etherbone". TCP connections
+    /// are common for systems with complete networking stacks and proxies.
+    TCP,
+
+    /// An ephemeral Ethernet connection used for systems with limited networking
+    /// stacks. UDP connections are common in FPGA hardware designs.
+    UDP,
+
+    /// Etherbone carried in binary WebSocket messages, for reaching gateways
+    /// such as browser-hosted bridges, or for getting through proxies that
+    /// only pass HTTP.
+    WebSocket,
+}
+
+#[derive(Clone)]
+/// A builder to create a connection to a target via Ethernet, using TCP,
+/// UDP, or a WebSocket. The `EthernetBridge` struct also describes
+/// connections to proxy servers that relay Wishbone packets
+/// over Ethernet, such as remote USB-to-Ethernet bridges.
+///
+/// ```no_run
+/// use wishbone_bridge::EthernetBridge;
+/// let bridge = EthernetBridge::new("192.168.50.100:1234").unwrap().create().unwrap();
+/// ```
+pub struct EthernetBridge {
+    protocol: EthernetBridgeProtocol,
+    addr: SocketAddr,
+    mtu: usize,
+    websocket_host: Option<String>,
+    websocket_path: String,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsOptions>,
+    trace: Option<PathBuf>,
+    fast_writes: bool,
+}
+
+/// Describes all configuration parameters required to connect to a
+/// Wishbone bridge via Ethernet. The protocol defaults to `UDP`, which
+/// is what most embedded hardware uses. Set the protocol to TCP by using `.protocol()`.
+impl EthernetBridge {
+    pub fn new<A: std::net::ToSocketAddrs>(addr: A) -> Result<EthernetBridge, BridgeError> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(BridgeError::InvalidAddress)?;
+        Ok(EthernetBridge {
+            protocol: EthernetBridgeProtocol::UDP,
+            addr,
+            mtu: 1500,
+            websocket_host: None,
+            websocket_path: "/".to_owned(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            trace: None,
+            fast_writes: false,
+        })
+    }
+
+    /// Set the remote port for the target device.
+    pub fn port(&mut self, new_port: u16) -> &mut EthernetBridge {
+        self.addr.set_port(new_port);
+        self
+    }
+
+    /// Set the protocol to be used to connect to the remote device.
+    pub fn protocol(&mut self, prot: EthernetBridgeProtocol) -> &mut EthernetBridge {
+        self.protocol = prot;
+        self
+    }
+
+    /// Set the MTU of the link to the device, which defaults to 1500. When
+    /// using UDP, the device is probed with packets up to this size in order
+    /// to discover how large a burst may be sent in a single packet.
+    pub fn mtu(&mut self, mtu: usize) -> &mut EthernetBridge {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Set the `Host` header sent when opening a WebSocket connection. This
+    /// defaults to the address being connected to, which won't do for
+    /// gateways that serve several sites from one address.
+    pub fn websocket_host(&mut self, host: &str) -> &mut EthernetBridge {
+        self.websocket_host = Some(host.to_owned());
+        self
+    }
+
+    /// Set the path to request when opening a WebSocket connection, which
+    /// defaults to `/`.
+    pub fn websocket_path(&mut self, path: &str) -> &mut EthernetBridge {
+        self.websocket_path = path.to_owned();
+        self
+    }
+
+    /// Wrap the TCP connection in TLS. The server's certificate is checked
+    /// against the usual public certificate authorities, unless
+    /// `tls_ca_file()` gives others to use instead.
+    #[cfg(feature = "tls")]
+    pub fn tls(&mut self, enable: bool) -> &mut EthernetBridge {
+        self.tls = if enable {
+            self.tls.take().or_else(|| Some(TlsOptions::default()))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Trust the certificate authorities in the PEM file at `path`, such as
+    /// a private CA used to sign the server's certificate. Enables TLS.
+    #[cfg(feature = "tls")]
+    pub fn tls_ca_file<P: AsRef<Path>>(&mut self, path: P) -> &mut EthernetBridge {
+        self.tls.get_or_insert_with(TlsOptions::default).ca_file =
+            Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Present the certificate in the PEM file `cert` to the server, using
+    /// the private key in the PEM file `key`. Enables TLS.
+    #[cfg(feature = "tls")]
+    pub fn tls_client_cert<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        cert: P,
+        key: Q,
+    ) -> &mut EthernetBridge {
+        self.tls.get_or_insert_with(TlsOptions::default).client_cert =
+            Some((cert.as_ref().to_path_buf(), key.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Set the name that the server's certificate must be valid for. This
+    /// defaults to the IP address being connected to. Enables TLS.
+    #[cfg(feature = "tls")]
+    pub fn tls_server_name(&mut self, name: &str) -> &mut EthernetBridge {
+        self.tls.get_or_insert_with(TlsOptions::default).server_name = Some(name.to_owned());
+        self
+    }
+
+    /// Write the Etherbone packets sent and received for every successful
+    /// operation to `path`, replacing anything already there. The trace can
+    /// be checked against the protocol code later with `check_trace()`.
+    pub fn trace_to<P: AsRef<Path>>(&mut self, path: P) -> &mut EthernetBridge {
+        self.trace = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Gather writes into packets that are as full as the link allows,
+    /// rather than sending each one as soon as it's made. Packets of writes
+    /// are marked as having no reads, so the device knows not to answer
+    /// them. Once writes stop coming, whatever is left is sent, followed by
+    /// a read of the device's config space that only returns once they've
+    /// all been handled. This is much faster for loading memory one word
+    /// at a time over a slow link, such as with GDB's `load`.
+    ///
+    /// A write that fails is reported by whichever operation sends it,
+    /// which may be a later one. Over UDP this is only used with devices
+    /// that answer probes, and it's turned off when tracing. Defaults to
+    /// `false`.
+    pub fn fast_writes(&mut self, fast_writes: bool) -> &mut EthernetBridge {
+        self.fast_writes = fast_writes;
+        self
+    }
+
+    /// Create a new `Bridge` based on the current configuration.
+    pub fn create(&self) -> Result<Bridge, BridgeError> {
+        Bridge::new(BridgeConfig::EthernetBridge(self.clone()))
+    }
+}
+
+enum EthernetConnection {
+    UDP(UdpSocket),
+    TCP(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    WebSocket(WebSocket),
+
+    /// Another connection, with every packet written to a trace
+    Traced(Box<EthernetConnection>, Tracer),
+
+    /// Packets played back from a trace
+    Replay(TraceReplay),
+}
+
+impl EthernetConnection {
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> ::std::io::Result<()> {
+        match self {
+            EthernetConnection::UDP(u) => u.set_write_timeout(dur),
+            EthernetConnection::TCP(t) => t.set_write_timeout(dur),
+            #[cfg(feature = "tls")]
+            EthernetConnection::Tls(t) => t.sock.set_write_timeout(dur),
+            EthernetConnection::WebSocket(w) => w.set_write_timeout(dur),
+            EthernetConnection::Traced(c, _) => c.set_write_timeout(dur),
+            EthernetConnection::Replay(_) => Ok(()),
+        }
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> ::std::io::Result<()> {
+        match self {
+            EthernetConnection::UDP(u) => u.set_read_timeout(dur),
+            EthernetConnection::TCP(t) => t.set_read_timeout(dur),
+            #[cfg(feature = "tls")]
+            EthernetConnection::Tls(t) => t.sock.set_read_timeout(dur),
+            EthernetConnection::WebSocket(w) => w.set_read_timeout(dur),
+            EthernetConnection::Traced(c, _) => c.set_read_timeout(dur),
+            EthernetConnection::Replay(_) => Ok(()),
+        }
+    }
+
+    /// Send one Etherbone packet.
+    fn send(&mut self, packet: &[u8], remote_addr: &SocketAddr) -> ::std::io::Result<()> {
+        match self {
+            EthernetConnection::UDP(u) => {
+                u.send_to(packet, remote_addr)?;
+            }
+            EthernetConnection::TCP(t) => t.write_all(packet)?,
+            #[cfg(feature = "tls")]
+            EthernetConnection::Tls(t) => {
+                t.write_all(packet)?;
+                t.flush()?;
+            }
+            EthernetConnection::WebSocket(w) => w.send(packet)?,
+            EthernetConnection::Traced(c, tracer) => {
+                c.send(packet, remote_addr)?;
+                tracer.tx(packet);
+            }
+            EthernetConnection::Replay(r) => r.send(packet)?,
+        }
+        Ok(())
+    }
+
+    /// Send one Etherbone packet and receive the reply into `buffer`,
+    /// returning the length of the reply.
+    fn exchange(
+        &mut self,
+        packet: &[u8],
+        remote_addr: &SocketAddr,
+        buffer: &mut [u8],
+    ) -> ::std::io::Result<usize> {
+        match self {
+            EthernetConnection::UDP(u) => {
+                u.send_to(packet, remote_addr)?;
+                let (amt, _src) = u.recv_from(buffer)?;
+                Ok(amt)
+            }
+            EthernetConnection::TCP(t) => {
+                t.write_all(packet)?;
+                t.read_exact(buffer)?;
+                Ok(buffer.len())
+            }
+            #[cfg(feature = "tls")]
+            EthernetConnection::Tls(t) => {
+                t.write_all(packet)?;
+                t.flush()?;
+                t.read_exact(buffer)?;
+                Ok(buffer.len())
+            }
+            EthernetConnection::WebSocket(w) => {
+                w.send(packet)?;
+                w.read_exact(buffer)?;
+                Ok(buffer.len())
+            }
+            EthernetConnection::Traced(c, tracer) => {
+                let amt = c.exchange(packet, remote_addr, buffer)?;
+                tracer.tx(packet);
+                tracer.rx(&buffer[..amt]);
+                Ok(amt)
+            }
+            EthernetConnection::Replay(r) => {
+                r.send(packet)?;
+                r.recv(buffer)
+            }
+        }
+    }
+}
+
+/// What we know about how the device on the other end handles packets.
+#[derive(Clone, Copy, Debug)]
+struct EtherboneLink {
+    /// The largest Etherbone packet to send, in bytes
+    max_payload: usize,
+
+    /// Whether the device answered a probe, and so is assumed to accept
+    /// several records in one packet
+    multi_record: bool,
+}
+
+impl EtherboneLink {
+    /// The number of reads or writes that fit into a single packet.
+    fn ops_per_packet(&self) -> usize {
+        // 8 bytes of packet header, then 4 bytes of record header and 4
+        // bytes of base address for each record.
+        let mut remaining = self.max_payload.saturating_sub(8);
+        let mut ops = 0;
+        while remaining > 8 {
+            let count = ((remaining - 8) / 4).min(MAX_RECORD_OPS);
+            if count == 0 {
+                break;
+            }
+            ops += count;
+            remaining -= 8 + 4 * count;
+            if !self.multi_record {
+                break;
+            }
+        }
+        ops.max(1)
+    }
+
+    /// The number of writes that fit into a single record, keeping each
+    /// record small enough to go in a packet of its own.
+    fn record_ops(&self) -> usize {
+        (self.max_payload.saturating_sub(16) / 4).clamp(1, MAX_RECORD_OPS)
+    }
+}
+
+/// Writes held back by `fast_writes()`, waiting to be sent in full packets
+#[derive(Default)]
+struct PendingWrites {
+    /// The base address, byte enable, and values of each record, in order
+    records: Vec<(u32, u8, Vec<u32>)>,
+}
+
+impl PendingWrites {
+    fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Queue writes of `values` to consecutive addresses starting at `addr`,
+    /// adding them to the last record where they carry on from it.
+    fn push(&mut self, link: &EtherboneLink, addr: u32, values: &[u32], byte_enable: u8) {
+        let limit = link.record_ops();
+        for (i, value) in values.iter().enumerate() {
+            let addr = addr + (i * 4) as u32;
+            match self.records.last_mut() {
+                Some((base, be, queued))
+                    if *be == byte_enable
+                        && queued.len() < limit
+                        && base.wrapping_add((queued.len() * 4) as u32) == addr =>
+                {
+                    queued.push(*value)
+                }
+                _ => self.records.push((addr, byte_enable, vec![*value])),
+            }
+        }
+    }
+
+    /// Send every packet that can't take any more records. If `all` is set,
+    /// send the last, partly filled, packet as well.
+    fn send(
+        &mut self,
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        all: bool,
+    ) -> Result<(), BridgeError> {
+        let mut packet = eb_header(EB_NO_READS);
+        let mut in_packet = 0;
+        let mut sent = 0;
+        for (i, (addr, byte_enable, values)) in self.records.iter().enumerate() {
+            if in_packet > 0
+                && (!link.multi_record || packet.len() + 8 + 4 * values.len() > link.max_payload)
+            {
+                connection.send(&packet, remote_addr)?;
+                packet = eb_header(EB_NO_READS);
+                in_packet = 0;
+                sent = i;
+            }
+            eb_write_record(&mut packet, *addr, values, *byte_enable);
+            in_packet += 1;
+        }
+        if all && in_packet > 0 {
+            connection.send(&packet, remote_addr)?;
+            sent = self.records.len();
+        }
+        self.records.drain(..sent);
+        Ok(())
+    }
+}
+
+pub struct EthernetBridgeInner {
+    cfg: EthernetBridge,
+    main_tx: Sender<ConnectThreadRequests>,
+    main_rx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+    mutex: Arc<Mutex<()>>,
+    poll_thread: Option<thread::JoinHandle<()>>,
+}
+
+enum ConnectThreadRequests {
+    StartPolling(SocketAddr),
+    Exit,
+    Poke(u32 /* addr */, u32 /* val */),
+    Peek(u32 /* addr */),
+    BurstRead(u32 /* addr */, u32 /* len */),
+    BurstWrite(u32 /* addr */, Vec<u8> /* write data */),
+}
+
+#[derive(Debug)]
+enum ConnectThreadResponses {
+    Exiting,
+    OpenedDevice,
+    PeekResult(Result<u32, BridgeError>),
+    PokeResult(Result<(), BridgeError>),
+    BurstReadResult(Result<Vec<u8>, BridgeError>),
+    BurstWriteResult(Result<(), BridgeError>),
+}
+
+impl Clone for EthernetBridgeInner {
+    fn clone(&self) -> Self {
+        EthernetBridgeInner {
+            cfg: self.cfg.clone(),
+            main_tx: self.main_tx.clone(),
+            main_rx: self.main_rx.clone(),
+            mutex: self.mutex.clone(),
+            poll_thread: None,
+        }
+    }
+}
+
+impl EthernetBridgeInner {
+    pub fn new(cfg: &EthernetBridge) -> Result<Self, BridgeError> {
+        #[cfg(feature = "tls")]
+        let tls_config = match &cfg.tls {
+            Some(_) if cfg.protocol != EthernetBridgeProtocol::TCP => {
+                return Err(BridgeError::ProtocolNotSupported)
+            }
+            Some(options) => Some(tls::client_config(options)?),
+            None => None,
+        };
+
+        let tracer = match &cfg.trace {
+            Some(path) => Some(Tracer::create(path, TraceTransport::Etherbone)?),
+            None => None,
+        };
+
+        let (main_tx, thread_rx) = channel();
+        let cv = Arc::new((Mutex::new(None), Condvar::new()));
+
+        let thr_cv = cv.clone();
+        let thr_cfg = cfg.clone();
+        let poll_thread = Some(thread::spawn(move || {
+            Self::ethernet_thread(
+                thr_cv,
+                thread_rx,
+                thr_cfg,
+                tracer,
+                #[cfg(feature = "tls")]
+                tls_config,
+            )
+        }));
+
+        Ok(EthernetBridgeInner {
+            cfg: cfg.clone(),
+            main_tx,
+            main_rx: cv,
+            mutex: Arc::new(Mutex::new(())),
+            poll_thread,
+        })
+    }
+
+    fn ethernet_thread(
+        tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+        rx: Receiver<ConnectThreadRequests>,
+        cfg: EthernetBridge,
+        tracer: Option<Tracer>,
+        #[cfg(feature = "tls")] tls_config: Option<Arc<rustls::ClientConfig>>,
+    ) {
+        let mut remote_addr = cfg.addr;
+        let mut print_waiting_message = true;
+        let mut first_run = true;
+        let &(ref response, ref cvar) = &*tx;
+        loop {
+            let mut connection = if cfg.protocol == EthernetBridgeProtocol::WebSocket {
+                match Self::open_websocket(&cfg, &remote_addr) {
+                    Ok(conn) => {
+                        info!("Re-opened websocket {}{}", remote_addr, cfg.websocket_path);
+                        EthernetConnection::WebSocket(conn)
+                    }
+                    Err(e) => {
+                        if print_waiting_message {
+                            print_waiting_message = false;
+                            error!("unable to open websocket {}{}, will wait for it to appear again: {}", remote_addr, cfg.websocket_path, e);
+                        }
+                        thread::park_timeout(Duration::from_millis(500));
+                        continue;
+                    }
+                }
+            } else if cfg.protocol == EthernetBridgeProtocol::TCP {
+                match Self::open_tcp(
+                    &cfg,
+                    &remote_addr,
+                    #[cfg(feature = "tls")]
+                    &tls_config,
+                ) {
+                    Ok(conn) => {
+                        info!("Re-opened ethernet host {}", remote_addr);
+                        conn
+                    }
+                    Err(e) => {
+                        if print_waiting_message {
+                            print_waiting_message = false;
+                            error!("unable to open ethernet host {}, will wait for it to appear again: {}", remote_addr, e);
+                        }
+                        thread::park_timeout(Duration::from_millis(500));
+                        continue;
+                    }
+                }
+            } else {
+                match UdpSocket::bind(format!("0.0.0.0:{}", remote_addr.port())) {
+                    Ok(conn) => {
+                        info!("Re-opened ethernet host {}", remote_addr);
+                        EthernetConnection::UDP(conn)
+                    }
+                    Err(e) => {
+                        if print_waiting_message {
+                            print_waiting_message = false;
+                            error!("unable to open ethernet host {}, will wait for it to appear again: {}", remote_addr, e);
+                        }
+                        thread::park_timeout(Duration::from_millis(500));
+                        continue;
+                    }
+                }
+            };
+
+            if first_run {
+                *response.lock().unwrap() = Some(ConnectThreadResponses::OpenedDevice);
+                first_run = false;
+                cvar.notify_one();
+            }
+            print_waiting_message = true;
+
+            if let Err(e) = connection.set_read_timeout(Some(Duration::from_millis(1000))) {
+                error!("unable to set ethernet read duration timeout: {}", e);
+            }
+            if let Err(e) = connection.set_write_timeout(Some(Duration::from_millis(1000))) {
+                error!("unable to set ethernet write duration timeout: {}", e);
+            }
+
+            // TCP is a stream, so there's no MTU to worry about. Keep to one
+            // record per packet, since that's all that proxies such as
+            // `wishbone-tool --server wishbone` understand.
+            let link = match &connection {
+                EthernetConnection::UDP(u) => Self::probe(u, &remote_addr, cfg.mtu),
+                _ => EtherboneLink {
+                    max_payload: 16 + 4 * MAX_RECORD_OPS,
+                    multi_record: false,
+                },
+            };
+            if let Some(tracer) = &tracer {
+                tracer.set("max_payload", link.max_payload);
+                tracer.set("multi_record", link.multi_record);
+                connection = EthernetConnection::Traced(Box::new(connection), tracer.clone());
+            }
+
+            // Writes can only be held back if the device will handle them in
+            // order with whatever comes after. A device that answers probes
+            // does, as does anything at the other end of a stream. Traces
+            // record each operation's packets, so they'd be muddled by it.
+            let mut pending = if cfg.fast_writes
+                && tracer.is_none()
+                && (link.multi_record || cfg.protocol != EthernetBridgeProtocol::UDP)
+            {
+                Some(PendingWrites::default())
+            } else {
+                if cfg.fast_writes {
+                    info!("fast writes aren't available on this connection");
+                }
+                None
+            };
+
+            let mut keep_going = true;
+            let mut result_error = "".to_owned();
+            while keep_going {
+                let var = match &pending {
+                    Some(p) if !p.is_empty() => rx.recv_timeout(FAST_WRITE_IDLE),
+                    _ => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match var {
+                    Err(RecvTimeoutError::Timeout) => {
+                        // Nothing else has come along to share a packet with
+                        // the writes that are waiting, so send them.
+                        if let Err(err) = Self::flush_writes(
+                            &mut connection,
+                            &remote_addr,
+                            &link,
+                            pending.as_mut(),
+                        ) {
+                            result_error = format!("write {:?}", err);
+                            keep_going = false;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        error!("connection closed");
+                        return;
+                    }
+                    Ok(o) => match o {
+                        ConnectThreadRequests::Exit => {
+                            debug!("ethernet_thread requested exit");
+                            if let Err(err) = Self::flush_writes(
+                                &mut connection,
+                                &remote_addr,
+                                &link,
+                                pending.as_mut(),
+                            ) {
+                                error!("unable to send the last writes: {:?}", err);
+                            }
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::Exiting);
+                            cvar.notify_one();
+                            return;
+                        }
+                        ConnectThreadRequests::StartPolling(new_remote_addr) => {
+                            remote_addr = new_remote_addr;
+                        }
+                        ConnectThreadRequests::Peek(addr) => {
+                            // The device answers reads in order, so there's
+                            // no need to wait for writes before this one.
+                            let result = Self::send_writes(
+                                &mut connection,
+                                &remote_addr,
+                                &link,
+                                &mut pending,
+                            )
+                            .and_then(|()| {
+                                Self::do_peek(&mut connection, &remote_addr, &link, addr)
+                            });
+                            if let Some(tracer) = &tracer {
+                                let value = result.as_ref().ok().map(|v| v.to_le_bytes().to_vec());
+                                tracer.finish(Operation::Peek, addr, value);
+                            }
+                            if let Err(err) = &result {
+                                result_error = format!("peek {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::PeekResult(result));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::Poke(addr, val) => {
+                            let result = Self::do_poke(
+                                &mut connection,
+                                &remote_addr,
+                                &link,
+                                pending.as_mut(),
+                                addr,
+                                val,
+                            );
+                            if let Some(tracer) = &tracer {
+                                let value =
+                                    result.as_ref().ok().map(|_| val.to_le_bytes().to_vec());
+                                tracer.finish(Operation::Poke, addr, value);
+                            }
+                            if let Err(err) = &result {
+                                result_error = format!("poke {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::PokeResult(result));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstRead(addr, len) => {
+                            let result = Self::send_writes(
+                                &mut connection,
+                                &remote_addr,
+                                &link,
+                                &mut pending,
+                            )
+                            .and_then(|()| {
+                                Self::do_burst_read(&mut connection, &remote_addr, &link, addr, len)
+                            });
+                            if let Some(tracer) = &tracer {
+                                tracer.finish(
+                                    Operation::BurstRead,
+                                    addr,
+                                    result.as_ref().ok().cloned(),
+                                );
+                            }
+                            if let Err(err) = &result {
+                                result_error = format!("burst read {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstReadResult(result));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstWrite(addr, data) => {
+                            let result = Self::do_burst_write(
+                                &mut connection,
+                                &remote_addr,
+                                &link,
+                                pending.as_mut(),
+                                addr,
+                                &data,
+                            );
+                            if let Some(tracer) = &tracer {
+                                let written = result.as_ref().ok().map(|_| data.clone());
+                                tracer.finish(Operation::BurstWrite, addr, written);
+                            }
+                            if let Err(err) = &result {
+                                result_error = format!("burst write {:?} @ {:08x}", err, addr);
+                                keep_going = false;
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstWriteResult(result));
+                            cvar.notify_one();
+                        }
+                    },
+                }
+            }
+            error!("ethernet connection was closed: {}", result_error);
+            thread::park_timeout(Duration::from_millis(500));
+
+            // Respond to any messages in the buffer with NotConnected.  As soon
+            // as the channel is empty, loop back to the start of this function.
+            loop {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("main thread disconnected"),
+                    Ok(m) => match m {
+                        ConnectThreadRequests::Exit => {
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::Exiting);
+                            cvar.notify_one();
+                            debug!("main thread requested exit");
+                            return;
+                        }
+                        ConnectThreadRequests::Peek(_addr) => {
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::PeekResult(
+                                Err(BridgeError::NotConnected),
+                            ));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::Poke(_addr, _val) => {
+                            *response.lock().unwrap() = Some(ConnectThreadResponses::PokeResult(
+                                Err(BridgeError::NotConnected),
+                            ));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstRead(_addr, _len) => {
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstReadResult(Err(
+                                    BridgeError::NotConnected,
+                                )));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstWrite(_addr, _data) => {
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstWriteResult(Err(
+                                    BridgeError::NotConnected,
+                                )));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::StartPolling(new_remote_addr) => {
+                            remote_addr = new_remote_addr
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        self.main_tx
+            .send(ConnectThreadRequests::StartPolling(self.cfg.addr))
+            .unwrap();
+        loop {
+            // The thread announces `OpenedDevice` as soon as the socket is
+            // open, which may be before we get here, so don't clear it first.
+            let &(ref lock, ref cvar) = &*self.main_rx;
+            let mut _mtx = lock.lock().unwrap();
+            while _mtx.is_none() {
+                _mtx = cvar.wait(_mtx).unwrap();
+            }
+            if let Some(ConnectThreadResponses::OpenedDevice) = _mtx.take() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Open a TCP connection, wrapped in TLS if that was asked for.
+    fn open_tcp(
+        #[allow(unused_variables)] cfg: &EthernetBridge,
+        remote_addr: &SocketAddr,
+        #[cfg(feature = "tls")] tls_config: &Option<Arc<rustls::ClientConfig>>,
+    ) -> std::io::Result<EthernetConnection> {
+        let stream = TcpStream::connect(remote_addr)?;
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = tls_config {
+            // Don't let a server that never answers hold up the handshake.
+            stream.set_read_timeout(Some(Duration::from_millis(1000)))?;
+            let server_name = match cfg.tls.as_ref().and_then(|t| t.server_name.as_ref()) {
+                Some(name) => name.clone(),
+                None => remote_addr.ip().to_string(),
+            };
+            let stream = tls::connect(tls_config.clone(), &server_name, stream)?;
+            return Ok(EthernetConnection::Tls(Box::new(stream)));
+        }
+        Ok(EthernetConnection::TCP(stream))
+    }
+
+    /// Connect to the gateway and upgrade the connection to a WebSocket.
+    fn open_websocket(
+        cfg: &EthernetBridge,
+        remote_addr: &SocketAddr,
+    ) -> std::io::Result<WebSocket> {
+        let stream = TcpStream::connect(remote_addr)?;
+        // The handshake is read a byte at a time, so don't let a silent
+        // server hang it forever.
+        stream.set_read_timeout(Some(Duration::from_millis(1000)))?;
+        let host = match &cfg.websocket_host {
+            Some(host) => host.clone(),
+            None => remote_addr.to_string(),
+        };
+        WebSocket::connect(stream, &host, &cfg.websocket_path)
+    }
+
+    /// Send an Etherbone probe and check that the device can talk to us.
+    ///
+    /// Probes are sent padded out to successively smaller sizes, starting at
+    /// the largest payload the MTU allows. Devices such as LiteEth echo the
+    /// whole probe back, so the largest probe that gets an answer is taken as
+    /// the largest packet the device can receive.
+    fn probe(connection: &UdpSocket, remote_addr: &SocketAddr, mtu: usize) -> EtherboneLink {
+        let largest = mtu.saturating_sub(UDP_IP_OVERHEAD).max(8);
+        let mut sizes = vec![largest];
+        for size in &[1024, SAFE_UDP_PAYLOAD, 8] {
+            if *size < largest {
+                sizes.push(*size);
+            }
+        }
+
+        if let Err(e) = connection.set_read_timeout(Some(PROBE_TIMEOUT)) {
+            error!("unable to set ethernet probe timeout: {}", e);
+        }
+        let mut link = None;
+        for size in sizes {
+            let mut packet = eb_header(EB_PROBE_FLAG);
+            packet.resize(size, 0);
+            if let Err(e) = connection.send_to(&packet, remote_addr) {
+                debug!("unable to send {} byte probe: {}", size, e);
+                continue;
+            }
+            let mut reply = vec![0; size.max(8)];
+            let amt = match connection.recv_from(&mut reply) {
+                Ok((amt, _src)) => amt,
+                Err(_) => {
+                    debug!("no answer to {} byte probe", size);
+                    continue;
+                }
+            };
+            if amt < 8
+                || reply[0] != 0x4e
+                || reply[1] != 0x6f
+                || (reply[2] & EB_PROBE_RESPONSE) == 0
+            {
+                debug!("unexpected answer to {} byte probe", size);
+                continue;
+            }
+            if (reply[3] & EB_WIDTH_32) != EB_WIDTH_32 {
+                error!(
+                    "device doesn't support 32-bit addresses and data (widths {:02x})",
+                    reply[3]
+                );
+            }
+            // A header-only probe tells us the device is there, but nothing
+            // about how big a packet it takes.
+            link = Some(EtherboneLink {
+                max_payload: if size > 8 { size } else { SAFE_UDP_PAYLOAD },
+                multi_record: true,
+            });
+            break;
+        }
+        if let Err(e) = connection.set_read_timeout(Some(Duration::from_millis(1000))) {
+            error!("unable to set ethernet read duration timeout: {}", e);
+        }
+
+        match link {
+            Some(link) => {
+                info!(
+                    "etherbone device answered probe, using {} byte packets",
+                    link.max_payload
+                );
+                link
+            }
+            None => {
+                info!(
+                    "etherbone device didn't answer probe, using {} byte packets",
+                    SAFE_UDP_PAYLOAD
+                );
+                EtherboneLink {
+                    max_payload: SAFE_UDP_PAYLOAD,
+                    multi_record: false,
+                }
+            }
+        }
+    }
+
+    /// Write `values` to consecutive addresses starting at `addr`, or queue
+    /// them in `pending` if fast writes are in use.
+    fn do_write(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        pending: Option<&mut PendingWrites>,
+        addr: u32,
+        values: &[u32],
+        byte_enable: u8,
+    ) -> Result<(), BridgeError> {
+        if let Some(pending) = pending {
+            pending.push(link, addr, values, byte_enable);
+            return pending.send(connection, remote_addr, link, false);
+        }
+        let ops = link.ops_per_packet();
+        for (i, chunk) in values.chunks(ops).enumerate() {
+            let packet = eb_write_packet(addr + (i * ops * 4) as u32, chunk, byte_enable);
+            connection.send(&packet, remote_addr)?;
+        }
+        Ok(())
+    }
+
+    /// Send all of the writes in `pending`, without waiting for them to be
+    /// handled.
+    fn send_writes(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        pending: &mut Option<PendingWrites>,
+    ) -> Result<(), BridgeError> {
+        match pending {
+            Some(pending) => pending.send(connection, remote_addr, link, true),
+            None => Ok(()),
+        }
+    }
+
+    /// Send all of the writes in `pending`, then wait until the device has
+    /// handled them.
+    fn flush_writes(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        pending: Option<&mut PendingWrites>,
+    ) -> Result<(), BridgeError> {
+        let pending = match pending {
+            Some(pending) if !pending.is_empty() => pending,
+            _ => return Ok(()),
+        };
+        pending.send(connection, remote_addr, link, true)?;
+        let packet = eb_barrier_packet();
+        let mut buffer = vec![0; packet.len()];
+        let amt = connection.exchange(&packet, remote_addr, &mut buffer)?;
+        if amt != buffer.len() {
+            return Err(BridgeError::LengthError(amt, buffer.len()));
+        }
+        eb_parse_read_response(&buffer)?;
+        Ok(())
+    }
+
+    fn do_read(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        addr: u32,
+        count: usize,
+    ) -> Result<Vec<u32>, BridgeError> {
+        let ops = link.ops_per_packet();
+        let mut values = Vec::with_capacity(count);
+        while values.len() < count {
+            let chunk = (count - values.len()).min(ops);
+            let packet = eb_read_packet(addr + (values.len() * 4) as u32, chunk);
+            // Each read record comes back as a write record of the same size.
+            let mut buffer = vec![0; packet.len()];
+            let amt = connection.exchange(&packet, remote_addr, &mut buffer)?;
+            if amt != buffer.len() {
+                return Err(BridgeError::LengthError(amt, buffer.len()));
+            }
+            let received = eb_parse_read_response(&buffer)?;
+            if received.len() != chunk {
+                return Err(BridgeError::LengthError(received.len() * 4, chunk * 4));
+            }
+            values.extend(received);
+        }
+        Ok(values)
+    }
+
+    fn do_poke(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        pending: Option<&mut PendingWrites>,
+        addr: u32,
+        value: u32,
+    ) -> Result<(), BridgeError> {
+        debug!("POKE @ {:08x} -> {:08x}", addr, value);
+        Self::do_write(connection, remote_addr, link, pending, addr, &[value], 0x0f)
+    }
+
+    fn do_peek(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        addr: u32,
+    ) -> Result<u32, BridgeError> {
+        let val = Self::do_read(connection, remote_addr, link, addr, 1)?[0];
+        debug!("PEEK @ {:08x} = {:08x}", addr, val);
+        Ok(val)
+    }
+
+    fn do_burst_read(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, BridgeError> {
+        debug!("BURST READ @ {:08x} ({} bytes)", addr, len);
+        if addr & 3 != 0 {
+            return Err(BridgeError::InvalidAddress);
+        }
+        let words = (len as usize).div_ceil(4);
+        let mut data: Vec<u8> = Self::do_read(connection, remote_addr, link, addr, words)?
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        data.truncate(len as usize);
+        Ok(data)
+    }
+
+    fn do_burst_write(
+        connection: &mut EthernetConnection,
+        remote_addr: &SocketAddr,
+        link: &EtherboneLink,
+        mut pending: Option<&mut PendingWrites>,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), BridgeError> {
+        debug!("BURST WRITE @ {:08x} ({} bytes)", addr, data.len());
+        if addr & 3 != 0 {
+            return Err(BridgeError::InvalidAddress);
+        }
+        let whole = data.len() & !3;
+        let values: Vec<u32> = data[..whole]
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        Self::do_write(
+            connection,
+            remote_addr,
+            link,
+            pending.as_deref_mut(),
+            addr,
+            &values,
+            0x0f,
+        )?;
+
+        // Write any trailing bytes using only the byte lanes they cover.
+        let tail = &data[whole..];
+        if !tail.is_empty() {
+            let mut word = [0; 4];
+            word[..tail.len()].copy_from_slice(tail);
+            let byte_enable = (1u8 << tail.len()) - 1;
+            Self::do_write(
+                connection,
+                remote_addr,
+                link,
+                pending,
+                addr + whole as u32,
+                &[u32::from_le_bytes(word)],
+                byte_enable,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        let &(ref lock, ref cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::Poke(addr, value))
+            .expect("Unable to send poke to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::PokeResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let &(ref lock, ref cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::Peek(addr))
+            .expect("Unable to send peek to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::PeekResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn burst_read(&self, addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstRead(addr, len))
+            .expect("Unable to send burst read to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstReadResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst read response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstWrite(addr, data.to_vec()))
+            .expect("Unable to send burst write to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstWriteResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst write response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+}
+
+impl Drop for EthernetBridgeInner {
+    fn drop(&mut self) {
+        // If this is the last reference to the bridge, tell the control thread
+        // to exit.
+        let sc = Arc::strong_count(&self.mutex);
+        let wc = Arc::weak_count(&self.mutex);
+        debug!("strong count: {}  weak count: {}", sc, wc);
+        if (sc + wc) <= 1 {
+            let &(ref lock, ref cvar) = &*self.main_rx;
+            let mut mtx = lock.lock().unwrap();
+            self.main_tx
+                .send(ConnectThreadRequests::Exit)
+                .expect("Unable to send Exit request to thread");
+
+            *mtx = None;
+            while mtx.is_none() {
+                mtx = cvar.wait(mtx).unwrap();
+            }
+            match mtx.take() {
+                Some(ConnectThreadResponses::Exiting) => (),
+                e => {
+                    error!("unexpected bridge exit response: {:?}", e);
+                }
+            }
+            if let Some(pt) = self.poll_thread.take() {
+                pt.join().expect("Unable to join polling thread");
+            }
+        }
+    }
+}
+
+/// Run one operation from a transport trace through the Etherbone encoder
+/// and decoder, splitting it into packets the way the traced link did.
+pub(crate) fn replay_op(
+    replay: &mut TraceReplay,
+    settings: &TraceSettings,
+    op: Operation,
+    addr: u32,
+    data: &[u8],
+) -> Result<Vec<u8>, BridgeError> {
+    let link = EtherboneLink {
+        max_payload: settings.get("max_payload")?,
+        multi_record: settings.get("multi_record")?,
+    };
+    // Only UDP needs an address, and the packets never leave here.
+    let remote_addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    let mut connection = EthernetConnection::Replay(std::mem::take(replay));
+    let result = match op {
+        Operation::Peek => EthernetBridgeInner::do_peek(&mut connection, &remote_addr, &link, addr)
+            .map(|value| value.to_le_bytes().to_vec()),
+        Operation::Poke => word(data).and_then(|value| {
+            EthernetBridgeInner::do_poke(&mut connection, &remote_addr, &link, None, addr, value)
+                .map(|()| vec![])
+        }),
+        Operation::BurstRead => EthernetBridgeInner::do_burst_read(
+            &mut connection,
+            &remote_addr,
+            &link,
+            addr,
+            data.len() as u32,
+        ),
+        Operation::BurstWrite => EthernetBridgeInner::do_burst_write(
+            &mut connection,
+            &remote_addr,
+            &link,
+            None,
+            addr,
+            data,
+        )
+        .map(|()| vec![]),
+        Operation::Connect => Err(BridgeError::ProtocolNotSupported),
+    };
+    if let EthernetConnection::Replay(r) = connection {
+        *replay = r;
+    }
+    result
+}
@@ -0,0 +1,123 @@
+//! TLS for the TCP Ethernet bridge, so that a remote Wishbone server can be
+//! reached over an untrusted network without exposing the bus to anyone who
+//! can open a connection to it.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    ServerName, StreamOwned,
+};
+
+use crate::BridgeError;
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// How to set up TLS, as given to the `EthernetBridge` builder
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM file of certificate authorities to trust instead of the usual
+    /// public ones
+    pub ca_file: Option<PathBuf>,
+
+    /// PEM files holding a certificate and its private key, to prove who
+    /// we are to the server
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+
+    /// Name to expect in the server's certificate, if it isn't the address
+    /// being connected to
+    pub server_name: Option<String>,
+}
+
+impl std::convert::From<rustls::Error> for BridgeError {
+    fn from(e: rustls::Error) -> BridgeError {
+        BridgeError::IoError(io::Error::other(e))
+    }
+}
+
+fn open_pem(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn pem_error(path: &Path, msg: &str) -> BridgeError {
+    BridgeError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), msg),
+    ))
+}
+
+/// Load the certificates and key named in `options`, so that any problems
+/// with them show up when the bridge is created rather than on connection.
+pub fn client_config(options: &TlsOptions) -> Result<Arc<ClientConfig>, BridgeError> {
+    let mut roots = RootCertStore::empty();
+    match &options.ca_file {
+        Some(path) => {
+            let certs = rustls_pemfile::certs(&mut open_pem(path)?)?;
+            let (added, _ignored) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(pem_error(path, "no usable CA certificates found"));
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match &options.client_cert {
+        Some((cert_path, key_path)) => {
+            let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open_pem(cert_path)?)?
+                .into_iter()
+                .map(Certificate)
+                .collect();
+            if certs.is_empty() {
+                return Err(pem_error(cert_path, "no certificates found"));
+            }
+            let key = rustls_pemfile::read_all(&mut open_pem(key_path)?)?
+                .into_iter()
+                .find_map(|item| match item {
+                    rustls_pemfile::Item::RSAKey(key)
+                    | rustls_pemfile::Item::PKCS8Key(key)
+                    | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+                    _ => None,
+                })
+                .ok_or_else(|| pem_error(key_path, "no private key found"))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Wrap `stream` in TLS and complete the handshake, checking that the
+/// server's certificate is valid for `server_name`.
+pub fn connect(
+    config: Arc<ClientConfig>,
+    server_name: &str,
+    mut stream: TcpStream,
+) -> io::Result<TlsStream> {
+    let name = ServerName::try_from(server_name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} isn't a valid TLS server name", server_name),
+        )
+    })?;
+    let mut connection = ClientConnection::new(config, name).map_err(io::Error::other)?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+    Ok(StreamOwned::new(connection, stream))
+}
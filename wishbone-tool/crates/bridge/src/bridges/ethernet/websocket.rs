@@ -0,0 +1,299 @@
+//! Just enough of a WebSocket client (RFC 6455) to carry Etherbone packets
+//! through HTTP gateways. Each packet goes out as a single binary message,
+//! and incoming messages are treated as one continuous stream of bytes, so
+//! it doesn't matter how the gateway chooses to split up its replies.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Appended to the client's key before hashing, as given by the RFC
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest HTTP response header we're willing to buffer during the handshake
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+pub struct WebSocket {
+    stream: TcpStream,
+
+    /// Payload that has been received but not yet read
+    pending: Vec<u8>,
+
+    /// Used to generate the masking key for each frame
+    mask_state: u32,
+}
+
+impl WebSocket {
+    /// Perform the opening handshake over `stream`, asking for `path` on
+    /// `host`. `host` is sent as-is in the `Host` header, so it should
+    /// include the port if that isn't the default.
+    pub fn connect(stream: TcpStream, host: &str, path: &str) -> io::Result<WebSocket> {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos()
+            ^ std::process::id().rotate_left(16);
+        let mut ws = WebSocket {
+            stream,
+            pending: vec![],
+            mask_state: seed | 1,
+        };
+
+        let mut nonce = [0u8; 16];
+        for chunk in nonce.chunks_mut(4) {
+            chunk.copy_from_slice(&ws.next_mask());
+        }
+        let key = base64(&nonce);
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             \r\n",
+            path, host, key
+        );
+        ws.stream.write_all(request.as_bytes())?;
+
+        // Read the response one byte at a time, so nothing past the end of
+        // the headers is consumed.
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > MAX_HANDSHAKE_LEN {
+                return Err(handshake_error("response headers are too long"));
+            }
+            let mut byte = [0u8];
+            ws.stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let mut lines = response.split("\r\n");
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(handshake_error(&format!(
+                "server refused the upgrade: {}",
+                status
+            )));
+        }
+        let expected = base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+        let accepted = lines.any(|line| match line.split_once(':') {
+            Some((name, value)) => {
+                name.trim().eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == expected
+            }
+            None => false,
+        });
+        if !accepted {
+            return Err(handshake_error(
+                "server sent the wrong Sec-WebSocket-Accept",
+            ));
+        }
+        Ok(ws)
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(dur)
+    }
+
+    pub fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(dur)
+    }
+
+    /// Send `data` as a single binary message.
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send_frame(OP_BINARY, data)
+    }
+
+    fn send_frame(&mut self, opcode: u8, data: &[u8]) -> io::Result<()> {
+        // Clients must always set the mask bit.
+        let mut frame = vec![0x80 | opcode];
+        match data.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= 0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let mask = self.next_mask();
+        frame.extend_from_slice(&mask);
+        frame.extend(data.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        self.stream.write_all(&frame)
+    }
+
+    /// A xorshift generator is plenty here: the mask only needs to be
+    /// unpredictable enough that proxies can't be confused by the payload.
+    fn next_mask(&mut self) -> [u8; 4] {
+        let mut x = self.mask_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.mask_state = x;
+        x.to_le_bytes()
+    }
+
+    /// Wait for the next data frame and add its payload to `pending`,
+    /// answering any pings along the way.
+    fn receive_frame(&mut self) -> io::Result<()> {
+        loop {
+            let mut header = [0u8; 2];
+            self.stream.read_exact(&mut header)?;
+            let opcode = header[0] & 0x0f;
+            let masked = header[1] & 0x80 != 0;
+            let len = match header[1] & 0x7f {
+                126 => {
+                    let mut len = [0u8; 2];
+                    self.stream.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as u64
+                }
+                127 => {
+                    let mut len = [0u8; 8];
+                    self.stream.read_exact(&mut len)?;
+                    u64::from_be_bytes(len)
+                }
+                len => len as u64,
+            };
+            let mut mask = [0u8; 4];
+            if masked {
+                self.stream.read_exact(&mut mask)?;
+            }
+            let mut payload = vec![0; len as usize];
+            self.stream.read_exact(&mut payload)?;
+            if masked {
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
+                }
+            }
+
+            match opcode {
+                OP_BINARY | OP_CONTINUATION => {
+                    self.pending.extend_from_slice(&payload);
+                    return Ok(());
+                }
+                OP_PING => self.send_frame(OP_PONG, &payload)?,
+                OP_PONG => (),
+                OP_CLOSE => {
+                    // Echo the close so the server can shut down cleanly.
+                    self.send_frame(OP_CLOSE, &payload[..payload.len().min(2)])
+                        .ok();
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "websocket closed by server",
+                    ));
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected websocket opcode {:x}", other),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            self.receive_frame()?;
+        }
+        let amt = buf.len().min(self.pending.len());
+        buf[..amt].copy_from_slice(&self.pending[..amt]);
+        self.pending.drain(..amt);
+        Ok(amt)
+    }
+}
+
+fn handshake_error(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("websocket handshake failed: {}", msg),
+    )
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// SHA-1, which the handshake uses to prove that the server understood the
+/// request. It isn't used for anything security-related.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (hi, v) in h.iter_mut().zip(&[a, b, c, d, e]) {
+            *hi = hi.wrapping_add(*v);
+        }
+    }
+
+    let mut out = [0u8; 20];
+    for (chunk, word) in out.chunks_mut(4).zip(&h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
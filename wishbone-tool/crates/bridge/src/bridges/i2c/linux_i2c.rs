@@ -0,0 +1,53 @@
+extern crate i2cdev;
+
+use std::io;
+
+use i2cdev::core::{I2CMessage, I2CTransfer};
+use i2cdev::linux::{LinuxI2CBus, LinuxI2CMessage};
+
+use super::I2cTransport;
+use crate::BridgeError;
+
+// The kernel reports a NACK of the address as one of these, depending
+// on the adapter driver.
+const ENXIO: i32 = 6;
+const EREMOTEIO: i32 = 121;
+
+/// An I2C adapter exposed by Linux as `/dev/i2c-N`.
+pub struct LinuxI2c {
+    bus: LinuxI2CBus,
+}
+
+impl LinuxI2c {
+    pub fn open(path: &str) -> Result<LinuxI2c, BridgeError> {
+        let bus = LinuxI2CBus::new(path).map_err(io::Error::from)?;
+        Ok(LinuxI2c { bus })
+    }
+}
+
+impl I2cTransport for LinuxI2c {
+    fn transfer(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<bool, BridgeError> {
+        let address = address as u16;
+        let result = if read.is_empty() {
+            self.bus
+                .transfer(&mut [LinuxI2CMessage::write(write).with_address(address)])
+        } else {
+            self.bus.transfer(&mut [
+                LinuxI2CMessage::write(write).with_address(address),
+                LinuxI2CMessage::read(read).with_address(address),
+            ])
+        };
+        match result.map_err(io::Error::from) {
+            Ok(_) => Ok(true),
+            Err(e) => match e.raw_os_error() {
+                Some(ENXIO) | Some(EREMOTEIO) => Ok(false),
+                _ => Err(BridgeError::IoError(e)),
+            },
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, info};
+
+use crate::{Bridge, BridgeConfig, BridgeError};
+
+#[cfg(target_os = "linux")]
+pub mod linux_i2c;
+
+#[cfg(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64")))]
+pub mod raspberry_i2c;
+
+/// The target NACKs its address while a Wishbone transaction is in
+/// progress. This is how many times to retry before giving up.
+const TIMEOUT_COUNT: u32 = 100;
+
+#[derive(Clone, Debug)]
+enum I2cPort {
+    /// A Linux I2C adapter, such as `/dev/i2c-1`
+    #[allow(dead_code)]
+    Device(String),
+
+    /// Bit-banged GPIO pins
+    #[allow(dead_code)]
+    Gpio { sda: u8, scl: u8 },
+}
+
+#[derive(Clone)]
+/// Describes a connection to a device over I2C. Note that not all
+/// platforms support I2C connections.
+pub struct I2cBridge {
+    port: I2cPort,
+    address: u8,
+    #[allow(dead_code)]
+    frequency: u32,
+}
+
+/// A builder to create a connection to a target via I2C. This is useful
+/// for small designs where only two pins are available for debugging.
+///
+/// The target responds at a single 7-bit address. A write is a single
+/// I2C write of nine bytes: the command `0x00`, followed by the address
+/// and the value, both big-endian. A read is an I2C write of the command
+/// `0x01` followed by the big-endian address, then a repeated start and a
+/// four-byte read of the value. While the Wishbone transaction is in
+/// progress, the target NACKs its address, and the transfer is retried.
+///
+/// ```no_run
+/// use wishbone_bridge::I2cBridge;
+/// let bridge = I2cBridge::new("/dev/i2c-1").address(0x10).create().unwrap();
+/// ```
+impl I2cBridge {
+    /// Create a new I2cBridge that uses a Linux I2C adapter at `path`,
+    /// such as `/dev/i2c-1`. The target is expected at address `0x10`.
+    pub fn new(path: &str) -> Self {
+        I2cBridge {
+            port: I2cPort::Device(path.to_owned()),
+            address: 0x10,
+            frequency: 100_000,
+        }
+    }
+
+    /// Create a new I2cBridge that bit-bangs GPIO pins. The `pinspec` is
+    /// a comma-delimited pair of pins in the form `SDA,SCL`. This is
+    /// currently only supported on Raspberry Pi.
+    ///
+    /// This function returns an error if the spec cannot be parsed.
+    pub fn new_gpio(pinspec: &str) -> Result<Self, String> {
+        let pins: Vec<&str> = pinspec.split(',').collect();
+        if pins.len() != 2 {
+            return Err(format!(
+                "{} is not a valid pin spec -- must be SDA,SCL (e.g. \"2,3\")",
+                pinspec
+            ));
+        }
+        let parse_pin = |pin: &str| {
+            pin.trim()
+                .parse::<u8>()
+                .map_err(|e| format!("unable to parse pin '{}': {}", pin, e))
+        };
+        Ok(I2cBridge {
+            port: I2cPort::Gpio {
+                sda: parse_pin(pins[0])?,
+                scl: parse_pin(pins[1])?,
+            },
+            address: 0x10,
+            frequency: 100_000,
+        })
+    }
+
+    /// Specify the 7-bit I2C address of the target.
+    pub fn address(&mut self, address: u8) -> &mut I2cBridge {
+        self.address = address;
+        self
+    }
+
+    /// GPIO: Specify the clock frequency in Hz. Adapters opened with
+    /// `new()` run at whatever rate the kernel driver was configured for.
+    pub fn frequency(&mut self, frequency: u32) -> &mut I2cBridge {
+        self.frequency = frequency;
+        self
+    }
+
+    /// Create a `Bridge` struct based on the current configuration.
+    /// This will return an error on platforms that do not support I2C.
+    pub fn create(&self) -> Result<Bridge, BridgeError> {
+        Bridge::new(BridgeConfig::I2cBridge(self.clone()))
+    }
+}
+
+/// A way of performing raw I2C transfers.
+trait I2cTransport {
+    /// Write `write` to the device at `address`. If `read` is not empty,
+    /// follow this with a repeated start and fill `read`. Returns `false`
+    /// if the device did not acknowledge its address.
+    fn transfer(&mut self, address: u8, write: &[u8], read: &mut [u8])
+        -> Result<bool, BridgeError>;
+}
+
+fn open_transport(cfg: &I2cBridge) -> Result<Box<dyn I2cTransport>, BridgeError> {
+    match &cfg.port {
+        #[cfg(target_os = "linux")]
+        I2cPort::Device(path) => Ok(Box::new(linux_i2c::LinuxI2c::open(path)?)),
+        #[cfg(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64")))]
+        I2cPort::Gpio { sda, scl } => Ok(Box::new(raspberry_i2c::GpioI2c::open(
+            *sda,
+            *scl,
+            cfg.frequency,
+        )?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(BridgeError::ProtocolNotSupported),
+    }
+}
+
+#[derive(Clone)]
+pub struct I2cBridgeInner {
+    main_tx: Sender<ConnectThreadRequests>,
+    main_rx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+    mutex: Arc<Mutex<()>>,
+}
+
+enum ConnectThreadRequests {
+    Exit,
+    Connect,
+    Poke(u32 /* addr */, u32 /* val */),
+    Peek(u32 /* addr */),
+}
+
+#[derive(Debug)]
+enum ConnectThreadResponses {
+    Connected(Result<(), BridgeError>),
+    PeekResult(Result<u32, BridgeError>),
+    PokeResult(Result<(), BridgeError>),
+}
+
+impl I2cBridgeInner {
+    pub fn new(cfg: &I2cBridge) -> Result<Self, BridgeError> {
+        // Try to open the port first, just to make sure we can.
+        open_transport(cfg)?;
+
+        let (main_tx, thread_rx) = channel();
+        let cv = Arc::new((Mutex::new(None), Condvar::new()));
+
+        let thr_cv = cv.clone();
+        let thr_cfg = cfg.clone();
+        thread::spawn(move || Self::i2c_connect_thread(thr_cv, thread_rx, thr_cfg));
+
+        Ok(I2cBridgeInner {
+            main_tx,
+            main_rx: cv,
+            mutex: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn i2c_connect_thread(
+        tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+        rx: Receiver<ConnectThreadRequests>,
+        cfg: I2cBridge,
+    ) {
+        use ConnectThreadRequests::*;
+        use ConnectThreadResponses::*;
+        let (response, cvar) = &*tx;
+
+        let mut print_waiting_message = true;
+        loop {
+            match open_transport(&cfg) {
+                Ok(mut transport) => {
+                    info!(
+                        "opened i2c bridge {:?} at address 0x{:02x}",
+                        cfg.port, cfg.address
+                    );
+                    print_waiting_message = true;
+                    let mut keep_going = true;
+                    while keep_going {
+                        match rx.recv() {
+                            Err(_) => {
+                                error!("connection closed");
+                                return;
+                            }
+                            Ok(Exit) => {
+                                debug!("i2c_connect_thread requested exit");
+                                return;
+                            }
+                            Ok(Connect) => {
+                                *response.lock().unwrap() = Some(Connected(Ok(())));
+                                cvar.notify_one();
+                            }
+                            Ok(Peek(addr)) => {
+                                let result = Self::do_peek(&mut *transport, cfg.address, addr);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PeekResult(result));
+                                cvar.notify_one();
+                            }
+                            Ok(Poke(addr, val)) => {
+                                let result = Self::do_poke(&mut *transport, cfg.address, addr, val);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PokeResult(result));
+                                cvar.notify_one();
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    if print_waiting_message {
+                        info!("waiting for i2c device: {}", e);
+                        print_waiting_message = false;
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_millis(500));
+
+            // Respond to any messages in the buffer with NotConnected.  As soon
+            // as the channel is empty, loop back to the start of this function.
+            loop {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("main thread disconnected"),
+                    Ok(m) => {
+                        let reply = match m {
+                            Exit => {
+                                debug!("main thread requested exit");
+                                return;
+                            }
+                            Connect => Connected(Err(BridgeError::NotConnected)),
+                            Peek(_addr) => PeekResult(Err(BridgeError::NotConnected)),
+                            Poke(_addr, _val) => PokeResult(Err(BridgeError::NotConnected)),
+                        };
+                        *response.lock().unwrap() = Some(reply);
+                        cvar.notify_one();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Perform a transfer, retrying for as long as the target is busy.
+    fn do_transfer(
+        transport: &mut dyn I2cTransport,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), BridgeError> {
+        for _ in 0..TIMEOUT_COUNT {
+            if transport.transfer(address, write, read)? {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Err(BridgeError::Timeout)
+    }
+
+    fn do_poke(
+        transport: &mut dyn I2cTransport,
+        address: u8,
+        addr: u32,
+        value: u32,
+    ) -> Result<(), BridgeError> {
+        debug!("poke: writing 0x{:08x} to 0x{:08x}", value, addr);
+        let write_cmd = 0;
+
+        let mut packet = vec![write_cmd];
+        packet.extend_from_slice(&addr.to_be_bytes());
+        packet.extend_from_slice(&value.to_be_bytes());
+        Self::do_transfer(transport, address, &packet, &mut [])
+    }
+
+    fn do_peek(
+        transport: &mut dyn I2cTransport,
+        address: u8,
+        addr: u32,
+    ) -> Result<u32, BridgeError> {
+        let read_cmd = 1;
+
+        let mut packet = vec![read_cmd];
+        packet.extend_from_slice(&addr.to_be_bytes());
+        let mut value = [0u8; 4];
+        Self::do_transfer(transport, address, &packet, &mut value)?;
+
+        let value = u32::from_be_bytes(value);
+        debug!("peek: value 0x{:08x} at addr 0x{:08x}", value, addr);
+        Ok(value)
+    }
+
+    fn request(&self, req: ConnectThreadRequests) -> ConnectThreadResponses {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(req)
+            .expect("Unable to send request to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        _mtx.take().unwrap()
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
+    /// Block until the I2C port has been opened.
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        loop {
+            if let ConnectThreadResponses::Connected(Ok(())) =
+                self.request(ConnectThreadRequests::Connect)
+            {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        match self.request(ConnectThreadRequests::Poke(addr, value)) {
+            ConnectThreadResponses::PokeResult(r) => r,
+            e => {
+                error!("unexpected bridge poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        match self.request(ConnectThreadRequests::Peek(addr)) {
+            ConnectThreadResponses::PeekResult(r) => r,
+            e => {
+                error!("unexpected bridge peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+}
+
+impl Drop for I2cBridgeInner {
+    fn drop(&mut self) {
+        // If this is the last reference to the bridge, tell the control thread
+        // to exit.
+        if Arc::strong_count(&self.mutex) + Arc::weak_count(&self.mutex) <= 1 {
+            let (lock, _cvar) = &*self.main_rx;
+            let mut _mtx = lock.lock().unwrap();
+            self.main_tx.send(ConnectThreadRequests::Exit).ok();
+        }
+    }
+}
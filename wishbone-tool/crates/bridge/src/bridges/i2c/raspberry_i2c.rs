@@ -0,0 +1,164 @@
+extern crate rppal;
+extern crate spin_sleep;
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use rppal::gpio::Mode::{Input, Output};
+use rppal::gpio::{Gpio, IoPin, PullUpDown};
+
+use super::I2cTransport;
+use crate::BridgeError;
+
+/// How long the target may hold SCL low to stretch the clock
+const STRETCH_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// I2C bit-banged on a pair of GPIO pins. Both lines are open-drain, so
+/// a "high" is produced by turning the pin into an input and letting the
+/// pull-up do its job.
+pub struct GpioI2c {
+    sda: IoPin,
+    scl: IoPin,
+    delay: Duration,
+}
+
+impl GpioI2c {
+    pub fn open(sda: u8, scl: u8, frequency: u32) -> Result<GpioI2c, BridgeError> {
+        let gpio = Gpio::new().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let mut sda = gpio
+            .get(sda)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            .into_io(Input);
+        let mut scl = gpio
+            .get(scl)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            .into_io(Input);
+        sda.set_pullupdown(PullUpDown::PullUp);
+        scl.set_pullupdown(PullUpDown::PullUp);
+        Ok(GpioI2c {
+            sda,
+            scl,
+            delay: Duration::from_nanos(500_000_000 / frequency.max(1) as u64),
+        })
+    }
+
+    fn release(pin: &mut IoPin) {
+        pin.set_mode(Input);
+    }
+
+    fn drive_low(pin: &mut IoPin) {
+        pin.set_mode(Output);
+        pin.set_low();
+    }
+
+    /// Release SCL and wait for it to actually go high, in case the
+    /// target is stretching the clock.
+    fn scl_high(&mut self) -> Result<(), BridgeError> {
+        Self::release(&mut self.scl);
+        let start = Instant::now();
+        while self.scl.is_low() {
+            if start.elapsed() > STRETCH_TIMEOUT {
+                return Err(BridgeError::Timeout);
+            }
+        }
+        spin_sleep::sleep(self.delay);
+        Ok(())
+    }
+
+    fn scl_low(&mut self) {
+        Self::drive_low(&mut self.scl);
+        spin_sleep::sleep(self.delay);
+    }
+
+    fn start(&mut self) -> Result<(), BridgeError> {
+        Self::release(&mut self.sda);
+        self.scl_high()?;
+        Self::drive_low(&mut self.sda);
+        spin_sleep::sleep(self.delay);
+        self.scl_low();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), BridgeError> {
+        Self::drive_low(&mut self.sda);
+        spin_sleep::sleep(self.delay);
+        self.scl_high()?;
+        Self::release(&mut self.sda);
+        spin_sleep::sleep(self.delay);
+        Ok(())
+    }
+
+    /// Write a byte, and return whether the target acknowledged it.
+    fn write_byte(&mut self, b: u8) -> Result<bool, BridgeError> {
+        for i in &[7, 6, 5, 4, 3, 2, 1, 0] {
+            if (b & (1 << i)) == 0 {
+                Self::drive_low(&mut self.sda);
+            } else {
+                Self::release(&mut self.sda);
+            }
+            self.scl_high()?;
+            self.scl_low();
+        }
+        Self::release(&mut self.sda);
+        self.scl_high()?;
+        let ack = self.sda.is_low();
+        self.scl_low();
+        Ok(ack)
+    }
+
+    fn read_byte(&mut self, ack: bool) -> Result<u8, BridgeError> {
+        let mut val = 0;
+        Self::release(&mut self.sda);
+        for i in &[7, 6, 5, 4, 3, 2, 1, 0] {
+            self.scl_high()?;
+            if self.sda.is_high() {
+                val |= 1 << i;
+            }
+            self.scl_low();
+        }
+        if ack {
+            Self::drive_low(&mut self.sda);
+        }
+        self.scl_high()?;
+        self.scl_low();
+        Self::release(&mut self.sda);
+        Ok(val)
+    }
+}
+
+impl I2cTransport for GpioI2c {
+    fn transfer(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<bool, BridgeError> {
+        self.start()?;
+        if !self.write_byte(address << 1)? {
+            self.stop()?;
+            return Ok(false);
+        }
+        for b in write {
+            if !self.write_byte(*b)? {
+                self.stop()?;
+                return Err(BridgeError::WrongResponse);
+            }
+        }
+
+        if !read.is_empty() {
+            // Repeated start
+            self.start()?;
+            if !self.write_byte((address << 1) | 1)? {
+                self.stop()?;
+                return Ok(false);
+            }
+            let last = read.len() - 1;
+            for (i, b) in read.iter_mut().enumerate() {
+                *b = self.read_byte(i != last)?;
+            }
+        }
+
+        self.stop()?;
+        Ok(true)
+    }
+}
@@ -1,7 +1,17 @@
+#[cfg(feature = "can")]
+pub mod can;
+#[cfg(any(feature = "ethernet", feature = "sim"))]
+mod etherbone;
 #[cfg(feature = "ethernet")]
 pub mod ethernet;
+#[cfg(feature = "i2c")]
+pub mod i2c;
 #[cfg(feature = "pcie")]
 pub mod pcie;
+#[cfg(feature = "replay")]
+pub mod replay;
+#[cfg(feature = "sim")]
+pub mod sim;
 #[cfg(feature = "spi")]
 pub mod spi;
 #[cfg(feature = "uart")]
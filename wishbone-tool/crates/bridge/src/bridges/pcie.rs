@@ -6,10 +6,13 @@ use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use log::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::{Bridge, BridgeConfig, BridgeError};
 
+/// Flag in a sysfs `resource` entry indicating a memory (rather than I/O) BAR
+const IORESOURCE_MEM: u64 = 0x200;
+
 /// Describes a connection to a target via PCI Express.
 #[derive(Clone)]
 pub struct PCIeBridge {
@@ -28,6 +31,13 @@ pub struct PCIeBridge {
 /// use wishbone_bridge::PCIeBridge;
 /// let bridge = PCIeBridge::new("/sys/devices/pci0001:00/0001:00:07.0/resource0").unwrap().create().unwrap();
 /// ```
+///
+/// Alternately, let the bridge find the BAR itself:
+///
+/// ```no_run
+/// use wishbone_bridge::PCIeBridge;
+/// let bridge = PCIeBridge::from_bdf("0000:03:00.0").unwrap().create().unwrap();
+/// ```
 impl PCIeBridge {
     /// Create a new `PCIeBridge` struct. The file must exist. This does
     /// not check to ensure you have access permissions.
@@ -40,6 +50,54 @@ impl PCIeBridge {
         })
     }
 
+    /// Create a new `PCIeBridge` for the device at the given PCI address,
+    /// such as `0000:03:00.0`. If the domain is left off, `0000` is assumed.
+    /// The device is looked up in sysfs, and its first memory BAR is used,
+    /// since that's where LitePCIe exposes the Wishbone bus.
+    pub fn from_bdf(bdf: &str) -> Result<PCIeBridge, BridgeError> {
+        let bdf = if bdf.matches(':').count() == 1 {
+            format!("0000:{}", bdf)
+        } else {
+            bdf.to_owned()
+        };
+        let device = Path::new("/sys/bus/pci/devices").join(&bdf);
+        if !device.exists() {
+            error!("no pci device found at {}", bdf);
+            return Err(BridgeError::InvalidAddress);
+        }
+
+        if let Ok(enable) = std::fs::read_to_string(device.join("enable")) {
+            if enable.trim() == "0" {
+                warn!(
+                    "pci device {} is not enabled, try writing 1 to {}",
+                    bdf,
+                    device.join("enable").display()
+                );
+            }
+        }
+
+        // Each line of `resource` is the start, end, and flags of a BAR.
+        let resources = std::fs::read_to_string(device.join("resource"))?;
+        for (bar, line) in resources.lines().take(6).enumerate() {
+            let fields: Vec<u64> = line
+                .split_whitespace()
+                .filter_map(|f| u64::from_str_radix(f.trim_start_matches("0x"), 16).ok())
+                .collect();
+            if fields.len() < 3 {
+                continue;
+            }
+            let (start, end, flags) = (fields[0], fields[1], fields[2]);
+            if (flags & IORESOURCE_MEM) == 0 || end <= start {
+                continue;
+            }
+            let path = device.join(format!("resource{}", bar));
+            debug!("using BAR{} of {} ({} bytes)", bar, bdf, end - start + 1);
+            return PCIeBridge::new(path);
+        }
+        error!("pci device {} has no memory BARs", bdf);
+        Err(BridgeError::InvalidAddress)
+    }
+
     /// Create a new `Bridge` with the given file. This will produce
     /// an error if the PCIe device could not be opened.
     pub fn create(&self) -> Result<Bridge, BridgeError> {
@@ -68,6 +126,8 @@ enum ConnectThreadRequests {
     Exit,
     Poke(u32 /* addr */, u32 /* val */),
     Peek(u32 /* addr */),
+    BurstRead(u32 /* addr */, u32 /* len */),
+    BurstWrite(u32 /* addr */, Vec<u8> /* write data */),
 }
 
 #[derive(Debug)]
@@ -76,6 +136,8 @@ enum ConnectThreadResponses {
     OpenedDevice,
     PeekResult(Result<u32, BridgeError>),
     PokeResult(Result<(), BridgeError>),
+    BurstReadResult(Result<Vec<u8>, BridgeError>),
+    BurstWriteResult(Result<(), BridgeError>),
 }
 
 fn mmap_mut_path(path: &Path) -> MmapMut {
@@ -164,7 +226,7 @@ impl PCIeBridgeInner {
                             let result = Self::do_peek_32(&mut mem, addr);
                             if let Err(err) = &result {
                                 result_error = format!("peek {:?} @ {:08x}", err, addr);
-                                keep_going = false;
+                                keep_going = Self::is_out_of_range(err);
                             }
                             *response.lock().unwrap() =
                                 Some(ConnectThreadResponses::PeekResult(result));
@@ -174,12 +236,32 @@ impl PCIeBridgeInner {
                             let result = Self::do_poke_32(&mut mem, addr, val);
                             if let Err(err) = &result {
                                 result_error = format!("poke {:?} @ {:08x}", err, addr);
-                                keep_going = false;
+                                keep_going = Self::is_out_of_range(err);
                             }
                             *response.lock().unwrap() =
                                 Some(ConnectThreadResponses::PokeResult(result));
                             cvar.notify_one();
                         }
+                        ConnectThreadRequests::BurstRead(addr, len) => {
+                            let result = Self::do_burst_read(&mut mem, addr, len);
+                            if let Err(err) = &result {
+                                result_error = format!("burst read {:?} @ {:08x}", err, addr);
+                                keep_going = Self::is_out_of_range(err);
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstReadResult(result));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstWrite(addr, data) => {
+                            let result = Self::do_burst_write(&mut mem, addr, &data);
+                            if let Err(err) = &result {
+                                result_error = format!("burst write {:?} @ {:08x}", err, addr);
+                                keep_going = Self::is_out_of_range(err);
+                            }
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstWriteResult(result));
+                            cvar.notify_one();
+                        }
                     },
                 }
             }
@@ -211,6 +293,20 @@ impl PCIeBridgeInner {
                             ));
                             cvar.notify_one();
                         }
+                        ConnectThreadRequests::BurstRead(_addr, _len) => {
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstReadResult(Err(
+                                    BridgeError::NotConnected,
+                                )));
+                            cvar.notify_one();
+                        }
+                        ConnectThreadRequests::BurstWrite(_addr, _data) => {
+                            *response.lock().unwrap() =
+                                Some(ConnectThreadResponses::BurstWriteResult(Err(
+                                    BridgeError::NotConnected,
+                                )));
+                            cvar.notify_one();
+                        }
                         ConnectThreadRequests::StartPolling(p) => {
                             path = p;
                         }
@@ -229,9 +325,10 @@ impl PCIeBridgeInner {
             .send(ConnectThreadRequests::StartPolling(self.path.clone()))
             .unwrap();
         loop {
+            // The poll thread announces `OpenedDevice` as soon as it maps the
+            // BAR, which may be before we get here, so don't clear it first.
             let &(ref lock, ref cvar) = &*self.main_rx;
             let mut _mtx = lock.lock().unwrap();
-            *_mtx = None;
             while _mtx.is_none() {
                 _mtx = cvar.wait(_mtx).unwrap();
             }
@@ -241,8 +338,29 @@ impl PCIeBridgeInner {
         }
     }
 
+    /// An access outside of the BAR fails on its own, and doesn't mean the
+    /// mapping itself has gone bad.
+    fn is_out_of_range(err: &BridgeError) -> bool {
+        matches!(err, BridgeError::InvalidAddress)
+    }
+
+    /// Ensure an access of `len` bytes at `addr` lies entirely within the BAR.
+    fn check_bounds(mem: &MmapMut, addr: u32, len: usize) -> Result<(), BridgeError> {
+        if addr as usize + len > mem.len() {
+            error!(
+                "address {:08x}+{} is outside of the {} byte PCIe BAR",
+                addr,
+                len,
+                mem.len()
+            );
+            return Err(BridgeError::InvalidAddress);
+        }
+        Ok(())
+    }
+
     fn do_poke_32(mem: &mut MmapMut, addr: u32, value: u32) -> Result<(), BridgeError> {
         debug!("POKE @ {:08x} -> {:08x}", addr, value);
+        Self::check_bounds(mem, addr, 4)?;
         #[allow(clippy::cast_ptr_alignment)]
         let memory_range = mem.as_mut_ptr() as *mut u32;
         unsafe { memory_range.add(addr as usize / 4).write_volatile(value) };
@@ -250,6 +368,7 @@ impl PCIeBridgeInner {
     }
 
     fn do_peek_32(mem: &mut MmapMut, addr: u32) -> Result<u32, BridgeError> {
+        Self::check_bounds(mem, addr, 4)?;
         #[allow(clippy::cast_ptr_alignment)]
         let memory_range = mem.as_mut_ptr() as *mut u32;
         let val = unsafe { memory_range.add(addr as usize / 4).read_volatile() };
@@ -257,6 +376,58 @@ impl PCIeBridgeInner {
         Ok(val)
     }
 
+    /// Copy `len` bytes out of the BAR. The bus only supports word accesses,
+    /// so whole words are read and any unaligned head or tail is trimmed.
+    fn do_burst_read(mem: &mut MmapMut, addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+        debug!("BURST READ @ {:08x} ({} bytes)", addr, len);
+        Self::check_bounds(mem, addr, len as usize)?;
+        let start = addr as usize & !3;
+        let end = (addr as usize + len as usize + 3) & !3;
+        // The final word may poke past the end of a BAR that isn't a
+        // multiple of 4 bytes long, so make sure it's still mapped.
+        Self::check_bounds(mem, start as u32, end - start)?;
+
+        #[allow(clippy::cast_ptr_alignment)]
+        let memory_range = mem.as_mut_ptr() as *mut u32;
+        let mut data = Vec::with_capacity(end - start);
+        for word in (start / 4)..(end / 4) {
+            let val = unsafe { memory_range.add(word).read_volatile() };
+            data.extend_from_slice(&val.to_ne_bytes());
+        }
+        let head = addr as usize - start;
+        Ok(data[head..head + len as usize].to_vec())
+    }
+
+    /// Copy `data` into the BAR using word writes. Partial words at either
+    /// end are read first so the neighbouring bytes are preserved.
+    fn do_burst_write(mem: &mut MmapMut, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        debug!("BURST WRITE @ {:08x} ({} bytes)", addr, data.len());
+        Self::check_bounds(mem, addr, data.len())?;
+        let start = addr as usize & !3;
+        let end = (addr as usize + data.len() + 3) & !3;
+        Self::check_bounds(mem, start as u32, end - start)?;
+
+        #[allow(clippy::cast_ptr_alignment)]
+        let memory_range = mem.as_mut_ptr() as *mut u32;
+        for word in (start / 4)..(end / 4) {
+            let word_addr = word * 4;
+            let mut bytes = [0u8; 4];
+            let lo = word_addr.max(addr as usize);
+            let hi = (word_addr + 4).min(addr as usize + data.len());
+            if hi - lo != 4 {
+                bytes = unsafe { memory_range.add(word).read_volatile() }.to_ne_bytes();
+            }
+            bytes[lo - word_addr..hi - word_addr]
+                .copy_from_slice(&data[lo - addr as usize..hi - addr as usize]);
+            unsafe {
+                memory_range
+                    .add(word)
+                    .write_volatile(u32::from_ne_bytes(bytes))
+            };
+        }
+        Ok(())
+    }
+
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
         let &(ref lock, ref cvar) = &*self.main_rx;
         let mut _mtx = lock.lock().unwrap();
@@ -294,6 +465,44 @@ impl PCIeBridgeInner {
             }
         }
     }
+
+    pub fn burst_read(&self, addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstRead(addr, len))
+            .expect("Unable to send burst read to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstReadResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst read response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::BurstWrite(addr, data.to_vec()))
+            .expect("Unable to send burst write to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::BurstWriteResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge burst write response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
 }
 
 impl Drop for PCIeBridgeInner {
@@ -0,0 +1,244 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, warn};
+
+use crate::record::{read_recording, Outcome, Recorded};
+use crate::stats::Operation;
+use crate::{Bridge, BridgeConfig, BridgeError};
+
+#[derive(Clone)]
+/// A builder for a bridge that plays back a recording made with
+/// `Bridge::record_to()` instead of talking to hardware. This lets the GDB
+/// server, the flash programmer, and other tools be tested in CI against a
+/// session captured from a real board.
+///
+/// By default the replay is strict: every operation must be the same as
+/// the next one in the recording, with the same address and, for writes,
+/// the same data. Reads return whatever was read when the recording was
+/// made. Anything else fails with `BridgeError::ReplayDiverged`, so a test
+/// notices as soon as the code under test behaves differently.
+///
+/// With `strict(false)`, operations may come in any order. Reads of an
+/// address return the values recorded for it in turn, repeating the last
+/// one once they run out, and writes are accepted without being checked.
+///
+/// ```no_run
+/// use wishbone_bridge::ReplayBridge;
+/// let bridge = ReplayBridge::new("session.csv").create().unwrap();
+/// assert_eq!(bridge.peek(0xe000_1800).unwrap(), 1);
+/// ```
+pub struct ReplayBridge {
+    path: PathBuf,
+    strict: bool,
+}
+
+impl ReplayBridge {
+    /// Play back the recording at `path`. It isn't read until the bridge
+    /// is created.
+    pub fn new<P: AsRef<Path>>(path: P) -> ReplayBridge {
+        ReplayBridge {
+            path: path.as_ref().to_path_buf(),
+            strict: true,
+        }
+    }
+
+    /// Whether operations must happen in exactly the order they were
+    /// recorded. Defaults to `true`.
+    pub fn strict(&mut self, strict: bool) -> &mut ReplayBridge {
+        self.strict = strict;
+        self
+    }
+
+    /// Read the recording and create a `Bridge` that plays it back.
+    pub fn create(&self) -> Result<Bridge, BridgeError> {
+        Bridge::new(BridgeConfig::ReplayBridge(self.clone()))
+    }
+}
+
+#[derive(Clone)]
+pub struct ReplayBridgeInner {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+enum ReplayState {
+    /// What's left of the recording, in order
+    Strict(VecDeque<Recorded>),
+
+    /// Reads from anywhere in the recording, served in any order
+    AnyOrder(RecordedReads),
+}
+
+/// What was read by each kind of read, at each address and length, and how
+/// many of those reads have been replayed
+type RecordedReads = HashMap<(Operation, u32, usize), (Vec<Vec<u8>>, usize)>;
+
+impl ReplayBridgeInner {
+    pub fn new(cfg: &ReplayBridge) -> Result<Self, BridgeError> {
+        let recording = read_recording(&cfg.path)?;
+        debug!(
+            "replaying {} operations from {}",
+            recording.len(),
+            cfg.path.display()
+        );
+
+        // Failed attempts that were retried don't change the outcome, and
+        // the bridge retries on its own, so there's no need to replay them.
+        let recording = recording
+            .into_iter()
+            .filter(|r| r.outcome != Outcome::Retry);
+        let state = if cfg.strict {
+            ReplayState::Strict(recording.collect())
+        } else {
+            let mut reads = HashMap::new();
+            for r in recording {
+                if r.outcome == Outcome::Ok
+                    && (r.op == Operation::Peek || r.op == Operation::BurstRead)
+                {
+                    reads
+                        .entry((r.op, r.addr, r.data.len()))
+                        .or_insert_with(|| (vec![], 0))
+                        .0
+                        .push(r.data);
+                }
+            }
+            ReplayState::AnyOrder(reads)
+        };
+        Ok(ReplayBridgeInner {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Replay one operation. `data` is what's being written, or how many
+    /// bytes are wanted for a read.
+    fn replay(&self, op: Operation, addr: u32, data: Data) -> Result<Vec<u8>, BridgeError> {
+        let mut state = self.state.lock().unwrap();
+        match &mut *state {
+            ReplayState::Strict(recording) => {
+                let next = recording.pop_front().ok_or_else(|| {
+                    BridgeError::ReplayDiverged(format!(
+                        "{} of 0x{:08x} is past the end of the recording",
+                        op.name(),
+                        addr
+                    ))
+                })?;
+                if next.op != op || next.addr != addr || !data.matches(&next) {
+                    let diverged = BridgeError::ReplayDiverged(format!(
+                        "{} of 0x{:08x} ({}) when the recording has {} of 0x{:08x} ({} bytes)",
+                        op.name(),
+                        addr,
+                        data,
+                        next.op.name(),
+                        next.addr,
+                        next.data.len()
+                    ));
+                    // Leave it there, in case the caller wants to carry on
+                    // with what the recording expected.
+                    recording.push_front(next);
+                    return Err(diverged);
+                }
+                match next.outcome {
+                    // Errors that weren't retried were always ones that
+                    // retrying wouldn't fix.
+                    Outcome::Error => Err(BridgeError::InvalidAddress),
+                    _ => Ok(next.data),
+                }
+            }
+            ReplayState::AnyOrder(reads) => {
+                let len = match data {
+                    Data::Write(_) => return Ok(vec![]),
+                    Data::Read(len) => len,
+                };
+                match reads.get_mut(&(op, addr, len)) {
+                    Some((values, served)) => {
+                        let value = values[(*served).min(values.len() - 1)].clone();
+                        *served += 1;
+                        Ok(value)
+                    }
+                    None => Err(BridgeError::ReplayDiverged(format!(
+                        "the recording has no {} of {} bytes at 0x{:08x}",
+                        op.name(),
+                        len,
+                        addr
+                    ))),
+                }
+            }
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let data = self.replay(Operation::Peek, addr, Data::Read(4))?;
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.replay(
+            Operation::Poke,
+            addr,
+            Data::Write(value.to_le_bytes().to_vec()),
+        )?;
+        Ok(())
+    }
+
+    pub fn burst_read(&self, addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+        self.replay(Operation::BurstRead, addr, Data::Read(len as usize))
+    }
+
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        self.replay(Operation::BurstWrite, addr, Data::Write(data.to_vec()))?;
+        Ok(())
+    }
+}
+
+impl Drop for ReplayBridgeInner {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.state) != 1 {
+            return;
+        }
+        if let Ok(state) = self.state.lock() {
+            if let ReplayState::Strict(recording) = &*state {
+                if let Some(next) = recording.front() {
+                    warn!(
+                        "replay finished with {} operations left, starting with {} of 0x{:08x}",
+                        recording.len(),
+                        next.op.name(),
+                        next.addr
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The data side of an operation being replayed
+enum Data {
+    /// Bytes being written
+    Write(Vec<u8>),
+
+    /// Number of bytes to read
+    Read(usize),
+}
+
+impl Data {
+    fn matches(&self, recorded: &Recorded) -> bool {
+        match self {
+            Data::Write(data) => *data == recorded.data,
+            // A read that failed recorded no data, so only the address counts.
+            Data::Read(len) => recorded.outcome == Outcome::Error || *len == recorded.data.len(),
+        }
+    }
+}
+
+impl std::fmt::Display for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Data::Write(data) => write!(f, "{} bytes", data.len()),
+            Data::Read(len) => write!(f, "{} bytes", len),
+        }
+    }
+}
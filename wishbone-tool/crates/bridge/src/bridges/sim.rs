@@ -0,0 +1,411 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, info};
+
+use super::etherbone::{stream_burst_read, stream_burst_write, stream_read, stream_write};
+use crate::{Bridge, BridgeConfig, BridgeError};
+
+/// How long to wait for the simulation to answer a request. Simulations
+/// run many times slower than real hardware, so this is generous.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where to find the simulation.
+#[derive(Clone, Debug)]
+enum SimSocket {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl fmt::Display for SimSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(unix)]
+            SimSocket::Unix(path) => write!(f, "{}", path.display()),
+            SimSocket::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A builder to create a connection to a simulated design, such as a
+/// Verilator model built by `litex_sim`. This lets firmware and host tools
+/// be tested in CI without any hardware attached.
+///
+/// The simulation is expected to accept Etherbone packets over a stream,
+/// one record per packet, in the same way as `litex_server` and
+/// `wishbone-tool --server wishbone` do over TCP. The stream may be a Unix
+/// domain socket, which avoids needing a network stack inside the
+/// simulation, or a TCP connection.
+///
+/// Unlike most bridges, `connect()` waits for the simulation to start
+/// listening, so the simulator and `wishbone-tool` may be started in
+/// either order.
+///
+/// ```no_run
+/// use wishbone_bridge::SimBridge;
+/// let bridge = SimBridge::new("/tmp/litex_sim.sock").create().unwrap();
+/// bridge.connect().unwrap();
+/// ```
+pub struct SimBridge {
+    socket: SimSocket,
+    timeout: Duration,
+}
+
+impl SimBridge {
+    /// Connect to a simulation listening on the Unix domain socket at `path`.
+    #[cfg(unix)]
+    pub fn new<P: AsRef<Path>>(path: P) -> SimBridge {
+        SimBridge {
+            socket: SimSocket::Unix(path.as_ref().to_path_buf()),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Connect to a simulation listening on a TCP port, for example one
+    /// exposed by `litex_server`.
+    pub fn tcp<A: std::net::ToSocketAddrs>(addr: A) -> Result<SimBridge, BridgeError> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or(BridgeError::InvalidAddress)?;
+        Ok(SimBridge {
+            socket: SimSocket::Tcp(addr),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Set how long to wait for the simulation to answer each request before
+    /// giving up and reconnecting. Defaults to 30 seconds.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut SimBridge {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Create a new `Bridge` based on the current configuration.
+    pub fn create(&self) -> Result<Bridge, BridgeError> {
+        Bridge::new(BridgeConfig::SimBridge(self.clone()))
+    }
+}
+
+enum SimConnection {
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl SimConnection {
+    fn open(cfg: &SimBridge) -> io::Result<SimConnection> {
+        let connection = match &cfg.socket {
+            #[cfg(unix)]
+            SimSocket::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(cfg.timeout))?;
+                stream.set_write_timeout(Some(cfg.timeout))?;
+                SimConnection::Unix(stream)
+            }
+            SimSocket::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_read_timeout(Some(cfg.timeout))?;
+                stream.set_write_timeout(Some(cfg.timeout))?;
+                // Every request waits for its answer, so don't hold packets back.
+                stream.set_nodelay(true)?;
+                SimConnection::Tcp(stream)
+            }
+        };
+        Ok(connection)
+    }
+}
+
+impl Read for SimConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            SimConnection::Unix(s) => s.read(buf),
+            SimConnection::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for SimConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            SimConnection::Unix(s) => s.write(buf),
+            SimConnection::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            SimConnection::Unix(s) => s.flush(),
+            SimConnection::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+pub struct SimBridgeInner {
+    main_tx: Sender<ConnectThreadRequests>,
+    main_rx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+    mutex: Arc<Mutex<()>>,
+    poll_thread: Option<thread::JoinHandle<()>>,
+}
+
+enum ConnectThreadRequests {
+    Exit,
+    Connect,
+    Poke(u32 /* addr */, u32 /* val */),
+    Peek(u32 /* addr */),
+    BurstRead(u32 /* addr */, u32 /* len */),
+    BurstWrite(u32 /* addr */, Vec<u8> /* write data */),
+}
+
+#[derive(Debug)]
+enum ConnectThreadResponses {
+    Exiting,
+    Connected,
+    PeekResult(Result<u32, BridgeError>),
+    PokeResult(Result<(), BridgeError>),
+    BurstReadResult(Result<Vec<u8>, BridgeError>),
+    BurstWriteResult(Result<(), BridgeError>),
+}
+
+impl Clone for SimBridgeInner {
+    fn clone(&self) -> Self {
+        SimBridgeInner {
+            main_tx: self.main_tx.clone(),
+            main_rx: self.main_rx.clone(),
+            mutex: self.mutex.clone(),
+            poll_thread: None,
+        }
+    }
+}
+
+impl SimBridgeInner {
+    pub fn new(cfg: &SimBridge) -> Result<Self, BridgeError> {
+        let (main_tx, thread_rx) = channel();
+        let cv = Arc::new((Mutex::new(None), Condvar::new()));
+
+        let thr_cv = cv.clone();
+        let thr_cfg = cfg.clone();
+        let poll_thread = Some(thread::spawn(move || {
+            Self::sim_thread(thr_cv, thread_rx, thr_cfg)
+        }));
+
+        Ok(SimBridgeInner {
+            main_tx,
+            main_rx: cv,
+            mutex: Arc::new(Mutex::new(())),
+            poll_thread,
+        })
+    }
+
+    fn sim_thread(
+        tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+        rx: Receiver<ConnectThreadRequests>,
+        cfg: SimBridge,
+    ) {
+        use ConnectThreadRequests::*;
+        use ConnectThreadResponses::*;
+        let (response, cvar) = &*tx;
+
+        let mut print_waiting_message = true;
+        let mut connect_pending = false;
+        loop {
+            match SimConnection::open(&cfg) {
+                Ok(mut connection) => {
+                    info!("connected to simulation at {}", cfg.socket);
+                    print_waiting_message = true;
+                    if connect_pending {
+                        connect_pending = false;
+                        *response.lock().unwrap() = Some(Connected);
+                        cvar.notify_one();
+                    }
+
+                    let mut keep_going = true;
+                    while keep_going {
+                        let reply = match rx.recv() {
+                            Err(_) => {
+                                error!("connection closed");
+                                return;
+                            }
+                            Ok(Exit) => {
+                                debug!("sim_thread requested exit");
+                                *response.lock().unwrap() = Some(Exiting);
+                                cvar.notify_one();
+                                return;
+                            }
+                            Ok(Connect) => Connected,
+                            Ok(Peek(addr)) => PeekResult(
+                                stream_read(&mut connection, addr, 1).map(|values| values[0]),
+                            ),
+                            Ok(Poke(addr, val)) => {
+                                PokeResult(stream_write(&mut connection, addr, &[val], 0x0f))
+                            }
+                            Ok(BurstRead(addr, len)) => {
+                                BurstReadResult(stream_burst_read(&mut connection, addr, len))
+                            }
+                            Ok(BurstWrite(addr, data)) => {
+                                BurstWriteResult(stream_burst_write(&mut connection, addr, &data))
+                            }
+                        };
+                        // Invalid addresses are refused before anything is
+                        // sent, so the stream is still in sync afterwards.
+                        if let PeekResult(Err(e))
+                        | PokeResult(Err(e))
+                        | BurstReadResult(Err(e))
+                        | BurstWriteResult(Err(e)) = &reply
+                        {
+                            if !matches!(e, BridgeError::InvalidAddress) {
+                                error!("simulation connection was lost: {}", e);
+                                keep_going = false;
+                            }
+                        }
+                        *response.lock().unwrap() = Some(reply);
+                        cvar.notify_one();
+                    }
+                }
+                Err(e) => {
+                    if print_waiting_message {
+                        info!("waiting for simulation at {}: {}", cfg.socket, e);
+                        print_waiting_message = false;
+                    }
+                }
+            }
+
+            thread::park_timeout(Duration::from_millis(500));
+
+            // Respond to any messages in the buffer with NotConnected.  As soon
+            // as the channel is empty, loop back to the start of this function.
+            // Requests to connect are answered once the simulation appears.
+            loop {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("main thread disconnected"),
+                    Ok(m) => {
+                        let reply = match m {
+                            Exit => {
+                                debug!("main thread requested exit");
+                                Exiting
+                            }
+                            Connect => {
+                                connect_pending = true;
+                                continue;
+                            }
+                            Peek(_addr) => PeekResult(Err(BridgeError::NotConnected)),
+                            Poke(_addr, _val) => PokeResult(Err(BridgeError::NotConnected)),
+                            BurstRead(_addr, _len) => {
+                                BurstReadResult(Err(BridgeError::NotConnected))
+                            }
+                            BurstWrite(_addr, _data) => {
+                                BurstWriteResult(Err(BridgeError::NotConnected))
+                            }
+                        };
+                        let exiting = matches!(reply, Exiting);
+                        *response.lock().unwrap() = Some(reply);
+                        cvar.notify_one();
+                        if exiting {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
+    /// Send a request to the connection thread and wait for its answer.
+    fn request(&self, request: ConnectThreadRequests) -> Option<ConnectThreadResponses> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(request)
+            .expect("Unable to send request to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        _mtx.take()
+    }
+
+    /// Wait until the simulation is listening.
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        match self.request(ConnectThreadRequests::Connect) {
+            Some(ConnectThreadResponses::Connected) => Ok(()),
+            e => {
+                error!("unexpected bridge connect response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        match self.request(ConnectThreadRequests::Poke(addr, value)) {
+            Some(ConnectThreadResponses::PokeResult(r)) => r,
+            e => {
+                error!("unexpected bridge poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        match self.request(ConnectThreadRequests::Peek(addr)) {
+            Some(ConnectThreadResponses::PeekResult(r)) => r,
+            e => {
+                error!("unexpected bridge peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn burst_read(&self, addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+        match self.request(ConnectThreadRequests::BurstRead(addr, len)) {
+            Some(ConnectThreadResponses::BurstReadResult(r)) => r,
+            e => {
+                error!("unexpected bridge burst read response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        match self.request(ConnectThreadRequests::BurstWrite(addr, data.to_vec())) {
+            Some(ConnectThreadResponses::BurstWriteResult(r)) => r,
+            e => {
+                error!("unexpected bridge burst write response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+}
+
+impl Drop for SimBridgeInner {
+    fn drop(&mut self) {
+        // If this is the last reference to the bridge, tell the control thread
+        // to exit.
+        if Arc::strong_count(&self.mutex) + Arc::weak_count(&self.mutex) <= 1 {
+            match self.request(ConnectThreadRequests::Exit) {
+                Some(ConnectThreadResponses::Exiting) => (),
+                e => error!("unexpected bridge exit response: {:?}", e),
+            }
+            if let Some(pt) = self.poll_thread.take() {
+                pt.join().expect("Unable to join polling thread");
+            }
+        }
+    }
+}
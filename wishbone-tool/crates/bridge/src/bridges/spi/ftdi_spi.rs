@@ -0,0 +1,477 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, info};
+
+use super::FtdiSpi;
+use crate::BridgeError;
+
+/// How many times to poll for a response before giving up. Each poll is a
+/// USB round trip, so this is much smaller than the bit-banged version.
+const TIMEOUT_COUNT: u32 = 1000;
+
+const USB_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Vendor-specific control requests understood by FTDI chips
+const FTDI_REQUEST_OUT: u8 = 0x40;
+const SIO_RESET: u8 = 0x00;
+const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+const SIO_SET_BITMODE: u8 = 0x0b;
+const SIO_RESET_SIO: u16 = 0;
+const SIO_RESET_PURGE_RX: u16 = 1;
+const SIO_RESET_PURGE_TX: u16 = 2;
+const BITMODE_RESET: u16 = 0x0000;
+const BITMODE_MPSSE: u16 = 0x0200;
+
+// MPSSE opcodes. Data is clocked out on the falling edge and sampled on
+// the rising edge, which is SPI mode 0.
+const MPSSE_WRITE_BYTES: u8 = 0x11;
+const MPSSE_READ_BYTES: u8 = 0x20;
+const MPSSE_SET_LOW_BYTE: u8 = 0x80;
+const MPSSE_LOOPBACK_OFF: u8 = 0x85;
+const MPSSE_SET_DIVISOR: u8 = 0x86;
+const MPSSE_SEND_IMMEDIATE: u8 = 0x87;
+const MPSSE_DISABLE_DIV5: u8 = 0x8a;
+const MPSSE_DISABLE_3PHASE: u8 = 0x8d;
+const MPSSE_DISABLE_ADAPTIVE: u8 = 0x97;
+const MPSSE_BAD_COMMAND: u8 = 0xaa;
+
+// Pin assignments on the low byte of the MPSSE port
+const PIN_SCK: u8 = 1 << 0;
+const PIN_DO: u8 = 1 << 1;
+const PIN_CS: u8 = 1 << 3;
+
+/// Base clock of the MPSSE engine with the divide-by-5 prescaler disabled
+const MPSSE_BASE_CLOCK: u32 = 60_000_000;
+
+#[derive(Clone)]
+pub struct FtdiSpiBridgeInner {
+    main_tx: Sender<ConnectThreadRequests>,
+    main_rx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+    mutex: Arc<Mutex<()>>,
+}
+
+enum ConnectThreadRequests {
+    Exit,
+    Connect,
+    Poke(u32 /* addr */, u32 /* val */),
+    Peek(u32 /* addr */),
+}
+
+#[derive(Debug)]
+enum ConnectThreadResponses {
+    Connected(Result<(), BridgeError>),
+    PeekResult(Result<u32, BridgeError>),
+    PokeResult(Result<(), BridgeError>),
+}
+
+/// An opened FTDI device that has been placed into MPSSE mode.
+struct Mpsse<'a> {
+    usb: libusb_wishbone_tool::DeviceHandle<'a>,
+    ep_out: u8,
+    ep_in: u8,
+    packet_size: usize,
+    three_wire: bool,
+}
+
+impl<'a> Mpsse<'a> {
+    fn write(&self, data: &[u8]) -> Result<(), BridgeError> {
+        let written = self.usb.write_bulk(self.ep_out, data, USB_TIMEOUT)?;
+        if written != data.len() {
+            return Err(BridgeError::LengthError(data.len(), written));
+        }
+        Ok(())
+    }
+
+    /// Read exactly `count` bytes from the device. FTDI devices prefix
+    /// every USB packet with two modem status bytes, which are discarded.
+    fn read(&self, count: usize) -> Result<Vec<u8>, BridgeError> {
+        let mut result = Vec::with_capacity(count);
+        let mut buffer = vec![0u8; self.packet_size];
+        let mut empty_reads = 0;
+        while result.len() < count {
+            let len = self.usb.read_bulk(self.ep_in, &mut buffer, USB_TIMEOUT)?;
+            let mut got_data = false;
+            for packet in buffer[..len].chunks(self.packet_size) {
+                if packet.len() > 2 {
+                    result.extend_from_slice(&packet[2..]);
+                    got_data = true;
+                }
+            }
+            if !got_data {
+                empty_reads += 1;
+                if empty_reads > TIMEOUT_COUNT {
+                    return Err(BridgeError::Timeout);
+                }
+            }
+        }
+        if result.len() != count {
+            return Err(BridgeError::LengthError(count, result.len()));
+        }
+        Ok(result)
+    }
+
+    /// Assert CS and drive the data line
+    fn start(&self, cmd: &mut Vec<u8>) {
+        cmd.extend_from_slice(&[MPSSE_SET_LOW_BYTE, 0, PIN_SCK | PIN_DO | PIN_CS]);
+    }
+
+    /// Release CS and leave the bus idle
+    fn finish(&self) -> Result<(), BridgeError> {
+        self.write(&[MPSSE_SET_LOW_BYTE, PIN_CS, PIN_SCK | PIN_DO | PIN_CS])
+    }
+
+    fn write_bytes(&self, cmd: &mut Vec<u8>, data: &[u8]) {
+        let len = data.len() - 1;
+        cmd.extend_from_slice(&[MPSSE_WRITE_BYTES, len as u8, (len >> 8) as u8]);
+        cmd.extend_from_slice(data);
+    }
+
+    /// Clock in `count` bytes. In three-wire mode the data output is shared
+    /// with the input, so release it before reading.
+    fn read_bytes(&self, mut cmd: Vec<u8>, count: usize) -> Result<Vec<u8>, BridgeError> {
+        if self.three_wire {
+            cmd.extend_from_slice(&[MPSSE_SET_LOW_BYTE, 0, PIN_SCK | PIN_CS]);
+        }
+        let len = count - 1;
+        cmd.extend_from_slice(&[
+            MPSSE_READ_BYTES,
+            len as u8,
+            (len >> 8) as u8,
+            MPSSE_SEND_IMMEDIATE,
+        ]);
+        self.write(&cmd)?;
+        self.read(count)
+    }
+}
+
+impl FtdiSpiBridgeInner {
+    pub fn new(cfg: &FtdiSpi) -> Result<Self, BridgeError> {
+        // Make sure libusb is available before spawning the thread.
+        libusb_wishbone_tool::Context::new()?;
+
+        let (main_tx, thread_rx) = channel();
+        let cv = Arc::new((Mutex::new(None), Condvar::new()));
+
+        let thr_cv = cv.clone();
+        let thr_cfg = cfg.clone();
+        thread::spawn(move || Self::ftdi_connect_thread(thr_cv, thread_rx, thr_cfg));
+
+        Ok(FtdiSpiBridgeInner {
+            main_tx,
+            main_rx: cv,
+            mutex: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn open<'a>(
+        usb_ctx: &'a libusb_wishbone_tool::Context,
+        cfg: &FtdiSpi,
+    ) -> Result<Mpsse<'a>, BridgeError> {
+        let devices = usb_ctx.devices()?;
+        for device in devices.iter() {
+            let device_desc = device.device_descriptor()?;
+            if device_desc.vendor_id() != cfg.vid || device_desc.product_id() != cfg.pid {
+                continue;
+            }
+            let mut usb = device.open()?;
+
+            // Channel A is interface 0, channel B is interface 1, and so on.
+            // Control requests address them starting from 1.
+            let iface = cfg.interface;
+            let index = iface as u16 + 1;
+            let ep_out = 0x02 + iface * 2;
+            let ep_in = 0x81 + iface * 2;
+            let packet_size = device
+                .active_config_descriptor()?
+                .interfaces()
+                .nth(iface as usize)
+                .and_then(|i| i.descriptors().next())
+                .and_then(|d| {
+                    d.endpoint_descriptors()
+                        .find(|e| e.address() == ep_in)
+                        .map(|e| e.max_packet_size() as usize)
+                })
+                .unwrap_or(512);
+
+            if let Ok(true) = usb.kernel_driver_active(iface) {
+                usb.detach_kernel_driver(iface)?;
+            }
+            usb.claim_interface(iface)?;
+
+            for (request, value) in &[
+                (SIO_RESET, SIO_RESET_SIO),
+                (SIO_RESET, SIO_RESET_PURGE_RX),
+                (SIO_RESET, SIO_RESET_PURGE_TX),
+                (SIO_SET_LATENCY_TIMER, 1),
+                (SIO_SET_BITMODE, BITMODE_RESET),
+                (SIO_SET_BITMODE, BITMODE_MPSSE),
+            ] {
+                usb.write_control(FTDI_REQUEST_OUT, *request, *value, index, &[], USB_TIMEOUT)?;
+            }
+
+            let mpsse = Mpsse {
+                usb,
+                ep_out,
+                ep_in,
+                packet_size,
+                three_wire: cfg.three_wire,
+            };
+
+            // Send a bogus command. The MPSSE engine responds with 0xfa
+            // followed by the bad opcode, which confirms we're in sync.
+            mpsse.write(&[MPSSE_BAD_COMMAND, MPSSE_SEND_IMMEDIATE])?;
+            let sync = mpsse.read(2)?;
+            if sync != [0xfa, MPSSE_BAD_COMMAND] {
+                error!("ftdi: mpsse failed to sync: {:02x?}", sync);
+                return Err(BridgeError::WrongResponse);
+            }
+
+            let divisor = (MPSSE_BASE_CLOCK / 2 / cfg.frequency.max(1)).max(1) - 1;
+            mpsse.write(&[
+                MPSSE_DISABLE_DIV5,
+                MPSSE_DISABLE_ADAPTIVE,
+                MPSSE_DISABLE_3PHASE,
+                MPSSE_LOOPBACK_OFF,
+                MPSSE_SET_DIVISOR,
+                divisor as u8,
+                (divisor >> 8) as u8,
+            ])?;
+            mpsse.finish()?;
+
+            info!(
+                "opened ftdi device {:04x}:{:04x} channel {} at {} Hz",
+                cfg.vid,
+                cfg.pid,
+                (b'A' + iface) as char,
+                MPSSE_BASE_CLOCK / 2 / (divisor + 1)
+            );
+            return Ok(mpsse);
+        }
+        Err(BridgeError::NotConnected)
+    }
+
+    fn ftdi_connect_thread(
+        tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+        rx: Receiver<ConnectThreadRequests>,
+        cfg: FtdiSpi,
+    ) {
+        use ConnectThreadRequests::*;
+        use ConnectThreadResponses::*;
+        let (response, cvar) = &*tx;
+
+        let usb_ctx = libusb_wishbone_tool::Context::new().unwrap();
+        let mut print_waiting_message = true;
+        loop {
+            match Self::open(&usb_ctx, &cfg) {
+                Ok(mpsse) => {
+                    print_waiting_message = true;
+                    let mut keep_going = true;
+                    while keep_going {
+                        match rx.recv() {
+                            Err(_) => {
+                                error!("connection closed");
+                                return;
+                            }
+                            Ok(Exit) => {
+                                debug!("ftdi_connect_thread requested exit");
+                                return;
+                            }
+                            Ok(Connect) => {
+                                *response.lock().unwrap() = Some(Connected(Ok(())));
+                                cvar.notify_one();
+                            }
+                            Ok(Peek(addr)) => {
+                                let result = Self::do_peek(&mpsse, addr);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PeekResult(result));
+                                cvar.notify_one();
+                            }
+                            Ok(Poke(addr, val)) => {
+                                let result = Self::do_poke(&mpsse, addr, val);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PokeResult(result));
+                                cvar.notify_one();
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    if print_waiting_message {
+                        info!("waiting for ftdi device: {}", e);
+                        print_waiting_message = false;
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_millis(500));
+
+            // Respond to any messages in the buffer with NotConnected.  As soon
+            // as the channel is empty, loop back to the start of this function.
+            loop {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("main thread disconnected"),
+                    Ok(m) => {
+                        let reply = match m {
+                            Exit => {
+                                debug!("main thread requested exit");
+                                return;
+                            }
+                            Connect => Connected(Err(BridgeError::NotConnected)),
+                            Peek(_addr) => PeekResult(Err(BridgeError::NotConnected)),
+                            Poke(_addr, _val) => PokeResult(Err(BridgeError::NotConnected)),
+                        };
+                        *response.lock().unwrap() = Some(reply);
+                        cvar.notify_one();
+                    }
+                }
+            }
+        }
+    }
+
+    fn do_poke(mpsse: &Mpsse, addr: u32, value: u32) -> Result<(), BridgeError> {
+        debug!("poke: writing 0x{:08x} to 0x{:08x}", value, addr);
+        let write_cmd = 0;
+
+        let mut cmd = vec![];
+        mpsse.start(&mut cmd);
+        let mut packet = vec![write_cmd];
+        packet.extend_from_slice(&addr.to_be_bytes());
+        packet.extend_from_slice(&value.to_be_bytes());
+        mpsse.write_bytes(&mut cmd, &packet);
+
+        // Wait for the response indicating the write has completed. Poll a
+        // single byte at a time, since there's no telling what the device
+        // does with extra clocks after the response.
+        let mut timeout_counter = 0;
+        loop {
+            let val = mpsse.read_bytes(cmd, 1)?[0];
+            cmd = vec![];
+            if val == write_cmd {
+                break;
+            }
+            if val != 0xff {
+                mpsse.finish()?;
+                error!("write: val was not {} or 0xff: {:02x}", write_cmd, val);
+                return Err(BridgeError::WrongResponse);
+            }
+            if timeout_counter > TIMEOUT_COUNT {
+                mpsse.finish()?;
+                return Err(BridgeError::Timeout);
+            }
+            timeout_counter += 1;
+        }
+
+        mpsse.finish()
+    }
+
+    fn do_peek(mpsse: &Mpsse, addr: u32) -> Result<u32, BridgeError> {
+        let read_cmd = 1;
+
+        let mut cmd = vec![];
+        mpsse.start(&mut cmd);
+        let mut packet = vec![read_cmd];
+        packet.extend_from_slice(&addr.to_be_bytes());
+        mpsse.write_bytes(&mut cmd, &packet);
+
+        // Poll five bytes at a time. If the response byte is the first one,
+        // the remaining four are exactly the value, so we never clock past
+        // the end of the transaction.
+        let mut timeout_counter = 0;
+        let mut value_bytes = loop {
+            let chunk = mpsse.read_bytes(cmd, 5)?;
+            cmd = vec![];
+            if let Some(pos) = chunk.iter().position(|&b| b != 0xff) {
+                if chunk[pos] != read_cmd {
+                    mpsse.finish()?;
+                    error!("read: val was not {} or 0xff: {:02x}", read_cmd, chunk[pos]);
+                    return Err(BridgeError::WrongResponse);
+                }
+                break chunk[pos + 1..].to_vec();
+            }
+            if timeout_counter > TIMEOUT_COUNT {
+                mpsse.finish()?;
+                return Err(BridgeError::Timeout);
+            }
+            timeout_counter += 1;
+        };
+        if value_bytes.len() < 4 {
+            let remaining = 4 - value_bytes.len();
+            value_bytes.extend(mpsse.read_bytes(vec![], remaining)?);
+        }
+        mpsse.finish()?;
+
+        let value = u32::from_be_bytes([
+            value_bytes[0],
+            value_bytes[1],
+            value_bytes[2],
+            value_bytes[3],
+        ]);
+        debug!("peek: value 0x{:08x} at addr 0x{:08x}", value, addr);
+        Ok(value)
+    }
+
+    fn request(&self, req: ConnectThreadRequests) -> ConnectThreadResponses {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(req)
+            .expect("Unable to send request to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        _mtx.take().unwrap()
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
+    /// Block until the FTDI device has been opened.
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        loop {
+            if let ConnectThreadResponses::Connected(Ok(())) =
+                self.request(ConnectThreadRequests::Connect)
+            {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        match self.request(ConnectThreadRequests::Poke(addr, value)) {
+            ConnectThreadResponses::PokeResult(r) => r,
+            e => {
+                error!("unexpected bridge poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        match self.request(ConnectThreadRequests::Peek(addr)) {
+            ConnectThreadResponses::PeekResult(r) => r,
+            e => {
+                error!("unexpected bridge peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+}
+
+impl Drop for FtdiSpiBridgeInner {
+    fn drop(&mut self) {
+        // If this is the last reference to the bridge, tell the control thread
+        // to exit.
+        if Arc::strong_count(&self.mutex) + Arc::weak_count(&self.mutex) <= 1 {
+            let (lock, _cvar) = &*self.main_rx;
+            let mut _mtx = lock.lock().unwrap();
+            self.main_tx.send(ConnectThreadRequests::Exit).ok();
+        }
+    }
+}
@@ -0,0 +1,461 @@
+extern crate gpio_cdev;
+extern crate spin_sleep;
+
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, info};
+
+use gpio_cdev::{Chip, Line, LineHandle, LineRequestFlags};
+
+use crate::{BridgeError, SpiBridge};
+
+const TIMEOUT_COUNT: u32 = 20000;
+
+/// The name the kernel reports as the owner of the lines we request
+const CONSUMER: &str = "wishbone-tool";
+
+/// A single line on a GPIO chip. The character device API fixes the
+/// direction of a line when it is requested, so changing direction means
+/// releasing the line and requesting it again.
+struct GpioLine {
+    line: Line,
+    handle: Option<LineHandle>,
+    is_input: bool,
+}
+
+impl GpioLine {
+    fn output(chip: &mut Chip, offset: u32, value: u8) -> Result<GpioLine, BridgeError> {
+        let line = chip.get_line(offset)?;
+        let handle = line.request(LineRequestFlags::OUTPUT, value, CONSUMER)?;
+        Ok(GpioLine {
+            line,
+            handle: Some(handle),
+            is_input: false,
+        })
+    }
+
+    fn input(chip: &mut Chip, offset: u32) -> Result<GpioLine, BridgeError> {
+        let line = chip.get_line(offset)?;
+        let handle = line.request(LineRequestFlags::INPUT, 0, CONSUMER)?;
+        Ok(GpioLine {
+            line,
+            handle: Some(handle),
+            is_input: true,
+        })
+    }
+
+    fn set_direction(&mut self, input: bool, value: u8) -> Result<(), BridgeError> {
+        if self.is_input == input && self.handle.is_some() {
+            return Ok(());
+        }
+        // The old handle must be closed before the kernel will hand out a new one.
+        self.handle = None;
+        let flags = if input {
+            LineRequestFlags::INPUT
+        } else {
+            LineRequestFlags::OUTPUT
+        };
+        self.handle = Some(self.line.request(flags, value, CONSUMER)?);
+        self.is_input = input;
+        Ok(())
+    }
+
+    fn handle(&self) -> Result<&LineHandle, BridgeError> {
+        self.handle.as_ref().ok_or(BridgeError::NotConnected)
+    }
+
+    fn set(&self, value: u8) -> Result<(), BridgeError> {
+        Ok(self.handle()?.set_value(value)?)
+    }
+
+    fn get(&self) -> Result<u8, BridgeError> {
+        Ok(self.handle()?.get_value()?)
+    }
+}
+
+struct SpiPins {
+    copi: GpioLine,
+    cipo: Option<GpioLine>,
+    clk: GpioLine,
+    cs: Option<GpioLine>,
+    delay: Duration,
+}
+
+impl fmt::Display for SpiPins {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let copi = format!("COPI:{}", self.copi.line.offset());
+        let cipo = if let Some(ref p) = self.cipo {
+            format!("CIPO:{}", p.line.offset())
+        } else {
+            "none".to_owned()
+        };
+        let clk = format!("CLK:{}", self.clk.line.offset());
+        let cs = if let Some(ref p) = self.cs {
+            format!("CS:{}", p.line.offset())
+        } else {
+            "none".to_owned()
+        };
+        fmt.write_str(&format!("{} {} {} {}", copi, cipo, clk, cs))
+    }
+}
+
+impl SpiPins {
+    /// Request all of the lines named in the config, leaving the bus idle.
+    fn open(cfg: &SpiBridge) -> Result<SpiPins, BridgeError> {
+        let mut chip = Chip::new(&cfg.gpio_chip)?;
+        let copi = GpioLine::output(&mut chip, cfg.copi, 1)?;
+        let cipo = match cfg.cipo {
+            Some(cipo) => Some(GpioLine::input(&mut chip, cipo)?),
+            None => None,
+        };
+        let clk = GpioLine::output(&mut chip, cfg.clk, 0)?;
+        let cs = match cfg.cs {
+            Some(cs) => Some(GpioLine::output(&mut chip, cs, 1)?),
+            None => None,
+        };
+        Ok(SpiPins {
+            copi,
+            cipo,
+            clk,
+            cs,
+            delay: Duration::from_nanos(333),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct SpiBridgeInner {
+    main_tx: Sender<ConnectThreadRequests>,
+    main_rx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+    mutex: Arc<Mutex<()>>,
+}
+
+enum ConnectThreadRequests {
+    Exit,
+    Poke(u32 /* addr */, u32 /* val */),
+    Peek(u32 /* addr */),
+}
+
+#[derive(Debug)]
+enum ConnectThreadResponses {
+    PeekResult(Result<u32, BridgeError>),
+    PokeResult(Result<(), BridgeError>),
+}
+
+impl SpiBridgeInner {
+    pub fn new(cfg: &SpiBridge) -> Result<Self, BridgeError> {
+        // Try to open the pins first, just to make sure we can. They are
+        // released again when `pins` goes out of scope.
+        {
+            let pins = SpiPins::open(cfg)?;
+            debug!("able to open spi pins {} on {:?}", pins, cfg.gpio_chip);
+        }
+
+        let (main_tx, thread_rx) = channel();
+        let cv = Arc::new((Mutex::new(None), Condvar::new()));
+
+        let thr_cv = cv.clone();
+        let thr_cfg = cfg.clone();
+        thread::spawn(move || Self::spi_connect_thread(thr_cv, thread_rx, thr_cfg));
+
+        Ok(SpiBridgeInner {
+            main_tx,
+            main_rx: cv,
+            mutex: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn spi_connect_thread(
+        tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
+        rx: Receiver<ConnectThreadRequests>,
+        cfg: SpiBridge,
+    ) {
+        use ConnectThreadRequests::*;
+        use ConnectThreadResponses::*;
+        let (response, cvar) = &*tx;
+
+        let mut print_waiting_message = true;
+        loop {
+            match SpiPins::open(&cfg) {
+                Ok(mut pins) => {
+                    info!(
+                        "re-initialized spi device with pins {} on {:?}",
+                        pins, cfg.gpio_chip
+                    );
+                    print_waiting_message = true;
+                    let mut keep_going = true;
+                    while keep_going {
+                        match rx.recv() {
+                            Err(_) => {
+                                error!("connection closed");
+                                return;
+                            }
+                            Ok(Exit) => {
+                                debug!("spi_connect_thread requested exit");
+                                return;
+                            }
+                            Ok(Peek(addr)) => {
+                                let result = Self::do_peek(&mut pins, addr);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PeekResult(result));
+                                cvar.notify_one();
+                            }
+                            Ok(Poke(addr, val)) => {
+                                let result = Self::do_poke(&mut pins, addr, val);
+                                keep_going = result.is_ok();
+                                *response.lock().unwrap() = Some(PokeResult(result));
+                                cvar.notify_one();
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    if print_waiting_message {
+                        info!("waiting for spi gpio lines: {}", e);
+                        print_waiting_message = false;
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_millis(50));
+
+            // Respond to any messages in the buffer with NotConnected.  As soon
+            // as the channel is empty, loop back to the start of this function.
+            loop {
+                match rx.try_recv() {
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("main thread disconnected"),
+                    Ok(m) => {
+                        let reply = match m {
+                            Exit => {
+                                debug!("main thread requested exit");
+                                return;
+                            }
+                            Peek(_addr) => PeekResult(Err(BridgeError::NotConnected)),
+                            Poke(_addr, _val) => PokeResult(Err(BridgeError::NotConnected)),
+                        };
+                        *response.lock().unwrap() = Some(reply);
+                        cvar.notify_one();
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn mutex(&self) -> &Arc<Mutex<()>> {
+        &self.mutex
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Get the appropriate input line.  If COPI is the input, ensure that
+    /// it is set as an input.
+    fn get_input(pins: &mut SpiPins) -> Result<(&GpioLine, &GpioLine, Duration), BridgeError> {
+        // If there's a CIPO line, use that.
+        // Otherwise, turn COPI into an input if necessary.
+        if let Some(ref pin) = pins.cipo {
+            Ok((pin, &pins.clk, pins.delay))
+        } else {
+            pins.copi.set_direction(true, 0)?;
+            Ok((&pins.copi, &pins.clk, pins.delay))
+        }
+    }
+
+    /// Get the appropriate output line.  If COPI is shared, ensure that
+    /// it is set as an output.
+    fn get_output(pins: &mut SpiPins) -> Result<(&GpioLine, &GpioLine, Duration), BridgeError> {
+        pins.copi.set_direction(false, 0)?;
+        Ok((&pins.copi, &pins.clk, pins.delay))
+    }
+
+    fn do_start(pins: &mut SpiPins) -> Result<(), BridgeError> {
+        pins.clk.set(0)?;
+        pins.copi.set_direction(false, 0)?;
+        pins.copi.set(0)?;
+        if let Some(cs) = &pins.cs {
+            cs.set(0)
+        } else {
+            Self::do_write_byte(pins, 0xab)
+        }
+    }
+
+    fn do_finish(pins: &mut SpiPins) -> Result<(), BridgeError> {
+        if let Some(cs) = &pins.cs {
+            cs.set(1)?;
+        }
+        pins.copi.set_direction(false, 0)?;
+        pins.copi.set(0)?;
+        pins.clk.set(0)
+    }
+
+    fn do_write_byte(pins: &mut SpiPins, b: u8) -> Result<(), BridgeError> {
+        let (pin, clk, delay) = Self::get_output(pins)?;
+        for i in &[7, 6, 5, 4, 3, 2, 1, 0] {
+            clk.set(0)?;
+            spin_sleep::sleep(delay);
+            pin.set((b >> i) & 1)?;
+            clk.set(1)?;
+            spin_sleep::sleep(delay);
+        }
+        Ok(())
+    }
+
+    fn do_read_byte(pins: &mut SpiPins) -> Result<u8, BridgeError> {
+        let mut val = 0;
+
+        // If running with less than four wires, use the
+        // copi line as an input.
+        let (pin, clk, delay) = Self::get_input(pins)?;
+
+        for i in &[7, 6, 5, 4, 3, 2, 1, 0] {
+            clk.set(0)?;
+            spin_sleep::sleep(delay);
+            clk.set(1)?;
+            spin_sleep::sleep(delay);
+            if pin.get()? != 0 {
+                val |= 1 << i;
+            }
+        }
+        Ok(val)
+    }
+
+    fn do_poke(pins: &mut SpiPins, addr: u32, value: u32) -> Result<(), BridgeError> {
+        debug!("poke: writing 0x{:08x} to 0x{:08x}", value, addr);
+        let write_cmd = 0;
+
+        Self::do_start(pins)?;
+
+        // Send the "Write" command
+        Self::do_write_byte(pins, write_cmd)?;
+
+        // Send the "Address"
+        for shift in &[24, 16, 8, 0] {
+            Self::do_write_byte(pins, (addr >> shift) as u8)?;
+        }
+
+        // Send the "Value"
+        for shift in &[24, 16, 8, 0] {
+            Self::do_write_byte(pins, (value >> shift) as u8)?;
+        }
+
+        // Wait for the response indicating the write has completed.
+        let mut timeout_counter = 0;
+        loop {
+            let val = Self::do_read_byte(pins)?;
+            if val == write_cmd {
+                break;
+            }
+            if val != 0xff {
+                error!("write: val was not {} or 0xff: {:02x}", write_cmd, val);
+                return Err(BridgeError::WrongResponse);
+            }
+            if timeout_counter > TIMEOUT_COUNT {
+                Self::do_finish(pins)?;
+                return Err(BridgeError::Timeout);
+            }
+            timeout_counter += 1;
+        }
+
+        Self::do_finish(pins)
+    }
+
+    fn do_peek(pins: &mut SpiPins, addr: u32) -> Result<u32, BridgeError> {
+        let read_cmd = 1;
+        Self::do_start(pins)?;
+
+        // Send the "Read" command
+        Self::do_write_byte(pins, read_cmd)?;
+
+        // Send the "Address"
+        for shift in &[24, 16, 8, 0] {
+            Self::do_write_byte(pins, (addr >> shift) as u8)?;
+        }
+
+        // Wait for the response indicating the read has completed.
+        let mut timeout_counter = 0;
+        loop {
+            let val = Self::do_read_byte(pins)?;
+            if val == read_cmd {
+                break;
+            }
+            if val != 0xff {
+                error!("read: val was not {} or 0xff: {:02x}", read_cmd, val);
+                return Err(BridgeError::WrongResponse);
+            }
+            if timeout_counter > TIMEOUT_COUNT {
+                Self::do_finish(pins)?;
+                return Err(BridgeError::Timeout);
+            }
+            timeout_counter += 1;
+        }
+
+        // Receive the "Value"
+        let mut value: u32 = 0;
+        for shift in &[24, 16, 8, 0] {
+            let b = Self::do_read_byte(pins)?;
+            value |= (b as u32) << shift;
+        }
+
+        Self::do_finish(pins)?;
+        debug!("peek: value 0x{:08x} at addr 0x{:08x}", value, addr);
+        Ok(value)
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::Poke(addr, value))
+            .expect("Unable to send poke to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::PokeResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge poke response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let (lock, cvar) = &*self.main_rx;
+        let mut _mtx = lock.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::Peek(addr))
+            .expect("Unable to send peek to connect thread");
+        *_mtx = None;
+        while _mtx.is_none() {
+            _mtx = cvar.wait(_mtx).unwrap();
+        }
+        match _mtx.take() {
+            Some(ConnectThreadResponses::PeekResult(r)) => Ok(r?),
+            e => {
+                error!("unexpected bridge peek response: {:?}", e);
+                Err(BridgeError::WrongResponse)
+            }
+        }
+    }
+}
+
+impl Drop for SpiBridgeInner {
+    fn drop(&mut self) {
+        // If this is the last reference to the bridge, tell the control thread
+        // to exit.
+        if Arc::strong_count(&self.mutex) + Arc::weak_count(&self.mutex) <= 1 {
+            let (lock, _cvar) = &*self.main_rx;
+            let mut _mtx = lock.lock().unwrap();
+            self.main_tx
+                .send(ConnectThreadRequests::Exit)
+                .expect("Unable to send Exit request to thread");
+        }
+    }
+}
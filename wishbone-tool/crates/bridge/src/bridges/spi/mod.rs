@@ -1,3 +1,5 @@
+use std::path::{Path, PathBuf};
+
 use crate::{Bridge, BridgeConfig, BridgeError};
 
 pub fn get_base(value: &str) -> (&str, u32) {
@@ -22,24 +24,48 @@ pub fn parse_u32(value: &str) -> Result<u32, String> {
         .or_else(|e| Err(format!("unable to parse pin '{}': {}", value, e)))
 }
 
+/// The GPIO chip used when none is specified
+const DEFAULT_GPIO_CHIP: &str = "/dev/gpiochip0";
+
 #[derive(Clone)]
 /// Describes a connection to a SPI bus. Note that not all platforms
 /// support SPI connections.
 pub struct SpiBridge {
     #[allow(dead_code)]
-    copi: u8,
+    copi: u32,
+    #[allow(dead_code)]
+    cipo: Option<u32>,
     #[allow(dead_code)]
-    cipo: Option<u8>,
+    clk: u32,
     #[allow(dead_code)]
-    clk: u8,
+    cs: Option<u32>,
+
+    /// The GPIO character device that the pins belong to.
     #[allow(dead_code)]
-    cs: Option<u8>,
+    gpio_chip: PathBuf,
+
+    /// If specified, use an FTDI MPSSE adapter rather than GPIO pins.
+    #[cfg(feature = "ftdi")]
+    ftdi: Option<FtdiSpi>,
 }
 
-/// A builder to create a connection to a target via SPI. These
-/// connections are currently only supported on Raspberry Pi through
-/// the use of bit-banging. There are interesting opportunities to
-/// add support for SPI connections to other platforms.
+/// Settings for an FTDI adapter running in MPSSE mode. The SPI signals
+/// are fixed by the hardware: ADBUS0 is CLK, ADBUS1 is COPI, ADBUS2 is
+/// CIPO, and ADBUS3 is CS.
+#[cfg(feature = "ftdi")]
+#[derive(Clone, Debug)]
+pub struct FtdiSpi {
+    vid: u16,
+    pid: u16,
+    interface: u8,
+    frequency: u32,
+    three_wire: bool,
+}
+
+/// A builder to create a connection to a target via SPI. The pins are
+/// bit-banged using the Linux GPIO character device API, so this works
+/// on any Linux board with GPIOs exposed through `/dev/gpiochipN`.
+/// On other platforms, an FTDI adapter may be used instead.
 ///
 /// ```no_run
 /// use wishbone_bridge::SpiBridge;
@@ -47,7 +73,8 @@ pub struct SpiBridge {
 /// ```
 impl SpiBridge {
     /// Create a new SpiBridge struct with the provided `pinspec`.
-    /// This spec is a comma-delimited list of pins to use for the SPI connection.
+    /// This spec is a comma-delimited list of pins to use for the SPI connection,
+    /// given as line offsets on the GPIO chip (`/dev/gpiochip0` by default).
     /// The number of pins provided indicates the type of connection to use:
     ///
     /// ```text
@@ -61,23 +88,18 @@ impl SpiBridge {
         let chars: Vec<&str> = pinspec.split(',').collect();
 
         let (copi, cipo, clk, cs) = match chars.len() {
-            2 => (
-                parse_u32(chars[0])? as u8,
-                None,
-                parse_u32(chars[1])? as u8,
-                None,
-            ),
+            2 => (parse_u32(chars[0])?, None, parse_u32(chars[1])?, None),
             3 => (
-                parse_u32(chars[0])? as u8,
+                parse_u32(chars[0])?,
                 None,
-                parse_u32(chars[1])? as u8,
-                Some(parse_u32(chars[2])? as u8),
+                parse_u32(chars[1])?,
+                Some(parse_u32(chars[2])?),
             ),
             4 => (
-                parse_u32(chars[0])? as u8,
-                Some(parse_u32(chars[1])? as u8),
-                parse_u32(chars[2])? as u8,
-                Some(parse_u32(chars[3])? as u8),
+                parse_u32(chars[0])?,
+                Some(parse_u32(chars[1])?),
+                parse_u32(chars[2])?,
+                Some(parse_u32(chars[3])?),
             ),
             _ => {
                 return Err(format!(
@@ -92,9 +114,89 @@ impl SpiBridge {
             cipo,
             clk,
             cs,
+            gpio_chip: PathBuf::from(DEFAULT_GPIO_CHIP),
+            #[cfg(feature = "ftdi")]
+            ftdi: None,
         })
     }
 
+    /// GPIO: Specify the GPIO chip that the pins belong to, such as
+    /// `/dev/gpiochip4` for the header on a Raspberry Pi 5.
+    pub fn gpio_chip<P: AsRef<Path>>(&mut self, path: P) -> &mut SpiBridge {
+        self.gpio_chip = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Create a new SpiBridge that uses an FTDI adapter with an MPSSE engine,
+    /// such as an FT2232H or FT232H. By default this looks for an FT2232H
+    /// (`0403:6010`), uses channel A, and runs at 1 MHz.
+    ///
+    /// ```no_run
+    /// use wishbone_bridge::SpiBridge;
+    /// let bridge = SpiBridge::new_ftdi().frequency(2_000_000).create().unwrap();
+    /// ```
+    #[cfg(feature = "ftdi")]
+    pub fn new_ftdi() -> Self {
+        SpiBridge {
+            copi: 0,
+            cipo: None,
+            clk: 0,
+            cs: None,
+            gpio_chip: PathBuf::from(DEFAULT_GPIO_CHIP),
+            ftdi: Some(FtdiSpi {
+                vid: 0x0403,
+                pid: 0x6010,
+                interface: 0,
+                frequency: 1_000_000,
+                three_wire: false,
+            }),
+        }
+    }
+
+    #[cfg(feature = "ftdi")]
+    fn ftdi_mut(&mut self) -> &mut FtdiSpi {
+        self.ftdi
+            .as_mut()
+            .expect("FTDI settings are only valid for bridges created with new_ftdi()")
+    }
+
+    /// FTDI: Specify the USB VID of the adapter.
+    #[cfg(feature = "ftdi")]
+    pub fn ftdi_vid(&mut self, vid: u16) -> &mut SpiBridge {
+        self.ftdi_mut().vid = vid;
+        self
+    }
+
+    /// FTDI: Specify the USB PID of the adapter.
+    #[cfg(feature = "ftdi")]
+    pub fn ftdi_pid(&mut self, pid: u16) -> &mut SpiBridge {
+        self.ftdi_mut().pid = pid;
+        self
+    }
+
+    /// FTDI: Specify which channel to use, where 0 is channel A.
+    #[cfg(feature = "ftdi")]
+    pub fn ftdi_interface(&mut self, interface: u8) -> &mut SpiBridge {
+        self.ftdi_mut().interface = interface;
+        self
+    }
+
+    /// FTDI: Specify the SPI clock frequency in Hz. The actual frequency
+    /// is rounded down to something the MPSSE can generate.
+    #[cfg(feature = "ftdi")]
+    pub fn frequency(&mut self, frequency: u32) -> &mut SpiBridge {
+        self.ftdi_mut().frequency = frequency;
+        self
+    }
+
+    /// FTDI: Use three-wire mode, where COPI and CIPO are tied together
+    /// (e.g. through a resistor) and connected to the target's single data line.
+    #[cfg(feature = "ftdi")]
+    pub fn three_wire(&mut self, three_wire: bool) -> &mut SpiBridge {
+        self.ftdi_mut().three_wire = three_wire;
+        self
+    }
+
     /// Create a `Bridge` struct based on the current configuration.
     /// This will return an error on platforms that do not support SPI.
     pub fn create(&self) -> Result<Bridge, BridgeError> {
@@ -102,12 +204,59 @@ impl SpiBridge {
     }
 }
 
-#[cfg(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64")))]
-pub mod raspberry_spi;
-#[cfg(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64")))]
-pub use raspberry_spi::SpiBridgeInner;
+#[cfg(target_os = "linux")]
+pub mod gpio_spi;
+#[cfg(target_os = "linux")]
+use gpio_spi::SpiBridgeInner as GpioSpiBridgeInner;
 
-#[cfg(not(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64"))))]
+#[cfg(not(target_os = "linux"))]
 pub mod dummy_spi;
-#[cfg(not(all(target_os = "linux", any(target_arch = "arm", target_arch = "aarch64"))))]
-pub use dummy_spi::SpiBridgeInner;
+#[cfg(not(target_os = "linux"))]
+use dummy_spi::SpiBridgeInner as GpioSpiBridgeInner;
+
+#[cfg(feature = "ftdi")]
+pub mod ftdi_spi;
+#[cfg(feature = "ftdi")]
+use ftdi_spi::FtdiSpiBridgeInner;
+
+/// The SPI bridge may either bit-bang GPIO pins, or use a USB adapter.
+#[derive(Clone)]
+pub enum SpiBridgeInner {
+    Gpio(GpioSpiBridgeInner),
+    #[cfg(feature = "ftdi")]
+    Ftdi(FtdiSpiBridgeInner),
+}
+
+impl SpiBridgeInner {
+    pub fn new(cfg: &SpiBridge) -> Result<Self, BridgeError> {
+        #[cfg(feature = "ftdi")]
+        if let Some(ftdi) = &cfg.ftdi {
+            return Ok(SpiBridgeInner::Ftdi(FtdiSpiBridgeInner::new(ftdi)?));
+        }
+        Ok(SpiBridgeInner::Gpio(GpioSpiBridgeInner::new(cfg)?))
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        match self {
+            SpiBridgeInner::Gpio(b) => b.connect(),
+            #[cfg(feature = "ftdi")]
+            SpiBridgeInner::Ftdi(b) => b.connect(),
+        }
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        match self {
+            SpiBridgeInner::Gpio(b) => b.poke(addr, value),
+            #[cfg(feature = "ftdi")]
+            SpiBridgeInner::Ftdi(b) => b.poke(addr, value),
+        }
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        match self {
+            SpiBridgeInner::Gpio(b) => b.peek(addr),
+            #[cfg(feature = "ftdi")]
+            SpiBridgeInner::Ftdi(b) => b.peek(addr),
+        }
+    }
+}
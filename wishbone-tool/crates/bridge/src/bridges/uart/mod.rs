@@ -1,25 +1,96 @@
-use std::io::prelude::*;
+use std::fmt;
+use std::io::{self, prelude::*};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use log::{debug, error, info};
+use tracing::{debug, error, info};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use serialport::prelude::*;
 
+use crate::stats::Operation;
+use crate::trace::{word, TraceReplay, TraceTap, TraceTransport, Tracer};
 use crate::{Bridge, BridgeConfig, BridgeError};
 
+#[cfg(target_os = "linux")]
+mod rfcomm;
+
 /// The default baud rate for the serial port. To change, call `set_baud()`
 pub const DEFAULT_BAUD_RATE: u32 = 115_200;
 
+/// The parity bit sent with each byte
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UartParity {
+    None,
+    Odd,
+    Even,
+}
+
+/// The number of stop bits sent after each byte
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UartStopBits {
+    One,
+    Two,
+}
+
+impl From<UartParity> for Parity {
+    fn from(parity: UartParity) -> Parity {
+        match parity {
+            UartParity::None => Parity::None,
+            UartParity::Odd => Parity::Odd,
+            UartParity::Even => Parity::Even,
+        }
+    }
+}
+
+impl From<UartStopBits> for StopBits {
+    fn from(stop_bits: UartStopBits) -> StopBits {
+        match stop_bits {
+            UartStopBits::One => StopBits::One,
+            UartStopBits::Two => StopBits::Two,
+        }
+    }
+}
+
+/// Where the Wishbone serial protocol is carried
+#[derive(Clone, Debug, PartialEq)]
+enum UartPort {
+    /// A serial port, such as `/dev/ttyUSB0` or `COM3`
+    Serial(PathBuf),
+
+    /// An RFCOMM channel on a Bluetooth device, such as an HC-05 module
+    Rfcomm { addr: [u8; 6], channel: u8 },
+}
+
+impl fmt::Display for UartPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UartPort::Serial(path) => write!(f, "{}", path.display()),
+            UartPort::Rfcomm { addr, channel } => write!(
+                f,
+                "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X} channel {}",
+                addr[0], addr[1], addr[2], addr[3], addr[4], addr[5], channel
+            ),
+        }
+    }
+}
+
+/// Either a serial port or an RFCOMM socket
+trait UartStream: Read + Write + Send {}
+impl<T: Read + Write + Send> UartStream for T {}
+
 /// Describes a connection to a UART or serial port
 #[derive(Clone)]
 pub struct UartBridge {
-    serial_port: PathBuf,
+    port: UartPort,
     baud: u32,
+    parity: UartParity,
+    stop_bits: UartStopBits,
+    rts_cts: bool,
+    trace: Option<PathBuf>,
 }
 
 impl UartBridge {
@@ -28,8 +99,42 @@ impl UartBridge {
             return Err(BridgeError::InvalidAddress);
         }
         Ok(UartBridge {
-            serial_port: path.as_ref().to_path_buf(),
+            port: UartPort::Serial(path.as_ref().to_path_buf()),
+            baud: DEFAULT_BAUD_RATE,
+            parity: UartParity::None,
+            stop_bits: UartStopBits::One,
+            rts_cts: false,
+            trace: None,
+        })
+    }
+
+    /// Connect to RFCOMM channel `channel` of the Bluetooth device at `addr`,
+    /// which is written as `AA:BB:CC:DD:EE:FF`. This is how Bluetooth serial
+    /// adapters such as the HC-05 are reached without binding them to a
+    /// `/dev/rfcommN` device first. The device should already be paired.
+    /// The baud rate, parity, stop bits, and flow control are set on the
+    /// adapter itself, so they're ignored here. Only supported on Linux.
+    pub fn rfcomm(addr: &str, channel: u8) -> Result<UartBridge, BridgeError> {
+        let octets = addr
+            .split(':')
+            .map(|octet| u8::from_str_radix(octet, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| BridgeError::InvalidAddress)?;
+        if octets.len() != 6 || !(1..=30).contains(&channel) {
+            return Err(BridgeError::InvalidAddress);
+        }
+        let mut bdaddr = [0; 6];
+        bdaddr.copy_from_slice(&octets);
+        Ok(UartBridge {
+            port: UartPort::Rfcomm {
+                addr: bdaddr,
+                channel,
+            },
             baud: DEFAULT_BAUD_RATE,
+            parity: UartParity::None,
+            stop_bits: UartStopBits::One,
+            rts_cts: false,
+            trace: None,
         })
     }
 
@@ -38,13 +143,41 @@ impl UartBridge {
         self
     }
 
+    /// Set the parity bit. Defaults to `UartParity::None`.
+    pub fn parity(&mut self, parity: UartParity) -> &mut UartBridge {
+        self.parity = parity;
+        self
+    }
+
+    /// Set the number of stop bits. Defaults to `UartStopBits::One`.
+    pub fn stop_bits(&mut self, stop_bits: UartStopBits) -> &mut UartBridge {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    /// Enable RTS/CTS hardware flow control, which is needed at high baud
+    /// rates to avoid dropping bytes. Software (XON/XOFF) flow control isn't
+    /// offered, since those bytes may appear in the data being sent.
+    pub fn rts_cts(&mut self, enabled: bool) -> &mut UartBridge {
+        self.rts_cts = enabled;
+        self
+    }
+
+    /// Write the bytes sent and received for every successful operation to
+    /// `path`, replacing anything already there. The trace can be checked
+    /// against the protocol code later with `check_trace()`.
+    pub fn trace_to<P: AsRef<Path>>(&mut self, path: P) -> &mut UartBridge {
+        self.trace = Some(path.as_ref().to_path_buf());
+        self
+    }
+
     pub fn create(&self) -> Result<Bridge, BridgeError> {
         Bridge::new(BridgeConfig::UartBridge(self.clone()))
     }
 }
 
 pub struct UartBridgeInner {
-    path: PathBuf,
+    port: UartPort,
     baudrate: u32,
     main_tx: Sender<ConnectThreadRequests>,
     main_rx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
@@ -55,7 +188,7 @@ pub struct UartBridgeInner {
 impl Clone for UartBridgeInner {
     fn clone(&self) -> Self {
         UartBridgeInner {
-            path: self.path.clone(),
+            port: self.port.clone(),
             baudrate: self.baudrate,
             main_tx: self.main_tx.clone(),
             main_rx: self.main_rx.clone(),
@@ -66,7 +199,7 @@ impl Clone for UartBridgeInner {
 }
 
 enum ConnectThreadRequests {
-    StartPolling(PathBuf /* path */, u32 /* baudrate */),
+    StartPolling(UartPort, u32 /* baudrate */),
     Exit,
     Poke(u32 /* addr */, u32 /* val */),
     Peek(u32 /* addr */),
@@ -85,17 +218,21 @@ impl UartBridgeInner {
         let (main_tx, thread_rx) = channel();
         let cv = Arc::new((Mutex::new(None), Condvar::new()));
 
-        let path = cfg.serial_port.clone();
+        let port = cfg.port.clone();
         let baudrate = cfg.baud;
+        let tracer = match &cfg.trace {
+            Some(path) => Some(Tracer::create(path, TraceTransport::Uart)?),
+            None => None,
+        };
 
         let thr_cv = cv.clone();
-        let thr_path = path.clone();
+        let thr_cfg = cfg.clone();
         let poll_thread = Some(thread::spawn(move || {
-            Self::serial_connect_thread(thr_cv, thread_rx, thr_path, baudrate)
+            Self::serial_connect_thread(thr_cv, thread_rx, thr_cfg, tracer)
         }));
 
         Ok(UartBridgeInner {
-            path,
+            port,
             baudrate,
             main_tx,
             main_rx: cv,
@@ -107,25 +244,28 @@ impl UartBridgeInner {
     fn serial_connect_thread(
         tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
         rx: Receiver<ConnectThreadRequests>,
-        path: PathBuf,
-        baud: u32,
+        cfg: UartBridge,
+        tracer: Option<Tracer>,
     ) {
-        let mut path = path;
-        let mut baud = baud;
+        let mut path = cfg.port.clone();
+        let mut baud = cfg.baud;
         let mut print_waiting_message = true;
         let mut first_run = true;
         let &(ref response, ref cvar) = &*tx;
         loop {
-            let mut port = match serialport::open(&path) {
+            let mut port = match Self::open_port(&path, baud, &cfg) {
                 Ok(port) => {
-                    info!("Re-opened serial device {}", path.display());
+                    info!("Re-opened serial device {}", path);
                     if first_run {
                         *response.lock().unwrap() = Some(ConnectThreadResponses::OpenedDevice);
                         first_run = false;
                         cvar.notify_one();
                     }
                     print_waiting_message = true;
-                    port
+                    match &tracer {
+                        Some(tracer) => Box::new(TraceTap::new(port, tracer.clone())),
+                        None => port,
+                    }
                 }
                 Err(e) => {
                     if print_waiting_message {
@@ -139,19 +279,6 @@ impl UartBridgeInner {
                     continue;
                 }
             };
-            port.set_baud_rate(baud as _)
-                .unwrap_or_else(|e| error!("unable to set serial port speed: {}", e));
-            port.set_data_bits(DataBits::Eight)
-                .unwrap_or_else(|e| error!("unable to set data bits: {}", e));
-            port.set_parity(Parity::None)
-                .unwrap_or_else(|e| error!("unable to set parity: {}", e));
-            port.set_stop_bits(StopBits::One)
-                .unwrap_or_else(|e| error!("unable to set stop bits: {}", e));
-            port.set_flow_control(FlowControl::None)
-                .unwrap_or_else(|e| error!("unable to set flow control: {}", e));
-            if let Err(e) = port.set_timeout(Duration::from_millis(1000)) {
-                error!("unable to set port duration timeout: {}", e);
-            }
 
             let mut keep_going = true;
             let mut result_error = "".to_owned();
@@ -175,6 +302,10 @@ impl UartBridgeInner {
                         }
                         ConnectThreadRequests::Peek(addr) => {
                             let result = Self::do_peek(&mut port, addr);
+                            if let Some(tracer) = &tracer {
+                                let value = result.as_ref().ok().map(|v| v.to_le_bytes().to_vec());
+                                tracer.finish(Operation::Peek, addr, value);
+                            }
                             if let Err(err) = &result {
                                 result_error = format!("peek {:?} @ {:08x}", err, addr);
                                 keep_going = false;
@@ -185,6 +316,11 @@ impl UartBridgeInner {
                         }
                         ConnectThreadRequests::Poke(addr, val) => {
                             let result = Self::do_poke(&mut port, addr, val);
+                            if let Some(tracer) = &tracer {
+                                let value =
+                                    result.as_ref().ok().map(|_| val.to_le_bytes().to_vec());
+                                tracer.finish(Operation::Poke, addr, value);
+                            }
                             if let Err(err) = &result {
                                 result_error = format!("poke {:?} @ {:08x}", err, addr);
                                 keep_going = false;
@@ -234,6 +370,46 @@ impl UartBridgeInner {
         }
     }
 
+    fn open_port(path: &UartPort, baud: u32, cfg: &UartBridge) -> io::Result<Box<dyn UartStream>> {
+        let path = match path {
+            UartPort::Serial(path) => path,
+            #[cfg(target_os = "linux")]
+            UartPort::Rfcomm { addr, channel } => {
+                return Ok(Box::new(rfcomm::connect(
+                    *addr,
+                    *channel,
+                    Duration::from_millis(1000),
+                )?))
+            }
+            #[cfg(not(target_os = "linux"))]
+            UartPort::Rfcomm { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "RFCOMM is only supported on Linux",
+                ))
+            }
+        };
+        let mut port = serialport::open(path)?;
+        port.set_baud_rate(baud as _)
+            .unwrap_or_else(|e| error!("unable to set serial port speed: {}", e));
+        port.set_data_bits(DataBits::Eight)
+            .unwrap_or_else(|e| error!("unable to set data bits: {}", e));
+        port.set_parity(cfg.parity.into())
+            .unwrap_or_else(|e| error!("unable to set parity: {}", e));
+        port.set_stop_bits(cfg.stop_bits.into())
+            .unwrap_or_else(|e| error!("unable to set stop bits: {}", e));
+        port.set_flow_control(if cfg.rts_cts {
+            FlowControl::Hardware
+        } else {
+            FlowControl::None
+        })
+        .unwrap_or_else(|e| error!("unable to set flow control: {}", e));
+        if let Err(e) = port.set_timeout(Duration::from_millis(1000)) {
+            error!("unable to set port duration timeout: {}", e);
+        }
+        Ok(Box::new(port))
+    }
+
     pub fn mutex(&self) -> &Arc<Mutex<()>> {
         &self.mutex
     }
@@ -241,7 +417,7 @@ impl UartBridgeInner {
     pub fn connect(&self) -> Result<(), BridgeError> {
         self.main_tx
             .send(ConnectThreadRequests::StartPolling(
-                self.path.clone(),
+                self.port.clone(),
                 self.baudrate,
             ))
             .unwrap();
@@ -258,11 +434,7 @@ impl UartBridgeInner {
         }
     }
 
-    fn do_poke(
-        serial: &mut std::boxed::Box<dyn serialport::SerialPort>,
-        addr: u32,
-        value: u32,
-    ) -> Result<(), BridgeError> {
+    fn do_poke<S: Read + Write>(serial: &mut S, addr: u32, value: u32) -> Result<(), BridgeError> {
         debug!("POKE @ {:08x} -> {:08x}", addr, value);
         // WRITE, 1 word
         serial.write_all(&[0x01, 0x01])?;
@@ -275,10 +447,7 @@ impl UartBridgeInner {
         Ok(())
     }
 
-    fn do_peek(
-        serial: &mut std::boxed::Box<dyn serialport::SerialPort>,
-        addr: u32,
-    ) -> Result<u32, BridgeError> {
+    fn do_peek<S: Read + Write>(serial: &mut S, addr: u32) -> Result<u32, BridgeError> {
         // READ, 1 word
         debug!("Peeking @ {:08x}", addr);
         serial.write_all(&[0x02, 0x01])?;
@@ -361,3 +530,22 @@ impl Drop for UartBridgeInner {
         }
     }
 }
+
+/// Run one operation from a transport trace through the serial protocol.
+pub(crate) fn replay_op(
+    serial: &mut TraceReplay,
+    op: Operation,
+    addr: u32,
+    data: &[u8],
+) -> Result<Vec<u8>, BridgeError> {
+    match op {
+        Operation::Peek => Ok(UartBridgeInner::do_peek(serial, addr)?
+            .to_le_bytes()
+            .to_vec()),
+        Operation::Poke => {
+            UartBridgeInner::do_poke(serial, addr, word(data)?)?;
+            Ok(vec![])
+        }
+        _ => Err(BridgeError::ProtocolNotSupported),
+    }
+}
@@ -0,0 +1,58 @@
+extern crate libc;
+
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use crate::sys::{self, check};
+
+/// From `<bluetooth/bluetooth.h>`, which `libc` doesn't cover.
+const BTPROTO_RFCOMM: libc::c_int = 3;
+
+/// `struct sockaddr_rc` from `<bluetooth/rfcomm.h>`
+#[repr(C)]
+struct SockaddrRc {
+    rc_family: libc::sa_family_t,
+    rc_bdaddr: [u8; 6],
+    rc_channel: u8,
+}
+
+/// Open an RFCOMM connection to channel `channel` of the device at `addr`.
+/// The address is given in the usual order, as printed by `bluetoothctl`.
+/// Reads time out after `timeout`, just like a serial port.
+pub fn connect(addr: [u8; 6], channel: u8, timeout: Duration) -> io::Result<File> {
+    let socket: File = sys::socket(libc::AF_BLUETOOTH, libc::SOCK_STREAM, BTPROTO_RFCOMM)?;
+
+    // BlueZ stores addresses with the least significant byte first.
+    let mut rc_bdaddr = addr;
+    rc_bdaddr.reverse();
+    let sockaddr = SockaddrRc {
+        rc_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+        rc_bdaddr,
+        rc_channel: channel,
+    };
+    check(unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &sockaddr as *const SockaddrRc as *const libc::sockaddr,
+            mem::size_of::<SockaddrRc>() as libc::socklen_t,
+        )
+    })?;
+
+    let timeval = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    check(unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeval as *const libc::timeval as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    })?;
+    Ok(socket)
+}
@@ -1,11 +1,23 @@
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use log::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-use crate::{Bridge, BridgeConfig, BridgeError};
+use crate::stats::Operation;
+use crate::trace::{word, TraceReplay, TraceTransport, Tracer};
+use crate::{Bridge, BridgeConfig, BridgeError, ConnectionState, StateCallback};
+
+/// When hotplug events are available, how often to check for them while
+/// the device is connected.
+const HOTPLUG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The `bmRequestType` of the vendor requests used to reach the Wishbone
+/// bus, without the direction bit.
+const WISHBONE_REQUEST_TYPE: u8 = 0x43;
 
 /// Connect to a target device via USB.
 #[derive(Clone, Default, Debug)]
@@ -21,6 +33,15 @@ pub struct UsbBridge {
 
     /// If specified, indicate the USB device number to look for.
     device: Option<u8>,
+
+    /// If specified, only connect to a device with this serial number.
+    serial: Option<String>,
+
+    /// If specified, called whenever the device is opened or goes away.
+    state_callback: Option<StateCallback>,
+
+    /// If specified, where to write a trace of the control transfers.
+    trace: Option<PathBuf>,
 }
 
 /// A builder to create a connection to a target via USB. You should
@@ -40,6 +61,9 @@ impl UsbBridge {
             vid: None,
             bus: None,
             device: None,
+            serial: None,
+            state_callback: None,
+            trace: None,
         }
     }
 
@@ -67,10 +91,219 @@ impl UsbBridge {
         self
     }
 
+    /// Limit connections to a device with the given serial number. Unlike
+    /// the bus and device numbers, this doesn't change when the device is
+    /// plugged back in, so it can be used to pick one of several identical
+    /// boards.
+    pub fn serial(&mut self, serial: &str) -> &mut UsbBridge {
+        self.serial = Some(serial.to_owned());
+        self
+    }
+
+    /// Call `callback` whenever the device is opened, or when it goes away
+    /// e.g. because it was unplugged or rebooted. The bridge re-opens the
+    /// device on its own once it comes back. Where libusb supports hotplug
+    /// events, a device that goes away is noticed right away, rather than
+    /// the next time the bridge is used.
+    pub fn on_state_change<F>(&mut self, callback: F) -> &mut UsbBridge
+    where
+        F: Fn(ConnectionState) + Send + Sync + 'static,
+    {
+        self.state_callback = Some(StateCallback::new(callback));
+        self
+    }
+
+    fn notify_state(&self, state: ConnectionState) {
+        if let Some(callback) = &self.state_callback {
+            callback.call(state);
+        }
+    }
+
+    /// Write the control transfers made for every successful operation to
+    /// `path`, replacing anything already there. The trace can be checked
+    /// against the protocol code later with `check_trace()`.
+    pub fn trace_to<P: AsRef<Path>>(&mut self, path: P) -> &mut UsbBridge {
+        self.trace = Some(path.as_ref().to_path_buf());
+        self
+    }
+
     /// Create a bridge based on the current configuration.
     pub fn create(&self) -> Result<Bridge, BridgeError> {
         Bridge::new(BridgeConfig::UsbBridge(self.clone()))
     }
+
+    /// Attempt to un-wedge a device whose USB Wishbone engine has stopped
+    /// responding. This re-reads the descriptors, re-claims each interface,
+    /// clears any halted endpoints, and, if `reset` is `true`, finally
+    /// resets the device.
+    ///
+    /// `report` is called after each step with its outcome. A failed step
+    /// does not stop the sequence, since a later step may still clear the
+    /// fault, but the first failure is returned once all steps have run.
+    ///
+    /// This talks to the device directly, so it should not be called while
+    /// a `Bridge` is connected to the same device.
+    pub fn recover<F>(&self, reset: bool, mut report: F) -> Result<(), BridgeError>
+    where
+        F: FnMut(&UsbRecoveryStep, &Result<(), BridgeError>),
+    {
+        let usb_ctx = libusb_wishbone_tool::Context::new()?;
+        let devices = usb_ctx.devices()?;
+        let (device, opened) = devices
+            .iter()
+            .find_map(|device| {
+                UsbBridgeInner::open_matching(&device, self).map(|opened| (device, opened))
+            })
+            .ok_or(BridgeError::NotConnected)?;
+
+        let found = UsbRecoveryStep::OpenDevice(device.bus_number(), device.address());
+        let mut usb = match opened {
+            Ok(usb) => {
+                report(&found, &Ok(()));
+                usb
+            }
+            Err(e) => {
+                let result = Err(BridgeError::USBError(e));
+                report(&found, &result);
+                return result;
+            }
+        };
+
+        let mut first_error = None;
+        let mut step = |step: UsbRecoveryStep, result: Result<(), BridgeError>| {
+            report(&step, &result);
+            if let Err(e) = result {
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            }
+        };
+
+        // Ask the device itself for its descriptor, rather than relying on
+        // the copy the OS cached at enumeration.
+        step(
+            UsbRecoveryStep::ReadDescriptors,
+            Self::read_device_descriptor(&usb)
+                .and_then(|_| device.active_config_descriptor().map(|_| ()))
+                .map_err(BridgeError::USBError),
+        );
+
+        let config = device.active_config_descriptor();
+        for interface in config.iter().flat_map(|c| c.interfaces()) {
+            let number = interface.number();
+            if let Ok(true) = usb.kernel_driver_active(number) {
+                step(
+                    UsbRecoveryStep::DetachKernelDriver(number),
+                    usb.detach_kernel_driver(number)
+                        .map_err(BridgeError::USBError),
+                );
+            }
+
+            // Releasing an interface we never claimed is expected to fail.
+            usb.release_interface(number).ok();
+            let claimed = usb.claim_interface(number);
+            let is_claimed = claimed.is_ok();
+            step(
+                UsbRecoveryStep::ClaimInterface(number),
+                claimed.map_err(BridgeError::USBError),
+            );
+            if !is_claimed {
+                continue;
+            }
+
+            for address in interface.descriptors().flat_map(|d| {
+                d.endpoint_descriptors()
+                    .map(|e| e.address())
+                    .collect::<Vec<_>>()
+            }) {
+                step(
+                    UsbRecoveryStep::ClearHalt(address),
+                    usb.clear_halt(address).map_err(BridgeError::USBError),
+                );
+            }
+        }
+
+        if reset {
+            step(
+                UsbRecoveryStep::ResetDevice,
+                usb.reset().map_err(BridgeError::USBError),
+            );
+        }
+
+        // Make sure the device is talking to us again. After a reset that
+        // caused the device to re-enumerate, this is expected to fail, and the
+        // device must be opened afresh.
+        step(
+            UsbRecoveryStep::Verify,
+            Self::read_device_descriptor(&usb).map_err(BridgeError::USBError),
+        );
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Issue a GET_DESCRIPTOR request for the device descriptor.
+    fn read_device_descriptor(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+    ) -> Result<(), libusb_wishbone_tool::Error> {
+        let mut descriptor = [0; 18];
+        let len = usb.read_control(
+            0x80,
+            0x06,
+            0x0100,
+            0,
+            &mut descriptor,
+            Duration::from_millis(500),
+        )?;
+        if len != descriptor.len() {
+            return Err(libusb_wishbone_tool::Error::Io);
+        }
+        Ok(())
+    }
+}
+
+/// A single step of the `UsbBridge::recover()` sequence.
+#[derive(Debug)]
+pub enum UsbRecoveryStep {
+    /// Open the device at the given bus and address
+    OpenDevice(u8 /* bus */, u8 /* address */),
+
+    /// Re-read the device and configuration descriptors from the device
+    ReadDescriptors,
+
+    /// Detach the kernel driver bound to the given interface
+    DetachKernelDriver(u8),
+
+    /// Release and re-claim the given interface
+    ClaimInterface(u8),
+
+    /// Clear a halt condition on the given endpoint address
+    ClearHalt(u8),
+
+    /// Issue a USB port reset to the device
+    ResetDevice,
+
+    /// Check that the device responds to control requests again
+    Verify,
+}
+
+impl ::std::fmt::Display for UsbRecoveryStep {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        use UsbRecoveryStep::*;
+        match self {
+            OpenDevice(bus, address) => {
+                write!(f, "open device {:03} on bus {:03}", address, bus)
+            }
+            ReadDescriptors => write!(f, "re-read descriptors"),
+            DetachKernelDriver(i) => write!(f, "detach kernel driver from interface {}", i),
+            ClaimInterface(i) => write!(f, "re-claim interface {}", i),
+            ClearHalt(ep) => write!(f, "clear halt on endpoint 0x{:02x}", ep),
+            ResetDevice => write!(f, "reset device"),
+            Verify => write!(f, "verify device responds"),
+        }
+    }
 }
 
 pub struct UsbBridgeInner {
@@ -82,6 +315,31 @@ pub struct UsbBridgeInner {
     poll_thread: Option<thread::JoinHandle<()>>,
 }
 
+/// A device arriving, or a device leaving along with its bus number and
+/// address
+#[derive(Debug)]
+enum HotplugEvent {
+    Arrived,
+    Left(u8, u8),
+}
+
+/// Forwards libusb hotplug callbacks to the poll thread.
+struct HotplugListener {
+    tx: Sender<HotplugEvent>,
+}
+
+impl libusb_wishbone_tool::Hotplug for HotplugListener {
+    fn device_arrived(&mut self, _device: libusb_wishbone_tool::Device) {
+        self.tx.send(HotplugEvent::Arrived).ok();
+    }
+
+    fn device_left(&mut self, device: libusb_wishbone_tool::Device) {
+        self.tx
+            .send(HotplugEvent::Left(device.bus_number(), device.address()))
+            .ok();
+    }
+}
+
 #[derive(Debug)]
 enum ConnectThreadRequests {
     StartPolling(Option<u16> /* vid */, Option<u16> /* pid */),
@@ -118,13 +376,24 @@ impl Clone for UsbBridgeInner {
 impl UsbBridgeInner {
     pub fn new(cfg: &UsbBridge) -> Result<Self, BridgeError> {
         let usb_ctx = libusb_wishbone_tool::Context::new()?;
+        let tracer = match &cfg.trace {
+            Some(path) => Some(Tracer::create(path, TraceTransport::Usb)?),
+            None => None,
+        };
         let (main_tx, thread_rx) = channel();
         let cv = Arc::new((Mutex::new(None), Condvar::new()));
 
         let thr_cfg = cfg.clone();
         let thr_cv = cv.clone();
         let poll_thread = Some(thread::spawn(move || {
-            Self::usb_poll_thread(usb_ctx, thr_cv, thread_rx, thr_cfg, 0x43)
+            Self::usb_poll_thread(
+                usb_ctx,
+                thr_cv,
+                thread_rx,
+                thr_cfg,
+                tracer,
+                WISHBONE_REQUEST_TYPE,
+            )
         }));
 
         Ok(UsbBridgeInner {
@@ -165,6 +434,94 @@ impl UsbBridgeInner {
         true
     }
 
+    /// Read the serial number string of an open device.
+    fn read_serial(
+        usb: &libusb_wishbone_tool::DeviceHandle,
+        device_desc: &libusb_wishbone_tool::DeviceDescriptor,
+    ) -> Result<String, libusb_wishbone_tool::Error> {
+        let timeout = Duration::from_millis(100);
+        let language = usb
+            .read_languages(timeout)?
+            .first()
+            .copied()
+            .ok_or(libusb_wishbone_tool::Error::NotFound)?;
+        usb.read_serial_number_string(language, device_desc, timeout)
+    }
+
+    /// Open `device` if it matches `cfg`. Returns `None` if it doesn't match.
+    /// Checking the serial number requires opening the device, so devices
+    /// that can't be opened are only reported if no serial was specified.
+    fn open_matching<'a>(
+        device: &libusb_wishbone_tool::Device<'a>,
+        cfg: &UsbBridge,
+    ) -> Option<Result<libusb_wishbone_tool::DeviceHandle<'a>, libusb_wishbone_tool::Error>> {
+        let device_desc = device.device_descriptor().ok()?;
+        if !Self::device_matches(device, &device_desc, cfg) {
+            return None;
+        }
+        let usb = match device.open() {
+            Ok(usb) => usb,
+            Err(e) if cfg.serial.is_some() => {
+                debug!(
+                    "unable to open device {:03} on bus {:03} to check its serial: {}",
+                    device.address(),
+                    device.bus_number(),
+                    e
+                );
+                return None;
+            }
+            Err(e) => return Some(Err(e)),
+        };
+        if let Some(serial) = &cfg.serial {
+            match Self::read_serial(&usb, &device_desc) {
+                Ok(ref s) if s == serial => (),
+                Ok(s) => {
+                    debug!("skipping device with serial {}", s);
+                    return None;
+                }
+                Err(e) => {
+                    debug!("unable to read device serial: {}", e);
+                    return None;
+                }
+            }
+        }
+        Some(Ok(usb))
+    }
+
+    /// If no serial number was given and several devices match, say which
+    /// ones were found so the user can pick one with `serial()`.
+    fn warn_if_ambiguous(devices: &libusb_wishbone_tool::DeviceList, cfg: &UsbBridge) {
+        if cfg.serial.is_some() {
+            return;
+        }
+        let matching: Vec<String> = devices
+            .iter()
+            .filter_map(|device| {
+                let device_desc = device.device_descriptor().ok()?;
+                if !Self::device_matches(&device, &device_desc, cfg) {
+                    return None;
+                }
+                let serial = device
+                    .open()
+                    .and_then(|usb| Self::read_serial(&usb, &device_desc))
+                    .unwrap_or_else(|_| "unknown".to_owned());
+                Some(format!(
+                    "device {:03} on bus {:03} (serial {})",
+                    device.address(),
+                    device.bus_number(),
+                    serial
+                ))
+            })
+            .collect();
+        if matching.len() > 1 {
+            warn!(
+                "{} devices match, using the first one. Specify a serial number to choose: {}",
+                matching.len(),
+                matching.join(", ")
+            );
+        }
+    }
+
     pub fn mutex(&self) -> &Arc<Mutex<()>> {
         &self.mutex
     }
@@ -190,22 +547,75 @@ impl UsbBridgeInner {
         }
     }
 
+    /// Ask libusb to tell us about devices arriving and leaving. Returns
+    /// `None` if hotplug events aren't supported on this platform, in which
+    /// case the poll thread falls back to noticing failed transfers.
+    fn register_hotplug(
+        usb_ctx: &libusb_wishbone_tool::Context,
+        cfg: &UsbBridge,
+    ) -> Option<(libusb_wishbone_tool::Registration, Receiver<HotplugEvent>)> {
+        if !usb_ctx.has_hotplug() {
+            debug!("libusb doesn't support hotplug events");
+            return None;
+        }
+        let (tx, rx) = channel();
+        match usb_ctx.register_callback(cfg.vid, cfg.pid, None, Box::new(HotplugListener { tx })) {
+            Ok(registration) => Some((registration, rx)),
+            Err(e) => {
+                debug!("unable to register for hotplug events: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Process any pending libusb events, waiting up to `timeout` for one to
+    /// arrive. Returns `true` if the device at `location` went away.
+    fn check_hotplug(
+        usb_ctx: &libusb_wishbone_tool::Context,
+        events: &Receiver<HotplugEvent>,
+        timeout: Duration,
+        location: Option<(u8, u8)>,
+    ) -> bool {
+        if let Err(e) = usb_ctx.handle_events(Some(timeout)) {
+            debug!("error handling usb events: {}", e);
+        }
+        let mut left = false;
+        while let Ok(event) = events.try_recv() {
+            debug!("usb hotplug event: {:?}", event);
+            if let HotplugEvent::Left(bus, address) = event {
+                left |= location == Some((bus, address));
+            }
+        }
+        left
+    }
+
     fn usb_poll_thread(
         usb_ctx: libusb_wishbone_tool::Context,
         tx: Arc<(Mutex<Option<ConnectThreadResponses>>, Condvar)>,
         rx: Receiver<ConnectThreadRequests>,
         mut cfg: UsbBridge,
+        tracer: Option<Tracer>,
         debug_byte: u8,
     ) {
         let mut print_waiting_message = true;
         let mut first_open = true;
         let &(ref response, ref cvar) = &*tx;
+        let hotplug = Self::register_hotplug(&usb_ctx, &cfg);
+        let exit = |usb_ctx: &libusb_wishbone_tool::Context| {
+            if let Some((registration, _)) = &hotplug {
+                usb_ctx.unregister_callback(*registration);
+            }
+            *response.lock().unwrap() = Some(ConnectThreadResponses::Exiting);
+            cvar.notify_one();
+        };
         loop {
             let devices = usb_ctx.devices().unwrap();
+            if print_waiting_message {
+                Self::warn_if_ambiguous(&devices, &cfg);
+            }
             for device in devices.iter() {
-                let device_desc = device.device_descriptor().unwrap();
-                if Self::device_matches(&device, &device_desc, &cfg) {
-                    let usb = match device.open() {
+                if let Some(opened) = Self::open_matching(&device, &cfg) {
+                    let usb = match opened {
                         Ok(o) => {
                             info!(
                                 "opened USB device device {:03} on bus {:03}",
@@ -219,6 +629,7 @@ impl UsbBridgeInner {
                                 first_open = false;
                             }
                             print_waiting_message = true;
+                            cfg.notify_state(ConnectionState::Connected);
                             o
                         }
                         Err(e) => {
@@ -226,17 +637,40 @@ impl UsbBridgeInner {
                             continue;
                         }
                     };
+                    let location = Some((device.bus_number(), device.address()));
+                    let usb = TracedDevice {
+                        usb: &usb,
+                        tracer: tracer.as_ref(),
+                    };
                     let mut keep_going = true;
                     while keep_going {
-                        let var = rx.recv();
+                        let var = match &hotplug {
+                            Some((_, events)) => match rx.recv_timeout(HOTPLUG_POLL_INTERVAL) {
+                                Err(RecvTimeoutError::Timeout) => {
+                                    if Self::check_hotplug(
+                                        &usb_ctx,
+                                        events,
+                                        Duration::from_millis(0),
+                                        location,
+                                    ) {
+                                        info!("USB device was unplugged");
+                                        keep_going = false;
+                                    }
+                                    continue;
+                                }
+                                Err(RecvTimeoutError::Disconnected) => {
+                                    panic!("error in connect thread: main thread disconnected")
+                                }
+                                Ok(o) => Ok(o),
+                            },
+                            None => rx.recv(),
+                        };
                         match var {
                             Err(e) => panic!("error in connect thread: {}", e),
                             Ok(o) => match o {
                                 ConnectThreadRequests::Exit => {
                                     debug!("usb_poll_thread requested exit");
-                                    *response.lock().unwrap() =
-                                        Some(ConnectThreadResponses::Exiting);
-                                    cvar.notify_one();
+                                    exit(&usb_ctx);
                                     return;
                                 }
                                 ConnectThreadRequests::StartPolling(p, v) => {
@@ -245,6 +679,11 @@ impl UsbBridgeInner {
                                 }
                                 ConnectThreadRequests::Peek(addr) => {
                                     let result = Self::do_peek(&usb, addr, debug_byte);
+                                    if let Some(tracer) = &tracer {
+                                        let value =
+                                            result.as_ref().ok().map(|v| v.to_le_bytes().to_vec());
+                                        tracer.finish(Operation::Peek, addr, value);
+                                    }
                                     keep_going = result.is_ok();
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::PeekResult(result));
@@ -252,6 +691,13 @@ impl UsbBridgeInner {
                                 }
                                 ConnectThreadRequests::Poke(addr, val) => {
                                     let result = Self::do_poke(&usb, addr, val, debug_byte);
+                                    if let Some(tracer) = &tracer {
+                                        let value = result
+                                            .as_ref()
+                                            .ok()
+                                            .map(|_| val.to_le_bytes().to_vec());
+                                        tracer.finish(Operation::Poke, addr, value);
+                                    }
                                     keep_going = result.is_ok();
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::PokeResult(result));
@@ -259,13 +705,22 @@ impl UsbBridgeInner {
                                 }
                                 ConnectThreadRequests::BurstRead(addr, len) => {
                                     let result = Self::do_burst_read(&usb, addr, len, debug_byte);
+                                    if let Some(tracer) = &tracer {
+                                        let value = result.as_ref().ok().cloned();
+                                        tracer.finish(Operation::BurstRead, addr, value);
+                                    }
                                     keep_going = result.is_ok();
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::BurstReadResult(result));
                                     cvar.notify_one();
                                 }
                                 ConnectThreadRequests::BurstWrite(addr, data) => {
+                                    let written = tracer.as_ref().map(|_| data.clone());
                                     let result = Self::do_burst_write(&usb, addr, data, debug_byte);
+                                    if let Some(tracer) = &tracer {
+                                        let value = result.as_ref().ok().and(written);
+                                        tracer.finish(Operation::BurstWrite, addr, value);
+                                    }
                                     keep_going = result.is_ok();
                                     *response.lock().unwrap() =
                                         Some(ConnectThreadResponses::BurstWriteResult(result));
@@ -274,6 +729,7 @@ impl UsbBridgeInner {
                             },
                         }
                     }
+                    cfg.notify_state(ConnectionState::Disconnected);
                 }
             }
 
@@ -284,7 +740,13 @@ impl UsbBridgeInner {
                 info!("waiting for target device");
                 print_waiting_message = false;
             }
-            thread::park_timeout(Duration::from_millis(500));
+            match &hotplug {
+                // Returns early if a device arrives, so it gets opened right away.
+                Some((_, events)) => {
+                    Self::check_hotplug(&usb_ctx, events, Duration::from_millis(500), None);
+                }
+                None => thread::park_timeout(Duration::from_millis(500)),
+            }
 
             // Respond to any messages in the buffer with NotConnected.  As soon
             // as the channel is empty, loop back to the start of this function.
@@ -295,8 +757,7 @@ impl UsbBridgeInner {
                     Ok(m) => match m {
                         ConnectThreadRequests::Exit => {
                             debug!("main thread requested exit");
-                            *response.lock().unwrap() = Some(ConnectThreadResponses::Exiting);
-                            cvar.notify_one();
+                            exit(&usb_ctx);
                             return;
                         }
                         ConnectThreadRequests::Peek(_addr) => {
@@ -335,8 +796,8 @@ impl UsbBridgeInner {
         }
     }
 
-    fn do_poke(
-        usb: &libusb_wishbone_tool::DeviceHandle,
+    fn do_poke<C: ControlTransfer>(
+        usb: &C,
         addr: u32,
         value: u32,
         debug_byte: u8,
@@ -373,8 +834,8 @@ impl UsbBridgeInner {
         }
     }
 
-    fn do_burst_write(
-        usb: &libusb_wishbone_tool::DeviceHandle,
+    fn do_burst_write<C: ControlTransfer>(
+        usb: &C,
         addr: u32,
         data: Vec<u8>,
         debug_byte: u8,
@@ -423,11 +884,7 @@ impl UsbBridgeInner {
         Ok(())
     }
 
-    fn do_peek(
-        usb: &libusb_wishbone_tool::DeviceHandle,
-        addr: u32,
-        debug_byte: u8,
-    ) -> Result<u32, BridgeError> {
+    fn do_peek<C: ControlTransfer>(usb: &C, addr: u32, debug_byte: u8) -> Result<u32, BridgeError> {
         let mut data_val = [0; 4];
         match usb.read_control(
             0x80 | debug_byte,
@@ -460,8 +917,8 @@ impl UsbBridgeInner {
         }
     }
 
-    fn do_burst_read(
-        usb: &libusb_wishbone_tool::DeviceHandle,
+    fn do_burst_read<C: ControlTransfer>(
+        usb: &C,
         addr: u32,
         len: u32,
         debug_byte: u8,
@@ -599,6 +1056,161 @@ impl UsbBridgeInner {
     }
 }
 
+/// Somewhere to send the control transfers that make up each operation
+trait ControlTransfer {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> libusb_wishbone_tool::Result<usize>;
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> libusb_wishbone_tool::Result<usize>;
+}
+
+/// The eight-byte setup packet that starts a control transfer
+fn setup_packet(request_type: u8, request: u8, value: u16, index: u16, length: usize) -> Vec<u8> {
+    let mut setup = vec![request_type, request];
+    setup.extend_from_slice(&value.to_le_bytes());
+    setup.extend_from_slice(&index.to_le_bytes());
+    setup.extend_from_slice(&(length as u16).to_le_bytes());
+    setup
+}
+
+/// An open device, along with the trace to write its transfers to, if any
+struct TracedDevice<'a> {
+    usb: &'a libusb_wishbone_tool::DeviceHandle<'a>,
+    tracer: Option<&'a Tracer>,
+}
+
+impl ControlTransfer for TracedDevice<'_> {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> libusb_wishbone_tool::Result<usize> {
+        let len = self
+            .usb
+            .read_control(request_type, request, value, index, buf, timeout)?;
+        if let Some(tracer) = self.tracer {
+            tracer.tx(&setup_packet(
+                request_type,
+                request,
+                value,
+                index,
+                buf.len(),
+            ));
+            tracer.rx(&buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> libusb_wishbone_tool::Result<usize> {
+        let len = self
+            .usb
+            .write_control(request_type, request, value, index, buf, timeout)?;
+        if let Some(tracer) = self.tracer {
+            let mut setup = setup_packet(request_type, request, value, index, buf.len());
+            setup.extend_from_slice(&buf[..len]);
+            tracer.tx(&setup);
+        }
+        Ok(len)
+    }
+}
+
+/// Control transfers played back from a trace
+impl ControlTransfer for RefCell<&mut TraceReplay> {
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> libusb_wishbone_tool::Result<usize> {
+        let mut replay = self.borrow_mut();
+        replay
+            .send(&setup_packet(
+                request_type,
+                request,
+                value,
+                index,
+                buf.len(),
+            ))
+            .and_then(|()| replay.recv(buf))
+            .map_err(|_| libusb_wishbone_tool::Error::Io)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        _timeout: Duration,
+    ) -> libusb_wishbone_tool::Result<usize> {
+        let mut setup = setup_packet(request_type, request, value, index, buf.len());
+        setup.extend_from_slice(buf);
+        self.borrow_mut()
+            .send(&setup)
+            .map(|()| buf.len())
+            .map_err(|_| libusb_wishbone_tool::Error::Io)
+    }
+}
+
+/// Run one operation from a transport trace through the USB protocol.
+pub(crate) fn replay_op(
+    replay: &mut TraceReplay,
+    op: Operation,
+    addr: u32,
+    data: &[u8],
+) -> Result<Vec<u8>, BridgeError> {
+    let usb = RefCell::new(replay);
+    let debug_byte = WISHBONE_REQUEST_TYPE;
+    match op {
+        Operation::Peek => Ok(UsbBridgeInner::do_peek(&usb, addr, debug_byte)?
+            .to_le_bytes()
+            .to_vec()),
+        Operation::Poke => {
+            UsbBridgeInner::do_poke(&usb, addr, word(data)?, debug_byte)?;
+            Ok(vec![])
+        }
+        Operation::BurstRead => {
+            UsbBridgeInner::do_burst_read(&usb, addr, data.len() as u32, debug_byte)
+        }
+        Operation::BurstWrite => {
+            UsbBridgeInner::do_burst_write(&usb, addr, data.to_vec(), debug_byte)?;
+            Ok(vec![])
+        }
+        Operation::Connect => Err(BridgeError::ProtocolNotSupported),
+    }
+}
+
 impl Drop for UsbBridgeInner {
     fn drop(&mut self) {
         // If this is the last reference to the bridge, tell the control thread
@@ -0,0 +1,63 @@
+use crate::BridgeError;
+
+/// A transport for a `Bridge` that lives outside of this crate, such as a
+/// vendor's debug dongle. Implement this and pass it to
+/// `Bridge::from_driver()` to get a `Bridge` that works with everything
+/// else here, including `MuxBridge`, `MappedBridge`, recording, and the
+/// `Read` and `Write` impls.
+///
+/// Every method takes `&self`, since the `Bridge` and all of its clones
+/// share a single driver. Calls never overlap, because the `Bridge` only
+/// makes one at a time, so a `Mutex` around any state the driver needs
+/// will never be contended.
+///
+/// Errors other than `BridgeError::InvalidAddress` are retried, the same as
+/// they are for the built-in bridges. Drivers that can't do bursts should
+/// leave `burst_read()` and `burst_write()` as they are, which makes
+/// `Bridge` fall back to single words where it can.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use std::sync::Mutex;
+/// use wishbone_bridge::{Bridge, BridgeDriver, BridgeError};
+///
+/// #[derive(Default)]
+/// struct Memory(Mutex<HashMap<u32, u32>>);
+///
+/// impl BridgeDriver for Memory {
+///     fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+///         Ok(*self.0.lock().unwrap().get(&addr).unwrap_or(&0))
+///     }
+///
+///     fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+///         self.0.lock().unwrap().insert(addr, value);
+///         Ok(())
+///     }
+/// }
+///
+/// let bridge = Bridge::from_driver(Box::new(Memory::default()));
+/// bridge.poke(0x1000_0000, 0x1234_5678).unwrap();
+/// assert_eq!(bridge.peek(0x1000_0000).unwrap(), 0x1234_5678);
+/// ```
+pub trait BridgeDriver: Send + Sync {
+    /// Wait until the device can be reached. Called by `Bridge::connect()`.
+    fn connect(&self) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Read a single 32-bit word.
+    fn peek(&self, addr: u32) -> Result<u32, BridgeError>;
+
+    /// Write a single 32-bit word.
+    fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError>;
+
+    /// Read `len` bytes starting at `addr`.
+    fn burst_read(&self, _addr: u32, _len: u32) -> Result<Vec<u8>, BridgeError> {
+        Err(BridgeError::ProtocolNotSupported)
+    }
+
+    /// Write `data` starting at `addr`.
+    fn burst_write(&self, _addr: u32, _data: &[u8]) -> Result<(), BridgeError> {
+        Err(BridgeError::ProtocolNotSupported)
+    }
+}
@@ -28,23 +28,50 @@
 //! pages for more information.
 
 #[cfg(not(any(
+    feature = "can",
     feature = "pcie",
     feature = "uart",
     feature = "spi",
     feature = "ethernet",
-    feature = "usb"
+    feature = "usb",
+    feature = "i2c",
+    feature = "sim",
+    feature = "replay"
 )))]
-compile_error!("Must enable at least one bridge type: pcie, uart, spi, ethernet, or usb");
+compile_error!(
+    "Must enable at least one bridge type: pcie, uart, spi, ethernet, usb, i2c, sim, can, or replay"
+);
 
 pub(crate) mod bridges;
+mod driver;
+mod mapped;
+mod mux;
+mod record;
+mod stats;
+#[cfg(all(unix, feature = "sys"))]
+pub mod sys;
+#[cfg(any(feature = "uart", feature = "ethernet", feature = "usb"))]
+mod trace;
 
+#[doc(hidden)]
+#[cfg(feature = "can")]
+pub use bridges::can::CanBridgeInner;
 #[doc(hidden)]
 #[cfg(feature = "ethernet")]
 pub use bridges::ethernet::EthernetBridgeInner;
 #[doc(hidden)]
+#[cfg(feature = "i2c")]
+pub use bridges::i2c::I2cBridgeInner;
+#[doc(hidden)]
 #[cfg(feature = "pcie")]
 pub use bridges::pcie::PCIeBridgeInner;
 #[doc(hidden)]
+#[cfg(feature = "replay")]
+pub use bridges::replay::ReplayBridgeInner;
+#[doc(hidden)]
+#[cfg(feature = "sim")]
+pub use bridges::sim::SimBridgeInner;
+#[doc(hidden)]
 #[cfg(feature = "spi")]
 pub use bridges::spi::SpiBridgeInner;
 #[doc(hidden)]
@@ -54,21 +81,39 @@ pub use bridges::uart::UartBridgeInner;
 #[cfg(feature = "usb")]
 pub use bridges::usb::UsbBridgeInner;
 
+#[cfg(feature = "can")]
+pub use bridges::can::{CanBridge, CanByteOrder};
 #[cfg(feature = "ethernet")]
 pub use bridges::ethernet::{EthernetBridge, EthernetBridgeProtocol};
+#[cfg(feature = "i2c")]
+pub use bridges::i2c::I2cBridge;
 #[cfg(feature = "pcie")]
 pub use bridges::pcie::PCIeBridge;
+#[cfg(feature = "replay")]
+pub use bridges::replay::ReplayBridge;
+#[cfg(feature = "sim")]
+pub use bridges::sim::SimBridge;
 #[cfg(feature = "spi")]
 pub use bridges::spi::SpiBridge;
 #[cfg(feature = "uart")]
-pub use bridges::uart::UartBridge;
+pub use bridges::uart::{UartBridge, UartParity, UartStopBits};
 #[cfg(feature = "usb")]
-pub use bridges::usb::UsbBridge;
+pub use bridges::usb::{UsbBridge, UsbRecoveryStep};
 
-use log::debug;
+pub use driver::BridgeDriver;
+pub use mapped::{MappedBridge, MemoryRegion, RegionAccess, UnmappedAccessPolicy};
+pub use mux::{ClientStats, MuxBridge, RecentOperation};
+pub use stats::BridgeStats;
+#[cfg(any(feature = "uart", feature = "ethernet", feature = "usb"))]
+pub use trace::check_trace;
+
+use mux::MuxHandle;
+use stats::Operation;
+
+use tracing::{debug, error, trace_span};
 
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 #[doc(hidden)]
 #[derive(Clone)]
@@ -80,18 +125,35 @@ pub enum BridgeConfig {
     /// may be implemented.
     None,
 
+    /// Describes a connection to a device via a CAN bus.
+    #[cfg(feature = "can")]
+    CanBridge(CanBridge),
+
     /// Describes a bridge that connects via Ethernet, either via UDP
     /// (for direct hardware connections) or TCP (for connecting to
     /// other Wishbone servers such as `litex_server` or `wishbone-tool`)
     #[cfg(feature = "ethernet")]
     EthernetBridge(EthernetBridge),
 
+    /// Describes a connection to a device via I2C.
+    #[cfg(feature = "i2c")]
+    I2cBridge(I2cBridge),
+
     /// Describes a connection to a device via a PCIe bridge. Unlike most
     /// other bridges, a PCIe bridge does not provide a complete view of
     /// the memory space.
     #[cfg(feature = "pcie")]
     PCIeBridge(PCIeBridge),
 
+    /// Describes a bridge that plays back a recording rather than
+    /// talking to a device.
+    #[cfg(feature = "replay")]
+    ReplayBridge(ReplayBridge),
+
+    /// Describes a connection to a simulated device.
+    #[cfg(feature = "sim")]
+    SimBridge(SimBridge),
+
     /// Describes a connection to a device via SPI wires.
     #[cfg(feature = "spi")]
     SpiBridge(SpiBridge),
@@ -108,16 +170,25 @@ pub enum BridgeConfig {
 #[doc(hidden)]
 #[derive(Clone)]
 pub enum BridgeCore {
+    #[cfg(feature = "can")]
+    CanBridge(CanBridgeInner),
     #[cfg(feature = "ethernet")]
     EthernetBridge(EthernetBridgeInner),
+    #[cfg(feature = "i2c")]
+    I2cBridge(I2cBridgeInner),
     #[cfg(feature = "pcie")]
     PCIeBridge(PCIeBridgeInner),
+    #[cfg(feature = "replay")]
+    ReplayBridge(ReplayBridgeInner),
+    #[cfg(feature = "sim")]
+    SimBridge(SimBridgeInner),
     #[cfg(feature = "spi")]
     SpiBridge(SpiBridgeInner),
     #[cfg(feature = "uart")]
     UartBridge(UartBridgeInner),
     #[cfg(feature = "usb")]
     UsbBridge(UsbBridgeInner),
+    Driver(Arc<dyn BridgeDriver>),
 }
 
 /// Bridges represent the actual connection to the device. You must create
@@ -139,8 +210,65 @@ pub struct Bridge {
     /// Current offset for `Read` and `Write` operations
     offset: usize,
 
-    /// A Mutex to enforce only a single operation at a time
-    mutex: Arc<Mutex<()>>,
+    /// Bytes passed to `Write` that haven't been sent yet
+    write_buffer: WriteBuffer,
+
+    /// Queue that ensures only a single operation happens at a time, and
+    /// which client this handle counts towards
+    mux: MuxHandle,
+}
+
+/// Bytes passed to `Write` that don't yet fill a whole word. They're held
+/// until the rest of the word arrives, or until the writer is flushed.
+#[derive(Default)]
+struct WriteBuffer {
+    /// Address of the first byte in `data`
+    addr: usize,
+    data: Vec<u8>,
+}
+
+impl Clone for WriteBuffer {
+    /// The buffered bytes belong to the original `Bridge`, which sends them
+    /// when it's flushed. Giving them to the clone too would write them twice.
+    fn clone(&self) -> Self {
+        WriteBuffer::default()
+    }
+}
+
+/// Whether a bridge is able to talk to its device. Bridges that can tell
+/// when the device goes away and comes back report changes to this through
+/// a callback, such as the one set with `UsbBridge::on_state_change()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The device was found and opened
+    Connected,
+
+    /// The device went away, and the bridge is waiting for it to return
+    Disconnected,
+}
+
+/// A function that gets called with the new `ConnectionState` whenever
+/// it changes. It is called from the bridge's own thread.
+#[cfg(feature = "usb")]
+#[derive(Clone)]
+pub(crate) struct StateCallback(Arc<dyn Fn(ConnectionState) + Send + Sync>);
+
+#[cfg(feature = "usb")]
+impl StateCallback {
+    pub(crate) fn new<F: Fn(ConnectionState) + Send + Sync + 'static>(f: F) -> StateCallback {
+        StateCallback(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, state: ConnectionState) {
+        (self.0)(state)
+    }
+}
+
+#[cfg(feature = "usb")]
+impl ::std::fmt::Debug for StateCallback {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        write!(f, "StateCallback")
+    }
 }
 
 /// Errors that are generated while creating or using the Wishbone Bridge.
@@ -175,6 +303,16 @@ pub enum BridgeError {
     /// We got nothing back from the bridge
     #[allow(dead_code)]
     Timeout,
+
+    /// The address isn't part of any known memory region
+    UnmappedAddress(u32),
+
+    /// The memory region doesn't permit this kind of access
+    AccessDenied(u32),
+
+    /// A `ReplayBridge` was asked to do something other than what's next
+    /// in its recording
+    ReplayDiverged(String),
 }
 
 impl ::std::fmt::Display for BridgeError {
@@ -193,6 +331,9 @@ impl ::std::fmt::Display for BridgeError {
             InvalidAddress => write!(f, "bad address or path"),
             ProtocolNotSupported => write!(f, "protocol not supported on this platform"),
             Timeout => write!(f, "connection timed out"),
+            UnmappedAddress(addr) => write!(f, "address 0x{:08x} is not in any known region", addr),
+            AccessDenied(addr) => write!(f, "access to 0x{:08x} not permitted by its region", addr),
+            ReplayDiverged(msg) => write!(f, "replay diverged from the recording: {}", msg),
         }
     }
 }
@@ -204,72 +345,187 @@ impl std::convert::From<libusb_wishbone_tool::Error> for BridgeError {
     }
 }
 
+#[cfg(all(feature = "spi", target_os = "linux"))]
+impl std::convert::From<gpio_cdev::Error> for BridgeError {
+    fn from(e: gpio_cdev::Error) -> BridgeError {
+        BridgeError::IoError(io::Error::other(e))
+    }
+}
+
 impl std::convert::From<io::Error> for BridgeError {
     fn from(e: io::Error) -> BridgeError {
         BridgeError::IoError(e)
     }
 }
 
+/// Whether an operation that failed with `e` should give up, rather than
+/// try again
+fn is_fatal(e: &BridgeError) -> bool {
+    match e {
+        // The device has gone away, which shows up as `Pipe` on Windows and
+        // `Io` elsewhere
+        #[cfg(feature = "usb")]
+        BridgeError::USBError(libusb_wishbone_tool::Error::Pipe)
+        | BridgeError::USBError(libusb_wishbone_tool::Error::Io) => {
+            debug!("USB device disconnected, forcing early return");
+            true
+        }
+        // Retrying won't bring an out-of-range address into range, or a
+        // replay back onto its recording.
+        BridgeError::InvalidAddress | BridgeError::ReplayDiverged(_) => true,
+        _ => false,
+    }
+}
+
 impl Bridge {
     /// Create a new Bridge with the specified configuration. The new bridge
     /// starts out in a Disconnected state, but may be connecting in the background.
     /// To ensure the bridge is connected, so you must call `connect()`.
     pub(crate) fn new(bridge_cfg: BridgeConfig) -> Result<Bridge, BridgeError> {
-        let mutex = Arc::new(Mutex::new(()));
+        let mux = MuxHandle::new();
         match &bridge_cfg {
             BridgeConfig::None => Err(BridgeError::NoBridgeSpecified),
+            #[cfg(feature = "can")]
+            BridgeConfig::CanBridge(bridge_cfg) => Ok(Bridge {
+                mux,
+                core: BridgeCore::CanBridge(CanBridgeInner::new(bridge_cfg)?),
+                offset: 0,
+                write_buffer: WriteBuffer::default(),
+            }),
             #[cfg(feature = "ethernet")]
             BridgeConfig::EthernetBridge(bridge_cfg) => Ok(Bridge {
-                mutex,
+                mux,
                 core: BridgeCore::EthernetBridge(EthernetBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                write_buffer: WriteBuffer::default(),
+            }),
+            #[cfg(feature = "i2c")]
+            BridgeConfig::I2cBridge(bridge_cfg) => Ok(Bridge {
+                mux,
+                core: BridgeCore::I2cBridge(I2cBridgeInner::new(bridge_cfg)?),
+                offset: 0,
+                write_buffer: WriteBuffer::default(),
             }),
             #[cfg(feature = "pcie")]
             BridgeConfig::PCIeBridge(bridge_cfg) => Ok(Bridge {
-                mutex,
+                mux,
                 core: BridgeCore::PCIeBridge(PCIeBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                write_buffer: WriteBuffer::default(),
+            }),
+            #[cfg(feature = "replay")]
+            BridgeConfig::ReplayBridge(bridge_cfg) => Ok(Bridge {
+                mux,
+                core: BridgeCore::ReplayBridge(ReplayBridgeInner::new(bridge_cfg)?),
+                offset: 0,
+                write_buffer: WriteBuffer::default(),
+            }),
+            #[cfg(feature = "sim")]
+            BridgeConfig::SimBridge(bridge_cfg) => Ok(Bridge {
+                mux,
+                core: BridgeCore::SimBridge(SimBridgeInner::new(bridge_cfg)?),
+                offset: 0,
+                write_buffer: WriteBuffer::default(),
             }),
             #[cfg(feature = "spi")]
             BridgeConfig::SpiBridge(bridge_cfg) => Ok(Bridge {
-                mutex,
+                mux,
                 core: BridgeCore::SpiBridge(SpiBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                write_buffer: WriteBuffer::default(),
             }),
             #[cfg(feature = "uart")]
             BridgeConfig::UartBridge(bridge_cfg) => Ok(Bridge {
-                mutex,
+                mux,
                 core: BridgeCore::UartBridge(UartBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                write_buffer: WriteBuffer::default(),
             }),
             #[cfg(feature = "usb")]
             BridgeConfig::UsbBridge(bridge_cfg) => Ok(Bridge {
-                mutex,
+                mux,
                 core: BridgeCore::UsbBridge(UsbBridgeInner::new(bridge_cfg)?),
                 offset: 0,
+                write_buffer: WriteBuffer::default(),
             }),
         }
     }
 
+    /// Create a `Bridge` that talks to its device through `driver`, for
+    /// transports that aren't built into this crate. See `BridgeDriver` for
+    /// an example.
+    pub fn from_driver(driver: Box<dyn BridgeDriver>) -> Bridge {
+        Bridge {
+            mux: MuxHandle::new(),
+            core: BridgeCore::Driver(Arc::from(driver)),
+            offset: 0,
+            write_buffer: WriteBuffer::default(),
+        }
+    }
+
     /// Ensure the bridge is connected. Many bridges support performing connection
     /// in the background, so calling `connect()` ensures that the bridge has been
     /// established.
     pub fn connect(&self) -> Result<(), BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
+        let _span = trace_span!("connect").entered();
+        let _turn = self.mux.acquire(Operation::Connect);
         match &self.core {
+            #[cfg(feature = "can")]
+            BridgeCore::CanBridge(b) => b.connect(),
             #[cfg(feature = "ethernet")]
             BridgeCore::EthernetBridge(b) => b.connect(),
+            #[cfg(feature = "i2c")]
+            BridgeCore::I2cBridge(b) => b.connect(),
             #[cfg(feature = "pcie")]
             BridgeCore::PCIeBridge(b) => b.connect(),
+            #[cfg(feature = "replay")]
+            BridgeCore::ReplayBridge(b) => b.connect(),
+            #[cfg(feature = "sim")]
+            BridgeCore::SimBridge(b) => b.connect(),
             #[cfg(feature = "spi")]
             BridgeCore::SpiBridge(b) => b.connect(),
             #[cfg(feature = "uart")]
             BridgeCore::UartBridge(b) => b.connect(),
             #[cfg(feature = "usb")]
             BridgeCore::UsbBridge(b) => b.connect(),
+            BridgeCore::Driver(d) => d.connect(),
         }
     }
 
+    /// Counters for everything done over this bridge so far, by this handle
+    /// and every other handle sharing the same connection. Useful for
+    /// working out why a link is slow.
+    /// ```no_run
+    /// use wishbone_bridge::UartBridge;
+    /// let bridge = UartBridge::new("/dev/ttyUSB0").unwrap().create().unwrap();
+    /// bridge.peek(0).unwrap();
+    /// println!("{}", bridge.stats());
+    /// ```
+    pub fn stats(&self) -> BridgeStats {
+        self.mux.counters().snapshot()
+    }
+
+    /// Append every peek, poke, and burst made over this bridge, by any
+    /// handle sharing the connection, to the CSV file at `path`. Each line
+    /// gives the time, operation, address, data, and whether it succeeded,
+    /// and is written out straight away so that nothing is lost if the
+    /// program dies. Recording to a new file replaces the old one.
+    /// ```no_run
+    /// use wishbone_bridge::UartBridge;
+    /// let bridge = UartBridge::new("/dev/ttyUSB0").unwrap().create().unwrap();
+    /// bridge.record_to("session.csv").unwrap();
+    /// bridge.poke(0x1000_0000, 0x1234_5678).unwrap();
+    /// bridge.stop_recording();
+    /// ```
+    pub fn record_to<P: AsRef<std::path::Path>>(&self, path: P) -> Result<(), BridgeError> {
+        self.mux.record_to(path.as_ref())
+    }
+
+    /// Stop the recording started by `record_to()`, if there is one.
+    pub fn stop_recording(&self) {
+        self.mux.stop_recording()
+    }
+
     /// Read a single 32-bit value from the target device.
     /// ```no_run
     /// use wishbone_bridge::UsbBridge;
@@ -278,30 +534,41 @@ impl Bridge {
     /// println!("The value at address 0 is: {:08x}", bridge.peek(0).unwrap());
     /// ```
     pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
+        let _span = trace_span!("peek", addr).entered();
+        let turn = self.mux.acquire(Operation::Peek);
         loop {
             let result = match &self.core {
+                #[cfg(feature = "can")]
+                BridgeCore::CanBridge(b) => b.peek(addr),
                 #[cfg(feature = "ethernet")]
                 BridgeCore::EthernetBridge(b) => b.peek(addr),
+                #[cfg(feature = "i2c")]
+                BridgeCore::I2cBridge(b) => b.peek(addr),
                 #[cfg(feature = "pcie")]
                 BridgeCore::PCIeBridge(b) => b.peek(addr),
+                #[cfg(feature = "replay")]
+                BridgeCore::ReplayBridge(b) => b.peek(addr),
+                #[cfg(feature = "sim")]
+                BridgeCore::SimBridge(b) => b.peek(addr),
                 #[cfg(feature = "spi")]
                 BridgeCore::SpiBridge(b) => b.peek(addr),
                 #[cfg(feature = "uart")]
                 BridgeCore::UartBridge(b) => b.peek(addr),
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.peek(addr),
+                BridgeCore::Driver(d) => d.peek(addr),
             };
             #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
-                #[cfg(feature = "usb")]
-                if let BridgeError::USBError(libusb_wishbone_tool::Error::Pipe) = e {
-                    debug!("USB device disconnected, forcing early return");
+                if is_fatal(&e) {
+                    turn.failed(addr, &e);
                     return Err(e);
                 }
+                turn.retry(addr, &e);
                 debug!("Peek failed, trying again: {:?}", e);
-            } else {
-                return result;
+            } else if let Ok(value) = result {
+                turn.read(addr, &value.to_le_bytes());
+                return Ok(value);
             }
         }
     }
@@ -315,95 +582,187 @@ impl Bridge {
     /// bridge.poke(0, 0x12345678).unwrap();
     /// ```
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
+        let _span = trace_span!("poke", addr, value).entered();
+        let turn = self.mux.acquire(Operation::Poke);
         loop {
             let result = match &self.core {
+                #[cfg(feature = "can")]
+                BridgeCore::CanBridge(b) => b.poke(addr, value),
                 #[cfg(feature = "ethernet")]
                 BridgeCore::EthernetBridge(b) => b.poke(addr, value),
+                #[cfg(feature = "i2c")]
+                BridgeCore::I2cBridge(b) => b.poke(addr, value),
                 #[cfg(feature = "pcie")]
                 BridgeCore::PCIeBridge(b) => b.poke(addr, value),
+                #[cfg(feature = "replay")]
+                BridgeCore::ReplayBridge(b) => b.poke(addr, value),
+                #[cfg(feature = "sim")]
+                BridgeCore::SimBridge(b) => b.poke(addr, value),
                 #[cfg(feature = "spi")]
                 BridgeCore::SpiBridge(b) => b.poke(addr, value),
                 #[cfg(feature = "uart")]
                 BridgeCore::UartBridge(b) => b.poke(addr, value),
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.poke(addr, value),
+                BridgeCore::Driver(d) => d.poke(addr, value),
             };
             #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
-                match e {
-                    #[cfg(feature = "usb")]
-                    BridgeError::USBError(libusb_wishbone_tool::Error::Pipe) => {
-                        debug!("USB device disconnected (Windows), forcing early return");
-                        return Err(e);
-                    }
-                    #[cfg(feature = "usb")]
-                    BridgeError::USBError(libusb_wishbone_tool::Error::Io) => {
-                        debug!("USB device disconnected (Posix), forcing early return");
-                        return Err(e);
-                    }
-                    _ => {}
+                if is_fatal(&e) {
+                    turn.failed(addr, &e);
+                    return Err(e);
                 }
+                turn.retry(addr, &e);
                 debug!("Poke failed, trying again: {:?}", e);
             } else {
+                turn.wrote(addr, &value.to_le_bytes());
                 return result;
             }
         }
     }
 
+    /// Write `data` to `addr`, neither of which need be word-aligned. Words
+    /// at either end that are only partly covered are read first, so that
+    /// the bytes outside of `data` are preserved.
+    fn write_unaligned(&self, addr: usize, data: &[u8]) -> Result<(), BridgeError> {
+        let mut addr = addr;
+        let mut data = data;
+
+        let lead = addr & 3;
+        if lead != 0 || data.len() < 4 {
+            let count = (4 - lead).min(data.len());
+            self.merge_word(addr - lead, lead, &data[..count])?;
+            addr += count;
+            data = &data[count..];
+        }
+
+        let whole = data.len() & !3;
+        if whole > 0 {
+            self.write_words(addr as u32, &data[..whole])?;
+            addr += whole;
+            data = &data[whole..];
+        }
+
+        if !data.is_empty() {
+            self.merge_word(addr, 0, data)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the bytes of the word at `addr` starting at `start` with
+    /// `bytes`, leaving the rest of the word as it was.
+    fn merge_word(&self, addr: usize, start: usize, bytes: &[u8]) -> Result<(), BridgeError> {
+        let mut word = self.peek(addr as u32)?.to_le_bytes();
+        word[start..start + bytes.len()].copy_from_slice(bytes);
+        self.poke(addr as u32, u32::from_le_bytes(word))
+    }
+
+    /// Write whole words, as a burst if the bridge supports it and one
+    /// word at a time if it doesn't.
+    fn write_words(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        match self.burst_write(addr, data) {
+            Err(BridgeError::ProtocolNotSupported) => {
+                for (word, offset) in data.chunks_exact(4).zip((addr..).step_by(4)) {
+                    self.poke(
+                        offset,
+                        u32::from_le_bytes([word[0], word[1], word[2], word[3]]),
+                    )?;
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
     pub fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
+        let _span = trace_span!("burst_read", addr, length).entered();
+        let turn = self.mux.acquire(Operation::BurstRead);
         loop {
+            // Unused if none of the enabled bridges support bursts
+            #[allow(unused_variables)]
             let result = match &self.core {
+                #[cfg(feature = "can")]
+                BridgeCore::CanBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "ethernet")]
-                BridgeCore::EthernetBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
+                BridgeCore::EthernetBridge(b) => b.burst_read(addr, length),
+                #[cfg(feature = "i2c")]
+                BridgeCore::I2cBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "pcie")]
-                BridgeCore::PCIeBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
+                BridgeCore::PCIeBridge(b) => b.burst_read(addr, length),
+                #[cfg(feature = "replay")]
+                BridgeCore::ReplayBridge(b) => b.burst_read(addr, length),
+                #[cfg(feature = "sim")]
+                BridgeCore::SimBridge(b) => b.burst_read(addr, length),
                 #[cfg(feature = "spi")]
                 BridgeCore::SpiBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "uart")]
                 BridgeCore::UartBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.burst_read(addr, length),
+                BridgeCore::Driver(d) => match d.burst_read(addr, length) {
+                    Err(BridgeError::ProtocolNotSupported) => {
+                        return Err(BridgeError::ProtocolNotSupported)
+                    }
+                    result => result,
+                },
             };
             #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
-                #[cfg(feature = "usb")]
-                if let BridgeError::USBError(libusb_wishbone_tool::Error::Pipe) = e {
-                    debug!("USB device disconnected, forcing early return");
+                if is_fatal(&e) {
+                    turn.failed(addr, &e);
                     return Err(e);
                 }
-                debug!("Peek failed, trying again: {:?}", e);
-            } else {
-                return result;
+                turn.retry(addr, &e);
+                debug!("Burst read failed, trying again: {:?}", e);
+            } else if let Ok(data) = result {
+                turn.read(addr, &data);
+                return Ok(data);
             }
         }
     }
 
-    pub fn burst_write(&self, addr: u32, data: &Vec<u8>) -> Result<(), BridgeError> {
-        let _mtx = self.mutex.lock().unwrap();
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        let _span = trace_span!("burst_write", addr, length = data.len()).entered();
+        let turn = self.mux.acquire(Operation::BurstWrite);
         loop {
+            // Unused if none of the enabled bridges support bursts
+            #[allow(unused_variables)]
             let result = match &self.core {
+                #[cfg(feature = "can")]
+                BridgeCore::CanBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "ethernet")]
-                BridgeCore::EthernetBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
+                BridgeCore::EthernetBridge(b) => b.burst_write(addr, data),
+                #[cfg(feature = "i2c")]
+                BridgeCore::I2cBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "pcie")]
-                BridgeCore::PCIeBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
+                BridgeCore::PCIeBridge(b) => b.burst_write(addr, data),
+                #[cfg(feature = "replay")]
+                BridgeCore::ReplayBridge(b) => b.burst_write(addr, data),
+                #[cfg(feature = "sim")]
+                BridgeCore::SimBridge(b) => b.burst_write(addr, data),
                 #[cfg(feature = "spi")]
                 BridgeCore::SpiBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "uart")]
                 BridgeCore::UartBridge(_b) => return Err(BridgeError::ProtocolNotSupported),
                 #[cfg(feature = "usb")]
                 BridgeCore::UsbBridge(b) => b.burst_write(addr, data),
+                BridgeCore::Driver(d) => match d.burst_write(addr, data) {
+                    Err(BridgeError::ProtocolNotSupported) => {
+                        return Err(BridgeError::ProtocolNotSupported)
+                    }
+                    result => result,
+                },
             };
             #[allow(unreachable_code)] // Only possible when no features are enabled (compile error)
             if let Err(e) = result {
-                #[cfg(feature = "usb")]
-                if let BridgeError::USBError(libusb_wishbone_tool::Error::Pipe) = e {
-                    debug!("USB device disconnected, forcing early return");
+                if is_fatal(&e) {
+                    turn.failed(addr, &e);
                     return Err(e);
                 }
-                debug!("Peek failed, trying again: {:?}", e);
+                turn.retry(addr, &e);
+                debug!("Burst write failed, trying again: {:?}", e);
             } else {
+                turn.wrote(addr, data);
                 return result;
             }
         }
@@ -412,8 +771,17 @@ impl Bridge {
 
 impl std::io::Read for Bridge {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let _mtx = self.mutex.lock().unwrap();
+        // Make sure anything written through this cursor is read back.
+        std::io::Write::flush(self)?;
+        let turn = self.mux.acquire(Operation::BurstRead);
         let addr = self.offset as _;
+        #[cfg(any(
+            feature = "ethernet",
+            feature = "pcie",
+            feature = "replay",
+            feature = "sim",
+            feature = "usb"
+        ))]
         use std::convert::TryInto;
         use std::io::{Error, ErrorKind};
 
@@ -427,12 +795,26 @@ impl std::io::Read for Bridge {
         }
 
         let copied = match &self.core {
+            #[cfg(feature = "can")]
+            BridgeCore::CanBridge(b) => b.peek(addr).map(|v| fill_array(&v.to_le_bytes(), buf)),
             #[cfg(feature = "ethernet")]
-            BridgeCore::EthernetBridge(b) => {
-                b.peek(addr).map(|v| fill_array(&v.to_le_bytes(), buf))
-            }
+            BridgeCore::EthernetBridge(b) => b
+                .burst_read(addr, buf.len().try_into().unwrap())
+                .map(|v| fill_array(&v, buf)),
+            #[cfg(feature = "i2c")]
+            BridgeCore::I2cBridge(b) => b.peek(addr).map(|v| fill_array(&v.to_le_bytes(), buf)),
             #[cfg(feature = "pcie")]
-            BridgeCore::PCIeBridge(b) => b.peek(addr).map(|v| fill_array(&v.to_le_bytes(), buf)),
+            BridgeCore::PCIeBridge(b) => b
+                .burst_read(addr, buf.len().try_into().unwrap())
+                .map(|v| fill_array(&v, buf)),
+            #[cfg(feature = "replay")]
+            BridgeCore::ReplayBridge(b) => b
+                .burst_read(addr, buf.len().try_into().unwrap())
+                .map(|v| fill_array(&v, buf)),
+            #[cfg(feature = "sim")]
+            BridgeCore::SimBridge(b) => b
+                .burst_read(addr, buf.len().try_into().unwrap())
+                .map(|v| fill_array(&v, buf)),
             #[cfg(feature = "spi")]
             BridgeCore::SpiBridge(b) => b.peek(addr).map(|v| fill_array(&v.to_le_bytes(), buf)),
             #[cfg(feature = "uart")]
@@ -441,8 +823,18 @@ impl std::io::Read for Bridge {
             BridgeCore::UsbBridge(b) => b
                 .burst_read(addr, buf.len().try_into().unwrap())
                 .map(|v| fill_array(&v, buf)),
+            BridgeCore::Driver(d) => match d.burst_read(addr, buf.len() as u32) {
+                Err(BridgeError::ProtocolNotSupported) => {
+                    d.peek(addr).map(|v| fill_array(&v.to_le_bytes(), buf))
+                }
+                result => result.map(|v| fill_array(&v, buf)),
+            },
         }
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        .map_err(|e| {
+            turn.failed(addr, &e);
+            Error::new(ErrorKind::Other, e.to_string())
+        })?;
+        turn.read(addr, &buf[..copied]);
         self.offset += copied;
         Ok(copied)
     }
@@ -452,6 +844,7 @@ impl std::io::Seek for Bridge {
     fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
         use std::convert::TryInto;
         use std::io::{Error, ErrorKind};
+        std::io::Write::flush(self)?;
         let new_offset = match pos {
             std::io::SeekFrom::End(_) => Err(Error::new(
                 ErrorKind::AddrNotAvailable,
@@ -466,46 +859,74 @@ impl std::io::Seek for Bridge {
             }
             std::io::SeekFrom::Start(offset) => offset as usize,
         };
-        self.offset += new_offset;
+        self.offset = new_offset;
         Ok(self.offset.try_into().unwrap())
     }
 }
 
+/// Writes through a `Bridge` may be any length and start at any address.
+/// Whole words are sent as soon as they're written, using a burst if the
+/// bridge supports it. A word that's only partly covered is read from the
+/// device first so that its other bytes are kept. The bytes at the end of a
+/// write that don't fill a word are held until the next write supplies the
+/// rest, or until the writer is flushed, seeked, read from, or dropped.
+///
+/// ```no_run
+/// use wishbone_bridge::UsbBridge;
+/// use std::io::{Seek, SeekFrom, Write};
+/// let mut bridge = UsbBridge::new().pid(0x5bf0).create().unwrap();
+/// let mut file = std::fs::File::open("firmware.bin").unwrap();
+/// bridge.seek(SeekFrom::Start(0x1000_0000)).unwrap();
+/// std::io::copy(&mut file, &mut bridge).unwrap();
+/// bridge.flush().unwrap();
+/// ```
 impl std::io::Write for Bridge {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        use std::convert::TryInto;
-        use std::io::{Error, ErrorKind};
-        let _mtx = self.mutex.lock().unwrap();
-
-        fn slice_to_u32(buf: &[u8]) -> std::io::Result<u32> {
-            if buf.len() < 3 {
-                Err(Error::new(
-                    ErrorKind::InvalidData,
-                    "data not a multiple of 4 bytes",
-                ))?;
-            }
-            Ok(u32::from_le_bytes(buf[0..3].try_into().unwrap()))
+        // Bytes that don't follow on from the buffered ones can't share a
+        // word with them.
+        if !self.write_buffer.data.is_empty()
+            && self.write_buffer.addr + self.write_buffer.data.len() != self.offset
+        {
+            self.flush()?;
         }
-
-        let addr = self.offset as _;
-        let bytes_written = match &self.core {
-            #[cfg(feature = "ethernet")]
-            BridgeCore::EthernetBridge(_) => self.poke(addr, slice_to_u32(buf)?).map(|_| 4),
-            #[cfg(feature = "pcie")]
-            BridgeCore::PCIeBridge(_) => self.poke(addr, slice_to_u32(buf)?).map(|_| 4),
-            #[cfg(feature = "spi")]
-            BridgeCore::SpiBridge(_) => self.poke(addr, slice_to_u32(buf)?).map(|_| 4),
-            #[cfg(feature = "uart")]
-            BridgeCore::UartBridge(_) => self.poke(addr, slice_to_u32(buf)?).map(|_| 4),
-            #[cfg(feature = "usb")]
-            BridgeCore::UsbBridge(b) => b.burst_write(addr, buf).map(|_| buf.len()),
+        if self.write_buffer.data.is_empty() {
+            self.write_buffer.addr = self.offset;
+        }
+        self.write_buffer.data.extend_from_slice(buf);
+        self.offset += buf.len();
+
+        // Send everything up to the last word boundary.
+        let addr = self.write_buffer.addr;
+        let end = addr + self.write_buffer.data.len();
+        let split = (end & !3).saturating_sub(addr);
+        if split > 0 {
+            self.write_unaligned(addr, &self.write_buffer.data[..split])
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            self.write_buffer.data.drain(..split);
+            self.write_buffer.addr += split;
         }
-        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
-        self.offset += bytes_written;
-        Ok(bytes_written)
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
+        if self.write_buffer.data.is_empty() {
+            return Ok(());
+        }
+        self.write_unaligned(self.write_buffer.addr, &self.write_buffer.data)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.write_buffer.data.clear();
         Ok(())
     }
 }
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        // Don't lose the end of a write that was never flushed.
+        if let Err(e) = std::io::Write::flush(self) {
+            error!(
+                "unable to write buffered bytes while dropping bridge: {}",
+                e
+            );
+        }
+    }
+}
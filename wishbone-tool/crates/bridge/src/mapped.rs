@@ -0,0 +1,261 @@
+use crate::{Bridge, BridgeError};
+use tracing::warn;
+
+/// Describes what sort of accesses are permitted within a `MemoryRegion`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionAccess {
+    /// The region may only be read from
+    ReadOnly,
+
+    /// The region may only be written to
+    WriteOnly,
+
+    /// The region may be both read from and written to
+    ReadWrite,
+}
+
+impl RegionAccess {
+    /// Parse the access type used in LiteX `csr.csv` files, such as `rw`,
+    /// `ro`, or `wo`. Anything that isn't recognized is treated as `ReadWrite`.
+    pub fn from_csr_mode(mode: &str) -> RegionAccess {
+        match mode.trim() {
+            "ro" => RegionAccess::ReadOnly,
+            "wo" => RegionAccess::WriteOnly,
+            _ => RegionAccess::ReadWrite,
+        }
+    }
+
+    pub fn readable(self) -> bool {
+        self != RegionAccess::WriteOnly
+    }
+
+    pub fn writable(self) -> bool {
+        self != RegionAccess::ReadOnly
+    }
+}
+
+/// A contiguous range of the Wishbone address space that is known to
+/// be safe to access.
+#[derive(Clone, Debug)]
+pub struct MemoryRegion {
+    /// Human-readable name of this region, e.g. `sram` or `ctrl_scratch`
+    pub name: String,
+
+    /// First address of the region
+    pub base: u32,
+
+    /// Length of the region, in bytes
+    pub size: u32,
+
+    /// What sort of accesses are allowed
+    pub access: RegionAccess,
+
+    /// Reading from this region has side effects, such as popping a value
+    /// from a FIFO. Such regions are only ever read when explicitly asked.
+    pub read_sensitive: bool,
+}
+
+impl MemoryRegion {
+    pub fn new(name: &str, base: u32, size: u32, access: RegionAccess) -> MemoryRegion {
+        MemoryRegion {
+            name: name.to_owned(),
+            base,
+            size,
+            access,
+            read_sensitive: false,
+        }
+    }
+
+    /// Returns `true` if the range `addr..addr+length` lies entirely
+    /// within this region.
+    pub fn contains(&self, addr: u32, length: u32) -> bool {
+        let end = addr as u64 + length as u64;
+        addr >= self.base && end <= self.base as u64 + self.size as u64
+    }
+
+    /// Returns `true` if any part of `addr..addr+length` lies within this region.
+    pub fn overlaps(&self, addr: u32, length: u32) -> bool {
+        let end = addr as u64 + length as u64;
+        (addr as u64) < self.base as u64 + self.size as u64 && end > self.base as u64
+    }
+}
+
+/// What to do when an access falls outside of every known region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnmappedAccessPolicy {
+    /// Refuse to perform the access and return an error
+    Reject,
+
+    /// Print a warning, but perform the access anyway
+    Warn,
+
+    /// Perform the access without any checks
+    Allow,
+}
+
+/// A `MappedBridge` wraps a `Bridge` along with a memory map, and checks
+/// every access against that map before passing it on. Touching an address
+/// that doesn't decode to anything can wedge the Wishbone bus on many SoCs,
+/// so this provides a safety net when exploring a device by hand.
+///
+/// ```no_run
+/// use wishbone_bridge::{MappedBridge, MemoryRegion, RegionAccess, UsbBridge};
+/// let bridge = UsbBridge::new().pid(0x5bf0).create().unwrap();
+/// let mut mapped = MappedBridge::new(bridge);
+/// mapped.region(MemoryRegion::new("sram", 0x1000_0000, 0x2_0000, RegionAccess::ReadWrite));
+/// // This is fine
+/// mapped.poke(0x1000_0000, 0x1234_5678).unwrap();
+/// // This is outside of the map, and will return an error
+/// assert!(mapped.peek(0x4000_0000).is_err());
+/// ```
+#[derive(Clone)]
+pub struct MappedBridge {
+    bridge: Bridge,
+    regions: Vec<MemoryRegion>,
+    policy: UnmappedAccessPolicy,
+}
+
+impl MappedBridge {
+    /// Wrap an existing `Bridge`. The map starts out empty, and the
+    /// policy defaults to `UnmappedAccessPolicy::Reject`.
+    pub fn new(bridge: Bridge) -> MappedBridge {
+        MappedBridge {
+            bridge,
+            regions: vec![],
+            policy: UnmappedAccessPolicy::Reject,
+        }
+    }
+
+    /// Add a region to the memory map.
+    pub fn region(&mut self, region: MemoryRegion) -> &mut MappedBridge {
+        self.regions.push(region);
+        self
+    }
+
+    /// Add several regions to the memory map at once.
+    pub fn regions<I: IntoIterator<Item = MemoryRegion>>(
+        &mut self,
+        regions: I,
+    ) -> &mut MappedBridge {
+        self.regions.extend(regions);
+        self
+    }
+
+    /// Determine what happens when an access falls outside of the map.
+    pub fn policy(&mut self, policy: UnmappedAccessPolicy) -> &mut MappedBridge {
+        self.policy = policy;
+        self
+    }
+
+    /// Return the region that fully contains `addr..addr+length`, if any.
+    pub fn find_region(&self, addr: u32, length: u32) -> Option<&MemoryRegion> {
+        self.regions.iter().find(|r| r.contains(addr, length))
+    }
+
+    /// Returns `true` if reading any part of `addr..addr+length` would
+    /// touch a read-sensitive region.
+    pub fn is_read_sensitive(&self, addr: u32, length: u32) -> bool {
+        self.regions
+            .iter()
+            .any(|r| r.read_sensitive && r.overlaps(addr, length))
+    }
+
+    /// Access the underlying `Bridge`, bypassing all checks.
+    pub fn bridge(&self) -> &Bridge {
+        &self.bridge
+    }
+
+    fn check(&self, addr: u32, length: u32, write: bool) -> Result<(), BridgeError> {
+        if self.policy == UnmappedAccessPolicy::Allow {
+            return Ok(());
+        }
+        let err = match self.find_region(addr, length) {
+            Some(region) => {
+                if (write && region.access.writable()) || (!write && region.access.readable()) {
+                    return Ok(());
+                }
+                BridgeError::AccessDenied(addr)
+            }
+            None => BridgeError::UnmappedAddress(addr),
+        };
+        if self.policy == UnmappedAccessPolicy::Warn {
+            warn!("{} -- continuing anyway", err);
+            return Ok(());
+        }
+        Err(err)
+    }
+
+    /// Ensure the bridge is connected.
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        self.bridge.connect()
+    }
+
+    /// Read a single 32-bit value, after checking it against the memory map.
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        self.check(addr, 4, false)?;
+        self.bridge.peek(addr)
+    }
+
+    /// Write a single 32-bit value, after checking it against the memory map.
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.check(addr, 4, true)?;
+        self.bridge.poke(addr, value)
+    }
+
+    /// Perform a burst read, after checking the entire range against the memory map.
+    pub fn burst_read(&self, addr: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        self.check(addr, length, false)?;
+        self.bridge.burst_read(addr, length)
+    }
+
+    /// Read a range of memory for inspection, without touching any
+    /// read-sensitive regions. Bytes that were skipped are returned as `None`.
+    /// This should be used whenever memory is read implicitly, such as when
+    /// dumping a range, so that e.g. UART bytes aren't lost.
+    pub fn burst_read_skip_sensitive(
+        &self,
+        addr: u32,
+        length: u32,
+    ) -> Result<Vec<Option<u8>>, BridgeError> {
+        let mut sensitive: Vec<&MemoryRegion> = self
+            .regions
+            .iter()
+            .filter(|r| r.read_sensitive && r.overlaps(addr, length))
+            .collect();
+        if sensitive.is_empty() {
+            return Ok(self
+                .burst_read(addr, length)?
+                .into_iter()
+                .map(Some)
+                .collect());
+        }
+        sensitive.sort_by_key(|r| r.base);
+
+        let end = addr as u64 + length as u64;
+        let mut result = Vec::with_capacity(length as usize);
+        let mut offset = addr as u64;
+        for region in sensitive {
+            let region_start = (region.base as u64).max(offset);
+            let region_end = (region.base as u64 + region.size as u64).min(end);
+            if region_start > offset {
+                let chunk = self.burst_read(offset as u32, (region_start - offset) as u32)?;
+                result.extend(chunk.into_iter().map(Some));
+            }
+            if region_end > region_start {
+                result.extend((region_start..region_end).map(|_| None));
+                offset = region_end;
+            }
+        }
+        if end > offset {
+            let chunk = self.burst_read(offset as u32, (end - offset) as u32)?;
+            result.extend(chunk.into_iter().map(Some));
+        }
+        Ok(result)
+    }
+
+    /// Perform a burst write, after checking the entire range against the memory map.
+    pub fn burst_write(&self, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        self.check(addr, data.len() as u32, true)?;
+        self.bridge.burst_write(addr, data)
+    }
+}
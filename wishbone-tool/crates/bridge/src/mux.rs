@@ -0,0 +1,422 @@
+use crate::record::Recorder;
+use crate::stats::{BridgeCounters, Operation};
+use crate::{Bridge, BridgeError};
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::error;
+
+/// How many operations `Bridge::recent_operations()` remembers
+const HISTORY_LENGTH: usize = 64;
+
+/// Shares one physical bridge between several independent clients, such as
+/// a GDB server, a terminal, and a Wishbone server all talking over the same
+/// UART.
+///
+/// Each client gets its own `Bridge` handle from `client()`. Handles are
+/// cheap to clone, and clones count towards the same client. Operations are
+/// served strictly in the order they were requested, so a client that issues
+/// requests back to back goes to the back of the queue each time rather than
+/// starving everyone else.
+///
+/// ```no_run
+/// use wishbone_bridge::{MuxBridge, UartBridge};
+/// let bridge = UartBridge::new("/dev/ttyUSB0").unwrap().create().unwrap();
+/// let mux = MuxBridge::new(bridge);
+/// let gdb = mux.client("gdb");
+/// let terminal = mux.client("terminal");
+/// std::thread::spawn(move || gdb.peek(0x1000_0000));
+/// terminal.peek(0xe000_1800).unwrap();
+/// for client in mux.stats() {
+///     println!("{}: {} operations", client.name, client.operations);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MuxBridge {
+    bridge: Bridge,
+}
+
+/// How much use a single client has made of a shared bridge.
+#[derive(Clone, Debug)]
+pub struct ClientStats {
+    /// The name the client was given when it was created
+    pub name: String,
+
+    /// Number of operations that have completed, including ones that failed
+    pub operations: u64,
+
+    /// Bytes returned by reads that succeeded
+    pub bytes_read: u64,
+
+    /// Bytes sent by writes that succeeded
+    pub bytes_written: u64,
+
+    /// Total time spent queued behind other operations
+    pub waiting: Duration,
+
+    /// Total time spent with the bridge to itself
+    pub busy: Duration,
+}
+
+/// One of the last few operations made over a bridge, as returned by
+/// `Bridge::recent_operations()`.
+#[derive(Clone, Debug)]
+pub struct RecentOperation {
+    /// Goes up by one for each operation made over the bridge, so that
+    /// operations already seen can be told apart from new ones
+    pub sequence: u64,
+
+    /// Name of the client that made the operation
+    pub client: String,
+
+    /// Which operation it was: `peek`, `poke`, `burst_read`, or `burst_write`
+    pub operation: &'static str,
+
+    /// Address the operation started at
+    pub addr: u32,
+
+    /// Bytes read or written, or zero if the operation failed
+    pub length: usize,
+
+    /// Whether the operation gave up and returned an error
+    pub failed: bool,
+
+    /// How long ago the operation finished
+    pub age: Duration,
+}
+
+impl std::fmt::Display for RecentOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} of 0x{:08x}",
+            self.client, self.operation, self.addr
+        )?;
+        if self.failed {
+            write!(f, " (failed)")?;
+        } else {
+            write!(f, " ({} bytes)", self.length)?;
+        }
+        write!(f, " {:.1} ms ago", self.age.as_secs_f64() * 1000.0)
+    }
+}
+
+impl MuxBridge {
+    /// Share `bridge`. Any handles that were cloned from it beforehand are
+    /// already part of the same queue, and show up in `stats()` as a client
+    /// called `bridge`.
+    pub fn new(bridge: Bridge) -> MuxBridge {
+        MuxBridge { bridge }
+    }
+
+    /// Create a handle for a new client called `name`.
+    pub fn client(&self, name: &str) -> Bridge {
+        let mut bridge = self.bridge.clone();
+        bridge.mux = self.bridge.mux.client(name);
+        bridge
+    }
+
+    /// Statistics for every client created so far, in the order they were
+    /// created.
+    pub fn stats(&self) -> Vec<ClientStats> {
+        self.bridge.mux.arbiter.stats()
+    }
+}
+
+impl Bridge {
+    /// The `MuxBridge` that this bridge is part of. Every bridge is part
+    /// of one, even if it has never been shared.
+    pub fn mux(&self) -> MuxBridge {
+        MuxBridge::new(self.clone())
+    }
+
+    /// The last few operations made over this bridge by any of its clients,
+    /// oldest first. Connecting and attempts that were retried aren't
+    /// included. Useful for working out what the host was doing when the
+    /// device noticed something go wrong.
+    /// ```no_run
+    /// use wishbone_bridge::UartBridge;
+    /// let bridge = UartBridge::new("/dev/ttyUSB0").unwrap().create().unwrap();
+    /// bridge.poke(0x1000_0000, 0x1234_5678).unwrap();
+    /// for op in bridge.recent_operations() {
+    ///     println!("{}", op);
+    /// }
+    /// ```
+    pub fn recent_operations(&self) -> Vec<RecentOperation> {
+        let history = self.mux.arbiter.history.lock().unwrap();
+        history
+            .entries
+            .iter()
+            .map(|entry| RecentOperation {
+                sequence: entry.sequence,
+                client: entry.client.name.clone(),
+                operation: entry.op.name(),
+                addr: entry.addr,
+                length: entry.length,
+                failed: entry.failed,
+                age: entry.finished.elapsed(),
+            })
+            .collect()
+    }
+}
+
+/// The queue that every handle to a bridge waits in.
+struct Arbiter {
+    tickets: Mutex<Tickets>,
+    turn: Condvar,
+    clients: Mutex<Vec<Arc<ClientCounters>>>,
+    counters: BridgeCounters,
+    recorder: Mutex<Option<Recorder>>,
+    history: Mutex<History>,
+}
+
+/// The operations behind `Bridge::recent_operations()`
+#[derive(Default)]
+struct History {
+    /// Sequence number of the next operation to finish
+    next: u64,
+    entries: VecDeque<HistoryEntry>,
+}
+
+struct HistoryEntry {
+    sequence: u64,
+    client: Arc<ClientCounters>,
+    op: Operation,
+    addr: u32,
+    length: usize,
+    failed: bool,
+    finished: Instant,
+}
+
+#[derive(Default)]
+struct Tickets {
+    /// Ticket that the next operation to arrive will get
+    next: u64,
+
+    /// Ticket of the operation that currently owns the bridge
+    serving: u64,
+}
+
+#[derive(Default)]
+struct ClientCounters {
+    name: String,
+    operations: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    waiting_ns: AtomicU64,
+    busy_ns: AtomicU64,
+}
+
+impl Arbiter {
+    fn stats(&self) -> Vec<ClientStats> {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| ClientStats {
+                name: c.name.clone(),
+                operations: c.operations.load(Ordering::Relaxed),
+                bytes_read: c.bytes_read.load(Ordering::Relaxed),
+                bytes_written: c.bytes_written.load(Ordering::Relaxed),
+                waiting: Duration::from_nanos(c.waiting_ns.load(Ordering::Relaxed)),
+                busy: Duration::from_nanos(c.busy_ns.load(Ordering::Relaxed)),
+            })
+            .collect()
+    }
+}
+
+/// One client's place in the queue for a bridge.
+#[derive(Clone)]
+pub(crate) struct MuxHandle {
+    arbiter: Arc<Arbiter>,
+    client: Arc<ClientCounters>,
+}
+
+impl MuxHandle {
+    /// Start a new queue, with a single client called `bridge`.
+    pub(crate) fn new() -> MuxHandle {
+        let arbiter = Arc::new(Arbiter {
+            tickets: Mutex::new(Tickets::default()),
+            turn: Condvar::new(),
+            clients: Mutex::new(vec![]),
+            counters: BridgeCounters::default(),
+            recorder: Mutex::new(None),
+            history: Mutex::new(History::default()),
+        });
+        MuxHandle::join(arbiter, "bridge")
+    }
+
+    fn join(arbiter: Arc<Arbiter>, name: &str) -> MuxHandle {
+        let client = Arc::new(ClientCounters {
+            name: name.to_owned(),
+            ..Default::default()
+        });
+        arbiter.clients.lock().unwrap().push(client.clone());
+        MuxHandle { arbiter, client }
+    }
+
+    fn client(&self, name: &str) -> MuxHandle {
+        MuxHandle::join(self.arbiter.clone(), name)
+    }
+
+    /// Counters covering every client of the bridge.
+    pub(crate) fn counters(&self) -> &BridgeCounters {
+        &self.arbiter.counters
+    }
+
+    /// Record every operation from now on to `path`, replacing any
+    /// recording already in progress.
+    pub(crate) fn record_to(&self, path: &Path) -> Result<(), BridgeError> {
+        let recorder = Recorder::create(path)?;
+        *self.arbiter.recorder.lock().unwrap() = Some(recorder);
+        Ok(())
+    }
+
+    pub(crate) fn stop_recording(&self) {
+        *self.arbiter.recorder.lock().unwrap() = None;
+    }
+
+    /// Wait until every operation that was requested before this one has
+    /// finished. The bridge belongs to the caller until the `Turn` is dropped.
+    pub(crate) fn acquire(&self, op: Operation) -> Turn<'_> {
+        let queued = Instant::now();
+        let mut tickets = self.arbiter.tickets.lock().unwrap();
+        let ticket = tickets.next;
+        tickets.next += 1;
+        while tickets.serving != ticket {
+            tickets = self.arbiter.turn.wait(tickets).unwrap();
+        }
+        drop(tickets);
+
+        let started = Instant::now();
+        self.client
+            .waiting_ns
+            .fetch_add((started - queued).as_nanos() as u64, Ordering::Relaxed);
+        Turn {
+            handle: self,
+            op,
+            started,
+            finished: Cell::new(false),
+        }
+    }
+}
+
+/// Exclusive use of a bridge, for the length of one operation.
+pub(crate) struct Turn<'a> {
+    handle: &'a MuxHandle,
+    op: Operation,
+    started: Instant,
+
+    /// Whether the operation reached the hardware and either succeeded or
+    /// failed. Operations the bridge turns down straight away, such as
+    /// bursts over a UART, don't appear in the bridge's statistics.
+    finished: Cell<bool>,
+}
+
+impl Turn<'_> {
+    /// Note that `data` was read from `addr`.
+    pub(crate) fn read(&self, addr: u32, data: &[u8]) {
+        self.handle
+            .client
+            .bytes_read
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.finished.set(true);
+        if self.op == Operation::BurstRead {
+            self.handle.counters().burst_read(data.len());
+        }
+        self.remember(addr, data.len(), false);
+        self.record(addr, data, Ok(()));
+    }
+
+    /// Note that `data` was written to `addr`.
+    pub(crate) fn wrote(&self, addr: u32, data: &[u8]) {
+        self.handle
+            .client
+            .bytes_written
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.finished.set(true);
+        if self.op == Operation::BurstWrite {
+            self.handle.counters().burst_written(data.len());
+        }
+        self.remember(addr, data.len(), false);
+        self.record(addr, data, Ok(()));
+    }
+
+    /// Note that an attempt failed and is about to be tried again.
+    pub(crate) fn retry(&self, addr: u32, e: &BridgeError) {
+        self.handle.counters().retry();
+        self.record(addr, &[], Err(("retry", e)));
+    }
+
+    /// Note that the operation is giving up and returning an error.
+    pub(crate) fn failed(&self, addr: u32, e: &BridgeError) {
+        self.finished.set(true);
+        self.handle.counters().error();
+        self.remember(addr, 0, true);
+        self.record(addr, &[], Err(("error", e)));
+    }
+
+    /// Add the operation to the bridge's history, forgetting the oldest
+    /// one if it's full.
+    fn remember(&self, addr: u32, length: usize, failed: bool) {
+        let mut history = self.handle.arbiter.history.lock().unwrap();
+        let sequence = history.next;
+        history.next += 1;
+        if history.entries.len() == HISTORY_LENGTH {
+            history.entries.pop_front();
+        }
+        history.entries.push_back(HistoryEntry {
+            sequence,
+            client: self.handle.client.clone(),
+            op: self.op,
+            addr,
+            length,
+            failed,
+            finished: Instant::now(),
+        });
+    }
+
+    fn record(&self, addr: u32, data: &[u8], result: Result<(), (&str, &BridgeError)>) {
+        let mut recorder = self.handle.arbiter.recorder.lock().unwrap();
+        if let Some(r) = recorder.as_mut() {
+            if let Err(e) = r.record(self.op, addr, data, result) {
+                // Carry on without the recording rather than failing the
+                // operation, which has already happened.
+                error!(
+                    "unable to record bridge operation, stopping recording: {}",
+                    e
+                );
+                *recorder = None;
+            }
+        }
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let busy = self.started.elapsed();
+        let client = &self.handle.client;
+        client.operations.fetch_add(1, Ordering::Relaxed);
+        client
+            .busy_ns
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+        if self.finished.get() {
+            self.handle.counters().finished(self.op, busy);
+        }
+
+        // Hand the bridge to whoever is next. This also runs when unwinding,
+        // so a panicking client can't leave the others waiting forever.
+        let arbiter = &self.handle.arbiter;
+        let mut tickets = match arbiter.tickets.lock() {
+            Ok(tickets) => tickets,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        tickets.serving += 1;
+        arbiter.turn.notify_all();
+    }
+}
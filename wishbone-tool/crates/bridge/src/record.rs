@@ -0,0 +1,167 @@
+//! Recording of every bus transaction to a CSV file, so that a session can
+//! be inspected afterwards or replayed against a simulator.
+
+use crate::stats::Operation;
+use crate::BridgeError;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The first line of every recording, naming the columns.
+const RECORDING_HEADER: &str = "time,operation,address,data,result";
+
+/// Appends one line per bus transaction to a file.
+///
+/// Each line holds the time as seconds since the Unix epoch, the operation
+/// (`peek`, `poke`, `burst_read`, or `burst_write`), the address, the data,
+/// and either `ok`, `retry`, or `error` followed by the error. Peeks and
+/// pokes show their data as a single word, while bursts show every byte in
+/// the order it appeared on the bus.
+///
+/// ```text
+/// time,operation,address,data,result
+/// 1760000000.000123,peek,0xe0001800,0x00000001,ok
+/// 1760000000.000456,burst_write,0x40000000,deadbeef00112233,ok
+/// 1760000000.000789,peek,0x00000000,,"error: bad address or path"
+/// ```
+pub(crate) struct Recorder {
+    file: LineWriter<File>,
+}
+
+impl Recorder {
+    /// Start appending to `path`, writing a header first if the file is new
+    /// or empty. Each line is flushed as soon as it's written, so that the
+    /// recording survives the program being killed.
+    pub(crate) fn create(path: &Path) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut file = LineWriter::new(file);
+        if empty {
+            writeln!(file, "{}", RECORDING_HEADER)?;
+        }
+        Ok(Recorder { file })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        op: Operation,
+        addr: u32,
+        data: &[u8],
+        result: Result<(), (&str, &BridgeError)>,
+    ) -> io::Result<()> {
+        // Connecting doesn't touch the bus.
+        if op == Operation::Connect {
+            return Ok(());
+        }
+        let data = match op {
+            Operation::Peek | Operation::Poke if data.len() == 4 => format!(
+                "0x{:08x}",
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+            ),
+            _ => data.iter().map(|b| format!("{:02x}", b)).collect(),
+        };
+        let result = match result {
+            Ok(()) => "ok".to_owned(),
+            // Quote the message, since it may contain commas.
+            Err((kind, e)) => format!("\"{}: {}\"", kind, e.to_string().replace('"', "\"\"")),
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            self.file,
+            "{}.{:06},{},0x{:08x},{},{}",
+            time.as_secs(),
+            time.subsec_micros(),
+            op.name(),
+            addr,
+            data,
+            result
+        )
+    }
+}
+
+/// How a recorded operation turned out
+#[cfg(feature = "replay")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Ok,
+
+    /// The attempt failed and the operation was tried again
+    Retry,
+
+    /// The operation gave up and returned an error
+    Error,
+}
+
+/// One line of a recording, as read back by `ReplayBridge`
+#[cfg(feature = "replay")]
+#[derive(Clone, Debug)]
+pub(crate) struct Recorded {
+    pub op: Operation,
+    pub addr: u32,
+
+    /// The bytes read or written, in bus order. Peeks and pokes have four.
+    pub data: Vec<u8>,
+    pub outcome: Outcome,
+}
+
+/// Read back a recording made by `Recorder`.
+#[cfg(feature = "replay")]
+pub(crate) fn read_recording(path: &Path) -> Result<Vec<Recorded>, BridgeError> {
+    let text = std::fs::read_to_string(path)?;
+    let mut recording = vec![];
+    for (number, line) in text.lines().enumerate() {
+        if line.is_empty() || line == RECORDING_HEADER {
+            continue;
+        }
+        let recorded = parse_line(line).ok_or_else(|| {
+            BridgeError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}:{}: not a valid recording line: {}",
+                    path.display(),
+                    number + 1,
+                    line
+                ),
+            ))
+        })?;
+        recording.push(recorded);
+    }
+    Ok(recording)
+}
+
+#[cfg(feature = "replay")]
+fn parse_line(line: &str) -> Option<Recorded> {
+    // The result comes last, so any commas in an error message stay in it.
+    let mut fields = line.splitn(5, ',');
+    let _time = fields.next()?;
+    let op = Operation::from_name(fields.next()?)?;
+    let addr = u32::from_str_radix(fields.next()?.strip_prefix("0x")?, 16).ok()?;
+    let data = fields.next()?;
+    let outcome = match fields.next()?.trim_start_matches('"') {
+        "ok" => Outcome::Ok,
+        result if result.starts_with("retry") => Outcome::Retry,
+        result if result.starts_with("error") => Outcome::Error,
+        _ => return None,
+    };
+    let data = if let Some(word) = data.strip_prefix("0x") {
+        u32::from_str_radix(word, 16).ok()?.to_le_bytes().to_vec()
+    } else {
+        let pairs = data.as_bytes().chunks_exact(2);
+        if !pairs.remainder().is_empty() {
+            return None;
+        }
+        pairs
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?
+    };
+    Some(Recorded {
+        op,
+        addr,
+        data,
+        outcome,
+    })
+}
@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters describing everything a bridge has done since it was created,
+/// as returned by `Bridge::stats()`. They cover every handle to the bridge,
+/// including clones and `MuxBridge` clients.
+#[derive(Clone, Debug, Default)]
+pub struct BridgeStats {
+    /// Number of single-word reads
+    pub peeks: u64,
+
+    /// Number of single-word writes
+    pub pokes: u64,
+
+    /// Number of burst reads, including reads through `std::io::Read`
+    pub burst_reads: u64,
+
+    /// Number of burst writes
+    pub burst_writes: u64,
+
+    /// Bytes returned by bursts that succeeded
+    pub bytes_burst_read: u64,
+
+    /// Bytes sent by bursts that succeeded
+    pub bytes_burst_written: u64,
+
+    /// Attempts that failed and were tried again
+    pub retries: u64,
+
+    /// Operations that gave up and returned an error
+    pub errors: u64,
+
+    /// Total time spent on operations, not counting time spent waiting
+    /// for other operations to finish
+    pub busy: Duration,
+}
+
+impl BridgeStats {
+    pub fn operations(&self) -> u64 {
+        self.peeks + self.pokes + self.burst_reads + self.burst_writes
+    }
+
+    /// The average time taken by each operation, including any retries.
+    pub fn average_latency(&self) -> Duration {
+        match self.operations() {
+            0 => Duration::default(),
+            ops => self.busy / ops as u32,
+        }
+    }
+}
+
+impl std::fmt::Display for BridgeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} peeks, {} pokes, {} bytes read in {} bursts, {} bytes written in {} bursts, \
+             {} retries, {} errors, average latency {:.3} ms",
+            self.peeks,
+            self.pokes,
+            self.bytes_burst_read,
+            self.burst_reads,
+            self.bytes_burst_written,
+            self.burst_writes,
+            self.retries,
+            self.errors,
+            self.average_latency().as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// The kinds of operation that `BridgeStats` counts separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Operation {
+    Connect,
+    Peek,
+    Poke,
+    BurstRead,
+    BurstWrite,
+}
+
+impl Operation {
+    /// The name used for this operation in recordings.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Operation::Connect => "connect",
+            Operation::Peek => "peek",
+            Operation::Poke => "poke",
+            Operation::BurstRead => "burst_read",
+            Operation::BurstWrite => "burst_write",
+        }
+    }
+
+    #[cfg(any(
+        feature = "replay",
+        feature = "uart",
+        feature = "ethernet",
+        feature = "usb"
+    ))]
+    pub(crate) fn from_name(name: &str) -> Option<Operation> {
+        [
+            Operation::Peek,
+            Operation::Poke,
+            Operation::BurstRead,
+            Operation::BurstWrite,
+        ]
+        .iter()
+        .copied()
+        .find(|op| op.name() == name)
+    }
+}
+
+/// The live version of `BridgeStats`, shared by every handle to a bridge
+#[derive(Default)]
+pub(crate) struct BridgeCounters {
+    peeks: AtomicU64,
+    pokes: AtomicU64,
+    burst_reads: AtomicU64,
+    burst_writes: AtomicU64,
+    bytes_burst_read: AtomicU64,
+    bytes_burst_written: AtomicU64,
+    retries: AtomicU64,
+    errors: AtomicU64,
+    busy_ns: AtomicU64,
+}
+
+impl BridgeCounters {
+    /// Count an operation of kind `op` that took `busy`.
+    pub(crate) fn finished(&self, op: Operation, busy: Duration) {
+        let counter = match op {
+            // Connecting isn't a bus operation, so it doesn't count
+            // towards the average latency either.
+            Operation::Connect => return,
+            Operation::Peek => &self.peeks,
+            Operation::Poke => &self.pokes,
+            Operation::BurstRead => &self.burst_reads,
+            Operation::BurstWrite => &self.burst_writes,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.busy_ns
+            .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn burst_read(&self, bytes: usize) {
+        self.bytes_burst_read
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn burst_written(&self, bytes: usize) {
+        self.bytes_burst_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> BridgeStats {
+        BridgeStats {
+            peeks: self.peeks.load(Ordering::Relaxed),
+            pokes: self.pokes.load(Ordering::Relaxed),
+            burst_reads: self.burst_reads.load(Ordering::Relaxed),
+            burst_writes: self.burst_writes.load(Ordering::Relaxed),
+            bytes_burst_read: self.bytes_burst_read.load(Ordering::Relaxed),
+            bytes_burst_written: self.bytes_burst_written.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.busy_ns.load(Ordering::Relaxed)),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+//! Helpers for code that needs more of the OS than `std` offers, through
+//! `libc`. The bridges use them for their sockets, and so do the servers
+//! in wishbone-tool.
+
+use std::io;
+
+/// Turn a negative return from a `libc` call into the error it set
+pub fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Open a close-on-exec socket. It's owned by the returned value straight
+/// away, so it's closed if setting it up goes wrong later on.
+#[cfg(target_os = "linux")]
+pub fn socket<T: std::os::unix::io::FromRawFd>(
+    domain: libc::c_int,
+    kind: libc::c_int,
+    protocol: libc::c_int,
+) -> io::Result<T> {
+    let fd = check(unsafe { libc::socket(domain, kind | libc::SOCK_CLOEXEC, protocol) })?;
+    Ok(unsafe { T::from_raw_fd(fd) })
+}
@@ -0,0 +1,612 @@
+//! Captures of the raw bytes that a bridge exchanges with its device, and a
+//! harness that replays them through the current protocol code.
+//!
+//! A capture is made by calling `trace_to()` on a `UartBridge`,
+//! `EthernetBridge`, or `UsbBridge` before creating it. Every operation that
+//! succeeds is written out along with the bytes that went over the wire for
+//! it. Feeding the file to `check_trace()` later runs the same operations
+//! through the encoder and decoder again, checking that they still produce
+//! the captured bytes and still make the same sense of the replies. This
+//! catches byte-order and framing regressions without needing the hardware
+//! the trace was captured from.
+//!
+//! The traces in `fixtures/` are replayed this way by the tests below.
+//!
+//! The file is text, with one line per operation followed by one line for
+//! each transfer that made it up:
+//!
+//! ```text
+//! # wishbone-bridge transport trace
+//! transport uart
+//! peek 0xe0001800 0x00000001
+//! tx 0201e0001800
+//! rx 00000001
+//! ```
+//!
+//! What a transfer is depends on the transport. A UART trace holds the
+//! bytes sent and received by each operation, and only their order matters.
+//! An Etherbone trace holds one packet per line, however it was carried, and
+//! `set` lines recording how packets were split up. A USB trace holds one
+//! control transfer per line, with `tx` giving the eight-byte setup packet
+//! followed by any data sent, and `rx` giving any data received.
+
+use crate::stats::Operation;
+use crate::BridgeError;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, LineWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tracing::error;
+
+const TRACE_HEADER: &str = "# wishbone-bridge transport trace";
+
+/// The protocols whose transfers can be traced
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TraceTransport {
+    /// The Wishbone serial protocol, over a UART or RFCOMM
+    Uart,
+
+    /// Etherbone packets, over UDP, TCP, TLS, or a WebSocket
+    Etherbone,
+
+    /// USB control transfers to a device such as a Fomu
+    Usb,
+}
+
+impl TraceTransport {
+    fn name(self) -> &'static str {
+        match self {
+            TraceTransport::Uart => "uart",
+            TraceTransport::Etherbone => "etherbone",
+            TraceTransport::Usb => "usb",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<TraceTransport> {
+        [
+            TraceTransport::Uart,
+            TraceTransport::Etherbone,
+            TraceTransport::Usb,
+        ]
+        .iter()
+        .copied()
+        .find(|t| t.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Tx,
+    Rx,
+}
+
+struct TraceWriter {
+    file: LineWriter<File>,
+
+    /// Whether transfers are parts of a byte stream, and so may be joined
+    stream: bool,
+
+    /// Transfers made so far by the operation in progress
+    pending: Vec<(Direction, Vec<u8>)>,
+}
+
+/// Where a bridge writes its trace. Clones share the same file.
+#[derive(Clone)]
+pub(crate) struct Tracer {
+    writer: Arc<Mutex<TraceWriter>>,
+}
+
+impl Tracer {
+    /// Start a new trace at `path`, replacing anything already there.
+    pub(crate) fn create(path: &Path, transport: TraceTransport) -> io::Result<Tracer> {
+        let mut file = LineWriter::new(File::create(path)?);
+        writeln!(file, "{}", TRACE_HEADER)?;
+        writeln!(file, "transport {}", transport.name())?;
+        Ok(Tracer {
+            writer: Arc::new(Mutex::new(TraceWriter {
+                file,
+                stream: transport == TraceTransport::Uart,
+                pending: vec![],
+            })),
+        })
+    }
+
+    pub(crate) fn tx(&self, data: &[u8]) {
+        self.transfer(Direction::Tx, data);
+    }
+
+    pub(crate) fn rx(&self, data: &[u8]) {
+        self.transfer(Direction::Rx, data);
+    }
+
+    fn transfer(&self, direction: Direction, data: &[u8]) {
+        let mut writer = self.writer.lock().unwrap();
+        let stream = writer.stream;
+        match writer.pending.last_mut() {
+            Some((last, bytes)) if stream && *last == direction => bytes.extend_from_slice(data),
+            _ if stream && data.is_empty() => (),
+            _ => writer.pending.push((direction, data.to_vec())),
+        }
+    }
+
+    /// Note a setting that affects how later operations are encoded, such
+    /// as how many Etherbone records fit in a packet.
+    #[cfg(feature = "ethernet")]
+    pub(crate) fn set<T: std::fmt::Display>(&self, key: &str, value: T) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(writer.file, "set {} {}", key, value) {
+            error!("unable to write transport trace: {}", e);
+        }
+    }
+
+    /// End the operation in progress. If it succeeded, `data` is what it
+    /// read or wrote, and it gets written out along with its transfers.
+    /// Failed operations are left out, since there's nothing to check their
+    /// result against.
+    pub(crate) fn finish(&self, op: Operation, addr: u32, data: Option<Vec<u8>>) {
+        let mut writer = self.writer.lock().unwrap();
+        let pending = std::mem::take(&mut writer.pending);
+        let data = match data {
+            Some(data) => data,
+            None => return,
+        };
+        let mut text = format!("{} 0x{:08x} {}\n", op.name(), addr, op_data(op, &data));
+        for (direction, bytes) in pending {
+            let direction = match direction {
+                Direction::Tx => "tx",
+                Direction::Rx => "rx",
+            };
+            text.push_str(&format!("{} {}\n", direction, hex(&bytes)));
+        }
+        if let Err(e) = writer.file.write_all(text.as_bytes()) {
+            error!("unable to write transport trace: {}", e);
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    let pairs = text.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Peeks and pokes show their value as a word, bursts show their bytes.
+fn op_data(op: Operation, data: &[u8]) -> String {
+    match op {
+        Operation::Peek | Operation::Poke if data.len() == 4 => format!(
+            "0x{:08x}",
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+        ),
+        _ => hex(data),
+    }
+}
+
+/// Wraps a stream so that everything written to it or read from it ends up
+/// in a trace.
+#[cfg(feature = "uart")]
+pub(crate) struct TraceTap<S> {
+    inner: S,
+    tracer: Tracer,
+}
+
+#[cfg(feature = "uart")]
+impl<S> TraceTap<S> {
+    pub(crate) fn new(inner: S, tracer: Tracer) -> TraceTap<S> {
+        TraceTap { inner, tracer }
+    }
+}
+
+#[cfg(feature = "uart")]
+impl<S: Read> Read for TraceTap<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.tracer.rx(&buf[..len]);
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "uart")]
+impl<S: Write> Write for TraceTap<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.tracer.tx(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The transfers captured for one operation, played back to the protocol
+/// code in place of a device. Sending anything other than what was captured
+/// is an error, and the first such difference is kept for reporting.
+#[derive(Default)]
+pub(crate) struct TraceReplay {
+    transfers: VecDeque<(Direction, Vec<u8>)>,
+    diverged: Option<String>,
+}
+
+impl TraceReplay {
+    fn new(transfers: Vec<(Direction, Vec<u8>)>) -> TraceReplay {
+        TraceReplay {
+            transfers: transfers.into(),
+            diverged: None,
+        }
+    }
+
+    fn diverge(&mut self, msg: String) -> io::Error {
+        self.diverged.get_or_insert(msg.clone());
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    /// Send one whole packet or control transfer, which must match the next
+    /// one in the trace exactly.
+    #[cfg(any(feature = "ethernet", feature = "usb"))]
+    pub(crate) fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.transfers.pop_front() {
+            Some((Direction::Tx, expected)) if expected == data => Ok(()),
+            Some((Direction::Tx, expected)) => Err(self.diverge(format!(
+                "sent {} where the trace has {}",
+                hex(data),
+                hex(&expected)
+            ))),
+            _ => Err(self.diverge(format!(
+                "sent {} where the trace has nothing more to send",
+                hex(data)
+            ))),
+        }
+    }
+
+    /// Receive one whole packet or control transfer into `buf`, returning
+    /// its length.
+    #[cfg(any(feature = "ethernet", feature = "usb"))]
+    pub(crate) fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.transfers.pop_front() {
+            Some((Direction::Rx, data)) if data.len() <= buf.len() => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            Some((Direction::Rx, data)) => Err(self.diverge(format!(
+                "expected at most {} bytes where the trace received {}",
+                buf.len(),
+                data.len()
+            ))),
+            _ => Err(self.diverge(format!(
+                "tried to receive {} bytes where the trace has nothing more to receive",
+                buf.len()
+            ))),
+        }
+    }
+
+    fn check_finished(&self) -> Result<(), String> {
+        if let Some(msg) = &self.diverged {
+            return Err(msg.clone());
+        }
+        match self.transfers.front() {
+            Some((Direction::Tx, data)) => Err(format!("never sent {}", hex(data))),
+            Some((Direction::Rx, data)) => Err(format!("never received {}", hex(data))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// As a stream, transfers may be split or joined in any way, as long as
+/// the bytes come in the same order.
+impl Read for TraceReplay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let front = match self.transfers.front_mut() {
+            Some((Direction::Rx, data)) => data,
+            _ => {
+                return Err(self.diverge(format!(
+                    "tried to read {} bytes where the trace has nothing to read",
+                    buf.len()
+                )))
+            }
+        };
+        let len = buf.len().min(front.len());
+        buf[..len].copy_from_slice(&front[..len]);
+        front.drain(..len);
+        if front.is_empty() {
+            self.transfers.pop_front();
+        }
+        Ok(len)
+    }
+}
+
+impl Write for TraceReplay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let front = match self.transfers.front_mut() {
+            Some((Direction::Tx, data)) => data,
+            _ => {
+                return Err(self.diverge(format!(
+                    "wrote {} where the trace has nothing to write",
+                    hex(buf)
+                )))
+            }
+        };
+        let len = buf.len().min(front.len());
+        if buf[..len] != front[..len] {
+            let expected = hex(&front[..len]);
+            return Err(self.diverge(format!(
+                "wrote {} where the trace has {}",
+                hex(&buf[..len]),
+                expected
+            )));
+        }
+        front.drain(..len);
+        if front.is_empty() {
+            self.transfers.pop_front();
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The `set` lines that came before an operation in a trace
+#[derive(Clone, Default)]
+pub(crate) struct TraceSettings(HashMap<String, String>);
+
+#[cfg(feature = "ethernet")]
+impl TraceSettings {
+    pub(crate) fn get<T: std::str::FromStr>(&self, key: &str) -> Result<T, BridgeError> {
+        self.0
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| {
+                BridgeError::ReplayDiverged(format!("the trace has no valid setting for {}", key))
+            })
+    }
+}
+
+/// The word written by a poke, from the bytes given in a trace
+pub(crate) fn word(data: &[u8]) -> Result<u32, BridgeError> {
+    match data {
+        [a, b, c, d] => Ok(u32::from_le_bytes([*a, *b, *c, *d])),
+        _ => Err(BridgeError::LengthError(4, data.len())),
+    }
+}
+
+/// One operation read back from a trace
+struct Traced {
+    line: usize,
+    op: Operation,
+    addr: u32,
+    data: Vec<u8>,
+    settings: TraceSettings,
+    transfers: Vec<(Direction, Vec<u8>)>,
+}
+
+fn invalid_trace(path: &Path, line: usize, msg: &str) -> BridgeError {
+    BridgeError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, msg),
+    ))
+}
+
+/// Replay every operation in the trace at `path` through the protocol code
+/// for its transport, returning how many were checked. Fails with
+/// `BridgeError::ReplayDiverged` at the first operation that no longer
+/// sends the same bytes or no longer gets the same result from them.
+///
+/// ```no_run
+/// let checked = wishbone_bridge::check_trace("fixtures/uart.trace").unwrap();
+/// println!("{} operations still match", checked);
+/// ```
+pub fn check_trace<P: AsRef<Path>>(path: P) -> Result<usize, BridgeError> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    let mut transport = None;
+    let mut settings = TraceSettings::default();
+    let mut ops: Vec<Traced> = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "transport" => {
+                transport = Some(
+                    TraceTransport::from_name(rest)
+                        .ok_or_else(|| invalid_trace(path, number, "unknown transport"))?,
+                )
+            }
+            "set" => {
+                let (key, value) = rest
+                    .split_once(' ')
+                    .ok_or_else(|| invalid_trace(path, number, "missing value"))?;
+                settings.0.insert(key.to_owned(), value.to_owned());
+            }
+            "tx" | "rx" => {
+                let direction = if keyword == "tx" {
+                    Direction::Tx
+                } else {
+                    Direction::Rx
+                };
+                let data = unhex(rest).ok_or_else(|| invalid_trace(path, number, "bad hex"))?;
+                ops.last_mut()
+                    .ok_or_else(|| invalid_trace(path, number, "transfer before any operation"))?
+                    .transfers
+                    .push((direction, data));
+            }
+            name => {
+                let op = Operation::from_name(name)
+                    .ok_or_else(|| invalid_trace(path, number, "unknown operation"))?;
+                let (addr, data) = rest
+                    .split_once(' ')
+                    .ok_or_else(|| invalid_trace(path, number, "missing data"))?;
+                let addr = addr
+                    .strip_prefix("0x")
+                    .and_then(|a| u32::from_str_radix(a, 16).ok())
+                    .ok_or_else(|| invalid_trace(path, number, "bad address"))?;
+                let data = match data.strip_prefix("0x") {
+                    Some(word) => u32::from_str_radix(word, 16)
+                        .ok()
+                        .map(|w| w.to_le_bytes().to_vec()),
+                    None => unhex(data),
+                }
+                .ok_or_else(|| invalid_trace(path, number, "bad data"))?;
+                ops.push(Traced {
+                    line: number,
+                    op,
+                    addr,
+                    data,
+                    settings: settings.clone(),
+                    transfers: vec![],
+                });
+            }
+        }
+    }
+    let transport = transport.ok_or_else(|| invalid_trace(path, 1, "no transport given"))?;
+
+    for traced in &ops {
+        let mut replay = TraceReplay::new(traced.transfers.clone());
+        let result = replay_op(
+            transport,
+            &mut replay,
+            &traced.settings,
+            traced.op,
+            traced.addr,
+            &traced.data,
+        );
+        let diverged = |msg: String| {
+            BridgeError::ReplayDiverged(format!(
+                "{}:{}: {} of 0x{:08x}: {}",
+                path.display(),
+                traced.line,
+                traced.op.name(),
+                traced.addr,
+                msg
+            ))
+        };
+        match result {
+            Ok(read) => {
+                replay.check_finished().map_err(diverged)?;
+                let op = traced.op;
+                if (op == Operation::Peek || op == Operation::BurstRead) && read != traced.data {
+                    return Err(diverged(format!(
+                        "decoded {} where the trace has {}",
+                        op_data(op, &read),
+                        op_data(op, &traced.data)
+                    )));
+                }
+            }
+            Err(e) => {
+                return Err(diverged(
+                    replay.diverged.clone().unwrap_or_else(|| e.to_string()),
+                ))
+            }
+        }
+    }
+    Ok(ops.len())
+}
+
+/// Run one operation against `replay` using the protocol code for
+/// `transport`, returning whatever it read.
+#[allow(unused_variables)]
+fn replay_op(
+    transport: TraceTransport,
+    replay: &mut TraceReplay,
+    settings: &TraceSettings,
+    op: Operation,
+    addr: u32,
+    data: &[u8],
+) -> Result<Vec<u8>, BridgeError> {
+    match transport {
+        #[cfg(feature = "uart")]
+        TraceTransport::Uart => crate::bridges::uart::replay_op(replay, op, addr, data),
+        #[cfg(feature = "ethernet")]
+        TraceTransport::Etherbone => {
+            crate::bridges::ethernet::replay_op(replay, settings, op, addr, data)
+        }
+        #[cfg(feature = "usb")]
+        TraceTransport::Usb => crate::bridges::usb::replay_op(replay, op, addr, data),
+        #[allow(unreachable_patterns)]
+        _ => Err(BridgeError::ProtocolNotSupported),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(name)
+    }
+
+    /// Check a copy of the UART fixture with `from` replaced by `to`
+    #[cfg(feature = "uart")]
+    fn check_edited(test: &str, from: &str, to: &str) -> Result<usize, BridgeError> {
+        let text = std::fs::read_to_string(fixture("uart.trace")).unwrap();
+        assert!(text.contains(from));
+        let path = std::env::temp_dir().join(format!(
+            "wishbone-bridge-{}-{}.trace",
+            test,
+            std::process::id()
+        ));
+        std::fs::write(&path, text.replacen(from, to, 1)).unwrap();
+        let result = check_trace(&path);
+        std::fs::remove_file(path).unwrap();
+        result
+    }
+
+    #[cfg(feature = "uart")]
+    #[test]
+    fn uart_trace_replays() {
+        assert_eq!(check_trace(fixture("uart.trace")).unwrap(), 5);
+    }
+
+    #[cfg(feature = "ethernet")]
+    #[test]
+    fn etherbone_trace_replays() {
+        assert_eq!(check_trace(fixture("etherbone.trace")).unwrap(), 4);
+    }
+
+    #[cfg(feature = "usb")]
+    #[test]
+    fn usb_trace_replays() {
+        assert_eq!(check_trace(fixture("usb.trace")).unwrap(), 4);
+    }
+
+    #[cfg(feature = "uart")]
+    #[test]
+    fn changed_bytes_diverge() {
+        // The address goes out shifted right by two
+        match check_edited("sent", "tx 020138000601", "tx 0201e0001804") {
+            Err(BridgeError::ReplayDiverged(msg)) => {
+                assert!(
+                    msg.ends_with("wrote 38000601 where the trace has e0001804"),
+                    "{}",
+                    msg
+                )
+            }
+            other => panic!("expected the replay to diverge, got {:?}", other),
+        }
+        // The reply comes back big-endian
+        match check_edited("decoded", "rx a5a5005a", "rx 5a00a5a5") {
+            Err(BridgeError::ReplayDiverged(msg)) => assert!(
+                msg.ends_with("decoded 0x5a00a5a5 where the trace has 0xa5a5005a"),
+                "{}",
+                msg
+            ),
+            other => panic!("expected the replay to diverge, got {:?}", other),
+        }
+    }
+}
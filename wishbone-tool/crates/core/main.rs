@@ -1,24 +1,32 @@
 #[macro_use]
-extern crate bitflags;
-#[macro_use]
 extern crate clap;
 
-extern crate indicatif;
-
-use log::debug;
+use tracing::{error, info};
 
-mod config;
-mod gdb;
-mod riscv;
-mod server;
-mod wishbone;
-
-use clap::{App, Arg, Shell};
-use config::Config;
-use server::ServerKind;
+use clap::{App, AppSettings, Arg, Shell, SubCommand};
+use wishbone_tool_lib::config::{self, Config};
+use wishbone_tool_lib::server::{self, ServerKind};
 
 use std::sync::Arc;
 
+/// What `--server` and the `server` subcommand can run
+const SERVER_KINDS: &[&str] = &[
+    "gdb",
+    "wishbone",
+    "random-test",
+    "load-file",
+    "terminal",
+    "messible",
+    "vcd-gpio",
+    "sniff",
+    "core-dump",
+    "http",
+    "repl",
+    "benchmark",
+    "boot",
+    "fpga-program",
+];
+
 fn clap_app<'a, 'b>() -> App<'a, 'b> {
     App::new("Wishbone Tool")
         .version(crate_version!())
@@ -26,7 +34,8 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         .about("Work with Wishbone devices over various bridges")
         .arg(
             Arg::with_name("completion")
-            .group("command")
+                .global(true)
+                .group("command")
                 .short("c")
                 .long("completion")
                 .help("Generate shell auto-completion file")
@@ -37,6 +46,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
 
         .arg(
             Arg::with_name("pid")
+                .global(true)
                 .short("p")
                 .long("pid")
                 .value_name("USB_PID")
@@ -47,6 +57,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("vid")
+                .global(true)
                 .short("v")
                 .long("vid")
                 .value_name("USB_VID")
@@ -56,6 +67,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("bus")
+                .global(true)
                 .short("B")
                 .long("bus")
                 .value_name("USB_BUS")
@@ -65,6 +77,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("device")
+                .global(true)
                 .short("d")
                 .long("device")
                 .value_name("USB_DEVICE")
@@ -72,9 +85,34 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(3)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("usb-serial")
+                .global(true)
+                .long("usb-serial")
+                .value_name("SERIAL")
+                .help("USB: serial number of the device to match")
+                .display_order(3)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("usb-recover")
+                .global(true)
+                .long("usb-recover")
+                .help("USB: try to recover a wedged device by re-reading descriptors, re-claiming interfaces, and clearing halts, then exit")
+                .display_order(3),
+        )
+        .arg(
+            Arg::with_name("usb-recover-reset")
+                .global(true)
+                .long("usb-recover-reset")
+                .help("USB: also reset the device as the last step of --usb-recover")
+                .requires("usb-recover")
+                .display_order(3),
+        )
 
         .arg(
             Arg::with_name("serial")
+                .global(true)
                 .short("u")
                 .long("serial")
                 .alias("uart")
@@ -85,6 +123,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("baud")
+                .global(true)
                 .short("b")
                 .long("baud")
                 .value_name("RATE")
@@ -93,9 +132,63 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(5)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("serial-flow-control")
+                .global(true)
+                .long("serial-flow-control")
+                .value_name("MODE")
+                .possible_values(&["none", "rts-cts"])
+                .default_value("none")
+                .help("SERIAL: flow control, needed at high baudrates to avoid dropped bytes")
+                .display_order(5)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serial-parity")
+                .global(true)
+                .long("serial-parity")
+                .value_name("PARITY")
+                .possible_values(&["none", "odd", "even"])
+                .default_value("none")
+                .help("SERIAL: parity bit to use")
+                .display_order(5)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serial-stop-bits")
+                .global(true)
+                .long("serial-stop-bits")
+                .value_name("BITS")
+                .possible_values(&["1", "2"])
+                .default_value("1")
+                .help("SERIAL: number of stop bits")
+                .display_order(5)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rfcomm")
+                .global(true)
+                .long("rfcomm")
+                .value_name("BDADDR")
+                .help("SERIAL: Bluetooth address of a paired serial adapter to connect to over RFCOMM, e.g. 00:14:03:05:0A:1B")
+                .conflicts_with("serial")
+                .display_order(5)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rfcomm-channel")
+                .global(true)
+                .long("rfcomm-channel")
+                .value_name("CHANNEL")
+                .help("SERIAL: RFCOMM channel of the serial port service")
+                .default_value("1")
+                .display_order(5)
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("ethernet-host")
+                .global(true)
                 .long("ethernet-host")
                 .value_name("ADDRESS")
                 .help("ETHERNET: address of device or proxy to connect to")
@@ -104,6 +197,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("ethernet-port")
+                .global(true)
                 .long("ethernet-port")
                 .value_name("PORT")
                 .help("ETHERNET: port to use for Ethernet bridge")
@@ -113,21 +207,99 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("ethernet-tcp")
+                .global(true)
                 .long("ethernet-tcp")
                 .help("ETHERNET: use TCP to connect to Wishbone, such as when using a proxy")
                 .display_order(8)
         )
+        .arg(
+            Arg::with_name("ethernet-websocket")
+                .global(true)
+                .long("ethernet-websocket")
+                .value_name("PATH")
+                .help("ETHERNET: carry Etherbone over a WebSocket opened at PATH, such as through an HTTP gateway")
+                .conflicts_with("ethernet-tcp")
+                .display_order(8)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("ethernet-tls")
+                .global(true)
+                .long("ethernet-tls")
+                .help("ETHERNET: wrap the TCP connection in TLS")
+                .requires("ethernet-tcp")
+                .display_order(8)
+        )
+        .arg(
+            Arg::with_name("ethernet-tls-ca")
+                .global(true)
+                .long("ethernet-tls-ca")
+                .value_name("PEM_FILE")
+                .help("ETHERNET: certificate authorities to check the server against, instead of the public ones")
+                .requires("ethernet-tls")
+                .display_order(8)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("ethernet-tls-cert")
+                .global(true)
+                .long("ethernet-tls-cert")
+                .value_name("PEM_FILE")
+                .help("ETHERNET: client certificate to present to the server")
+                .requires_all(&["ethernet-tls", "ethernet-tls-key"])
+                .display_order(8)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("ethernet-tls-key")
+                .global(true)
+                .long("ethernet-tls-key")
+                .value_name("PEM_FILE")
+                .help("ETHERNET: private key for --ethernet-tls-cert")
+                .requires("ethernet-tls-cert")
+                .display_order(8)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("ethernet-mtu")
+                .global(true)
+                .long("ethernet-mtu")
+                .value_name("BYTES")
+                .help("ETHERNET: largest frame to probe the device with over UDP")
+                .default_value("1500")
+                .display_order(8)
+                .takes_value(true)
+        )
+        .arg(
+            Arg::with_name("fast-writes")
+                .global(true)
+                .long("fast-writes")
+                .help("ETHERNET: gather writes into full packets without waiting for each one, then confirm they landed once they stop")
+                .display_order(8)
+        )
 
         .arg(
             Arg::with_name("pcie-bar")
+                .global(true)
                 .long("pcie-bar")
                 .help("PCIe: use the specified file as a PCIe BAR")
                 .display_order(9)
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("pcie-device")
+                .global(true)
+                .long("pcie-device")
+                .value_name("BDF")
+                .help("PCIe: locate the BAR of the device at this PCI address (e.g. 0000:03:00.0)")
+                .display_order(9)
+                .conflicts_with("pcie-bar")
+                .takes_value(true)
+        )
 
         .arg(
             Arg::with_name("spi-pins")
+                .global(true)
                 .short("g")
                 .long("spi-pins")
                 .value_delimiter("PINS")
@@ -135,6 +307,185 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(10)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("spi-gpiochip")
+                .global(true)
+                .long("spi-gpiochip")
+                .value_name("DEVICE")
+                .help("SPI: GPIO chip that --spi-pins refers to")
+                .default_value("/dev/gpiochip0")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("spi-ftdi")
+                .global(true)
+                .long("spi-ftdi")
+                .value_name("VID:PID[:CHANNEL]")
+                .help("SPI: use an FTDI MPSSE adapter instead of GPIO pins (e.g. --spi-ftdi=0403:6014, default 0403:6010:A)")
+                .display_order(10)
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("spi-frequency")
+                .global(true)
+                .long("spi-frequency")
+                .value_name("HZ")
+                .help("SPI: clock frequency to use with --spi-ftdi")
+                .default_value("1000000")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("spi-three-wire")
+                .global(true)
+                .long("spi-three-wire")
+                .help("SPI: with --spi-ftdi, COPI and CIPO are tied together to form a single data line")
+                .display_order(10),
+        )
+
+        .arg(
+            Arg::with_name("i2c")
+                .global(true)
+                .long("i2c")
+                .value_name("DEVICE")
+                .help("I2C: Linux I2C adapter to use, e.g. /dev/i2c-1")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("i2c-pins")
+                .global(true)
+                .long("i2c-pins")
+                .value_name("PINS")
+                .help("I2C: GPIO pins to bit-bang as SDA,SCL (e.g. 2,3)")
+                .conflicts_with("i2c")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("i2c-address")
+                .global(true)
+                .long("i2c-address")
+                .value_name("ADDRESS")
+                .help("I2C: 7-bit address of the target's Wishbone bridge")
+                .default_value("0x10")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("i2c-frequency")
+                .global(true)
+                .long("i2c-frequency")
+                .value_name("HZ")
+                .help("I2C: clock frequency to use with --i2c-pins")
+                .default_value("100000")
+                .display_order(10)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("sim")
+                .global(true)
+                .long("sim")
+                .value_name("SOCKET")
+                .help("SIM: Unix socket of a simulation to connect to, or HOST:PORT with --sim-tcp")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sim-tcp")
+                .global(true)
+                .long("sim-tcp")
+                .help("SIM: connect to the simulation over TCP")
+                .display_order(10),
+        )
+        .arg(
+            Arg::with_name("sim-timeout")
+                .global(true)
+                .long("sim-timeout")
+                .value_name("SECONDS")
+                .help("SIM: how long to wait for the simulation to answer each request")
+                .default_value("30")
+                .display_order(10)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("replay")
+                .global(true)
+                .long("replay")
+                .value_name("FILE")
+                .help("REPLAY: play back a recording made with --record instead of talking to hardware")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay-any-order")
+                .global(true)
+                .long("replay-any-order")
+                .help("REPLAY: answer reads from the recording in any order, rather than requiring the same operations in the same order")
+                .requires("replay")
+                .display_order(10),
+        )
+
+        .arg(
+            Arg::with_name("can")
+                .global(true)
+                .long("can")
+                .value_name("INTERFACE")
+                .help("CAN: SocketCAN interface to use, e.g. can0")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("can-tx-id")
+                .global(true)
+                .long("can-tx-id")
+                .value_name("ID")
+                .help("CAN: arbitration ID to send requests with")
+                .default_value("0x5b0")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("can-rx-id")
+                .global(true)
+                .long("can-rx-id")
+                .value_name("ID")
+                .help("CAN: arbitration ID the target answers with")
+                .default_value("0x5b8")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("can-extended-ids")
+                .global(true)
+                .long("can-extended-ids")
+                .help("CAN: use 29-bit extended IDs")
+                .display_order(10),
+        )
+        .arg(
+            Arg::with_name("can-byte-order")
+                .global(true)
+                .long("can-byte-order")
+                .value_name("ORDER")
+                .possible_values(&["big", "little"])
+                .default_value("big")
+                .help("CAN: byte order of the address and value in each frame")
+                .display_order(10)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("can-no-write-ack")
+                .global(true)
+                .long("can-no-write-ack")
+                .help("CAN: don't wait for the target to acknowledge writes")
+                .display_order(10),
+        )
 
         .arg(
             Arg::with_name("address")
@@ -151,16 +502,48 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(12)
                 .help("value to write"),
         )
+        .arg(
+            Arg::with_name("set-field")
+                .global(true)
+                .long("set-field")
+                .value_name("REG.FIELD=VALUE")
+                .group("command")
+                .help("change one field of a register, leaving the rest of it as it was")
+                .display_order(12)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("fields")
+                .global(true)
+                .long("fields")
+                .help("show the fields of registers that are read by number, not just by name")
+                .display_order(12),
+        )
 
         .arg(
             Arg::with_name("csr-csv")
+                .global(true)
                 .long("csr-csv")
-                .help("csr.csv file containing register mappings")
+                .help("csr.csv, csr.json, or SVD file containing register mappings")
                 .display_order(13)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("csr-auto")
+                .global(true)
+                .long("csr-auto")
+                .value_name("ADDR")
+                .help("read the register map from the device, at --csr-auto=ADDR or wherever it can be found in the boot ROM, falling back to --csr-csv")
+                .display_order(13)
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true),
+        )
         .arg(
             Arg::with_name("register-offset")
+                .global(true)
                 .long("register-offset")
                 .alias("csr-csv-offset")
                 .help("apply an offset to addresses, e.g. to compensate for PCIe BAR offset")
@@ -170,6 +553,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
 
         .arg(
             Arg::with_name("server-kind")
+                .global(true)
                 .short("s")
                 .group("command")
                 .long("server")
@@ -178,28 +562,96 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .multiple(true)
                 .help("which server to run (if any)")
                 .display_order(15)
-                .possible_values(&["gdb", "wishbone", "random-test", "load-file", "terminal", "messible"]),
+                .possible_values(SERVER_KINDS),
         )
 
         .arg(
             Arg::with_name("gdb-port")
+                .global(true)
                 .long("gdb-port")
                 .help("GDB: port to listen on for GDB connections")
                 .default_value("3333")
                 .display_order(16)
                 .takes_value(true)
         )
+        .arg(
+            Arg::with_name("gdb-pipe")
+                .global(true)
+                .long("gdb-pipe")
+                .help("GDB: talk to GDB over stdin and stdout, for `target remote | wishbone-tool ...`")
+                .display_order(16)
+        )
+        .arg(
+            Arg::with_name("gdb-interrupt-signal")
+                .global(true)
+                .long("gdb-interrupt-signal")
+                .value_name("SIGNAL")
+                .help("GDB: signal to report when Ctrl-C halts the CPU: SIGINT (the default), SIGTRAP, or a number")
+                .display_order(16)
+                .takes_value(true)
+        )
         .arg(
             Arg::with_name("debug-offset")
+                .global(true)
                 .long("debug-offset")
                 .help("GDB: address of the CPU's debug bridge")
                 .default_value("0xf00f0000")
                 .display_order(17)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("debug-interface")
+                .global(true)
+                .long("debug-interface")
+                .value_name("INTERFACE")
+                .help("GDB: how to debug the CPU: the VexRiscv debug bridge, or a standard RISC-V debug module (dm)")
+                .possible_values(&["vexriscv", "dm"])
+                .default_value("vexriscv")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("xlen")
+                .global(true)
+                .long("xlen")
+                .value_name("BITS")
+                .help("GDB: width of the CPU's registers, or auto to read it from misa")
+                .possible_values(&["32", "64", "auto"])
+                .default_value("32")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("heartbeat")
+                .global(true)
+                .long("heartbeat")
+                .value_name("ADDRESS")
+                .help("GDB: address or CSR name of a counter the firmware increments, to detect stalls")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("heartbeat-timeout")
+                .global(true)
+                .long("heartbeat-timeout")
+                .value_name("SECONDS")
+                .help("GDB: how long the heartbeat may stay the same while the CPU is running")
+                .default_value("5")
+                .display_order(17)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("heartbeat-halt")
+                .global(true)
+                .long("heartbeat-halt")
+                .help("GDB: halt the CPU and print the trap and backtrace when the heartbeat stalls")
+                .requires("heartbeat")
+                .display_order(17),
+        )
 
         .arg(
             Arg::with_name("bind-addr")
+                .global(true)
                 .short("a")
                 .long("bind-addr")
                 .value_name("IP_ADDRESS")
@@ -208,8 +660,44 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(18)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("read-only")
+                .global(true)
+                .long("read-only")
+                .help("Refuse writes from clients of the wishbone, http and gdb servers")
+                .display_order(18),
+        )
+        .arg(
+            Arg::with_name("allow-range")
+                .global(true)
+                .long("allow-range")
+                .value_name("ADDR:LEN")
+                .help("Only let clients of the wishbone, http and gdb servers access this range (may be given more than once)")
+                .display_order(18)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("mdns")
+                .global(true)
+                .long("mdns")
+                .help("Advertise the gdb and wishbone servers on the local network via mDNS")
+                .display_order(18),
+        )
+        .arg(
+            Arg::with_name("mdns-name")
+                .global(true)
+                .long("mdns-name")
+                .value_name("NAME")
+                .help("Name to advertise the servers under, instead of this machine's hostname")
+                .requires("mdns")
+                .display_order(18)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("wishbone-port")
+                .global(true)
                 .short("n")
                 .long("wishbone-port")
                 .alias("port")
@@ -219,9 +707,27 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
                 .display_order(19)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("wishbone-udp")
+                .global(true)
+                .long("wishbone-udp")
+                .help("WISHBONE: also accept Etherbone datagrams over UDP on the same port, as a device would")
+                .display_order(19),
+        )
+        .arg(
+            Arg::with_name("http-port")
+                .global(true)
+                .long("http-port")
+                .value_name("PORT_NUMBER")
+                .help("HTTP: port number to listen on for `--server http`")
+                .default_value("8080")
+                .display_order(19)
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("random-address")
+                .global(true)
                 .long("random-address")
                 .help("RANDOM_TEST: address at which to write")
                 .display_order(20)
@@ -229,6 +735,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("random-loops")
+                .global(true)
                 .long("random-loops")
                 .help("RANDOM_TEST: Number of loops to run")
                 .display_order(21)
@@ -236,52 +743,351 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
         )
         .arg(
             Arg::with_name("random-range")
+                .global(true)
                 .long("random-range")
                 .help("RANDOM_TEST: the size of the random address range (i.e. how many bytes to randomly add to the address)")
                 .display_order(22)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("random-threads")
+                .global(true)
+                .long("random-threads")
+                .value_name("N")
+                .help("RANDOM_TEST: number of threads to run at once, each in its own part of the range, to test access from several clients")
+                .default_value("1")
+                .display_order(22)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("benchmark-address")
+                .global(true)
+                .long("benchmark-address")
+                .value_name("ADDR")
+                .help("BENCHMARK: memory to read and write (defaults to sram from the register map)")
+                .display_order(22)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("benchmark-sizes")
+                .global(true)
+                .long("benchmark-sizes")
+                .value_name("BYTES,...")
+                .help("BENCHMARK: burst sizes to time")
+                .default_value("64,1024,16384")
+                .display_order(22)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("benchmark-count")
+                .global(true)
+                .long("benchmark-count")
+                .value_name("N")
+                .help("BENCHMARK: how many times to repeat each operation, stopping early after two seconds")
+                .default_value("100")
+                .display_order(22)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("benchmark-csv")
+                .global(true)
+                .long("benchmark-csv")
+                .value_name("FILE")
+                .help("BENCHMARK: also write the results to FILE as CSV")
+                .display_order(22)
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("load-name")
+                .global(true)
                 .long("load-name")
-                .help("LOAD_FILE: Name of the file to load into RAM or FLASH (defaults to RAM unless load-flash is set)")
+                .help("LOAD_FILE: Name of the file to load into RAM or FLASH (defaults to RAM unless load-flash is set), either an ELF file or a raw binary")
                 .takes_value(true)
                 .display_order(23),
         )
         .arg(
             Arg::with_name("load-address")
+                .global(true)
                 .long("load-address")
-                .help("LOAD_FILE: Address at which to load the file")
+                .help("LOAD_FILE: Address at which to load the file, if it's a raw binary")
                 .takes_value(true)
                 .display_order(24),
         )
+        .arg(
+            Arg::with_name("load-zero-bss")
+                .global(true)
+                .long("load-zero-bss")
+                .help("LOAD_FILE: clear the parts of each ELF segment that aren't in the file, such as .bss")
+                .display_order(24),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .global(true)
+                .long("verify")
+                .help("LOAD_FILE: read back what was loaded and compare it with the file")
+                .display_order(24),
+        )
+        .arg(
+            Arg::with_name("load-run")
+                .global(true)
+                .long("load-run")
+                .help("LOAD_FILE: halt the CPU while loading, then start it at the ELF file's entry point")
+                .display_order(24),
+        )
+        .arg(
+            Arg::with_name("jump")
+                .global(true)
+                .long("jump")
+                .value_name("ADDR")
+                .help("LOAD_FILE: like --load-run, but start the CPU at ADDR")
+                .display_order(24)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("boot-reset")
+                .global(true)
+                .long("boot-reset")
+                .help("BOOT: reset the SoC with ctrl_reset before loading")
+                .display_order(24),
+        )
 
         .arg(
             Arg::with_name("load-flash")
+                 .global(true)
                  .long("load-flash")
                  .help("when specified, load-name and load-address attempt to load to FLASH")
                  .display_order(25),
         )
 
+        .arg(
+            Arg::with_name("script")
+                .global(true)
+                .long("script")
+                .value_name("FILE")
+                .help("Run a Rhai script of peeks, pokes, and waits, then exit")
+                .takes_value(true)
+                .display_order(25),
+        )
+
         .arg(
             Arg::with_name("terminal-mouse")
+                .global(true)
                 .long("terminal-mouse")
                 .help("TERMINAL: enable capturing of mouse events")
                 .display_order(26)
                 .takes_value(false)
         )
+        .arg(
+            Arg::with_name("terminal-paste-delay")
+                .global(true)
+                .long("terminal-paste-delay")
+                .value_name("MILLISECONDS")
+                .help("TERMINAL: how long to pause after sending each 16 characters of a paste")
+                .default_value("10")
+                .display_order(26)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("terminal-uart")
+                .global(true)
+                .long("terminal-uart")
+                .value_name("NAME")
+                .help("TERMINAL: UART to attach to, named by its CSR prefix (default uart_xover); give it more than once to attach to several, and press Ctrl-T to switch between them")
+                .multiple(true)
+                .number_of_values(1)
+                .display_order(26)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("terminal-pty")
+                .global(true)
+                .long("terminal-pty")
+                .help("TERMINAL: mirror the UART on a pseudo-terminal for other programs to open")
+                .display_order(26)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("terminal-pty-link")
+                .global(true)
+                .long("terminal-pty-link")
+                .value_name("PATH")
+                .help("TERMINAL: symlink the pseudo-terminal to PATH (implies --terminal-pty)")
+                .display_order(26)
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("messible-address")
+                .global(true)
                 .long("messible-address")
                 .help("MESSIBLE: address to use to get messible messages from")
                 .display_order(27)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("messible")
+                .global(true)
+                .long("messible")
+                .value_name("NAME")
+                .help("MESSIBLE: messible to show, named by its CSR prefix; give it more than once to show several")
+                .multiple(true)
+                .number_of_values(1)
+                .display_order(27)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("messible-in")
+                .global(true)
+                .long("messible-in")
+                .value_name("NAME")
+                .help("MESSIBLE: messible to send keystrokes to, named by its CSR prefix")
+                .display_order(27)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("vcd-signal")
+                .global(true)
+                .long("vcd-signal")
+                .value_name("CSR[:BITS]")
+                .help("VCD_GPIO: register to sample, by name or address, optionally with its width in bits (defaults to every CSR ending in _in)")
+                .display_order(27)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("vcd-file")
+                .global(true)
+                .long("vcd-file")
+                .value_name("FILE")
+                .help("VCD_GPIO: file to write the waveform to")
+                .default_value("gpio.vcd")
+                .display_order(27)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vcd-rate")
+                .global(true)
+                .long("vcd-rate")
+                .value_name("HZ")
+                .help("VCD_GPIO: how many times a second to sample")
+                .default_value("100")
+                .display_order(27)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vcd-duration")
+                .global(true)
+                .long("vcd-duration")
+                .value_name("SECONDS")
+                .help("VCD_GPIO: stop after this long, rather than running until interrupted")
+                .display_order(27)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sniff-forward")
+                .global(true)
+                .long("sniff-forward")
+                .help("SNIFF: carry out each packet on the bridge and answer reads from it, rather than with zeros")
+                .display_order(27),
+        )
+        .arg(
+            Arg::with_name("core-file")
+                .global(true)
+                .long("core-file")
+                .value_name("FILE")
+                .help("CORE_DUMP: file to write the core to")
+                .default_value("core")
+                .display_order(27)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("core-region")
+                .global(true)
+                .long("core-region")
+                .value_name("REGION")
+                .help("CORE_DUMP: memory to include in core files, by csr.csv region name or as ADDR:SIZE (defaults to sram)")
+                .display_order(27)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("core-dir")
+                .global(true)
+                .long("core-dir")
+                .value_name("DIR")
+                .help("GDB: let `monitor coredump` write core files into this directory")
+                .display_order(27)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump-region")
+                .global(true)
+                .long("dump-region")
+                .value_name("REGION")
+                .group("command")
+                .help("read a whole memory region, by name or as ADDR:SIZE, into the file given with --out")
+                .display_order(28)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump-all")
+                .global(true)
+                .long("dump-all")
+                .group("command")
+                .help("read every memory region in the register map into its own file, in the directory given with --out")
+                .display_order(28),
+        )
+        .arg(
+            Arg::with_name("out")
+                .global(true)
+                .long("out")
+                .value_name("PATH")
+                .help("where --dump-region and --dump-all write to (defaults to REGION.bin, and the current directory)")
+                .display_order(28)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fill")
+                .global(true)
+                .long("fill")
+                .value_name("VALUE")
+                .help("write VALUE to every word from the address onwards, for --length bytes")
+                .requires("address")
+                .display_order(28)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memtest")
+                .global(true)
+                .long("memtest")
+                .value_name("TEST")
+                .help("test the memory from the address onwards, for --length bytes, with burst writes and reads")
+                .requires("address")
+                .conflicts_with("fill")
+                .possible_values(&["walking-ones", "addr-in-addr", "random"])
+                .min_values(0)
+                .require_equals(true)
+                .display_order(28)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("length")
+                .global(true)
+                .long("length")
+                .value_name("BYTES")
+                .help("how much memory --fill and --memtest cover (defaults to the whole region, if the address is the name of one)")
+                .display_order(28)
+                .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("burst-length")
+            .global(true)
             .long("burst-length")
             .help("Number of bytes in a burst (implies burst operation)")
             .default_value("4")
@@ -291,14 +1097,46 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
 
         .arg(
             Arg::with_name("hexdump")
+            .global(true)
             .long("hexdump")
             .help("In conjunction with burst-length, report reads as text hexdumps, instead of binary data")
             .display_order(29)
             .takes_value(false),
         )
+        .arg(
+            Arg::with_name("output")
+            .global(true)
+            .long("output")
+            .value_name("FORMAT")
+            .help("How to print peeks, bursts, benchmarks, and lists -- json prints one object per line, and errors as objects too")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .display_order(29)
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch")
+            .global(true)
+            .long("watch")
+            .value_name("MILLISECONDS")
+            .help("Show a hexdump of the address, reading it again every so often and highlighting what changed")
+            .display_order(29)
+            .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("diff")
+            .global(true)
+            .long("diff")
+            .value_name("FILE")
+            .help("Show where memory at the address differs from FILE (reads the length of FILE unless --burst-length is given)")
+            .display_order(29)
+            .conflicts_with("watch")
+            .takes_value(true),
+        )
 
         .arg(
             Arg::with_name("burst-source")
+            .global(true)
             .long("burst-source")
             .help("File for burst data input when sending data to device")
             .display_order(30)
@@ -307,6 +1145,7 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
 
         .arg(
             Arg::with_name("flash-no-reset")
+            .global(true)
             .long("flash-no-reset")
             .help("Don't reset the CPU after resuming")
             .display_order(31)
@@ -315,24 +1154,496 @@ fn clap_app<'a, 'b>() -> App<'a, 'b> {
 
         .arg(
             Arg::with_name("careful-flashing")
+            .global(true)
             .long("careful-flashing")
             .help("Check all intermediate results from burning, instead of just relying on post-flash readback verification. Roughly doubles programming time.")
             .display_order(32)
             .takes_value(false),
         )
+
+        .arg(
+            Arg::with_name("flash-read")
+            .global(true)
+            .long("flash-read")
+            .value_names(&["OFFSET", "LENGTH", "FILE"])
+            .help("Read LENGTH bytes of the flash at OFFSET into FILE, such as to back it up")
+            .number_of_values(3)
+            .display_order(32)
+            .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("flash-erase")
+            .global(true)
+            .long("flash-erase")
+            .value_names(&["OFFSET", "LENGTH"])
+            .help("Erase every flash sector that LENGTH bytes at OFFSET touch")
+            .number_of_values(2)
+            .display_order(32)
+            .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("flash-protect")
+            .global(true)
+            .long("flash-protect")
+            .help("Set the flash's block-protect bits, so that none of it can be erased or programmed")
+            .display_order(32)
+            .takes_value(false),
+        )
+
+        .arg(
+            Arg::with_name("flash-unprotect")
+            .global(true)
+            .long("flash-unprotect")
+            .help("Clear the flash's block-protect bits")
+            .conflicts_with("flash-protect")
+            .display_order(32)
+            .takes_value(false),
+        )
+
+        .arg(
+            Arg::with_name("flash-golden")
+            .global(true)
+            .long("flash-golden")
+            .value_name("OFFSET:LENGTH")
+            .help("Region of flash holding the golden image, which flashing will refuse to erase")
+            .display_order(32)
+            .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("allow-golden")
+            .global(true)
+            .long("allow-golden")
+            .help("Flash even if it means erasing part of the golden image")
+            .requires("flash-golden")
+            .display_order(32)
+            .takes_value(false),
+        )
+
+        .arg(
+            Arg::with_name("flash-idcode")
+            .global(true)
+            .long("flash-idcode")
+            .value_name("IDCODE")
+            .help("IDCODE of the target FPGA, which the bitstream being flashed must be built for")
+            .display_order(32)
+            .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("flash-multiboot")
+            .global(true)
+            .long("flash-multiboot")
+            .value_name("OFFSET")
+            .help("After flashing a bitstream, write a multiboot header at this offset that jumps to it")
+            .display_order(32)
+            .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("update")
+            .global(true)
+            .long("update")
+            .value_name("MANIFEST")
+            .help("Check and write the gateware and firmware images listed in a TOML or JSON manifest, then reset")
+            .display_order(32)
+            .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("fpga-reboot")
+            .global(true)
+            .long("fpga-reboot")
+            .help("FPGA_PROGRAM: once the bitstream is written, reboot the FPGA into it with reboot_ctrl or the ICAP")
+            .display_order(32)
+            .takes_value(false),
+        )
+
+        .arg(
+            Arg::with_name("regions")
+                .global(true)
+                .long("regions")
+                .value_name("FILE")
+                .help("LiteX regions.ld file describing additional memory regions")
+                .display_order(33)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("region-check")
+                .global(true)
+                .long("region-check")
+                .value_name("MODE")
+                .help("Check peek/poke addresses against known memory regions from csr.csv and regions.ld")
+                .possible_values(&["reject", "warn"])
+                .display_order(34)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("force")
+                .global(true)
+                .long("force")
+                .help("Allow writes to the CPU debug interface and to the bridge's own registers, which can wedge the session")
+                .display_order(35)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("read-sensitive")
+                .global(true)
+                .long("read-sensitive")
+                .value_name("CSR_OR_ADDRESS")
+                .help("Register that must never be read implicitly, e.g. when dumping memory (FIFOs and UART data registers are detected automatically)")
+                .multiple(true)
+                .number_of_values(1)
+                .display_order(35)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("tracing")
+                .global(true)
+                .long("tracing")
+                .value_name("LEVEL")
+                .help("Log using tracing-subscriber at the given level, including timing for each bridge operation and GDB packet")
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .display_order(36)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("gdb-sysroot")
+                .global(true)
+                .long("gdb-sysroot")
+                .value_name("DIR")
+                .help("GDB: allow GDB to read files from this directory using vFile packets")
+                .display_order(37)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gdb-exec-file")
+                .global(true)
+                .long("gdb-exec-file")
+                .value_name("PATH")
+                .help("GDB: path of the program being debugged, relative to --gdb-sysroot")
+                .requires("gdb-sysroot")
+                .display_order(38)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("config")
+                .global(true)
+                .long("config")
+                .value_name("FILE")
+                .help("TOML file with [defaults] and [profile.NAME] options, and an [init] section of writes, delays, and asserts to run after connecting")
+                .display_order(39)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .global(true)
+                .long("profile")
+                .value_name("NAME")
+                .help("use the options in [profile.NAME] of the config file")
+                .display_order(39)
+                .takes_value(true),
+        )
+
+        .arg(
+            Arg::with_name("stats")
+                .global(true)
+                .long("stats")
+                .help("Print counts of bridge operations, retries, errors, and average latency on exit")
+                .display_order(40),
+        )
+        .arg(
+            Arg::with_name("stats-interval")
+                .global(true)
+                .long("stats-interval")
+                .value_name("SECONDS")
+                .help("Also log bridge statistics this often while servers are running (implies --stats)")
+                .display_order(40)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bus-errors")
+                .global(true)
+                .long("bus-errors")
+                .help("Check the target's ctrl_bus_errors counter after each batch of server operations, and log which host operations most likely caused any new errors")
+                .display_order(40),
+        )
+
+        .arg(
+            Arg::with_name("record")
+                .global(true)
+                .long("record")
+                .value_name("FILE")
+                .help("Append every bus transaction, with a timestamp, address, and data, to this CSV file")
+                .display_order(41)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .global(true)
+                .long("trace")
+                .value_name("FILE")
+                .help("UART, Ethernet, and USB: write the raw bytes sent and received for each operation to this file, for use as a test fixture")
+                .display_order(41)
+                .takes_value(true),
+        )
+
+        .subcommand(
+            SubCommand::with_name("power")
+                .about("Control power switches and resets described in csr.csv")
+                .arg(
+                    Arg::with_name("action")
+                        .index(1)
+                        .required(true)
+                        .possible_values(&["list", "on", "off", "cycle", "reset"])
+                        .help("operation to perform"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .index(2)
+                        .help("name of the CSR to operate on (reset defaults to ctrl_reset)"),
+                )
+                .arg(
+                    Arg::with_name("delay")
+                        .long("delay")
+                        .value_name("MS")
+                        .default_value("500")
+                        .help("how long to leave power off when cycling")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .short("y")
+                        .long("yes")
+                        .help("don't ask for confirmation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("macro")
+                .about("Run a macro defined in the --config file")
+                .arg(
+                    Arg::with_name("name")
+                        .index(1)
+                        .help("name of the macro to run (omit to list all macros)"),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .index(2)
+                        .multiple(true)
+                        .help("arguments to pass to the macro"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("clock")
+                .about("Inspect and change clock selectors described in csr.csv")
+                .arg(
+                    Arg::with_name("action")
+                        .index(1)
+                        .required(true)
+                        .possible_values(&["list", "set"])
+                        .help("operation to perform"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .index(2)
+                        .help("name of the CSR to operate on"),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .index(3)
+                        .help("value to write to the clock selector"),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .short("y")
+                        .long("yes")
+                        .help("don't ask for confirmation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("peek")
+                .about("Read a CSR or memory address")
+                .arg(
+                    Arg::with_name("address")
+                        .index(1)
+                        .required(true)
+                        .help("address or CSR name to read"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("poke")
+                .about("Write a value to a CSR or memory address")
+                .arg(
+                    Arg::with_name("address")
+                        .index(1)
+                        .required(true)
+                        .help("address or CSR name to write"),
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .index(2)
+                        .required(true)
+                        .help("value to write"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("server")
+                .about("Run one or more servers, like --server")
+                .arg(
+                    Arg::with_name("kind")
+                        .index(1)
+                        .required(true)
+                        .multiple(true)
+                        .possible_values(SERVER_KINDS)
+                        .help("which servers to run"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .about("Load a file into memory")
+                .arg(
+                    Arg::with_name("file")
+                        .index(1)
+                        .required(true)
+                        .help("ELF, Intel hex, or raw binary file to load"),
+                )
+                .arg(
+                    Arg::with_name("address")
+                        .index(2)
+                        .help("where to load a raw binary"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("flash")
+                .about("Write, read, or erase the SPI flash")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("write")
+                        .about("Write a file to flash, only changing what's different")
+                        .arg(
+                            Arg::with_name("file")
+                                .index(1)
+                                .required(true)
+                                .help("file to write"),
+                        )
+                        .arg(
+                            Arg::with_name("offset")
+                                .index(2)
+                                .help("where in the flash to write a raw binary"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("read")
+                        .about("Read part of the flash into a file")
+                        .arg(Arg::with_name("offset").index(1).required(true))
+                        .arg(Arg::with_name("flash-length").value_name("length").index(2).required(true))
+                        .arg(Arg::with_name("file").index(3).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("erase")
+                        .about("Erase every sector that a range of the flash touches")
+                        .arg(Arg::with_name("offset").index(1).required(true))
+                        .arg(Arg::with_name("flash-length").value_name("length").index(2).required(true)),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List the CSRs and memory regions in the register map"),
+        )
+}
+
+fn config_error_message(e: config::ConfigError) -> String {
+    match e {
+        config::ConfigError::NumberParseError(num, e) => {
+            format!("unable to parse the number \"{}\": {}", num, e)
+        }
+        config::ConfigError::NoOperationSpecified => format!("no operation was specified"),
+        config::ConfigError::UnknownServerKind(s) => format!("unknown server '{}', see --help", s),
+        config::ConfigError::SpiParseError(s) => format!("couldn't parse spi pins: {}", s),
+        config::ConfigError::IoError(s) => format!("file error: {}", s),
+        config::ConfigError::InvalidConfig(s) => format!("invalid configuration: {}", s),
+        config::ConfigError::AddressOutOfRange(s) => {
+            format!("address was not in mappable range: {}", s)
+        }
+        config::ConfigError::FeatureNotEnabled(s) => {
+            format!("wishbone-tool was built without the {} feature", s)
+        }
+    }
+}
+
+/// Run the USB recovery sequence against the device selected on the
+/// command line, printing the outcome of each step as it goes.
+fn usb_recover(matches: &clap::ArgMatches) -> Result<(), String> {
+    let usb = Config::usb_bridge(matches).map_err(config_error_message)?;
+    usb.recover(
+        matches.is_present("usb-recover-reset"),
+        |step, result| match result {
+            Ok(()) => println!("{}: ok", step),
+            Err(e) => println!("{}: FAILED ({})", step, e),
+        },
+    )
+    .map_err(|e| match e {
+        wishbone_bridge::BridgeError::NotConnected => "no matching usb device found".to_owned(),
+        e => format!("usb recovery incomplete: {}", e),
+    })
 }
 
 fn main() -> Result<(), String> {
-    flexi_logger::Logger::with_env_or_str("wishbone_tool=info")
-        .format_for_stderr(|write, now, record| {
-            flexi_logger::colored_default_format(write, now, record)?;
-            write!(write, "\r")
-        })
-        .start()
-        .unwrap();
+    let mut matches = clap_app().get_matches();
 
-    let matches = clap_app().get_matches();
+    // Options from config files go in front of the ones that were typed, as
+    // they can't come after a subcommand.
+    let extra = config::file_options(&matches).map_err(config_error_message)?;
+    if !extra.is_empty() {
+        let mut args = std::env::args_os();
+        let args = args
+            .next()
+            .into_iter()
+            .chain(extra.iter().map(Into::into))
+            .chain(args);
+        matches = clap_app().get_matches_from_safe(args).unwrap_or_else(|e| {
+            eprintln!("options from the config file: {}", extra.join(" "));
+            e.exit()
+        });
+    }
+
+    if let Some(level) = matches.value_of("tracing") {
+        use std::str::FromStr;
+        use tracing_subscriber::fmt::format::FmtSpan;
+        // Report each span as it closes so its duration gets printed. `init()`
+        // also routes any remaining `log` records into `tracing`.
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::from_str(level).unwrap())
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        flexi_logger::Logger::with_env_or_str("wishbone_tool=info,wishbone_tool_lib=info")
+            .format_for_stderr(|write, now, record| {
+                flexi_logger::colored_default_format(write, now, record)?;
+                write!(write, "\r")
+            })
+            .start()
+            .unwrap();
+    }
 
+    // With --output json, scripts are reading stdout rather than the log,
+    // so errors go there as well
+    let json = matches.value_of("output") == Some("json");
+    run(matches).inspect_err(|e| {
+        if json {
+            server::print_json_error(e);
+        }
+    })
+}
+
+/// Do whatever the command line asks for, once logging is set up.
+fn run(matches: clap::ArgMatches) -> Result<(), String> {
     // If they specify a "--completion", print it to stdout and exit without error.
     if let Some(shell_str) = matches.value_of("completion") {
         use std::io;
@@ -343,48 +1654,133 @@ fn main() -> Result<(), String> {
         return Ok(());
     }
 
-    let (cfg, bridge) = Config::parse(matches).map_err(|e| match e {
-        config::ConfigError::NumberParseError(num, e) => {
-            format!("unable to parse the number \"{}\": {}", num, e)
+    if matches.is_present("usb-recover") {
+        return usb_recover(&matches);
+    }
+
+    let (cfg, bridge) = Config::parse(matches).map_err(config_error_message)?;
+    server::shutdown::handle_signals().map_err(|e| format!("unable to handle signals: {}", e))?;
+    if let Some(path) = &cfg.record {
+        bridge
+            .record_to(path)
+            .map_err(|e| format!("unable to record to {}: {}", path, e))?;
+    }
+    // A sniffer that doesn't forward packets never uses the bridge, and
+    // neither does listing the register map, so there needn't be a device
+    // at the other end.
+    let uses_bridge = (cfg.server_kind != [ServerKind::Sniff] || cfg.sniff_forward)
+        && cfg.server_kind != [ServerKind::List];
+    if uses_bridge {
+        if !cfg.bridge_connected {
+            bridge
+                .connect()
+                .map_err(|e| format!("unable to connect to bridge: {}", e))?;
         }
-        config::ConfigError::NoOperationSpecified => format!("no operation was specified"),
-        config::ConfigError::UnknownServerKind(s) => format!("unknown server '{}', see --help", s),
-        config::ConfigError::SpiParseError(s) => format!("couldn't parse spi pins: {}", s),
-        config::ConfigError::IoError(s) => format!("file error: {}", s),
-        config::ConfigError::InvalidConfig(s) => format!("invalid configuration: {}", s),
-        config::ConfigError::AddressOutOfRange(s) => {
-            format!("address was not in mappable range: {}", s)
+        // Note where the bus error counter starts, so that only errors caused
+        // from here on are reported.
+        if let Some(watch) = &cfg.bus_errors {
+            watch.report(&bridge);
         }
-    })?;
-    bridge
-        .connect()
-        .map_err(|e| format!("unable to connect to bridge: {}", e))?;
+        server::run_init_steps(&cfg, &bridge).map_err(|e| match e {
+            server::ServerError::InitAssertFailed(name, expected, observed) => format!(
+                "init assert failed: {} was 0x{:08x}, expected 0x{:08x}",
+                name, observed, expected
+            ),
+            server::ServerError::ProtectedWrite(addr, region) => format!(
+                "init step writes to 0x{:08x}, which is part of {} (use --force to allow this)",
+                addr, region
+            ),
+            e => format!("unable to initialize board: {:?}", e),
+        })?;
+    }
+
+    // Macros and scripts run once and exit, so report their errors nicely
+    // rather than panicking in a server thread.
+    if cfg.server_kind == [ServerKind::Macro] || cfg.server_kind == [ServerKind::Script] {
+        let stats_bridge = bridge.clone();
+        let what = if cfg.server_kind == [ServerKind::Script] {
+            "script"
+        } else {
+            "macro"
+        };
+        let result = server::run(cfg.server_kind[0], &cfg, bridge).map_err(|e| match e {
+            server::ServerError::MacroError(e) => format!("macro failed: {}", e),
+            server::ServerError::ScriptError(e) => format!("script failed: {}", e),
+            e => format!("{} failed: {:?}", what, e),
+        });
+        if let Some(watch) = &cfg.bus_errors {
+            watch.report(&stats_bridge);
+        }
+        if cfg.stats {
+            info!("bridge statistics: {}", stats_bridge.stats());
+        }
+        return result;
+    }
+
+    #[cfg(feature = "mdns")]
+    if cfg.mdns {
+        if let Err(e) = server::advertise_mdns(&cfg, &bridge) {
+            error!("unable to advertise servers via mDNS: {}", e);
+        }
+    }
 
+    // Give each server its own place in the bridge's queue, so that one
+    // busy server can't starve the others.
+    let stats_bridge = bridge.clone();
+    let mux = wishbone_bridge::MuxBridge::new(bridge);
     let cfg = Arc::new(cfg);
-    let mut threads = vec![];
-    for server_kind in cfg.server_kind.iter() {
-        use std::thread;
-        let bridge = bridge.clone();
-        let cfg = cfg.clone();
-        let server_kind = *server_kind;
-        let thr_handle = thread::spawn(move || {
-            match server_kind {
-                ServerKind::GDB => server::gdb_server(&cfg, bridge),
-                ServerKind::Wishbone => server::wishbone_server(&cfg, bridge),
-                ServerKind::RandomTest => server::random_test(&cfg, bridge),
-                ServerKind::LoadFile => server::load_file(&cfg, bridge),
-                ServerKind::Terminal => server::terminal_client(&cfg, bridge),
-                ServerKind::MemoryAccess => server::memory_access(&cfg, bridge),
-                ServerKind::Messible => server::messible_client(&cfg, bridge),
-                ServerKind::FlashProgram => server::flash_program(&cfg, bridge),
+    if let Some(interval) = cfg.stats_interval {
+        let bridge = stats_bridge.clone();
+        let bus_errors = cfg.bus_errors.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            info!("bridge statistics: {}", bridge.stats());
+            if let Some(watch) = &bus_errors {
+                watch.report(&bridge);
             }
-            .expect("couldn't start server");
-            debug!("Exited {:?} thread", server_kind);
         });
-        threads.push(thr_handle);
     }
-    for handle in threads {
-        handle.join().ok();
+    // Servers are restarted if they fail, but some errors mean giving up.
+    if let Err(e) = server::supervise(&cfg, &mux) {
+        let message = match e {
+            server::ServerError::ProtectedWrite(addr, region) => format!(
+                "refusing to write to 0x{:08x}, which is part of {} (use --force to allow this)",
+                addr, region
+            ),
+            server::ServerError::GoldenImage(start, end) => format!(
+                "refusing to erase 0x{:08x}-0x{:08x}, which overlaps the golden image (use --allow-golden to allow this)",
+                start, end
+            ),
+            server::ServerError::BitstreamError(e) => format!("refusing to flash: {}", e),
+            server::ServerError::UpdateRefused(e) => format!("refusing to update: {}", e),
+            server::ServerError::NoRegisterMap => {
+                "there's no register map to list -- did you specify --csr-csv?".to_owned()
+            }
+            server::ServerError::BridgeError(e) => format!("{}", e),
+            e => format!("{:?}", e),
+        };
+        error!("{}", message);
+        if cfg.output == config::OutputFormat::Json {
+            server::print_json_error(&message);
+        }
+        std::process::exit(1);
+    }
+    if let Some(watch) = &cfg.bus_errors {
+        watch.report(&stats_bridge);
+    }
+    if cfg.stats {
+        info!("bridge statistics: {}", stats_bridge.stats());
+        for client in mux.stats().iter().filter(|c| c.operations > 0) {
+            info!(
+                "  {}: {} operations, {} bytes read, {} bytes written, {:.3} s busy, {:.3} s waiting",
+                client.name,
+                client.operations,
+                client.bytes_read,
+                client.bytes_written,
+                client.busy.as_secs_f64(),
+                client.waiting.as_secs_f64()
+            );
+        }
     }
 
     Ok(())
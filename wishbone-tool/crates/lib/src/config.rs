@@ -0,0 +1,2616 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::csr_macros::{CsrMacro, Expr, MacroMap, MacroStep};
+use crate::csr_map::{CsrField, CsrMap, FieldMapping};
+use crate::image::{hex_bytes, ImageFormat};
+use crate::server::{
+    AccessPolicy, BoardControl, BusErrorWatch, ControlDomain, Heartbeat, MemTest, NoDevice,
+    RegionDump, ServerKind, VcdCapture, VcdSignal, WriteGuard, BUS_ERRORS_CSR,
+    DEFAULT_TERMINAL_UART,
+};
+use clap::ArgMatches;
+use serde::Deserialize;
+use tracing::warn;
+use wishbone_bridge::{
+    Bridge, CanBridge, CanByteOrder, EthernetBridge, EthernetBridgeProtocol, I2cBridge,
+    MemoryRegion, PCIeBridge, RegionAccess, ReplayBridge, SimBridge, SpiBridge, UartBridge,
+    UartParity, UartStopBits, UnmappedAccessPolicy, UsbBridge,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Couldn't parse string as number
+    NumberParseError(String, std::num::ParseIntError),
+
+    /// Specified a bridge kind that we didn't recognize
+    UnknownServerKind(String),
+
+    /// Specified SPI pinspec was invalid
+    SpiParseError(String),
+
+    /// No operation was specified
+    NoOperationSpecified,
+
+    /// Generic IO Error
+    IoError(io::Error),
+
+    /// The configuration doesn't make sense
+    InvalidConfig(String),
+
+    /// The specified address is outside of legal memory
+    AddressOutOfRange(String),
+
+    /// The requested server needs a cargo feature that wasn't enabled
+    FeatureNotEnabled(String),
+}
+
+impl std::convert::From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::IoError(e)
+    }
+}
+
+pub fn get_base(value: &str) -> (&str, u32) {
+    if value.starts_with("0x") {
+        (value.trim_start_matches("0x"), 16)
+    } else if value.starts_with("0X") {
+        (value.trim_start_matches("0X"), 16)
+    } else if value.starts_with("0b") {
+        (value.trim_start_matches("0b"), 2)
+    } else if value.starts_with("0B") {
+        (value.trim_start_matches("0B"), 2)
+    } else if value.starts_with('0') && value != "0" {
+        (value.trim_start_matches('0'), 8)
+    } else {
+        (value, 10)
+    }
+}
+
+pub fn parse_u8(value: &str) -> Result<u8, ConfigError> {
+    let (value, base) = get_base(value);
+    match u8::from_str_radix(value, base) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(ConfigError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+pub fn parse_u16(value: &str) -> Result<u16, ConfigError> {
+    let (value, base) = get_base(value);
+    match u16::from_str_radix(value, base) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(ConfigError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+pub fn parse_u32(value: &str) -> Result<u32, ConfigError> {
+    let (value, base) = get_base(value);
+    match u32::from_str_radix(value, base) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(ConfigError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+pub fn parse_u32_address(value: &str, offset: u32) -> Result<Option<u32>, ConfigError> {
+    let (value, base) = get_base(value);
+    u32::from_str_radix(value, base)
+        .map(|n| if n >= offset { Some(n - offset) } else { None })
+        .or_else(|e| Err(ConfigError::NumberParseError(value.to_owned(), e)))
+}
+
+/// Parse a SHA-256 digest written out in hex
+fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    let bytes = hex_bytes(hex)?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&bytes);
+    Some(digest)
+}
+
+/// Size of the VexRiscv debug window, used when csr.csv doesn't give one
+const DEBUG_WINDOW_SIZE: u32 = 0x100;
+
+/// A mapping of CSR names to their addresses, as loaded with `--csr-csv`
+pub type RegisterMapping = HashMap<String, Option<u32>>;
+
+/// A step to run right after connecting to the bridge, before any
+/// servers are started.
+#[derive(Debug, Clone)]
+pub enum InitStep {
+    /// Write `value` to `addr`
+    Write { name: String, addr: u32, value: u32 },
+
+    /// Wait for a fixed amount of time
+    Delay(Duration),
+
+    /// Ensure `addr & mask == value`, polling for up to `timeout`
+    Assert {
+        name: String,
+        addr: u32,
+        value: u32,
+        mask: u32,
+        timeout: Duration,
+    },
+}
+
+/// How results such as peeks, bursts, and benchmarks are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// For people to read
+    Text,
+
+    /// One JSON object per line, for scripts to parse
+    Json,
+}
+
+/// A change to one field of a register, from `--set-field`
+#[derive(Debug, Clone)]
+pub struct FieldWrite {
+    pub register: String,
+    pub addr: u32,
+    pub field: CsrField,
+    pub value: u32,
+}
+
+/// An in-field update, as described by an `--update` manifest
+#[derive(Debug, Clone)]
+pub struct UpdateManifest {
+    /// Register that says which device, or which version of it, the target
+    /// is. It has to read `id_value` under `id_mask` for the update to go
+    /// ahead.
+    pub id_register: String,
+    pub id_addr: u32,
+    pub id_value: u32,
+    pub id_mask: u32,
+
+    /// What to write, in order
+    pub images: Vec<UpdateImage>,
+}
+
+/// One of the files that an update writes to flash
+#[derive(Debug, Clone)]
+pub struct UpdateImage {
+    pub name: String,
+    pub file: PathBuf,
+
+    /// Offset into the flash
+    pub offset: u32,
+    pub sha256: [u8; 32],
+}
+
+/// The `[defaults]` and `[profile.NAME]` tables of a config file, which hold
+/// command line options
+#[derive(Deserialize, Default)]
+struct OptionsFile {
+    #[serde(default)]
+    defaults: toml::value::Table,
+
+    #[serde(default)]
+    profile: HashMap<String, toml::value::Table>,
+}
+
+/// The contents of a `--config` file
+#[derive(Deserialize, Default)]
+struct ConfigFile {
+    #[serde(default)]
+    init: InitSection,
+
+    #[serde(default)]
+    macros: HashMap<String, ConfigMacro>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct InitSection {
+    #[serde(default)]
+    steps: Vec<ConfigInitStep>,
+}
+
+/// A single entry in `[init] steps`. Exactly one of `write`, `delay`,
+/// or `assert` must be given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigInitStep {
+    write: Option<String>,
+    delay: Option<u64>,
+    assert: Option<String>,
+    value: Option<u32>,
+    mask: Option<u32>,
+    timeout: Option<u64>,
+}
+
+/// A `[macros.NAME]` table
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigMacro {
+    #[serde(default)]
+    params: Vec<String>,
+    steps: Vec<ConfigMacroStep>,
+}
+
+/// A single entry in a macro's `steps`. Exactly one of `read`, `write`,
+/// `modify`, or `delay` must be given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigMacroStep {
+    read: Option<String>,
+    write: Option<String>,
+    modify: Option<String>,
+    delay: Option<u64>,
+    value: Option<ConfigMacroValue>,
+    mask: Option<u32>,
+}
+
+/// A macro value may be given either as a number or as an expression.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigMacroValue {
+    Number(u32),
+    Expression(String),
+}
+
+/// The contents of an `--update` manifest, in TOML or JSON
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    device: ManifestDevice,
+    images: Vec<ManifestImage>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestDevice {
+    register: String,
+    value: ManifestNumber,
+    mask: Option<ManifestNumber>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestImage {
+    name: String,
+    file: String,
+    offset: ManifestNumber,
+    sha256: String,
+}
+
+/// JSON has no hex numbers, so a number may also be given as a string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ManifestNumber {
+    Number(u32),
+    Text(String),
+}
+
+impl UpdateManifest {
+    /// Load an `--update` manifest. It's read as JSON if the name ends in
+    /// `.json`, and as TOML otherwise.
+    pub fn load(
+        filename: &str,
+        register_mapping: &RegisterMapping,
+    ) -> Result<UpdateManifest, ConfigError> {
+        let contents = std::fs::read_to_string(filename)?;
+        Self::parse(filename, &contents, register_mapping)
+    }
+
+    fn parse(
+        filename: &str,
+        contents: &str,
+        register_mapping: &RegisterMapping,
+    ) -> Result<UpdateManifest, ConfigError> {
+        let invalid = |msg: String| ConfigError::InvalidConfig(format!("{}: {}", filename, msg));
+        let file: ManifestFile = if filename.ends_with(".json") {
+            serde_json::from_str(contents).map_err(|e| invalid(e.to_string()))?
+        } else {
+            toml::from_str(contents).map_err(|e| invalid(e.to_string()))?
+        };
+        let number = |n: ManifestNumber| match n {
+            ManifestNumber::Number(n) => Ok(n),
+            ManifestNumber::Text(text) => parse_u32(&text),
+        };
+
+        // Files are found relative to the manifest
+        let dir = Path::new(filename)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let mut images = vec![];
+        for image in file.images {
+            let sha256 = parse_sha256(&image.sha256)
+                .ok_or_else(|| invalid(format!("{}: sha256 must be 64 hex digits", image.name)))?;
+            images.push(UpdateImage {
+                file: dir.join(&image.file),
+                offset: number(image.offset)?,
+                sha256,
+                name: image.name,
+            });
+        }
+        if images.is_empty() {
+            return Err(invalid("no images to write".to_owned()));
+        }
+
+        Ok(UpdateManifest {
+            id_addr: Config::resolve_absolute(&file.device.register, register_mapping)?,
+            id_register: file.device.register,
+            id_value: number(file.device.value)?,
+            id_mask: file
+                .device
+                .mask
+                .map(number)
+                .transpose()?
+                .unwrap_or(0xffff_ffff),
+            images,
+        })
+    }
+}
+
+/// What to do, from a subcommand such as `peek` or `flash write`, or from
+/// the flags and bare address that did the same job before subcommands.
+/// The flags still work, and can be mixed with subcommands.
+struct Command<'a> {
+    address: Option<&'a str>,
+    value: Option<&'a str>,
+    server_kinds: Vec<&'a str>,
+    load_name: Option<&'a str>,
+    load_address: Option<&'a str>,
+    load_flash: bool,
+    flash_read: Option<Vec<&'a str>>,
+    flash_erase: Option<Vec<&'a str>>,
+    list: bool,
+}
+
+impl<'a> Command<'a> {
+    fn from_matches(matches: &'a ArgMatches) -> Command<'a> {
+        let mut command = Command {
+            address: matches.value_of("address"),
+            value: matches.value_of("value"),
+            server_kinds: matches
+                .values_of("server-kind")
+                .map(Iterator::collect)
+                .unwrap_or_default(),
+            load_name: matches.value_of("load-name"),
+            load_address: matches.value_of("load-address"),
+            load_flash: matches.is_present("load-flash"),
+            flash_read: matches.values_of("flash-read").map(Iterator::collect),
+            flash_erase: matches.values_of("flash-erase").map(Iterator::collect),
+            list: false,
+        };
+        match matches.subcommand() {
+            ("peek", Some(sub)) => command.address = sub.value_of("address"),
+            ("poke", Some(sub)) => {
+                command.address = sub.value_of("address");
+                command.value = sub.value_of("value");
+            }
+            ("server", Some(sub)) => command
+                .server_kinds
+                .extend(sub.values_of("kind").into_iter().flatten()),
+            ("load", Some(sub)) => {
+                command.load_name = sub.value_of("file");
+                command.load_address = sub.value_of("address").or(command.load_address);
+                command.server_kinds.push("load-file");
+            }
+            ("flash", Some(sub)) => match sub.subcommand() {
+                ("write", Some(write)) => {
+                    command.load_name = write.value_of("file");
+                    command.load_address = write.value_of("offset").or(command.load_address);
+                    command.load_flash = true;
+                }
+                ("read", Some(read)) => {
+                    command.flash_read = ["offset", "flash-length", "file"]
+                        .iter()
+                        .map(|arg| read.value_of(arg))
+                        .collect()
+                }
+                ("erase", Some(erase)) => {
+                    command.flash_erase = ["offset", "flash-length"]
+                        .iter()
+                        .map(|arg| erase.value_of(arg))
+                        .collect()
+                }
+                _ => (),
+            },
+            ("list", Some(_)) => command.list = true,
+            _ => (),
+        }
+        command
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub memory_address: Option<u32>,
+
+    /// The CSR or memory region that `memory_address` was given as, if it
+    /// was given by name
+    pub memory_register: Option<String>,
+
+    /// `parse()` had to connect the bridge already, to read the register map
+    /// from the device for `--csr-auto`
+    pub bridge_connected: bool,
+    pub memory_value: Option<u32>,
+    pub server_kind: Vec<ServerKind>,
+    pub bind_addr: String,
+    pub bind_port: u16,
+
+    /// Accept Etherbone datagrams over UDP as well as TCP
+    pub wishbone_udp: bool,
+    pub gdb_port: u16,
+
+    /// Port for `--server http` to listen on
+    pub http_port: u16,
+    pub random_loops: Option<u32>,
+    pub random_address: Option<u32>,
+    pub random_range: Option<u32>,
+
+    /// How many threads `--server random-test` runs at once
+    pub random_threads: u32,
+
+    /// Memory for `--server benchmark` to read and write
+    pub benchmark_address: u32,
+
+    /// Burst lengths for `--server benchmark` to time, in bytes
+    pub benchmark_sizes: Vec<u32>,
+
+    /// How many times `--server benchmark` repeats each operation
+    pub benchmark_count: u32,
+
+    /// Where `--server benchmark` also writes its results
+    pub benchmark_csv: Option<String>,
+    pub messible_address: Option<u32>,
+
+    /// Messibles for `--server messible` to show, named by the prefix of
+    /// their CSRs. If there are none, the one at `messible_address` is shown.
+    pub messibles: Vec<String>,
+
+    /// The messible for `--server messible` to send keystrokes to
+    pub messible_in: Option<String>,
+
+    /// A mapping of CSR names to translated register offsets. If an
+    /// address is a valid CSR but cannot be mapped due to the Wishbone
+    /// aperture being too small, this will contain `Some(None)`.
+    pub register_mapping: RegisterMapping,
+
+    /// The fields of registers, for register maps that describe them
+    pub csr_fields: FieldMapping,
+
+    /// Show the fields of registers read by number, as well as by name
+    pub show_fields: bool,
+
+    /// Fields to change with a read-modify-write
+    pub field_writes: Vec<FieldWrite>,
+
+    /// Known memory regions, used to validate addresses before
+    /// accessing them.
+    pub memory_regions: Vec<MemoryRegion>,
+    pub region_policy: UnmappedAccessPolicy,
+    pub debug_offset: u32,
+    pub load_name: Option<String>,
+    pub load_addr: Option<u32>,
+    pub load_flash: bool,
+
+    /// Clear the parts of ELF segments that aren't in the file, such as
+    /// `.bss`, after loading
+    pub load_zero_bss: bool,
+
+    /// Halt the CPU while loading, then start it at the entry point
+    pub load_run: bool,
+
+    /// Read back what was loaded and compare it with the file
+    pub load_verify: bool,
+
+    /// Where to start the CPU after loading, instead of the entry point
+    pub load_jump: Option<u32>,
+
+    /// Reset the SoC with `ctrl_reset` before `--server boot` loads anything
+    pub boot_reset: bool,
+    pub terminal_mouse: bool,
+
+    /// How long to pause between chunks of a paste into the terminal
+    pub terminal_paste_delay: Duration,
+
+    /// The UARTs for `--server terminal` to attach to, named by the prefix
+    /// of their CSRs. Keystrokes go to the first one to begin with.
+    pub terminal_uarts: Vec<String>,
+
+    /// Mirror the terminal's UARTs on pseudo-terminals, optionally with a
+    /// symlink to the first one
+    pub terminal_pty: bool,
+    pub terminal_pty_link: Option<String>,
+    pub burst_length: u32,
+    pub hexdump: bool,
+
+    /// How to print results on stdout
+    pub output: OutputFormat,
+
+    /// Keep reading `memory_address` this often, showing what changed
+    pub watch_interval: Option<Duration>,
+
+    /// Compare memory at `memory_address` with this file
+    pub diff_file: Option<String>,
+    pub burst_source: Option<String>,
+    pub flash_no_reset: bool,
+    pub careful_flashing: bool,
+
+    /// Offset into the flash, length, and file for `--flash-read`
+    pub flash_read: Option<(u32, u32, String)>,
+
+    /// Offset into the flash and length for `--flash-erase`
+    pub flash_erase: Option<(u32, u32)>,
+
+    /// Set (`true`) or clear (`false`) the flash's block-protect bits
+    pub flash_protect: Option<bool>,
+
+    /// Offset and length of the golden image in flash, which mustn't be
+    /// erased unless `allow_golden` is set
+    pub flash_golden: Option<(u32, u32)>,
+    pub allow_golden: bool,
+
+    /// IDCODE of the target FPGA, which a bitstream being flashed must match
+    pub flash_idcode: Option<u32>,
+
+    /// Offset of a multiboot header to point at the newly flashed bitstream
+    pub flash_multiboot: Option<u32>,
+
+    /// Reboot the FPGA once `--server fpga-program` has written a bitstream
+    pub fpga_reboot: bool,
+
+    /// Images to check and write for `--update`
+    pub update: Option<UpdateManifest>,
+
+    pub board_control: Option<BoardControl>,
+    pub assume_yes: bool,
+
+    /// Directory that GDB may read files from using `vFile` packets
+    pub gdb_sysroot: Option<String>,
+
+    /// Path of the program being debugged, relative to `gdb_sysroot`
+    pub gdb_exec_file: Option<String>,
+
+    /// Width of the CPU's registers in bits, or `None` to read it from `misa`
+    pub gdb_xlen: Option<u32>,
+
+    /// Debug the CPU through a standard RISC-V Debug Module at
+    /// `debug_offset` rather than the VexRiscv debug bridge
+    pub debug_module: bool,
+
+    /// Talk to GDB over stdin and stdout rather than listening on `gdb_port`
+    pub gdb_pipe: bool,
+
+    /// Signal reported to GDB when it interrupts the CPU with Ctrl-C
+    pub gdb_interrupt_signal: u8,
+
+    /// Register writes and checks to perform after connecting
+    pub init_steps: Vec<InitStep>,
+
+    /// Macros defined in the config file
+    pub macros: MacroMap,
+
+    /// A counter to watch for firmware stalls while the GDB server runs
+    pub heartbeat: Option<Heartbeat>,
+
+    /// Regions that user-initiated writes must not touch
+    pub write_guard: WriteGuard,
+
+    /// What clients of the network servers may read and write
+    pub network_access: AccessPolicy,
+
+    /// Advertise the GDB and Wishbone servers via mDNS
+    pub mdns: bool,
+
+    /// Instance name to advertise the servers under, instead of the hostname
+    pub mdns_name: Option<String>,
+
+    /// The macro to run from the command line, along with its arguments.
+    /// A name of `None` lists the available macros instead.
+    pub macro_call: Option<(Option<String>, Vec<u32>)>,
+
+    /// Rhai script to run with `--script`
+    pub script_file: Option<String>,
+
+    /// Print the bridge's statistics on exit
+    pub stats: bool,
+
+    /// How often to log the bridge's statistics while servers are running
+    pub stats_interval: Option<Duration>,
+
+    /// The target's bus error counter, checked along with the statistics
+    /// and, if asked for, after every batch of server operations
+    pub bus_errors: Option<BusErrorWatch>,
+
+    /// CSV file to record every bus transaction to
+    pub record: Option<String>,
+
+    /// What to sample for `--server vcd-gpio`
+    pub vcd: Option<VcdCapture>,
+
+    /// Carry out the packets seen by `--server sniff` on the bridge
+    pub sniff_forward: bool,
+
+    /// Where `--server core-dump` writes the core file
+    pub core_file: String,
+
+    /// Memory to include in core files
+    pub core_regions: Vec<MemoryRegion>,
+
+    /// Directory that `monitor coredump` may write core files into
+    pub core_dir: Option<String>,
+
+    /// Regions to read into files with `--dump-region` or `--dump-all`
+    pub region_dumps: Vec<RegionDump>,
+
+    /// Memory covered by `--fill` or `--memtest`
+    pub test_region: Option<MemoryRegion>,
+
+    /// Word to write across `test_region`
+    pub fill_value: Option<u32>,
+
+    /// Test to run across `test_region`
+    pub memtest: Option<MemTest>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            memory_address: None,
+            memory_register: None,
+            bridge_connected: false,
+            memory_value: None,
+            server_kind: vec![],
+            bind_addr: "127.0.0.1".to_owned(),
+            bind_port: 1234,
+            wishbone_udp: false,
+            gdb_port: 3333,
+            http_port: 8080,
+            random_loops: None,
+            random_address: None,
+            random_range: None,
+            random_threads: 1,
+            benchmark_address: 0,
+            benchmark_sizes: vec![64, 1024, 16384],
+            benchmark_count: 100,
+            benchmark_csv: None,
+            messible_address: None,
+            messibles: vec![],
+            messible_in: None,
+            register_mapping: HashMap::new(),
+            csr_fields: HashMap::new(),
+            show_fields: false,
+            field_writes: vec![],
+            memory_regions: vec![],
+            region_policy: UnmappedAccessPolicy::Allow,
+            debug_offset: 0,
+            load_name: None,
+            load_addr: None,
+            load_flash: false,
+            load_zero_bss: false,
+            load_run: false,
+            load_verify: false,
+            load_jump: None,
+            boot_reset: false,
+            terminal_mouse: false,
+            terminal_paste_delay: Duration::from_millis(10),
+            terminal_uarts: vec![DEFAULT_TERMINAL_UART.to_owned()],
+            terminal_pty: false,
+            terminal_pty_link: None,
+            burst_length: 4,
+            hexdump: false,
+            output: OutputFormat::Text,
+            watch_interval: None,
+            diff_file: None,
+            burst_source: None,
+            flash_no_reset: false,
+            careful_flashing: false,
+            flash_read: None,
+            flash_erase: None,
+            flash_protect: None,
+            flash_golden: None,
+            allow_golden: false,
+            flash_idcode: None,
+            flash_multiboot: None,
+            fpga_reboot: false,
+            update: None,
+            board_control: None,
+            assume_yes: false,
+            gdb_sysroot: None,
+            gdb_exec_file: None,
+            gdb_xlen: Some(32),
+            debug_module: false,
+            gdb_pipe: false,
+            gdb_interrupt_signal: 2,
+            init_steps: vec![],
+            macros: HashMap::new(),
+            macro_call: None,
+            script_file: None,
+            heartbeat: None,
+            write_guard: WriteGuard::default(),
+            network_access: AccessPolicy::default(),
+            mdns: false,
+            mdns_name: None,
+            stats: false,
+            stats_interval: None,
+            bus_errors: None,
+            record: None,
+            vcd: None,
+            sniff_forward: false,
+            core_file: "core".to_owned(),
+            core_regions: vec![],
+            core_dir: None,
+            region_dumps: vec![],
+            test_region: None,
+            fill_value: None,
+            memtest: None,
+        }
+    }
+}
+
+/// Where options that should apply every time are kept:
+/// `$XDG_CONFIG_HOME/wishbone-tool/config.toml`, falling back to
+/// `~/.config/wishbone-tool/config.toml`.
+pub fn user_config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("wishbone-tool").join("config.toml"))
+}
+
+/// Command line options to add from config files. `[defaults]` is read from
+/// the user's config file and then from `--config`, after which the
+/// `[profile.NAME]` chosen with `--profile` is laid over the top. Anything
+/// that was given on the command line is left out, so that it wins.
+///
+/// Each key is the long name of an option. `true` adds a flag, and a list
+/// gives an option once for each entry.
+pub fn file_options(matches: &ArgMatches) -> Result<Vec<String>, ConfigError> {
+    let mut files = vec![];
+    if let Some(path) = user_config_path().filter(|path| path.exists()) {
+        files.push(path);
+    }
+    if let Some(path) = matches.value_of("config") {
+        files.push(PathBuf::from(path));
+    }
+
+    let mut options = toml::value::Table::new();
+    let mut profiles: HashMap<String, toml::value::Table> = HashMap::new();
+    for path in &files {
+        let contents = std::fs::read_to_string(path)?;
+        let file: OptionsFile = toml::from_str(&contents)
+            .map_err(|e| ConfigError::InvalidConfig(format!("{}: {}", path.display(), e)))?;
+        options.extend(file.defaults);
+        for (name, table) in file.profile {
+            profiles.entry(name).or_default().extend(table);
+        }
+    }
+    if let Some(name) = matches.value_of("profile") {
+        let profile = profiles.remove(name).ok_or_else(|| {
+            ConfigError::InvalidConfig(format!("there is no [profile.{}] in any config file", name))
+        })?;
+        options.extend(profile);
+    }
+
+    let mut args = vec![];
+    for (key, value) in options {
+        if key == "config" || key == "profile" {
+            return Err(ConfigError::InvalidConfig(format!(
+                "{} can only be given on the command line",
+                key
+            )));
+        }
+        // The only option whose name differs from its long flag
+        let name = if key == "server" { "server-kind" } else { &key };
+        if matches.occurrences_of(name) > 0 {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => args.push(format!("--{}", key)),
+                toml::Value::Boolean(false) => (),
+                toml::Value::String(s) => args.push(format!("--{}={}", key, s)),
+                toml::Value::Integer(i) => args.push(format!("--{}={}", key, i)),
+                toml::Value::Float(f) => args.push(format!("--{}={}", key, f)),
+                _ => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "{} must be a string, number, boolean, or list of those",
+                        key
+                    )))
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+impl Config {
+    /// The name and fields of the register at `addr`, if the register map
+    /// describes its fields
+    pub fn fields_at(&self, addr: u32) -> Option<(&str, &[CsrField])> {
+        self.csr_fields
+            .iter()
+            .find(|(name, _)| self.register_mapping.get(*name) == Some(&Some(addr)))
+            .map(|(name, fields)| (name.as_str(), fields.as_slice()))
+    }
+
+    /// Names of the CSR groups belonging to the peripheral that the bridge
+    /// selected by `create_bridge()` talks through.
+    fn bridge_csr_prefixes(matches: &ArgMatches) -> &'static [&'static str] {
+        if matches.is_present("spi-ftdi")
+            || matches.is_present("spi-pins")
+            || matches.is_present("i2c")
+            || matches.is_present("i2c-pins")
+            || matches.is_present("sim")
+            || matches.is_present("can")
+            || matches.is_present("replay")
+        {
+            &[]
+        } else if matches.is_present("rfcomm") || matches.is_present("serial") {
+            &["uartbone"]
+        } else if matches.is_present("pcie-bar") || matches.is_present("pcie-device") {
+            &["pcie"]
+        } else if matches.is_present("ethernet-host") {
+            &["ethmac", "ethphy"]
+        } else {
+            &["usb"]
+        }
+    }
+
+    fn create_bridge(matches: &ArgMatches) -> Result<Bridge, ConfigError> {
+        // FTDI adapters speak the SPI protocol, but aren't configured with pins.
+        if matches.is_present("spi-ftdi") {
+            let mut spi_config = SpiBridge::new_ftdi();
+            if let Some(spec) = matches.value_of("spi-ftdi") {
+                let fields: Vec<&str> = spec.split(':').collect();
+                if fields.len() < 2 || fields.len() > 3 {
+                    return Err(ConfigError::SpiParseError(format!(
+                        "{} is not a valid ftdi device -- must be VID:PID[:CHANNEL]",
+                        spec
+                    )));
+                }
+                spi_config
+                    .ftdi_vid(parse_u16(&format!(
+                        "0x{}",
+                        fields[0].trim_start_matches("0x")
+                    ))?)
+                    .ftdi_pid(parse_u16(&format!(
+                        "0x{}",
+                        fields[1].trim_start_matches("0x")
+                    ))?);
+                if let Some(channel) = fields.get(2) {
+                    spi_config.ftdi_interface(match channel.to_uppercase().as_str() {
+                        "A" => 0,
+                        "B" => 1,
+                        "C" => 2,
+                        "D" => 3,
+                        other => {
+                            return Err(ConfigError::SpiParseError(format!(
+                                "unknown ftdi channel {}",
+                                other
+                            )))
+                        }
+                    });
+                }
+            }
+            // unwrap() is safe because there is a default value
+            spi_config
+                .frequency(parse_u32(matches.value_of("spi-frequency").unwrap())?)
+                .three_wire(matches.is_present("spi-three-wire"));
+            return spi_config.create().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create ftdi spi bridge: {}", e))
+            });
+        }
+
+        // If SPI pins are specified, then assume the bridge must be SPI.
+        if let Some(pins) = matches.value_of("spi-pins") {
+            return SpiBridge::new(pins)
+                .or_else(|e| Err(ConfigError::SpiParseError(e)))?
+                .gpio_chip(matches.value_of("spi-gpiochip").unwrap())
+                .create()
+                .map_err(|e| {
+                    ConfigError::InvalidConfig(format!("unable to create spi bridge: {}", e))
+                });
+        }
+
+        // I2C, either via a Linux adapter or bit-banged pins
+        if matches.is_present("i2c") || matches.is_present("i2c-pins") {
+            let mut i2c_config = if let Some(pins) = matches.value_of("i2c-pins") {
+                I2cBridge::new_gpio(pins).map_err(ConfigError::InvalidConfig)?
+            } else {
+                // unwrap() is safe because we just checked one of them was present
+                I2cBridge::new(matches.value_of("i2c").unwrap())
+            };
+            // unwrap() is safe because there is a default value
+            let address = parse_u8(matches.value_of("i2c-address").unwrap())?;
+            if address > 0x7f {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "i2c address 0x{:02x} is not a 7-bit address",
+                    address
+                )));
+            }
+            i2c_config
+                .address(address)
+                .frequency(parse_u32(matches.value_of("i2c-frequency").unwrap())?);
+            return i2c_config.create().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create i2c bridge: {}", e))
+            });
+        }
+
+        // A recording of an earlier session
+        if let Some(path) = matches.value_of("replay") {
+            return ReplayBridge::new(path)
+                .strict(!matches.is_present("replay-any-order"))
+                .create()
+                .map_err(|e| {
+                    ConfigError::InvalidConfig(format!("unable to read recording: {}", e))
+                });
+        }
+
+        // A simulation, reached over a Unix socket or TCP
+        if let Some(socket) = matches.value_of("sim") {
+            let mut sim_config = if matches.is_present("sim-tcp") {
+                SimBridge::tcp(socket).map_err(|e| {
+                    ConfigError::InvalidConfig(format!("invalid simulation address: {}", e))
+                })?
+            } else {
+                Self::sim_unix_bridge(socket)?
+            };
+            // unwrap() is safe because there is a default value
+            sim_config.timeout(Duration::from_secs(parse_u32(
+                matches.value_of("sim-timeout").unwrap(),
+            )? as u64));
+            return sim_config.create().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create simulation bridge: {}", e))
+            });
+        }
+
+        // A CAN bus, via SocketCAN
+        if let Some(interface) = matches.value_of("can") {
+            let mut can_config = CanBridge::new(interface);
+            // unwrap() is safe because there is a default value
+            can_config
+                .tx_id(parse_u32(matches.value_of("can-tx-id").unwrap())?)
+                .rx_id(parse_u32(matches.value_of("can-rx-id").unwrap())?)
+                .extended_ids(matches.is_present("can-extended-ids"))
+                .byte_order(match matches.value_of("can-byte-order") {
+                    Some("little") => CanByteOrder::LittleEndian,
+                    _ => CanByteOrder::BigEndian,
+                })
+                .write_ack(!matches.is_present("can-no-write-ack"));
+            return can_config.create().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create can bridge: {}", e))
+            });
+        }
+
+        // A Bluetooth serial adapter, reached over RFCOMM. The adapter sets
+        // its own line settings, so none of the serial options apply.
+        if let Some(addr) = matches.value_of("rfcomm") {
+            // unwrap() is safe because there is a default value
+            let channel = parse_u8(matches.value_of("rfcomm-channel").unwrap())?;
+            let mut uart_config = UartBridge::rfcomm(addr, channel).map_err(|e| {
+                ConfigError::InvalidConfig(format!(
+                    "invalid rfcomm address or channel {} {}: {}",
+                    addr, channel, e
+                ))
+            })?;
+            if let Some(path) = matches.value_of("trace") {
+                uart_config.trace_to(path);
+            }
+            return uart_config.create().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create uart bridge: {}", e))
+            });
+        }
+
+        // UART bridge config
+        if let Some(port) = matches.value_of("serial") {
+            // Strip off the trailing ":" on Windows, since it's confusing
+            let serial_port = if cfg!(windows) && port.ends_with(':') {
+                port.get(0..port.len() - 1).unwrap_or("")
+            } else {
+                port
+            };
+            let mut uart_config = UartBridge::new(serial_port).or_else(|e| {
+                Err(ConfigError::InvalidConfig(format!(
+                    "invalid serial port: {}",
+                    e
+                )))
+            })?;
+
+            if let Some(baud) = matches.value_of("baud") {
+                uart_config.baud(parse_u32(baud)?);
+            }
+            // clap limits these to the values listed in --help
+            match matches.value_of("serial-parity") {
+                Some("odd") => uart_config.parity(UartParity::Odd),
+                Some("even") => uart_config.parity(UartParity::Even),
+                _ => uart_config.parity(UartParity::None),
+            };
+            match matches.value_of("serial-stop-bits") {
+                Some("2") => uart_config.stop_bits(UartStopBits::Two),
+                _ => uart_config.stop_bits(UartStopBits::One),
+            };
+            uart_config.rts_cts(matches.value_of("serial-flow-control") == Some("rts-cts"));
+            if let Some(path) = matches.value_of("trace") {
+                uart_config.trace_to(path);
+            }
+
+            return uart_config.create().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create uart bridge: {}", e))
+            });
+        }
+
+        // PCIe BAR-as-a-file
+        if let Some(pcie_bar) = matches.value_of("pcie-bar") {
+            return PCIeBridge::new(pcie_bar)
+                .or_else(|e| {
+                    Err(ConfigError::InvalidConfig(format!(
+                        "invalid pcie bar: {}",
+                        e
+                    )))
+                })?
+                .create()
+                .map_err(|e| {
+                    ConfigError::InvalidConfig(format!("unable to create pcie bridge: {}", e))
+                });
+        }
+
+        // PCIe device located via sysfs
+        if let Some(bdf) = matches.value_of("pcie-device") {
+            return PCIeBridge::from_bdf(bdf)
+                .map_err(|e| ConfigError::InvalidConfig(format!("invalid pcie device: {}", e)))?
+                .create()
+                .map_err(|e| {
+                    ConfigError::InvalidConfig(format!("unable to create pcie bridge: {}", e))
+                });
+        }
+
+        // Ethernet (TCP, UDP, or WebSocket)
+        if let Some(host) = matches.value_of("ethernet-host") {
+            let ethernet_tcp = matches.is_present("ethernet-tcp");
+            let ethernet_port = parse_u16(matches.value_of("ethernet-port").unwrap())?;
+            let mut ebc = EthernetBridge::new(host)
+                .or_else(|_| EthernetBridge::new(&format!("{}:{}", host, ethernet_port)))
+                .or_else(|e| {
+                    Err(ConfigError::InvalidConfig(format!(
+                        "invalid ethernet address: {}",
+                        e
+                    )))
+                })?;
+            if let Some(path) = matches.value_of("ethernet-websocket") {
+                ebc.websocket_path(path);
+                // Send the host name as it was given, so that gateways serving
+                // several sites from one address can tell which one is wanted.
+                if !host.contains(':') {
+                    ebc.websocket_host(&format!("{}:{}", host, ethernet_port));
+                }
+            }
+            if matches.is_present("ethernet-tls") {
+                Self::ethernet_tls(&mut ebc, matches, host)?;
+            }
+            ebc.protocol(if matches.is_present("ethernet-websocket") {
+                EthernetBridgeProtocol::WebSocket
+            } else if ethernet_tcp {
+                EthernetBridgeProtocol::TCP
+            } else {
+                EthernetBridgeProtocol::UDP
+            })
+            .port(ethernet_port)
+            .mtu(parse_u16(matches.value_of("ethernet-mtu").unwrap())? as usize);
+            ebc.fast_writes(matches.is_present("fast-writes"));
+            if let Some(path) = matches.value_of("trace") {
+                ebc.trace_to(path);
+            }
+            return ebc.create().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to create ethernet bridge: {}", e))
+            });
+        }
+
+        // Fall back to USB
+        Self::usb_bridge(matches)?
+            .create()
+            .map_err(|e| ConfigError::InvalidConfig(format!("unable to create usb bridge: {}", e)))
+    }
+
+    #[cfg(unix)]
+    fn sim_unix_bridge(socket: &str) -> Result<SimBridge, ConfigError> {
+        Ok(SimBridge::new(socket))
+    }
+
+    #[cfg(not(unix))]
+    fn sim_unix_bridge(_socket: &str) -> Result<SimBridge, ConfigError> {
+        Err(ConfigError::InvalidConfig(
+            "Unix sockets aren't available on this platform, use --sim-tcp".to_owned(),
+        ))
+    }
+
+    /// Apply the `--ethernet-tls` options to `ebc`.
+    #[cfg(feature = "tls")]
+    fn ethernet_tls(
+        ebc: &mut EthernetBridge,
+        matches: &ArgMatches,
+        host: &str,
+    ) -> Result<(), ConfigError> {
+        ebc.tls(true);
+        // Check the certificate against the host name as it was given, unless
+        // it's an address.
+        if host.parse::<std::net::SocketAddr>().is_err() {
+            ebc.tls_server_name(host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host));
+        }
+        if let Some(ca) = matches.value_of("ethernet-tls-ca") {
+            ebc.tls_ca_file(ca);
+        }
+        if let (Some(cert), Some(key)) = (
+            matches.value_of("ethernet-tls-cert"),
+            matches.value_of("ethernet-tls-key"),
+        ) {
+            ebc.tls_client_cert(cert, key);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn ethernet_tls(
+        _ebc: &mut EthernetBridge,
+        _matches: &ArgMatches,
+        _host: &str,
+    ) -> Result<(), ConfigError> {
+        Err(ConfigError::FeatureNotEnabled("tls".to_owned()))
+    }
+
+    /// Build the USB bridge configuration from the `--vid`, `--pid`, `--bus`,
+    /// and `--device` arguments.
+    pub fn usb_bridge(matches: &ArgMatches) -> Result<UsbBridge, ConfigError> {
+        let mut usb_config = UsbBridge::new();
+        if let Some(vid) = matches.value_of("vid") {
+            usb_config.vid(parse_u16(vid)?);
+        }
+        if let Some(pid) = matches.value_of("pid") {
+            usb_config.pid(parse_u16(pid)?);
+        }
+        if let Some(bus) = matches.value_of("bus") {
+            usb_config.bus(parse_u8(bus)?);
+        }
+        if let Some(device) = matches.value_of("device") {
+            usb_config.device(parse_u8(device)?);
+        }
+        if let Some(serial) = matches.value_of("usb-serial") {
+            usb_config.serial(serial);
+        }
+        if let Some(path) = matches.value_of("trace") {
+            usb_config.trace_to(path);
+        }
+        Ok(usb_config)
+    }
+
+    pub fn parse(matches: ArgMatches) -> Result<(Self, Bridge), ConfigError> {
+        let mut server_kind = vec![];
+        // The register map may have to be read from the device, in which
+        // case connect to it before anything is looked up by name.
+        let early_bridge = if matches.is_present("csr-auto") {
+            let bridge = Self::create_bridge(&matches)?;
+            bridge.connect().map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to connect to bridge: {}", e))
+            })?;
+            Some(bridge)
+        } else {
+            None
+        };
+        let embedded = match (&early_bridge, matches.value_of("csr-auto")) {
+            (Some(bridge), addr) => {
+                let map = CsrMap::download(bridge, addr.map(parse_u32).transpose()?)?;
+                if map.is_none() {
+                    match matches.value_of("csr-csv") {
+                        Some(file) => warn!("no register map on the device, using {}", file),
+                        None => warn!("no register map on the device, carrying on without one"),
+                    }
+                }
+                map
+            }
+            (None, _) => None,
+        };
+        let (
+            CsrMap {
+                registers: register_mapping,
+                regions: mut memory_regions,
+                fields: csr_fields,
+                memories,
+            },
+            offset,
+        ) = Self::parse_csr_map(
+            embedded,
+            matches.value_of("csr-csv"),
+            matches.value_of("register-offset"),
+        )?;
+
+        let command = Command::from_matches(&matches);
+        let load_name = command.load_name.map(|n| n.to_owned());
+        let load_flash = command.load_flash;
+        let load_addr = if let Some(addr) = command.load_address {
+            if load_name.is_none() & !load_flash {
+                server_kind.push(ServerKind::MemoryAccess);
+            }
+            Some(Self::resolve_absolute(addr, &register_mapping)?)
+        } else {
+            None
+        };
+        if let Some(name) = load_name.as_deref().filter(|_| load_flash) {
+            if load_addr.is_some() || ImageFormat::of_file(name)?.has_addresses() {
+                server_kind.push(ServerKind::FlashProgram);
+            } else if matches.subcommand_name() == Some("flash") {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} doesn't say where it goes, so flash write needs an OFFSET",
+                    name
+                )));
+            }
+        }
+        let load_zero_bss = matches.is_present("load-zero-bss");
+        let load_jump = matches
+            .value_of("jump")
+            .map(|addr| Self::resolve_absolute(addr, &register_mapping))
+            .transpose()?;
+        let boot_reset = matches.is_present("boot-reset");
+        // Server kinds are only parsed further down
+        let booting = command.server_kinds.contains(&"boot");
+        if booting && load_name.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "--server boot needs a program to load with --load-name".to_owned(),
+            ));
+        }
+        let fpga_programming = command.server_kinds.contains(&"fpga-program");
+        if fpga_programming && load_name.is_none() {
+            return Err(ConfigError::InvalidConfig(
+                "--server fpga-program needs a bitstream to write with --load-name".to_owned(),
+            ));
+        }
+        // Both would write the file to flash at once
+        if fpga_programming && load_flash {
+            return Err(ConfigError::InvalidConfig(
+                "--server fpga-program already writes to flash, so leave out --load-flash"
+                    .to_owned(),
+            ));
+        }
+        let load_run = matches.is_present("load-run") || load_jump.is_some() || booting;
+        let load_verify = matches.is_present("verify");
+        if load_run && !cfg!(feature = "cpu") {
+            return Err(ConfigError::FeatureNotEnabled("cpu".to_owned()));
+        }
+
+        let memory_value = command.value.map(|v| parse_u32(v)).transpose()?;
+
+        // unwrap() is safe because there is a default value
+        let gdb_port = parse_u16(matches.value_of("gdb-port").unwrap())?;
+        let bind_port = parse_u16(matches.value_of("wishbone-port").unwrap())?;
+        let http_port = parse_u16(matches.value_of("http-port").unwrap())?;
+        let diff_file = matches.value_of("diff").map(|f| f.to_owned());
+        let burst_length = match &diff_file {
+            // Reads are made a word at a time
+            Some(file) if matches.occurrences_of("burst-length") == 0 => {
+                (std::fs::metadata(file)?.len() as u32 + 3) & !3
+            }
+            _ => parse_u32(matches.value_of("burst-length").unwrap())?,
+        };
+        let watch_interval = matches
+            .value_of("watch")
+            .map(|ms| parse_u32(ms).map(|ms| Duration::from_millis(ms as u64)))
+            .transpose()?;
+
+        let bind_addr = matches
+            .value_of("bind-addr")
+            .map(|addr| addr.to_owned())
+            .unwrap_or_else(|| "127.0.0.1".to_owned());
+
+        for sk in &command.server_kinds {
+            server_kind.push(ServerKind::from_string(sk)?);
+        }
+        if command.list {
+            server_kind.push(ServerKind::List);
+        }
+
+        let random_loops = if let Some(random_loops) = matches.value_of("random-loops") {
+            Some(parse_u32(random_loops)?)
+        } else {
+            None
+        };
+
+        let random_address = if let Some(random_address) = matches.value_of("random-address") {
+            Some(Self::resolve_absolute(random_address, &register_mapping)?)
+        } else {
+            None
+        };
+
+        let random_range = if let Some(random_range) = matches.value_of("random-range") {
+            Some(parse_u32(random_range)?)
+        } else {
+            None
+        };
+
+        let random_threads = parse_u32(matches.value_of("random-threads").unwrap())?;
+        if random_threads == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "--random-threads should be at least 1".to_owned(),
+            ));
+        }
+        if let Some(range) = random_range.filter(|_| random_threads > 1) {
+            if range / random_threads < 4 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--random-range {} is too small to give each of the {} threads a word of its own",
+                    range, random_threads
+                )));
+            }
+        }
+
+        let benchmark_address = match matches.value_of("benchmark-address") {
+            Some(addr) => Self::resolve_absolute(addr, &register_mapping)?,
+            None => match register_mapping.get("sram") {
+                Some(Some(addr)) => *addr,
+                _ if server_kind.contains(&ServerKind::Benchmark) => {
+                    return Err(ConfigError::InvalidConfig(
+                        "--server benchmark needs --benchmark-address, or a register map with sram in it"
+                            .to_owned(),
+                    ))
+                }
+                _ => 0,
+            },
+        };
+        let mut benchmark_sizes = vec![];
+        for size in matches.value_of("benchmark-sizes").unwrap().split(',') {
+            let size = parse_u32(size.trim())?;
+            if size == 0 || size & 3 != 0 {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--benchmark-sizes {} should be a multiple of 4",
+                    size
+                )));
+            }
+            benchmark_sizes.push(size);
+        }
+        let benchmark_count = parse_u32(matches.value_of("benchmark-count").unwrap())?.max(1);
+        let benchmark_csv = matches.value_of("benchmark-csv").map(|f| f.to_owned());
+
+        if let Some(regions_file) = matches.value_of("regions") {
+            memory_regions.append(&mut Self::parse_regions_ld(regions_file, offset)?);
+        }
+        if let Some(sensitive) = matches.values_of("read-sensitive") {
+            for item in sensitive {
+                Self::mark_read_sensitive(&mut memory_regions, item, offset)?;
+            }
+        }
+
+        let region_policy = match matches.value_of("region-check") {
+            Some("reject") => UnmappedAccessPolicy::Reject,
+            Some("warn") => UnmappedAccessPolicy::Warn,
+            _ => UnmappedAccessPolicy::Allow,
+        };
+        if region_policy != UnmappedAccessPolicy::Allow && memory_regions.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "region checking requested, but no memory regions were loaded".to_owned(),
+            ));
+        }
+
+        let messible_address = if let Some(messible_address) = matches.value_of("messible-address")
+        {
+            Some(Self::resolve_address(
+                messible_address,
+                &register_mapping,
+                offset,
+            )?)
+        } else if let Some(base) = register_mapping.get("messible_out") {
+            Some((*base).ok_or_else(|| ConfigError::AddressOutOfRange("messible_out".to_owned()))?)
+        } else {
+            None
+        };
+
+        let messibles: Vec<String> = matches
+            .values_of("messible")
+            .map(|names| names.map(|name| name.to_lowercase()).collect())
+            .unwrap_or_default();
+        let messible_in = matches.value_of("messible-in").map(|n| n.to_lowercase());
+        for name in messibles.iter().chain(&messible_in) {
+            if !["in", "out", "status"]
+                .iter()
+                .all(|reg| register_mapping.contains_key(&format!("{}_{}", name, reg)))
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "no {} messible registers present in csv file",
+                    name
+                )));
+            }
+        }
+
+        let debug_offset = if let Some(debug_offset) = matches.value_of("debug-offset") {
+            Self::resolve_address(debug_offset, &register_mapping, offset)?
+        } else if let Some(debug_offset) = register_mapping.get("vexriscv_debug") {
+            (*debug_offset)
+                .ok_or_else(|| ConfigError::AddressOutOfRange("vexriscv_debug".to_owned()))?
+        } else {
+            0xf00f_0000
+        };
+
+        // Writing to the debug window or to the bridge's own peripheral can
+        // cut the session off, so keep user-initiated writes away from them.
+        let bridge_prefixes = Self::bridge_csr_prefixes(&matches);
+        let mut protected_regions: Vec<MemoryRegion> = memory_regions
+            .iter()
+            .filter(|r| bridge_prefixes.iter().any(|p| r.name.starts_with(p)))
+            .cloned()
+            .collect();
+        protected_regions.push(
+            match memory_regions.iter().find(|r| r.name == "vexriscv_debug") {
+                Some(region) => region.clone(),
+                None => MemoryRegion::new(
+                    "vexriscv_debug",
+                    debug_offset,
+                    DEBUG_WINDOW_SIZE,
+                    RegionAccess::ReadWrite,
+                ),
+            },
+        );
+        let write_guard = WriteGuard::new(protected_regions, matches.is_present("force"));
+        let network_access = AccessPolicy::new(
+            matches.is_present("read-only"),
+            Self::parse_allowed_ranges(&matches, &register_mapping, offset)?,
+        );
+
+        let heartbeat = if let Some(addr) = matches.value_of("heartbeat") {
+            if !server_kind.contains(&ServerKind::GDB) {
+                return Err(ConfigError::InvalidConfig(
+                    "--heartbeat needs the CPU's run state, so it requires --server gdb".to_owned(),
+                ));
+            }
+            Some(Heartbeat {
+                addr: Self::resolve_address(addr, &register_mapping, offset)?,
+                // unwrap() is safe because there is a default value
+                timeout: Duration::from_secs(parse_u32(
+                    matches.value_of("heartbeat-timeout").unwrap(),
+                )? as u64),
+                halt: matches.is_present("heartbeat-halt"),
+            })
+        } else {
+            None
+        };
+
+        let memory_address = command
+            .address
+            .map(|addr| Self::resolve_address(addr, &register_mapping, offset))
+            .transpose()?;
+        let field_writes = Self::parse_field_writes(&matches, &register_mapping, &csr_fields)?;
+
+        let memory_register = command
+            .address
+            .map(|addr| addr.to_lowercase())
+            .filter(|name| register_mapping.contains_key(name));
+
+        let (init_steps, macros) = if let Some(config_file) = matches.value_of("config") {
+            Self::parse_config_file(config_file, &register_mapping, offset)?
+        } else {
+            (vec![], HashMap::new())
+        };
+
+        let mut assume_yes = false;
+        let board_control = match matches.subcommand() {
+            (domain @ "power", Some(sub)) | (domain @ "clock", Some(sub)) => {
+                assume_yes = sub.is_present("yes");
+                let domain = if domain == "power" {
+                    ControlDomain::Power
+                } else {
+                    ControlDomain::Clock
+                };
+                server_kind.push(ServerKind::BoardControl);
+                Some(Self::parse_board_control(domain, sub, &register_mapping)?)
+            }
+            _ => None,
+        };
+
+        let macro_call = if let ("macro", Some(sub)) = matches.subcommand() {
+            let name = sub.value_of("name").map(|n| n.to_owned());
+            let args = sub
+                .values_of("args")
+                .map(|args| args.map(parse_u32).collect::<Result<Vec<_>, _>>())
+                .transpose()?
+                .unwrap_or_default();
+            if let Some(name) = &name {
+                let csr_macro = macros.get(name).ok_or_else(|| {
+                    ConfigError::InvalidConfig(format!(
+                        "no macro named {} -- did you specify --config?",
+                        name
+                    ))
+                })?;
+                if csr_macro.params.len() != args.len() {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "macro {} takes {} argument(s): {}",
+                        name,
+                        csr_macro.params.len(),
+                        csr_macro.params.join(" ")
+                    )));
+                }
+            }
+            server_kind.push(ServerKind::Macro);
+            Some((name, args))
+        } else {
+            None
+        };
+
+        let script_file = matches.value_of("script").map(|s| s.to_owned());
+        if script_file.is_some() {
+            server_kind.push(ServerKind::Script);
+        }
+
+        let region_dumps = Self::parse_region_dumps(&matches, &memory_regions, &memories)?;
+        if !region_dumps.is_empty() {
+            server_kind.push(ServerKind::DumpRegions);
+        }
+
+        let fill_value = matches.value_of("fill").map(parse_u32).transpose()?;
+        let memtest = if matches.is_present("memtest") {
+            Some(MemTest::from_string(
+                matches.value_of("memtest").unwrap_or("walking-ones"),
+            )?)
+        } else {
+            None
+        };
+        let test_region = if fill_value.is_some() || memtest.is_some() {
+            Some(Self::parse_test_region(
+                &matches,
+                command.address.unwrap_or_default(),
+                memory_address,
+                &memory_regions,
+            )?)
+        } else {
+            None
+        };
+        if fill_value.is_some() {
+            server_kind.push(ServerKind::Fill);
+        }
+        if memtest.is_some() {
+            server_kind.push(ServerKind::MemTest);
+        }
+
+        let flash_read = match &command.flash_read {
+            Some(values) => match values[..] {
+                [offset, length, file_name] => {
+                    Some((parse_u32(offset)?, parse_u32(length)?, file_name.to_owned()))
+                }
+                _ => None,
+            },
+            None => None,
+        };
+        let flash_erase = match &command.flash_erase {
+            Some(values) => match values[..] {
+                [offset, length] => Some((parse_u32(offset)?, parse_u32(length)?)),
+                _ => None,
+            },
+            None => None,
+        };
+        let flash_protect = if matches.is_present("flash-protect") {
+            Some(true)
+        } else if matches.is_present("flash-unprotect") {
+            Some(false)
+        } else {
+            None
+        };
+        if flash_read.is_some() || flash_erase.is_some() || flash_protect.is_some() {
+            // They would race each other for the flash
+            if server_kind.contains(&ServerKind::FlashProgram) || fpga_programming {
+                return Err(ConfigError::InvalidConfig(
+                    "--flash-read, --flash-erase, and --flash-protect can't be combined with --load-flash or --server fpga-program".to_owned(),
+                ));
+            }
+            server_kind.push(ServerKind::FlashCommands);
+        }
+        let update = matches
+            .value_of("update")
+            .map(|file_name| UpdateManifest::load(file_name, &register_mapping))
+            .transpose()?;
+        if update.is_some() {
+            if server_kind.contains(&ServerKind::FlashProgram)
+                || server_kind.contains(&ServerKind::FlashCommands)
+                || fpga_programming
+            {
+                return Err(ConfigError::InvalidConfig(
+                    "--update writes the flash itself, so it can't be combined with other flash operations".to_owned(),
+                ));
+            }
+            server_kind.push(ServerKind::Update);
+        }
+
+        if server_kind.is_empty() {
+            if memory_address.is_none() && field_writes.is_empty() {
+                return Err(ConfigError::NoOperationSpecified);
+            }
+            server_kind.push(ServerKind::MemoryAccess);
+        }
+        if let Some(feature) = server_kind.iter().find_map(|s| s.missing_feature()) {
+            return Err(ConfigError::FeatureNotEnabled(feature.to_owned()));
+        }
+
+        let mdns = matches.is_present("mdns");
+        if mdns {
+            if !cfg!(feature = "mdns") {
+                return Err(ConfigError::FeatureNotEnabled("mdns".to_owned()));
+            }
+            if !server_kind.contains(&ServerKind::GDB)
+                && !server_kind.contains(&ServerKind::Wishbone)
+                && !server_kind.contains(&ServerKind::Http)
+            {
+                return Err(ConfigError::InvalidConfig(
+                    "--mdns advertises the gdb, wishbone, and http servers, but none was requested"
+                        .to_owned(),
+                ));
+            }
+        }
+        let mdns_name = matches.value_of("mdns-name").map(|s| s.to_owned());
+        let stats_interval = matches
+            .value_of("stats-interval")
+            .map(parse_u32)
+            .transpose()?
+            .map(|secs| Duration::from_secs(secs as u64));
+        let stats = matches.is_present("stats") || stats_interval.is_some();
+        let bus_errors = match register_mapping.get(BUS_ERRORS_CSR) {
+            Some(Some(addr)) if stats || matches.is_present("bus-errors") => {
+                Some(BusErrorWatch::new(
+                    *addr,
+                    matches.is_present("bus-errors"),
+                    memory_regions.clone(),
+                ))
+            }
+            _ if matches.is_present("bus-errors") => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--bus-errors needs a --csr-csv with a reachable {} register",
+                    BUS_ERRORS_CSR
+                )))
+            }
+            _ => None,
+        };
+        let record = matches.value_of("record").map(|s| s.to_owned());
+        let core_regions = Self::parse_core_regions(&matches, &memory_regions)?;
+        let vcd = if server_kind.contains(&ServerKind::VcdGpio) {
+            Some(Self::parse_vcd_capture(
+                &matches,
+                &register_mapping,
+                offset,
+            )?)
+        } else {
+            None
+        };
+
+        let terminal_uarts: Vec<String> = match matches.values_of("terminal-uart") {
+            Some(names) => names.map(|name| name.to_lowercase()).collect(),
+            None => vec![DEFAULT_TERMINAL_UART.to_owned()],
+        };
+
+        // Validate the configuration is correct
+        if matches.value_of("csr-csv").is_none()
+            && server_kind.contains(&ServerKind::Terminal)
+            && terminal_uarts != [DEFAULT_TERMINAL_UART]
+        {
+            return Err(ConfigError::InvalidConfig(
+                "--terminal-uart needs --csr-csv to find the UART's registers".to_owned(),
+            ));
+        }
+        if matches.value_of("csr-csv").is_some() {
+            if server_kind.contains(&ServerKind::GDB) {
+                // You asked for --server gdb but no vexriscv jtag interfaces is found in the csr.csv file it should complain.
+                if !register_mapping.contains_key("vexriscv_debug") {
+                    return Err(ConfigError::InvalidConfig(
+                        "GDB specified but no vexriscv address present in csv file".to_owned(),
+                    ));
+                }
+            }
+            if server_kind.contains(&ServerKind::Terminal) {
+                // You asked for --server terminal but no uart is found in the csr.csv file it should complain.
+                for name in &terminal_uarts {
+                    if !["rxtx", "rxempty", "txfull"]
+                        .iter()
+                        .all(|reg| register_mapping.contains_key(&format!("{}_{}", name, reg)))
+                    {
+                        return Err(ConfigError::InvalidConfig(format!(
+                            "Terminal specified, but no {} uart registers present in csv file",
+                            name
+                        )));
+                    }
+                }
+            }
+            if server_kind.contains(&ServerKind::FlashProgram)
+                || server_kind.contains(&ServerKind::FlashCommands)
+                || server_kind.contains(&ServerKind::FpgaProgram)
+                || server_kind.contains(&ServerKind::Update)
+            {
+                if !["spinor", "spiflash_core_master_rxtx", "spiflash_bitbang"]
+                    .iter()
+                    .any(|name| register_mapping.contains_key(*name))
+                {
+                    return Err(ConfigError::InvalidConfig(
+                        "Flash programming requested, but no spinor or LiteSPI flash controller present in csv file"
+                            .to_owned(),
+                    ));
+                }
+            }
+        }
+
+        let terminal_mouse = matches.is_present("terminal-mouse") || cfg!(windows);
+        // unwrap() is safe because there is a default value
+        let terminal_paste_delay = Duration::from_millis(parse_u32(
+            matches.value_of("terminal-paste-delay").unwrap(),
+        )? as u64);
+        let terminal_pty_link = matches.value_of("terminal-pty-link").map(|l| l.to_owned());
+        let terminal_pty = matches.is_present("terminal-pty") || terminal_pty_link.is_some();
+        let hexdump = matches.is_present("hexdump");
+        let output = match matches.value_of("output") {
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        };
+        let flash_no_reset = matches.is_present("flash-no-reset");
+        let careful_flashing = matches.is_present("careful-flashing");
+        let flash_golden = match matches.value_of("flash-golden") {
+            Some(spec) => match spec.split_once(':') {
+                Some((offset, length)) => Some((parse_u32(offset)?, parse_u32(length)?)),
+                None => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "{} is not a valid golden image -- must be OFFSET:LENGTH",
+                        spec
+                    )))
+                }
+            },
+            None => None,
+        };
+        let allow_golden = matches.is_present("allow-golden");
+        let flash_idcode = matches
+            .value_of("flash-idcode")
+            .map(parse_u32)
+            .transpose()?;
+        let flash_multiboot = matches
+            .value_of("flash-multiboot")
+            .map(parse_u32)
+            .transpose()?;
+        let fpga_reboot = matches.is_present("fpga-reboot");
+
+        let burst_source = matches.value_of("burst-source").map(|n| n.to_owned());
+
+        let gdb_sysroot = matches.value_of("gdb-sysroot").map(|n| n.to_owned());
+        let gdb_exec_file = matches.value_of("gdb-exec-file").map(|n| n.to_owned());
+        let gdb_xlen = match matches.value_of("xlen") {
+            None | Some("32") => Some(32),
+            Some("64") => Some(64),
+            Some("auto") => None,
+            Some(other) => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--xlen must be 32, 64, or auto, not {}",
+                    other
+                )))
+            }
+        };
+        let debug_module = match matches.value_of("debug-interface") {
+            None | Some("vexriscv") => false,
+            Some("dm") => true,
+            Some(other) => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "--debug-interface must be vexriscv or dm, not {}",
+                    other
+                )))
+            }
+        };
+        // Most servers print to stdout, which GDB needs to itself.
+        let gdb_pipe = matches.is_present("gdb-pipe");
+        if gdb_pipe && server_kind != [ServerKind::GDB] {
+            return Err(ConfigError::InvalidConfig(
+                "--gdb-pipe needs stdout to itself, so it only works with `-s gdb` on its own"
+                    .to_owned(),
+            ));
+        }
+        let gdb_interrupt_signal = match matches.value_of("gdb-interrupt-signal") {
+            None | Some("SIGINT") | Some("INT") => 2,
+            Some("SIGTRAP") | Some("TRAP") => 5,
+            Some(other) => other.parse::<u8>().map_err(|_| {
+                ConfigError::InvalidConfig(format!(
+                    "--gdb-interrupt-signal must be SIGINT, SIGTRAP, or a number, not {}",
+                    other
+                ))
+            })?,
+        };
+        let core_dir = matches.value_of("core-dir").map(|n| n.to_owned());
+        if let Some(core_dir) = &core_dir {
+            if !Path::new(core_dir).is_dir() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "core file directory {} is not a directory",
+                    core_dir
+                )));
+            }
+        }
+        if let Some(sysroot) = &gdb_sysroot {
+            if !Path::new(sysroot).is_dir() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "gdb sysroot {} is not a directory",
+                    sysroot
+                )));
+            }
+            if let Some(exec_file) = &gdb_exec_file {
+                if !Path::new(sysroot)
+                    .join(exec_file.trim_start_matches('/'))
+                    .is_file()
+                {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "gdb exec file {} does not exist in {}",
+                        exec_file, sysroot
+                    )));
+                }
+            }
+        }
+
+        // Sniffing without forwarding never touches a device, and neither
+        // does listing the register map, so don't insist on finding one.
+        let bridge_connected = early_bridge.is_some();
+        let bridge = if let Some(bridge) = early_bridge {
+            bridge
+        } else if (server_kind == [ServerKind::Sniff] && !matches.is_present("sniff-forward"))
+            || server_kind == [ServerKind::List]
+        {
+            Bridge::from_driver(Box::new(NoDevice))
+        } else {
+            Self::create_bridge(&matches)?
+        };
+
+        Ok((
+            Config {
+                memory_address,
+                memory_register,
+                bridge_connected,
+                memory_value,
+                server_kind,
+                bind_port,
+                wishbone_udp: matches.is_present("wishbone-udp"),
+                bind_addr,
+                gdb_port,
+                http_port,
+                random_loops,
+                random_address,
+                random_range,
+                random_threads,
+                benchmark_address,
+                benchmark_sizes,
+                benchmark_count,
+                benchmark_csv,
+                messible_address,
+                messibles,
+                messible_in,
+                register_mapping,
+                csr_fields,
+                show_fields: matches.is_present("fields"),
+                field_writes,
+                memory_regions,
+                region_policy,
+                debug_offset,
+                load_name,
+                load_addr,
+                load_flash,
+                load_zero_bss,
+                load_run,
+                load_verify,
+                load_jump,
+                boot_reset,
+                terminal_mouse,
+                terminal_paste_delay,
+                terminal_uarts,
+                terminal_pty,
+                terminal_pty_link,
+                burst_length,
+                hexdump,
+                output,
+                watch_interval,
+                diff_file,
+                burst_source,
+                flash_no_reset,
+                careful_flashing,
+                flash_read,
+                flash_erase,
+                flash_protect,
+                flash_golden,
+                allow_golden,
+                flash_idcode,
+                flash_multiboot,
+                fpga_reboot,
+                update,
+                board_control,
+                assume_yes,
+                gdb_sysroot,
+                gdb_exec_file,
+                gdb_xlen,
+                debug_module,
+                gdb_pipe,
+                gdb_interrupt_signal,
+                init_steps,
+                macros,
+                macro_call,
+                script_file,
+                heartbeat,
+                write_guard,
+                network_access,
+                mdns,
+                mdns_name,
+                stats,
+                stats_interval,
+                bus_errors,
+                record,
+                vcd,
+                sniff_forward: matches.is_present("sniff-forward"),
+                // unwrap() is safe because there is a default value
+                core_file: matches.value_of("core-file").unwrap().to_owned(),
+                core_regions,
+                core_dir,
+                region_dumps,
+                test_region,
+                fill_value,
+                memtest,
+            },
+            bridge,
+        ))
+    }
+
+    /// Work out which memory goes in core files. Each `--core-region` is
+    /// either the name of a region from csr.csv or `ADDR:SIZE`. With none
+    /// given, `sram` is used if csr.csv has it, as that's usually where
+    /// the stack is.
+    fn parse_core_regions(
+        matches: &ArgMatches,
+        memory_regions: &[MemoryRegion],
+    ) -> Result<Vec<MemoryRegion>, ConfigError> {
+        let mut core_regions = vec![];
+        for spec in matches.values_of("core-region").into_iter().flatten() {
+            core_regions.push(Self::find_region(spec, memory_regions)?);
+        }
+        if matches.occurrences_of("core-region") == 0 {
+            core_regions.extend(memory_regions.iter().find(|r| r.name == "sram").cloned());
+        }
+        Ok(core_regions)
+    }
+
+    /// Look up a region given either by name or as `ADDR:SIZE`.
+    fn find_region(
+        spec: &str,
+        memory_regions: &[MemoryRegion],
+    ) -> Result<MemoryRegion, ConfigError> {
+        if let Some((addr, size)) = spec.split_once(':') {
+            return Ok(MemoryRegion::new(
+                spec,
+                parse_u32(addr)?,
+                parse_u32(size)?,
+                RegionAccess::ReadWrite,
+            ));
+        }
+        memory_regions
+            .iter()
+            .find(|region| region.name == spec.to_lowercase())
+            .cloned()
+            .ok_or_else(|| {
+                ConfigError::InvalidConfig(format!("no memory region named {} in csr.csv", spec))
+            })
+    }
+
+    /// Work out what `--dump-region` or `--dump-all` should read, and which
+    /// file each region goes to.
+    fn parse_region_dumps(
+        matches: &ArgMatches,
+        memory_regions: &[MemoryRegion],
+        memories: &[String],
+    ) -> Result<Vec<RegionDump>, ConfigError> {
+        let out = matches.value_of("out");
+        if let Some(spec) = matches.value_of("dump-region") {
+            let region = Self::find_region(spec, memory_regions)?;
+            let file = match out {
+                Some(out) => PathBuf::from(out),
+                None if spec.contains(':') => PathBuf::from(format!("{:08x}.bin", region.base)),
+                None => PathBuf::from(format!("{}.bin", region.name)),
+            };
+            return Ok(vec![RegionDump { region, file }]);
+        }
+        if !matches.is_present("dump-all") {
+            return Ok(vec![]);
+        }
+
+        let dir = Path::new(out.unwrap_or("."));
+        let dumps: Vec<RegionDump> = memories
+            .iter()
+            .filter_map(|name| memory_regions.iter().find(|r| &r.name == name))
+            .map(|region| RegionDump {
+                region: region.clone(),
+                file: dir.join(format!("{}.bin", region.name)),
+            })
+            .collect();
+        if dumps.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "--dump-all needs a register map that lists memory regions".to_owned(),
+            ));
+        }
+        Ok(dumps)
+    }
+
+    /// Work out what `--fill` or `--memtest` should cover: `--length` bytes
+    /// from the address, or the whole of the region `spec` names.
+    fn parse_test_region(
+        matches: &ArgMatches,
+        spec: &str,
+        memory_address: Option<u32>,
+        memory_regions: &[MemoryRegion],
+    ) -> Result<MemoryRegion, ConfigError> {
+        let base = memory_address.unwrap_or_default();
+        let size = match matches.value_of("length") {
+            Some(length) => parse_u32(length)?,
+            None => Self::find_region(spec, memory_regions)
+                .map(|region| region.size)
+                .map_err(|_| {
+                    ConfigError::InvalidConfig(format!(
+                        "{} isn't the name of a memory region, so --length is needed",
+                        spec
+                    ))
+                })?,
+        };
+        if size == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "--length should be more than 0".to_owned(),
+            ));
+        }
+        if base & 3 != 0 || size & 3 != 0 {
+            return Err(ConfigError::InvalidConfig(format!(
+                "--fill and --memtest work a word at a time, so 0x{:08x} and --length {} should both be multiples of 4",
+                base, size
+            )));
+        }
+        Ok(MemoryRegion::new(spec, base, size, RegionAccess::ReadWrite))
+    }
+
+    /// Parse each `--allow-range ADDR:LEN`, where `ADDR` may also be the
+    /// name of a CSR.
+    fn parse_allowed_ranges(
+        matches: &ArgMatches,
+        register_mapping: &RegisterMapping,
+        offset: u32,
+    ) -> Result<Vec<MemoryRegion>, ConfigError> {
+        let mut ranges = vec![];
+        for spec in matches.values_of("allow-range").into_iter().flatten() {
+            let (addr, len) = spec.split_once(':').ok_or_else(|| {
+                ConfigError::InvalidConfig(format!(
+                    "--allow-range {} should be of the form ADDR:LEN",
+                    spec
+                ))
+            })?;
+            ranges.push(MemoryRegion::new(
+                spec,
+                Self::resolve_address(addr, register_mapping, offset)?,
+                parse_u32(len)?,
+                RegionAccess::ReadWrite,
+            ));
+        }
+        Ok(ranges)
+    }
+
+    /// Work out what `--server vcd-gpio` should sample. Each `--vcd-signal`
+    /// is a CSR name or address, optionally followed by `:BITS`. With none
+    /// given, every CSR whose name ends in `_in` is sampled, which covers
+    /// LiteX `GPIOIn` and `GPIOTristate` blocks.
+    fn parse_vcd_capture(
+        matches: &ArgMatches,
+        register_mapping: &RegisterMapping,
+        offset: u32,
+    ) -> Result<VcdCapture, ConfigError> {
+        let mut signals = vec![];
+        for spec in matches.values_of("vcd-signal").into_iter().flatten() {
+            let (name, width) = match spec.split_once(':') {
+                Some((name, bits)) => (name, parse_u32(bits)?),
+                None => (spec, 32),
+            };
+            if !(1..=32).contains(&width) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} is not a valid signal -- width must be between 1 and 32 bits",
+                    spec
+                )));
+            }
+            signals.push(VcdSignal {
+                name: name.to_lowercase(),
+                addr: Self::resolve_address(name, register_mapping, offset)?,
+                width,
+            });
+        }
+        if signals.is_empty() {
+            let mut names: Vec<&String> = register_mapping
+                .keys()
+                .filter(|name| name.ends_with("_in"))
+                .collect();
+            names.sort();
+            for name in names {
+                signals.push(VcdSignal {
+                    name: name.clone(),
+                    addr: Self::resolve_address(name, register_mapping, offset)?,
+                    width: 32,
+                });
+            }
+        }
+        if signals.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "vcd-gpio needs --vcd-signal, or a csr.csv with GPIO input CSRs".to_owned(),
+            ));
+        }
+
+        // unwrap() is safe because there is a default value
+        let rate = parse_u32(matches.value_of("vcd-rate").unwrap())?;
+        if rate == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "--vcd-rate must be at least 1".to_owned(),
+            ));
+        }
+        let duration = matches
+            .value_of("vcd-duration")
+            .map(parse_u32)
+            .transpose()?
+            .map(|secs| Duration::from_secs(secs as u64));
+        Ok(VcdCapture {
+            signals,
+            file: matches.value_of("vcd-file").unwrap().to_owned(),
+            rate,
+            duration,
+        })
+    }
+
+    /// Parse each `--set-field REG.FIELD=VALUE`, checking that the register
+    /// has such a field and that the value fits in it.
+    fn parse_field_writes(
+        matches: &ArgMatches,
+        register_mapping: &RegisterMapping,
+        csr_fields: &FieldMapping,
+    ) -> Result<Vec<FieldWrite>, ConfigError> {
+        let mut writes = vec![];
+        for spec in matches.values_of("set-field").into_iter().flatten() {
+            let invalid =
+                |msg: String| ConfigError::InvalidConfig(format!("--set-field {}: {}", spec, msg));
+            let (target, value) = spec
+                .split_once('=')
+                .ok_or_else(|| invalid("expected REG.FIELD=VALUE".to_owned()))?;
+            let (register, field_name) = target
+                .rsplit_once('.')
+                .ok_or_else(|| invalid("expected REG.FIELD=VALUE".to_owned()))?;
+            let register = register.to_lowercase();
+            let fields = csr_fields.get(&register).ok_or_else(|| {
+                invalid(format!(
+                    "the register map doesn't describe any fields of {}",
+                    register
+                ))
+            })?;
+            let field = fields
+                .iter()
+                .find(|f| f.name.eq_ignore_ascii_case(field_name))
+                .ok_or_else(|| {
+                    let names: Vec<&str> = fields.iter().map(|f| f.name.as_str()).collect();
+                    invalid(format!(
+                        "{} has no field {} (it has {})",
+                        register,
+                        field_name,
+                        names.join(", ")
+                    ))
+                })?;
+            let value = parse_u32(value)?;
+            if value > field.mask() >> field.offset {
+                return Err(invalid(format!(
+                    "{} is too big for a {}-bit field",
+                    value, field.width
+                )));
+            }
+            let addr = register_mapping
+                .get(&register)
+                .copied()
+                .flatten()
+                .ok_or_else(|| ConfigError::AddressOutOfRange(register.clone()))?;
+            writes.push(FieldWrite {
+                addr,
+                register,
+                field: field.clone(),
+                value,
+            });
+        }
+        Ok(writes)
+    }
+
+    /// Turn either a CSR name or a number into an address on the bus.
+    fn resolve_address(
+        addr: &str,
+        register_mapping: &RegisterMapping,
+        offset: u32,
+    ) -> Result<u32, ConfigError> {
+        if let Some(mapped_addr) = register_mapping.get(&addr.to_lowercase()) {
+            (*mapped_addr).ok_or_else(|| ConfigError::AddressOutOfRange(addr.to_owned()))
+        } else {
+            Self::check_not_a_name(addr, register_mapping)?;
+            parse_u32_address(addr, offset)?
+                .ok_or_else(|| ConfigError::AddressOutOfRange(addr.to_owned()))
+        }
+    }
+
+    /// Like `resolve_address()`, but for options where a number is taken as
+    /// it is, without `--register-offset`.
+    fn resolve_absolute(
+        addr: &str,
+        register_mapping: &RegisterMapping,
+    ) -> Result<u32, ConfigError> {
+        if let Some(mapped_addr) = register_mapping.get(&addr.to_lowercase()) {
+            (*mapped_addr).ok_or_else(|| ConfigError::AddressOutOfRange(addr.to_owned()))
+        } else {
+            Self::check_not_a_name(addr, register_mapping)?;
+            parse_u32(addr)
+        }
+    }
+
+    /// Numbers always start with a digit, so anything else was meant to be
+    /// the name of a register that isn't in the map.
+    fn check_not_a_name(addr: &str, register_mapping: &RegisterMapping) -> Result<(), ConfigError> {
+        if addr.starts_with(|c: char| c.is_ascii_digit()) {
+            return Ok(());
+        }
+        Err(ConfigError::InvalidConfig(if register_mapping.is_empty() {
+            format!("{} isn't a number -- did you specify --csr-csv?", addr)
+        } else {
+            format!("no CSR or memory region named {}", addr)
+        }))
+    }
+
+    fn parse_config_file(
+        filename: &str,
+        register_mapping: &RegisterMapping,
+        offset: u32,
+    ) -> Result<(Vec<InitStep>, MacroMap), ConfigError> {
+        let contents = std::fs::read_to_string(filename)?;
+        let config_file: ConfigFile = toml::from_str(&contents)
+            .map_err(|e| ConfigError::InvalidConfig(format!("{}: {}", filename, e)))?;
+
+        let mut steps = vec![];
+        for (idx, step) in config_file.init.steps.into_iter().enumerate() {
+            let invalid = |msg: &str| {
+                ConfigError::InvalidConfig(format!("{}: init step {}: {}", filename, idx + 1, msg))
+            };
+            steps.push(match (step.write, step.delay, step.assert) {
+                (Some(name), None, None) => InitStep::Write {
+                    addr: Self::resolve_address(&name, register_mapping, offset)?,
+                    value: step
+                        .value
+                        .ok_or_else(|| invalid("write requires a value"))?,
+                    name,
+                },
+                (None, Some(ms), None) => InitStep::Delay(Duration::from_millis(ms)),
+                (None, None, Some(name)) => InitStep::Assert {
+                    addr: Self::resolve_address(&name, register_mapping, offset)?,
+                    value: step
+                        .value
+                        .ok_or_else(|| invalid("assert requires a value"))?,
+                    mask: step.mask.unwrap_or(0xffff_ffff),
+                    timeout: Duration::from_millis(step.timeout.unwrap_or(0)),
+                    name,
+                },
+                _ => {
+                    return Err(invalid(
+                        "must contain exactly one of write, delay, or assert",
+                    ))
+                }
+            });
+        }
+
+        let mut macros = HashMap::new();
+        for (name, config_macro) in config_file.macros {
+            let csr_macro = Self::parse_macro(&name, config_macro, register_mapping, offset)
+                .map_err(|e| match e {
+                    ConfigError::InvalidConfig(msg) => {
+                        ConfigError::InvalidConfig(format!("{}: {}", filename, msg))
+                    }
+                    e => e,
+                })?;
+            macros.insert(name, csr_macro);
+        }
+        Ok((steps, macros))
+    }
+
+    fn parse_macro(
+        name: &str,
+        config_macro: ConfigMacro,
+        register_mapping: &RegisterMapping,
+        offset: u32,
+    ) -> Result<CsrMacro, ConfigError> {
+        let params = config_macro.params;
+        let mut steps = vec![];
+        for (idx, step) in config_macro.steps.into_iter().enumerate() {
+            let invalid = |msg: &str| {
+                ConfigError::InvalidConfig(format!("macro {} step {}: {}", name, idx + 1, msg))
+            };
+            let value = |value: Option<ConfigMacroValue>, what: &str| match value {
+                Some(ConfigMacroValue::Number(v)) => Ok(Expr::Literal(v as u64)),
+                Some(ConfigMacroValue::Expression(e)) => {
+                    Expr::parse(&e, &params).map_err(|msg| invalid(&format!("{}: {}", e, msg)))
+                }
+                None => Err(invalid(&format!("{} requires a value", what))),
+            };
+            steps.push(match (step.read, step.write, step.modify, step.delay) {
+                (Some(reg), None, None, None) => MacroStep::Read {
+                    addr: Self::resolve_address(&reg, register_mapping, offset)?,
+                    name: reg,
+                },
+                (None, Some(reg), None, None) => MacroStep::Write {
+                    addr: Self::resolve_address(&reg, register_mapping, offset)?,
+                    value: value(step.value, "write")?,
+                    name: reg,
+                },
+                (None, None, Some(reg), None) => MacroStep::Modify {
+                    addr: Self::resolve_address(&reg, register_mapping, offset)?,
+                    value: value(step.value, "modify")?,
+                    mask: step.mask.ok_or_else(|| invalid("modify requires a mask"))?,
+                    name: reg,
+                },
+                (None, None, None, Some(ms)) => MacroStep::Delay(Duration::from_millis(ms)),
+                _ => {
+                    return Err(invalid(
+                        "must contain exactly one of read, write, modify, or delay",
+                    ))
+                }
+            });
+        }
+        Ok(CsrMacro { params, steps })
+    }
+
+    fn parse_board_control(
+        domain: ControlDomain,
+        matches: &ArgMatches,
+        register_mapping: &RegisterMapping,
+    ) -> Result<BoardControl, ConfigError> {
+        // unwrap() is safe because `action` is a required argument
+        let action = matches.value_of("action").unwrap();
+        if action == "list" {
+            return Ok(BoardControl::List(domain));
+        }
+
+        let name = match (action, matches.value_of("target")) {
+            (_, Some(name)) => name.to_lowercase(),
+            ("reset", None) => "ctrl_reset".to_owned(),
+            (_, None) => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{} requires the name of a CSR",
+                    action
+                )))
+            }
+        };
+        let addr = match register_mapping.get(&name) {
+            Some(Some(addr)) => *addr,
+            Some(None) => return Err(ConfigError::AddressOutOfRange(name)),
+            None => {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "CSR {} not found -- did you specify --csr-csv?",
+                    name
+                )))
+            }
+        };
+
+        Ok(match action {
+            "on" => BoardControl::Set {
+                name,
+                addr,
+                value: 1,
+            },
+            "off" => BoardControl::Set {
+                name,
+                addr,
+                value: 0,
+            },
+            "set" => {
+                let value = matches.value_of("value").ok_or_else(|| {
+                    ConfigError::InvalidConfig("clock set requires a value".to_owned())
+                })?;
+                BoardControl::Set {
+                    name,
+                    addr,
+                    value: parse_u32(value)?,
+                }
+            }
+            "cycle" => BoardControl::Cycle {
+                name,
+                addr,
+                // unwrap() is safe because there is a default value
+                delay: std::time::Duration::from_millis(parse_u32(
+                    matches.value_of("delay").unwrap(),
+                )? as u64),
+            },
+            _ => BoardControl::Reset { name, addr },
+        })
+    }
+
+    /// Load the register map given with `--csr-csv`, which may be a
+    /// LiteX `csr.csv` or `csr.json`, or an SVD file, and rebase it by
+    /// `--register-offset`. A map read from the device with `--csr-auto`
+    /// takes the place of the file.
+    fn parse_csr_map(
+        embedded: Option<CsrMap>,
+        filename: Option<&str>,
+        offset_str: Option<&str>,
+    ) -> Result<(CsrMap, u32), ConfigError> {
+        let mut csr_map = match (embedded, filename) {
+            (Some(map), _) => map,
+            (None, Some(s)) => CsrMap::load(s)?,
+            (None, None) => CsrMap::default(),
+        };
+        let map = &mut csr_map.registers;
+
+        let mut offset = 0;
+
+        // Now that we have everything loaded into the hashmap, see if we need to offset values.
+        if let Some(offset_str) = offset_str {
+            if let Some(offset_value) = map.get(offset_str) {
+                // All values in the map should be non-None now, since we haven't updated the offsets yet.
+                offset =
+                    offset_value.expect("offset_value was None even though it was in the array");
+            } else {
+                offset = parse_u32(offset_str)?;
+            }
+            for (_key, val) in map.iter_mut() {
+                // If there's an offset and it's outside of the range of
+                // this CSR, just ignore the CSR.
+                if val.expect("val was None") < offset {
+                    *val = None;
+                } else {
+                    *val.as_mut().unwrap() -= offset;
+                }
+            }
+            csr_map.regions = Self::offset_regions(std::mem::take(&mut csr_map.regions), offset);
+        }
+        Ok((csr_map, offset))
+    }
+
+    /// Flag a register as read-sensitive. `item` may either be the name of a
+    /// region loaded from csr.csv, or an address.
+    fn mark_read_sensitive(
+        regions: &mut Vec<MemoryRegion>,
+        item: &str,
+        offset: u32,
+    ) -> Result<(), ConfigError> {
+        let name = item.to_lowercase();
+        let mut found = false;
+        for region in regions.iter_mut().filter(|r| r.name == name) {
+            region.read_sensitive = true;
+            found = true;
+        }
+        if found {
+            return Ok(());
+        }
+
+        let addr = parse_u32_address(item, offset)
+            .map_err(|_| {
+                ConfigError::InvalidConfig(format!("read-sensitive register {} not found", item))
+            })?
+            .ok_or_else(|| ConfigError::AddressOutOfRange(item.to_owned()))?;
+        let mut region = MemoryRegion::new(&name, addr, 4, RegionAccess::ReadWrite);
+        region.read_sensitive = true;
+        regions.push(region);
+        Ok(())
+    }
+
+    /// Rebase a list of regions by `offset`, dropping any that start below it.
+    fn offset_regions(regions: Vec<MemoryRegion>, offset: u32) -> Vec<MemoryRegion> {
+        regions
+            .into_iter()
+            .filter(|r| r.base >= offset)
+            .map(|mut r| {
+                r.base -= offset;
+                r
+            })
+            .collect()
+    }
+
+    /// Parse the `MEMORY` block of a LiteX `regions.ld` file, which
+    /// contains lines such as `sram : ORIGIN = 0x10000000, LENGTH = 0x00002000`.
+    fn parse_regions_ld(filename: &str, offset: u32) -> Result<Vec<MemoryRegion>, ConfigError> {
+        use std::io::{BufRead, BufReader};
+        let mut regions = vec![];
+        for line in BufReader::new(File::open(filename)?).lines() {
+            let line = line?;
+            let (name, rest) = match line.find(':') {
+                Some(idx) => (line[..idx].trim(), &line[idx + 1..]),
+                None => continue,
+            };
+            let mut origin = None;
+            let mut length = None;
+            for field in rest.split(',') {
+                let mut kv = field.splitn(2, '=');
+                match (kv.next().map(|k| k.trim()), kv.next().map(|v| v.trim())) {
+                    (Some("ORIGIN"), Some(v)) => origin = Some(parse_u32(v)?),
+                    (Some("LENGTH"), Some(v)) => length = Some(parse_u32(v)?),
+                    _ => (),
+                }
+            }
+            if let (Some(origin), Some(length)) = (origin, length) {
+                regions.push(MemoryRegion::new(
+                    &name.to_lowercase(),
+                    origin,
+                    length,
+                    RegionAccess::ReadWrite,
+                ));
+            }
+        }
+        Ok(Self::offset_regions(regions, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn mapping() -> RegisterMapping {
+        let mut mapping = RegisterMapping::new();
+        mapping.insert("ctrl_scratch".to_owned(), Some(0xe000_0004));
+        mapping
+    }
+
+    fn toml_manifest(sha256: &str) -> String {
+        [
+            "[device]\nregister = \"ctrl_scratch\"\nvalue = 0x1234\n",
+            "[[images]]\nname = \"gateware\"\nfile = \"top.bin\"\noffset = 0x40000\nsha256 = \"",
+            sha256,
+            "\"\n",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn manifest_from_toml() {
+        let manifest =
+            UpdateManifest::parse("fw/update.toml", &toml_manifest(DIGEST), &mapping()).unwrap();
+        assert_eq!(manifest.id_register, "ctrl_scratch");
+        assert_eq!(manifest.id_addr, 0xe000_0004);
+        assert_eq!(manifest.id_value, 0x1234);
+        assert_eq!(manifest.id_mask, 0xffff_ffff);
+        assert_eq!(manifest.images.len(), 1);
+        let image = &manifest.images[0];
+        assert_eq!(image.name, "gateware");
+        assert_eq!(image.file, Path::new("fw/top.bin"));
+        assert_eq!(image.offset, 0x40000);
+        assert_eq!(image.sha256[..4], [0xba, 0x78, 0x16, 0xbf]);
+        assert_eq!(image.sha256[31], 0xad);
+    }
+
+    #[test]
+    fn manifest_from_json() {
+        let json = [
+            r#"{"device": {"register": "0x10", "value": 7, "mask": "0xff"}, "#,
+            r#""images": [{"name": "bios", "file": "bios.bin", "offset": "0x0", "sha256": ""#,
+            &DIGEST.to_uppercase(),
+            r#""}]}"#,
+        ]
+        .concat();
+        let manifest = UpdateManifest::parse("update.json", &json, &mapping()).unwrap();
+        assert_eq!(manifest.id_addr, 0x10);
+        assert_eq!(manifest.id_value, 7);
+        assert_eq!(manifest.id_mask, 0xff);
+        assert_eq!(manifest.images[0].file, Path::new("bios.bin"));
+        assert_eq!(manifest.images[0].offset, 0);
+    }
+
+    #[test]
+    fn manifest_sha256_must_be_32_bytes() {
+        let short = &DIGEST[..62];
+        let long = [DIGEST, "00"].concat();
+        let odd = &DIGEST[..63];
+        let not_hex = ["zz", &DIGEST[2..]].concat();
+        for sha256 in &[short, &long, odd, &not_hex, ""] {
+            match UpdateManifest::parse("update.toml", &toml_manifest(sha256), &mapping()) {
+                Err(ConfigError::InvalidConfig(msg)) => {
+                    assert_eq!(msg, "update.toml: gateware: sha256 must be 64 hex digits")
+                }
+                other => panic!("{} was accepted: {:?}", sha256, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn manifest_needs_images_and_a_known_register() {
+        let no_images = "images = []\n[device]\nregister = \"ctrl_scratch\"\nvalue = 1\n";
+        assert!(UpdateManifest::parse("update.toml", no_images, &mapping()).is_err());
+
+        let unknown = toml_manifest(DIGEST).replace("ctrl_scratch", "ctrl_reset");
+        assert!(UpdateManifest::parse("update.toml", &unknown, &mapping()).is_err());
+    }
+}
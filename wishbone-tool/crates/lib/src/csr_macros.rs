@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use tracing::info;
+use wishbone_bridge::{Bridge, BridgeError};
+
+use crate::server::{AccessPolicy, WriteGuard};
+
+/// Named sequences of register operations, as loaded from the `[macros]`
+/// section of a `--config` file.
+pub type MacroMap = HashMap<String, CsrMacro>;
+
+#[derive(Debug)]
+pub enum MacroError {
+    /// No macro by that name was defined
+    UnknownMacro(String),
+
+    /// The macro takes a different number of arguments (expected, given)
+    WrongArgumentCount(usize, usize),
+
+    /// A value couldn't be computed, e.g. due to dividing by zero
+    Expression(String),
+
+    /// There was a problem with the device bridge
+    BridgeError(BridgeError),
+
+    /// The macro writes to a protected region (register, region)
+    ProtectedWrite(String, String),
+
+    /// The macro touches a register that a network client isn't allowed to
+    /// (register, reason)
+    AccessDenied(String, String),
+}
+
+impl std::fmt::Display for MacroError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use MacroError::*;
+        match self {
+            UnknownMacro(name) => write!(f, "no macro named {}", name),
+            WrongArgumentCount(expected, given) => write!(
+                f,
+                "expected {} argument{}, but got {}",
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                given
+            ),
+            Expression(msg) => write!(f, "{}", msg),
+            BridgeError(e) => write!(f, "bridge error: {}", e),
+            ProtectedWrite(register, region) => write!(
+                f,
+                "{} is part of {}, and writing it may wedge the bridge (use --force to allow this)",
+                register, region
+            ),
+            AccessDenied(register, why) => write!(f, "can't access {}: {}", register, why),
+        }
+    }
+}
+
+impl std::convert::From<BridgeError> for MacroError {
+    fn from(e: BridgeError) -> MacroError {
+        MacroError::BridgeError(e)
+    }
+}
+
+/// A value computed from the macro's parameters when it runs.
+///
+/// Expressions support integer literals (decimal, `0x` hex, and `0b`
+/// binary), parameter names, parentheses, unary `-` and `~`, and the
+/// binary operators `* / % + - << >> & ^ |` with their usual C precedence.
+/// Arithmetic is done in 64 bits, so e.g. `baud * 4294967296 / 100000000`
+/// doesn't overflow, and the result must fit in 32 bits.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal(u64),
+
+    /// The parameter at this index
+    Param(usize),
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// Binary operators, from loosest to tightest binding.
+const PRECEDENCE: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct ExprParser<'a> {
+    input: &'a str,
+    pos: usize,
+    params: &'a [String],
+}
+
+impl<'a> ExprParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.input[self.pos..].starts_with(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        'outer: loop {
+            self.skip_whitespace();
+            for op in PRECEDENCE[level] {
+                if self.input[self.pos..].starts_with(op) {
+                    self.pos += op.len();
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        let rest = &self.input[self.pos..];
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Err("unexpected end of expression".to_owned()),
+        };
+        match c {
+            '-' | '~' => {
+                self.pos += 1;
+                Ok(Expr::Unary(c, Box::new(self.unary()?)))
+            }
+            '(' => {
+                self.pos += 1;
+                let inner = self.binary(0)?;
+                self.skip_whitespace();
+                if !self.input[self.pos..].starts_with(')') {
+                    return Err("missing )".to_owned());
+                }
+                self.pos += 1;
+                Ok(inner)
+            }
+            _ => {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let token = &rest[..len];
+                if token.is_empty() {
+                    return Err(format!("unexpected '{}'", c));
+                }
+                self.pos += len;
+                if c.is_ascii_digit() {
+                    let (digits, radix) = if let Some(hex) = token.strip_prefix("0x") {
+                        (hex, 16)
+                    } else if let Some(bin) = token.strip_prefix("0b") {
+                        (bin, 2)
+                    } else {
+                        (token, 10)
+                    };
+                    u64::from_str_radix(&digits.replace('_', ""), radix)
+                        .map(Expr::Literal)
+                        .map_err(|_| format!("invalid number {}", token))
+                } else {
+                    self.params
+                        .iter()
+                        .position(|p| p == token)
+                        .map(Expr::Param)
+                        .ok_or_else(|| format!("unknown parameter {}", token))
+                }
+            }
+        }
+    }
+}
+
+impl Expr {
+    /// Parse `input`, which may refer to any of `params` by name.
+    pub fn parse(input: &str, params: &[String]) -> Result<Expr, String> {
+        let mut parser = ExprParser {
+            input,
+            pos: 0,
+            params,
+        };
+        let expr = parser.binary(0)?;
+        parser.skip_whitespace();
+        if parser.pos != input.len() {
+            return Err(format!("unexpected '{}'", &input[parser.pos..]));
+        }
+        Ok(expr)
+    }
+
+    fn eval_u64(&self, args: &[u32]) -> Result<u64, MacroError> {
+        Ok(match self {
+            Expr::Literal(v) => *v,
+            Expr::Param(idx) => args[*idx] as u64,
+            Expr::Unary('-', e) => e.eval_u64(args)?.wrapping_neg(),
+            Expr::Unary(_, e) => !e.eval_u64(args)?,
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval_u64(args)?, rhs.eval_u64(args)?);
+                match *op {
+                    "|" => lhs | rhs,
+                    "^" => lhs ^ rhs,
+                    "&" => lhs & rhs,
+                    "<<" => lhs.checked_shl(rhs as u32).unwrap_or(0),
+                    ">>" => lhs.checked_shr(rhs as u32).unwrap_or(0),
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" | "%" if rhs == 0 => {
+                        return Err(MacroError::Expression("division by zero".to_owned()))
+                    }
+                    "/" => lhs / rhs,
+                    _ => lhs % rhs,
+                }
+            }
+        })
+    }
+
+    /// Compute the value of this expression given the macro's arguments.
+    pub fn eval(&self, args: &[u32]) -> Result<u32, MacroError> {
+        let value = self.eval_u64(args)?;
+        // Allow negative numbers to wrap, as they would in 32 bits.
+        let negative = (value as i64) < 0 && (value as i64) >= i32::MIN as i64;
+        if value > u32::MAX as u64 && !negative {
+            return Err(MacroError::Expression(format!(
+                "0x{:x} doesn't fit in 32 bits",
+                value
+            )));
+        }
+        Ok(value as u32)
+    }
+}
+
+/// A single operation within a macro.
+#[derive(Debug, Clone)]
+pub enum MacroStep {
+    /// Read `addr` and report its value
+    Read { name: String, addr: u32 },
+
+    /// Write `value` to `addr`
+    Write {
+        name: String,
+        addr: u32,
+        value: Expr,
+    },
+
+    /// Read `addr`, replace the bits in `mask` with `value`, and write it
+    /// back. `value` is shifted up to the lowest set bit of `mask`, so it
+    /// gives the contents of the field rather than of the whole register.
+    Modify {
+        name: String,
+        addr: u32,
+        mask: u32,
+        value: Expr,
+    },
+
+    /// Wait for a fixed amount of time
+    Delay(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct CsrMacro {
+    /// Names of the arguments the macro takes, in order
+    pub params: Vec<String>,
+    pub steps: Vec<MacroStep>,
+}
+
+impl CsrMacro {
+    /// Make sure that none of the steps write to a region protected by
+    /// `guard`. This is done up front, so a refused macro leaves the device
+    /// untouched rather than half-configured.
+    pub fn check_writes(&self, guard: &WriteGuard) -> Result<(), MacroError> {
+        for step in &self.steps {
+            match step {
+                MacroStep::Write { name, addr, .. } | MacroStep::Modify { name, addr, .. } => {
+                    guard.check(*addr, 4).map_err(|region| {
+                        MacroError::ProtectedWrite(name.clone(), region.name.clone())
+                    })?;
+                }
+                MacroStep::Read { .. } | MacroStep::Delay(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure that `policy` allows every step, for macros run by clients
+    /// of a network server.
+    pub fn check_access(&self, policy: &AccessPolicy) -> Result<(), MacroError> {
+        for step in &self.steps {
+            let (name, result) = match step {
+                MacroStep::Write { name, addr, .. } | MacroStep::Modify { name, addr, .. } => {
+                    (name, policy.check_write(*addr, 4))
+                }
+                MacroStep::Read { name, addr } => (name, policy.check_read(*addr, 4)),
+                MacroStep::Delay(_) => continue,
+            };
+            result.map_err(|why| MacroError::AccessDenied(name.clone(), why))?;
+        }
+        Ok(())
+    }
+
+    /// Run each step against `bridge`, returning a line of output for every
+    /// register that was read.
+    pub fn run(&self, bridge: &Bridge, args: &[u32]) -> Result<Vec<String>, MacroError> {
+        if args.len() != self.params.len() {
+            return Err(MacroError::WrongArgumentCount(
+                self.params.len(),
+                args.len(),
+            ));
+        }
+        let mut output = vec![];
+        for step in &self.steps {
+            match step {
+                MacroStep::Read { name, addr } => {
+                    let value = bridge.peek(*addr)?;
+                    output.push(format!("{} = 0x{:08x}", name, value));
+                }
+                MacroStep::Write { name, addr, value } => {
+                    let value = value.eval(args)?;
+                    info!("macro: writing 0x{:08x} to {}", value, name);
+                    bridge.poke(*addr, value)?;
+                }
+                MacroStep::Modify {
+                    name,
+                    addr,
+                    mask,
+                    value,
+                } => {
+                    let field = value.eval(args)?;
+                    let old = bridge.peek(*addr)?;
+                    let shifted = field.checked_shl(mask.trailing_zeros()).unwrap_or(0);
+                    let new = (old & !mask) | (shifted & mask);
+                    info!(
+                        "macro: changing {} from 0x{:08x} to 0x{:08x}",
+                        name, old, new
+                    );
+                    bridge.poke(*addr, new)?;
+                }
+                MacroStep::Delay(delay) => thread::sleep(*delay),
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Describe each macro and its parameters, sorted by name.
+pub fn list_macros(macros: &MacroMap) -> Vec<String> {
+    let mut names: Vec<_> = macros.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let params = &macros[name].params;
+            if params.is_empty() {
+                name.clone()
+            } else {
+                format!("{} {}", name, params.join(" "))
+            }
+        })
+        .collect()
+}
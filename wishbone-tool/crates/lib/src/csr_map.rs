@@ -0,0 +1,525 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::{parse_u32, ConfigError, RegisterMapping};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+use wishbone_bridge::{Bridge, MemoryRegion, RegionAccess};
+
+/// CSRs whose names contain any of these are assumed to have side effects
+/// when read, such as UART data registers that pop a byte from the FIFO.
+const READ_SENSITIVE_PATTERNS: &[&str] = &["rxtx", "fifo", "messible_out"];
+
+/// Marks the start of a register map kept in the device's memory. It's
+/// followed by the map's length in bytes, as a little-endian `u32`, and then
+/// the contents of a `csr.csv` or `csr.json`.
+pub const EMBEDDED_MAGIC: &[u8; 8] = b"LXCSRMAP";
+
+/// Without an address, `--csr-auto` looks at the start of each 4 KiB block
+/// up to here, which covers the end of a LiteX BIOS ROM at address 0.
+const SEARCH_LIMIT: u32 = 0x2_0000;
+const SEARCH_STEP: u32 = 0x1000;
+
+/// Anything bigger than this is assumed to be something else that happens
+/// to start with the magic number.
+const MAX_EMBEDDED_LEN: u32 = 4 * 1024 * 1024;
+
+/// A group of bits within a register, such as `WIP` in `spinor_status`
+#[derive(Debug, Clone)]
+pub struct CsrField {
+    pub name: String,
+
+    /// Position of the lowest bit
+    pub offset: u32,
+
+    /// Number of bits
+    pub width: u32,
+
+    pub description: Option<String>,
+}
+
+impl CsrField {
+    /// The bits of the register that make up this field
+    pub fn mask(&self) -> u32 {
+        let bits = if self.width >= 32 {
+            u32::MAX
+        } else {
+            (1 << self.width) - 1
+        };
+        bits << self.offset
+    }
+
+    /// Pick this field out of a register's value.
+    pub fn extract(&self, value: u32) -> u32 {
+        (value & self.mask()) >> self.offset
+    }
+
+    /// Replace this field in a register's value with `field`.
+    pub fn insert(&self, value: u32, field: u32) -> u32 {
+        (value & !self.mask()) | ((field << self.offset) & self.mask())
+    }
+}
+
+/// Show each of `fields` in `value`, as in `WIP=1 WEL=0`. Single bits are
+/// shown as 0 or 1, and anything wider in hex.
+pub fn decode_fields(fields: &[CsrField], value: u32) -> String {
+    fields
+        .iter()
+        .map(|field| match field.width {
+            1 => format!("{}={}", field.name, field.extract(value)),
+            _ => format!("{}=0x{:x}", field.name, field.extract(value)),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Fields of each register that has them, keyed by the register's name
+pub type FieldMapping = HashMap<String, Vec<CsrField>>;
+
+/// Registers, memory regions, and register fields, as described by a
+/// LiteX `csr.csv` or `csr.json`, or by an SVD file. Names are lowercase,
+/// and addresses are as given in the file, without `--register-offset`.
+#[derive(Debug, Default)]
+pub struct CsrMap {
+    pub registers: RegisterMapping,
+    pub regions: Vec<MemoryRegion>,
+    pub fields: FieldMapping,
+
+    /// Names of the regions that are plain memory, rather than registers or
+    /// I/O, and so are safe to read from end to end
+    pub memories: Vec<String>,
+}
+
+/// The parts of a LiteX `csr.json` that describe the memory map
+#[derive(Deserialize)]
+struct CsrJson {
+    #[serde(default)]
+    csr_bases: BTreeMap<String, u32>,
+
+    #[serde(default)]
+    csr_registers: BTreeMap<String, CsrJsonRegister>,
+
+    #[serde(default)]
+    memories: BTreeMap<String, CsrJsonMemory>,
+}
+
+#[derive(Deserialize)]
+struct CsrJsonRegister {
+    addr: u32,
+
+    /// Number of CSR words the register spans
+    size: u32,
+
+    #[serde(rename = "type")]
+    mode: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CsrJsonMemory {
+    base: u32,
+    size: u32,
+
+    #[serde(rename = "type")]
+    mode: Option<String>,
+}
+
+impl CsrMap {
+    /// Load `filename`, working out which format it's in from its contents.
+    pub fn load(filename: &str) -> Result<CsrMap, ConfigError> {
+        Self::parse(&std::fs::read_to_string(filename)?, filename)
+    }
+
+    /// Read the register map that was built into the device, either at `addr`
+    /// or, failing that, wherever it can be found in the boot ROM. Returns
+    /// `None` if there isn't one.
+    pub fn download(bridge: &Bridge, addr: Option<u32>) -> Result<Option<CsrMap>, ConfigError> {
+        let candidates: Vec<u32> = match addr {
+            Some(addr) => vec![addr],
+            None => (0..SEARCH_LIMIT).step_by(SEARCH_STEP as usize).collect(),
+        };
+        for base in candidates {
+            let header = match bridge.burst_read(base, 12) {
+                Ok(header) => header,
+                // Past the end of the ROM, most likely
+                Err(e) => {
+                    debug!("stopped looking for a register map at {:08x}: {}", base, e);
+                    break;
+                }
+            };
+            if &header[0..8] != EMBEDDED_MAGIC {
+                continue;
+            }
+            let len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+            if len > MAX_EMBEDDED_LEN {
+                warn!(
+                    "ignoring a register map at {:08x} that claims to be {} bytes",
+                    base, len
+                );
+                continue;
+            }
+            info!("reading the register map at {:08x} ({} bytes)", base, len);
+            let contents = bridge.burst_read(base + 12, len).map_err(|e| {
+                ConfigError::InvalidConfig(format!("unable to read the register map: {}", e))
+            })?;
+            let contents = String::from_utf8_lossy(&contents);
+            return Self::parse(&contents, &format!("register map at {:08x}", base)).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Parse `contents`, which came from `source`.
+    fn parse(contents: &str, source: &str) -> Result<CsrMap, ConfigError> {
+        let invalid = |e: String| ConfigError::InvalidConfig(format!("{}: {}", source, e));
+        let mut map = CsrMap::default();
+        match contents.trim_start().chars().next() {
+            Some('{') => map.read_json(contents).map_err(invalid)?,
+            Some('<') => map.read_svd(contents).map_err(invalid)?,
+            _ => map.read_csv(contents)?,
+        }
+        for region in map.regions.iter_mut() {
+            region.read_sensitive = READ_SENSITIVE_PATTERNS
+                .iter()
+                .any(|p| region.name.contains(p));
+        }
+        Ok(map)
+    }
+
+    fn add_register(&mut self, name: &str, base_addr: u32, num_regs: u32, access: RegionAccess) {
+        let name = name.to_lowercase();
+        self.regions
+            .push(MemoryRegion::new(&name, base_addr, num_regs * 4, access));
+
+        // CSRs can span multiple registers, and do so in reverse. If this is
+        // the case, create indexed offsets for those registers.
+        if num_regs > 1 {
+            for logical_reg in 0..num_regs {
+                self.registers.insert(
+                    format!("{}{}", name, num_regs - logical_reg - 1),
+                    Some(base_addr + logical_reg * 4),
+                );
+            }
+        }
+        self.registers.insert(name, Some(base_addr));
+    }
+
+    fn add_memory(&mut self, name: &str, base_addr: u32, size: Option<u32>) {
+        let name = name.to_lowercase();
+        if let Some(size) = size {
+            self.regions.push(MemoryRegion::new(
+                &name,
+                base_addr,
+                size,
+                RegionAccess::ReadWrite,
+            ));
+        }
+        self.registers.insert(name, Some(base_addr));
+    }
+
+    fn read_csv(&mut self, contents: &str) -> Result<(), ConfigError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(contents.as_bytes());
+        for r in rdr.records().flatten() {
+            match &r[0] {
+                "csr_register" => {
+                    let access = r
+                        .get(4)
+                        .map(RegionAccess::from_csr_mode)
+                        .unwrap_or(RegionAccess::ReadWrite);
+                    self.add_register(&r[1], parse_u32(&r[2])?, parse_u32(&r[3])?, access);
+                }
+                "memory_region" => {
+                    let size = r.get(3).map(parse_u32).transpose()?;
+                    self.add_memory(&r[1], parse_u32(&r[2])?, size);
+                    if size.is_some() && r.get(4) != Some("io") {
+                        self.memories.push(r[1].to_lowercase());
+                    }
+                }
+                "csr_base" => self.add_memory(&r[1], parse_u32(&r[2])?, None),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_json(&mut self, contents: &str) -> Result<(), String> {
+        let json: CsrJson = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        for (name, base) in json.csr_bases {
+            self.add_memory(&name, base, None);
+        }
+        for (name, reg) in json.csr_registers {
+            let access = reg
+                .mode
+                .as_deref()
+                .map(RegionAccess::from_csr_mode)
+                .unwrap_or(RegionAccess::ReadWrite);
+            self.add_register(&name, reg.addr, reg.size, access);
+        }
+        for (name, memory) in json.memories {
+            self.add_memory(&name, memory.base, Some(memory.size));
+            if memory.mode.as_deref() != Some("io") {
+                self.memories.push(name.to_lowercase());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the peripherals of an SVD file. Each register is named after its
+    /// peripheral and itself, as in `ctrl_scratch`, and each peripheral's
+    /// name is mapped to its base address. Clusters and `dim` arrays are
+    /// not supported.
+    fn read_svd(&mut self, contents: &str) -> Result<(), String> {
+        let doc = roxmltree::Document::parse(contents).map_err(|e| e.to_string())?;
+        let peripherals: Vec<_> = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("peripheral"))
+            .collect();
+
+        for peripheral in &peripherals {
+            let name = svd_text(*peripheral, "name").ok_or("peripheral without a name")?;
+            let base = svd_number(*peripheral, "baseAddress")?
+                .ok_or_else(|| format!("peripheral {} has no baseAddress", name))?;
+            self.add_memory(name, base, None);
+
+            // A derived peripheral has the same registers at another address
+            let template = match peripheral.attribute("derivedFrom") {
+                Some(parent) => *peripherals
+                    .iter()
+                    .find(|p| svd_text(**p, "name") == Some(parent))
+                    .ok_or_else(|| format!("{} is derived from unknown {}", name, parent))?,
+                None => *peripheral,
+            };
+            let registers = template
+                .children()
+                .filter(|n| n.has_tag_name("registers"))
+                .flat_map(|n| n.children())
+                .filter(|n| n.has_tag_name("register"));
+
+            for register in registers {
+                let reg_name = svd_text(register, "name")
+                    .ok_or_else(|| format!("register without a name in {}", name))?;
+                if reg_name.contains("%s") {
+                    continue;
+                }
+                let full_name = format!("{}_{}", name, reg_name).to_lowercase();
+                let offset = svd_number(register, "addressOffset")?.unwrap_or(0);
+                let bits = svd_inherited(register, "size")?.unwrap_or(32);
+                let access = match svd_inherited_text(register, "access") {
+                    Some("read-only") => RegionAccess::ReadOnly,
+                    Some("write-only") | Some("writeOnce") => RegionAccess::WriteOnly,
+                    _ => RegionAccess::ReadWrite,
+                };
+                self.add_register(&full_name, base + offset, bits.div_ceil(32), access);
+
+                let fields = register
+                    .children()
+                    .filter(|n| n.has_tag_name("fields"))
+                    .flat_map(|n| n.children())
+                    .filter(|n| n.has_tag_name("field"));
+                let mut parsed = vec![];
+                for field in fields {
+                    let field_name = svd_text(field, "name")
+                        .ok_or_else(|| format!("field without a name in {}", full_name))?;
+                    let (offset, width) = svd_bit_range(field)
+                        .map_err(|e| format!("{}.{}: {}", full_name, field_name, e))?;
+                    parsed.push(CsrField {
+                        name: field_name.to_owned(),
+                        offset,
+                        width,
+                        description: svd_text(field, "description")
+                            .map(|d| d.split_whitespace().collect::<Vec<_>>().join(" ")),
+                    });
+                }
+                if !parsed.is_empty() {
+                    parsed.sort_by_key(|f| f.offset);
+                    self.fields.insert(full_name, parsed);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The text of the child element of `node` called `tag`
+fn svd_text<'a>(node: roxmltree::Node<'a, '_>, tag: &str) -> Option<&'a str> {
+    node.children()
+        .find(|n| n.has_tag_name(tag))
+        .and_then(|n| n.text())
+        .map(str::trim)
+}
+
+/// Like `svd_text()`, but falling back to the defaults given by the
+/// enclosing peripheral or device
+fn svd_inherited_text<'a>(node: roxmltree::Node<'a, '_>, tag: &str) -> Option<&'a str> {
+    node.ancestors().find_map(|n| svd_text(n, tag))
+}
+
+/// SVD numbers may be decimal, hex with `0x`, or binary with `#`.
+fn parse_svd_u32(text: &str) -> Result<u32, String> {
+    let result = match text.strip_prefix('#') {
+        Some(bits) => u32::from_str_radix(bits, 2),
+        None => match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => text.parse(),
+        },
+    };
+    result.map_err(|e| format!("unable to parse the number \"{}\": {}", text, e))
+}
+
+fn svd_number(node: roxmltree::Node, tag: &str) -> Result<Option<u32>, String> {
+    svd_text(node, tag).map(parse_svd_u32).transpose()
+}
+
+fn svd_inherited(node: roxmltree::Node, tag: &str) -> Result<Option<u32>, String> {
+    svd_inherited_text(node, tag).map(parse_svd_u32).transpose()
+}
+
+/// The lowest bit and width of a field, which SVD allows to be given in any
+/// of three ways
+fn svd_bit_range(field: roxmltree::Node) -> Result<(u32, u32), String> {
+    if let Some(offset) = svd_number(field, "bitOffset")? {
+        let width = svd_number(field, "bitWidth")?.unwrap_or(1);
+        return Ok((offset, width));
+    }
+    if let (Some(lsb), Some(msb)) = (svd_number(field, "lsb")?, svd_number(field, "msb")?) {
+        return svd_span(lsb, msb);
+    }
+    if let Some(range) = svd_text(field, "bitRange") {
+        let bits = range
+            .strip_prefix('[')
+            .and_then(|r| r.strip_suffix(']'))
+            .and_then(|r| r.split_once(':'))
+            .ok_or_else(|| format!("bitRange {} isn't of the form [msb:lsb]", range))?;
+        return svd_span(parse_svd_u32(bits.1.trim())?, parse_svd_u32(bits.0.trim())?);
+    }
+    Err("no bitOffset, lsb and msb, or bitRange".to_owned())
+}
+
+fn svd_span(lsb: u32, msb: u32) -> Result<(u32, u32), String> {
+    if msb < lsb {
+        return Err(format!("msb {} is below lsb {}", msb, lsb));
+    }
+    Ok((lsb, msb + 1 - lsb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+        "csr_bases": {"ctrl": 3758096384},
+        "csr_registers": {
+            "ctrl_scratch": {"addr": 3758096388, "size": 1, "type": "rw"},
+            "timer0_value": {"addr": 3758100480, "size": 2, "type": "ro"}
+        },
+        "memories": {
+            "sram": {"base": 268435456, "size": 8192, "type": "cached"},
+            "csr": {"base": 3758096384, "size": 65536, "type": "io"}
+        }
+    }"#;
+
+    const SVD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<device>
+  <name>SOC</name>
+  <size>32</size>
+  <peripherals>
+    <peripheral>
+      <name>SPINOR</name>
+      <baseAddress>0xE0005000</baseAddress>
+      <registers>
+        <register>
+          <name>STATUS</name>
+          <addressOffset>0x0008</addressOffset>
+          <access>read-only</access>
+          <fields>
+            <field><name>WEL</name><bitRange>[1:1]</bitRange></field>
+            <field><name>WIP</name><bitOffset>0</bitOffset><bitWidth>1</bitWidth></field>
+            <field><name>STATE</name><lsb>4</lsb><msb>7</msb></field>
+          </fields>
+        </register>
+      </registers>
+    </peripheral>
+    <peripheral derivedFrom="SPINOR">
+      <name>SPINOR2</name>
+      <baseAddress>#11100000000000000110000000000000</baseAddress>
+    </peripheral>
+  </peripherals>
+</device>
+"#;
+
+    fn error(contents: &str) -> String {
+        match CsrMap::parse(contents, "csr.test") {
+            Err(ConfigError::InvalidConfig(e)) => e,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn json_parses() {
+        let map = CsrMap::parse(JSON, "csr.json").unwrap();
+        assert_eq!(map.registers["ctrl"], Some(0xe000_0000));
+        assert_eq!(map.registers["ctrl_scratch"], Some(0xe000_0004));
+        assert_eq!(map.registers["timer0_value1"], Some(0xe000_1000));
+        assert_eq!(map.registers["timer0_value0"], Some(0xe000_1004));
+        assert_eq!(map.registers["sram"], Some(0x1000_0000));
+        assert_eq!(map.memories, vec!["sram".to_owned()]);
+        let timer = map
+            .regions
+            .iter()
+            .find(|r| r.name == "timer0_value")
+            .unwrap();
+        assert_eq!((timer.size, timer.access), (8, RegionAccess::ReadOnly));
+    }
+
+    #[test]
+    fn svd_parses() {
+        let map = CsrMap::parse(SVD, "soc.svd").unwrap();
+        assert_eq!(map.registers["spinor"], Some(0xe000_5000));
+        assert_eq!(map.registers["spinor_status"], Some(0xe000_5008));
+        assert_eq!(map.registers["spinor2_status"], Some(0xe000_6008));
+        let status = map
+            .regions
+            .iter()
+            .find(|r| r.name == "spinor_status")
+            .unwrap();
+        assert_eq!(status.access, RegionAccess::ReadOnly);
+
+        let fields = &map.fields["spinor_status"];
+        let names: Vec<_> = fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["WIP", "WEL", "STATE"]);
+        assert_eq!(decode_fields(fields, 0x52), "WIP=0 WEL=1 STATE=0x5");
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        assert!(error(&JSON[..JSON.len() / 2]).starts_with("csr.test: EOF while parsing"));
+        assert!(error(&SVD[..SVD.len() / 2]).starts_with("csr.test: "));
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        assert!(error(r#"{"csr_bases": {"ctrl": "e0000000"}}"#).contains("invalid type"));
+        assert!(error(r#"{"csr_registers": {"ctrl_reset": {"addr": 0}}}"#)
+            .contains("missing field `size`"));
+    }
+
+    #[test]
+    fn malformed_svd_is_rejected() {
+        let no_base = SVD.replace("<baseAddress>0xE0005000</baseAddress>", "");
+        assert_eq!(
+            error(&no_base),
+            "csr.test: peripheral SPINOR has no baseAddress"
+        );
+        let bad_number = SVD.replace("0x0008", "0xzz");
+        assert!(error(&bad_number).contains("unable to parse the number \"0xzz\""));
+        let backwards = SVD.replace("<lsb>4</lsb><msb>7</msb>", "<lsb>7</lsb><msb>4</msb>");
+        assert_eq!(
+            error(&backwards),
+            "csr.test: spinor_status.STATE: msb 4 is below lsb 7"
+        );
+        let orphan = SVD.replace("derivedFrom=\"SPINOR\"", "derivedFrom=\"QSPI\"");
+        assert_eq!(
+            error(&orphan),
+            "csr.test: SPINOR2 is derived from unknown QSPI"
+        );
+    }
+}
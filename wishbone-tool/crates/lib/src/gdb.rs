@@ -0,0 +1,2450 @@
+extern crate byteorder;
+use std::collections::HashMap;
+use std::io;
+use std::io::BufWriter;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::config::RegisterMapping;
+use super::csr_macros::{self, MacroError, MacroMap};
+use super::hostio::{self, CoreDir, HostFiles};
+use super::riscv::{
+    FrameQuery, InterruptStop, RiscvCpu, RiscvCpuError, TraceFrame, WatchpointKind,
+};
+use super::rtos::{self, Rtos, RtosThread};
+use super::server::{AccessPolicy, WriteGuard};
+#[cfg(feature = "flash")]
+use super::server::{ServerError, SpiNor, SECTOR_SIZE};
+use wishbone_bridge::{Bridge, BridgeError, MemoryRegion};
+
+use tracing::{debug, error, info};
+
+use crate::gdb::byteorder::ByteOrder;
+use byteorder::{BigEndian, NativeEndian};
+
+const SUPPORTED_QUERIES: &[u8] =
+    b"PacketSize=3fff;qXfer:features:read+;qXfer:threads:read+;QStartNoAckMode+;vContSupported+;QNonStop+";
+
+/// The CPU is reported to GDB as a single thread with this ID, which
+/// non-stop mode needs in order to say what stopped.
+const THREAD_ID: u64 = 1;
+
+/// GDB's number for the PC, which stop replies include
+const PC_INDEX: u32 = 32;
+
+/// Largest chunk of a host file to return for a single `vFile:pread`. Binary
+/// data may double in size once escaped, so keep this well under `PacketSize`.
+const MAX_HOST_READ: u32 = 4096;
+
+/// Most words `monitor mrd` will print, so that the console output fits
+/// in a single packet once hex-encoded.
+const MAX_RAW_READ_WORDS: u32 = 256;
+
+/// Where GDB's packets come from and go to
+pub enum GdbConnection {
+    /// A client that connected with `target remote HOST:PORT`
+    Tcp(TcpStream),
+
+    /// GDB on the other end of stdin and stdout, as with
+    /// `target remote | wishbone-tool -s gdb --gdb-pipe`
+    Pipe,
+}
+
+impl GdbConnection {
+    fn try_clone(&self) -> io::Result<GdbConnection> {
+        match self {
+            GdbConnection::Tcp(stream) => Ok(GdbConnection::Tcp(stream.try_clone()?)),
+            GdbConnection::Pipe => Ok(GdbConnection::Pipe),
+        }
+    }
+}
+
+impl From<TcpStream> for GdbConnection {
+    fn from(stream: TcpStream) -> Self {
+        GdbConnection::Tcp(stream)
+    }
+}
+
+impl Read for GdbConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            GdbConnection::Tcp(stream) => stream.read(buf),
+            GdbConnection::Pipe => io::stdin().read(buf),
+        }
+    }
+}
+
+impl Write for GdbConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            GdbConnection::Tcp(stream) => stream.write(buf),
+            GdbConnection::Pipe => {
+                let mut stdout = io::stdout().lock();
+                let written = stdout.write(buf)?;
+                stdout.flush()?;
+                Ok(written)
+            }
+        }
+    }
+
+    /// Packets from the poll thread and the server share stdout, so write
+    /// each one while holding the lock.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            GdbConnection::Tcp(stream) => stream.write_all(buf),
+            GdbConnection::Pipe => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(buf)?;
+                stdout.flush()
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            GdbConnection::Tcp(stream) => stream.flush(),
+            GdbConnection::Pipe => io::stdout().flush(),
+        }
+    }
+}
+
+pub struct GdbController {
+    connection: GdbConnection,
+    non_stop: Arc<AtomicBool>,
+}
+
+impl Write for GdbController {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.connection.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.flush()
+    }
+}
+
+impl GdbController {
+    pub fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
+        let to_write = gdb_packet(inp);
+        debug!(
+            " > Writing {} bytes: {}",
+            to_write.len(),
+            String::from_utf8_lossy(&to_write)
+        );
+        self.connection.write_all(&to_write)?;
+        Ok(())
+    }
+
+    /// Tell GDB that the CPU has stopped, with a `T` stop reply such as
+    /// `T05`. In non-stop mode this goes out as a notification.
+    pub fn send_stop(&mut self, reply: &str) -> io::Result<()> {
+        let to_write = gdb_stop_packet(reply, self.non_stop.load(Ordering::Relaxed));
+        debug!(" > Writing stop: {}", String::from_utf8_lossy(&to_write));
+        self.connection.write_all(&to_write)
+    }
+
+    pub fn is_non_stop(&self) -> bool {
+        self.non_stop.load(Ordering::Relaxed)
+    }
+
+    /// Print `msg` on the GDB console. Non-stop mode has no way to send
+    /// output while the CPU runs, so it's logged instead.
+    pub fn print_string(&mut self, msg: &str) -> io::Result<()> {
+        if self.non_stop.load(Ordering::Relaxed) {
+            info!("{}", msg.trim_end());
+            return Ok(());
+        }
+        debug!("Printing string {} to GDB", msg);
+        let mut strs: Vec<String> = msg
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        strs.insert(0, "O".to_string());
+        let joined = strs.join("");
+        self.gdb_send(joined.as_bytes())
+    }
+}
+
+pub struct GdbServer {
+    connection: GdbConnection,
+    reader: BufReader<GdbConnection>,
+    no_ack_mode: bool,
+    non_stop: Arc<AtomicBool>,
+    is_alive: bool,
+    last_signal: u8,
+
+    /// Signal to report when GDB interrupts the CPU
+    interrupt_signal: u8,
+    host_files: Option<HostFiles>,
+    macros: MacroMap,
+    register_mapping: RegisterMapping,
+    memory_regions: Vec<MemoryRegion>,
+    core_regions: Vec<MemoryRegion>,
+    core_dir: Option<CoreDir>,
+    write_guard: WriteGuard,
+    access: AccessPolicy,
+
+    /// Symbols still to ask GDB about with `qSymbol`
+    pending_symbols: Vec<&'static str>,
+    symbols: HashMap<String, u32>,
+    rtos: Option<Rtos>,
+
+    /// Tasks as of the last time GDB listed them, which is forgotten as soon
+    /// as the CPU runs again
+    threads: Vec<RtosThread>,
+
+    /// The thread `Hg` picked for register access, or 0 for any
+    selected_thread: u64,
+
+    /// The trace frame `tfind` picked, and its number. Registers and memory
+    /// are read from it rather than from the CPU.
+    trace_frame: Option<(usize, TraceFrame)>,
+    #[cfg(feature = "flash")]
+    flash: Option<SpiNor>,
+}
+
+fn swab(src: u32) -> u32 {
+    (src << 24) & 0xff00_0000
+        | (src << 8) & 0x00ff_0000
+        | (src >> 8) & 0x0000_ff00
+        | (src >> 24) & 0x0000_00ff
+}
+
+/// Encode the low `bytes` bytes of a register for GDB, least significant
+/// byte first.
+fn gdb_register_hex(value: u64, bytes: usize) -> String {
+    value.to_le_bytes()[..bytes]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Decode a register value sent by GDB, least significant byte first.
+fn parse_register(value: &str) -> Result<u64, GdbServerError> {
+    if value.len() > 16 {
+        return Err(GdbServerError::ProtocolError);
+    }
+    parse_u64(value)?;
+    let mut bytes = [0; 8];
+    for (byte, decoded) in bytes.iter_mut().zip(gdb_hex_decode(value)) {
+        *byte = decoded;
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn parse_u32(value: &str) -> Result<u32, GdbServerError> {
+    match u32::from_str_radix(value, 16) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(GdbServerError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+pub fn parse_i32(value: &str) -> Result<i32, GdbServerError> {
+    match i32::from_str_radix(value, 16) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(GdbServerError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+/// Undo the escaping of binary data sent by GDB, where `}` is followed by
+/// the original byte XORed with `0x20`.
+fn gdb_unescape(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+    while let Some(c) = bytes.next() {
+        if *c == b'}' {
+            match bytes.next() {
+                Some(escaped) => out.push(escaped ^ 0x20),
+                None => break,
+            }
+        } else {
+            out.push(*c);
+        }
+    }
+    out
+}
+
+/// Escape binary data for a reply, so it contains none of the characters
+/// that mean something to the protocol.
+fn gdb_escape(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    for c in input {
+        match c {
+            b'#' | b'$' | b'}' | b'*' => {
+                out.push(b'}');
+                out.push(c ^ 0x20);
+            }
+            c => out.push(*c),
+        }
+    }
+    out
+}
+
+/// Compress runs of the same character in a reply. A run is sent as the
+/// character followed by `*` and the number of extra repeats plus 29.
+/// The repeat count must be printable and mustn't be `#` or `$`, so runs
+/// that would need 6 or 7 repeats are split up.
+fn gdb_run_length_encode(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut idx = 0;
+    while idx < input.len() {
+        let c = input[idx];
+        let mut remaining = input[idx..].iter().take_while(|&&b| b == c).count();
+        idx += remaining;
+        while remaining > 0 {
+            if remaining < 4 {
+                out.push(c);
+                remaining -= 1;
+                continue;
+            }
+            let repeats = match (remaining - 1).min(126 - 29) {
+                6 | 7 => 5,
+                n => n,
+            };
+            out.push(c);
+            out.push(b'*');
+            out.push(repeats as u8 + 29);
+            remaining -= repeats + 1;
+        }
+    }
+    out
+}
+
+/// Frame a reply as `$data#checksum`, run-length encoding the data on the way.
+fn gdb_packet(inp: &[u8]) -> Vec<u8> {
+    gdb_frame(b'$', inp)
+}
+
+/// Frame a stop reply. In non-stop mode it becomes a `%Stop` notification,
+/// which has to name the thread that stopped.
+fn gdb_stop_packet(reply: &str, non_stop: bool) -> Vec<u8> {
+    if non_stop {
+        gdb_frame(
+            b'%',
+            format!("Stop:{}thread:{:x};", reply, THREAD_ID).as_bytes(),
+        )
+    } else {
+        gdb_packet(reply.as_bytes())
+    }
+}
+
+fn gdb_frame(start: u8, inp: &[u8]) -> Vec<u8> {
+    let data = gdb_run_length_encode(inp);
+    let checksum = data.iter().fold(0u8, |acc, c| acc.wrapping_add(*c));
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(start);
+    packet.extend_from_slice(&data);
+    packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+    packet
+}
+
+fn gdb_hex_decode(input: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut acc = 0;
+    for (i, byte) in input.as_bytes().iter().enumerate() {
+        let nybble = if *byte >= 0x30 && *byte <= 0x39 {
+            *byte - 0x30
+        } else if *byte >= 0x61 && *byte <= 0x66 {
+            *byte + 10 - 0x61
+        } else if *byte >= 0x41 && *byte <= 0x46 {
+            *byte + 10 - 0x41
+        } else {
+            0
+        };
+        if i & 1 == 1 {
+            out.push((acc << 4) | nybble);
+            acc = 0;
+        } else {
+            acc = nybble;
+        }
+    }
+    out
+}
+
+pub fn parse_u64(value: &str) -> Result<u64, GdbServerError> {
+    match u64::from_str_radix(value, 16) {
+        Ok(o) => Ok(o),
+        Err(e) => Err(GdbServerError::NumberParseError(value.to_owned(), e)),
+    }
+}
+
+#[derive(Debug)]
+pub enum GdbServerError {
+    /// Rust standard IO error
+    IoError(io::Error),
+
+    /// The network connection has closed
+    ConnectionClosed,
+
+    /// We were unable to parse an integer
+    NumberParseError(String, std::num::ParseIntError),
+
+    /// Something happened with the CPU
+    CpuError(RiscvCpuError),
+
+    /// The bridge failed somehow
+    BridgeError(BridgeError),
+
+    /// Something strange was received
+    ProtocolError,
+
+    /// Client tried to give us a breakpoint we didn't recognize
+    UnknownBreakpointType(String),
+
+    /// Client sent a `vCont` action we didn't recognize
+    UnknownVContAction(String),
+}
+
+impl std::convert::From<BridgeError> for GdbServerError {
+    fn from(e: BridgeError) -> Self {
+        GdbServerError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<RiscvCpuError> for GdbServerError {
+    fn from(e: RiscvCpuError) -> Self {
+        GdbServerError::CpuError(e)
+    }
+}
+
+impl std::convert::From<io::Error> for GdbServerError {
+    fn from(e: io::Error) -> Self {
+        GdbServerError::IoError(e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BreakPointType {
+    BreakSoft,
+    BreakHard,
+    WatchWrite,
+    WatchRead,
+    WatchAccess,
+}
+
+impl BreakPointType {
+    fn from_str(r: &str) -> Result<BreakPointType, GdbServerError> {
+        match r {
+            "0" => Ok(BreakPointType::BreakSoft),
+            "1" => Ok(BreakPointType::BreakHard),
+            "2" => Ok(BreakPointType::WatchWrite),
+            "3" => Ok(BreakPointType::WatchRead),
+            "4" => Ok(BreakPointType::WatchAccess),
+            c => Err(GdbServerError::UnknownBreakpointType(c.to_string())),
+        }
+    }
+
+    /// The kind of watchpoint this is, or `None` for breakpoints.
+    fn watchpoint_kind(&self) -> Option<WatchpointKind> {
+        match self {
+            BreakPointType::BreakSoft | BreakPointType::BreakHard => None,
+            BreakPointType::WatchWrite => Some(WatchpointKind::Write),
+            BreakPointType::WatchRead => Some(WatchpointKind::Read),
+            BreakPointType::WatchAccess => Some(WatchpointKind::Access),
+        }
+    }
+}
+
+/// What a `vCont` action asks a thread to do
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VContKind {
+    Continue,
+    Step,
+    Stop,
+}
+
+/// One action from a `vCont` packet, such as `C02:1`
+#[derive(Debug, PartialEq)]
+pub struct VContAction {
+    pub kind: VContKind,
+
+    /// Signal to deliver, which a bare-metal CPU has no way to take
+    pub signal: Option<u8>,
+
+    /// Thread the action is for, or `None` for every thread that an
+    /// earlier action didn't name
+    pub thread: Option<u64>,
+}
+
+impl VContAction {
+    fn from_str(r: &str) -> Result<VContAction, GdbServerError> {
+        let (action, thread) = match r.split_once(':') {
+            Some((action, thread)) => (action, Some(thread)),
+            None => (r, None),
+        };
+        let thread = match thread {
+            None | Some("-1") => None,
+            Some(thread) => Some(parse_u64(thread)?),
+        };
+        let (kind, signal) = match (action.get(..1), action.get(1..)) {
+            (Some("c"), Some("")) => (VContKind::Continue, None),
+            (Some("C"), Some(signal)) => (VContKind::Continue, Some(parse_u32(signal)? as u8)),
+            (Some("s"), Some("")) => (VContKind::Step, None),
+            (Some("S"), Some(signal)) => (VContKind::Step, Some(parse_u32(signal)? as u8)),
+            (Some("t"), Some("")) => (VContKind::Stop, None),
+            _ => return Err(GdbServerError::UnknownVContAction(r.to_string())),
+        };
+        Ok(VContAction {
+            kind,
+            signal,
+            thread,
+        })
+    }
+
+    /// Whether this action covers `thread`. Thread 0 means any thread.
+    fn applies_to(&self, thread: u64) -> bool {
+        match self.thread {
+            None | Some(0) => true,
+            Some(t) => t == thread,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GdbCommand {
+    /// Server gave an unrecognized command
+    Unknown(String),
+
+    /// This should be responded to in the same way as Unknown(String),
+    /// sent by the server to test how it responds to unknown packets.
+    MustReplyEmpty,
+
+    /// qSupported
+    SupportedQueries(String),
+
+    /// QStartNoAckMode
+    StartNoAckMode,
+
+    /// QNonStop:1
+    SetNonStop(bool),
+
+    /// D
+    Disconnect,
+
+    /// Hg#
+    SetCurrentThread(u64),
+
+    /// Hc# (# may be -1)
+    ContinueThread(i32),
+
+    /// ?
+    LastSignalPacket,
+
+    /// qfThreadInfo
+    GetThreadInfo,
+
+    /// qsThreadInfo
+    GetMoreThreadInfo,
+
+    /// qC
+    GetCurrentThreadId,
+
+    /// qAttached
+    CheckIsAttached,
+
+    /// g
+    GetRegisters,
+
+    /// p#
+    GetRegister(u32),
+
+    /// P#=#
+    SetRegister(u32, u64),
+
+    /// qSymbol::
+    SymbolsReady,
+
+    /// qSymbol:#:# or qSymbol::# for a symbol GDB couldn't find
+    SymbolValue(Option<u32>, String),
+
+    /// T#
+    IsThreadAlive(u64),
+
+    /// m#,#
+    ReadMemory(u32 /* addr */, u32 /* length */),
+
+    /// M#,#:#
+    WriteMemory(
+        u32,      /* addr */
+        u32,      /* length */
+        Vec<u32>, /* value */
+    ),
+
+    /// vCont?
+    VContQuery,
+
+    /// vCont;s:1;c
+    VCont(Vec<VContAction>),
+
+    /// vStopped
+    StopNotificationAck,
+
+    /// vCtrlC
+    InterruptNonStop,
+
+    /// c
+    Continue,
+
+    /// s
+    Step,
+
+    /// Ctrl-C
+    Interrupt,
+
+    /// F
+    FileIoReply(
+        i64,  /* retcode */
+        u32,  /* errno */
+        bool, /* interrupted */
+    ),
+
+    /// qRcmd,
+    MonitorCommand(String),
+
+    /// Z0,###,2
+    AddBreakpoint(
+        BreakPointType,
+        u32, /* address */
+        u32, /* length */
+    ),
+
+    /// z0,###,2
+    RemoveBreakpoint(
+        BreakPointType,
+        u32, /* address */
+        u32, /* length */
+    ),
+
+    /// qOffsets
+    GetOffsets,
+
+    /// qXfer:memory-map:read::
+    ReadMemoryMap(u32 /* offset */, u32 /* len */),
+
+    /// qXfer:features:read:target.xml:0,1000
+    ReadFeature(
+        String, /* filename */
+        u32,    /* offset */
+        u32,    /* len */
+    ),
+
+    /// qTStatus
+    TraceStatusQuery,
+
+    /// QTinit
+    TraceInit,
+
+    /// QTDP:#:#:E:#:#
+    TraceDefine(
+        u32,  /* number */
+        u32,  /* address */
+        bool, /* enabled */
+        u32,  /* step count */
+        u32,  /* pass count */
+    ),
+
+    /// QTDP:-#:#:action
+    TraceAction(u32 /* number */, u32 /* address */, String),
+
+    /// QTStart
+    TraceStart,
+
+    /// QTStop
+    TraceStop,
+
+    /// QTFrame:#, or QTFrame:ffffffff to go back to the live CPU
+    TraceFrame(Option<FrameQuery>),
+
+    /// QTDV, QTDPsrc, QTro, QTBuffer, and QTNotes, which are accepted but
+    /// make no difference
+    TraceAccepted,
+
+    /// qXfer:threads:read::0,1000
+    ReadThreads(u32 /* offset */, u32 /* len */),
+
+    /// qXfer:exec-file:read::0,1000
+    ReadExecFile(u32 /* offset */, u32 /* len */),
+
+    /// vFile:setfs:0
+    HostSetFs(u32 /* pid */),
+
+    /// vFile:open:2f666f6f2e656c66,0,0
+    HostOpen(
+        String, /* filename */
+        u32,    /* flags */
+        u32,    /* mode */
+    ),
+
+    /// vFile:close:1
+    HostClose(u32 /* fd */),
+
+    /// vFile:pread:1,1000,0
+    HostPread(
+        u32, /* fd */
+        u32, /* count */
+        u64, /* offset */
+    ),
+
+    /// vFile:fstat:1
+    HostFstat(u32 /* fd */),
+
+    /// vFile:readlink:2f666f6f2e656c66
+    HostReadlink(String /* filename */),
+
+    /// vFile:pwrite or vFile:unlink, which aren't allowed
+    HostWrite,
+
+    /// vFlashErase:20000000,1000
+    FlashErase(u32 /* addr */, u32 /* length */),
+
+    /// vFlashWrite:20000000:XX...
+    FlashWrite(u32 /* addr */, Vec<u8> /* data */),
+
+    /// vFlashDone
+    FlashDone,
+}
+
+impl GdbServer {
+    pub fn new(connection: GdbConnection) -> Result<GdbServer, GdbServerError> {
+        Ok(GdbServer {
+            reader: BufReader::new(connection.try_clone()?),
+            connection,
+            no_ack_mode: false,
+            non_stop: Arc::new(AtomicBool::new(false)),
+            is_alive: true,
+            last_signal: 0,
+            interrupt_signal: 2,
+            host_files: None,
+            macros: MacroMap::new(),
+            register_mapping: RegisterMapping::new(),
+            memory_regions: vec![],
+            core_regions: vec![],
+            core_dir: None,
+            write_guard: WriteGuard::default(),
+            access: AccessPolicy::default(),
+            pending_symbols: vec![],
+            symbols: HashMap::new(),
+            rtos: None,
+            threads: vec![],
+            selected_thread: 0,
+            trace_frame: None,
+            #[cfg(feature = "flash")]
+            flash: None,
+        })
+    }
+
+    /// Serve files from a directory on this machine to GDB via `vFile`.
+    pub fn set_host_files(&mut self, host_files: HostFiles) {
+        self.host_files = Some(host_files);
+    }
+
+    /// Make the config file's macros available via `monitor macro`.
+    pub fn set_macros(&mut self, macros: MacroMap) {
+        self.macros = macros;
+    }
+
+    /// Look up CSRs from csr.csv by name for `monitor csr`.
+    pub fn set_register_mapping(&mut self, register_mapping: RegisterMapping) {
+        self.register_mapping = register_mapping;
+    }
+
+    /// Refuse `monitor mwr`, `monitor csr write`, and macro writes that would hit the debug
+    /// interface or the bridge's own registers.
+    pub fn set_write_guard(&mut self, write_guard: WriteGuard) {
+        self.write_guard = write_guard;
+    }
+
+    /// Limit what GDB may read and write, as set with `--read-only` and
+    /// `--allow-range`. Going through the CPU doesn't get around this, and
+    /// a read-only client can't change registers or reset the CPU either.
+    pub fn set_access_policy(&mut self, access: AccessPolicy) {
+        self.access = access;
+    }
+
+    /// Report `signal` rather than SIGINT when GDB interrupts the CPU.
+    pub fn set_interrupt_signal(&mut self, signal: u8) {
+        self.interrupt_signal = signal;
+    }
+
+    /// Describe these regions from csr.csv to GDB in the memory map.
+    pub fn set_memory_regions(&mut self, memory_regions: Vec<MemoryRegion>) {
+        self.memory_regions = memory_regions;
+    }
+
+    /// Include these regions in core files written by `monitor coredump`.
+    pub fn set_core_regions(&mut self, core_regions: Vec<MemoryRegion>) {
+        self.core_regions = core_regions;
+    }
+
+    /// Let `monitor coredump` write core files into `core_dir`. Without
+    /// one, it's refused.
+    pub fn set_core_dir(&mut self, core_dir: CoreDir) {
+        self.core_dir = Some(core_dir);
+    }
+
+    /// Program the SPI flash behind `flash` when GDB `load`s into it, and
+    /// describe it in the memory map so that GDB knows to.
+    #[cfg(feature = "flash")]
+    pub fn set_flash(&mut self, flash: SpiNor) {
+        self.flash = Some(flash);
+    }
+
+    /// Handle `monitor macro [NAME ARGS...]`, returning the text to print.
+    fn run_macro(&self, bridge: &Bridge, args: &[&str]) -> Result<String, MacroError> {
+        let (name, args) = match args.split_first() {
+            Some(split) => split,
+            None => {
+                let mut out = "Available macros:\n".to_owned();
+                for line in csr_macros::list_macros(&self.macros) {
+                    out.push_str(&format!("    {}\n", line));
+                }
+                return Ok(out);
+            }
+        };
+        let csr_macro = self
+            .macros
+            .get(*name)
+            .ok_or_else(|| MacroError::UnknownMacro(name.to_string()))?;
+        let args = args
+            .iter()
+            .map(|arg| {
+                crate::config::parse_u32(arg)
+                    .map_err(|_| MacroError::Expression(format!("invalid number {}", arg)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        csr_macro.check_writes(&self.write_guard)?;
+        csr_macro.check_access(&self.access)?;
+        let mut out = String::new();
+        for line in csr_macro.run(bridge, &args)? {
+            out.push_str(&line);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+
+    /// Handle `monitor mrd ADDR [COUNT]` and `monitor mwr ADDR VALUE`, which
+    /// go straight to the bridge rather than through the CPU, returning the
+    /// text to print.
+    fn raw_bus_access(&self, bridge: &Bridge, args: &[&str]) -> String {
+        let numbers: Result<Vec<u32>, _> = args[1..]
+            .iter()
+            .map(|arg| crate::config::parse_u32(arg))
+            .collect();
+        let access = match (args[0], numbers.as_deref()) {
+            ("mrd", Ok([addr])) => self.access.check_read(*addr, 4),
+            ("mrd", Ok([addr, count])) => self.access.check_read(*addr, count.saturating_mul(4)),
+            ("mwr", Ok([addr, _])) => self.access.check_write(*addr, 4),
+            _ => Ok(()),
+        };
+        if let Err(why) = access {
+            return format!("{}\n", why);
+        }
+        let result = match (args[0], numbers.as_deref()) {
+            ("mrd", Ok([addr, ..])) if addr & 3 != 0 => {
+                return format!("address {:08x} is not word-aligned\n", addr)
+            }
+            ("mrd", Ok([addr])) => Self::raw_bus_read(bridge, *addr, 1),
+            ("mrd", Ok([addr, count])) => Self::raw_bus_read(bridge, *addr, *count),
+            ("mwr", Ok([addr, value])) => match self.write_guard.check(*addr, 4) {
+                Err(region) => {
+                    return format!(
+                        "{:08x} is part of {}, and writing it may wedge the bridge (use --force to allow this)\n",
+                        addr, region.name
+                    )
+                }
+                Ok(()) => bridge
+                    .poke(*addr, *value)
+                    .map(|_| format!("{:08x}: {:08x}\n", addr, value)),
+            },
+            ("mrd", _) => return "usage: monitor mrd ADDR [COUNT]\n".to_owned(),
+            _ => return "usage: monitor mwr ADDR VALUE\n".to_owned(),
+        };
+        result.unwrap_or_else(|e| format!("bus access failed: {}\n", e))
+    }
+
+    /// Handle `monitor csr read NAME` and `monitor csr write NAME VALUE`,
+    /// returning the text to print.
+    fn csr_access(&self, bridge: &Bridge, args: &[&str]) -> String {
+        let (op, name) = match args {
+            [op @ "read", name] | [op @ "write", name, _] => (*op, name.to_lowercase()),
+            _ => {
+                return "usage: monitor csr read NAME, or monitor csr write NAME VALUE\n".to_owned()
+            }
+        };
+        let addr = match self.register_mapping.get(&name) {
+            Some(Some(addr)) => *addr,
+            Some(None) => return format!("{} is outside the bridge's address space\n", name),
+            None => return format!("no csr called {} in csr.csv\n", name),
+        };
+        let access = if op == "read" {
+            self.access.check_read(addr, 4)
+        } else {
+            self.access.check_write(addr, 4)
+        };
+        if let Err(why) = access {
+            return format!("{}\n", why);
+        }
+        let result = if op == "read" {
+            bridge
+                .peek(addr)
+                .map(|value| format!("{} ({:08x}): {:08x}\n", name, addr, value))
+        } else {
+            let value = match crate::config::parse_u32(args[2]) {
+                Ok(value) => value,
+                Err(_) => return format!("invalid value {}\n", args[2]),
+            };
+            if let Err(region) = self.write_guard.check(addr, 4) {
+                return format!(
+                    "{} is part of {}, and writing it may wedge the bridge (use --force to allow this)\n",
+                    name, region.name
+                );
+            }
+            bridge
+                .poke(addr, value)
+                .map(|_| format!("{} ({:08x}): {:08x}\n", name, addr, value))
+        };
+        result.unwrap_or_else(|e| format!("bus access failed: {}\n", e))
+    }
+
+    /// Handle `monitor flash erase ADDR LENGTH`, erasing every sector that
+    /// overlaps the range, and return the text to print.
+    #[cfg(feature = "flash")]
+    fn flash_command(&self, bridge: &Bridge, args: &[&str]) -> String {
+        let flash = match &self.flash {
+            Some(flash) => flash,
+            None => return "no SPI flash controller in csr.csv\n".to_owned(),
+        };
+        let numbers: Result<Vec<u32>, _> = args
+            .iter()
+            .skip(1)
+            .map(|arg| crate::config::parse_u32(arg))
+            .collect();
+        let (addr, length) = match (args.first(), numbers.as_deref()) {
+            (Some(&"erase"), Ok([addr, length])) => (*addr, *length),
+            _ => return "usage: monitor flash erase ADDR LENGTH\n".to_owned(),
+        };
+        if let Err(why) = self.access.check_write(addr, length) {
+            return format!("{}\n", why);
+        }
+        if !flash.contains(addr, length) {
+            return format!(
+                "can't erase {:08x}-{:08x}, as it isn't all flash\n",
+                addr,
+                addr as u64 + length as u64
+            );
+        }
+        match flash
+            .probe(bridge)
+            .and_then(|_| flash.erase_range(bridge, addr, length))
+            .and_then(|()| flash.finish(bridge).map_err(ServerError::from))
+        {
+            Ok(()) => format!("erased {:08x}-{:08x}\n", addr, addr as u64 + length as u64),
+            Err(e) => format!("flash erase failed: {:?}\n", e),
+        }
+    }
+
+    #[cfg(not(feature = "flash"))]
+    fn flash_command(&self, _bridge: &Bridge, _args: &[&str]) -> String {
+        "flash support isn't built in\n".to_owned()
+    }
+
+    /// Handle `monitor coredump FILE`, writing the registers and the core
+    /// regions to a core file in `--core-dir`.
+    fn core_dump(&self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let path = match args {
+            [path] => path,
+            _ => return "usage: monitor coredump FILE\n".to_owned(),
+        };
+        // Dumping halts the CPU and writes to this machine
+        if self.access.is_read_only() {
+            return "can't write a core file: the server is read-only\n".to_owned();
+        }
+        let core_dir = match &self.core_dir {
+            Some(core_dir) => core_dir,
+            None => return "can't write a core file without --core-dir\n".to_owned(),
+        };
+        for region in &self.core_regions {
+            if let Err(why) = self.access.check_read(region.base, region.size) {
+                return format!("can't include {} in the core file: {}\n", region.name, why);
+            }
+        }
+        let (full_path, file) = match core_dir.create(path) {
+            Ok(created) => created,
+            Err(e) => return format!("couldn't write core file: {}\n", e),
+        };
+        match cpu.write_core_dump(bridge, &self.core_regions, BufWriter::new(file)) {
+            Ok(()) => format!("wrote core file {}\n", full_path.display()),
+            Err(e) => format!("couldn't write core file: {}\n", e),
+        }
+    }
+
+    /// Handle `monitor stats`, listing everything sharing the bridge.
+    fn mux_stats(bridge: &Bridge) -> String {
+        let mut out = format!(
+            "{:<12} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+            "client", "ops", "read", "written", "busy ms", "wait ms"
+        );
+        for client in bridge.mux().stats() {
+            out.push_str(&format!(
+                "{:<12} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+                client.name,
+                client.operations,
+                client.bytes_read,
+                client.bytes_written,
+                client.busy.as_millis(),
+                client.waiting.as_millis()
+            ));
+        }
+        out
+    }
+
+    fn raw_bus_read(bridge: &Bridge, addr: u32, count: u32) -> Result<String, BridgeError> {
+        let mut out = String::new();
+        for i in 0..count.min(MAX_RAW_READ_WORDS) {
+            let word_addr = addr.wrapping_add(i * 4);
+            if i % 4 == 0 {
+                if i != 0 {
+                    out.push('\n');
+                }
+                out.push_str(&format!("{:08x}:", word_addr));
+            }
+            out.push_str(&format!(" {:08x}", bridge.peek(word_addr)?));
+        }
+        out.push('\n');
+        if count > MAX_RAW_READ_WORDS {
+            out.push_str(&format!("(stopped after {} words)\n", MAX_RAW_READ_WORDS));
+        }
+        Ok(out)
+    }
+
+    fn parse_host_io(pkt: &str) -> Result<GdbCommand, GdbServerError> {
+        let (operation, args) = match pkt.find(':') {
+            Some(idx) => (&pkt[..idx], &pkt[idx + 1..]),
+            None => (pkt, ""),
+        };
+        let args: Vec<&str> = args.split(',').collect();
+        match operation {
+            "setfs" => Ok(GdbCommand::HostSetFs(parse_u32(args[0])?)),
+            "open" if args.len() >= 3 => Ok(GdbCommand::HostOpen(
+                String::from_utf8_lossy(&gdb_hex_decode(args[0])).to_string(),
+                parse_u32(args[1])?,
+                parse_u32(args[2])?,
+            )),
+            "close" => Ok(GdbCommand::HostClose(parse_u32(args[0])?)),
+            "pread" if args.len() >= 3 => Ok(GdbCommand::HostPread(
+                parse_u32(args[0])?,
+                parse_u32(args[1])?,
+                parse_u64(args[2])?,
+            )),
+            "fstat" => Ok(GdbCommand::HostFstat(parse_u32(args[0])?)),
+            "readlink" => Ok(GdbCommand::HostReadlink(
+                String::from_utf8_lossy(&gdb_hex_decode(args[0])).to_string(),
+            )),
+            "pwrite" | "unlink" => Ok(GdbCommand::HostWrite),
+            "open" | "pread" => Err(GdbServerError::ProtocolError),
+            _ => {
+                info!("unrecognized vFile operation: {}", operation);
+                Ok(GdbCommand::Unknown(format!("vFile:{}", pkt)))
+            }
+        }
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn packet_to_command(raw_pkt: &[u8]) -> Result<GdbCommand, GdbServerError> {
+        let pkt = String::from_utf8_lossy(raw_pkt).to_string();
+        debug!("Raw GDB packet: {}", pkt);
+
+        if pkt == "qSupported" || pkt.starts_with("qSupported:") {
+            Ok(GdbCommand::SupportedQueries(pkt))
+        } else if pkt == "D" {
+            Ok(GdbCommand::Disconnect)
+        } else if pkt == "QStartNoAckMode" {
+            Ok(GdbCommand::StartNoAckMode)
+        } else if pkt.starts_with("QNonStop:") {
+            Ok(GdbCommand::SetNonStop(pkt == "QNonStop:1"))
+        } else if pkt == "qAttached" {
+            Ok(GdbCommand::CheckIsAttached)
+        } else if pkt == "qOffsets" {
+            Ok(GdbCommand::GetOffsets)
+        } else if pkt == "qTStatus" {
+            Ok(GdbCommand::TraceStatusQuery)
+        } else if pkt == "QTinit" {
+            Ok(GdbCommand::TraceInit)
+        } else if pkt.starts_with("QTDP:-") {
+            let pkt = pkt.trim_start_matches("QTDP:-").trim_end_matches('-');
+            let fields: Vec<&str> = pkt.splitn(3, ':').collect();
+            if fields.len() != 3 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            Ok(GdbCommand::TraceAction(
+                parse_u32(fields[0])?,
+                parse_u64(fields[1])? as u32,
+                fields[2].to_owned(),
+            ))
+        } else if pkt.starts_with("QTDP:") {
+            let pkt = pkt.trim_start_matches("QTDP:").trim_end_matches('-');
+            let fields: Vec<&str> = pkt.split(':').collect();
+            if fields.len() < 5 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            Ok(GdbCommand::TraceDefine(
+                parse_u32(fields[0])?,
+                parse_u64(fields[1])? as u32,
+                fields[2] == "E",
+                parse_u32(fields[3])?,
+                parse_u32(fields[4])?,
+            ))
+        } else if pkt == "QTStart" {
+            Ok(GdbCommand::TraceStart)
+        } else if pkt == "QTStop" {
+            Ok(GdbCommand::TraceStop)
+        } else if pkt.starts_with("QTFrame:") {
+            let fields: Vec<&str> = pkt.trim_start_matches("QTFrame:").split(':').collect();
+            let query = match fields.as_slice() {
+                ["pc", pc] => Some(FrameQuery::Pc(parse_u64(pc)? as u32)),
+                ["tdp", t] => Some(FrameQuery::Tracepoint(parse_u32(t)?)),
+                ["range", start, end] => Some(FrameQuery::Range(
+                    parse_u64(start)? as u32,
+                    parse_u64(end)? as u32,
+                )),
+                ["outside", start, end] => Some(FrameQuery::Outside(
+                    parse_u64(start)? as u32,
+                    parse_u64(end)? as u32,
+                )),
+                [n] if parse_u32(n)? == 0xffff_ffff => None,
+                [n] => Some(FrameQuery::Number(parse_u32(n)?)),
+                _ => return Err(GdbServerError::ProtocolError),
+            };
+            Ok(GdbCommand::TraceFrame(query))
+        } else if pkt.starts_with("QTDV:")
+            || pkt.starts_with("QTDPsrc:")
+            || pkt.starts_with("QTro")
+            || pkt.starts_with("QTBuffer:")
+            || pkt.starts_with("QTNotes:")
+        {
+            Ok(GdbCommand::TraceAccepted)
+        } else if pkt.starts_with("qXfer:memory-map:read::") {
+            let pkt = pkt.trim_start_matches("qXfer:memory-map:read::");
+            let offsets: Vec<&str> = pkt.split(',').collect();
+            let offset = parse_u32(offsets[0])?;
+            let len = parse_u32(offsets[1])?;
+            Ok(GdbCommand::ReadMemoryMap(offset, len))
+        } else if pkt.starts_with("qXfer:features:read:") {
+            let pkt = pkt.trim_start_matches("qXfer:features:read:");
+            let fields: Vec<&str> = pkt.split(':').collect();
+            let offsets: Vec<&str> = fields[1].split(',').collect();
+            let offset = parse_u32(offsets[0])?;
+            let len = parse_u32(offsets[1])?;
+            Ok(GdbCommand::ReadFeature(fields[0].to_string(), offset, len))
+        } else if pkt.starts_with("qXfer:threads:read::") {
+            let pkt = pkt.trim_start_matches("qXfer:threads:read::");
+            let offsets: Vec<&str> = pkt.split(',').collect();
+            let offset = parse_u32(offsets[0])?;
+            let len = parse_u32(offsets[1])?;
+            Ok(GdbCommand::ReadThreads(offset, len))
+        } else if pkt.starts_with("qXfer:exec-file:read:") {
+            let pkt = pkt.trim_start_matches("qXfer:exec-file:read:");
+            let fields: Vec<&str> = pkt.split(':').collect();
+            if fields.len() < 2 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            let offsets: Vec<&str> = fields[1].split(',').collect();
+            let offset = parse_u32(offsets[0])?;
+            let len = parse_u32(offsets[1])?;
+            Ok(GdbCommand::ReadExecFile(offset, len))
+        } else if pkt.starts_with("vFlashErase:") {
+            let pkt = pkt.trim_start_matches("vFlashErase:");
+            let fields: Vec<&str> = pkt.split(',').collect();
+            if fields.len() < 2 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            Ok(GdbCommand::FlashErase(
+                parse_u32(fields[0])?,
+                parse_u32(fields[1])?,
+            ))
+        } else if raw_pkt.starts_with(b"vFlashWrite:") {
+            // Packet format: vFlashWrite:addr:data
+            let (_prefix, data) = raw_pkt.split_at(b"vFlashWrite:".len());
+            let delimiter_offset = data
+                .iter()
+                .position(|c| *c == b':')
+                .ok_or(GdbServerError::ProtocolError)?;
+            let (addr, bin_data) = data.split_at(delimiter_offset);
+            let addr = parse_u32(&String::from_utf8_lossy(addr))?;
+            Ok(GdbCommand::FlashWrite(addr, gdb_unescape(&bin_data[1..])))
+        } else if pkt == "vFlashDone" {
+            Ok(GdbCommand::FlashDone)
+        } else if pkt.starts_with("vFile:") {
+            Self::parse_host_io(pkt.trim_start_matches("vFile:"))
+        } else if pkt.starts_with('Z') {
+            let pkt = pkt.trim_start_matches('Z');
+            let fields: Vec<&str> = pkt.split(',').collect();
+            let bptype = BreakPointType::from_str(fields[0])?;
+            let address = parse_u32(fields[1])?;
+            let size = parse_u32(fields[2])?;
+            Ok(GdbCommand::AddBreakpoint(bptype, address, size))
+        } else if pkt.starts_with('z') {
+            let pkt = pkt.trim_start_matches('z');
+            let fields: Vec<&str> = pkt.split(',').collect();
+            let bptype = BreakPointType::from_str(fields[0])?;
+            let address = parse_u32(fields[1])?;
+            let size = parse_u32(fields[2])?;
+            Ok(GdbCommand::RemoveBreakpoint(bptype, address, size))
+        } else if pkt.starts_with("qRcmd,") {
+            let pkt = pkt.trim_start_matches("qRcmd,");
+            Ok(GdbCommand::MonitorCommand(
+                String::from_utf8_lossy(&gdb_hex_decode(pkt)).to_string(),
+            ))
+        } else if pkt == "g" {
+            Ok(GdbCommand::GetRegisters)
+        } else if pkt.starts_with('P') {
+            let pkt = pkt.trim_start_matches('P').to_string();
+            let v: Vec<&str> = pkt.split('=').collect();
+            let addr = parse_u32(v[0])?;
+            let value = parse_register(v[1])?;
+            Ok(GdbCommand::SetRegister(addr, value))
+        } else if pkt == "c" {
+            Ok(GdbCommand::Continue)
+        } else if pkt == "s" {
+            Ok(GdbCommand::Step)
+        } else if pkt.starts_with('m') {
+            let pkt = pkt.trim_start_matches('m').to_string();
+            let v: Vec<&str> = pkt.split(',').collect();
+            let addr = parse_u32(v[0])?;
+            let length = parse_u32(v[1])?;
+            Ok(GdbCommand::ReadMemory(addr, length))
+        } else if pkt.starts_with('M') {
+            let pkt = pkt.trim_start_matches('M').to_string();
+            let d: Vec<&str> = pkt.split(':').collect();
+            let v: Vec<&str> = d[0].split(',').collect();
+            let addr = parse_u32(v[0])?;
+            let length = parse_u32(v[1])?;
+            let value = swab(parse_u32(d[1])?);
+            Ok(GdbCommand::WriteMemory(addr, length, vec![value]))
+        } else if pkt.starts_with('X') {
+            let (_opcode, data) = match raw_pkt.split_first() {
+                None => return Err(GdbServerError::ProtocolError),
+                Some(s) => s,
+            };
+            // Packet format: Xaddr,count:data
+            // Look for ":"
+            let mut delimiter_offset = None;
+            for (idx, c) in data.iter().enumerate() {
+                if *c == b':' {
+                    delimiter_offset = Some(idx);
+                    break;
+                }
+            }
+            let delimiter_offset = match delimiter_offset {
+                Some(s) => s,
+                None => return Err(GdbServerError::ProtocolError),
+            };
+            let (description, bin_data_plus) = data.split_at(delimiter_offset);
+            let bin_data_plus = bin_data_plus.split_first();
+            let description = String::from_utf8_lossy(&description).to_string();
+            let v: Vec<&str> = description.split(',').collect();
+            if v.len() < 2 {
+                return Err(GdbServerError::ProtocolError);
+            }
+            let addr = parse_u32(v[0])?;
+            let length = parse_u32(v[1])?;
+
+            let mut values = vec![];
+            if let Some((_delimiter, bin_data)) = bin_data_plus {
+                let bin_data = gdb_unescape(bin_data);
+                for value in bin_data.chunks_exact(4) {
+                    values.push(swab(BigEndian::read_u32(&value)));
+                }
+                let remainder = bin_data.chunks_exact(4).remainder();
+                if !remainder.is_empty() {
+                    let mut remainder = remainder.to_vec();
+                    while remainder.len() < 4 {
+                        remainder.insert(0, 0);
+                    }
+                    // remainder.resize(4, 0);
+                    values.push(swab(BigEndian::read_u32(&remainder)));
+                }
+            }
+            Ok(GdbCommand::WriteMemory(addr, length, values))
+        } else if pkt.starts_with('p') {
+            Ok(GdbCommand::GetRegister(parse_u32(
+                pkt.trim_start_matches('p'),
+            )?))
+        } else if pkt.starts_with("Hg") {
+            Ok(GdbCommand::SetCurrentThread(parse_u64(
+                pkt.trim_start_matches("Hg"),
+            )?))
+        } else if pkt.starts_with("Hc") {
+            Ok(GdbCommand::ContinueThread(parse_i32(
+                pkt.trim_start_matches("Hc"),
+            )?))
+        } else if pkt == "qC" {
+            Ok(GdbCommand::GetCurrentThreadId)
+        } else if pkt == "?" {
+            Ok(GdbCommand::LastSignalPacket)
+        } else if pkt == "qfThreadInfo" {
+            Ok(GdbCommand::GetThreadInfo)
+        } else if pkt == "qsThreadInfo" {
+            Ok(GdbCommand::GetMoreThreadInfo)
+        } else if pkt == "vCont?" {
+            Ok(GdbCommand::VContQuery)
+        } else if pkt.starts_with("vCont;") {
+            let actions = pkt
+                .trim_start_matches("vCont;")
+                .split(';')
+                .map(VContAction::from_str)
+                .collect::<Result<_, _>>()?;
+            Ok(GdbCommand::VCont(actions))
+        } else if pkt == "vStopped" {
+            Ok(GdbCommand::StopNotificationAck)
+        } else if pkt == "vCtrlC" {
+            Ok(GdbCommand::InterruptNonStop)
+        } else if pkt.starts_with('F') {
+            // Anything after the semicolon is only sent for calls we
+            // never make.
+            let pkt = pkt.trim_start_matches('F');
+            let fields: Vec<&str> = pkt.split(';').next().unwrap().split(',').collect();
+            let retcode = match fields[0].strip_prefix('-') {
+                Some(magnitude) => -(parse_u64(magnitude)? as i64),
+                None => parse_u64(fields[0])? as i64,
+            };
+            let errno = match fields.get(1) {
+                Some(errno) => parse_u32(errno)?,
+                None => 0,
+            };
+            Ok(GdbCommand::FileIoReply(
+                retcode,
+                errno,
+                fields.get(2) == Some(&"C"),
+            ))
+        } else if pkt == "qSymbol::" {
+            Ok(GdbCommand::SymbolsReady)
+        } else if pkt.starts_with("qSymbol:") {
+            let pkt = pkt.trim_start_matches("qSymbol:");
+            let (value, name) = pkt.split_once(':').ok_or(GdbServerError::ProtocolError)?;
+            let value = match value {
+                "" => None,
+                value => Some(parse_u32(value)?),
+            };
+            let name = String::from_utf8_lossy(&gdb_hex_decode(name)).into_owned();
+            Ok(GdbCommand::SymbolValue(value, name))
+        } else if pkt.starts_with('T') {
+            Ok(GdbCommand::IsThreadAlive(parse_u64(
+                pkt.trim_start_matches('T'),
+            )?))
+        } else if pkt == "vMustReplyEmpty" {
+            Ok(GdbCommand::MustReplyEmpty)
+        } else {
+            info!("unrecognized GDB command: {}", pkt);
+            Ok(GdbCommand::Unknown(pkt))
+        }
+    }
+
+    pub fn get_controller(&self) -> GdbController {
+        GdbController {
+            connection: self.connection.try_clone().unwrap(),
+            non_stop: self.non_stop.clone(),
+        }
+    }
+
+    pub fn get_command(&mut self) -> Result<GdbCommand, GdbServerError> {
+        let cmd = self.do_get_command()?;
+        debug!("<  GDB packet: {:?}", cmd);
+        Ok(cmd)
+    }
+
+    fn do_get_command(&mut self) -> Result<GdbCommand, GdbServerError> {
+        let mut byte = [0; 1];
+        let mut remote_checksum = [0; 2];
+
+        loop {
+            let len = self.reader.read(&mut byte)?;
+            if len == 0 {
+                return Err(GdbServerError::ConnectionClosed);
+            }
+
+            match byte[0] {
+                0x24 /*'$'*/ => {
+                    let mut packet = vec![];
+                    self.reader.read_until(b'#', &mut packet)?;
+                    if packet.pop() != Some(b'#') {
+                        return Err(GdbServerError::ConnectionClosed);
+                    }
+                    self.reader.read_exact(&mut remote_checksum)?;
+                    let checksum = packet.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+                    let checksum_str = format!("{:02x}", checksum);
+                    if checksum_str != String::from_utf8_lossy(&remote_checksum) {
+                        info!(
+                            "Checksum mismatch: Calculated {:?} vs {}",
+                            checksum_str,
+                            String::from_utf8_lossy(&remote_checksum)
+                        );
+                        // GDB sends the packet again.
+                        self.gdb_send_nak()?;
+                        continue;
+                    }
+                    if !self.no_ack_mode {
+                        self.gdb_send_ack()?;
+                    }
+                    return Self::packet_to_command(&packet);
+                }
+                0x2b /*'+'*/ => {}
+                0x2d /*'-'*/ => {}
+                0x3 => return Ok(GdbCommand::Interrupt),
+                other => error!("Warning: unrecognied byte received: {}", other),
+            }
+        }
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    pub fn process(
+        &mut self,
+        cmd: GdbCommand,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<(), GdbServerError> {
+        match cmd {
+            GdbCommand::SupportedQueries(_) => {
+                let mut queries = SUPPORTED_QUERIES.to_vec();
+                if self.exec_file().is_some() {
+                    queries.extend_from_slice(b";qXfer:exec-file:read+");
+                }
+                if self.memory_map().is_some() {
+                    queries.extend_from_slice(b";qXfer:memory-map:read+");
+                }
+                self.gdb_send(&queries)?
+            }
+            GdbCommand::StartNoAckMode => {
+                self.no_ack_mode = true;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::SetNonStop(non_stop) => {
+                self.non_stop.store(non_stop, Ordering::Relaxed);
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::SetCurrentThread(thread) => {
+                self.selected_thread = thread;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::ContinueThread(_) => self.gdb_send(b"OK")?,
+            GdbCommand::AddBreakpoint(bptype, address, size) => {
+                let response = if let Some(kind) = bptype.watchpoint_kind() {
+                    match cpu.add_watchpoint(bridge, kind, address, size) {
+                        Ok(_) => "OK",
+                        // An empty reply makes GDB fall back to software
+                        // watchpoints.
+                        Err(RiscvCpuError::NoTriggers) => "",
+                        Err(e) => {
+                            error!("couldn't add watchpoint: {}", e);
+                            "E0E"
+                        }
+                    }
+                } else {
+                    // Once the hardware breakpoints run out, `Z0` ones can
+                    // be written into the code instead, as long as this
+                    // client may write there.
+                    let result = match cpu.add_breakpoint(bridge, address) {
+                        Err(RiscvCpuError::BreakpointExhausted)
+                            if bptype == BreakPointType::BreakSoft =>
+                        {
+                            match self.access.check_write(address, 4) {
+                                Ok(()) => Some(cpu.add_soft_breakpoint(bridge, address)),
+                                Err(_) => None,
+                            }
+                        }
+                        other => Some(other),
+                    };
+                    match result {
+                        None => "E01",
+                        Some(Ok(_)) => "OK",
+                        Some(Err(RiscvCpuError::BreakpointExhausted)) => {
+                            error!("No available breakpoint found");
+                            "E0E"
+                        }
+                        Some(Err(e)) => {
+                            error!(
+                                "An error occurred while trying to add the breakpoint: {:?}",
+                                e
+                            );
+                            "E0E"
+                        }
+                    }
+                };
+                self.gdb_send(response.as_bytes())?;
+            }
+            GdbCommand::TraceStatusQuery => self.gdb_send(cpu.trace_status().as_bytes())?,
+            GdbCommand::TraceInit => {
+                cpu.stop_trace(bridge)?;
+                cpu.clear_tracepoints();
+                self.trace_frame = None;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::TraceDefine(number, address, enabled, step, pass_count) => {
+                if step != 0 {
+                    info!("tracepoint {} can't collect while stepping", number);
+                }
+                cpu.define_tracepoint(number, address, enabled, pass_count);
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::TraceAction(number, address, action) => {
+                cpu.add_tracepoint_action(number, address, &action);
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::TraceStart => match cpu.start_trace(bridge) {
+                Ok(()) => self.gdb_send(b"OK")?,
+                Err(e) => {
+                    error!("couldn't start tracing: {}", e);
+                    self.gdb_send(b"E0E")?
+                }
+            },
+            GdbCommand::TraceStop => {
+                cpu.stop_trace(bridge)?;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::TraceFrame(query) => {
+                let after = self.trace_frame.as_ref().map(|(index, _)| *index);
+                self.trace_frame = query.and_then(|query| cpu.find_trace_frame(query, after));
+                match &self.trace_frame {
+                    Some((index, frame)) => {
+                        let reply = format!("F{:x}T{:x}", index, frame.tracepoint);
+                        self.gdb_send(reply.as_bytes())?
+                    }
+                    None => self.gdb_send(b"F-1")?,
+                }
+            }
+            GdbCommand::TraceAccepted => self.gdb_send(b"OK")?,
+            GdbCommand::RemoveBreakpoint(bptype, address, size) => {
+                if let Some(kind) = bptype.watchpoint_kind() {
+                    match cpu.remove_watchpoint(bridge, kind, address, size) {
+                        Ok(_) => self.gdb_send(b"OK")?,
+                        Err(e) => {
+                            error!("couldn't remove watchpoint: {}", e);
+                            self.gdb_send(b"E0E")?
+                        }
+                    }
+                } else if cpu.has_soft_breakpoint(address)
+                    && self.access.check_write(address, 4).is_err()
+                {
+                    // Putting the code back is a write too
+                    self.gdb_send(b"E01")?
+                } else {
+                    cpu.remove_breakpoint(bridge, address)?;
+                    self.gdb_send(b"OK")?
+                }
+            }
+            // In non-stop mode, report the CPU as though each stopped thread
+            // had a stop notification queued, which GDB collects with
+            // `vStopped`.
+            GdbCommand::LastSignalPacket if self.is_non_stop() => {
+                if cpu.is_running() {
+                    self.gdb_send(b"OK")?
+                } else {
+                    self.gdb_send(
+                        format!("T{:02x}thread:{:x};", self.last_signal, THREAD_ID).as_bytes(),
+                    )?
+                }
+            }
+            GdbCommand::StopNotificationAck => self.gdb_send(b"OK")?,
+            GdbCommand::LastSignalPacket => {
+                let sig_str = format!("S{:02x}", self.last_signal);
+                self.gdb_send(if self.is_alive {
+                    sig_str.as_bytes()
+                } else {
+                    b"W00"
+                })?
+            }
+            GdbCommand::GetThreadInfo => {
+                self.update_threads(cpu, bridge);
+                let ids: Vec<String> = self
+                    .thread_ids()
+                    .iter()
+                    .map(|id| format!("{:x}", id))
+                    .collect();
+                self.gdb_send(format!("m{}", ids.join(",")).as_bytes())?
+            }
+            GdbCommand::GetMoreThreadInfo => self.gdb_send(b"l")?,
+            GdbCommand::GetCurrentThreadId => {
+                self.update_threads(cpu, bridge);
+                let current = match self.threads.iter().find(|t| t.running) {
+                    Some(thread) => thread.id,
+                    None => THREAD_ID,
+                };
+                self.gdb_send(format!("QC{:x}", current).as_bytes())?
+            }
+            GdbCommand::IsThreadAlive(thread) => {
+                if self.thread_ids().contains(&thread) {
+                    self.gdb_send(b"OK")?
+                } else {
+                    self.gdb_send(b"E01")?
+                }
+            }
+            GdbCommand::CheckIsAttached => self.gdb_send(b"1")?,
+            GdbCommand::Disconnect => {
+                cpu.resume(bridge)?;
+                self.gdb_send(b"OK")?
+            }
+            // Registers and memory that a trace frame didn't record are
+            // unavailable.
+            GdbCommand::GetRegisters if self.trace_frame.is_some() => {
+                let frame = &self.trace_frame.as_ref().unwrap().1;
+                let bytes = cpu.xlen().bytes();
+                let mut register_list = String::new();
+                for idx in cpu.all_cpu_registers() {
+                    match frame.register(idx) {
+                        Some(val) => register_list.push_str(&gdb_register_hex(val, bytes)),
+                        None => register_list.push_str(&"xx".repeat(bytes)),
+                    }
+                }
+                self.gdb_send(register_list.as_bytes())?
+            }
+            GdbCommand::GetRegister(reg) if self.trace_frame.is_some() => {
+                let frame = &self.trace_frame.as_ref().unwrap().1;
+                let response = match frame.register(reg) {
+                    Some(val) => gdb_register_hex(val, cpu.register_bytes(reg)),
+                    None => "xx".repeat(cpu.register_bytes(reg)),
+                };
+                self.gdb_send(response.as_bytes())?
+            }
+            GdbCommand::ReadMemory(addr, len) if self.trace_frame.is_some() => {
+                let frame = &self.trace_frame.as_ref().unwrap().1;
+                let response = match frame.read_memory(addr, len) {
+                    Some(data) => data.iter().map(|b| format!("{:02x}", b)).collect(),
+                    None => "E01".to_owned(),
+                };
+                self.gdb_send(response.as_bytes())?
+            }
+            GdbCommand::GetRegisters if self.stacked_thread().is_some() => {
+                let mut register_list = String::new();
+                for idx in cpu.all_cpu_registers() {
+                    let val = self.read_thread_register(cpu, bridge, idx)?;
+                    register_list.push_str(&gdb_register_hex(val, cpu.xlen().bytes()));
+                }
+                self.gdb_send(register_list.as_bytes())?
+            }
+            GdbCommand::GetRegisters => {
+                let mut register_list = String::new();
+                for val in cpu.read_all_registers(bridge)? {
+                    register_list.push_str(&gdb_register_hex(val, cpu.xlen().bytes()));
+                }
+                self.gdb_send(register_list.as_bytes())?
+            }
+            GdbCommand::GetRegister(reg) => {
+                let response = match self.read_thread_register(cpu, bridge, reg) {
+                    Ok(val) => gdb_register_hex(val, cpu.register_bytes(reg)),
+                    Err(e) => {
+                        error!("Error reading register: {}", e);
+                        "E01".to_string()
+                    }
+                };
+                self.gdb_send(response.as_bytes())?
+            }
+            // Changing what's saved on a task's stack isn't supported.
+            GdbCommand::SetRegister(_, _) if self.stacked_thread().is_some() => {
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::SetRegister(_, _) if self.access.is_read_only() => self.gdb_send(b"E01")?,
+            GdbCommand::SetRegister(reg, val) => {
+                let response = match cpu.write_register(bridge, reg, val) {
+                    Ok(()) => "OK",
+                    Err(_) => "E01",
+                };
+                self.gdb_send(response.as_bytes())?
+            }
+            GdbCommand::SymbolsReady => {
+                self.symbols.clear();
+                self.pending_symbols = rtos::SYMBOLS.iter().rev().copied().collect();
+                self.next_symbol()?
+            }
+            GdbCommand::SymbolValue(value, name) => {
+                if let Some(value) = value {
+                    self.symbols.insert(name, value);
+                }
+                self.next_symbol()?
+            }
+            GdbCommand::ReadMemory(addr, len) if self.access.check_read(addr, len).is_err() => {
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::ReadMemory(addr, len) => {
+                debug!("Reading memory {:08x}", addr);
+                let mut values = vec![];
+
+                let mut out_str = String::new();
+
+                if len == 1 {
+                    let val = cpu.read_memory(bridge, addr, 1)? as u8;
+                    out_str.push_str(&format!("{:02x}", val));
+                    self.gdb_send(out_str.as_bytes())?
+                } else if len == 2 {
+                    let val = cpu.read_memory(bridge, addr, 2)? as u16;
+                    let mut buf = [0; 2];
+                    BigEndian::write_u16(&mut buf, val);
+                    out_str.push_str(&format!("{:04x}", NativeEndian::read_u16(&buf)));
+                    self.gdb_send(out_str.as_bytes())?
+                } else if len == 4 {
+                    values.push(cpu.read_memory(bridge, addr, 4)?);
+                    self.gdb_send_u32(values)?
+                } else {
+                    self.gdb_send_u32(Self::read_words(cpu, bridge, addr, len)?)?
+                }
+            }
+            GdbCommand::WriteMemory(addr, len, _)
+                if self.access.check_write(addr, len).is_err() =>
+            {
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::WriteMemory(addr, len, values) => {
+                if len == 1 {
+                    debug!("Writing memory {:08x} -> {:08x}", addr, values[0] >> 24);
+                    cpu.write_memory(bridge, addr, 1, values[0] >> 24)?;
+                } else if len == 2 {
+                    debug!("Writing memory {:08x} -> {:08x}", addr, values[0] >> 16);
+                    cpu.write_memory(bridge, addr, 2, values[0] >> 16)?;
+                } else if len == 4 {
+                    debug!("Writing memory {:08x} -> {:08x}", addr, values[0]);
+                    cpu.write_memory(bridge, addr, 4, values[0])?;
+                } else {
+                    Self::write_words(cpu, bridge, addr, len, &values)?;
+                }
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::VContQuery => self.gdb_send(b"vCont;c;C;s;S;t")?,
+            GdbCommand::VCont(actions) => self.vcont(&actions, cpu, bridge)?,
+            GdbCommand::GetOffsets => self.gdb_send(b"Text=0;Data=0;Bss=0")?,
+            GdbCommand::Continue => self.resume(cpu, bridge)?,
+            GdbCommand::Step => self.step(cpu, bridge)?,
+            GdbCommand::MonitorCommand(cmd) => {
+                let words: Vec<&str> = cmd.split_whitespace().collect();
+                match cmd.as_str() {
+                    _ if matches!(words.first(), Some(&"mrd") | Some(&"mwr")) => {
+                        self.print_string(&self.raw_bus_access(bridge, &words))?
+                    }
+                    _ if words.first() == Some(&"macro") => {
+                        match self.run_macro(bridge, &words[1..]) {
+                            Ok(out) => self.print_string(&out)?,
+                            Err(e) => self.print_string(&format!("macro failed: {}\n", e))?,
+                        }
+                    }
+                    _ if words.first() == Some(&"csr") => {
+                        self.print_string(&self.csr_access(bridge, &words[1..]))?
+                    }
+                    _ if words.first() == Some(&"flash") => {
+                        self.print_string(&self.flash_command(bridge, &words[1..]))?
+                    }
+                    _ if words.first() == Some(&"coredump") => {
+                        self.print_string(&self.core_dump(cpu, bridge, &words[1..]))?
+                    }
+                    _ if words.first() == Some(&"reset") && self.access.is_read_only() => {
+                        self.print_string("the server is read-only\n")?;
+                    }
+                    _ if words == ["reset"] || words == ["reset", "halt"] => {
+                        self.print_string("Resetting CPU...\n")?;
+                        cpu.reset(&bridge)?;
+                    }
+                    _ if words == ["reset", "run"] => {
+                        self.print_string(
+                            "Resetting CPU and letting it run.  Use `continue` to wait for it to stop.\n",
+                        )?;
+                        cpu.reset_and_run(&bridge)?;
+                    }
+                    "about" => {
+                        self.print_string("VexRiscv GDB bridge\n")?;
+                    }
+                    "breakpoints" => {
+                        self.print_string(&cpu.describe_breakpoints())?;
+                    }
+                    "explain" => {
+                        self.print_string(&cpu.explain(&bridge)?)?;
+                    }
+                    "stats" => {
+                        self.print_string(&Self::mux_stats(bridge))?;
+                    }
+                    _ => {
+                        self.print_string("Unrecognized monitor command.  Available commands:\n")?;
+                        self.print_string("    about           - Information about the bridge\n")?;
+                        self.print_string("    breakpoints     - List the breakpoints\n")?;
+                        self.print_string(
+                            "    coredump FILE   - Write registers and memory to a core file\n",
+                        )?;
+                        self.print_string(
+                            "    csr read NAME   - Read a CSR from csr.csv, bypassing the CPU\n",
+                        )?;
+                        self.print_string("    csr write NAME VALUE - Write a CSR from csr.csv\n")?;
+                        self.print_string("    explain         - Explain what the CPU is doing\n")?;
+                        self.print_string(
+                            "    flash erase ADDR LEN - Erase the flash sectors in a range\n",
+                        )?;
+                        self.print_string(
+                            "    macro [NAME]    - Run a macro from the config file\n",
+                        )?;
+                        self.print_string(
+                            "    mrd ADDR [N]    - Read N words from the bus, bypassing the CPU\n",
+                        )?;
+                        self.print_string(
+                            "    mwr ADDR VALUE  - Write a word to the bus, bypassing the CPU\n",
+                        )?;
+                        self.print_string(
+                            "    reset [halt]    - Reset the CPU, leaving it halted\n",
+                        )?;
+                        self.print_string("    reset run       - Reset the CPU and let it run\n")?;
+                        self.print_string(
+                            "    stats           - Show how each client is using the bridge\n",
+                        )?;
+                    }
+                }
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::ReadFeature(filename, offset, len) => {
+                self.gdb_send_file(cpu.get_feature(&filename)?, offset, len)?
+            }
+            GdbCommand::ReadMemoryMap(offset, len) => match self.memory_map() {
+                Some(memory_map) => self.gdb_send_file(memory_map.into_bytes(), offset, len)?,
+                None => self.gdb_send(b"")?,
+            },
+            GdbCommand::ReadThreads(offset, len) => {
+                if offset == 0 {
+                    self.update_threads(cpu, bridge);
+                }
+                if self.threads.is_empty() {
+                    self.gdb_send_file(cpu.get_threads()?, offset, len)?
+                } else {
+                    self.gdb_send_file(self.threads_xml().into_bytes(), offset, len)?
+                }
+            }
+            GdbCommand::ReadExecFile(offset, len) => match self.exec_file() {
+                Some(exec_file) => self.gdb_send_file(exec_file.into_bytes(), offset, len)?,
+                None => self.gdb_send(b"")?,
+            },
+            GdbCommand::HostSetFs(_)
+            | GdbCommand::HostOpen(_, _, _)
+            | GdbCommand::HostClose(_)
+            | GdbCommand::HostPread(_, _, _)
+            | GdbCommand::HostFstat(_)
+            | GdbCommand::HostReadlink(_)
+            | GdbCommand::HostWrite => self.process_host_io(cmd)?,
+            GdbCommand::FlashErase(addr, len) if self.access.check_write(addr, len).is_err() => {
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::FlashWrite(addr, ref data)
+                if self.access.check_write(addr, data.len() as u32).is_err() =>
+            {
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::FlashErase(_, _) | GdbCommand::FlashWrite(_, _) | GdbCommand::FlashDone => {
+                self.process_flash(cmd, bridge)?
+            }
+            GdbCommand::Interrupt => self.interrupt(cpu, bridge)?,
+            // GDB has finished a semihosting call. The CPU carries on, so
+            // there's no reply until it stops again, unless the user hit
+            // Ctrl-C during the call.
+            GdbCommand::FileIoReply(retcode, errno, interrupted) => {
+                cpu.finish_semihosting(bridge, retcode, errno, interrupted)?;
+                if interrupted {
+                    self.last_signal = 2;
+                    self.report_stop()?;
+                }
+            }
+            GdbCommand::InterruptNonStop => {
+                self.gdb_send(b"OK")?;
+                self.interrupt(cpu, bridge)?
+            }
+            GdbCommand::MustReplyEmpty => self.gdb_send(b"")?,
+            GdbCommand::Unknown(_) => self.gdb_send(b"")?,
+        };
+        Ok(())
+    }
+
+    /// Read the words covering `len` bytes at `addr`, in a single burst if
+    /// the bridge can, and a word at a time if it can't.
+    fn read_words(
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u32>, GdbServerError> {
+        let burst_len = (len + 3) & !3;
+        if burst_len > 0 && addr & 3 == 0 && addr.checked_add(burst_len - 1).is_some() {
+            match bridge.burst_read(addr, burst_len) {
+                Ok(data) => {
+                    return Ok(data
+                        .chunks_exact(4)
+                        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                        .collect())
+                }
+                Err(BridgeError::ProtocolNotSupported) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut values = vec![];
+        for offset in (0..len).step_by(4) {
+            values.push(cpu.read_memory(bridge, addr + offset, 4)?);
+            if addr + offset >= 0xffff_fffc {
+                break;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Write `values` to `addr`, in a single burst if the bridge can and
+    /// they're whole, aligned words, and a word at a time otherwise.
+    fn write_words(
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        addr: u32,
+        len: u32,
+        values: &[u32],
+    ) -> Result<(), GdbServerError> {
+        if !values.is_empty() && addr & 3 == 0 && len & 3 == 0 {
+            let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            debug!("Writing memory {:08x} -> {} bytes", addr, data.len());
+            match bridge.burst_write(addr, &data) {
+                Ok(()) => return Ok(()),
+                Err(BridgeError::ProtocolNotSupported) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for (offset, value) in values.iter().enumerate() {
+            debug!("Writing memory {:08x} -> {:08x}", addr, value);
+            cpu.write_memory(bridge, addr + (offset as u32 * 4), 4, *value)?;
+        }
+        Ok(())
+    }
+
+    fn is_non_stop(&self) -> bool {
+        self.non_stop.load(Ordering::Relaxed)
+    }
+
+    /// Carry out the first `vCont` action that covers the CPU's thread.
+    /// Actions for any other thread are ignored, as there aren't any.
+    fn vcont(
+        &mut self,
+        actions: &[VContAction],
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<(), GdbServerError> {
+        let action = match actions.iter().find(|a| a.applies_to(THREAD_ID)) {
+            Some(action) => action,
+            None => {
+                self.gdb_send(b"OK")?;
+                return Ok(());
+            }
+        };
+        if let Some(signal) = action.signal {
+            debug!("can't deliver signal {} to the CPU, ignoring it", signal);
+        }
+        match action.kind {
+            VContKind::Continue => self.resume(cpu, bridge),
+            VContKind::Step => self.step(cpu, bridge),
+            VContKind::Stop => {
+                if self.is_non_stop() {
+                    self.gdb_send(b"OK")?;
+                }
+                // A thread that's already stopped mustn't be reported again.
+                if cpu.is_running() || !self.is_non_stop() {
+                    self.last_signal = 0;
+                    cpu.halt(bridge)?;
+                    self.report_stop()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Let the CPU run. In all-stop mode there's no reply until it stops,
+    /// which `RiscvCpuController::poll()` reports. In non-stop mode the
+    /// request is acknowledged straight away and the stop comes later as
+    /// a notification.
+    fn resume(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), GdbServerError> {
+        self.threads.clear();
+        let trap = cpu.resume(bridge)?;
+        self.note_trap(trap)?;
+        if self.is_non_stop() {
+            self.gdb_send(b"OK")?;
+        }
+        Ok(())
+    }
+
+    fn step(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), GdbServerError> {
+        self.threads.clear();
+        let trap = cpu.step(bridge)?;
+        self.note_trap(trap)?;
+        self.last_signal = 5;
+        if self.is_non_stop() {
+            self.gdb_send(b"OK")?;
+        }
+        self.report_stop()?;
+        Ok(())
+    }
+
+    /// Mention that the CPU was in a trap when it was resumed. Non-stop mode
+    /// doesn't allow console output here, so it's logged instead.
+    fn note_trap(&mut self, trap: Option<String>) -> io::Result<()> {
+        match trap {
+            Some(s) if self.is_non_stop() => {
+                info!("CPU is currently in a trap: {}", s);
+                Ok(())
+            }
+            Some(s) => self.print_string(&format!("Note: CPU is currently in a trap: {}\n", s)),
+            None => Ok(()),
+        }
+    }
+
+    /// Ask GDB where the next RTOS symbol is, or once they've all been
+    /// asked about, work out which RTOS the program uses.
+    fn next_symbol(&mut self) -> io::Result<()> {
+        if let Some(name) = self.pending_symbols.pop() {
+            let hex: String = name.bytes().map(|b| format!("{:02x}", b)).collect();
+            return self.gdb_send(format!("qSymbol:{}", hex).as_bytes());
+        }
+        self.rtos = Rtos::detect(&self.symbols);
+        if let Some(rtos) = &self.rtos {
+            info!("found {}, showing its tasks as threads", rtos.kind());
+        }
+        self.gdb_send(b"OK")
+    }
+
+    /// Read the RTOS's task list again. The tasks only show up as threads
+    /// in all-stop mode, as the CPU has to be halted to find them. If
+    /// they can't be read, there's just the CPU.
+    fn update_threads(&mut self, cpu: &RiscvCpu, bridge: &Bridge) {
+        self.threads.clear();
+        let rtos = match &self.rtos {
+            Some(rtos) if !self.is_non_stop() && !cpu.is_running() => rtos,
+            _ => return,
+        };
+        match rtos.threads(cpu, bridge) {
+            Ok(threads) => self.threads = threads,
+            Err(e) => debug!("couldn't read {} tasks: {}", rtos.kind(), e),
+        }
+    }
+
+    fn thread_ids(&self) -> Vec<u64> {
+        if self.threads.is_empty() {
+            vec![THREAD_ID]
+        } else {
+            self.threads.iter().map(|t| t.id).collect()
+        }
+    }
+
+    /// The task `Hg` picked, if it isn't the one that was running and so
+    /// has its registers saved on its stack
+    fn stacked_thread(&self) -> Option<&RtosThread> {
+        self.threads
+            .iter()
+            .find(|t| t.id == self.selected_thread && !t.running)
+    }
+
+    /// Read a register of the thread `Hg` picked. Registers that a task
+    /// switch doesn't save come from the CPU.
+    fn read_thread_register(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        gdb_idx: u32,
+    ) -> Result<u64, RiscvCpuError> {
+        if let (Some(rtos), Some(thread)) = (&self.rtos, self.stacked_thread()) {
+            if let Some(val) = rtos.read_register(cpu, bridge, thread, gdb_idx)? {
+                return Ok(val);
+            }
+        }
+        cpu.read_register(bridge, gdb_idx)
+    }
+
+    fn threads_xml(&self) -> String {
+        let mut xml = "<?xml version=\"1.0\"?>\n<threads>\n".to_owned();
+        for thread in &self.threads {
+            let name: String = thread
+                .name
+                .chars()
+                .filter(|c| c.is_ascii_graphic() && !"<>&\"'".contains(*c) || *c == ' ')
+                .collect();
+            xml.push_str(&format!(
+                "    <thread id=\"{:x}\" core=\"0\" name=\"{}\">{}</thread>\n",
+                thread.id, name, thread.state
+            ));
+        }
+        xml.push_str("</threads>\n");
+        xml
+    }
+
+    /// Halt the CPU for Ctrl-C, and report why it stopped along with the
+    /// PC. If the CPU had already stopped, and the stop was reported, there
+    /// is nothing more to say.
+    fn interrupt(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), GdbServerError> {
+        let reason = match cpu.halt_for_interrupt(bridge)? {
+            None => return Ok(()),
+            Some(InterruptStop::Interrupted) => {
+                self.last_signal = self.interrupt_signal;
+                String::new()
+            }
+            Some(InterruptStop::Breakpoint) => {
+                self.last_signal = 5;
+                String::new()
+            }
+            Some(InterruptStop::Watchpoint(reason)) => {
+                self.last_signal = 5;
+                reason
+            }
+        };
+        let pc = cpu.read_register(bridge, PC_INDEX)?;
+        let reply = format!(
+            "T{:02x}{}{:02x}:{};",
+            self.last_signal,
+            reason,
+            PC_INDEX,
+            gdb_register_hex(pc, cpu.xlen().bytes())
+        );
+        self.connection
+            .write_all(&gdb_stop_packet(&reply, self.is_non_stop()))?;
+        Ok(())
+    }
+
+    /// Report that the CPU stopped with `last_signal`: as the reply in
+    /// all-stop mode, or as a notification in non-stop mode.
+    fn report_stop(&mut self) -> io::Result<()> {
+        let reply = if self.is_non_stop() {
+            format!("T{:02x}", self.last_signal)
+        } else {
+            format!("S{:02x}", self.last_signal)
+        };
+        let to_write = gdb_stop_packet(&reply, self.is_non_stop());
+        self.connection.write_all(&to_write)
+    }
+
+    /// Describe the address space for GDB, so that it knows which part is
+    /// flash and needs programming with `vFlash` packets rather than
+    /// writing, and which parts are read-only. The regions come from
+    /// csr.csv, and GDB won't touch anything outside of them. Without any
+    /// regions, everything but the flash is left as RAM.
+    fn memory_map(&self) -> Option<String> {
+        let flash = self.flash_extent();
+        let mut entries = vec![];
+        if self.memory_regions.is_empty() {
+            let (start, end) = flash?;
+            entries.push(("ram", 0, start));
+            entries.push(("flash", start, end));
+            entries.push(("ram", end, 1 << 32));
+        } else {
+            entries.extend(flash.map(|(start, end)| ("flash", start, end)));
+            for region in &self.memory_regions {
+                let start = u64::from(region.base);
+                // A flash that can't be programmed can still be read.
+                let kind = if region.name == "rom" || region.name.contains("flash") {
+                    "rom"
+                } else {
+                    "ram"
+                };
+                entries.push((kind, start, start + u64::from(region.size)));
+            }
+        }
+        // The flash goes first, so that the region it's mapped into doesn't
+        // hide it.
+        entries.sort_by_key(|&(kind, start, _)| (start, kind != "flash"));
+
+        // Each CSR has its own region, and GDB rejects any that overlap, so
+        // join up neighbours and trim the rest.
+        let mut merged: Vec<(&str, u64, u64)> = vec![];
+        for (kind, start, end) in entries {
+            let start = match merged.last_mut() {
+                Some(last) if last.0 == kind && start <= last.2 => {
+                    last.2 = last.2.max(end);
+                    continue;
+                }
+                Some(last) => start.max(last.2),
+                None => start,
+            };
+            if start < end {
+                merged.push((kind, start, end));
+            }
+        }
+
+        let mut out = r#"<?xml version="1.0"?>
+<!DOCTYPE memory-map
+          PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN"
+                 "http://sourceware.org/gdb/gdb-memory-map.dtd">
+<memory-map>
+"#
+        .to_owned();
+        for (kind, start, end) in merged {
+            let entry = format!(
+                "    <memory type=\"{}\" start=\"0x{:x}\" length=\"0x{:x}\"",
+                kind,
+                start,
+                end - start
+            );
+            if kind == "flash" {
+                out.push_str(&entry);
+                out.push_str(">\n");
+                out.push_str(&self.flash_properties());
+                out.push_str("    </memory>\n");
+            } else {
+                out.push_str(&entry);
+                out.push_str("/>\n");
+            }
+        }
+        out.push_str("</memory-map>\n");
+        Some(out)
+    }
+
+    /// Where the flash that GDB can program starts and ends, if there is one
+    #[cfg(feature = "flash")]
+    fn flash_extent(&self) -> Option<(u64, u64)> {
+        self.flash.as_ref().map(|flash| {
+            (
+                u64::from(flash.region),
+                u64::from(flash.region) + u64::from(flash.size),
+            )
+        })
+    }
+
+    #[cfg(not(feature = "flash"))]
+    fn flash_extent(&self) -> Option<(u64, u64)> {
+        None
+    }
+
+    #[cfg(feature = "flash")]
+    fn flash_properties(&self) -> String {
+        format!(
+            "        <property name=\"blocksize\">0x{:x}</property>\n",
+            SECTOR_SIZE
+        )
+    }
+
+    #[cfg(not(feature = "flash"))]
+    fn flash_properties(&self) -> String {
+        String::new()
+    }
+
+    /// Handle `vFlashErase`, `vFlashWrite`, and `vFlashDone`, which GDB
+    /// sends when `load` reaches a region that the memory map says is
+    /// flash. Sectors are erased and programmed as the packets arrive,
+    /// since there's nothing to be gained by waiting for `vFlashDone`.
+    #[cfg(feature = "flash")]
+    fn process_flash(&mut self, cmd: GdbCommand, bridge: &Bridge) -> Result<(), GdbServerError> {
+        let flash = match &self.flash {
+            Some(flash) => flash.clone(),
+            None => return Ok(self.gdb_send(b"")?),
+        };
+        let result = match cmd {
+            GdbCommand::FlashErase(addr, length) if flash.contains(addr, length) => flash
+                .probe(bridge)
+                .and_then(|_| flash.erase_range(bridge, addr, length)),
+            GdbCommand::FlashErase(addr, length) => {
+                error!(
+                    "can't erase {:08x}-{:08x}, as it isn't all flash",
+                    addr,
+                    addr as u64 + length as u64
+                );
+                return Ok(self.gdb_send(b"E01")?);
+            }
+            GdbCommand::FlashWrite(addr, data) if flash.contains(addr, data.len() as u32) => {
+                flash.program_range(bridge, addr, &data)
+            }
+            GdbCommand::FlashWrite(_, _) => return Ok(self.gdb_send(b"E.memtype")?),
+            GdbCommand::FlashDone => flash.finish(bridge).map_err(ServerError::from),
+            _ => return Err(GdbServerError::ProtocolError),
+        };
+        match result {
+            Ok(()) => self.gdb_send(b"OK")?,
+            Err(e) => {
+                error!("flash operation failed: {:?}", e);
+                self.gdb_send(b"E01")?
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "flash"))]
+    fn process_flash(&mut self, _cmd: GdbCommand, _bridge: &Bridge) -> Result<(), GdbServerError> {
+        Ok(self.gdb_send(b"")?)
+    }
+
+    fn exec_file(&self) -> Option<String> {
+        self.host_files
+            .as_ref()
+            .and_then(|f| f.exec_file())
+            .map(|f| f.to_owned())
+    }
+
+    /// Handle `vFile` requests. Replies are of the form `Fresult[;data]`,
+    /// or `F-1,errno` on failure. Without a sysroot these packets are
+    /// unsupported, and GDB falls back to using local files.
+    fn process_host_io(&mut self, cmd: GdbCommand) -> Result<(), GdbServerError> {
+        let host_files = match self.host_files.as_mut() {
+            Some(h) => h,
+            None => return Ok(self.gdb_send(b"")?),
+        };
+        let with_data = |result: usize, data: &[u8]| {
+            let mut response = format!("F{:x};", result).into_bytes();
+            response.append(&mut gdb_escape(data));
+            response
+        };
+        let response = match cmd {
+            GdbCommand::HostSetFs(0) => Ok(b"F0".to_vec()),
+            GdbCommand::HostSetFs(_) => Err(io::Error::from_raw_os_error(hostio::EINVAL as i32)),
+            GdbCommand::HostOpen(filename, flags, _mode) => host_files
+                .open(&filename, flags)
+                .map(|fd| format!("F{:x}", fd).into_bytes()),
+            GdbCommand::HostClose(fd) => host_files.close(fd).map(|_| b"F0".to_vec()),
+            GdbCommand::HostPread(fd, count, offset) => host_files
+                .pread(fd, count.min(MAX_HOST_READ) as usize, offset)
+                .map(|data| with_data(data.len(), &data)),
+            GdbCommand::HostFstat(fd) => host_files
+                .fstat(fd)
+                .map(|stat| with_data(stat.len(), &stat)),
+            GdbCommand::HostReadlink(filename) => host_files
+                .readlink(&filename)
+                .map(|target| with_data(target.len(), &target)),
+            GdbCommand::HostWrite => Err(io::Error::from_raw_os_error(hostio::EROFS as i32)),
+            _ => return Err(GdbServerError::ProtocolError),
+        };
+        let response = response.unwrap_or_else(|e| {
+            debug!("host file operation failed: {}", e);
+            format!("F-1,{:x}", hostio::gdb_errno(&e)).into_bytes()
+        });
+        self.gdb_send(&response)?;
+        Ok(())
+    }
+
+    fn gdb_send_ack(&mut self) -> io::Result<usize> {
+        self.connection.write(&[b'+'])
+    }
+
+    fn gdb_send_nak(&mut self) -> io::Result<usize> {
+        self.connection.write(&[b'-'])
+    }
+
+    fn gdb_send_u32(&mut self, vals: Vec<u32>) -> io::Result<()> {
+        let mut out_str = String::new();
+        for val in vals {
+            let mut buf = [0; 4];
+            BigEndian::write_u32(&mut buf, val);
+            out_str.push_str(&format!("{:08x}", NativeEndian::read_u32(&buf)));
+        }
+        self.gdb_send(out_str.as_bytes())
+    }
+
+    fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
+        let to_write = gdb_packet(inp);
+        // debug!(
+        //     " > Writing {} bytes: {}",
+        //     to_write.len(),
+        //     String::from_utf8_lossy(&to_write)
+        // );
+        self.connection.write_all(&to_write)?;
+        Ok(())
+    }
+
+    pub fn print_string(&mut self, msg: &str) -> io::Result<()> {
+        debug!("Printing string {} to GDB", msg);
+        let mut strs: Vec<String> = msg
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        strs.insert(0, "O".to_string());
+        let joined = strs.join("");
+        self.gdb_send(joined.as_bytes())
+    }
+
+    fn gdb_send_file(&mut self, mut data: Vec<u8>, offset: u32, len: u32) -> io::Result<()> {
+        let offset = offset as usize;
+        let len = len as usize;
+        let mut end = offset + len;
+        if offset > data.len() {
+            self.gdb_send(b"l")?;
+        } else {
+            if end > data.len() {
+                end = data.len();
+            }
+            let trimmed_data: Vec<u8> = data.drain(offset..end).collect();
+            let mut response = if trimmed_data.len() >= len {
+                // XXX should this be <= or < ?
+                vec![b'm']
+            } else {
+                vec![b'l']
+            };
+            response.append(&mut gdb_escape(&trimmed_data));
+            self.gdb_send(&response)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_bytes_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let escaped = gdb_escape(&data);
+        assert!(!escaped
+            .iter()
+            .any(|c| *c == b'#' || *c == b'$' || *c == b'*'));
+        assert_eq!(gdb_unescape(&escaped), data);
+        assert_eq!(gdb_unescape(b"a}\x03b}]"), b"a#b}");
+    }
+
+    #[test]
+    fn truncated_escape_is_dropped() {
+        assert_eq!(gdb_unescape(b"ab}"), b"ab");
+        assert_eq!(gdb_unescape(b"}"), b"");
+    }
+
+    #[test]
+    fn run_length_encoding() {
+        assert_eq!(gdb_run_length_encode(b"000"), b"000");
+        assert_eq!(gdb_run_length_encode(b"0000"), b"0* ");
+        // Six or seven repeats would be sent as `#` or `$`.
+        assert_eq!(gdb_run_length_encode(b"0000000"), b"0*\"0");
+        assert_eq!(gdb_run_length_encode(b"00000000"), b"0*\"00");
+        let long = vec![b'0'; 200];
+        let encoded = gdb_run_length_encode(&long);
+        assert!(!encoded.contains(&b'#') && !encoded.contains(&b'$'));
+        assert_eq!(gdb_packet(b"OK"), b"$OK#9a");
+    }
+
+    #[test]
+    fn binary_write_packet() {
+        assert_eq!(
+            GdbServer::packet_to_command(b"X1000,6:\x01\x02\x03\x04\xaa\xbb").unwrap(),
+            GdbCommand::WriteMemory(0x1000, 6, vec![0x0403_0201, 0xbbaa_0000])
+        );
+        assert_eq!(
+            GdbServer::packet_to_command(b"X1000,1:}]").unwrap(),
+            GdbCommand::WriteMemory(0x1000, 1, vec![0x7d00_0000])
+        );
+        assert_eq!(
+            GdbServer::packet_to_command(b"X1000,0:").unwrap(),
+            GdbCommand::WriteMemory(0x1000, 0, vec![])
+        );
+    }
+
+    #[test]
+    fn truncated_binary_write_packet() {
+        assert!(matches!(
+            GdbServer::packet_to_command(b"X1000,4"),
+            Err(GdbServerError::ProtocolError)
+        ));
+        assert!(matches!(
+            GdbServer::packet_to_command(b"X"),
+            Err(GdbServerError::ProtocolError)
+        ));
+    }
+
+    #[test]
+    fn malformed_binary_write_packet() {
+        assert!(matches!(
+            GdbServer::packet_to_command(b"X1000:\x01"),
+            Err(GdbServerError::ProtocolError)
+        ));
+        assert!(matches!(
+            GdbServer::packet_to_command(b"Xzz,4:\x01\x02\x03\x04"),
+            Err(GdbServerError::NumberParseError(..))
+        ));
+    }
+}
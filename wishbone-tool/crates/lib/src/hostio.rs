@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// GDB's File-I/O open flags. Only the access mode bits matter here,
+/// since the sandbox is read-only.
+const FILEIO_O_ACCMODE: u32 = 0x3;
+const FILEIO_O_RDONLY: u32 = 0x0;
+const FILEIO_O_CREAT: u32 = 0x200;
+const FILEIO_O_TRUNC: u32 = 0x400;
+
+/// Mode bits as defined by GDB's File-I/O protocol
+const FILEIO_S_IFREG: u32 = 0o100000;
+const FILEIO_S_IFDIR: u32 = 0o40000;
+
+/// Don't let a single GDB client exhaust our file descriptors.
+const MAX_OPEN_FILES: usize = 32;
+
+/// Errno values understood by GDB. These happen to match Linux for
+/// everything except `ENAMETOOLONG`.
+pub const ENOENT: u32 = 2;
+pub const EBADF: u32 = 9;
+pub const EACCES: u32 = 13;
+pub const EINVAL: u32 = 22;
+pub const EMFILE: u32 = 24;
+pub const EROFS: u32 = 30;
+pub const EUNKNOWN: u32 = 9999;
+
+/// Convert a host error into an errno value that GDB will understand.
+pub fn gdb_errno(e: &io::Error) -> u32 {
+    if let Some(code) = e.raw_os_error() {
+        // These codes are identical between GDB and every host we run on.
+        if let 1 | 2 | 4 | 9 | 13 | 14 | 16 | 17 | 19 | 20 | 21 | 22 | 23 | 24 | 27 | 28 | 29 | 30 =
+            code
+        {
+            return code as u32;
+        }
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ => EUNKNOWN,
+    }
+}
+
+fn permission_denied(path: &str, root: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is outside of the {}", path, root),
+    )
+}
+
+/// Canonicalize `root`, making sure it's a directory.
+fn canonical_dir(root: &str) -> io::Result<PathBuf> {
+    let root = fs::canonicalize(root)?;
+    if !root.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", root.display()),
+        ));
+    }
+    Ok(root)
+}
+
+/// Map `path` to a path under `root`, without following any symlinks.
+/// `None` is returned if it has a `..` in it.
+fn join_under(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut full_path = root.to_owned();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => full_path.push(c),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(full_path)
+}
+
+fn bad_fd() -> io::Error {
+    io::Error::from_raw_os_error(EBADF as i32)
+}
+
+const SYSROOT: &str = "gdb sysroot";
+const CORE_DIR: &str = "core file directory";
+
+/// A read-only view of a directory on the host, exposed to GDB through
+/// the `vFile` packets. Paths that GDB sends are treated as being relative
+/// to the root of this directory, and anything that would escape it
+/// (either with `..` or through a symlink) is refused.
+pub struct HostFiles {
+    root: PathBuf,
+    exec_file: Option<String>,
+    files: HashMap<u32, File>,
+    next_fd: u32,
+}
+
+impl HostFiles {
+    pub fn new(root: &str, exec_file: Option<String>) -> io::Result<HostFiles> {
+        Ok(HostFiles {
+            root: canonical_dir(root)?,
+            exec_file,
+            files: HashMap::new(),
+            next_fd: 1,
+        })
+    }
+
+    /// The path of the program being debugged, as GDB should see it.
+    pub fn exec_file(&self) -> Option<&str> {
+        self.exec_file.as_deref()
+    }
+
+    /// Map a path from GDB to a path under the root, without
+    /// following any symlinks.
+    fn sandboxed_path(&self, path: &str) -> io::Result<PathBuf> {
+        join_under(&self.root, path).ok_or_else(|| permission_denied(path, SYSROOT))
+    }
+
+    /// Map a path from GDB to a path under the root, following symlinks
+    /// and ensuring the result is still inside the root.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let resolved = fs::canonicalize(self.sandboxed_path(path)?)?;
+        if !resolved.starts_with(&self.root) {
+            return Err(permission_denied(path, SYSROOT));
+        }
+        Ok(resolved)
+    }
+
+    pub fn open(&mut self, path: &str, flags: u32) -> io::Result<u32> {
+        if (flags & FILEIO_O_ACCMODE) != FILEIO_O_RDONLY
+            || (flags & (FILEIO_O_CREAT | FILEIO_O_TRUNC)) != 0
+        {
+            return Err(io::Error::from_raw_os_error(EROFS as i32));
+        }
+        if self.files.len() >= MAX_OPEN_FILES {
+            return Err(io::Error::from_raw_os_error(EMFILE as i32));
+        }
+        let file = File::open(self.resolve(path)?)?;
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    pub fn close(&mut self, fd: u32) -> io::Result<()> {
+        self.files.remove(&fd).map(|_| ()).ok_or_else(bad_fd)
+    }
+
+    pub fn pread(&mut self, fd: u32, count: usize, offset: u64) -> io::Result<Vec<u8>> {
+        let file = self.files.get_mut(&fd).ok_or_else(bad_fd)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(count);
+        file.take(count as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Return the `struct stat` for an open file, encoded the way GDB
+    /// expects it: big-endian, with 32-bit fields except for the sizes.
+    pub fn fstat(&self, fd: u32) -> io::Result<Vec<u8>> {
+        let metadata = self.files.get(&fd).ok_or_else(bad_fd)?.metadata()?;
+        let mode = if metadata.is_dir() {
+            FILEIO_S_IFDIR | 0o555
+        } else {
+            FILEIO_S_IFREG | 0o444
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+
+        let mut stat = vec![];
+        // st_dev, st_ino, st_mode, st_nlink, st_uid, st_gid, st_rdev
+        for field in &[0, 0, mode, 1, 0, 0, 0] {
+            stat.extend_from_slice(&u32::to_be_bytes(*field));
+        }
+        // st_size, st_blksize, st_blocks
+        stat.extend_from_slice(&metadata.len().to_be_bytes());
+        stat.extend_from_slice(&512u64.to_be_bytes());
+        stat.extend_from_slice(&metadata.len().div_ceil(512).to_be_bytes());
+        // st_atime, st_mtime, st_ctime
+        for _ in 0..3 {
+            stat.extend_from_slice(&mtime.to_be_bytes());
+        }
+        Ok(stat)
+    }
+
+    pub fn readlink(&self, path: &str) -> io::Result<Vec<u8>> {
+        let link = self.sandboxed_path(path)?;
+        // The link itself may point anywhere, but the directory it lives in
+        // must be inside the root.
+        if let Some(parent) = link.parent() {
+            if !fs::canonicalize(parent)?.starts_with(&self.root) {
+                return Err(permission_denied(path, SYSROOT));
+            }
+        }
+        let target = fs::read_link(link)?;
+        Ok(target.to_string_lossy().as_bytes().to_vec())
+    }
+}
+
+/// The directory given with `--core-dir`, which `monitor coredump` writes
+/// core files into. Names from GDB are relative to it, and the same rules
+/// as for the sysroot keep them inside it.
+pub struct CoreDir {
+    root: PathBuf,
+}
+
+impl CoreDir {
+    pub fn new(root: &str) -> io::Result<CoreDir> {
+        Ok(CoreDir {
+            root: canonical_dir(root)?,
+        })
+    }
+
+    /// Create or truncate the core file `path`, returning where it is on
+    /// this machine along with the file.
+    pub fn create(&self, path: &str) -> io::Result<(PathBuf, File)> {
+        if Path::new(path).has_root() {
+            return Err(permission_denied(path, CORE_DIR));
+        }
+        let full_path =
+            join_under(&self.root, path).ok_or_else(|| permission_denied(path, CORE_DIR))?;
+        let parent = full_path.parent().unwrap_or(&self.root);
+        if !fs::canonicalize(parent)?.starts_with(&self.root) {
+            return Err(permission_denied(path, CORE_DIR));
+        }
+        // An existing file may be a symlink to somewhere else
+        if full_path.symlink_metadata().is_ok()
+            && !fs::canonicalize(&full_path)?.starts_with(&self.root)
+        {
+            return Err(permission_denied(path, CORE_DIR));
+        }
+        let file = File::create(&full_path)?;
+        Ok((full_path, file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for one test, with a file just outside of it
+    fn scratch(test: &str) -> (PathBuf, PathBuf) {
+        let base =
+            std::env::temp_dir().join(format!("wishbone-tool-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let root = base.join("cores");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let outside = base.join("outside");
+        fs::write(&outside, b"keep").unwrap();
+        (root, outside)
+    }
+
+    fn is_denied(result: io::Result<(PathBuf, File)>) -> bool {
+        matches!(result, Err(e) if e.kind() == io::ErrorKind::PermissionDenied)
+    }
+
+    #[test]
+    fn core_files_stay_in_the_directory() {
+        let (root, outside) = scratch("core-dir");
+        let core_dir = CoreDir::new(root.to_str().unwrap()).unwrap();
+
+        let (path, _) = core_dir.create("hang.core").unwrap();
+        assert_eq!(path, fs::canonicalize(&root).unwrap().join("hang.core"));
+        let (path, _) = core_dir.create("./sub/hang.core").unwrap();
+        assert!(path.ends_with("sub/hang.core"));
+
+        assert!(is_denied(core_dir.create("../outside")));
+        assert!(is_denied(core_dir.create("sub/../../outside")));
+        assert!(is_denied(core_dir.create(outside.to_str().unwrap())));
+        assert!(is_denied(core_dir.create("/hang.core")));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+            std::os::unix::fs::symlink(root.parent().unwrap(), root.join("up")).unwrap();
+            assert!(is_denied(core_dir.create("link")));
+            assert!(is_denied(core_dir.create("up/outside")));
+        }
+        assert_eq!(fs::read(&outside).unwrap(), b"keep");
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+}
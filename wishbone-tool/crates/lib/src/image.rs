@@ -0,0 +1,544 @@
+use object::elf::PT_LOAD;
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::Endianness;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum ImageError {
+    IoError(io::Error),
+
+    /// The file looked like an ELF file, but couldn't be read as one
+    ElfError(String),
+
+    /// A line of an Intel HEX or S-record file couldn't be read
+    RecordError(
+        usize,  // line number
+        String, // what was wrong with it
+    ),
+
+    /// Two records of an Intel HEX or S-record file write the same address
+    Overlap(u32),
+
+    /// A record of an Intel HEX or S-record file, starting at this address,
+    /// runs past the top of the 32-bit address space
+    PastEnd(u32),
+
+    /// A raw binary has nothing to say where it goes, and no address was
+    /// given
+    NoAddress(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::IoError(e) => write!(f, "{}", e),
+            ImageError::ElfError(e) => write!(f, "bad ELF file: {}", e),
+            ImageError::RecordError(line, e) => write!(f, "line {}: {}", line, e),
+            ImageError::Overlap(addr) => {
+                write!(f, "0x{:08x} is written by more than one record", addr)
+            }
+            ImageError::PastEnd(addr) => write!(
+                f,
+                "the record at 0x{:08x} runs past the end of the address space",
+                addr
+            ),
+            ImageError::NoAddress(file) => write!(
+                f,
+                "{} is a raw binary, so it needs an address to load it at",
+                file
+            ),
+        }
+    }
+}
+
+impl std::convert::From<io::Error> for ImageError {
+    fn from(e: io::Error) -> ImageError {
+        ImageError::IoError(e)
+    }
+}
+
+impl std::convert::From<object::Error> for ImageError {
+    fn from(e: object::Error) -> ImageError {
+        ImageError::ElfError(e.to_string())
+    }
+}
+
+/// The kinds of file that `Image::load()` understands
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ImageFormat {
+    /// Bytes to be loaded as they are, at an address given separately
+    Binary,
+    Elf,
+    IntelHex,
+    SRecord,
+}
+
+impl ImageFormat {
+    /// Work out the format of `file_name`, which starts with `data`. ELF
+    /// files are recognized by their magic number, and the text formats by
+    /// their extension.
+    pub fn of(file_name: &str, data: &[u8]) -> ImageFormat {
+        if data.starts_with(b"\x7fELF") {
+            return ImageFormat::Elf;
+        }
+        let extension = Path::new(file_name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("hex") | Some("ihex") | Some("ihx") => ImageFormat::IntelHex,
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+                ImageFormat::SRecord
+            }
+            _ => ImageFormat::Binary,
+        }
+    }
+
+    /// Work out the format of the file called `file_name`, reading only as
+    /// much of it as needed.
+    pub fn of_file(file_name: &str) -> io::Result<ImageFormat> {
+        use std::io::Read;
+        let mut magic = vec![];
+        std::fs::File::open(file_name)?
+            .take(4)
+            .read_to_end(&mut magic)?;
+        Ok(ImageFormat::of(file_name, &magic))
+    }
+
+    /// Returns `true` if files of this format say where they're loaded
+    pub fn has_addresses(self) -> bool {
+        self != ImageFormat::Binary
+    }
+}
+
+/// A run of bytes to be written to one place
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+
+    /// How many bytes after `data` the program expects to be zero, such as
+    /// `.bss`, which ELF files leave out
+    pub zeroed: u32,
+}
+
+impl Segment {
+    /// The address just past `data`, which is 2^32 for data that runs to
+    /// the top of memory
+    pub fn end(&self) -> u64 {
+        self.addr as u64 + self.data.len() as u64
+    }
+}
+
+/// A program to be loaded into memory, made up of one or more segments
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+
+    /// Where the program starts, if the file says
+    pub entry: Option<u32>,
+
+    /// What kind of file it came from
+    pub format: ImageFormat,
+}
+
+impl Image {
+    /// Read `file_name`, which may be an ELF file, an Intel HEX or S-record
+    /// file, or a raw binary. Raw binaries are loaded at `addr`, which the
+    /// other formats don't need.
+    pub fn load(file_name: &str, addr: Option<u32>) -> Result<Image, ImageError> {
+        let data = std::fs::read(file_name)?;
+        match ImageFormat::of(file_name, &data) {
+            ImageFormat::Elf => Image::from_elf(&data),
+            ImageFormat::IntelHex => Image::from_ihex(&String::from_utf8_lossy(&data)),
+            ImageFormat::SRecord => Image::from_srec(&String::from_utf8_lossy(&data)),
+            ImageFormat::Binary => match addr {
+                Some(addr) => Ok(Image::from_binary(data, addr)),
+                None => Err(ImageError::NoAddress(file_name.to_owned())),
+            },
+        }
+    }
+
+    /// A raw binary loaded at `addr`
+    pub fn from_binary(data: Vec<u8>, addr: u32) -> Image {
+        Image {
+            segments: vec![Segment {
+                addr,
+                data,
+                zeroed: 0,
+            }],
+            entry: None,
+            format: ImageFormat::Binary,
+        }
+    }
+
+    /// Each `PT_LOAD` segment of an ELF file, at its physical address, which
+    /// is where a ROM-resident `.data` needs to go rather than where it runs
+    pub fn from_elf(data: &[u8]) -> Result<Image, ImageError> {
+        match data.get(4) {
+            Some(1) => Image::from_elf_class::<object::elf::FileHeader32<Endianness>>(data),
+            Some(2) => Image::from_elf_class::<object::elf::FileHeader64<Endianness>>(data),
+            _ => Err(ImageError::ElfError("unknown ELF class".to_owned())),
+        }
+    }
+
+    fn from_elf_class<Elf: FileHeader<Endian = Endianness>>(
+        data: &[u8],
+    ) -> Result<Image, ImageError> {
+        let header = Elf::parse(data)?;
+        let endian = header.endian()?;
+        let address = |addr: u64| {
+            u32::try_from(addr)
+                .map_err(|_| ImageError::ElfError(format!("0x{:x} doesn't fit in 32 bits", addr)))
+        };
+
+        let mut segments = vec![];
+        for phdr in header.program_headers(endian, data)? {
+            if phdr.p_type(endian) != PT_LOAD || phdr.p_memsz(endian).into() == 0 {
+                continue;
+            }
+            let contents = phdr
+                .data(endian, data)
+                .map_err(|_| ImageError::ElfError("segment runs off the end".to_owned()))?;
+            let memsz: u64 = phdr.p_memsz(endian).into();
+            segments.push(Segment {
+                addr: address(phdr.p_paddr(endian).into())?,
+                data: contents.to_vec(),
+                zeroed: address(memsz.saturating_sub(contents.len() as u64))?,
+            });
+        }
+        Ok(Image {
+            segments,
+            entry: Some(address(header.e_entry(endian).into())?),
+            format: ImageFormat::Elf,
+        })
+    }
+
+    /// Records from an Intel HEX file. Extended segment and extended linear
+    /// address records are both understood, as are both kinds of start
+    /// address.
+    pub fn from_ihex(text: &str) -> Result<Image, ImageError> {
+        let mut records = Records::default();
+        let mut base: u32 = 0;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |e: &str| ImageError::RecordError(index + 1, e.to_owned());
+            let bytes = line
+                .strip_prefix(':')
+                .and_then(hex_bytes)
+                .ok_or_else(|| error("not an Intel HEX record"))?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(error("record length doesn't match its byte count"));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(error("bad checksum"));
+            }
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            let word = |len: usize| {
+                if data.len() == len {
+                    Ok(data.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+                } else {
+                    Err(error("wrong length for this record type"))
+                }
+            };
+            match bytes[3] {
+                0x00 => records.add(base.wrapping_add(offset), data)?,
+                0x01 => break,
+                0x02 => base = word(2)? << 4,
+                0x03 => {
+                    let cs_ip = word(4)?;
+                    records.entry = Some(((cs_ip >> 16) << 4) + (cs_ip & 0xffff));
+                }
+                0x04 => base = word(2)? << 16,
+                0x05 => records.entry = Some(word(4)?),
+                _ => return Err(error("unknown record type")),
+            }
+        }
+        records.into_image(ImageFormat::IntelHex)
+    }
+
+    /// Records from a Motorola S-record file, with 16-, 24-, or 32-bit
+    /// addresses
+    pub fn from_srec(text: &str) -> Result<Image, ImageError> {
+        let mut records = Records::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |e: &str| ImageError::RecordError(index + 1, e.to_owned());
+            let mut chars = line.chars();
+            if chars.next() != Some('S') {
+                return Err(error("not an S-record"));
+            }
+            let kind = chars
+                .next()
+                .and_then(|c| c.to_digit(10))
+                .ok_or_else(|| error("not an S-record"))?;
+            let bytes = hex_bytes(chars.as_str()).ok_or_else(|| error("bad hex digits"))?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(error("record length doesn't match its byte count"));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+                return Err(error("bad checksum"));
+            }
+            let address_len = match kind {
+                0 | 1 | 5 | 9 => 2,
+                2 | 6 | 8 => 3,
+                3 | 7 => 4,
+                _ => return Err(error("unknown record type")),
+            };
+            if bytes.len() < address_len + 2 {
+                return Err(error("record is too short for its address"));
+            }
+            let addr = bytes[1..=address_len]
+                .iter()
+                .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+            let data = &bytes[address_len + 1..bytes.len() - 1];
+            match kind {
+                1..=3 => records.add(addr, data)?,
+                7..=9 => records.entry = Some(addr),
+                // Headers and record counts
+                _ => (),
+            }
+        }
+        records.into_image(ImageFormat::SRecord)
+    }
+
+    /// The whole image as one run of bytes, with any gaps between segments
+    /// filled with `fill`, along with the address it starts at
+    pub fn flatten(&self, fill: u8) -> Option<(u32, Vec<u8>)> {
+        let start = self.segments.iter().map(|s| s.addr).min()?;
+        let end = self.segments.iter().map(Segment::end).max()?;
+        let mut data = vec![fill; (end - start as u64) as usize];
+        for segment in &self.segments {
+            let offset = (segment.addr - start) as usize;
+            data[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        Some((start, data))
+    }
+
+    /// How many bytes the segments hold, not counting ones to be zeroed
+    pub fn len(&self) -> u32 {
+        self.segments.iter().map(|s| s.data.len() as u32).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Parse a string of hex digit pairs
+pub(crate) fn hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if text.len() & 1 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Data records from an Intel HEX or S-record file, gathered up by address
+#[derive(Default)]
+struct Records {
+    data: BTreeMap<u32, Vec<u8>>,
+    entry: Option<u32>,
+}
+
+impl Records {
+    fn add(&mut self, addr: u32, data: &[u8]) -> Result<(), ImageError> {
+        if addr as u64 + data.len() as u64 > 1 << 32 {
+            return Err(ImageError::PastEnd(addr));
+        }
+        if !data.is_empty() && self.data.insert(addr, data.to_vec()).is_some() {
+            return Err(ImageError::Overlap(addr));
+        }
+        Ok(())
+    }
+
+    /// Join records that follow on from one another into segments
+    fn into_image(self, format: ImageFormat) -> Result<Image, ImageError> {
+        let mut segments: Vec<Segment> = vec![];
+        for (addr, data) in self.data {
+            match segments.last_mut() {
+                Some(last) if last.end() > addr as u64 => return Err(ImageError::Overlap(addr)),
+                Some(last) if last.end() == addr as u64 => last.data.extend_from_slice(&data),
+                _ => segments.push(Segment {
+                    addr,
+                    data,
+                    zeroed: 0,
+                }),
+            }
+        }
+        Ok(Image {
+            segments,
+            entry: self.entry,
+            format,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    /// An Intel HEX record with the right length and checksum
+    fn ihex(kind: u8, offset: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&offset.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(sum.wrapping_neg());
+        format!(":{}\n", hex(&bytes))
+    }
+
+    /// An S-record with the right length and checksum
+    fn srec(kind: u8, addr: &[u8], data: &[u8]) -> String {
+        let mut bytes = vec![(addr.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(addr);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(!sum);
+        format!("S{}{}\n", kind, hex(&bytes))
+    }
+
+    fn segments(image: &Image) -> Vec<(u32, Vec<u8>)> {
+        image
+            .segments
+            .iter()
+            .map(|s| (s.addr, s.data.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn ihex_extended_addresses() {
+        let text = [
+            ihex(0x04, 0, &[0x40, 0x00]),
+            ihex(0x00, 0x0010, &[1, 2]),
+            ihex(0x00, 0x0012, &[3, 4]),
+            ihex(0x02, 0, &[0x10, 0x00]),
+            ihex(0x00, 0x0000, &[5]),
+            ihex(0x05, 0, &[0x40, 0x00, 0x00, 0x10]),
+            ihex(0x01, 0, &[]),
+        ]
+        .concat();
+        let image = Image::from_ihex(&text).unwrap();
+        assert_eq!(
+            segments(&image),
+            vec![(0x0001_0000, vec![5]), (0x4000_0010, vec![1, 2, 3, 4])]
+        );
+        assert_eq!(image.entry, Some(0x4000_0010));
+    }
+
+    #[test]
+    fn ihex_bad_records() {
+        let mut record = ihex(0x00, 0, &[1, 2, 3]);
+        record.replace_range(9..11, "FF");
+        assert!(matches!(
+            Image::from_ihex(&record),
+            Err(ImageError::RecordError(1, e)) if e == "bad checksum"
+        ));
+
+        // The byte count says four, but there are three
+        let mut record = ihex(0x00, 0, &[1, 2, 3]);
+        record.replace_range(1..3, "04");
+        assert!(matches!(
+            Image::from_ihex(&record),
+            Err(ImageError::RecordError(1, e)) if e.contains("length")
+        ));
+
+        let text = [ihex(0x00, 0, &[1]), ihex(0x04, 0, &[0x00])].concat();
+        assert!(matches!(
+            Image::from_ihex(&text),
+            Err(ImageError::RecordError(2, e)) if e.contains("wrong length")
+        ));
+    }
+
+    #[test]
+    fn ihex_overlaps() {
+        let text = [ihex(0x00, 0x10, &[1, 2, 3, 4]), ihex(0x00, 0x12, &[5])].concat();
+        assert!(matches!(
+            Image::from_ihex(&text),
+            Err(ImageError::Overlap(0x12))
+        ));
+        let text = [ihex(0x00, 0x10, &[1]), ihex(0x00, 0x10, &[2])].concat();
+        assert!(matches!(
+            Image::from_ihex(&text),
+            Err(ImageError::Overlap(0x10))
+        ));
+    }
+
+    #[test]
+    fn srec_address_sizes() {
+        let text = [
+            srec(0, &[0, 0], b"hdr"),
+            srec(1, &[0x00, 0x10], &[1, 2]),
+            srec(2, &[0x01, 0x00, 0x00], &[3]),
+            srec(3, &[0x40, 0x00, 0x00, 0x00], &[4]),
+            srec(7, &[0x40, 0x00, 0x00, 0x00], &[]),
+        ]
+        .concat();
+        let image = Image::from_srec(&text).unwrap();
+        assert_eq!(
+            segments(&image),
+            vec![
+                (0x10, vec![1, 2]),
+                (0x0001_0000, vec![3]),
+                (0x4000_0000, vec![4])
+            ]
+        );
+        assert_eq!(image.entry, Some(0x4000_0000));
+    }
+
+    #[test]
+    fn srec_bad_records() {
+        let mut record = srec(1, &[0, 0], &[1, 2]);
+        let end = record.len() - 1;
+        record.replace_range(end - 2..end, "00");
+        assert!(matches!(
+            Image::from_srec(&record),
+            Err(ImageError::RecordError(1, e)) if e == "bad checksum"
+        ));
+        assert!(matches!(
+            Image::from_srec("S1050000"),
+            Err(ImageError::RecordError(1, e)) if e.contains("length")
+        ));
+    }
+
+    #[test]
+    fn records_at_the_top_of_memory() {
+        let top = srec(3, &[0xff, 0xff, 0xff, 0xf0], &[0xaa; 16]);
+        let image = Image::from_srec(&top).unwrap();
+        assert_eq!(image.segments[0].end(), 1 << 32);
+        assert_eq!(image.flatten(0xff), Some((0xffff_fff0, vec![0xaa; 16])));
+
+        let past = srec(3, &[0xff, 0xff, 0xff, 0xf0], &[0xaa; 17]);
+        assert!(matches!(
+            Image::from_srec(&past),
+            Err(ImageError::PastEnd(0xffff_fff0))
+        ));
+
+        let text = [
+            ihex(0x04, 0, &[0xff, 0xff]),
+            ihex(0x00, 0xfff0, &[0xaa; 16]),
+        ]
+        .concat();
+        let image = Image::from_ihex(&text).unwrap();
+        assert_eq!(image.segments[0].end(), 1 << 32);
+    }
+}
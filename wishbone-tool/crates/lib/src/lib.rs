@@ -0,0 +1,93 @@
+//! # Wishbone Tool Servers
+//!
+//! This crate contains the servers behind the `wishbone-tool` command line
+//! program, so that they can be embedded in other programs such as board
+//! management daemons. It builds on the `Bridge` objects from the
+//! `wishbone-bridge` crate, which handle talking to the device itself.
+//!
+//! Each server is driven by a `Config` and takes ownership of a `Bridge`.
+//! A `Config` may be filled in directly, starting from `Config::default()`,
+//! or parsed from the `wishbone-tool` command line with `Config::parse()`.
+//! For example, to run a GDB server on port 3333 for a VexRiscv CPU reached
+//! via Etherbone:
+//!
+//! ```no_run
+//! use wishbone_bridge::EthernetBridge;
+//! use wishbone_tool_lib::config::Config;
+//! use wishbone_tool_lib::server::{self, ServerKind};
+//!
+//! let bridge = EthernetBridge::new("192.168.100.50:1234")
+//!     .unwrap()
+//!     .create()
+//!     .unwrap();
+//! bridge.connect().unwrap();
+//!
+//! let mut cfg = Config::default();
+//! cfg.gdb_port = 3333;
+//! cfg.debug_offset = 0xf00f_0000;
+//! server::run(ServerKind::GDB, &cfg, bridge).unwrap();
+//! ```
+//!
+//! Each server is behind a cargo feature, all of which are enabled by
+//! default. Builds for constrained hosts can turn off `default-features`
+//! and pick only the ones they need:
+//!
+//! * `cpu` - halting, stepping, and inspecting a RISC-V CPU
+//! * `gdb` - the GDB server, which turns on `cpu`
+//! * `wishbone-server` - the Wishbone-over-TCP server
+//! * `http` - CSR and memory access over HTTP
+//! * `repl` - an interactive prompt for peeking and poking
+//! * `script` - running Rhai scripts with `--script`
+//! * `terminal` - the crossover UART terminal and messible viewer
+//! * `flash` - SPI flash programming, with progress bars
+//! * `random-test` - writing and reading back random values
+//!
+//! Peeking, poking, loading files, board control, and macros are always
+//! available.
+//!
+//! Lower-level pieces, such as the GDB protocol handler in `gdb` and the
+//! CPU debug interface in `riscv`, are also available for programs that
+//! want to manage connections themselves, or script the CPU without GDB.
+
+#[cfg(feature = "cpu")]
+#[macro_use]
+extern crate bitflags;
+
+/// Command line and config file parsing
+pub mod config;
+
+/// Named register sequences loaded from the config file
+pub mod csr_macros;
+
+/// Register maps loaded from csr.csv, csr.json, or SVD files
+pub mod csr_map;
+
+/// The GDB remote serial protocol
+#[cfg(feature = "gdb")]
+pub mod gdb;
+
+/// Serving host files to GDB via `vFile` packets
+#[cfg(feature = "cpu")]
+pub mod hostio;
+
+/// Programs to load into memory from raw binaries, ELF files, and Intel
+/// HEX or S-record files
+pub mod image;
+
+/// Debug access to a RISC-V CPU
+#[cfg(feature = "cpu")]
+pub mod riscv;
+
+/// Showing the tasks of an RTOS as GDB threads
+#[cfg(feature = "gdb")]
+pub mod rtos;
+
+/// Servers and one-shot operations that run against a `Bridge`
+pub mod server;
+
+/// A server that exposes the Wishbone bus over TCP
+#[cfg(feature = "wishbone-server")]
+pub mod wishbone;
+
+pub use config::{Config, ConfigError};
+pub use server::{ServerError, ServerKind};
@@ -0,0 +1,223 @@
+//! ELF core files, for looking at a hung target after the fact with
+//! `gdb firmware.elf core`.
+//!
+//! GDB only understands RISC-V core files laid out the way Linux writes
+//! them, so that's what this produces: an `NT_PRSTATUS` note holding the
+//! PC and `x1` to `x31`, an `NT_FPREGSET` note if the CPU has an FPU, and
+//! a `PT_LOAD` segment for each memory region that was dumped.
+
+use super::{RiscvCpu, RiscvCpuError, Xlen};
+use wishbone_bridge::{Bridge, MemoryRegion};
+
+use std::io::Write;
+use tracing::info;
+
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+const ELFOSABI_GNU: u8 = 3;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 7;
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+
+/// Reported as the signal that stopped the program
+const SIGINT: u16 = 2;
+
+/// GDB's numbers for the PC, `f0`, and `fcsr`
+const PC_INDEX: u32 = 32;
+const F0_INDEX: u32 = 33;
+const FCSR_INDEX: u32 = 68;
+
+/// Bytes read from the bus at a time
+const READ_CHUNK: u32 = 4096;
+
+/// Where things are in Linux's `struct elf_prstatus`, which differs
+/// between RV32 and RV64
+struct PrstatusLayout {
+    size: usize,
+    pid: usize,
+    regs: usize,
+}
+
+impl PrstatusLayout {
+    const CURSIG: usize = 12;
+
+    fn new(xlen: Xlen) -> PrstatusLayout {
+        match xlen {
+            Xlen::Rv32 => PrstatusLayout {
+                size: 204,
+                pid: 24,
+                regs: 72,
+            },
+            Xlen::Rv64 => PrstatusLayout {
+                size: 376,
+                pid: 32,
+                regs: 112,
+            },
+        }
+    }
+}
+
+/// Builds up the file, with addresses and offsets as wide as `xlen`.
+struct ElfWriter {
+    xlen: Xlen,
+    data: Vec<u8>,
+}
+
+impl ElfWriter {
+    fn half(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn word(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn xword(&mut self, value: u64) {
+        match self.xlen {
+            Xlen::Rv32 => self.word(value as u32),
+            Xlen::Rv64 => self.data.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    fn header_size(&self) -> usize {
+        match self.xlen {
+            Xlen::Rv32 => 52,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    fn program_header_size(&self) -> usize {
+        match self.xlen {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 56,
+        }
+    }
+
+    fn header(&mut self, program_headers: usize) {
+        let class = match self.xlen {
+            Xlen::Rv32 => 1,
+            Xlen::Rv64 => 2,
+        };
+        self.data
+            .extend_from_slice(&[0x7f, b'E', b'L', b'F', class, 1, 1, ELFOSABI_GNU]);
+        self.data.extend_from_slice(&[0; 8]);
+        self.half(ET_CORE);
+        self.half(EM_RISCV);
+        self.word(1);
+        self.xword(0);
+        self.xword(self.header_size() as u64);
+        self.xword(0);
+        self.word(0);
+        self.half(self.header_size() as u16);
+        self.half(self.program_header_size() as u16);
+        self.half(program_headers as u16);
+        self.half(0);
+        self.half(0);
+        self.half(0);
+    }
+
+    fn program_header(&mut self, kind: u32, offset: usize, addr: u32, size: usize) {
+        let (flags, align) = match kind {
+            PT_LOAD => (PF_RWX, 1),
+            _ => (0, 4),
+        };
+        self.word(kind);
+        if self.xlen == Xlen::Rv64 {
+            self.word(flags);
+        }
+        self.xword(offset as u64);
+        self.xword(u64::from(addr));
+        self.xword(u64::from(addr));
+        self.xword(size as u64);
+        self.xword(size as u64);
+        if self.xlen == Xlen::Rv32 {
+            self.word(flags);
+        }
+        self.xword(align);
+    }
+}
+
+/// A note with the name `CORE`, padded out to a multiple of four bytes
+fn note(kind: u32, desc: &[u8]) -> Vec<u8> {
+    let mut note = vec![];
+    note.extend_from_slice(&5u32.to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&kind.to_le_bytes());
+    note.extend_from_slice(b"CORE\0\0\0\0");
+    note.extend_from_slice(desc);
+    note.resize((note.len() + 3) & !3, 0);
+    note
+}
+
+fn push_value(out: &mut Vec<u8>, value: u64, bytes: usize) {
+    out.extend_from_slice(&value.to_le_bytes()[..bytes]);
+}
+
+impl RiscvCpu {
+    /// Write an ELF core file with the registers of the halted CPU and the
+    /// contents of `regions`. Memory is read straight from the bus, as
+    /// the regions from csr.csv are physical addresses.
+    pub fn write_core_dump<W: Write>(
+        &self,
+        bridge: &Bridge,
+        regions: &[MemoryRegion],
+        mut out: W,
+    ) -> Result<(), RiscvCpuError> {
+        let xlen = self.xlen();
+        let layout = PrstatusLayout::new(xlen);
+        let mut prstatus = vec![0; layout.size];
+        prstatus[PrstatusLayout::CURSIG..PrstatusLayout::CURSIG + 2]
+            .copy_from_slice(&SIGINT.to_le_bytes());
+        prstatus[layout.pid..layout.pid + 4].copy_from_slice(&1u32.to_le_bytes());
+        // Linux puts the PC where x0 would be.
+        let mut regs = vec![];
+        for gdb_idx in 0..32 {
+            let reg = if gdb_idx == 0 { PC_INDEX } else { gdb_idx };
+            push_value(&mut regs, self.read_register(bridge, reg)?, xlen.bytes());
+        }
+        prstatus[layout.regs..layout.regs + regs.len()].copy_from_slice(&regs);
+        let mut notes = note(NT_PRSTATUS, &prstatus);
+
+        if let Some(flen) = self.flen() {
+            let mut fpregs = vec![];
+            for gdb_idx in F0_INDEX..F0_INDEX + 32 {
+                push_value(
+                    &mut fpregs,
+                    self.read_register(bridge, gdb_idx)?,
+                    flen.bytes(),
+                );
+            }
+            push_value(&mut fpregs, self.read_register(bridge, FCSR_INDEX)?, 4);
+            notes.extend_from_slice(&note(NT_FPREGSET, &fpregs));
+        }
+
+        let mut elf = ElfWriter { xlen, data: vec![] };
+        elf.header(regions.len() + 1);
+        let mut offset = elf.header_size() + elf.program_header_size() * (regions.len() + 1);
+        elf.program_header(PT_NOTE, offset, 0, notes.len());
+        offset += notes.len();
+        for region in regions {
+            elf.program_header(PT_LOAD, offset, region.base, region.size as usize);
+            offset += region.size as usize;
+        }
+        out.write_all(&elf.data)?;
+        out.write_all(&notes)?;
+
+        for region in regions {
+            info!(
+                "dumping {} bytes of {} from 0x{:08x}",
+                region.size, region.name, region.base
+            );
+            let mut done = 0;
+            while done < region.size {
+                let len = READ_CHUNK.min(region.size - done);
+                out.write_all(&bridge.burst_read(region.base + done, len)?)?;
+                done += len;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+//! A standard RISC-V Debug Module (version 0.13 of the debug spec), for CPUs
+//! such as Rocket, BlackParrot, and VexRiscv built with the standard debug
+//! plugin.
+//!
+//! The Debug Module Interface registers are expected on the Wishbone bus at
+//! `debug_offset`, one 32-bit word per DMI address. Only hart 0 is debugged.
+//! Registers are read and written with abstract commands, while memory is
+//! reached over the bus as usual, so the hart doesn't have to be halted.
+
+use super::{RiscvCpuError, RiscvRegister, RiscvRegisterType, VexRiscvFlags, Xlen};
+use wishbone_bridge::Bridge;
+
+use tracing::debug;
+
+const DATA0: u32 = 0x04;
+const DATA1: u32 = 0x05;
+const DMCONTROL: u32 = 0x10;
+const DMSTATUS: u32 = 0x11;
+const ABSTRACTCS: u32 = 0x16;
+const COMMAND: u32 = 0x17;
+const PROGBUF0: u32 = 0x20;
+
+const DMCONTROL_DMACTIVE: u32 = 1;
+const DMCONTROL_NDMRESET: u32 = 1 << 1;
+const DMCONTROL_ACKHAVERESET: u32 = 1 << 28;
+const DMCONTROL_RESUMEREQ: u32 = 1 << 30;
+const DMCONTROL_HALTREQ: u32 = 1 << 31;
+
+const DMSTATUS_VERSION_MASK: u32 = 0xf;
+const DMSTATUS_ALLHALTED: u32 = 1 << 9;
+const DMSTATUS_ALLRESUMEACK: u32 = 1 << 17;
+
+const ABSTRACTCS_BUSY: u32 = 1 << 12;
+const ABSTRACTCS_CMDERR_SHIFT: u32 = 8;
+const ABSTRACTCS_CMDERR_MASK: u32 = 0x7 << ABSTRACTCS_CMDERR_SHIFT;
+const ABSTRACTCS_PROGBUFSIZE_SHIFT: u32 = 24;
+
+/// Fields of an Access Register abstract command
+const COMMAND_POSTEXEC: u32 = 1 << 18;
+const COMMAND_TRANSFER: u32 = 1 << 17;
+const COMMAND_WRITE: u32 = 1 << 16;
+const COMMAND_AARSIZE_SHIFT: u32 = 20;
+
+/// Abstract command register numbers
+const REGNO_GPR: u32 = 0x1000;
+const REGNO_FPR: u32 = 0x1020;
+const CSR_DCSR: u32 = 0x7b0;
+const CSR_DPC: u32 = 0x7b1;
+
+const DCSR_STEP: u64 = 1 << 2;
+const DCSR_CAUSE_SHIFT: u64 = 6;
+const DCSR_EBREAKU: u64 = 1 << 12;
+const DCSR_EBREAKS: u64 = 1 << 13;
+const DCSR_EBREAKM: u64 = 1 << 15;
+
+/// `dcsr.cause` values for an `ebreak` and a trigger
+const CAUSE_EBREAK: u64 = 1;
+const CAUSE_TRIGGER: u64 = 2;
+
+const FENCE_I: u32 = 0x0000_100f;
+const EBREAK: u32 = 0x0010_0073;
+
+/// How many times to check on the Debug Module before giving up
+const MAX_POLLS: u32 = 100;
+
+/// Versions of the debug spec that this understands: 0.13 and 1.0
+const SUPPORTED_VERSIONS: &[u32] = &[2, 3];
+
+pub(super) struct DebugModule {
+    /// Where DMI address 0 is on the bus
+    base: u32,
+
+    /// Words in the program buffer, which is only needed to flush the
+    /// instruction cache
+    progbuf_size: u32,
+}
+
+impl DebugModule {
+    /// Activate the Debug Module at `base` and check that it's one this
+    /// understands.
+    pub(super) fn new(bridge: &Bridge, base: u32) -> Result<DebugModule, RiscvCpuError> {
+        let dm = DebugModule {
+            base,
+            progbuf_size: 0,
+        };
+        dm.write(bridge, DMCONTROL, DMCONTROL_DMACTIVE)?;
+        let version = dm.read(bridge, DMSTATUS)? & DMSTATUS_VERSION_MASK;
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(RiscvCpuError::UnsupportedDebugModule(version));
+        }
+        let progbuf_size = (dm.read(bridge, ABSTRACTCS)? >> ABSTRACTCS_PROGBUFSIZE_SHIFT) & 0x1f;
+        debug!(
+            "debug module version {} with {} program buffer words",
+            version, progbuf_size
+        );
+        Ok(DebugModule { progbuf_size, ..dm })
+    }
+
+    /// Describe the hart the way the VexRiscv debug bridge would, so that
+    /// the rest of the CPU handling doesn't need to know the difference.
+    pub(super) fn status(
+        &self,
+        bridge: &Bridge,
+        xlen: Xlen,
+    ) -> Result<VexRiscvFlags, RiscvCpuError> {
+        if self.read(bridge, DMSTATUS)? & DMSTATUS_ALLHALTED == 0 {
+            return Ok(VexRiscvFlags::empty());
+        }
+        let dcsr = self.access_register(bridge, CSR_DCSR, None, xlen)?;
+        match (dcsr >> DCSR_CAUSE_SHIFT) & 0x7 {
+            CAUSE_EBREAK | CAUSE_TRIGGER => {
+                Ok(VexRiscvFlags::HALT | VexRiscvFlags::HALTED_BY_BREAK)
+            }
+            _ => Ok(VexRiscvFlags::HALT),
+        }
+    }
+
+    /// Carry out a write to the VexRiscv status register: halting,
+    /// resuming or stepping, and resetting.
+    pub(super) fn write_status(
+        &self,
+        bridge: &Bridge,
+        value: VexRiscvFlags,
+        xlen: Xlen,
+    ) -> Result<(), RiscvCpuError> {
+        if value.contains(VexRiscvFlags::RESET_SET) {
+            // Ask for a halt as well, so the hart stops at the reset vector.
+            return self.write(
+                bridge,
+                DMCONTROL,
+                DMCONTROL_DMACTIVE | DMCONTROL_NDMRESET | DMCONTROL_HALTREQ,
+            );
+        }
+        if value.contains(VexRiscvFlags::RESET_CLEAR) {
+            self.write(bridge, DMCONTROL, DMCONTROL_DMACTIVE | DMCONTROL_HALTREQ)?;
+            self.wait_for(bridge, DMSTATUS_ALLHALTED)?;
+            return self.write(
+                bridge,
+                DMCONTROL,
+                DMCONTROL_DMACTIVE | DMCONTROL_ACKHAVERESET,
+            );
+        }
+        if value.contains(VexRiscvFlags::HALT_SET) {
+            self.write(bridge, DMCONTROL, DMCONTROL_DMACTIVE | DMCONTROL_HALTREQ)?;
+            self.wait_for(bridge, DMSTATUS_ALLHALTED)?;
+            return self.write(bridge, DMCONTROL, DMCONTROL_DMACTIVE);
+        }
+        if value.contains(VexRiscvFlags::HALT_CLEAR) {
+            // Make `ebreak` enter debug mode, as it does on VexRiscv.
+            let mut dcsr = self.access_register(bridge, CSR_DCSR, None, xlen)?;
+            dcsr |= DCSR_EBREAKM | DCSR_EBREAKS | DCSR_EBREAKU;
+            if value.contains(VexRiscvFlags::STEP) {
+                dcsr |= DCSR_STEP;
+            } else {
+                dcsr &= !DCSR_STEP;
+            }
+            self.access_register(bridge, CSR_DCSR, Some(dcsr), xlen)?;
+            self.write(bridge, DMCONTROL, DMCONTROL_DMACTIVE | DMCONTROL_RESUMEREQ)?;
+            self.wait_for(bridge, DMSTATUS_ALLRESUMEACK)?;
+            return self.write(bridge, DMCONTROL, DMCONTROL_DMACTIVE);
+        }
+        Ok(())
+    }
+
+    /// The PC of a halted hart, which is where it stopped.
+    pub(super) fn pc(&self, bridge: &Bridge, xlen: Xlen) -> Result<u64, RiscvCpuError> {
+        self.access_register(bridge, CSR_DPC, None, xlen)
+    }
+
+    /// Read `reg`, which is `width` wide. That's XLEN, apart from the
+    /// floating-point registers, which are FLEN.
+    pub(super) fn read_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        width: Xlen,
+    ) -> Result<u64, RiscvCpuError> {
+        self.access_register(bridge, Self::regno(reg), None, width)
+    }
+
+    pub(super) fn write_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u64,
+        width: Xlen,
+    ) -> Result<(), RiscvCpuError> {
+        if reg.register_type == RiscvRegisterType::General && reg.index == 0 {
+            return Ok(());
+        }
+        self.access_register(bridge, Self::regno(reg), Some(value), width)?;
+        Ok(())
+    }
+
+    /// Run `fence.i` on the hart, so that it sees code that was just
+    /// written to memory. Debug Modules without a program buffer can't,
+    /// in which case the hart is left to fetch it in its own time.
+    pub(super) fn flush_cache(&self, bridge: &Bridge, xlen: Xlen) -> Result<(), RiscvCpuError> {
+        if self.progbuf_size == 0 {
+            return Ok(());
+        }
+        self.write(bridge, PROGBUF0, FENCE_I)?;
+        if self.progbuf_size > 1 {
+            self.write(bridge, PROGBUF0 + 1, EBREAK)?;
+        }
+        match self.run_command(bridge, Self::aarsize(xlen) | COMMAND_POSTEXEC) {
+            Err(RiscvCpuError::AbstractCommandFailed(err)) => {
+                debug!("couldn't flush the instruction cache: cmderr {}", err);
+                Ok(())
+            }
+            other => other,
+        }
+    }
+
+    /// The abstract command register number for `reg`. The PC is `dpc`
+    /// while the hart is halted.
+    fn regno(reg: &RiscvRegister) -> u32 {
+        match reg.register_type {
+            RiscvRegisterType::General if reg.index == 32 => CSR_DPC,
+            RiscvRegisterType::General => REGNO_GPR + reg.index,
+            RiscvRegisterType::Float => REGNO_FPR + reg.index,
+            RiscvRegisterType::CSR => reg.index,
+        }
+    }
+
+    fn aarsize(xlen: Xlen) -> u32 {
+        match xlen {
+            Xlen::Rv32 => 2 << COMMAND_AARSIZE_SHIFT,
+            Xlen::Rv64 => 3 << COMMAND_AARSIZE_SHIFT,
+        }
+    }
+
+    /// Read register `regno`, or write `value` to it.
+    fn access_register(
+        &self,
+        bridge: &Bridge,
+        regno: u32,
+        value: Option<u64>,
+        xlen: Xlen,
+    ) -> Result<u64, RiscvCpuError> {
+        let mut command = Self::aarsize(xlen) | COMMAND_TRANSFER | regno;
+        if let Some(value) = value {
+            self.write(bridge, DATA0, value as u32)?;
+            if xlen == Xlen::Rv64 {
+                self.write(bridge, DATA1, (value >> 32) as u32)?;
+            }
+            command |= COMMAND_WRITE;
+        }
+        self.run_command(bridge, command)?;
+        if value.is_some() {
+            return Ok(0);
+        }
+        let low = u64::from(self.read(bridge, DATA0)?);
+        match xlen {
+            Xlen::Rv32 => Ok(low),
+            Xlen::Rv64 => Ok(low | u64::from(self.read(bridge, DATA1)?) << 32),
+        }
+    }
+
+    /// Start an abstract command and wait for it to finish. A failed
+    /// command leaves an error behind, which is cleared.
+    fn run_command(&self, bridge: &Bridge, command: u32) -> Result<(), RiscvCpuError> {
+        self.write(bridge, COMMAND, command)?;
+        for _ in 0..MAX_POLLS {
+            let abstractcs = self.read(bridge, ABSTRACTCS)?;
+            if abstractcs & ABSTRACTCS_BUSY != 0 {
+                continue;
+            }
+            let cmderr = (abstractcs & ABSTRACTCS_CMDERR_MASK) >> ABSTRACTCS_CMDERR_SHIFT;
+            if cmderr != 0 {
+                self.write(bridge, ABSTRACTCS, ABSTRACTCS_CMDERR_MASK)?;
+                return Err(RiscvCpuError::AbstractCommandFailed(cmderr));
+            }
+            return Ok(());
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
+    /// Wait for every bit of `mask` to be set in `dmstatus`.
+    fn wait_for(&self, bridge: &Bridge, mask: u32) -> Result<(), RiscvCpuError> {
+        for _ in 0..MAX_POLLS {
+            if self.read(bridge, DMSTATUS)? & mask == mask {
+                return Ok(());
+            }
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
+    fn read(&self, bridge: &Bridge, dmi_addr: u32) -> Result<u32, RiscvCpuError> {
+        Ok(bridge.peek(self.base + dmi_addr * 4)?)
+    }
+
+    fn write(&self, bridge: &Bridge, dmi_addr: u32, value: u32) -> Result<(), RiscvCpuError> {
+        Ok(bridge.poke(self.base + dmi_addr * 4, value)?)
+    }
+}
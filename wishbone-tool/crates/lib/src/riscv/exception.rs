@@ -0,0 +1,475 @@
+//! Decoding `mcause`, `mepc` and `mtval` into a description of the last
+//! trap, along with where it came from: the instruction that faulted, or
+//! which peripherals were raising an external interrupt.
+
+use super::{RiscvCpuController, RiscvCpuError};
+use crate::config::RegisterMapping;
+use wishbone_bridge::Bridge;
+
+use std::fmt;
+
+#[derive(PartialEq)]
+pub enum RiscvException {
+    /// When things are all 0
+    NoException,
+
+    /// 1 0
+    UserSoftwareInterrupt(u32 /* mepc */),
+
+    /// 1 1
+    SupervisorSoftwareInterrupt(u32 /* mepc */),
+
+    // [reserved]
+    /// 1 3
+    MachineSoftwareInterrupt(u32 /* mepc */),
+
+    /// 1 4
+    UserTimerInterrupt(u32 /* mepc */),
+
+    /// 1 5
+    SupervisorTimerInterrupt(u32 /* mepc */),
+
+    // [reserved]
+    /// 1 7
+    MachineTimerInterrupt(u32 /* mepc */),
+
+    /// 1 8
+    UserExternalInterrupt(u32 /* mepc */),
+
+    /// 1 9
+    SupervisorExternalInterrupt(u32 /* mepc */),
+
+    // [reserved]
+    /// 1 11
+    MachineExternalInterrupt(u32 /* mepc */),
+
+    ReservedInterrupt(u32 /* unknown cause number */, u32 /* mepc */),
+
+    /// 1 16 and up, which are left for the platform to define
+    LocalInterrupt(u32 /* cause number */, u32 /* mepc */),
+
+    /// 0 0
+    InstructionAddressMisaligned(u32 /* mepc */, u32 /* target address */),
+
+    /// 0 1
+    InstructionAccessFault(u32 /* mepc */, u32 /* target address */),
+
+    /// 0 2
+    IllegalInstruction(u32 /* mepc */, u32 /* instruction value */),
+
+    /// 0 3
+    Breakpoint(u32 /* mepc */),
+
+    /// 0 4
+    LoadAddressMisaligned(u32 /* mepc */, u32 /* target address */),
+
+    /// 0 5
+    LoadAccessFault(u32 /* mepc */, u32 /* target address */),
+
+    /// 0 6
+    StoreAddressMisaligned(u32 /* mepc */, u32 /* target address */),
+
+    /// 0 7
+    StoreAccessFault(u32 /* mepc */, u32 /* target address */),
+
+    /// 0 8
+    CallFromUMode(u32 /* mepc */),
+
+    /// 0 9
+    CallFromSMode(u32 /* mepc */),
+
+    // [reserved]
+    /// 0 11
+    CallFromMMode(u32 /* mepc */),
+
+    /// 0 12
+    InstructionPageFault(u32 /* mepc */, u32 /* target address */),
+
+    /// 0 13
+    LoadPageFault(u32 /* mepc */, u32 /* target address */),
+
+    // [reserved]
+    /// 0 15
+    StorePageFault(u32 /* mepc */, u32 /* target address */),
+
+    ReservedFault(
+        u32, /* unknown cause number */
+        u32, /* mepc */
+        u32, /* mtval */
+    ),
+
+    /// 0 24 to 31 and 0 48 to 63, which are set aside for custom use
+    CustomFault(
+        u32, /* cause number */
+        u32, /* mepc */
+        u32, /* mtval */
+    ),
+}
+
+impl fmt::Display for RiscvException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use RiscvException::*;
+        match *self {
+            NoException => write!(f, "No trap"),
+            UserSoftwareInterrupt(epc) => write!(f, "User swi from 0x{:08x}", epc),
+            SupervisorSoftwareInterrupt(epc) => write!(f, "Supervisor swi from 0x{:08x}", epc),
+            // --reserved--
+            MachineSoftwareInterrupt(epc) => write!(f, "Machine swi at 0x{:08x}", epc),
+            UserTimerInterrupt(epc) => write!(f, "User timer interrupt at 0x{:08x}", epc),
+            SupervisorTimerInterrupt(epc) => {
+                write!(f, "Supervisor timer interrupt at 0x{:08x}", epc)
+            }
+            // --reserved--
+            MachineTimerInterrupt(epc) => write!(f, "Machine timer interrupt at 0x{:08x}", epc),
+            UserExternalInterrupt(epc) => write!(f, "User external interrupt at 0x{:08x}", epc),
+            SupervisorExternalInterrupt(epc) => {
+                write!(f, "Supervisor external interrupt at 0x{:08x}", epc)
+            }
+            // --reserved--
+            MachineExternalInterrupt(epc) => {
+                write!(f, "Machine external interrupt at 0x{:08x}", epc)
+            }
+            ReservedInterrupt(code, epc) => {
+                write!(f, "Reserved interrupt {} at 0x{:08x}", code, epc)
+            }
+            LocalInterrupt(code, epc) => {
+                write!(f, "Local interrupt {} at 0x{:08x}", code, epc)
+            }
+
+            InstructionAddressMisaligned(epc, mtval) => write!(
+                f,
+                "Misaligned address instruction 0x{:08x} at 0x{:08x}",
+                mtval, epc
+            ),
+            InstructionAccessFault(epc, mtval) => write!(
+                f,
+                "Instruction access fault to 0x{:08x} at 0x{:08x}",
+                mtval, epc
+            ),
+            IllegalInstruction(epc, mtval) => {
+                write!(f, "Illegal instruction 0x{:08x} at 0x{:08x}", mtval, epc)
+            }
+            Breakpoint(epc) => write!(f, "Breakpoint at 0x{:08x}", epc),
+            LoadAddressMisaligned(epc, mtval) => write!(
+                f,
+                "Misaligned load address of 0x{:08x} at 0x{:08x}",
+                mtval, epc
+            ),
+            LoadAccessFault(epc, mtval) => {
+                write!(f, "Load access fault from 0x{:08x} at 0x{:08x}", mtval, epc)
+            }
+            StoreAddressMisaligned(epc, mtval) => write!(
+                f,
+                "Misaligned store address of 0x{:08x} at 0x{:08x}",
+                mtval, epc
+            ),
+            StoreAccessFault(epc, mtval) => {
+                write!(f, "Store access fault to 0x{:08x} at 0x{:08x}", mtval, epc)
+            }
+            CallFromUMode(epc) => write!(f, "Call from User mode at 0x{:08x}", epc),
+            CallFromSMode(epc) => write!(f, "Call from Supervisor mode at 0x{:08x}", epc),
+            // --reserved--
+            CallFromMMode(epc) => write!(f, "Call from Machine mode at 0x{:08x}", epc),
+            InstructionPageFault(epc, mtval) => write!(
+                f,
+                "Instruction page fault of 0x{:08x} at 0x{:08x}",
+                mtval, epc
+            ),
+            LoadPageFault(epc, mtval) => {
+                write!(f, "Load page fault of 0x{:08x} at 0x{:08x}", mtval, epc)
+            }
+            // --reserved--
+            StorePageFault(epc, mtval) => {
+                write!(f, "Store page fault of 0x{:08x} at 0x{:08x}", mtval, epc)
+            }
+            ReservedFault(code, epc, mtval) => write!(
+                f,
+                "Reserved fault {} with mtval 0x{:08x} at 0x{:08x}",
+                code, mtval, epc
+            ),
+            CustomFault(code, epc, mtval) => write!(
+                f,
+                "Custom fault {} with mtval 0x{:08x} at 0x{:08x}",
+                code, mtval, epc
+            ),
+        }
+    }
+}
+
+impl RiscvException {
+    pub fn from_regs(mcause: u32, mepc: u32, mtval: u32) -> RiscvException {
+        use RiscvException::*;
+
+        if mepc == 0 && mtval == 0 {
+            return NoException;
+        }
+
+        match mcause {
+            0x8000_0000 => UserSoftwareInterrupt(mepc),
+            0x8000_0001 => SupervisorSoftwareInterrupt(mepc),
+            // --reserved--
+            0x8000_0003 => MachineSoftwareInterrupt(mepc),
+            0x8000_0004 => UserTimerInterrupt(mepc),
+            0x8000_0005 => SupervisorTimerInterrupt(mepc),
+            // --reserved--
+            0x8000_0007 => MachineTimerInterrupt(mepc),
+            0x8000_0008 => UserExternalInterrupt(mepc),
+            0x8000_0009 => SupervisorExternalInterrupt(mepc),
+            // --reserved--
+            0x8000_000b => MachineExternalInterrupt(mepc),
+            x @ 0x8000_0002 | x @ 0x8000_0006 | x @ 0x8000_000a | x @ 0x8000_000c..=0x8000_000f => {
+                ReservedInterrupt(x & 0x7fff_ffff, mepc)
+            }
+            x @ 0x8000_0010..=0xffff_ffff => LocalInterrupt(x & 0x7fff_ffff, mepc),
+
+            0 => InstructionAddressMisaligned(mepc, mtval),
+            1 => InstructionAccessFault(mepc, mtval),
+            2 => IllegalInstruction(mepc, mtval),
+            3 => Breakpoint(mepc),
+            4 => LoadAddressMisaligned(mepc, mtval),
+            5 => LoadAccessFault(mepc, mtval),
+            6 => StoreAddressMisaligned(mepc, mtval),
+            7 => StoreAccessFault(mepc, mtval),
+            8 => CallFromUMode(mepc),
+            9 => CallFromSMode(mepc),
+            // --reserved--
+            11 => CallFromMMode(mepc),
+            12 => InstructionPageFault(mepc, mtval),
+            13 => LoadPageFault(mepc, mtval),
+            // --reserved--
+            15 => StorePageFault(mepc, mtval),
+            x @ 24..=31 | x @ 48..=63 => CustomFault(x, mepc, mtval),
+            x @ 10 | x @ 14 | x @ 16..=0x7fff_ffff => ReservedFault(x, mepc, mtval),
+        }
+    }
+
+    /// The value of `mcause` that this came from, or `None` if there was
+    /// no trap.
+    pub fn mcause(&self) -> Option<u32> {
+        use RiscvException::*;
+        let interrupt = |code: u32| Some(0x8000_0000 | code);
+        match *self {
+            NoException => None,
+            UserSoftwareInterrupt(_) => interrupt(0),
+            SupervisorSoftwareInterrupt(_) => interrupt(1),
+            MachineSoftwareInterrupt(_) => interrupt(3),
+            UserTimerInterrupt(_) => interrupt(4),
+            SupervisorTimerInterrupt(_) => interrupt(5),
+            MachineTimerInterrupt(_) => interrupt(7),
+            UserExternalInterrupt(_) => interrupt(8),
+            SupervisorExternalInterrupt(_) => interrupt(9),
+            MachineExternalInterrupt(_) => interrupt(11),
+            ReservedInterrupt(code, _) | LocalInterrupt(code, _) => interrupt(code),
+            InstructionAddressMisaligned(..) => Some(0),
+            InstructionAccessFault(..) => Some(1),
+            IllegalInstruction(..) => Some(2),
+            Breakpoint(_) => Some(3),
+            LoadAddressMisaligned(..) => Some(4),
+            LoadAccessFault(..) => Some(5),
+            StoreAddressMisaligned(..) => Some(6),
+            StoreAccessFault(..) => Some(7),
+            CallFromUMode(_) => Some(8),
+            CallFromSMode(_) => Some(9),
+            CallFromMMode(_) => Some(11),
+            InstructionPageFault(..) => Some(12),
+            LoadPageFault(..) => Some(13),
+            StorePageFault(..) => Some(15),
+            ReservedFault(code, ..) | CustomFault(code, ..) => Some(code),
+        }
+    }
+
+    /// The name the privileged spec's `encoding.h` gives the cause, such
+    /// as `IRQ_M_EXT` or `CAUSE_ILLEGAL_INSTRUCTION`, or `None` for causes
+    /// it doesn't name.
+    pub fn symbolic_name(&self) -> Option<&'static str> {
+        use RiscvException::*;
+        Some(match *self {
+            UserSoftwareInterrupt(_) => "IRQ_U_SOFT",
+            SupervisorSoftwareInterrupt(_) => "IRQ_S_SOFT",
+            MachineSoftwareInterrupt(_) => "IRQ_M_SOFT",
+            UserTimerInterrupt(_) => "IRQ_U_TIMER",
+            SupervisorTimerInterrupt(_) => "IRQ_S_TIMER",
+            MachineTimerInterrupt(_) => "IRQ_M_TIMER",
+            UserExternalInterrupt(_) => "IRQ_U_EXT",
+            SupervisorExternalInterrupt(_) => "IRQ_S_EXT",
+            MachineExternalInterrupt(_) => "IRQ_M_EXT",
+            InstructionAddressMisaligned(..) => "CAUSE_MISALIGNED_FETCH",
+            InstructionAccessFault(..) => "CAUSE_FETCH_ACCESS",
+            IllegalInstruction(..) => "CAUSE_ILLEGAL_INSTRUCTION",
+            Breakpoint(_) => "CAUSE_BREAKPOINT",
+            LoadAddressMisaligned(..) => "CAUSE_MISALIGNED_LOAD",
+            LoadAccessFault(..) => "CAUSE_LOAD_ACCESS",
+            StoreAddressMisaligned(..) => "CAUSE_MISALIGNED_STORE",
+            StoreAccessFault(..) => "CAUSE_STORE_ACCESS",
+            CallFromUMode(_) => "CAUSE_USER_ECALL",
+            CallFromSMode(_) => "CAUSE_SUPERVISOR_ECALL",
+            CallFromMMode(_) => "CAUSE_MACHINE_ECALL",
+            InstructionPageFault(..) => "CAUSE_FETCH_PAGE_FAULT",
+            LoadPageFault(..) => "CAUSE_LOAD_PAGE_FAULT",
+            StorePageFault(..) => "CAUSE_STORE_PAGE_FAULT",
+            NoException
+            | ReservedInterrupt(..)
+            | LocalInterrupt(..)
+            | ReservedFault(..)
+            | CustomFault(..) => return None,
+        })
+    }
+
+    /// Where the CPU was when the trap was taken: the faulting instruction
+    /// for an exception, or the next one to run for an interrupt.
+    pub fn mepc(&self) -> Option<u32> {
+        use RiscvException::*;
+        match *self {
+            NoException => None,
+            UserSoftwareInterrupt(epc)
+            | SupervisorSoftwareInterrupt(epc)
+            | MachineSoftwareInterrupt(epc)
+            | UserTimerInterrupt(epc)
+            | SupervisorTimerInterrupt(epc)
+            | MachineTimerInterrupt(epc)
+            | UserExternalInterrupt(epc)
+            | SupervisorExternalInterrupt(epc)
+            | MachineExternalInterrupt(epc)
+            | ReservedInterrupt(_, epc)
+            | LocalInterrupt(_, epc)
+            | InstructionAddressMisaligned(epc, _)
+            | InstructionAccessFault(epc, _)
+            | IllegalInstruction(epc, _)
+            | Breakpoint(epc)
+            | LoadAddressMisaligned(epc, _)
+            | LoadAccessFault(epc, _)
+            | StoreAddressMisaligned(epc, _)
+            | StoreAccessFault(epc, _)
+            | CallFromUMode(epc)
+            | CallFromSMode(epc)
+            | CallFromMMode(epc)
+            | InstructionPageFault(epc, _)
+            | LoadPageFault(epc, _)
+            | StorePageFault(epc, _)
+            | ReservedFault(_, epc, _)
+            | CustomFault(_, epc, _) => Some(epc),
+        }
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        self.mcause()
+            .is_some_and(|mcause| mcause & 0x8000_0000 != 0)
+    }
+
+    /// Whether `mepc` points at an instruction that was actually fetched,
+    /// so it's safe to read it back. After a fetch fault it may well not
+    /// be readable at all.
+    fn has_faulting_instruction(&self) -> bool {
+        use RiscvException::*;
+        match *self {
+            InstructionAddressMisaligned(..)
+            | InstructionAccessFault(..)
+            | InstructionPageFault(..) => false,
+            _ => !self.is_interrupt() && self.mepc().is_some(),
+        }
+    }
+}
+
+/// A LiteX peripheral that can raise an external interrupt, found by its
+/// `<name>_ev_pending` and `<name>_ev_enable` registers in csr.csv.
+#[derive(Clone, Debug)]
+pub struct InterruptSource {
+    pub name: String,
+    pub pending: u32,
+    pub enable: Option<u32>,
+}
+
+impl InterruptSource {
+    /// Find every event manager in csr.csv, sorted by name.
+    pub fn from_register_mapping(register_mapping: &RegisterMapping) -> Vec<InterruptSource> {
+        let mut sources: Vec<InterruptSource> = register_mapping
+            .iter()
+            .filter_map(|(reg_name, addr)| {
+                let name = reg_name.strip_suffix("_ev_pending")?;
+                Some(InterruptSource {
+                    name: name.to_owned(),
+                    pending: (*addr)?,
+                    enable: register_mapping
+                        .get(&format!("{}_ev_enable", name))
+                        .cloned()
+                        .flatten(),
+                })
+            })
+            .collect();
+        sources.sort_by(|a, b| a.name.cmp(&b.name));
+        sources
+    }
+}
+
+impl RiscvCpuController {
+    /// Describe `trap` along with whatever can be found out about its
+    /// cause, with the CPU halted. Sources of an external interrupt are
+    /// read straight from the bus, as LiteX's own interrupt CSRs are
+    /// custom and reading them traps on CPUs that don't have them.
+    pub(super) fn describe_trap(
+        &self,
+        bridge: &Bridge,
+        trap: &RiscvException,
+    ) -> Result<String, RiscvCpuError> {
+        let mut description = format!("{}", trap);
+        if let Some(mcause) = trap.mcause() {
+            description.push_str(&format!(" (mcause 0x{:08x}", mcause));
+            if let Some(name) = trap.symbolic_name() {
+                description.push_str(&format!(", {}", name));
+            }
+            description.push(')');
+        }
+        description.push('\n');
+
+        if trap.has_faulting_instruction() {
+            let mepc = trap.mepc().unwrap();
+            let low = self.read_memory(bridge, mepc, 2)?;
+            let insn = if super::instruction_length(low) == 4 {
+                low | (self.read_memory(bridge, mepc + 2, 2)? << 16)
+            } else {
+                low
+            };
+            let length = super::instruction_length(insn) as usize;
+            let bytes: Vec<String> = insn.to_le_bytes()[..length]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            description.push_str(&format!(
+                "  instruction at 0x{:08x}: {}
+",
+                mepc,
+                bytes.join(" ")
+            ));
+        }
+
+        match trap {
+            RiscvException::MachineExternalInterrupt(_)
+            | RiscvException::SupervisorExternalInterrupt(_) => {
+                let mut pending = vec![];
+                for source in &self.interrupt_sources {
+                    let mut events = bridge.peek(source.pending)?;
+                    if let Some(enable) = source.enable {
+                        events &= bridge.peek(enable)?;
+                    }
+                    if events != 0 {
+                        pending.push(format!("{} (events 0x{:x})", source.name, events));
+                    }
+                }
+                if !pending.is_empty() {
+                    description.push_str(&format!(
+                        "  pending: {}
+",
+                        pending.join(", ")
+                    ));
+                } else if !self.interrupt_sources.is_empty() {
+                    description.push_str(
+                        "  no peripheral has an event pending any more
+",
+                    );
+                }
+            }
+            _ => (),
+        }
+        Ok(description)
+    }
+}
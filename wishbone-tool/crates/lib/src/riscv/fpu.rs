@@ -0,0 +1,185 @@
+//! Floating-point registers, for CPUs with the F or D extension.
+//!
+//! The VexRiscv debug bridge can only run integer instructions and read back
+//! what they write, so each register is moved through the integer registers
+//! with `fmv`. RV32 has no `fmv` for doubles, so there they go through
+//! memory instead, just below the stack pointer, which is put back
+//! afterwards. A Debug Module reads them with abstract commands.
+//!
+//! Either way the FPU has to be switched on in `mstatus` first, or the CPU
+//! takes an illegal instruction trap. `mstatus` is put back on resume.
+
+use super::{
+    DebugBackend, RiscvCpuController, RiscvCpuError, RiscvRegister, RiscvRegisterType, Xlen,
+};
+use wishbone_bridge::Bridge;
+
+use tracing::debug;
+
+/// `misa` extension bits
+const MISA_D: u32 = 1 << 3;
+const MISA_F: u32 = 1 << 5;
+
+/// `mstatus.FS`, which is 0 while the FPU is off
+const MSTATUS_FS_MASK: u64 = 3 << 13;
+const MSTATUS_FS_INITIAL: u64 = 1 << 13;
+
+/// `OP-FP` instructions that move a register between the two files, with
+/// `rd` and `rs1` left as zero
+const FMV_X_W: u32 = 0xe000_0053;
+const FMV_W_X: u32 = 0xf000_0053;
+const FMV_X_D: u32 = 0xe200_0053;
+const FMV_D_X: u32 = 0xf200_0053;
+
+/// `fsd f?, -8(sp)` and `fld f?, -8(sp)`, with the FP register left as zero
+const FSD_BELOW_SP: u32 = (0x7f << 25) | (2 << 15) | (3 << 12) | (0x18 << 7) | 0x27;
+const FLD_BELOW_SP: u32 = (0xff8 << 20) | (2 << 15) | (3 << 12) | 0x07;
+
+/// The width of the floating-point registers, going by the extensions
+/// listed in `misa`: single precision for F, or double for D.
+pub(super) fn flen_from_misa(misa: u32) -> Option<Xlen> {
+    if misa & MISA_D != 0 {
+        Some(Xlen::Rv64)
+    } else if misa & MISA_F != 0 {
+        Some(Xlen::Rv32)
+    } else {
+        None
+    }
+}
+
+impl RiscvRegister {
+    /// Floating-point register `f<index>`
+    pub fn float(index: u32) -> RiscvRegister {
+        RiscvRegister {
+            register_type: RiscvRegisterType::Float,
+            index,
+            gdb_index: 33 + index,
+            name: format!("f{}", index),
+            present: true,
+            save_restore: true,
+            contents: super::RegisterContentsType::Float,
+        }
+    }
+
+    /// Whether this is `fflags`, `frm` or `fcsr`, which belong to the FPU
+    /// even though they're CSRs.
+    pub(super) fn is_fpu_csr(&self) -> bool {
+        self.register_type == RiscvRegisterType::CSR && (0x001..=0x003).contains(&self.index)
+    }
+}
+
+impl RiscvCpuController {
+    /// Turn the FPU on before touching one of its CSRs.
+    pub(super) fn prepare_fpu_csr(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+    ) -> Result<(), RiscvCpuError> {
+        if reg.is_fpu_csr() && self.flen.is_some() {
+            self.enable_fpu(bridge)?;
+        }
+        Ok(())
+    }
+
+    /// Turn the FPU on if it's off, saving `mstatus` to be put back when
+    /// the CPU resumes.
+    fn enable_fpu(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let mstatus_reg = RiscvRegister::mstatus();
+        let mstatus = self.read_register_wide(bridge, &mstatus_reg)?;
+        if mstatus & MSTATUS_FS_MASK != 0 {
+            return Ok(());
+        }
+        debug!("turning the FPU on to reach its registers");
+        self.save_clobbered(bridge, &mstatus_reg)?;
+        self.write_register_wide(bridge, &mstatus_reg, mstatus | MSTATUS_FS_INITIAL)
+    }
+
+    pub(super) fn read_float_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+    ) -> Result<u64, RiscvCpuError> {
+        let flen = self
+            .flen
+            .ok_or(RiscvCpuError::InvalidRegister(reg.gdb_index))?;
+        self.enable_fpu(bridge)?;
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.read_register(bridge, reg, flen);
+        }
+        match (flen, self.xlen) {
+            (Xlen::Rv32, _) => {
+                // The result is whatever would be written to x0.
+                self.write_instruction(bridge, FMV_X_W | (reg.index << 15))?;
+                Ok(u64::from(self.read_result(bridge)?))
+            }
+            (Xlen::Rv64, Xlen::Rv64) => {
+                self.save_clobbered(bridge, &RiscvRegister::x1())?;
+                self.write_instruction(bridge, FMV_X_D | (reg.index << 15) | (1 << 7))?;
+                self.read_register_wide(bridge, &RiscvRegister::x1())
+            }
+            (Xlen::Rv64, Xlen::Rv32) => {
+                let addr = self.scratch_address(bridge, reg)?;
+                let saved = self.read_scratch(bridge, addr)?;
+                self.write_instruction(bridge, FSD_BELOW_SP | (reg.index << 20))?;
+                let value = self.read_scratch(bridge, addr)?;
+                self.write_scratch(bridge, addr, saved)?;
+                Ok(value)
+            }
+        }
+    }
+
+    pub(super) fn write_float_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u64,
+    ) -> Result<(), RiscvCpuError> {
+        let flen = self
+            .flen
+            .ok_or(RiscvCpuError::InvalidRegister(reg.gdb_index))?;
+        self.enable_fpu(bridge)?;
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.write_register(bridge, reg, value, flen);
+        }
+        match (flen, self.xlen) {
+            (Xlen::Rv32, _) => {
+                self.save_clobbered(bridge, &RiscvRegister::x1())?;
+                self.write_register(bridge, &RiscvRegister::x1(), value as u32)?;
+                self.write_instruction(bridge, FMV_W_X | (1 << 15) | (reg.index << 7))
+            }
+            (Xlen::Rv64, Xlen::Rv64) => {
+                self.save_clobbered(bridge, &RiscvRegister::x1())?;
+                self.write_register_wide(bridge, &RiscvRegister::x1(), value)?;
+                self.write_instruction(bridge, FMV_D_X | (1 << 15) | (reg.index << 7))
+            }
+            (Xlen::Rv64, Xlen::Rv32) => {
+                let addr = self.scratch_address(bridge, reg)?;
+                let saved = self.read_scratch(bridge, addr)?;
+                self.write_scratch(bridge, addr, value)?;
+                self.write_instruction(bridge, FLD_BELOW_SP | (reg.index << 7))?;
+                self.write_scratch(bridge, addr, saved)
+            }
+        }
+    }
+
+    /// Where a double is stored on its way through memory: the eight bytes
+    /// below the stack pointer, which nothing can be relying on, as the
+    /// ABI lets an interrupt handler use them at any time.
+    fn scratch_address(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u32, RiscvCpuError> {
+        let sp = self.read_register(bridge, &RiscvRegister::x2())?;
+        match sp.checked_sub(8) {
+            Some(addr) if addr & 3 == 0 => Ok(addr),
+            _ => Err(RiscvCpuError::InvalidRegister(reg.gdb_index)),
+        }
+    }
+
+    fn read_scratch(&self, bridge: &Bridge, addr: u32) -> Result<u64, RiscvCpuError> {
+        let low = u64::from(self.read_memory(bridge, addr, 4)?);
+        Ok(low | u64::from(self.read_memory(bridge, addr + 4, 4)?) << 32)
+    }
+
+    fn write_scratch(&self, bridge: &Bridge, addr: u32, value: u64) -> Result<(), RiscvCpuError> {
+        self.write_memory(bridge, addr, 4, value as u32)?;
+        self.write_memory(bridge, addr + 4, 4, (value >> 32) as u32)
+    }
+}
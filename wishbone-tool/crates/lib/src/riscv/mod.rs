@@ -0,0 +1,2798 @@
+//! Halting, stepping, and inspecting a RISC-V CPU, through either the
+//! VexRiscv debug bridge or a standard RISC-V Debug Module.
+//!
+//! `RiscvCpu` is what the GDB server drives, but programs can also use it
+//! directly, such as to check where a board has got to in a test, without
+//! GDB. The `cpu` feature provides this module without the GDB server.
+//!
+//! ```no_run
+//! use wishbone_bridge::EthernetBridge;
+//! use wishbone_tool_lib::riscv::{DebugInterface, RiscvCpu, Xlen};
+//!
+//! let bridge = EthernetBridge::new("192.168.100.50:1234")
+//!     .unwrap()
+//!     .create()
+//!     .unwrap();
+//! bridge.connect().unwrap();
+//!
+//! let cpu = RiscvCpu::new(&bridge, 0xf00f_0000, Some(Xlen::Rv32), DebugInterface::VexRiscv)
+//!     .unwrap();
+//! cpu.halt(&bridge).unwrap();
+//! let pc = cpu.read_register_by_name(&bridge, "pc").unwrap();
+//! let sp = cpu.read_register_by_name(&bridge, "sp").unwrap();
+//! let top = cpu.read_memory(&bridge, sp as u32, 4).unwrap();
+//! println!("halted at {:08x} with {:08x} on the stack", pc, top);
+//! cpu.step(&bridge).unwrap();
+//! cpu.resume(&bridge).unwrap();
+//! ```
+//!
+//! Registers are numbered as GDB numbers them: `x0` to `x31` are 0 to 31,
+//! the PC is 32, and CSRs follow from 65. `register_number()` looks them up
+//! by name. Memory accesses go through the CPU, and so through its MMU.
+
+#[cfg(feature = "gdb")]
+use super::gdb::GdbController;
+use wishbone_bridge::{Bridge, BridgeError};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+pub mod exception;
+use exception::{InterruptSource, RiscvException};
+
+mod semihosting;
+#[cfg(feature = "gdb")]
+use semihosting::Request;
+use semihosting::Semihosting;
+
+mod trace;
+use trace::Tracing;
+pub use trace::{FrameQuery, TraceFrame};
+
+mod dm;
+use dm::DebugModule;
+
+mod regcache;
+use regcache::{Provenance, RegisterCache};
+
+mod fpu;
+
+mod coredump;
+
+bitflags! {
+    struct VexRiscvFlags: u32 {
+        const RESET = 1;
+        const HALT = 1 << 1;
+        const PIP_BUSY = 1 << 2;
+        const HALTED_BY_BREAK = 1 << 3;
+        const STEP = 1 << 4;
+        const RESET_SET = 1 << 16;
+        const HALT_SET = 1 << 17;
+        const RESET_CLEAR = 1 << 24;
+        const HALT_CLEAR = 1 << 25;
+    }
+}
+
+bitflags! {
+    /// Fields of `tdata1` for an address match (`mcontrol`) trigger
+    struct McontrolFlags: u32 {
+        const LOAD = 1;
+        const STORE = 1 << 1;
+        const EXECUTE = 1 << 2;
+        const U = 1 << 3;
+        const S = 1 << 4;
+        const M = 1 << 6;
+        const MATCH_NAPOT = 1 << 7;
+        const ACTION_HALT = 1 << 12;
+        const HIT = 1 << 20;
+        const DMODE = 1 << 27;
+        const TYPE_MCONTROL = 2 << 28;
+    }
+}
+
+/// How many triggers to probe for before assuming there are no more
+const MAX_TRIGGERS: u32 = 16;
+
+/// How many VexRiscv breakpoint slots to probe for before assuming there
+/// are no more
+const MAX_BREAKPOINTS: usize = 16;
+
+/// Breakpoints to use when they can't be counted, which is what LiteX
+/// builds VexRiscv with
+const DEFAULT_BREAKPOINTS: usize = 2;
+
+/// Written to each breakpoint slot to see if it's there. The low bit is
+/// clear, so the breakpoint stays disabled.
+const BREAKPOINT_PROBE: u32 = 0xaaaa_aaa8;
+
+/// Written over the code for software breakpoints: `ebreak`, or `c.ebreak`
+/// over a compressed instruction
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+/// How many times to check that the CPU has finished a single step before
+/// giving up
+const MAX_STEP_POLLS: u32 = 100;
+
+// fn swab(src: u32) -> u32 {
+//     (src << 24) & 0xff000000
+//         | (src << 8) & 0x00ff0000
+//         | (src >> 8) & 0x0000ff00
+//         | (src >> 24) & 0x000000ff
+// }
+
+#[derive(Debug, PartialEq)]
+pub enum RiscvCpuState {
+    Unknown,
+    Halted,
+    Running,
+}
+
+/// Width of the CPU's integer registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Xlen {
+    Rv32,
+    Rv64,
+}
+
+impl Xlen {
+    /// The width for a register size of `bits`, which must be 32 or 64
+    pub fn from_bits(bits: u32) -> Option<Xlen> {
+        match bits {
+            32 => Some(Xlen::Rv32),
+            64 => Some(Xlen::Rv64),
+            _ => None,
+        }
+    }
+
+    /// Work out the width from the low word of `misa`. On RV32 its MXL
+    /// field is in bits 31:30 and reads as 1. On wider CPUs MXL is above
+    /// bit 31, so those bits read as zero even though the extension bits
+    /// below them don't. A CPU without `misa` reads it as zero, and is
+    /// assumed to be RV32.
+    fn from_misa(misa: u32) -> Xlen {
+        if misa >> 30 == 0 && misa != 0 {
+            Xlen::Rv64
+        } else {
+            Xlen::Rv32
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    /// How many bytes GDB expects for each register
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+}
+
+#[derive(Debug)]
+pub enum RiscvCpuError {
+    /// Someone tried to request an unrecognized feature file
+    UnrecognizedFile(String /* requested filename */),
+
+    /// The given register could not be decoded
+    InvalidRegister(u32),
+
+    /// No register has that name
+    UnknownRegister(String /* name */),
+
+    /// Ran out of breakpoionts
+    BreakpointExhausted,
+
+    /// Couldn't find that breakpoint
+    BreakpointNotFound(u32 /* address */),
+
+    /// Writing `ebreak` over the code didn't stick, such as in ROM
+    BreakpointNotWritable(u32 /* address */),
+
+    /// The CPU has no triggers to set watchpoints with
+    NoTriggers,
+
+    /// Every trigger is already in use by a watchpoint
+    WatchpointExhausted,
+
+    /// The triggers can't watch this range, or this kind of access
+    UnsupportedWatchpoint(u32 /* address */, u32 /* length */),
+
+    /// Couldn't find that watchpoint
+    WatchpointNotFound(u32 /* address */),
+
+    /// An error occurred with the bridge
+    BridgeError(BridgeError),
+
+    /// Generic IO error
+    IoError(io::Error),
+
+    /// CPU didn't complete write
+    InstructionTimeout,
+
+    /// The debug module implements a version of the spec that isn't
+    /// understood
+    UnsupportedDebugModule(u32 /* version */),
+
+    /// The debug module couldn't carry out an abstract command
+    AbstractCommandFailed(u32 /* cmderr */),
+}
+
+impl ::std::fmt::Display for RiscvCpuError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        use RiscvCpuError::*;
+        match self {
+            UnrecognizedFile(s) => write!(f, "unrecognized file: {}", s),
+            InvalidRegister(r) => write!(f, "invalid register {}", r),
+            UnknownRegister(r) => write!(f, "no register named {}", r),
+            BreakpointExhausted => write!(f, "ran out of hardware breakpoints"),
+            BreakpointNotFound(b) => write!(f, "breakpoint {} not found", b),
+            BreakpointNotWritable(b) => write!(f, "can't write a breakpoint at 0x{:08x}", b),
+            NoTriggers => write!(f, "cpu has no triggers for watchpoints"),
+            WatchpointExhausted => write!(f, "ran out of hardware watchpoints"),
+            UnsupportedWatchpoint(a, l) => {
+                write!(f, "can't watch {} bytes at 0x{:08x}", l, a)
+            }
+            WatchpointNotFound(w) => write!(f, "watchpoint 0x{:08x} not found", w),
+            BridgeError(e) => write!(f, "bridge error: {}", e),
+            IoError(e) => write!(f, "io error: {}", e),
+            InstructionTimeout => write!(f, "cpu instruction timed out"),
+            UnsupportedDebugModule(v) => write!(f, "unsupported debug module version {}", v),
+            AbstractCommandFailed(e) => write!(f, "abstract command failed with cmderr {}", e),
+        }
+    }
+}
+
+impl std::convert::From<BridgeError> for RiscvCpuError {
+    fn from(e: BridgeError) -> RiscvCpuError {
+        RiscvCpuError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<io::Error> for RiscvCpuError {
+    fn from(e: io::Error) -> Self {
+        RiscvCpuError::IoError(e)
+    }
+}
+
+/// ABI names for x0 to x31, as accepted by `register_number()`
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// How many frames `halt_and_explain()` follows before giving up, in case
+/// the frame pointers form a loop.
+const MAX_BACKTRACE_DEPTH: u32 = 16;
+
+const THREADS_XML: &str = r#"<?xml version="1.0"?>
+<threads>
+    <thread id="1" core="0" name="cpu"/>
+</threads>"#;
+
+#[derive(Debug, PartialEq, Hash, Eq, Clone)]
+enum RiscvRegisterType {
+    /// Normal CPU registers
+    General,
+
+    /// Arch-specific registers
+    CSR,
+
+    /// Floating-point registers, from the F or D extension
+    Float,
+}
+
+impl RiscvRegisterType {
+    fn feature_name(&self) -> &str {
+        match *self {
+            RiscvRegisterType::General => "org.gnu.gdb.riscv.cpu",
+            RiscvRegisterType::CSR => "org.gnu.gdb.riscv.csr",
+            RiscvRegisterType::Float => "org.gnu.gdb.riscv.fpu",
+        }
+    }
+
+    fn group(&self) -> &str {
+        match *self {
+            RiscvRegisterType::General => "general",
+            RiscvRegisterType::CSR => "csr",
+            RiscvRegisterType::Float => "float",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Hash, Eq, Clone)]
+enum RegisterContentsType {
+    Int,
+    DataPtr,
+    CodePtr,
+    Float,
+}
+
+#[derive(Debug, PartialEq, Hash, Eq, Clone)]
+struct RiscvRegister {
+    /// Which register group this belongs to
+    register_type: RiscvRegisterType,
+
+    /// Index within its namespace (e.g. `ustatus` is a CSR with index 0x000,
+    /// even though GDB registers are offset by 65, so GDB calls `ustatus` register 65.)
+    index: u32,
+
+    /// The "index" as understood by gdb.
+    gdb_index: u32,
+
+    /// Architecture name
+    name: String,
+
+    /// Whether this register is present on this device
+    present: bool,
+
+    /// Whether GDB needs to save and restore it
+    save_restore: bool,
+
+    /// What kind of data this register contains
+    contents: RegisterContentsType,
+}
+
+impl RiscvRegister {
+    pub fn general(
+        index: u32,
+        name: &str,
+        save_restore: bool,
+        contents: RegisterContentsType,
+    ) -> RiscvRegister {
+        RiscvRegister {
+            register_type: RiscvRegisterType::General,
+            index,
+            gdb_index: index,
+            name: name.to_string(),
+            present: true,
+            save_restore,
+            contents,
+        }
+    }
+
+    pub fn csr(index: u32, name: &str, present: bool) -> RiscvRegister {
+        RiscvRegister {
+            register_type: RiscvRegisterType::CSR,
+            index,
+            gdb_index: index + Self::csr_offset(),
+            name: name.to_string(),
+            present,
+            save_restore: true,
+            contents: RegisterContentsType::Int,
+        }
+    }
+
+    fn csr_offset() -> u32 {
+        65
+    }
+
+    /// The register's feature in `target.xml`. The FPU's CSRs go with the
+    /// rest of the FPU, where GDB looks for them.
+    fn feature_name(&self) -> &str {
+        if self.is_fpu_csr() {
+            RiscvRegisterType::Float.feature_name()
+        } else {
+            self.register_type.feature_name()
+        }
+    }
+
+    fn group(&self) -> &str {
+        if self.is_fpu_csr() {
+            RiscvRegisterType::Float.group()
+        } else {
+            self.register_type.group()
+        }
+    }
+
+    pub fn x0() -> RiscvRegister {
+        RiscvRegister::general(0, "x0", false, RegisterContentsType::Int)
+    }
+
+    pub fn x1() -> RiscvRegister {
+        RiscvRegister::general(1, "x1", false, RegisterContentsType::Int)
+    }
+
+    pub fn x2() -> RiscvRegister {
+        RiscvRegister::general(2, "x2", false, RegisterContentsType::DataPtr)
+    }
+
+    pub fn pc() -> RiscvRegister {
+        RiscvRegister::general(32, "pc", false, RegisterContentsType::CodePtr)
+    }
+
+    pub fn satp() -> RiscvRegister {
+        RiscvRegister::csr(0x180, "satp", true)
+    }
+
+    pub fn misa() -> RiscvRegister {
+        RiscvRegister::csr(0x301, "misa", false)
+    }
+
+    pub fn mstatus() -> RiscvRegister {
+        RiscvRegister::csr(0x300, "mstatus", true)
+    }
+
+    pub fn mtvec() -> RiscvRegister {
+        RiscvRegister::csr(0x305, "mtvec", true)
+    }
+
+    pub fn mepc() -> RiscvRegister {
+        RiscvRegister::csr(0x341, "mepc", true)
+    }
+
+    pub fn mcause() -> RiscvRegister {
+        RiscvRegister::csr(0x342, "mcause", true)
+    }
+
+    pub fn mtval() -> RiscvRegister {
+        RiscvRegister::csr(0x343, "mtval", true)
+    }
+
+    pub fn tselect() -> RiscvRegister {
+        RiscvRegister::csr(0x7a0, "tselect", false)
+    }
+
+    pub fn tdata1() -> RiscvRegister {
+        RiscvRegister::csr(0x7a1, "tdata1", false)
+    }
+
+    pub fn tdata2() -> RiscvRegister {
+        RiscvRegister::csr(0x7a2, "tdata2", false)
+    }
+}
+
+#[derive(Default)]
+struct RiscvBreakpoint {
+    /// The address of the breakpoint
+    address: u32,
+
+    /// Whether this breakpoint is enabled
+    enabled: bool,
+
+    /// Whether this value is empty or not
+    allocated: bool,
+}
+
+/// A breakpoint made by writing `ebreak` over the code, for when the
+/// hardware breakpoints run out
+struct SoftBreakpoint {
+    address: u32,
+
+    /// 2 for `c.ebreak`, or 4 for `ebreak`
+    length: u32,
+
+    /// The instruction that was there before
+    original: u32,
+}
+
+/// How long `insn` is in bytes, going by its lowest two bits, which are
+/// `11` for every 32-bit instruction and anything else for compressed ones
+fn instruction_length(insn: u32) -> u32 {
+    if insn & 3 == 3 {
+        4
+    } else {
+        2
+    }
+}
+
+impl SoftBreakpoint {
+    fn ebreak(&self) -> u32 {
+        if self.length == 2 {
+            C_EBREAK
+        } else {
+            EBREAK
+        }
+    }
+}
+
+/// What had stopped the CPU by the time GDB interrupted it
+#[derive(Clone, Debug, PartialEq)]
+pub enum InterruptStop {
+    /// Nothing; the interrupt halted it
+    Interrupted,
+
+    /// It had just reached a breakpoint
+    Breakpoint,
+
+    /// It had just hit a watchpoint, described as it is in stop replies,
+    /// such as `watch:1000;`
+    Watchpoint(String),
+}
+
+/// What sort of access a watchpoint stops the CPU on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchpointKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchpointKind {
+    /// The name GDB gives this kind of watchpoint in stop replies
+    fn stop_reason(self) -> &'static str {
+        match self {
+            WatchpointKind::Write => "watch",
+            WatchpointKind::Read => "rwatch",
+            WatchpointKind::Access => "awatch",
+        }
+    }
+
+    fn access(self) -> McontrolFlags {
+        match self {
+            WatchpointKind::Write => McontrolFlags::STORE,
+            WatchpointKind::Read => McontrolFlags::LOAD,
+            WatchpointKind::Access => McontrolFlags::LOAD | McontrolFlags::STORE,
+        }
+    }
+}
+
+/// A watchpoint, set using one of the triggers in the CPU's trigger module
+#[derive(Clone, Debug)]
+struct RiscvWatchpoint {
+    kind: WatchpointKind,
+
+    /// The first address being watched
+    address: u32,
+
+    /// How many bytes are being watched
+    length: u32,
+
+    /// Index of the trigger, as written to `tselect`
+    trigger: u32,
+}
+
+impl RiscvWatchpoint {
+    /// The values of `tdata1` and `tdata2` that make the trigger fire. A
+    /// single byte is matched exactly, and anything longer must be a
+    /// naturally aligned power of two so that it can be matched as a NAPOT
+    /// range.
+    fn trigger_data(&self) -> Result<(McontrolFlags, u32), RiscvCpuError> {
+        let tdata1 = McontrolFlags::TYPE_MCONTROL
+            | McontrolFlags::DMODE
+            | McontrolFlags::ACTION_HALT
+            | McontrolFlags::M
+            | McontrolFlags::S
+            | McontrolFlags::U
+            | self.kind.access();
+        match self.length {
+            1 => Ok((tdata1, self.address)),
+            len if len.is_power_of_two() && self.address & (len - 1) == 0 => Ok((
+                tdata1 | McontrolFlags::MATCH_NAPOT,
+                self.address | (len / 2 - 1),
+            )),
+            len => Err(RiscvCpuError::UnsupportedWatchpoint(self.address, len)),
+        }
+    }
+}
+
+/// How the CPU is debugged
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugInterface {
+    /// The VexRiscv debug bridge, which runs instructions on the CPU
+    VexRiscv,
+
+    /// A standard RISC-V Debug Module, as of version 0.13 of the debug spec
+    DebugModule,
+}
+
+/// The interface to the CPU, along with whatever it needs to keep
+#[derive(Clone)]
+enum DebugBackend {
+    VexRiscv,
+    DebugModule(Arc<DebugModule>),
+}
+
+pub struct RiscvCpu {
+    /// A list of all available registers on this CPU
+    gdb_register_map: HashMap<u32, RiscvRegister>,
+
+    /// An XML representation of the register mapping
+    target_xml: String,
+
+    /// The memory offset of the debug register
+    debug_offset: u32,
+
+    /// Registers read, saved, or written while the CPU is halted
+    cached_values: Arc<Mutex<RegisterCache>>,
+
+    /// All available breakpoints
+    breakpoints: RefCell<Vec<RiscvBreakpoint>>,
+
+    /// Breakpoints written into the code once the hardware ones ran out
+    soft_breakpoints: RefCell<Vec<SoftBreakpoint>>,
+
+    /// How many triggers the CPU has for watchpoints, once it's been checked
+    trigger_count: RefCell<Option<u32>>,
+
+    /// Watchpoints that are currently set
+    watchpoints: Arc<Mutex<Vec<RiscvWatchpoint>>>,
+
+    /// CPU state
+    cpu_state: Arc<Mutex<RiscvCpuState>>,
+
+    /// Our own interface to the CPU
+    controller: RiscvCpuController,
+
+    /// "true" if an MMU exists on this CPU
+    has_mmu: bool,
+
+    /// "true" if the MMU is currently enabled
+    mmu_enabled: Arc<AtomicBool>,
+
+    /// The last exception, if any
+    last_exception: Arc<Mutex<Option<RiscvException>>>,
+
+    /// Semihosting calls made by the firmware
+    semihosting: Arc<Mutex<Semihosting>>,
+
+    /// Tracepoints and what they've recorded
+    tracing: Arc<Mutex<Tracing>>,
+}
+
+pub struct RiscvCpuController {
+    /// The bridge offset for the debug register
+    debug_offset: u32,
+
+    /// How the CPU is debugged
+    backend: DebugBackend,
+
+    /// A copy of the CPU's state object
+    cpu_state: Arc<Mutex<RiscvCpuState>>,
+
+    /// Registers read, saved, or written while the CPU is halted
+    cached_values: Arc<Mutex<RegisterCache>>,
+
+    /// Width of the integer registers
+    xlen: Xlen,
+
+    /// Width of the floating-point registers, if there are any
+    flen: Option<Xlen>,
+
+    /// "true" if an MMU exists on this CPU
+    has_mmu: bool,
+
+    /// "true" if the MMU is currently enabled
+    mmu_enabled: Arc<AtomicBool>,
+
+    /// The last exception, if any
+    last_exception: Arc<Mutex<Option<RiscvException>>>,
+
+    /// Watchpoints that are currently set, checked whenever the CPU halts
+    watchpoints: Arc<Mutex<Vec<RiscvWatchpoint>>>,
+
+    /// Semihosting calls made by the firmware
+    #[cfg_attr(not(feature = "gdb"), allow(dead_code))]
+    semihosting: Arc<Mutex<Semihosting>>,
+
+    /// Tracepoints and what they've recorded
+    #[cfg_attr(not(feature = "gdb"), allow(dead_code))]
+    tracing: Arc<Mutex<Tracing>>,
+
+    /// Peripherals to check when explaining an external interrupt
+    interrupt_sources: Vec<InterruptSource>,
+}
+
+impl RiscvCpu {
+    /// Connect to the VexRiscv debug bridge or Debug Module at `offset`.
+    /// If `xlen` isn't given, the register width is worked out from `misa`.
+    pub fn new(
+        bridge: &Bridge,
+        offset: u32,
+        xlen: Option<Xlen>,
+        interface: DebugInterface,
+    ) -> Result<RiscvCpu, RiscvCpuError> {
+        let mut gdb_register_map = Self::make_registers();
+
+        let cpu_state = Arc::new(Mutex::new(RiscvCpuState::Unknown));
+        let debug_offset = offset;
+        let cached_values = Arc::new(Mutex::new(RegisterCache::default()));
+        let last_exception = Arc::new(Mutex::new(None));
+        let watchpoints = Arc::new(Mutex::new(vec![]));
+        let semihosting = Arc::new(Mutex::new(Semihosting::default()));
+        let tracing = Arc::new(Mutex::new(Tracing::default()));
+        let backend = match interface {
+            DebugInterface::VexRiscv => DebugBackend::VexRiscv,
+            DebugInterface::DebugModule => {
+                DebugBackend::DebugModule(Arc::new(DebugModule::new(bridge, offset)?))
+            }
+        };
+
+        let mmu_enabled = Arc::new(AtomicBool::new(false));
+        let mut controller = RiscvCpuController {
+            cpu_state: cpu_state.clone(),
+            cached_values: cached_values.clone(),
+            debug_offset,
+            backend,
+            has_mmu: false,
+            mmu_enabled: mmu_enabled.clone(),
+            last_exception: last_exception.clone(),
+            watchpoints: watchpoints.clone(),
+            semihosting: semihosting.clone(),
+            tracing: tracing.clone(),
+            xlen: Xlen::Rv32,
+            flen: None,
+            interrupt_sources: vec![],
+        };
+
+        // Determine if this CPU has an MMU.
+        // Read the "satp" register and write the opposite value back in.
+        // If the value changes, then we know this register exists.
+        let was_running =
+            (controller.read_status(bridge)? & VexRiscvFlags::HALT) != VexRiscvFlags::HALT;
+        if was_running {
+            controller.perform_halt(bridge)?;
+        }
+        // A VexRiscv built without `misa` can trap when it's read, so it's
+        // only read when it's needed for the width anyway. A Debug Module
+        // reports a missing register as an error instead.
+        let misa = if xlen.is_none() {
+            controller.read_register(bridge, &RiscvRegister::misa())?
+        } else if interface == DebugInterface::DebugModule {
+            controller
+                .read_register(bridge, &RiscvRegister::misa())
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        controller.xlen = xlen.unwrap_or_else(|| Xlen::from_misa(misa));
+        controller.flen = fpu::flen_from_misa(misa);
+        info!("debugging a {}-bit cpu", controller.xlen.bits());
+        if let Some(flen) = controller.flen {
+            info!("cpu has {}-bit floating point registers", flen.bits());
+            for index in 0..32 {
+                Self::insert_register(&mut gdb_register_map, RiscvRegister::float(index));
+            }
+            for reg in gdb_register_map.values_mut() {
+                if reg.is_fpu_csr() {
+                    reg.present = true;
+                }
+            }
+        }
+
+        // Only Sv32 translation is understood, so leave `satp` alone on
+        // wider CPUs, where an unsupported mode would be ignored anyway.
+        // Debug Modules reach memory without going through the CPU, so
+        // they don't need to know about the MMU at all.
+        if controller.xlen == Xlen::Rv32 && interface == DebugInterface::VexRiscv {
+            let satp_register = RiscvRegister::satp();
+            let old_satp = controller.read_register(bridge, &satp_register)?;
+            controller.write_register(bridge, &satp_register, !old_satp)?;
+            let new_satp = controller.read_register(bridge, &satp_register)?;
+            if new_satp != old_satp {
+                controller.write_register(bridge, &satp_register, old_satp)?;
+                controller.has_mmu = true;
+                Self::insert_register(&mut gdb_register_map, satp_register);
+                mmu_enabled.store((old_satp & 0x8000_0000) == 0x8000_0000, Ordering::Relaxed);
+            }
+        }
+        if was_running {
+            controller.perform_resume(bridge, false)?;
+        }
+
+        let breakpoint_count = match interface {
+            DebugInterface::VexRiscv => controller.probe_breakpoints(bridge)?,
+            DebugInterface::DebugModule => DEFAULT_BREAKPOINTS,
+        };
+        let breakpoints = (0..breakpoint_count)
+            .map(|_| RiscvBreakpoint::default())
+            .collect();
+
+        let target_xml = Self::make_target_xml(&gdb_register_map, controller.xlen, controller.flen);
+
+        let has_mmu = controller.has_mmu;
+        let cpu = RiscvCpu {
+            gdb_register_map,
+            target_xml,
+            debug_offset,
+            cached_values,
+            breakpoints: RefCell::new(breakpoints),
+            soft_breakpoints: RefCell::new(vec![]),
+            trigger_count: RefCell::new(None),
+            watchpoints,
+            controller,
+            cpu_state,
+            has_mmu,
+            mmu_enabled,
+            last_exception,
+            semihosting,
+            tracing,
+        };
+
+        Ok(cpu)
+    }
+
+    fn insert_register(target: &mut HashMap<u32, RiscvRegister>, reg: RiscvRegister) {
+        target.insert(reg.gdb_index, reg);
+    }
+
+    fn make_registers() -> HashMap<u32, RiscvRegister> {
+        let mut registers = HashMap::new();
+
+        // Add in general purpose registers x0 to x31
+        registers.insert(0, RiscvRegister::x0());
+        registers.insert(1, RiscvRegister::x1());
+        registers.insert(2, RiscvRegister::x2());
+        for reg_num in 3..32 {
+            registers.insert(
+                reg_num,
+                RiscvRegister::general(
+                    reg_num,
+                    &format!("x{}", reg_num),
+                    true,
+                    RegisterContentsType::Int,
+                ),
+            );
+        }
+
+        // Add the program counter
+        registers.insert(32, RiscvRegister::pc());
+
+        // User trap setup
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x000, "ustatus", false));
+
+        // User floating-point CSRs
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x001, "fflags", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x002, "frm", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x003, "fcsr", false));
+
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x004, "uie", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x005, "utvec", false));
+
+        // User trap handling
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x040, "uscratch", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x041, "uepc", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x042, "ucause", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x043, "utval", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x044, "uip", false));
+
+        // User counter/timers
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xc00, "cycle", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xc01, "time", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xc02, "instret", false));
+        for hpmcounter_n in 3..32 {
+            Self::insert_register(
+                &mut registers,
+                RiscvRegister::csr(
+                    0xc00 + hpmcounter_n,
+                    &format!("hpmcounter{}", hpmcounter_n),
+                    false,
+                ),
+            );
+        }
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xc80, "cycleh", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xc81, "timeh", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xc82, "instreth", false));
+        for hpmcounter_n in 3..32 {
+            Self::insert_register(
+                &mut registers,
+                RiscvRegister::csr(
+                    0xc80 + hpmcounter_n,
+                    &format!("hpmcounter{}h", hpmcounter_n),
+                    false,
+                ),
+            );
+        }
+
+        // Supervisor Trap Setup
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x100, "sstatus", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x102, "sedeleg", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x103, "sideleg", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x104, "sie", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x105, "stvec", false));
+        Self::insert_register(
+            &mut registers,
+            RiscvRegister::csr(0x106, "scounteren", false),
+        );
+
+        // Supervisor Trap Handling
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x140, "sscratch", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x141, "sepc", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x142, "scause", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x143, "stval", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x144, "sip", false));
+
+        // Supervisor protection and translation
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x180, "satp", false));
+
+        // Machine information registers
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xf11, "mvendorid", true));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xf12, "marchid", true));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xf13, "mimpid", true));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xf14, "mhartid", true));
+
+        // Machine trap setup
+        Self::insert_register(&mut registers, RiscvRegister::mstatus());
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x301, "misa", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x302, "medeleg", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x303, "mideleg", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x304, "mie", true));
+        Self::insert_register(&mut registers, RiscvRegister::mtvec());
+        Self::insert_register(
+            &mut registers,
+            RiscvRegister::csr(0x306, "mcounteren", false),
+        );
+
+        // Machine trap handling
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x340, "mscratch", true));
+        Self::insert_register(&mut registers, RiscvRegister::mepc());
+        Self::insert_register(&mut registers, RiscvRegister::mcause());
+        Self::insert_register(&mut registers, RiscvRegister::mtval());
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x344, "mip", true));
+
+        // Machine protection and translation
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x3a0, "mpmcfg0", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x3a1, "mpmcfg1", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x3a2, "mpmcfg2", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x3a3, "mpmcfg3", false));
+        for pmpaddr_n in 0..16 {
+            Self::insert_register(
+                &mut registers,
+                RiscvRegister::csr(0x3b0 + pmpaddr_n, &format!("pmpaddr{}", pmpaddr_n), false),
+            );
+        }
+
+        // Machine counter/timers
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xb00, "mcycle", true));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xb02, "minstret", true));
+        for mhpmcounter_n in 3..32 {
+            Self::insert_register(
+                &mut registers,
+                RiscvRegister::csr(
+                    0xb00 + mhpmcounter_n,
+                    &format!("mhpmcounter{}", mhpmcounter_n),
+                    false,
+                ),
+            );
+        }
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xb80, "mcycleh", true));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0xb82, "minstreth", true));
+        for mhpmcounter_n in 3..32 {
+            Self::insert_register(
+                &mut registers,
+                RiscvRegister::csr(
+                    0xb80 + mhpmcounter_n,
+                    &format!("mhpmcounter{}h", mhpmcounter_n),
+                    false,
+                ),
+            );
+        }
+
+        // Machine counter setup
+        for mhpmevent_n in 3..32 {
+            Self::insert_register(
+                &mut registers,
+                RiscvRegister::csr(
+                    0x320 + mhpmevent_n,
+                    &format!("mhpmevent{}", mhpmevent_n),
+                    false,
+                ),
+            );
+        }
+
+        // Debug/trace registers
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x7a0, "tselect", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x7a1, "tdata1", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x7a2, "tdata2", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x7a3, "tdata3", false));
+
+        // Debug mode registers
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x7b0, "dcsr", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x7b1, "dpc", false));
+        Self::insert_register(&mut registers, RiscvRegister::csr(0x7b2, "dscratch", false));
+
+        registers
+    }
+
+    fn make_target_xml(
+        registers: &HashMap<u32, RiscvRegister>,
+        xlen: Xlen,
+        flen: Option<Xlen>,
+    ) -> String {
+        let mut reg_indexes: Vec<u32> = registers.keys().copied().collect();
+        reg_indexes.sort();
+        let mut target_xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n".to_string();
+
+        let mut last_feature = None;
+        for reg_index in reg_indexes {
+            let reg = registers.get(&reg_index).unwrap();
+            if !reg.present {
+                continue;
+            }
+            if Some(reg.feature_name()) != last_feature {
+                if last_feature != None {
+                    target_xml.push_str("</feature>\n");
+                }
+                target_xml.push_str(&format!("<feature name=\"{}\">\n", reg.feature_name()));
+                last_feature = Some(reg.feature_name());
+            }
+            let (reg_type, bitsize) = match (&reg.contents, flen) {
+                (RegisterContentsType::Int, _) => ("int", xlen),
+                (RegisterContentsType::CodePtr, _) => ("code_ptr", xlen),
+                (RegisterContentsType::DataPtr, _) => ("data_ptr", xlen),
+                (RegisterContentsType::Float, Some(Xlen::Rv64)) => ("ieee_double", Xlen::Rv64),
+                (RegisterContentsType::Float, _) => ("ieee_single", Xlen::Rv32),
+            };
+            target_xml.push_str(&format!(
+                "<reg name=\"{}\" bitsize=\"{}\" regnum=\"{}\" type=\"{}\" group=\"{}\"",
+                reg.name,
+                bitsize.bits(),
+                reg.gdb_index,
+                reg_type,
+                reg.group()
+            ));
+            if !reg.save_restore {
+                target_xml.push_str(" save-restore=\"no\"");
+            }
+            target_xml.push_str("/>\n");
+        }
+        if last_feature != None {
+            target_xml.push_str("</feature>\n");
+        }
+        target_xml.push_str("</target>\n");
+        target_xml
+    }
+
+    pub fn get_feature(&self, name: &str) -> Result<Vec<u8>, RiscvCpuError> {
+        if name == "target.xml" {
+            let xml = self.target_xml.to_string().into_bytes();
+            Ok(xml)
+        } else {
+            Err(RiscvCpuError::UnrecognizedFile(name.to_string()))
+        }
+    }
+
+    pub fn get_threads(&self) -> Result<Vec<u8>, RiscvCpuError> {
+        Ok(THREADS_XML.to_string().into_bytes())
+    }
+
+    /// Print information about why the CPU got into its current state
+    pub fn explain(&self, bridge: &Bridge) -> Result<String, RiscvCpuError> {
+        let exception = self.controller.get_current_trap(bridge)?;
+        let description = self.controller.describe_trap(bridge, &exception)?;
+
+        // We assume interrupts are enabled, and if they're disabled it's
+        // because we're currently handling one.
+        if self.controller.interrupts_enabled(bridge)? {
+            Ok(format!("Last trap was: {}", description))
+        } else {
+            Ok(format!("Current trap is: {}", description))
+        }
+    }
+
+    /// List the breakpoints and what they're set to, for
+    /// `monitor breakpoints`.
+    pub fn describe_breakpoints(&self) -> String {
+        let bps = self.breakpoints.borrow();
+        let mut report = format!("{} hardware breakpoints\n", bps.len());
+        for (bpidx, bp) in bps.iter().enumerate() {
+            if bp.allocated {
+                report.push_str(&format!("  {}: 0x{:08x}\n", bpidx, bp.address));
+            } else {
+                report.push_str(&format!("  {}: free\n", bpidx));
+            }
+        }
+        let soft = self.soft_breakpoints.borrow();
+        if !soft.is_empty() {
+            report.push_str(&format!("{} software breakpoints\n", soft.len()));
+            for bp in soft.iter() {
+                report.push_str(&format!("  0x{:08x}\n", bp.address));
+            }
+        }
+        report
+    }
+
+    pub fn add_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        self.allocate_breakpoint(bridge, addr)?;
+        Ok(())
+    }
+
+    /// Set a hardware breakpoint at `addr`, returning which one it is.
+    fn allocate_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<usize, RiscvCpuError> {
+        let mut bp_index = None;
+        let mut bps = self.breakpoints.borrow_mut();
+        for (bpidx, bp) in bps.iter().enumerate() {
+            if !bp.allocated {
+                bp_index = Some(bpidx);
+            }
+        }
+        if bp_index.is_none() {
+            return Err(RiscvCpuError::BreakpointExhausted);
+        }
+
+        let bp_index = bp_index.unwrap();
+
+        bps[bp_index].address = addr;
+        bps[bp_index].allocated = true;
+        bps[bp_index].enabled = true;
+
+        self.controller
+            .set_breakpoint(bridge, bp_index, Some(addr))?;
+        Ok(bp_index)
+    }
+
+    /// Set a software breakpoint at `addr` by writing `ebreak` over the
+    /// instruction there, or `c.ebreak` if it's a compressed one. The
+    /// instruction is decoded rather than trusting the size GDB asks for,
+    /// as a 4-byte `ebreak` over a compressed instruction would also break
+    /// the one after it. Code that can't be written, such as in ROM, is
+    /// left alone.
+    pub fn add_soft_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        let mut soft = self.soft_breakpoints.borrow_mut();
+        if soft.iter().any(|bp| bp.address == addr) {
+            return Ok(());
+        }
+        let length = instruction_length(self.read_code(bridge, addr, 2)?);
+        let bp = SoftBreakpoint {
+            address: addr,
+            length,
+            original: self.read_code(bridge, addr, length)?,
+        };
+        self.write_code(bridge, addr, length, bp.ebreak())?;
+        if self.read_code(bridge, addr, length)? != bp.ebreak() {
+            self.write_code(bridge, addr, length, bp.original)?;
+            return Err(RiscvCpuError::BreakpointNotWritable(addr));
+        }
+        // The instruction cache may still hold the old code.
+        self.controller.flush_cache(bridge)?;
+        debug!("set software breakpoint at {:08x}", addr);
+        soft.push(bp);
+        Ok(())
+    }
+
+    /// Whether `addr` has a software breakpoint written over it
+    pub fn has_soft_breakpoint(&self, addr: u32) -> bool {
+        self.soft_breakpoints
+            .borrow()
+            .iter()
+            .any(|bp| bp.address == addr)
+    }
+
+    pub fn remove_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), RiscvCpuError> {
+        let soft_index = self
+            .soft_breakpoints
+            .borrow()
+            .iter()
+            .position(|bp| bp.address == addr);
+        if let Some(index) = soft_index {
+            let bp = self.soft_breakpoints.borrow_mut().remove(index);
+            if !self.soft_breakpoint_present(bridge, &bp)? {
+                debug!("code at {:08x} has changed, leaving it alone", bp.address);
+                return Ok(());
+            }
+            self.write_code(bridge, bp.address, bp.length, bp.original)?;
+            return self.controller.flush_cache(bridge);
+        }
+
+        let mut bp_index = None;
+        let mut bps = self.breakpoints.borrow_mut();
+        for (bpidx, bp) in bps.iter().enumerate() {
+            if bp.allocated && bp.address == addr {
+                bp_index = Some(bpidx);
+            }
+        }
+        if bp_index.is_none() {
+            return Err(RiscvCpuError::BreakpointNotFound(addr));
+        }
+
+        let bp_index = bp_index.unwrap();
+
+        bps[bp_index].allocated = false;
+        bps[bp_index].enabled = false;
+
+        self.controller.set_breakpoint(bridge, bp_index, None)?;
+        Ok(())
+    }
+
+    /// Take out every breakpoint and leave the CPU running, as it would be
+    /// had no debugger been attached. A CPU that was never halted and has
+    /// no breakpoints is left alone.
+    pub fn release(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let mut addresses: Vec<u32> = self
+            .soft_breakpoints
+            .borrow()
+            .iter()
+            .map(|bp| bp.address)
+            .collect();
+        addresses.extend(
+            self.breakpoints
+                .borrow()
+                .iter()
+                .filter(|bp| bp.allocated)
+                .map(|bp| bp.address),
+        );
+        let halted = *self.cpu_state.lock().unwrap() == RiscvCpuState::Halted;
+        if addresses.is_empty() && !halted {
+            return Ok(());
+        }
+        // Software breakpoints are taken out through the CPU, so it has to
+        // be halted for that.
+        if !halted {
+            self.halt(bridge)?;
+        }
+        for addr in addresses {
+            self.remove_breakpoint(bridge, addr)?;
+        }
+        self.resume(bridge)?;
+        Ok(())
+    }
+
+    /// Read the `length`-byte instruction at `addr`, which need only be
+    /// aligned to two bytes.
+    fn read_code(&self, bridge: &Bridge, addr: u32, length: u32) -> Result<u32, RiscvCpuError> {
+        if length == 4 && addr & 3 == 0 {
+            return self.controller.read_memory(bridge, addr, 4);
+        }
+        let mut value = self.controller.read_memory(bridge, addr, 2)?;
+        if length == 4 {
+            value |= self.controller.read_memory(bridge, addr + 2, 2)? << 16;
+        }
+        Ok(value)
+    }
+
+    fn write_code(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        length: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        if length == 4 && addr & 3 == 0 {
+            return self.controller.write_memory(bridge, addr, 4, value);
+        }
+        self.controller
+            .write_memory(bridge, addr, 2, value & 0xffff)?;
+        if length == 4 {
+            self.controller
+                .write_memory(bridge, addr + 2, 2, value >> 16)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the `ebreak` of `bp` is still in memory. Loading a new
+    /// program over it replaces it, along with the instruction saved when
+    /// it was set, which mustn't then be written back.
+    fn soft_breakpoint_present(
+        &self,
+        bridge: &Bridge,
+        bp: &SoftBreakpoint,
+    ) -> Result<bool, RiscvCpuError> {
+        Ok(self.read_code(bridge, bp.address, bp.length)? == bp.ebreak())
+    }
+
+    /// If the CPU is about to run the `ebreak` of a software breakpoint,
+    /// put the original instruction back for a single step, so that it
+    /// doesn't stop there again straight away. Returns whether it stepped.
+    fn step_over_soft_breakpoint(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        let soft = self.soft_breakpoints.borrow();
+        if soft.is_empty() {
+            return Ok(false);
+        }
+        let pc = match self.get_cached_reg(&RiscvRegister::pc()) {
+            Some(pc) => pc,
+            None => self
+                .controller
+                .read_register_wide(bridge, &RiscvRegister::pc())?,
+        };
+        let bp = match soft.iter().find(|bp| u64::from(bp.address) == pc) {
+            Some(bp) => bp,
+            None => return Ok(false),
+        };
+        if !self.soft_breakpoint_present(bridge, bp)? {
+            return Ok(false);
+        }
+        debug!(
+            "stepping over {}-byte software breakpoint at {:08x}",
+            bp.length, bp.address
+        );
+        // Resuming flushes the instruction cache, so the step runs the
+        // original instruction. Whether that falls through to the next one
+        // or branches somewhere else is up to the CPU, which leaves the PC
+        // wherever it ended up.
+        self.write_code(bridge, bp.address, bp.length, bp.original)?;
+        self.controller.perform_resume(bridge, true)?;
+        self.controller.wait_for_step(bridge)?;
+        self.write_code(bridge, bp.address, bp.length, bp.ebreak())?;
+        self.controller.flush_cache(bridge)?;
+        Ok(true)
+    }
+
+    /// Count the address match triggers in the CPU's trigger module, by
+    /// selecting each one in turn until `tselect` doesn't read back or the
+    /// trigger can't match addresses. CPUs without a trigger module read
+    /// these CSRs as zero, so they have none. The CPU must be halted.
+    fn trigger_count(&self, bridge: &Bridge) -> Result<u32, RiscvCpuError> {
+        if let Some(count) = *self.trigger_count.borrow() {
+            return Ok(count);
+        }
+        // The fields of `tdata1` move with XLEN, and only the RV32 layout
+        // is understood.
+        if self.controller.xlen != Xlen::Rv32 {
+            *self.trigger_count.borrow_mut() = Some(0);
+            return Ok(0);
+        }
+        let tselect = RiscvRegister::tselect();
+        let mut count = 0;
+        while count < MAX_TRIGGERS {
+            self.controller.write_register(bridge, &tselect, count)?;
+            if self.controller.read_register(bridge, &tselect)? != count {
+                break;
+            }
+            let tdata1 = self
+                .controller
+                .read_register(bridge, &RiscvRegister::tdata1())?;
+            if tdata1 & (0xf << 28) != McontrolFlags::TYPE_MCONTROL.bits {
+                break;
+            }
+            count += 1;
+        }
+        debug!("CPU has {} triggers for watchpoints", count);
+        *self.trigger_count.borrow_mut() = Some(count);
+        Ok(count)
+    }
+
+    /// Stop the CPU when `length` bytes starting at `addr` are accessed in
+    /// the way given by `kind`. Longer ranges must be a naturally aligned
+    /// power of two in size.
+    pub fn add_watchpoint(
+        &self,
+        bridge: &Bridge,
+        kind: WatchpointKind,
+        addr: u32,
+        length: u32,
+    ) -> Result<(), RiscvCpuError> {
+        // Debug Modules have no breakpoints of their own, so the first
+        // triggers are kept for them.
+        let first = match self.controller.backend {
+            DebugBackend::VexRiscv => 0,
+            DebugBackend::DebugModule(_) => self.breakpoints.borrow().len() as u32,
+        };
+        let count = self.trigger_count(bridge)?;
+        if count <= first {
+            return Err(RiscvCpuError::NoTriggers);
+        }
+        let mut watchpoints = self.watchpoints.lock().unwrap();
+        let trigger = (first..count)
+            .find(|t| watchpoints.iter().all(|w| w.trigger != *t))
+            .ok_or(RiscvCpuError::WatchpointExhausted)?;
+        let watchpoint = RiscvWatchpoint {
+            kind,
+            address: addr,
+            length,
+            trigger,
+        };
+        self.controller.arm_watchpoint(bridge, &watchpoint)?;
+        watchpoints.push(watchpoint);
+        Ok(())
+    }
+
+    pub fn remove_watchpoint(
+        &self,
+        bridge: &Bridge,
+        kind: WatchpointKind,
+        addr: u32,
+        length: u32,
+    ) -> Result<(), RiscvCpuError> {
+        let mut watchpoints = self.watchpoints.lock().unwrap();
+        let index = watchpoints
+            .iter()
+            .position(|w| w.kind == kind && w.address == addr && w.length == length)
+            .ok_or(RiscvCpuError::WatchpointNotFound(addr))?;
+        let watchpoint = watchpoints.remove(index);
+        self.controller.disarm_trigger(bridge, watchpoint.trigger)
+    }
+
+    /// Forget every tracepoint and everything they've recorded.
+    pub fn clear_tracepoints(&self) {
+        self.tracing.lock().unwrap().clear();
+    }
+
+    /// Define tracepoint `number` at `addr`, which stops the trace run after
+    /// `pass_count` hits unless that's 0.
+    pub fn define_tracepoint(&self, number: u32, addr: u32, enabled: bool, pass_count: u32) {
+        self.tracing
+            .lock()
+            .unwrap()
+            .define(number, addr, enabled, pass_count);
+    }
+
+    /// Add something for tracepoint `number` at `addr` to collect, given as
+    /// the action part of a `QTDP` packet.
+    pub fn add_tracepoint_action(&self, number: u32, addr: u32, action: &str) {
+        self.tracing
+            .lock()
+            .unwrap()
+            .add_action(number, addr, action);
+    }
+
+    /// Start a trace run, setting a breakpoint on each enabled tracepoint.
+    pub fn start_trace(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let addresses = self.tracing.lock().unwrap().start();
+        for addr in addresses {
+            match self.allocate_breakpoint(bridge, addr) {
+                Ok(slot) => self.tracing.lock().unwrap().set_slot(addr, slot),
+                Err(e) => {
+                    self.stop_trace(bridge)?;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop the trace run, if there is one, and remove the tracepoints'
+    /// breakpoints. The frames are kept for looking through.
+    pub fn stop_trace(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let addresses = self.tracing.lock().unwrap().stop();
+        for addr in addresses {
+            self.remove_breakpoint(bridge, addr)?;
+        }
+        Ok(())
+    }
+
+    /// Describe the trace run as a `qTStatus` reply.
+    pub fn trace_status(&self) -> String {
+        self.tracing.lock().unwrap().status()
+    }
+
+    /// Find a recorded frame, searching from the one after `after` for
+    /// anything but a frame number.
+    pub fn find_trace_frame(
+        &self,
+        query: FrameQuery,
+        after: Option<usize>,
+    ) -> Option<(usize, TraceFrame)> {
+        self.tracing.lock().unwrap().find(query, after)
+    }
+
+    /// Whether the CPU was last left running, rather than halted.
+    pub fn is_running(&self) -> bool {
+        *self.cpu_state.lock().unwrap() == RiscvCpuState::Running
+    }
+
+    /// Ask the CPU itself whether it's halted, whatever the debugger
+    /// thinks it's doing.
+    pub fn is_halted(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        Ok(!is_running(self.controller.read_status(bridge)?))
+    }
+
+    pub fn halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let mut current_status = self.cpu_state.lock().unwrap();
+        *current_status = RiscvCpuState::Halted;
+        self.controller.perform_halt(bridge)?;
+        debug!("HALT: CPU is now halted");
+        Ok(())
+    }
+
+    /// Halt the CPU because GDB interrupted it, and say what stopped it.
+    /// The CPU may have reached a breakpoint just before being halted, in
+    /// which case the PC is saved just as `poll()` would have. Returns
+    /// `None` if the CPU wasn't running, as its stop has been reported
+    /// already.
+    pub fn halt_for_interrupt(
+        &self,
+        bridge: &Bridge,
+    ) -> Result<Option<InterruptStop>, RiscvCpuError> {
+        let mut current_status = self.cpu_state.lock().unwrap();
+        if *current_status != RiscvCpuState::Running {
+            return Ok(None);
+        }
+        *current_status = RiscvCpuState::Halted;
+
+        let flags = self.controller.read_status(bridge)?;
+        let mut break_pc = None;
+        if !is_running(flags)
+            && flags & VexRiscvFlags::HALTED_BY_BREAK == VexRiscvFlags::HALTED_BY_BREAK
+        {
+            let pc = self.controller.break_pc(bridge)?;
+            self.controller
+                .save_halted_reg(&RiscvRegister::pc(), u64::from(pc));
+            break_pc = Some(pc);
+        }
+        self.controller.perform_halt(bridge)?;
+        debug!("INTERRUPT: CPU is now halted");
+
+        if let Some(watchpoint) = self.controller.watchpoint_hit(bridge)? {
+            return Ok(Some(InterruptStop::Watchpoint(format!(
+                "{}:{:x};",
+                watchpoint.kind.stop_reason(),
+                watchpoint.address
+            ))));
+        }
+        // GDB knows nothing of tracepoint breakpoints or semihosting calls,
+        // which carry on once the CPU resumes, so they count as
+        // interrupted.
+        Ok(Some(match break_pc {
+            Some(pc)
+                if self.tracing.lock().unwrap().slot_at(pc).is_none()
+                    && !self.controller.is_semihosting_call(bridge, pc)? =>
+            {
+                InterruptStop::Breakpoint
+            }
+            _ => InterruptStop::Interrupted,
+        }))
+    }
+
+    /// Halt the CPU, but leave its state alone so that `poll()` notices
+    /// and reports the stop to whichever debugger is waiting for it.
+    pub fn interrupt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.controller
+            .write_status(bridge, VexRiscvFlags::HALT_SET)
+    }
+
+    fn update_breakpoints(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        for (bpidx, bp) in self.breakpoints.borrow().iter().enumerate() {
+            if bp.allocated && bp.enabled {
+                debug!(
+                    "Re-enabling breakpoint {} at address {:08x}",
+                    bpidx, bp.address
+                );
+                self.controller
+                    .set_breakpoint(bridge, bpidx, Some(bp.address))?;
+            } else {
+                debug!("Breakpoint {} is unallocated", bpidx);
+                // If this breakpoint is unallocated, ensure that there is no
+                // garbage breakpoints leftover from a previous session.
+                self.controller.set_breakpoint(bridge, bpidx, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset the target CPU, restore any breakpoints, and leave it in
+    /// the "halted" state.
+    pub fn reset(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        // Since we're resetting the CPU, invalidate all cached registers
+        self.cached_values.lock().unwrap().clear();
+        self.flush_cache(bridge)?;
+        self.mmu_enabled.store(false, Ordering::Relaxed);
+        *self.last_exception.lock().unwrap() = None;
+
+        self.controller
+            .write_status(bridge, VexRiscvFlags::HALT_SET)?;
+        self.controller
+            .write_status(bridge, VexRiscvFlags::HALT_SET | VexRiscvFlags::RESET_SET)?;
+        self.controller
+            .write_status(bridge, VexRiscvFlags::RESET_CLEAR)?;
+
+        // Resetting clears the triggers, so set the watchpoints up again.
+        for watchpoint in self.watchpoints.lock().unwrap().iter() {
+            self.controller.arm_watchpoint(bridge, watchpoint)?;
+        }
+
+        *self.cpu_state.lock().unwrap() = RiscvCpuState::Halted;
+        debug!("RESET: CPU is now halted and reset");
+        Ok(())
+    }
+
+    /// Reset the CPU and let it run. GDB still thinks it's halted, so the
+    /// state is left as `Unknown`, which stops `poll()` reporting where it
+    /// stops until the next `resume()` takes it back.
+    pub fn reset_and_run(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.reset(bridge)?;
+        self.update_breakpoints(bridge)?;
+        *self.cpu_state.lock().unwrap() = RiscvCpuState::Unknown;
+        self.controller
+            .write_status(bridge, VexRiscvFlags::HALT_CLEAR)?;
+        debug!("RESET: CPU is now running on its own");
+        Ok(())
+    }
+
+    /// Restore the CPU state and continue execution.
+    pub fn resume(&self, bridge: &Bridge) -> Result<Option<String>, RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        // Hold on to the state until the CPU is running, so that `poll()`
+        // doesn't take stepping over a software breakpoint for a stop.
+        let mut state = self.cpu_state.lock().unwrap();
+        let previous = std::mem::replace(&mut *state, RiscvCpuState::Running);
+        if previous == RiscvCpuState::Unknown {
+            self.controller.take_over(bridge)?;
+        } else {
+            self.step_over_soft_breakpoint(bridge)?;
+        }
+        // Rewrite breakpoints (is this necessary?)
+        self.update_breakpoints(bridge)?;
+        self.controller.perform_resume(bridge, false)?;
+        drop(state);
+
+        if let Some(exception) = self.last_exception.lock().unwrap().take() {
+            if exception != RiscvException::NoException {
+                return Ok(Some(format!("{}", exception)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return the result of the semihosting call that GDB has just carried
+    /// out, and let the CPU carry on, unless the user interrupted the call
+    /// with Ctrl-C, in which case it stays halted.
+    pub fn finish_semihosting(
+        &self,
+        bridge: &Bridge,
+        retcode: i64,
+        errno: u32,
+        interrupted: bool,
+    ) -> Result<(), RiscvCpuError> {
+        let result = match self.semihosting.lock().unwrap().finish(retcode, errno) {
+            Some(result) => result,
+            None => {
+                debug!("ignoring a File-I/O reply with no semihosting call waiting on it");
+                return Ok(());
+            }
+        };
+        let pc = match self.get_cached_reg(&RiscvRegister::pc()) {
+            Some(pc) => pc,
+            None => self
+                .controller
+                .read_register_wide(bridge, &RiscvRegister::pc())?,
+        };
+        self.controller.return_from_semihosting(pc, result);
+        if !interrupted {
+            self.resume(bridge)?;
+        }
+        Ok(())
+    }
+
+    /// Step the CPU forward by one instruction.
+    pub fn step(&self, bridge: &Bridge) -> Result<Option<String>, RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        if !self.step_over_soft_breakpoint(bridge)? {
+            self.controller.perform_resume(bridge, true)?;
+            // GDB reads the PC as soon as it hears the step is done.
+            self.controller.wait_for_step(bridge)?;
+        }
+
+        if let Some(exception) = self.last_exception.lock().unwrap().take() {
+            if exception != RiscvException::NoException {
+                return Ok(Some(format!("{}", exception)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find a register by name, giving its GDB register number. Both the
+    /// architectural names, such as `x2`, `pc`, `mstatus` or `f0`, and
+    /// the ABI names, such as `sp` and `a0`, are understood.
+    pub fn register_number(&self, name: &str) -> Option<u32> {
+        if name == "fp" {
+            return Some(8);
+        }
+        if let Some(index) = ABI_NAMES.iter().position(|abi| *abi == name) {
+            return Some(index as u32);
+        }
+        self.gdb_register_map
+            .values()
+            .find(|reg| reg.present && reg.name == name)
+            .map(|reg| reg.gdb_index)
+    }
+
+    /// Read a register by name, as understood by `register_number()`. The
+    /// CPU must be halted.
+    pub fn read_register_by_name(&self, bridge: &Bridge, name: &str) -> Result<u64, RiscvCpuError> {
+        match self.register_number(name) {
+            Some(gdb_idx) => self.read_register(bridge, gdb_idx),
+            None => Err(RiscvCpuError::UnknownRegister(name.to_string())),
+        }
+    }
+
+    /// Write a register by name, as understood by `register_number()`. The
+    /// CPU must be halted, and general-purpose registers only reach the
+    /// CPU once it's resumed.
+    pub fn write_register_by_name(
+        &self,
+        bridge: &Bridge,
+        name: &str,
+        value: u64,
+    ) -> Result<(), RiscvCpuError> {
+        match self.register_number(name) {
+            Some(gdb_idx) => self.write_register(bridge, gdb_idx, value),
+            None => Err(RiscvCpuError::UnknownRegister(name.to_string())),
+        }
+    }
+
+    /// Convert a GDB `regnum` into a `RiscvRegister`
+    ///
+    /// Note that `regnum` is a GDB-based register number, and corresponds
+    /// to the `gdb_index` property.
+    fn gdb_to_register(&self, regnum: u32) -> Result<&RiscvRegister, RiscvCpuError> {
+        match self.gdb_register_map.get(&regnum) {
+            Some(s) => Ok(s),
+            None => Err(RiscvCpuError::InvalidRegister(regnum)),
+        }
+    }
+
+    /// Width of the CPU's integer registers
+    pub fn xlen(&self) -> Xlen {
+        self.controller.xlen
+    }
+
+    /// Width of the CPU's floating-point registers, if it has any
+    pub fn flen(&self) -> Option<Xlen> {
+        self.controller.flen
+    }
+
+    /// How many bytes GDB expects for register `gdb_idx`. The
+    /// floating-point registers can be wider or narrower than the rest.
+    pub fn register_bytes(&self, gdb_idx: u32) -> usize {
+        match (self.gdb_register_map.get(&gdb_idx), self.controller.flen) {
+            (Some(reg), Some(flen)) if reg.register_type == RiscvRegisterType::Float => {
+                flen.bytes()
+            }
+            _ => self.controller.xlen.bytes(),
+        }
+    }
+
+    /// Read the specified register and return its value.
+    ///
+    /// The `gdb_idx` is the GDB index, and may include both CPU registers
+    /// and CSR-index registers, which are offset by an index.
+    pub fn read_register(&self, bridge: &Bridge, gdb_idx: u32) -> Result<u64, RiscvCpuError> {
+        let reg = self.gdb_to_register(gdb_idx)?;
+
+        // Give the cached value, if we have it.
+        if let Some(val) = self.get_cached_reg(reg) {
+            return Ok(val);
+        }
+
+        let value = self.controller.read_register_wide(bridge, reg)?;
+        // CSRs such as counters may change even while the CPU is halted.
+        if reg.register_type == RiscvRegisterType::General {
+            self.cached_values.lock().unwrap().note_read(reg, value);
+        }
+        Ok(value)
+    }
+
+    /// Read every general-purpose register plus the PC, in GDB order, as
+    /// needed for a `g` reply.
+    ///
+    /// Rather than reading each register on its own, this takes a single
+    /// snapshot of the halted CPU and reads the registers back to back.
+    pub fn read_all_registers(&self, bridge: &Bridge) -> Result<Vec<u64>, RiscvCpuError> {
+        let regs: Vec<&RiscvRegister> = self
+            .all_cpu_registers()
+            .iter()
+            .map(|idx| self.gdb_to_register(*idx))
+            .collect::<Result<_, _>>()?;
+        let cached: Vec<Option<u64>> = regs.iter().map(|reg| self.get_cached_reg(reg)).collect();
+
+        // A snapshot reads a single word per register, which is only the
+        // whole register on RV32.
+        let mut values = None;
+        if self.controller.xlen == Xlen::Rv32 {
+            values = self.controller.snapshot_registers(bridge, &regs, &cached)?;
+        }
+        let values = match values {
+            Some(values) => values,
+            None => {
+                // The pipeline didn't drain in time, so fall back to reading
+                // the registers one at a time.
+                debug!("register snapshot failed, reading registers individually");
+                regs.iter()
+                    .zip(cached)
+                    .map(|(reg, cached)| match cached {
+                        Some(val) => Ok(val),
+                        None => self.controller.read_register_wide(bridge, reg),
+                    })
+                    .collect::<Result<Vec<u64>, RiscvCpuError>>()?
+            }
+        };
+
+        let mut cache = self.cached_values.lock().unwrap();
+        for (reg, value) in regs.iter().zip(&values) {
+            cache.note_read(reg, *value);
+        }
+        Ok(values)
+    }
+
+    /// Return a vec containing all valid CPU registers.
+    pub fn all_cpu_registers(&self) -> Vec<u32> {
+        let mut v = vec![];
+        for (idx, reg) in &self.gdb_register_map {
+            if reg.register_type == RiscvRegisterType::General {
+                v.push(*idx);
+            }
+        }
+        v.sort();
+        v
+    }
+
+    /// Write a register on the device.
+    ///
+    /// For general-purpose registers, simply place the new value in the
+    /// cache, to be updated when we resume the CPU.
+    ///
+    /// For CSRs and floating-point registers, initiate the write immediately.
+    pub fn write_register(
+        &self,
+        bridge: &Bridge,
+        gdb_idx: u32,
+        value: u64,
+    ) -> Result<(), RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let reg = self.gdb_to_register(gdb_idx)?;
+        if reg.register_type == RiscvRegisterType::General {
+            self.cached_values.lock().unwrap().write(reg, value);
+            Ok(())
+        } else if reg.gdb_index == RiscvRegister::satp().gdb_index {
+            self.mmu_enabled
+                .store(value & 0x8000_0000 == 0x8000_0000, Ordering::Relaxed);
+            self.cached_values.lock().unwrap().write(reg, value);
+            Ok(())
+        } else {
+            self.controller.write_register_wide(bridge, reg, value)
+        }
+    }
+
+    pub fn read_memory(&self, bridge: &Bridge, addr: u32, sz: u32) -> Result<u32, RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.controller.read_memory(bridge, addr, sz)
+    }
+
+    pub fn write_memory(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        sz: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        self.controller.write_memory(bridge, addr, sz, value)
+    }
+
+    /// Read a register-sized word from memory.
+    pub fn read_xlen_word(&self, bridge: &Bridge, addr: u32) -> Result<u64, RiscvCpuError> {
+        self.controller.read_xlen_word(bridge, addr)
+    }
+
+    pub fn get_controller(&self) -> RiscvCpuController {
+        RiscvCpuController {
+            cpu_state: self.cpu_state.clone(),
+            debug_offset: self.debug_offset,
+            backend: self.controller.backend.clone(),
+            cached_values: self.cached_values.clone(),
+            has_mmu: self.has_mmu,
+            mmu_enabled: self.mmu_enabled.clone(),
+            last_exception: self.last_exception.clone(),
+            watchpoints: self.watchpoints.clone(),
+            semihosting: self.semihosting.clone(),
+            tracing: self.tracing.clone(),
+            xlen: self.controller.xlen,
+            flen: self.controller.flen,
+            interrupt_sources: self.controller.interrupt_sources.clone(),
+        }
+    }
+
+    /// Set the peripherals whose events are listed when explaining an
+    /// external interrupt. This has to be done before `get_controller()`
+    /// is called.
+    pub fn set_interrupt_sources(&mut self, interrupt_sources: Vec<InterruptSource>) {
+        self.controller.interrupt_sources = interrupt_sources;
+    }
+
+    fn get_cached_reg(&self, reg: &RiscvRegister) -> Option<u64> {
+        self.cached_values.lock().unwrap().get(reg)
+    }
+
+    pub fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.controller.flush_cache(bridge)
+    }
+}
+
+fn is_running(flags: VexRiscvFlags) -> bool {
+    // debug!("CPU flags: {:?}", flags);
+    ((flags & VexRiscvFlags::PIP_BUSY) == VexRiscvFlags::PIP_BUSY)
+        || ((flags & VexRiscvFlags::HALT) != VexRiscvFlags::HALT)
+}
+
+impl RiscvCpuController {
+    /// Poll the CPU and determine if it's running or not.  If it
+    /// transitions between states, handle this transition as appropriate.
+    #[cfg(feature = "gdb")]
+    pub fn poll(
+        &self,
+        bridge: &Bridge,
+        gdb_controller: &mut GdbController,
+    ) -> Result<bool, RiscvCpuError> {
+        // let _bridge_mutex = bridge.mutex().lock().unwrap();
+        let flags = self.read_status(bridge)?;
+        let mut current_status = self.cpu_state.lock().unwrap();
+
+        if !is_running(flags) {
+            // If the status was running, transition to the `halted` state.
+            if *current_status == RiscvCpuState::Running {
+                *current_status = RiscvCpuState::Halted;
+                // gdb_controller.gdb_send(b"T05swbreak:;")?;
+
+                // If we were halted by a breakpoint, save the PC (because it will
+                // be unavailable later).
+                let mut break_pc = None;
+                let mut halt_msg =
+                    if flags & VexRiscvFlags::HALTED_BY_BREAK == VexRiscvFlags::HALTED_BY_BREAK {
+                        // The actual opcode doesn't get executed when halted by a break, but
+                        // the pc gets incremented.  Save the target pc so that we can execute it
+                        // when we step/resume.
+                        // The result is a single word, so on RV64 this
+                        // assumes the code is in the low 4 GiB.
+                        let pc = self.break_pc(bridge)?;
+                        self.save_halted_reg(&RiscvRegister::pc(), u64::from(pc));
+                        break_pc = Some(pc);
+                        "T05".to_owned()
+                    } else {
+                        "T02".to_owned()
+                    };
+
+                self.perform_halt(bridge)?;
+                if let Some(watchpoint) = self.watchpoint_hit(bridge)? {
+                    halt_msg = format!(
+                        "T05{}:{:x};",
+                        watchpoint.kind.stop_reason(),
+                        watchpoint.address
+                    );
+                } else if let Some(pc) = break_pc {
+                    // Tracepoints record what they collect and carry on.
+                    let trace_slot = self.tracing.lock().unwrap().slot_at(pc);
+                    if let Some(slot) = trace_slot {
+                        self.record_trace_frame(bridge, pc)?;
+                        self.step_over_breakpoint(bridge, slot, pc)?;
+                        *current_status = RiscvCpuState::Running;
+                        return Ok(true);
+                    }
+                    // File-I/O requests can only be made in all-stop mode.
+                    if !gdb_controller.is_non_stop() && self.is_semihosting_call(bridge, pc)? {
+                        self.semihost(bridge, pc, gdb_controller, &mut current_status)?;
+                        return Ok(*current_status == RiscvCpuState::Running);
+                    }
+                }
+                debug!("POLL: CPU is now halted");
+                gdb_controller.send_stop(&halt_msg)?;
+            }
+        } else {
+            // If we're currently running but we shouldn't be, flush caches and stop.
+            if *current_status == RiscvCpuState::Halted {
+                info!("POLL: The debugger thinks the CPU is halted, but CPU is now running!  Halting it and flushing the caches.");
+                self.cached_values.lock().unwrap().clear();
+                self.perform_halt(bridge)?;
+            }
+        }
+        Ok(*current_status == RiscvCpuState::Running)
+    }
+
+    /// Pick up a CPU that was left to run on its own. If it has since
+    /// stopped at a breakpoint, save the PC just as `poll()` would have.
+    fn take_over(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let flags = self.read_status(bridge)?;
+        if !is_running(flags)
+            && flags & VexRiscvFlags::HALTED_BY_BREAK == VexRiscvFlags::HALTED_BY_BREAK
+        {
+            let pc = self.break_pc(bridge)?;
+            self.save_halted_reg(&RiscvRegister::pc(), u64::from(pc));
+        }
+        Ok(())
+    }
+
+    /// Carry out the semihosting call that the CPU halted at `pc` to make.
+    /// Calls that can be handled here return straight away, while the rest
+    /// are sent to GDB as File-I/O requests and return once it replies.
+    #[cfg(feature = "gdb")]
+    fn semihost(
+        &self,
+        bridge: &Bridge,
+        pc: u32,
+        gdb_controller: &mut GdbController,
+        current_status: &mut RiscvCpuState,
+    ) -> Result<(), RiscvCpuError> {
+        match self.semihosting_request(bridge)? {
+            Request::Done(result) => {
+                self.return_from_semihosting(u64::from(pc), result);
+                self.perform_resume(bridge, false)?;
+                *current_status = RiscvCpuState::Running;
+            }
+            Request::Gdb(call, reply) => {
+                debug!("POLL: asking GDB to carry out {}", call);
+                self.semihosting.lock().unwrap().wait_for(reply);
+                gdb_controller.gdb_send(format!("F{}", call).as_bytes())?;
+            }
+            Request::Exit(code) => {
+                info!("program exited with code {}", code);
+                gdb_controller.send_stop(&format!("W{:02x}", code))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Halt a CPU that the debugger thinks is running, and describe where it
+    /// stopped. The state is left as `Running` so that the next `poll()`
+    /// notices the halt and reports it to GDB.
+    ///
+    /// The backtrace follows the frame pointer in `s0`, so it's only
+    /// meaningful if the firmware was built with `-fno-omit-frame-pointer`.
+    pub fn halt_and_explain(&self, bridge: &Bridge) -> Result<String, RiscvCpuError> {
+        let _current_status = self.cpu_state.lock().unwrap();
+        self.perform_halt(bridge)?;
+
+        // Halting reads the trap CSRs, which clobbers x1, so prefer the
+        // cached copy of any register.
+        let read_reg = |reg: &RiscvRegister| match self.get_cached_reg(reg) {
+            Some(value) => Ok(value),
+            None => self.read_register_wide(bridge, reg),
+        };
+        let pc = read_reg(&RiscvRegister::pc())?;
+        let ra = read_reg(&RiscvRegister::x1())?;
+        let sp = read_reg(&RiscvRegister::x2())?;
+        let mut fp = read_reg(&RiscvRegister::general(
+            8,
+            "x8",
+            false,
+            RegisterContentsType::DataPtr,
+        ))?;
+
+        let mut report = format!("pc: 0x{:08x}  ra: 0x{:08x}  sp: 0x{:08x}\n", pc, ra, sp);
+        let trap = self.get_current_trap(bridge)?;
+        let description = self.describe_trap(bridge, &trap)?;
+        if self.interrupts_enabled(bridge)? {
+            report.push_str(&format!("Last trap was: {}", description));
+        } else {
+            report.push_str(&format!("Current trap is: {}", description));
+        }
+
+        report.push_str(&format!("Backtrace:\n  #0  0x{:08x}\n", pc));
+        // With a frame pointer, the return address is saved just below the
+        // start of the frame, and the caller's frame pointer below that.
+        let word = self.xlen.bytes() as u32;
+        for depth in 1..=MAX_BACKTRACE_DEPTH {
+            if fp == 0 || fp % u64::from(word) != 0 || fp < sp || fp > u64::from(u32::MAX) {
+                break;
+            }
+            let saved_ra = self.read_xlen_word(bridge, fp as u32 - word)?;
+            let saved_fp = self.read_xlen_word(bridge, fp as u32 - 2 * word)?;
+            if saved_ra == 0 {
+                break;
+            }
+            report.push_str(&format!("  #{:<2} 0x{:08x}\n", depth, saved_ra));
+            // Frames grow down, so the caller's frame must be above this one.
+            if saved_fp <= fp {
+                break;
+            }
+            fp = saved_fp;
+        }
+        Ok(report)
+    }
+
+    /// Read a register-sized word from memory.
+    fn read_xlen_word(&self, bridge: &Bridge, addr: u32) -> Result<u64, RiscvCpuError> {
+        let low = u64::from(self.read_memory(bridge, addr, 4)?);
+        match self.xlen {
+            Xlen::Rv32 => Ok(low),
+            Xlen::Rv64 => Ok(low | u64::from(self.read_memory(bridge, addr + 4, 4)?) << 32),
+        }
+    }
+
+    /// Program the trigger for `watchpoint`, checking that the trigger
+    /// module accepted it. The CPU must be halted.
+    fn arm_watchpoint(
+        &self,
+        bridge: &Bridge,
+        watchpoint: &RiscvWatchpoint,
+    ) -> Result<(), RiscvCpuError> {
+        let (tdata1, tdata2) = watchpoint.trigger_data()?;
+        self.write_register(bridge, &RiscvRegister::tselect(), watchpoint.trigger)?;
+        // Turn the trigger off while its address changes.
+        self.write_register(
+            bridge,
+            &RiscvRegister::tdata1(),
+            (McontrolFlags::TYPE_MCONTROL | McontrolFlags::DMODE).bits,
+        )?;
+        self.write_register(bridge, &RiscvRegister::tdata2(), tdata2)?;
+        self.write_register(bridge, &RiscvRegister::tdata1(), tdata1.bits)?;
+
+        // Triggers ignore fields they don't support, such as loads on a
+        // core that only matches instruction fetches.
+        let written = self.read_register(bridge, &RiscvRegister::tdata1())?;
+        if written & tdata1.bits != tdata1.bits {
+            self.disarm_trigger(bridge, watchpoint.trigger)?;
+            return Err(RiscvCpuError::UnsupportedWatchpoint(
+                watchpoint.address,
+                watchpoint.length,
+            ));
+        }
+        Ok(())
+    }
+
+    fn disarm_trigger(&self, bridge: &Bridge, trigger: u32) -> Result<(), RiscvCpuError> {
+        self.write_register(bridge, &RiscvRegister::tselect(), trigger)?;
+        self.write_register(
+            bridge,
+            &RiscvRegister::tdata1(),
+            (McontrolFlags::TYPE_MCONTROL | McontrolFlags::DMODE).bits,
+        )
+    }
+
+    /// Find the watchpoint whose trigger made the CPU halt, if any, and
+    /// clear its `hit` bit so that it can fire again.
+    fn watchpoint_hit(&self, bridge: &Bridge) -> Result<Option<RiscvWatchpoint>, RiscvCpuError> {
+        for watchpoint in self.watchpoints.lock().unwrap().iter() {
+            self.write_register(bridge, &RiscvRegister::tselect(), watchpoint.trigger)?;
+            let tdata1 = self.read_register(bridge, &RiscvRegister::tdata1())?;
+            if tdata1 & McontrolFlags::HIT.bits != 0 {
+                self.write_register(
+                    bridge,
+                    &RiscvRegister::tdata1(),
+                    tdata1 & !McontrolFlags::HIT.bits,
+                )?;
+                return Ok(Some(watchpoint.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn perform_halt(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        self.write_status(bridge, VexRiscvFlags::HALT_SET)?;
+        self.flush_cache(bridge)?;
+
+        let mut last_exception = self.last_exception.lock().unwrap();
+        let exception = Some(self.get_current_trap(bridge)?);
+        if !self.interrupts_enabled(bridge)? && exception != *last_exception {
+            *last_exception = exception;
+        }
+
+        // VexRiscv can't leave the MMU running when in debug mode.  This is
+        // due to how it manipulates the D$.
+        // IF the MMU is present and enabled when we enter debug mode,
+        // capture the cached value and zero out the register prior to
+        // halting the CPU.
+        // It will be added to the register cache so that it is restored
+        // when the CPU is resumed.
+        if self.has_mmu {
+            let satp = RiscvRegister::satp();
+            let satp_value = self.read_register(bridge, &satp)?;
+            self.mmu_enabled
+                .store(satp_value & 0x8000_0000 == 0x8000_0000, Ordering::Relaxed);
+            if satp_value & 0x8000_0000 == 0x8000_0000 {
+                debug!("cpu has an mmu that is enabled -=  disabling it while in debug mode");
+                self.save_halted_reg(&satp, u64::from(satp_value));
+                self.write_register(bridge, &satp, satp_value & !0x8000_0000)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn perform_resume(&self, bridge: &Bridge, step_only: bool) -> Result<(), RiscvCpuError> {
+        let dirty = self.cached_values.lock().unwrap().take_dirty();
+
+        // Do two passes through the list.
+        // Register 32 (pc), as well as the CSRs all clobber x1/x2, so
+        // update those two values last.
+        let (late, early): (Vec<_>, Vec<_>) = dirty
+            .into_iter()
+            .partition(|(reg, _, _)| reg.gdb_index <= 2);
+        for (reg, value, provenance) in &early {
+            debug!(
+                "restoring {} value of {} to {:08x}",
+                provenance, reg.name, value
+            );
+            self.write_register_wide(bridge, reg, *value)?;
+        }
+
+        // Writing those saves x1 first if it wasn't already saved, in
+        // which case the copy saved just now is the one to put back.
+        let mut late: HashMap<RiscvRegister, (u64, Provenance)> = late
+            .into_iter()
+            .map(|(reg, value, provenance)| (reg, (value, provenance)))
+            .collect();
+        for (reg, value, provenance) in self.cached_values.lock().unwrap().take_dirty() {
+            late.entry(reg).or_insert((value, provenance));
+        }
+        for (reg, (value, provenance)) in late {
+            debug!(
+                "restoring {} value of {} to {:08x}",
+                provenance, reg.name, value
+            );
+            self.write_register_wide(bridge, &reg, value)?;
+        }
+
+        self.flush_cache(bridge)?;
+
+        if step_only {
+            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP)?;
+        } else {
+            self.write_status(bridge, VexRiscvFlags::HALT_CLEAR)?;
+            debug!("RESUME: CPU is now running");
+        }
+        Ok(())
+    }
+
+    pub fn interrupts_enabled(&self, bridge: &Bridge) -> Result<bool, RiscvCpuError> {
+        let mstatus_reg = RiscvRegister::mstatus();
+        let mstatus = self.read_register(bridge, &mstatus_reg)?;
+
+        if mstatus & (1 << 3) != 0 {
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Return the current CPU trap, which could be an interrupt or an
+    /// exception.
+    pub fn get_current_trap(&self, bridge: &Bridge) -> Result<RiscvException, RiscvCpuError> {
+        let mcause_reg = RiscvRegister::mcause();
+        let mepc_reg = RiscvRegister::mepc();
+        let mtval_reg = RiscvRegister::mtval();
+
+        let mcause = self.read_register(bridge, &mcause_reg)?;
+        let mepc = self.read_register(bridge, &mepc_reg)?;
+        let mtval = self.read_register(bridge, &mtval_reg)?;
+
+        Ok(RiscvException::from_regs(mcause, mepc, mtval))
+    }
+
+    fn read_status(&self, bridge: &Bridge) -> Result<VexRiscvFlags, RiscvCpuError> {
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.status(bridge, self.xlen);
+        }
+        match bridge.peek(self.debug_offset) {
+            Err(e) => Err(RiscvCpuError::BridgeError(e)),
+            Ok(bits) => Ok(VexRiscvFlags { bits }),
+        }
+    }
+
+    fn read_memory(&self, bridge: &Bridge, addr: u32, sz: u32) -> Result<u32, RiscvCpuError> {
+        if sz == 4 {
+            return Ok(bridge.peek(addr)?);
+        } else if sz == 2 {
+            return Ok((bridge.peek(addr & !0x3)? >> (8 * (addr & 2))) & 0xffff);
+        } else if sz == 1 {
+            return Ok((bridge.peek(addr & !0x3)? >> (8 * (addr & 3))) & 0xff);
+        }
+
+        // We clobber $x1 in this function, so read its previous value
+        // (if we haven't already).
+        // This will get restored when we do a reset.
+        self.save_clobbered(bridge, &RiscvRegister::x1())?;
+
+        self.write_register_wide(bridge, &RiscvRegister::x1(), u64::from(addr))?;
+        let inst = match sz {
+            // LW x1, 0(x1)
+            4 => (1 << 15) | (0x2 << 12) | (1 << 7) | 0x3,
+
+            // LHU x1, 0(x1)
+            2 => (1 << 15) | (0x5 << 12) | (1 << 7) | 0x3,
+
+            // LBU x1, 0(x1)
+            1 => (1 << 15) | (0x4 << 12) | (1 << 7) | 0x3,
+
+            x => panic!("Unrecognized memory size: {}", x),
+        };
+        self.write_instruction(bridge, inst)?;
+        Ok(self.read_result(bridge)?)
+    }
+
+    fn write_memory(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        sz: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        if sz == 4 {
+            return Ok(bridge.poke(addr, value)?);
+        }
+        // Debug Modules can't run stores, so merge the value into the word
+        // around it instead.
+        if let DebugBackend::DebugModule(_) = self.backend {
+            let shift = 8 * (addr & 3);
+            let mask = match sz {
+                2 => 0xffff << shift,
+                1 => 0xff << shift,
+                x => panic!("Unrecognized memory size: {}", x),
+            };
+            let word = bridge.peek(addr & !0x3)?;
+            return Ok(bridge.poke(addr & !0x3, (word & !mask) | ((value << shift) & mask))?);
+        }
+
+        // We clobber $x1 and $x2 in this function, so read their previous
+        // values (if we haven't already).
+        // This will get restored when we do a reset.
+        for reg in &[RiscvRegister::x1(), RiscvRegister::x2()] {
+            self.save_clobbered(bridge, reg)?;
+        }
+
+        self.write_register(bridge, &RiscvRegister::x1(), value)?;
+        self.write_register_wide(bridge, &RiscvRegister::x2(), u64::from(addr))?;
+        let inst = match sz {
+            // SW x1,0(x2)
+            4 => (1 << 20) | (2 << 15) | (0x2 << 12) | 0x23,
+
+            // SH x1,0(x2)
+            2 => (1 << 20) | (2 << 15) | (0x1 << 12) | 0x23,
+
+            //SB x1,0(x2)
+            #[allow(clippy::identity_op)]
+            1 => (1 << 20) | (2 << 15) | (0x0 << 12) | 0x23,
+
+            x => panic!("Unrecognized memory size: {}", x),
+        };
+        self.write_instruction(bridge, inst)?;
+        Ok(())
+    }
+
+    /// Actually read the value from a register
+    ///
+    /// Execute instructions on the CPU.  If reading a CSR, x1 will get clobbered.
+    /// This clobbered value will be saved in the register cache.
+    fn read_register(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<u32, RiscvCpuError> {
+        if reg.register_type == RiscvRegisterType::Float {
+            return Ok(self.read_float_register(bridge, reg)? as u32);
+        }
+        self.prepare_fpu_csr(bridge, reg)?;
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return Ok(dm.read_register(bridge, reg, self.xlen)? as u32);
+        }
+        match reg.register_type {
+            RiscvRegisterType::General => {
+                if reg.index == 32 {
+                    self.write_instruction(bridge, 0x17) // AUIPC x0,0
+                } else {
+                    self.write_instruction(bridge, (reg.index << 15) | 0x13) // ADDI x0, x?, 0
+                }
+            }
+            RiscvRegisterType::CSR => {
+                // We clobber $x1 in this function, so read its previous value
+                // (if we haven't already).
+                // This will get restored when we resume.
+                self.save_clobbered(bridge, &RiscvRegister::x1())?;
+
+                // Perform a CSRRW which does a Read/Write.  If rs1 is $x0, then the write
+                // is ignored and side-effect free.  Set rd to $x1 to make the read
+                // not side-effect free.
+                #[allow(clippy::identity_op)]
+                self.write_instruction(
+                    bridge,
+                    0
+                    | ((reg.index & 0x1fff) << 20)
+                    | (0 << 15)	    // rs1: x0
+                    | (2 << 12)	    // CSRRW
+                    | (1 << 7)	    // rd: x1
+                    | (0x73 << 0), // SYSTEM
+                )
+            }
+            RiscvRegisterType::Float => unreachable!("read through the FPU"),
+        }?;
+        let result = self.read_result(bridge)?;
+        debug!("Register x{} value: 0x{:08x}", reg.index, result);
+        Ok(result)
+    }
+
+    /// Read a batch of general-purpose registers from a halted CPU.
+    ///
+    /// Normally every injected instruction is followed by polling the status
+    /// register until the pipeline is idle. An `ADDI x0, xN, 0` retires long
+    /// before the next bridge transaction arrives, so here the status is only
+    /// checked once before the batch and once after it. Registers with an
+    /// entry in `cached` aren't read from the CPU, and neither is `x0`.
+    ///
+    /// Returns `None` if the CPU wasn't idle, in which case the values can't
+    /// be trusted and the caller should read the registers individually.
+    fn snapshot_registers(
+        &self,
+        bridge: &Bridge,
+        regs: &[&RiscvRegister],
+        cached: &[Option<u64>],
+    ) -> Result<Option<Vec<u64>>, RiscvCpuError> {
+        if let DebugBackend::DebugModule(_) = self.backend {
+            return Ok(None);
+        }
+        let pipeline_idle = |flags: VexRiscvFlags| {
+            (flags & VexRiscvFlags::PIP_BUSY) != VexRiscvFlags::PIP_BUSY
+                && (flags & VexRiscvFlags::HALT) == VexRiscvFlags::HALT
+        };
+        if !pipeline_idle(self.read_status(bridge)?) {
+            return Ok(None);
+        }
+
+        let mut values = Vec::with_capacity(regs.len());
+        for (reg, cached) in regs.iter().zip(cached) {
+            if let Some(val) = cached {
+                values.push(*val);
+                continue;
+            }
+            if reg.register_type != RiscvRegisterType::General {
+                return Ok(None);
+            }
+            let opcode = match reg.index {
+                0 => {
+                    values.push(0);
+                    continue;
+                }
+                32 => 0x17,                    // AUIPC x0,0
+                index => (index << 15) | 0x13, // ADDI x0, x?, 0
+            };
+            bridge.poke(self.debug_offset + 4, opcode)?;
+            values.push(u64::from(self.read_result(bridge)?));
+        }
+
+        if !pipeline_idle(self.read_status(bridge)?) {
+            return Ok(None);
+        }
+        Ok(Some(values))
+    }
+
+    /// Write a value to a specified register
+    ///
+    /// Poke instructions into the CPU to update a specified register.  This might
+    /// clobber register 1, and for CSRs might clobber register 2.  Clobbered values
+    /// will be saved to the register cache.
+    fn write_register(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        debug!("Setting register {:?} -> {:08x}", reg, value);
+        if reg.register_type == RiscvRegisterType::Float {
+            return self.write_float_register(bridge, reg, u64::from(value));
+        }
+        self.prepare_fpu_csr(bridge, reg)?;
+        self.cached_values.lock().unwrap().forget_read(reg);
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.write_register(bridge, reg, u64::from(value), self.xlen);
+        }
+        match reg.register_type {
+            RiscvRegisterType::General => {
+                // Handle PC separately
+                if reg.index == 32 {
+                    self.write_register(bridge, &RiscvRegister::x1(), value)?;
+                    self.write_x1_into(bridge, reg)
+                // Use LUI instruction if necessary
+                } else if (value & 0xffff_f800) != 0 {
+                    let low = value & 0x0000_0fff;
+                    let high = if (low & 0x800) != 0 {
+                        (value & 0xffff_f000).wrapping_add(0x1000)
+                    } else {
+                        value & 0xffff_f000
+                    };
+
+                    // LUI regId, high
+                    self.write_instruction(bridge, (reg.index << 7) | high | 0x37)?;
+
+                    // Also issue ADDI
+                    if low != 0 {
+                        // ADDI regId, regId, low
+                        self.write_instruction(
+                            bridge,
+                            (reg.index << 7) | (reg.index << 15) | (low << 20) | 0x13,
+                        )?;
+                    }
+                    Ok(())
+                } else {
+                    // ORI regId, x0, value
+                    self.write_instruction(
+                        bridge,
+                        (reg.index << 7) | (6 << 12) | (value << 20) | 0x13,
+                    )
+                }
+            }
+            RiscvRegisterType::CSR => {
+                // We clobber $x1 in this function, so read its previous value
+                // (if we haven't already).
+                // This will get restored when we do a reset.
+                self.save_clobbered(bridge, &RiscvRegister::x1())?;
+
+                self.write_register(bridge, &RiscvRegister::x1(), value)?;
+                self.write_x1_into(bridge, reg)
+            }
+            RiscvRegisterType::Float => unreachable!("written through the FPU"),
+        }
+    }
+
+    /// Copy x1 into the PC or a CSR.
+    fn write_x1_into(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<(), RiscvCpuError> {
+        match reg.register_type {
+            // JALR x1
+            RiscvRegisterType::General => self.write_instruction(bridge, 0x67 | (1 << 15)),
+            // Perform a CSRRW which does a Read/Write.  If rd is $x0, then the read
+            // is ignored and side-effect free.  Set rs1 to $x1 to make the write
+            // not side-effect free.
+            //
+            // cccc cccc cccc ssss s fff ddddd ooooooo
+            // c: CSR number
+            // s: rs1 (source register)
+            // f: Function
+            // d: rd (destination register)
+            // o: opcode - 0x73
+            #[allow(clippy::identity_op)]
+            RiscvRegisterType::CSR => self.write_instruction(
+                bridge,
+                0
+                | ((reg.index & 0x1fff) << 20)
+                | (1 << 15)	    // rs1: x1
+                | (1 << 12)	    // CSRRW
+                | (0 << 7)	    // rd: x0
+                | (0x73 << 0), // SYSTEM
+            ),
+            RiscvRegisterType::Float => unreachable!("written through the FPU"),
+        }
+    }
+
+    /// Read a whole register, however wide it is.
+    ///
+    /// The debug bridge only returns 32 bits at a time, so on RV64 the top
+    /// half is shifted down with `SRLI` and read separately. A general
+    /// register is shifted in place and then written back, while the PC
+    /// and CSRs go through x1, which is saved in the register cache.
+    fn read_register_wide(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+    ) -> Result<u64, RiscvCpuError> {
+        if reg.register_type == RiscvRegisterType::Float {
+            return self.read_float_register(bridge, reg);
+        }
+        self.prepare_fpu_csr(bridge, reg)?;
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.read_register(bridge, reg, self.xlen);
+        }
+        let low = u64::from(self.read_register(bridge, reg)?);
+        let general = reg.register_type == RiscvRegisterType::General;
+        if self.xlen == Xlen::Rv32 || (general && reg.index == 0) {
+            return Ok(low);
+        }
+
+        let shifted = if general && reg.index != 32 {
+            reg.index
+        } else {
+            self.save_clobbered(bridge, &RiscvRegister::x1())?;
+            if general {
+                // AUIPC x1, 0
+                self.write_instruction(bridge, (1 << 7) | 0x17)?;
+            }
+            // Reading a CSR already left its value in x1.
+            1
+        };
+        // SRLI xN, xN, 32
+        self.write_instruction(
+            bridge,
+            (32 << 20) | (shifted << 15) | (5 << 12) | (shifted << 7) | 0x13,
+        )?;
+        // ADDI x0, xN, 0
+        self.write_instruction(bridge, (shifted << 15) | 0x13)?;
+        let value = u64::from(self.read_result(bridge)?) << 32 | low;
+        if general && shifted == reg.index {
+            self.write_register_wide(bridge, reg, value)?;
+        }
+        debug!("Register {} value: 0x{:016x}", reg.name, value);
+        Ok(value)
+    }
+
+    /// Write a whole register, however wide it is.
+    ///
+    /// On RV64, `LUI` sign-extends, so the 32-bit sequence can't set the
+    /// top half on its own. Instead the top half is loaded first and the
+    /// bottom half is shifted in with `SLLI` and `ORI`, at most 11 bits at
+    /// a time to stay clear of `ORI`'s sign bit.
+    fn write_register_wide(
+        &self,
+        bridge: &Bridge,
+        reg: &RiscvRegister,
+        value: u64,
+    ) -> Result<(), RiscvCpuError> {
+        if reg.register_type == RiscvRegisterType::Float {
+            return self.write_float_register(bridge, reg, value);
+        }
+        self.prepare_fpu_csr(bridge, reg)?;
+        self.cached_values.lock().unwrap().forget_read(reg);
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.write_register(bridge, reg, value, self.xlen);
+        }
+        if self.xlen == Xlen::Rv32 {
+            return self.write_register(bridge, reg, value as u32);
+        }
+        let general = reg.register_type == RiscvRegisterType::General;
+        if general && reg.index == 0 {
+            return Ok(());
+        }
+        if !general {
+            self.save_clobbered(bridge, &RiscvRegister::x1())?;
+        }
+        // The PC and CSRs are set from x1.
+        let target = if general && reg.index != 32 {
+            reg.clone()
+        } else {
+            RiscvRegister::x1()
+        };
+        let rd = target.index;
+
+        self.write_register(bridge, &target, (value >> 32) as u32)?;
+        for (shift, from) in &[(11, 21), (11, 10), (10, 0)] {
+            // SLLI rd, rd, shift
+            self.write_instruction(
+                bridge,
+                (shift << 20) | (rd << 15) | (1 << 12) | (rd << 7) | 0x13,
+            )?;
+            let chunk = ((value >> from) & ((1 << shift) - 1)) as u32;
+            // ORI rd, rd, chunk
+            self.write_instruction(
+                bridge,
+                (chunk << 20) | (rd << 15) | (6 << 12) | (rd << 7) | 0x13,
+            )?;
+        }
+        if target != *reg {
+            self.write_x1_into(bridge, reg)?;
+        }
+        Ok(())
+    }
+
+    fn flush_cache(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.flush_cache(bridge, self.xlen);
+        }
+        for opcode in &[4111, 19, 19, 19] {
+            self.write_instruction(bridge, *opcode)?;
+        }
+        Ok(())
+    }
+
+    fn get_cached_reg(&self, reg: &RiscvRegister) -> Option<u64> {
+        self.cached_values.lock().unwrap().get(reg)
+    }
+
+    /// Save something the CPU stops reporting once it's halted, to be put
+    /// back when it resumes.
+    fn save_halted_reg(&self, reg: &RiscvRegister, value: u64) {
+        self.cached_values.lock().unwrap().save_halted(reg, value);
+    }
+
+    /// Save `reg` before the debugger overwrites it for its own use, unless
+    /// it's been saved already. It's put back when the CPU resumes.
+    fn save_clobbered(&self, bridge: &Bridge, reg: &RiscvRegister) -> Result<(), RiscvCpuError> {
+        if self.cached_values.lock().unwrap().clobber(reg) {
+            return Ok(());
+        }
+        let value = self.read_register_wide(bridge, reg)?;
+        self.cached_values
+            .lock()
+            .unwrap()
+            .save_clobbered(reg, value);
+        Ok(())
+    }
+
+    fn write_instruction(&self, bridge: &Bridge, opcode: u32) -> Result<(), RiscvCpuError> {
+        // debug!(
+        //     "WRITE INSTRUCTION: 0x{:08x} -- 0x{:08x}",
+        //     opcode,
+        //     swab(opcode)
+        // );
+        bridge.poke(self.debug_offset + 4, opcode)?;
+        for _ in 0..100 {
+            if (self.read_status(bridge)? & VexRiscvFlags::PIP_BUSY) != VexRiscvFlags::PIP_BUSY {
+                return Ok(());
+            }
+        }
+        Err(RiscvCpuError::InstructionTimeout)
+    }
+
+    fn read_result(&self, bridge: &Bridge) -> Result<u32, RiscvCpuError> {
+        Ok(bridge.peek(self.debug_offset + 4)?)
+    }
+
+    /// Count the VexRiscv debug unit's breakpoint slots by writing a
+    /// disabled breakpoint to each one in turn until it doesn't read back.
+    /// Debug units that can't read their breakpoints back at all are
+    /// assumed to have the usual number.
+    fn probe_breakpoints(&self, bridge: &Bridge) -> Result<usize, RiscvCpuError> {
+        let mut count = 0;
+        while count < MAX_BREAKPOINTS {
+            let slot = self.debug_offset + 0x40 + (count as u32 * 4);
+            bridge.poke(slot, BREAKPOINT_PROBE)?;
+            let readback = bridge.peek(slot)?;
+            bridge.poke(slot, 0)?;
+            if readback != BREAKPOINT_PROBE {
+                break;
+            }
+            count += 1;
+        }
+        if count == 0 {
+            debug!(
+                "couldn't count breakpoints, assuming there are {}",
+                DEFAULT_BREAKPOINTS
+            );
+            return Ok(DEFAULT_BREAKPOINTS);
+        }
+        info!("cpu has {} hardware breakpoints", count);
+        Ok(count)
+    }
+
+    /// Wait for the CPU to halt after being told to step. A step that
+    /// lands on a breakpoint halts with the PC already moved past it, by
+    /// two or four bytes depending on the instruction, so the PC it stopped
+    /// at is saved just as `poll()` would.
+    fn wait_for_step(&self, bridge: &Bridge) -> Result<(), RiscvCpuError> {
+        let mut polls = 0;
+        let flags = loop {
+            let flags = self.read_status(bridge)?;
+            if !is_running(flags) {
+                break flags;
+            }
+            polls += 1;
+            if polls > MAX_STEP_POLLS {
+                return Err(RiscvCpuError::InstructionTimeout);
+            }
+        };
+        if flags & VexRiscvFlags::HALTED_BY_BREAK == VexRiscvFlags::HALTED_BY_BREAK {
+            let pc = self.break_pc(bridge)?;
+            self.save_halted_reg(&RiscvRegister::pc(), u64::from(pc));
+        }
+        Ok(())
+    }
+
+    /// Where the CPU halted when it reached a breakpoint. The VexRiscv
+    /// debug bridge leaves this in the result register.
+    fn break_pc(&self, bridge: &Bridge) -> Result<u32, RiscvCpuError> {
+        match &self.backend {
+            DebugBackend::VexRiscv => self.read_result(bridge),
+            DebugBackend::DebugModule(dm) => Ok(dm.pc(bridge, self.xlen)? as u32),
+        }
+    }
+
+    /// Set hardware breakpoint `slot` to `addr`, or turn it off. A Debug
+    /// Module uses the trigger of the same number, which needs the CPU to
+    /// be halted.
+    fn set_breakpoint(
+        &self,
+        bridge: &Bridge,
+        slot: usize,
+        addr: Option<u32>,
+    ) -> Result<(), RiscvCpuError> {
+        if let DebugBackend::VexRiscv = self.backend {
+            let value = match addr {
+                Some(addr) => addr | 1,
+                None => 0,
+            };
+            return Ok(bridge.poke(self.debug_offset + 0x40 + (slot as u32 * 4), value)?);
+        }
+
+        let fields = match addr {
+            Some(_) => {
+                McontrolFlags::EXECUTE
+                    | McontrolFlags::U
+                    | McontrolFlags::S
+                    | McontrolFlags::M
+                    | McontrolFlags::ACTION_HALT
+            }
+            None => McontrolFlags::empty(),
+        };
+        // `type` and `dmode` are always the top bits of `tdata1`.
+        let tdata1 = match self.xlen {
+            Xlen::Rv32 => {
+                u64::from((fields | McontrolFlags::TYPE_MCONTROL | McontrolFlags::DMODE).bits)
+            }
+            Xlen::Rv64 => {
+                u64::from(fields.bits)
+                    | u64::from((McontrolFlags::TYPE_MCONTROL | McontrolFlags::DMODE).bits) << 32
+            }
+        };
+        self.write_register(bridge, &RiscvRegister::tselect(), slot as u32)?;
+        if let Some(addr) = addr {
+            self.write_register_wide(bridge, &RiscvRegister::tdata2(), u64::from(addr))?;
+        }
+        self.write_register_wide(bridge, &RiscvRegister::tdata1(), tdata1)
+    }
+
+    fn write_status(&self, bridge: &Bridge, value: VexRiscvFlags) -> Result<(), RiscvCpuError> {
+        if let DebugBackend::DebugModule(dm) = &self.backend {
+            return dm.write_status(bridge, value, self.xlen);
+        }
+        debug!("SETTING BRIDGE STATUS: {:08x}", value.bits);
+        bridge.poke(self.debug_offset, value.bits)?;
+        Ok(())
+    }
+}
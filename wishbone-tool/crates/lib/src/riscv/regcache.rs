@@ -0,0 +1,256 @@
+//! Register values kept on the host while the CPU is halted.
+//!
+//! Each value remembers where it came from. Values that were only read are
+//! clean, and are simply forgotten when the CPU resumes. Everything else is
+//! dirty and has to be written back first: registers the debugger saved
+//! before using them itself, state that the CPU stopped reporting when it
+//! halted, and values written by GDB. A value that GDB wrote is never
+//! replaced by one saved for any other reason, so it can't be lost.
+
+use std::collections::HashMap;
+
+use super::RiscvRegister;
+
+/// Where a cached value came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Provenance {
+    /// Read from the CPU, which still holds the same value
+    Read,
+
+    /// Saved before the debugger overwrote the register for its own use
+    Clobbered,
+
+    /// Saved when the CPU halted, as it no longer reports this itself,
+    /// such as the PC of a breakpoint
+    Halted,
+
+    /// Written by GDB, or on its behalf, such as a semihosting result
+    Written,
+}
+
+impl std::fmt::Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Provenance::Read => write!(f, "read"),
+            Provenance::Clobbered => write!(f, "clobbered"),
+            Provenance::Halted => write!(f, "halted"),
+            Provenance::Written => write!(f, "written"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CachedRegister {
+    value: u64,
+    provenance: Provenance,
+}
+
+impl CachedRegister {
+    /// Whether this has to be written back before the CPU runs
+    fn dirty(&self) -> bool {
+        self.provenance != Provenance::Read
+    }
+}
+
+#[derive(Default)]
+pub(super) struct RegisterCache {
+    registers: HashMap<RiscvRegister, CachedRegister>,
+}
+
+impl RegisterCache {
+    pub(super) fn get(&self, reg: &RiscvRegister) -> Option<u64> {
+        self.registers.get(reg).map(|cached| cached.value)
+    }
+
+    /// Remember a value read from the CPU, unless something else is
+    /// already cached.
+    pub(super) fn note_read(&mut self, reg: &RiscvRegister, value: u64) {
+        self.registers.entry(reg.clone()).or_insert(CachedRegister {
+            value,
+            provenance: Provenance::Read,
+        });
+    }
+
+    /// Forget a clean value, because the CPU's copy has been changed
+    /// directly.
+    pub(super) fn forget_read(&mut self, reg: &RiscvRegister) {
+        if let Some(cached) = self.registers.get(reg) {
+            if !cached.dirty() {
+                self.registers.remove(reg);
+            }
+        }
+    }
+
+    /// Mark a register as about to be clobbered, returning `true` if its
+    /// value is already cached and so doesn't need to be saved.
+    pub(super) fn clobber(&mut self, reg: &RiscvRegister) -> bool {
+        match self.registers.get_mut(reg) {
+            Some(cached) => {
+                if cached.provenance == Provenance::Read {
+                    cached.provenance = Provenance::Clobbered;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Save a register before it's clobbered. If anything has been cached
+    /// for it since it was read, that's kept instead.
+    pub(super) fn save_clobbered(&mut self, reg: &RiscvRegister, value: u64) {
+        self.registers.entry(reg.clone()).or_insert(CachedRegister {
+            value,
+            provenance: Provenance::Clobbered,
+        });
+    }
+
+    /// Save something the CPU stopped reporting when it halted. Only a
+    /// value GDB wrote takes precedence.
+    pub(super) fn save_halted(&mut self, reg: &RiscvRegister, value: u64) {
+        match self.registers.get(reg) {
+            Some(cached) if cached.provenance == Provenance::Written => (),
+            _ => {
+                self.registers.insert(
+                    reg.clone(),
+                    CachedRegister {
+                        value,
+                        provenance: Provenance::Halted,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Set a register on GDB's behalf, to be written back on resume.
+    pub(super) fn write(&mut self, reg: &RiscvRegister, value: u64) {
+        self.registers.insert(
+            reg.clone(),
+            CachedRegister {
+                value,
+                provenance: Provenance::Written,
+            },
+        );
+    }
+
+    /// Empty the cache, returning the dirty values to write back along
+    /// with where they came from. Everything is taken at once, so if two
+    /// callers race to resume the CPU, only one of them writes each value.
+    pub(super) fn take_dirty(&mut self) -> Vec<(RiscvRegister, u64, Provenance)> {
+        self.registers
+            .drain()
+            .filter(|(_, cached)| cached.dirty())
+            .map(|(reg, cached)| (reg, cached.value, cached.provenance))
+            .collect()
+    }
+
+    /// Forget everything, as the CPU's registers are no longer what was
+    /// cached, such as after a reset.
+    pub(super) fn clear(&mut self) {
+        self.registers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// What a resume would write back, by register name
+    fn resume(cache: &mut RegisterCache) -> Vec<(String, u64, Provenance)> {
+        let mut dirty: Vec<_> = cache
+            .take_dirty()
+            .into_iter()
+            .map(|(reg, value, provenance)| (reg.name, value, provenance))
+            .collect();
+        dirty.sort_by(|a, b| a.0.cmp(&b.0));
+        dirty
+    }
+
+    /// Do what `save_clobbered()` does when the CPU holds `value`
+    fn clobber(cache: &mut RegisterCache, reg: &RiscvRegister, value: u64) {
+        if !cache.clobber(reg) {
+            cache.save_clobbered(reg, value);
+        }
+    }
+
+    #[test]
+    fn write_survives_clobber_and_restore() {
+        let mut cache = RegisterCache::default();
+        let x1 = RiscvRegister::x1();
+
+        // The debugger borrows x1, GDB writes it, then it's borrowed again
+        clobber(&mut cache, &x1, 0x1111);
+        cache.write(&x1, 0x2222);
+        clobber(&mut cache, &x1, 0xdead);
+        cache.save_halted(&x1, 0xbeef);
+
+        assert_eq!(cache.get(&x1), Some(0x2222));
+        assert_eq!(
+            resume(&mut cache),
+            vec![("x1".to_owned(), 0x2222, Provenance::Written)]
+        );
+    }
+
+    #[test]
+    fn only_dirty_registers_are_written_back() {
+        let mut cache = RegisterCache::default();
+        cache.note_read(&RiscvRegister::x2(), 0x10);
+        cache.note_read(&RiscvRegister::mstatus(), 0x1800);
+        cache.note_read(&RiscvRegister::mepc(), 0x20);
+        clobber(&mut cache, &RiscvRegister::mepc(), 0xdead);
+        clobber(&mut cache, &RiscvRegister::x1(), 0x30);
+        cache.save_halted(&RiscvRegister::pc(), 0x4000_0000);
+
+        assert_eq!(
+            resume(&mut cache),
+            vec![
+                ("mepc".to_owned(), 0x20, Provenance::Clobbered),
+                ("pc".to_owned(), 0x4000_0000, Provenance::Halted),
+                ("x1".to_owned(), 0x30, Provenance::Clobbered),
+            ]
+        );
+        assert_eq!(cache.get(&RiscvRegister::x2()), None);
+    }
+
+    #[test]
+    fn step_then_resume_writes_back_once() {
+        let mut cache = RegisterCache::default();
+        cache.write(&RiscvRegister::x2(), 0x1234);
+        cache.save_halted(&RiscvRegister::pc(), 0x100);
+
+        // Stepping writes everything back, then the CPU halts again
+        assert_eq!(resume(&mut cache).len(), 2);
+        cache.save_halted(&RiscvRegister::pc(), 0x104);
+        cache.note_read(&RiscvRegister::x2(), 0x1234);
+
+        assert_eq!(
+            resume(&mut cache),
+            vec![("pc".to_owned(), 0x104, Provenance::Halted)]
+        );
+    }
+
+    #[test]
+    fn write_between_resumes_is_not_dropped() {
+        let cache = Arc::new(Mutex::new(RegisterCache::default()));
+        cache.lock().unwrap().write(&RiscvRegister::x1(), 0x1111);
+
+        // Two resumes race, and GDB writes x2 after the first has taken
+        // what it's going to write
+        let first = cache.lock().unwrap().take_dirty();
+        cache.lock().unwrap().write(&RiscvRegister::x2(), 0x2222);
+        let other = Arc::clone(&cache);
+        let second = thread::spawn(move || other.lock().unwrap().take_dirty())
+            .join()
+            .unwrap();
+
+        let names = |dirty: &[(RiscvRegister, u64, Provenance)]| -> Vec<String> {
+            dirty.iter().map(|(reg, _, _)| reg.name.clone()).collect()
+        };
+        assert_eq!(names(&first), vec!["x1"]);
+        assert_eq!(names(&second), vec!["x2"]);
+        assert_eq!(second[0].1, 0x2222);
+        assert!(cache.lock().unwrap().take_dirty().is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+//! RISC-V semihosting, carried out on the host by GDB's File-I/O protocol.
+//!
+//! Firmware makes a call by placing the operation in `a0` and its argument
+//! (usually a pointer to a block of register-sized words) in `a1`, then
+//! running the `slli x0, x0, 0x1f; ebreak; srai x0, x0, 7` sequence. The
+//! `ebreak` halts the CPU, and rather than reporting a stop, the call is
+//! turned into an `F` request for GDB. Once GDB replies, the result goes in
+//! `a0` and the CPU carries on from the instruction after the `ebreak`.
+
+// Calls are only carried out by `poll()`, which is part of the GDB server.
+#![cfg_attr(not(feature = "gdb"), allow(dead_code))]
+
+use super::{RegisterContentsType, RiscvCpuController, RiscvCpuError, RiscvRegister};
+use crate::hostio;
+use wishbone_bridge::Bridge;
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// `slli x0, x0, 0x1f`, just before the `ebreak`
+const ENTRY_NOP: u32 = 0x01f0_1013;
+
+/// `ebreak`
+const EBREAK: u32 = 0x0010_0073;
+
+/// `srai x0, x0, 7`, just after the `ebreak`
+const EXIT_NOP: u32 = 0x4070_5013;
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_ISERROR: u64 = 0x08;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_REMOVE: u64 = 0x0e;
+const SYS_RENAME: u64 = 0x0f;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_SYSTEM: u64 = 0x12;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+/// The reason `SYS_EXIT` gives when the program finished normally
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x2_0026;
+
+/// `SYS_OPEN` names the console this way rather than with a path.
+const CONSOLE_NAME: &[u8] = b":tt";
+
+/// Longest string `SYS_WRITE0` will print
+const MAX_WRITE0_LEN: u32 = 4096;
+
+/// GDB's File-I/O open flags
+const FILEIO_O_WRONLY: u32 = 0x1;
+const FILEIO_O_RDWR: u32 = 0x2;
+const FILEIO_O_APPEND: u32 = 0x8;
+const FILEIO_O_CREAT: u32 = 0x200;
+const FILEIO_O_TRUNC: u32 = 0x400;
+
+/// Files that get created are readable by everyone, but only writable by
+/// the user.
+const FILEIO_CREATE_MODE: u32 = 0o644;
+
+/// How a semihosting call is carried out
+pub(super) enum Request {
+    /// GDB does the work, as described by this `F` request
+    Gdb(String, Reply),
+
+    /// Nothing more to do, and `a0` gets this value
+    Done(i64),
+
+    /// The program has finished, with this exit code
+    Exit(u8),
+}
+
+/// How the result of a GDB File-I/O call turns into the value the
+/// semihosting call returns
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Reply {
+    /// The result is returned as it is
+    Result,
+
+    /// The number of these bytes that weren't transferred is returned
+    Remaining(u64),
+
+    /// Zero is returned on success
+    Zero,
+}
+
+impl Reply {
+    fn result(self, retcode: i64) -> i64 {
+        if retcode < 0 {
+            return -1;
+        }
+        match self {
+            Reply::Result => retcode,
+            Reply::Remaining(len) => len as i64 - retcode,
+            Reply::Zero => 0,
+        }
+    }
+}
+
+/// Semihosting state that lasts between calls
+#[derive(Debug)]
+pub(super) struct Semihosting {
+    /// The call waiting on GDB's reply, if any
+    pending: Option<Reply>,
+
+    /// The error from the last call that failed, for `SYS_ERRNO`
+    errno: u32,
+
+    /// When debugging started, for `SYS_CLOCK`
+    started: Instant,
+}
+
+impl Default for Semihosting {
+    fn default() -> Self {
+        Semihosting {
+            pending: None,
+            errno: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Semihosting {
+    /// Note that GDB is now carrying out a call.
+    pub(super) fn wait_for(&mut self, reply: Reply) {
+        self.pending = Some(reply);
+    }
+
+    /// Work out what the call GDB just finished returns, given the result
+    /// and errno from its `F` reply. Returns `None` if no call was waiting.
+    pub(super) fn finish(&mut self, retcode: i64, errno: u32) -> Option<i64> {
+        let reply = self.pending.take()?;
+        if retcode < 0 {
+            self.errno = errno;
+        }
+        Some(reply.result(retcode))
+    }
+}
+
+/// Turn a semihosting `fopen()` mode, from 0 (`"r"`) to 11 (`"a+b"`), into
+/// GDB's File-I/O open flags.
+fn open_flags(mode: u64) -> u32 {
+    match (mode >> 2, mode & 2 != 0) {
+        (0, false) => 0,
+        (0, true) => FILEIO_O_RDWR,
+        (1, false) => FILEIO_O_WRONLY | FILEIO_O_CREAT | FILEIO_O_TRUNC,
+        (1, true) => FILEIO_O_RDWR | FILEIO_O_CREAT | FILEIO_O_TRUNC,
+        (_, false) => FILEIO_O_WRONLY | FILEIO_O_CREAT | FILEIO_O_APPEND,
+        (_, true) => FILEIO_O_RDWR | FILEIO_O_CREAT | FILEIO_O_APPEND,
+    }
+}
+
+impl RiscvCpuController {
+    /// Whether the CPU halted on the `ebreak` of a semihosting call. The
+    /// sequence is always made of full-sized, aligned instructions.
+    pub(super) fn is_semihosting_call(
+        &self,
+        bridge: &Bridge,
+        pc: u32,
+    ) -> Result<bool, RiscvCpuError> {
+        if pc & 3 != 0 || !(4..=0xffff_fff8).contains(&pc) {
+            return Ok(false);
+        }
+        Ok(self.read_memory(bridge, pc, 4)? == EBREAK
+            && self.read_memory(bridge, pc - 4, 4)? == ENTRY_NOP
+            && self.read_memory(bridge, pc + 4, 4)? == EXIT_NOP)
+    }
+
+    /// Read the operation and its argument from a halted CPU, and work out
+    /// how to carry it out.
+    pub(super) fn semihosting_request(&self, bridge: &Bridge) -> Result<Request, RiscvCpuError> {
+        let operation = self.read_register_wide(bridge, &a0())?;
+        let arg = self.read_register_wide(bridge, &a1())?;
+        let word = self.xlen.bytes() as u32;
+        let param = |index: u32| self.read_xlen_word(bridge, arg as u32 + index * word);
+
+        let request = match operation {
+            SYS_OPEN => {
+                let (name, mode, len) = (param(0)?, param(1)?, param(2)?);
+                if len == CONSOLE_NAME.len() as u64 && self.is_console(bridge, name as u32)? {
+                    // Reading goes to stdin, writing to stdout, and
+                    // appending to stderr.
+                    Request::Done(match mode >> 2 {
+                        0 => 0,
+                        1 => 1,
+                        _ => 2,
+                    })
+                } else {
+                    Request::Gdb(
+                        format!(
+                            "open,{:x}/{:x},{:x},{:x}",
+                            name,
+                            len + 1,
+                            open_flags(mode),
+                            FILEIO_CREATE_MODE
+                        ),
+                        Reply::Result,
+                    )
+                }
+            }
+            // GDB's console can't be closed.
+            SYS_CLOSE => match param(0)? {
+                fd if fd <= 2 => Request::Done(0),
+                fd => Request::Gdb(format!("close,{:x}", fd), Reply::Result),
+            },
+            SYS_WRITEC => Request::Gdb(format!("write,1,{:x},1", arg), Reply::Result),
+            SYS_WRITE0 => {
+                let len = self.string_length(bridge, arg as u32)?;
+                Request::Gdb(format!("write,1,{:x},{:x}", arg, len), Reply::Result)
+            }
+            SYS_WRITE | SYS_READ => {
+                let (fd, buffer, len) = (param(0)?, param(1)?, param(2)?);
+                let call = if operation == SYS_WRITE {
+                    "write"
+                } else {
+                    "read"
+                };
+                Request::Gdb(
+                    format!("{},{:x},{:x},{:x}", call, fd, buffer, len),
+                    Reply::Remaining(len),
+                )
+            }
+            SYS_ISERROR => Request::Done(((param(0)? >> (self.xlen.bits() - 1)) & 1) as i64),
+            SYS_ISTTY => Request::Gdb(format!("isatty,{:x}", param(0)?), Reply::Result),
+            SYS_SEEK => Request::Gdb(
+                format!("lseek,{:x},{:x},0", param(0)?, param(1)?),
+                Reply::Zero,
+            ),
+            SYS_REMOVE => Request::Gdb(
+                format!("unlink,{:x}/{:x}", param(0)?, param(1)? + 1),
+                Reply::Zero,
+            ),
+            SYS_RENAME => Request::Gdb(
+                format!(
+                    "rename,{:x}/{:x},{:x}/{:x}",
+                    param(0)?,
+                    param(1)? + 1,
+                    param(2)?,
+                    param(3)? + 1
+                ),
+                Reply::Zero,
+            ),
+            SYS_SYSTEM => Request::Gdb(
+                format!("system,{:x}/{:x}", param(0)?, param(1)? + 1),
+                Reply::Result,
+            ),
+            SYS_CLOCK => {
+                let started = self.semihosting.lock().unwrap().started;
+                Request::Done((started.elapsed().as_millis() / 10) as i64)
+            }
+            SYS_TIME => Request::Done(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|t| t.as_secs() as i64)
+                    .unwrap_or(0),
+            ),
+            SYS_ERRNO => Request::Done(i64::from(self.semihosting.lock().unwrap().errno)),
+            // On RV32 the reason is passed directly, rather than in a block.
+            SYS_EXIT if word == 4 => Request::Exit(exit_code(arg, 0)),
+            SYS_EXIT | SYS_EXIT_EXTENDED => Request::Exit(exit_code(param(0)?, param(1)?)),
+            other => {
+                debug!("unsupported semihosting call 0x{:x}", other);
+                self.semihosting.lock().unwrap().errno = hostio::EINVAL;
+                Request::Done(-1)
+            }
+        };
+        Ok(request)
+    }
+
+    /// Have a halted CPU return `result` from its semihosting call, and
+    /// carry on from the instruction after the `ebreak` at `pc`, once it
+    /// resumes.
+    pub(super) fn return_from_semihosting(&self, pc: u64, result: i64) {
+        let mut cache = self.cached_values.lock().unwrap();
+        cache.write(&a0(), result as u64);
+        cache.write(&RiscvRegister::pc(), pc + 4);
+    }
+
+    fn is_console(&self, bridge: &Bridge, name: u32) -> Result<bool, RiscvCpuError> {
+        for (offset, expected) in CONSOLE_NAME.iter().enumerate() {
+            if self.read_memory(bridge, name + offset as u32, 1)? != u32::from(*expected) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The length of the NUL-terminated string at `addr`, up to
+    /// `MAX_WRITE0_LEN`.
+    fn string_length(&self, bridge: &Bridge, addr: u32) -> Result<u32, RiscvCpuError> {
+        let mut len = 0;
+        while len < MAX_WRITE0_LEN {
+            let word_addr = (addr + len) & !3;
+            let word = self.read_memory(bridge, word_addr, 4)?.to_le_bytes();
+            for byte in &word[((addr + len) - word_addr) as usize..] {
+                if *byte == 0 {
+                    return Ok(len);
+                }
+                len += 1;
+            }
+        }
+        Ok(MAX_WRITE0_LEN)
+    }
+}
+
+/// The exit code for `SYS_EXIT`'s `reason` and `subcode`. Only a normal
+/// exit carries a code, so anything else counts as a failure.
+fn exit_code(reason: u64, subcode: u64) -> u8 {
+    if reason == ADP_STOPPED_APPLICATION_EXIT {
+        subcode as u8
+    } else {
+        1
+    }
+}
+
+fn a0() -> RiscvRegister {
+    RiscvRegister::general(10, "x10", false, RegisterContentsType::Int)
+}
+
+fn a1() -> RiscvRegister {
+    RiscvRegister::general(11, "x11", false, RegisterContentsType::Int)
+}
@@ -0,0 +1,360 @@
+//! Tracepoints, which record the CPU's registers and some memory each time
+//! the program passes an address, and let it carry on without stopping in
+//! GDB.
+//!
+//! Each tracepoint takes one of the CPU's hardware breakpoints while a trace
+//! run is going. When the CPU halts on one, `poll()` records a frame into a
+//! buffer kept here on the host, steps over the breakpoint, and lets the CPU
+//! run again. GDB looks through the frames afterwards with `tfind`.
+
+// Frames are only collected by `poll()`, which is part of the GDB server.
+#![cfg_attr(not(feature = "gdb"), allow(dead_code))]
+
+use super::{RegisterContentsType, RiscvCpuController, RiscvCpuError, RiscvRegister};
+use wishbone_bridge::Bridge;
+
+use tracing::debug;
+
+/// Most frames kept before the trace run stops with the buffer full
+const MAX_TRACE_FRAMES: usize = 4096;
+
+/// GDB's register number for the PC
+const PC_INDEX: u32 = 32;
+
+/// The base register GDB gives for a memory range at a fixed address
+const ABSOLUTE_BASE: u32 = 0xffff_ffff;
+
+/// Something a tracepoint collects, besides the registers
+#[derive(Clone, Debug)]
+enum Collect {
+    /// `len` bytes at the value of register `base` plus `offset`, or at
+    /// `offset` if `base` is `ABSOLUTE_BASE`
+    Memory { base: u32, offset: u64, len: u32 },
+}
+
+#[derive(Clone, Debug)]
+struct Tracepoint {
+    number: u32,
+    address: u32,
+    enabled: bool,
+
+    /// The trace run stops after this many hits, or never if it's 0
+    pass_count: u32,
+    hits: u32,
+    collect: Vec<Collect>,
+
+    /// The hardware breakpoint this is using during a trace run
+    slot: Option<usize>,
+}
+
+/// What was recorded at one tracepoint hit
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    /// The number of the tracepoint that was hit
+    pub tracepoint: u32,
+
+    /// Where the tracepoint is
+    pub pc: u32,
+
+    /// General-purpose registers and the PC, in GDB order
+    registers: Vec<u64>,
+
+    /// Memory that was collected, with the address of each block
+    memory: Vec<(u32, Vec<u8>)>,
+}
+
+impl TraceFrame {
+    /// Register `gdb_idx` as it was at the hit, if it was recorded
+    pub fn register(&self, gdb_idx: u32) -> Option<u64> {
+        self.registers.get(gdb_idx as usize).copied()
+    }
+
+    /// `len` bytes at `addr` as they were at the hit, if they were all
+    /// collected in one block
+    pub fn read_memory(&self, addr: u32, len: u32) -> Option<&[u8]> {
+        self.memory.iter().find_map(|(start, data)| {
+            let offset = addr.checked_sub(*start)? as usize;
+            data.get(offset..offset + len as usize)
+        })
+    }
+}
+
+/// How GDB asks for a frame with `QTFrame`, searching after the current
+/// frame for all but `Number`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameQuery {
+    Number(u32),
+    Pc(u32),
+    Tracepoint(u32),
+    Range(u32, u32),
+    Outside(u32, u32),
+}
+
+impl FrameQuery {
+    fn matches(self, index: usize, frame: &TraceFrame) -> bool {
+        match self {
+            FrameQuery::Number(n) => index == n as usize,
+            FrameQuery::Pc(pc) => frame.pc == pc,
+            FrameQuery::Tracepoint(t) => frame.tracepoint == t,
+            FrameQuery::Range(start, end) => frame.pc >= start && frame.pc <= end,
+            FrameQuery::Outside(start, end) => frame.pc < start || frame.pc > end,
+        }
+    }
+}
+
+/// Why the last trace run stopped, for `qTStatus`
+#[derive(Clone, Copy, Debug)]
+enum StopReason {
+    NotRun,
+    Stopped,
+    PassCount(u32),
+    BufferFull,
+}
+
+/// Tracepoints and the frames they've recorded, shared with the poll thread
+#[derive(Debug)]
+pub(super) struct Tracing {
+    tracepoints: Vec<Tracepoint>,
+    frames: Vec<TraceFrame>,
+    running: bool,
+    stop_reason: StopReason,
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Tracing {
+            tracepoints: vec![],
+            frames: vec![],
+            running: false,
+            stop_reason: StopReason::NotRun,
+        }
+    }
+}
+
+impl Tracing {
+    /// Forget every tracepoint and frame, as for `QTinit`.
+    pub(super) fn clear(&mut self) {
+        self.tracepoints.clear();
+        self.frames.clear();
+        self.stop_reason = StopReason::NotRun;
+    }
+
+    pub(super) fn define(&mut self, number: u32, address: u32, enabled: bool, pass_count: u32) {
+        self.tracepoints
+            .retain(|t| !(t.number == number && t.address == address));
+        self.tracepoints.push(Tracepoint {
+            number,
+            address,
+            enabled,
+            pass_count,
+            hits: 0,
+            collect: vec![],
+            slot: None,
+        });
+    }
+
+    /// Add an action from a `QTDP` packet to a tracepoint. Registers are
+    /// always collected, and anything besides memory ranges is ignored.
+    pub(super) fn add_action(&mut self, number: u32, address: u32, action: &str) {
+        let tracepoint = match self
+            .tracepoints
+            .iter_mut()
+            .find(|t| t.number == number && t.address == address)
+        {
+            Some(tracepoint) => tracepoint,
+            None => return,
+        };
+        match action.strip_prefix('M') {
+            Some(range) => {
+                let fields: Vec<&str> = range.split(',').collect();
+                let parsed = match fields.as_slice() {
+                    [base, offset, len] => (|| {
+                        Some(Collect::Memory {
+                            base: u32::from_str_radix(base, 16).ok()?,
+                            offset: u64::from_str_radix(offset, 16).ok()?,
+                            len: u32::from_str_radix(len, 16).ok()?,
+                        })
+                    })(),
+                    _ => None,
+                };
+                match parsed {
+                    Some(collect) => tracepoint.collect.push(collect),
+                    None => debug!("ignoring malformed tracepoint action {}", action),
+                }
+            }
+            None if action.starts_with('R') => (),
+            None => debug!("ignoring unsupported tracepoint action {}", action),
+        }
+    }
+
+    /// Start a trace run, returning the enabled tracepoints' addresses so
+    /// that breakpoints can be set on them.
+    pub(super) fn start(&mut self) -> Vec<u32> {
+        self.frames.clear();
+        self.running = true;
+        let mut addresses = vec![];
+        for tracepoint in &mut self.tracepoints {
+            tracepoint.hits = 0;
+            if tracepoint.enabled && !addresses.contains(&tracepoint.address) {
+                addresses.push(tracepoint.address);
+            }
+        }
+        addresses
+    }
+
+    /// Note which breakpoint the tracepoint at `address` is using.
+    pub(super) fn set_slot(&mut self, address: u32, slot: usize) {
+        for tracepoint in self.tracepoints.iter_mut().filter(|t| t.address == address) {
+            tracepoint.slot = Some(slot);
+        }
+    }
+
+    /// Stop the trace run, returning the addresses of the breakpoints to
+    /// remove.
+    pub(super) fn stop(&mut self) -> Vec<u32> {
+        if self.running {
+            self.running = false;
+            self.stop_reason = StopReason::Stopped;
+        }
+        let mut addresses = vec![];
+        for tracepoint in &mut self.tracepoints {
+            if tracepoint.slot.take().is_some() && !addresses.contains(&tracepoint.address) {
+                addresses.push(tracepoint.address);
+            }
+        }
+        addresses
+    }
+
+    /// The breakpoint a tracepoint at `pc` is using, if there is one.
+    pub(super) fn slot_at(&self, pc: u32) -> Option<usize> {
+        self.tracepoints
+            .iter()
+            .find(|t| t.address == pc)
+            .and_then(|t| t.slot)
+    }
+
+    /// Describe the trace run for `qTStatus`.
+    pub(super) fn status(&self) -> String {
+        let reason = match self.stop_reason {
+            _ if self.running => "".to_owned(),
+            StopReason::NotRun => ";tnotrun:0".to_owned(),
+            StopReason::Stopped => ";tstop:0".to_owned(),
+            StopReason::PassCount(n) => format!(";tpasscount:{:x}", n),
+            StopReason::BufferFull => ";tfull:0".to_owned(),
+        };
+        format!(
+            "T{}{};tframes:{:x};tcreated:{:x};tfree:{:x};tsize:{:x};circular:0;disconn:0",
+            if self.running { 1 } else { 0 },
+            reason,
+            self.frames.len(),
+            self.frames.len(),
+            MAX_TRACE_FRAMES - self.frames.len(),
+            MAX_TRACE_FRAMES
+        )
+    }
+
+    /// Find the first frame after `after` that `query` matches, or the
+    /// first one at all if `after` is `None`.
+    pub(super) fn find(
+        &self,
+        query: FrameQuery,
+        after: Option<usize>,
+    ) -> Option<(usize, TraceFrame)> {
+        let first = match (query, after) {
+            (FrameQuery::Number(_), _) | (_, None) => 0,
+            (_, Some(index)) => index + 1,
+        };
+        self.frames
+            .iter()
+            .enumerate()
+            .skip(first)
+            .find(|(index, frame)| query.matches(*index, frame))
+            .map(|(index, frame)| (index, frame.clone()))
+    }
+}
+
+impl RiscvCpuController {
+    /// Record a frame for the tracepoint at `pc`, which the CPU has just
+    /// halted on, if a trace run is going.
+    pub(super) fn record_trace_frame(&self, bridge: &Bridge, pc: u32) -> Result<(), RiscvCpuError> {
+        let mut tracing = self.tracing.lock().unwrap();
+        if !tracing.running {
+            return Ok(());
+        }
+        let tracepoint = match tracing.tracepoints.iter().find(|t| t.address == pc) {
+            Some(tracepoint) => tracepoint.clone(),
+            None => return Ok(()),
+        };
+
+        // Halting clobbers some registers, so prefer the cached copies.
+        let mut registers = vec![0];
+        for index in 1..=PC_INDEX {
+            let reg = if index == PC_INDEX {
+                RiscvRegister::pc()
+            } else {
+                let name = format!("x{}", index);
+                RiscvRegister::general(index, &name, false, RegisterContentsType::Int)
+            };
+            registers.push(match self.get_cached_reg(&reg) {
+                Some(value) => value,
+                None => self.read_register_wide(bridge, &reg)?,
+            });
+        }
+
+        let mut memory = vec![];
+        for Collect::Memory { base, offset, len } in &tracepoint.collect {
+            let start = match *base {
+                ABSOLUTE_BASE => *offset as u32,
+                base => match registers.get(base as usize) {
+                    Some(value) => value.wrapping_add(*offset) as u32,
+                    None => continue,
+                },
+            };
+            let mut data = Vec::with_capacity(*len as usize);
+            for addr in start..start.saturating_add(*len) {
+                data.push(self.read_memory(bridge, addr, 1)? as u8);
+            }
+            memory.push((start, data));
+        }
+
+        tracing.frames.push(TraceFrame {
+            tracepoint: tracepoint.number,
+            pc,
+            registers,
+            memory,
+        });
+        let hits = tracepoint.hits + 1;
+        if let Some(t) = tracing
+            .tracepoints
+            .iter_mut()
+            .find(|t| t.number == tracepoint.number && t.address == pc)
+        {
+            t.hits = hits;
+        }
+        if tracepoint.pass_count != 0 && hits >= tracepoint.pass_count {
+            debug!("tracepoint {} reached its pass count", tracepoint.number);
+            tracing.running = false;
+            tracing.stop_reason = StopReason::PassCount(tracepoint.number);
+        } else if tracing.frames.len() >= MAX_TRACE_FRAMES {
+            debug!("trace buffer is full");
+            tracing.running = false;
+            tracing.stop_reason = StopReason::BufferFull;
+        }
+        Ok(())
+    }
+    /// Run the instruction under the breakpoint in `slot`, which the CPU
+    /// has halted on at `pc`, then put the breakpoint back and let the CPU
+    /// carry on.
+    pub(super) fn step_over_breakpoint(
+        &self,
+        bridge: &Bridge,
+        slot: usize,
+        pc: u32,
+    ) -> Result<(), RiscvCpuError> {
+        self.set_breakpoint(bridge, slot, None)?;
+        self.perform_resume(bridge, true)?;
+        self.wait_for_step(bridge)?;
+        self.set_breakpoint(bridge, slot, Some(pc))?;
+        self.perform_resume(bridge, false)
+    }
+}
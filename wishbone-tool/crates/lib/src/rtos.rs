@@ -0,0 +1,334 @@
+//! Tasks of an RTOS running on the CPU, found by walking the kernel's own
+//! structures while the CPU is halted, so that GDB can show each task as a
+//! thread.
+//!
+//! GDB looks up the kernel's symbols for us with `qSymbol` once it has
+//! loaded the program. The task that was running when the CPU halted has its
+//! registers in the CPU, and every other task has them saved on its stack
+//! by the last context switch.
+
+use std::collections::HashMap;
+
+use super::riscv::{RiscvCpu, RiscvCpuError};
+use wishbone_bridge::Bridge;
+
+/// Every symbol that might help find an RTOS, in the order GDB is asked
+/// about them.
+pub const SYMBOLS: &[&str] = &[
+    // FreeRTOS
+    "pxCurrentTCB",
+    "pxReadyTasksLists",
+    "uxTopUsedPriority",
+    "uxTopReadyPriority",
+    "xDelayedTaskList1",
+    "xDelayedTaskList2",
+    "xPendingReadyList",
+    "xSuspendedTaskList",
+    "xTasksWaitingTermination",
+    // RIOT
+    "sched_threads",
+    "sched_active_pid",
+    "max_threads",
+    "_tcb_name_offset",
+];
+
+/// Longest task name that gets read, which is FreeRTOS's default
+/// `configMAX_TASK_NAME_LEN`.
+const MAX_NAME_LEN: u32 = 16;
+
+/// Most tasks that are followed along a single FreeRTOS list, in case it has
+/// been corrupted into a loop.
+const MAX_LIST_ITEMS: u32 = 256;
+
+/// GDB's number for the PC
+const PC_INDEX: u32 = 32;
+
+/// The RTOSes that tasks can be found for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtosKind {
+    FreeRtos,
+    Riot,
+}
+
+impl std::fmt::Display for RtosKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RtosKind::FreeRtos => write!(f, "FreeRTOS"),
+            RtosKind::Riot => write!(f, "RIOT"),
+        }
+    }
+}
+
+/// A task, as GDB sees it
+#[derive(Clone, Debug)]
+pub struct RtosThread {
+    /// Thread ID given to GDB: the TCB address on FreeRTOS, and the PID on
+    /// RIOT
+    pub id: u64,
+
+    /// The name the task was created with
+    pub name: String,
+
+    /// What the task is doing, such as "Ready" or "Blocked"
+    pub state: &'static str,
+
+    /// Whether this task was running when the CPU halted, in which case
+    /// its registers are in the CPU rather than on its stack
+    pub running: bool,
+
+    /// Stack pointer saved when the task was switched out
+    stack: u32,
+}
+
+/// An RTOS found in the program GDB loaded
+#[derive(Debug)]
+pub struct Rtos {
+    kind: RtosKind,
+    symbols: HashMap<String, u32>,
+}
+
+impl Rtos {
+    /// Work out which RTOS the program uses from the symbols GDB found, if
+    /// any.
+    pub fn detect(symbols: &HashMap<String, u32>) -> Option<Rtos> {
+        let has = |name: &str| symbols.contains_key(name);
+        let kind = if has("pxCurrentTCB")
+            && has("pxReadyTasksLists")
+            && (has("uxTopUsedPriority") || has("uxTopReadyPriority"))
+        {
+            RtosKind::FreeRtos
+        } else if has("sched_threads") && has("sched_active_pid") && has("max_threads") {
+            RtosKind::Riot
+        } else {
+            return None;
+        };
+        Some(Rtos {
+            kind,
+            symbols: symbols.clone(),
+        })
+    }
+
+    pub fn kind(&self) -> RtosKind {
+        self.kind
+    }
+
+    /// List the tasks. The CPU must be halted.
+    pub fn threads(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<Vec<RtosThread>, RiscvCpuError> {
+        match self.kind {
+            RtosKind::FreeRtos => self.freertos_threads(cpu, bridge),
+            RtosKind::Riot => self.riot_threads(cpu, bridge),
+        }
+    }
+
+    /// Read register `gdb_idx` of a task that isn't running from the frame
+    /// on its stack. Returns `None` for registers that aren't saved, such
+    /// as `gp` and `tp`, which are the same for every task.
+    pub fn read_register(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        thread: &RtosThread,
+        gdb_idx: u32,
+    ) -> Result<Option<u64>, RiscvCpuError> {
+        let word = cpu.xlen().bytes() as u32;
+        let frame = self.frame_words(word);
+        let slot = match gdb_idx {
+            0 => return Ok(Some(0)),
+            2 => return Ok(Some(u64::from(thread.stack + frame * word))),
+            PC_INDEX => self.pc_slot(),
+            x if x < PC_INDEX => match self.register_slot(x) {
+                Some(slot) => slot,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(
+            cpu.read_xlen_word(bridge, thread.stack + slot * word)?,
+        ))
+    }
+
+    /// Size of the frame a context switch leaves on the stack, in words
+    fn frame_words(&self, word: u32) -> u32 {
+        match self.kind {
+            // `portCONTEXT_SIZE` in the RISC-V port
+            RtosKind::FreeRtos => 30,
+            // `struct context_switch_frame`, padded to 16 bytes
+            RtosKind::Riot => ((29 * word + 15) & !15) / word,
+        }
+    }
+
+    /// Where the PC is saved in the frame
+    fn pc_slot(&self) -> u32 {
+        match self.kind {
+            RtosKind::FreeRtos => 0,
+            RtosKind::Riot => 28,
+        }
+    }
+
+    /// Where register `x` is saved in the frame, if it is
+    fn register_slot(&self, x: u32) -> Option<u32> {
+        match (self.kind, x) {
+            // ra, then x5 to x31 in order
+            (RtosKind::FreeRtos, 1) => Some(1),
+            (RtosKind::FreeRtos, 5..=31) => Some(x - 3),
+            // s0 to s11, ra, t0 to t2, a0 to a7, then t3 to t6
+            (RtosKind::Riot, 8..=9) => Some(x - 8),
+            (RtosKind::Riot, 18..=27) => Some(x - 16),
+            (RtosKind::Riot, 1) => Some(12),
+            (RtosKind::Riot, 5..=7) => Some(x + 8),
+            (RtosKind::Riot, 10..=17) => Some(x + 6),
+            (RtosKind::Riot, 28..=31) => Some(x - 4),
+            _ => None,
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    fn freertos_threads(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<Vec<RtosThread>, RiscvCpuError> {
+        let word = cpu.xlen().bytes() as u32;
+        let read = |addr: u32| cpu.read_xlen_word(bridge, addr).map(|v| v as u32);
+
+        // Until the scheduler starts there are no tasks to speak of.
+        let current = read(self.symbol("pxCurrentTCB").unwrap())?;
+        if current == 0 {
+            return Ok(vec![]);
+        }
+
+        let top_priority = match self.symbol("uxTopUsedPriority") {
+            Some(addr) => read(addr)?,
+            None => read(self.symbol("uxTopReadyPriority").unwrap())?,
+        };
+        let priorities = top_priority.min(31) + 1;
+        // A list is a count, an index, and an end marker of three words.
+        let list_size = 5 * word;
+        let ready = self.symbol("pxReadyTasksLists").unwrap();
+        let mut lists: Vec<(u32, &'static str)> = (0..priorities)
+            .map(|priority| (ready + priority * list_size, "Ready"))
+            .collect();
+        for (name, state) in &[
+            ("xDelayedTaskList1", "Blocked"),
+            ("xDelayedTaskList2", "Blocked"),
+            ("xPendingReadyList", "Ready"),
+            ("xSuspendedTaskList", "Suspended"),
+            ("xTasksWaitingTermination", "Deleted"),
+        ] {
+            if let Some(addr) = self.symbol(name) {
+                lists.push((addr, state));
+            }
+        }
+
+        let mut threads: Vec<RtosThread> = vec![];
+        for (list, state) in lists {
+            let end = list + 2 * word;
+            let mut item = read(end + word)?;
+            let mut count = 0;
+            while item != end && item != 0 && count < MAX_LIST_ITEMS {
+                let tcb = read(item + 3 * word)?;
+                if tcb != 0 && !threads.iter().any(|t| t.id == u64::from(tcb)) {
+                    // The name follows the top of stack, two list items,
+                    // the priority, and the stack base.
+                    threads.push(RtosThread {
+                        id: u64::from(tcb),
+                        name: Self::read_name(cpu, bridge, tcb + 13 * word)?,
+                        state: if tcb == current { "Running" } else { state },
+                        running: tcb == current,
+                        stack: read(tcb)?,
+                    });
+                }
+                item = read(item + word)?;
+                count += 1;
+            }
+        }
+        Ok(threads)
+    }
+
+    fn riot_threads(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<Vec<RtosThread>, RiscvCpuError> {
+        let word = cpu.xlen().bytes() as u32;
+        let read = |addr: u32| cpu.read_xlen_word(bridge, addr).map(|v| v as u32);
+
+        let max_threads = cpu.read_memory(bridge, self.symbol("max_threads").unwrap(), 1)?;
+        let active = cpu.read_memory(bridge, self.symbol("sched_active_pid").unwrap(), 2)?;
+        let name_offset = match self.symbol("_tcb_name_offset") {
+            Some(addr) => Some(cpu.read_memory(bridge, addr, 1)?),
+            None => None,
+        };
+        let threads_addr = self.symbol("sched_threads").unwrap();
+
+        let mut threads = vec![];
+        for pid in 0..max_threads {
+            let tcb = read(threads_addr + pid * word)?;
+            if tcb == 0 {
+                continue;
+            }
+            let name = match name_offset {
+                Some(offset) => {
+                    let name_ptr = read(tcb + offset)?;
+                    if name_ptr == 0 {
+                        String::new()
+                    } else {
+                        Self::read_name(cpu, bridge, name_ptr)?
+                    }
+                }
+                None => String::new(),
+            };
+            let status = cpu.read_memory(bridge, tcb + word, 1)?;
+            threads.push(RtosThread {
+                id: u64::from(pid),
+                name,
+                state: if pid == active {
+                    "Running"
+                } else {
+                    riot_status(status)
+                },
+                running: pid == active,
+                stack: read(tcb)?,
+            });
+        }
+        Ok(threads)
+    }
+
+    /// Read the NUL-terminated name at `addr`, up to `MAX_NAME_LEN` bytes.
+    fn read_name(cpu: &RiscvCpu, bridge: &Bridge, addr: u32) -> Result<String, RiscvCpuError> {
+        let mut name = vec![];
+        for offset in 0..MAX_NAME_LEN {
+            match cpu.read_memory(bridge, addr + offset, 1)? as u8 {
+                0 => break,
+                c => name.push(c),
+            }
+        }
+        Ok(String::from_utf8_lossy(&name).into_owned())
+    }
+}
+
+/// Describe a RIOT `thread_status_t`.
+fn riot_status(status: u32) -> &'static str {
+    match status {
+        0 => "Stopped",
+        1 => "Zombie",
+        2 => "Sleeping",
+        3 => "Blocked on mutex",
+        4 => "Blocked on receive",
+        5 => "Blocked on send",
+        6 => "Blocked on reply",
+        7 | 8 => "Blocked on flags",
+        9 => "Blocked on mbox",
+        10 => "Blocked on condition",
+        11 => "Running",
+        12 => "Pending",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,176 @@
+use super::{check_write, print_json, shutdown, ServerError};
+use crate::config::{Config, OutputFormat};
+
+use serde_json::json;
+use tracing::info;
+use wishbone_bridge::Bridge;
+
+use std::time::{Duration, Instant};
+
+/// Each measurement stops after this long, even if it hasn't reached
+/// `cfg.benchmark_count`, so that large bursts over a slow link don't take
+/// all day
+const TIME_LIMIT: Duration = Duration::from_secs(2);
+
+/// One line of the results
+struct Measurement {
+    operation: &'static str,
+    size: u32,
+    count: u32,
+    min: Duration,
+    mean: Duration,
+    max: Duration,
+}
+
+impl Measurement {
+    /// Bytes moved per second, on average
+    fn throughput(&self) -> f64 {
+        let secs = self.mean.as_secs_f64();
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.size as f64 / secs
+        }
+    }
+}
+
+/// Run `op` up to `count` times, or until `TIME_LIMIT` is up, timing each one
+fn measure<F: FnMut() -> Result<(), ServerError>>(
+    operation: &'static str,
+    size: u32,
+    count: u32,
+    mut op: F,
+) -> Result<Measurement, ServerError> {
+    let mut times = vec![];
+    let started = Instant::now();
+    while times.len() < count as usize && (times.is_empty() || started.elapsed() < TIME_LIMIT) {
+        if shutdown::requested() {
+            return Err(ServerError::Interrupted);
+        }
+        let start = Instant::now();
+        op()?;
+        times.push(start.elapsed());
+    }
+    let total: Duration = times.iter().sum();
+    Ok(Measurement {
+        operation,
+        size,
+        count: times.len() as u32,
+        min: times.iter().copied().min().unwrap_or_default(),
+        mean: total / times.len() as u32,
+        max: times.iter().copied().max().unwrap_or_default(),
+    })
+}
+
+fn micros(d: Duration) -> f64 {
+    d.as_secs_f64() * 1_000_000.0
+}
+
+/// Time peeks, pokes, and bursts each way at `cfg.benchmark_address`,
+/// printing a table of the results and writing them to `cfg.benchmark_csv`
+/// if it's set.
+pub fn benchmark(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let addr = cfg.benchmark_address;
+    let largest = cfg
+        .benchmark_sizes
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(4)
+        .max(4);
+    check_write(cfg, addr, largest)?;
+    info!(
+        "benchmarking the bridge at 0x{:08x}, up to {} times per measurement",
+        addr, cfg.benchmark_count
+    );
+
+    let count = cfg.benchmark_count;
+    let mut results = vec![
+        measure("peek", 4, count, || {
+            bridge.peek(addr)?;
+            Ok(())
+        })?,
+        measure("poke", 4, count, || {
+            bridge.poke(addr, 0x5555_aaaa)?;
+            Ok(())
+        })?,
+    ];
+    for &size in &cfg.benchmark_sizes {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        results.push(measure("burst write", size, count, || {
+            bridge.burst_write(addr, &data)?;
+            Ok(())
+        })?);
+        results.push(measure("burst read", size, count, || {
+            bridge.burst_read(addr, size)?;
+            Ok(())
+        })?);
+    }
+
+    if cfg.output == OutputFormat::Json {
+        let results: Vec<_> = results
+            .iter()
+            .map(|m| {
+                json!({
+                    "operation": m.operation,
+                    "bytes": m.size,
+                    "count": m.count,
+                    "min_us": micros(m.min),
+                    "mean_us": micros(m.mean),
+                    "max_us": micros(m.max),
+                    "bytes_per_second": m.throughput(),
+                })
+            })
+            .collect();
+        print_json("benchmark", json!({ "address": addr, "results": results }));
+    } else {
+        println!(
+            "{:<12} {:>8} {:>6} {:>12} {:>12} {:>12} {:>14}",
+            "operation", "bytes", "count", "min (us)", "mean (us)", "max (us)", "KiB/s"
+        );
+        for m in &results {
+            println!(
+                "{:<12} {:>8} {:>6} {:>12.1} {:>12.1} {:>12.1} {:>14.1}",
+                m.operation,
+                m.size,
+                m.count,
+                micros(m.min),
+                micros(m.mean),
+                micros(m.max),
+                m.throughput() / 1024.0
+            );
+        }
+    }
+
+    if let Some(file_name) = &cfg.benchmark_csv {
+        let mut csv =
+            csv::Writer::from_path(file_name).map_err(|e| ServerError::IoError(e.into()))?;
+        let mut write = |record: &[String]| {
+            csv.write_record(record)
+                .map_err(|e| ServerError::IoError(e.into()))
+        };
+        write(&[
+            "operation".to_owned(),
+            "bytes".to_owned(),
+            "count".to_owned(),
+            "min_us".to_owned(),
+            "mean_us".to_owned(),
+            "max_us".to_owned(),
+            "bytes_per_second".to_owned(),
+        ])?;
+        for m in &results {
+            write(&[
+                m.operation.to_owned(),
+                m.size.to_string(),
+                m.count.to_string(),
+                format!("{:.1}", micros(m.min)),
+                format!("{:.1}", micros(m.mean)),
+                format!("{:.1}", micros(m.max)),
+                format!("{:.0}", m.throughput()),
+            ])?;
+        }
+        csv.flush()?;
+        info!("wrote results to {}", file_name);
+    }
+    Ok(())
+}
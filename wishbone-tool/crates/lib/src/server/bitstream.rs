@@ -0,0 +1,182 @@
+/// FPGA families whose bitstreams can be recognized when flashing gateware
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FpgaFamily {
+    /// Xilinx 7-series, such as the Spartan-7 and Artix-7
+    Xilinx7,
+
+    /// Lattice ECP5
+    Ecp5,
+
+    /// Lattice iCE40, whose bitstreams don't carry an IDCODE
+    Ice40,
+}
+
+/// What could be learned about a bitstream from its header
+#[derive(Clone, Debug)]
+pub struct Bitstream {
+    pub family: FpgaFamily,
+
+    /// The IDCODE of the part the bitstream was built for, if it says
+    pub idcode: Option<u32>,
+
+    /// Part name from the header of a Xilinx `.bit` file
+    pub part: Option<String>,
+}
+
+/// How far into the file to look for a sync word. Anything further in
+/// is assumed to be firmware that happens to contain the same bytes.
+const SYNC_SEARCH_LEN: usize = 4096;
+
+/// How many bytes past the sync word to look for the IDCODE
+const IDCODE_SEARCH_LEN: usize = 1024;
+
+const XILINX_SYNC: [u8; 4] = [0xaa, 0x99, 0x55, 0x66];
+const ECP5_PREAMBLE: [u8; 4] = [0xff, 0xff, 0xbd, 0xb3];
+const ICE40_PREAMBLE: [u8; 4] = [0x7e, 0xaa, 0x99, 0x7e];
+
+/// Each entry of an iCE40 multiboot header is padded to this length
+const ICE40_HEADER_ENTRY_LEN: usize = 32;
+
+/// Type 1 packet writing one word to the IDCODE register
+const XILINX_WRITE_IDCODE: u32 = 0x3001_8001;
+
+/// The `VERIFY_ID` command, followed by three bytes of padding
+const ECP5_VERIFY_ID: [u8; 4] = [0xe2, 0x00, 0x00, 0x00];
+
+/// The top four bits of an IDCODE give the silicon revision, which a
+/// bitstream doesn't care about.
+const IDCODE_MASK: u32 = 0x0fff_ffff;
+
+impl FpgaFamily {
+    pub fn name(self) -> &'static str {
+        match self {
+            FpgaFamily::Xilinx7 => "Xilinx 7-series",
+            FpgaFamily::Ecp5 => "Lattice ECP5",
+            FpgaFamily::Ice40 => "Lattice iCE40",
+        }
+    }
+}
+
+impl Bitstream {
+    /// Look for a bitstream sync word near the start of `data`, returning
+    /// `None` if it doesn't look like a bitstream at all.
+    pub fn parse(data: &[u8]) -> Option<Bitstream> {
+        let head = &data[..data.len().min(SYNC_SEARCH_LEN)];
+        if let Some(sync) = find(head, &XILINX_SYNC) {
+            let body = &data[sync + 4..];
+            let idcode = body[..body.len().min(IDCODE_SEARCH_LEN)]
+                .chunks_exact(4)
+                .map(|w| u32::from_be_bytes([w[0], w[1], w[2], w[3]]))
+                .skip_while(|&w| w != XILINX_WRITE_IDCODE)
+                .nth(1);
+            return Some(Bitstream {
+                family: FpgaFamily::Xilinx7,
+                idcode,
+                part: xilinx_part_name(data),
+            });
+        }
+        if let Some(preamble) = find(head, &ECP5_PREAMBLE) {
+            let body = &data[preamble + 4..];
+            let body = &body[..body.len().min(IDCODE_SEARCH_LEN)];
+            let idcode = find(body, &ECP5_VERIFY_ID)
+                .and_then(|cmd| body.get(cmd + 4..cmd + 8))
+                .map(|w| u32::from_be_bytes([w[0], w[1], w[2], w[3]]));
+            return Some(Bitstream {
+                family: FpgaFamily::Ecp5,
+                idcode,
+                part: None,
+            });
+        }
+        if find(head, &ICE40_PREAMBLE).is_some() {
+            return Some(Bitstream {
+                family: FpgaFamily::Ice40,
+                idcode: None,
+                part: None,
+            });
+        }
+        None
+    }
+
+    /// Whether this bitstream was built for the part with IDCODE `idcode`.
+    pub fn matches_idcode(&self, idcode: u32) -> bool {
+        match self.idcode {
+            Some(ours) => ours & IDCODE_MASK == idcode & IDCODE_MASK,
+            None => false,
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// A Xilinx `.bit` file starts with a header of tagged fields, where `b`
+/// holds the part name, such as `7s50csga324`.
+fn xilinx_part_name(data: &[u8]) -> Option<String> {
+    const BIT_MAGIC: [u8; 13] = [
+        0x00, 0x09, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x0f, 0xf0, 0x00, 0x00, 0x01,
+    ];
+    if !data.starts_with(&BIT_MAGIC) {
+        return None;
+    }
+    let mut pos = BIT_MAGIC.len();
+    while let Some(&tag) = data.get(pos) {
+        // Field `e` is the length of the bitstream itself, which follows.
+        if tag == b'e' {
+            break;
+        }
+        let len = u16::from_be_bytes([*data.get(pos + 1)?, *data.get(pos + 2)?]) as usize;
+        let value = data.get(pos + 3..pos + 3 + len)?;
+        if tag == b'b' {
+            let value = value.split(|&b| b == 0).next().unwrap_or_default();
+            return Some(String::from_utf8_lossy(value).into_owned());
+        }
+        pos += 3 + len;
+    }
+    None
+}
+
+/// A header that makes a Xilinx 7-series FPGA reconfigure itself from
+/// `target`, using the warm boot start address and an IPROG command as
+/// described in UG470. Placing this at the address the FPGA boots from
+/// turns it into a jump to the image at `target`.
+pub fn xilinx_multiboot_header(target: u32) -> Vec<u8> {
+    let words: [u32; 8] = [
+        0xffff_ffff, // dummy word
+        0xaa99_5566, // sync word
+        0x2000_0000, // NOOP
+        0x3002_0001, // write WBSTAR
+        target,      // warm boot start address
+        0x3000_8001, // write CMD
+        0x0000_000f, // IPROG
+        0x2000_0000, // NOOP
+    ];
+    words.iter().flat_map(|w| w.to_be_bytes()).collect()
+}
+
+/// Where the FPGA boots from in each of the five entries of an iCE40
+/// multiboot header, as written by `icemulti`. The first entry is the image
+/// loaded at power-on, and the other four are what `SB_WARMBOOT` can pick
+/// between. Returns `None` if `data` doesn't start with such a header.
+pub fn ice40_multiboot_images(data: &[u8]) -> Option<Vec<u32>> {
+    let images = data
+        .chunks_exact(ICE40_HEADER_ENTRY_LEN)
+        .take(5)
+        .map_while(|entry| {
+            // A bitstream proper has its frequency setting (0x51) after the
+            // preamble, where a header has the boot mode (0x92)
+            if !entry.starts_with(&ICE40_PREAMBLE)
+                || entry[4] != 0x92
+                || entry[7..9] != [0x44, 0x03]
+            {
+                return None;
+            }
+            Some(u32::from_be_bytes([0, entry[9], entry[10], entry[11]]))
+        })
+        .collect::<Vec<_>>();
+    if images.len() == 5 {
+        Some(images)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::Config;
+use crate::server::ServerError;
+
+use tracing::info;
+use wishbone_bridge::Bridge;
+
+use std::io::{self, BufRead, Write};
+use std::thread;
+use std::time::Duration;
+
+/// CSRs that look like they control power or resets. LiteX doesn't have a
+/// standard naming scheme for these, so match on common fragments.
+const POWER_PATTERNS: &[&str] = &["power", "pwr", "reset", "rst"];
+
+/// CSRs that look like clock selectors or PLL controls.
+const CLOCK_PATTERNS: &[&str] = &["clk", "clock", "pll", "mmcm"];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ControlDomain {
+    Power,
+    Clock,
+}
+
+impl ControlDomain {
+    fn patterns(self) -> &'static [&'static str] {
+        match self {
+            ControlDomain::Power => POWER_PATTERNS,
+            ControlDomain::Clock => CLOCK_PATTERNS,
+        }
+    }
+}
+
+/// A board-level control operation, such as toggling a power switch
+/// or selecting a clock source.
+#[derive(Debug, Clone)]
+pub enum BoardControl {
+    /// List all CSRs that belong to this domain, along with their values
+    List(ControlDomain),
+
+    /// Write `value` to the CSR `name` at `addr`
+    Set { name: String, addr: u32, value: u32 },
+
+    /// Write 0 to the CSR, wait for `delay`, then write 1
+    Cycle {
+        name: String,
+        addr: u32,
+        delay: Duration,
+    },
+
+    /// Pulse a reset line: write 1, then write 0. The SoC-wide `ctrl_reset`
+    /// is self-clearing, so only 1 is written to it.
+    Reset { name: String, addr: u32 },
+}
+
+/// Ask the user to confirm an operation. Anything other than `y` or
+/// `yes` is treated as "no".
+fn confirm(prompt: &str) -> Result<bool, ServerError> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let answer = line.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+fn list_domain(cfg: &Config, bridge: &Bridge, domain: ControlDomain) -> Result<(), ServerError> {
+    let mut regions: Vec<_> = cfg
+        .memory_regions
+        .iter()
+        .filter(|r| r.size <= 64)
+        .filter(|r| domain.patterns().iter().any(|p| r.name.contains(p)))
+        .collect();
+    regions.sort_by_key(|r| r.base);
+    if regions.is_empty() {
+        println!("No {:?} controls found in csr.csv", domain);
+        return Ok(());
+    }
+    for region in regions {
+        if region.read_sensitive {
+            println!(
+                "{:<32} 0x{:08x}: (read-sensitive)",
+                region.name, region.base
+            );
+        } else if region.access.readable() {
+            println!(
+                "{:<32} 0x{:08x}: 0x{:08x}",
+                region.name,
+                region.base,
+                bridge.peek(region.base)?
+            );
+        } else {
+            println!("{:<32} 0x{:08x}: (write-only)", region.name, region.base);
+        }
+    }
+    Ok(())
+}
+
+pub fn board_control(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let control = match &cfg.board_control {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    let description = match control {
+        BoardControl::List(domain) => return list_domain(cfg, &bridge, *domain),
+        BoardControl::Set { name, addr, value } => {
+            format!("Write 0x{:08x} to {} (0x{:08x})?", value, name, addr)
+        }
+        BoardControl::Cycle { name, addr, delay } => format!(
+            "Power-cycle {} (0x{:08x}) with a {} ms off time?",
+            name,
+            addr,
+            delay.as_millis()
+        ),
+        BoardControl::Reset { name, addr } => format!("Reset {} (0x{:08x})?", name, addr),
+    };
+
+    if !cfg.assume_yes && !confirm(&description)? {
+        println!("Aborted");
+        return Ok(());
+    }
+
+    match control {
+        BoardControl::List(_) => unreachable!(),
+        BoardControl::Set { name, addr, value } => {
+            bridge.poke(*addr, *value)?;
+            info!("wrote 0x{:08x} to {}", value, name);
+        }
+        BoardControl::Cycle { name, addr, delay } => {
+            bridge.poke(*addr, 0)?;
+            info!("{} off, waiting {} ms", name, delay.as_millis());
+            thread::sleep(*delay);
+            bridge.poke(*addr, 1)?;
+            info!("{} on", name);
+        }
+        BoardControl::Reset { name, addr } => {
+            bridge.poke(*addr, 1)?;
+            if name != "ctrl_reset" {
+                bridge.poke(*addr, 0)?;
+            }
+            info!("reset {}", name);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use wishbone_bridge::{Bridge, BridgeError, MemoryRegion, RecentOperation};
+
+use std::sync::{Arc, Mutex};
+
+use tracing::{error, info, warn};
+
+/// Name of the counter that the `ctrl` block of a LiteX SoC built with bus
+/// error counting increments each time a Wishbone access ends in an error,
+/// such as one to an address that nothing is mapped at.
+pub const BUS_ERRORS_CSR: &str = "ctrl_bus_errors";
+
+/// How many of the operations leading up to new bus errors to list
+const SUSPECTS_SHOWN: usize = 8;
+
+/// Watches the target's bus error counter, and when it goes up, points at
+/// the host operations most likely to have caused it. Clones share what has
+/// been seen so far, so each new error is only reported once however many
+/// servers are watching.
+#[derive(Clone, Debug)]
+pub struct BusErrorWatch {
+    /// Address of the counter
+    pub addr: u32,
+
+    /// Check the counter after every batch of operations a server makes,
+    /// rather than only when statistics are reported
+    pub every_batch: bool,
+
+    /// Regions known to exist, used to pick out accesses to unmapped addresses
+    regions: Vec<MemoryRegion>,
+
+    seen: Arc<Mutex<Seen>>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// Value of the counter at the last check
+    count: Option<u32>,
+
+    /// Sequence number of the newest bridge operation at the last check
+    sequence: Option<u64>,
+}
+
+/// Why an operation is thought to have caused a bus error, most likely first
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Suspicion {
+    Unmapped,
+    Failed,
+    Recent,
+}
+
+impl BusErrorWatch {
+    pub fn new(addr: u32, every_batch: bool, regions: Vec<MemoryRegion>) -> BusErrorWatch {
+        BusErrorWatch {
+            addr,
+            every_batch,
+            regions,
+            seen: Arc::new(Mutex::new(Seen::default())),
+        }
+    }
+
+    /// Read the counter and log the operations most likely to blame for any
+    /// errors counted since the last check. The first check only notes where
+    /// the counter starts. Returns the number of new errors.
+    pub fn check(&self, bridge: &Bridge) -> Result<u32, BridgeError> {
+        let mut seen = self.seen.lock().unwrap();
+        let count = bridge.peek(self.addr)?;
+        let history = bridge.recent_operations();
+        let since = seen.sequence;
+        seen.sequence = history.last().map(|op| op.sequence).or(since);
+
+        let previous = match seen.count.replace(count) {
+            Some(previous) => previous,
+            None => {
+                if count != 0 {
+                    info!("target has already counted {} bus errors", count);
+                }
+                return Ok(0);
+            }
+        };
+        // The counter starts again from zero when the SoC is reset.
+        let new_errors = if count >= previous {
+            count - previous
+        } else {
+            count
+        };
+        if new_errors == 0 {
+            return Ok(0);
+        }
+
+        error!(
+            "target counted {} new bus error{} ({} in total)",
+            new_errors,
+            if new_errors == 1 { "" } else { "s" },
+            count
+        );
+        let suspects = self.suspects(&history, since);
+        if suspects.is_empty() {
+            error!(
+                "  the host made no accesses since the last check, so the CPU is likely to blame"
+            );
+        }
+        for (i, (op, suspicion)) in suspects.iter().take(SUSPECTS_SHOWN).enumerate() {
+            let reason = match suspicion {
+                Suspicion::Unmapped => ", which is outside every known region",
+                Suspicion::Failed => ", which failed",
+                Suspicion::Recent => "",
+            };
+            let lead = if i == 0 {
+                "most likely caused by"
+            } else {
+                "or by"
+            };
+            error!("  {} {}{}", lead, op, reason);
+        }
+        if suspects.len() > SUSPECTS_SHOWN {
+            error!(
+                "  and {} earlier operations",
+                suspects.len() - SUSPECTS_SHOWN
+            );
+        }
+        Ok(new_errors)
+    }
+
+    /// Check the counter, logging rather than returning any problem reading it.
+    pub fn report(&self, bridge: &Bridge) {
+        if let Err(e) = self.check(bridge) {
+            warn!("couldn't read the bus error counter: {}", e);
+        }
+    }
+
+    /// Check the counter at the end of a batch of server operations, if
+    /// that was asked for.
+    pub fn after_batch(&self, bridge: &Bridge) {
+        if self.every_batch {
+            self.report(bridge);
+        }
+    }
+
+    /// The operations made since the operation numbered `since`, most
+    /// suspicious first and otherwise newest first. Reads of the counter
+    /// itself can't cause bus errors, so they're left out.
+    fn suspects<'a>(
+        &self,
+        history: &'a [RecentOperation],
+        since: Option<u64>,
+    ) -> Vec<(&'a RecentOperation, Suspicion)> {
+        let mut suspects: Vec<_> = history
+            .iter()
+            .rev()
+            .filter(|op| since.map(|since| op.sequence > since).unwrap_or(true))
+            .filter(|op| !(op.operation == "peek" && op.addr == self.addr))
+            .map(|op| (op, self.suspicion(op)))
+            .collect();
+        // A stable sort keeps each kind of suspect newest first.
+        suspects.sort_by(|a, b| a.1.cmp(&b.1));
+        suspects
+    }
+
+    fn suspicion(&self, op: &RecentOperation) -> Suspicion {
+        let length = (op.length as u32).max(1);
+        if !self.regions.is_empty() && !self.regions.iter().any(|r| r.contains(op.addr, length)) {
+            Suspicion::Unmapped
+        } else if op.failed {
+            Suspicion::Failed
+        } else {
+            Suspicion::Recent
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use super::ServerError;
+use crate::config::Config;
+
+#[cfg(feature = "flash")]
+use indicatif::{ProgressBar, ProgressStyle};
+use tracing::{info, warn};
+use wishbone_bridge::{Bridge, MappedBridge, MemoryRegion};
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// How much to read at a time, which is also how often the progress bar
+/// moves
+const CHUNK_SIZE: u32 = 64 * 1024;
+
+/// A memory region to be read into a file
+#[derive(Debug, Clone)]
+pub struct RegionDump {
+    pub region: MemoryRegion,
+    pub file: PathBuf,
+}
+
+/// Read each of `cfg.region_dumps` into its file. Read-sensitive registers
+/// that fall within a region are skipped, and written out as zeroes.
+pub fn dump_regions(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let mut bridge = MappedBridge::new(bridge);
+    bridge
+        .regions(cfg.memory_regions.iter().cloned())
+        .policy(cfg.region_policy);
+    for dump in &cfg.region_dumps {
+        if let Some(dir) = dump.file.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        info!(
+            "reading {} ({} bytes at {:08x}) into {}",
+            dump.region.name,
+            dump.region.size,
+            dump.region.base,
+            dump.file.display()
+        );
+        dump_region(
+            &bridge,
+            &dump.region,
+            &mut BufWriter::new(File::create(&dump.file)?),
+        )?;
+    }
+    Ok(())
+}
+
+fn dump_region<W: Write>(
+    bridge: &MappedBridge,
+    region: &MemoryRegion,
+    out: &mut W,
+) -> Result<(), ServerError> {
+    #[cfg(feature = "flash")]
+    let pb = {
+        let pb = ProgressBar::new(region.size as u64);
+        pb.set_style(ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .progress_chars("#>-"));
+        pb
+    };
+
+    let mut skipped = false;
+    let mut done = 0;
+    while done < region.size {
+        let length = CHUNK_SIZE.min(region.size - done);
+        let data = bridge.burst_read_skip_sensitive(region.base + done, length)?;
+        skipped |= data.iter().any(|b| b.is_none());
+        let data: Vec<u8> = data.into_iter().map(|b| b.unwrap_or(0)).collect();
+        out.write_all(&data)?;
+        done += length;
+        #[cfg(feature = "flash")]
+        pb.set_position(done as u64);
+    }
+    out.flush()?;
+
+    #[cfg(feature = "flash")]
+    pb.finish_and_clear();
+    if skipped {
+        warn!(
+            "skipped read-sensitive registers in {}, filling them with 0",
+            region.name
+        );
+    }
+    Ok(())
+}
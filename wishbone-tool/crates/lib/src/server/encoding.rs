@@ -0,0 +1,197 @@
+/// A response from one of the HTTP or WebSocket endpoints, before it has
+/// been turned into JSON or CBOR.
+///
+/// Bulk data is kept as `Bytes`, which CBOR sends as-is. JSON has no byte
+/// strings, so there it becomes a string of hex digits.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiValue {
+    Null,
+    Bool(bool),
+    Uint(u64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Array(Vec<ApiValue>),
+    Map(Vec<(String, ApiValue)>),
+}
+
+/// How responses are encoded, chosen from the request's `Accept` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+
+    /// CBOR (RFC 8949), which carries burst data without the overhead of
+    /// hex or base64. Intended for dashboards streaming lots of data.
+    Cbor,
+}
+
+impl Encoding {
+    /// Pick an encoding based on an `Accept` header, preferring whichever
+    /// media type has the highest `q` value. JSON is used if the header is
+    /// missing or names neither.
+    pub fn from_accept(accept: Option<&str>) -> Encoding {
+        let mut best = (Encoding::Json, 0.0);
+        for range in accept.unwrap_or_default().split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let encoding = match params.next().unwrap_or_default() {
+                "application/cbor" | "application/cbor-seq" => Encoding::Cbor,
+                "application/json" | "application/x-ndjson" => Encoding::Json,
+                _ => continue,
+            };
+            let q = params
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > best.1 {
+                best = (encoding, q);
+            }
+        }
+        best.0
+    }
+
+    /// The `Content-Type` of a single response.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    /// The `Content-Type` of a stream of responses, such as a watch.
+    pub fn stream_content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/x-ndjson",
+            Encoding::Cbor => "application/cbor-seq",
+        }
+    }
+
+    pub fn encode(self, value: &ApiValue) -> Vec<u8> {
+        match self {
+            Encoding::Json => {
+                let mut out = String::new();
+                write_json(&mut out, value);
+                out.into_bytes()
+            }
+            Encoding::Cbor => {
+                let mut out = vec![];
+                write_cbor(&mut out, value);
+                out
+            }
+        }
+    }
+
+    /// Encode one item of a stream. CBOR items mark their own ends, so they
+    /// can go back to back, while JSON items each get a line to themselves.
+    pub fn encode_stream_item(self, value: &ApiValue) -> Vec<u8> {
+        let mut out = self.encode(value);
+        if self == Encoding::Json {
+            out.push(b'\n');
+        }
+        out
+    }
+}
+
+fn write_json(out: &mut String, value: &ApiValue) {
+    use std::fmt::Write;
+    match value {
+        ApiValue::Null => out.push_str("null"),
+        ApiValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        ApiValue::Uint(n) => write!(out, "{}", n).unwrap(),
+        ApiValue::Text(s) => write_json_string(out, s),
+        ApiValue::Bytes(data) => {
+            out.push('"');
+            for b in data {
+                write!(out, "{:02x}", b).unwrap();
+            }
+            out.push('"');
+        }
+        ApiValue::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json(out, item);
+            }
+            out.push(']');
+        }
+        ApiValue::Map(entries) => {
+            out.push('{');
+            for (i, (key, item)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(out, key);
+                out.push(':');
+                write_json(out, item);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    use std::fmt::Write;
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Write the initial byte of a CBOR item of major type `major`, followed by
+/// `n` in as few bytes as possible.
+fn write_cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= 0xff {
+        out.extend_from_slice(&[major | 24, n as u8]);
+    } else if n <= 0xffff {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= 0xffff_ffff {
+        out.push(major | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn write_cbor(out: &mut Vec<u8>, value: &ApiValue) {
+    match value {
+        ApiValue::Null => out.push(0xf6),
+        ApiValue::Bool(b) => out.push(if *b { 0xf5 } else { 0xf4 }),
+        ApiValue::Uint(n) => write_cbor_head(out, 0, *n),
+        ApiValue::Bytes(data) => {
+            write_cbor_head(out, 2, data.len() as u64);
+            out.extend_from_slice(data);
+        }
+        ApiValue::Text(s) => {
+            write_cbor_head(out, 3, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        ApiValue::Array(items) => {
+            write_cbor_head(out, 4, items.len() as u64);
+            for item in items {
+                write_cbor(out, item);
+            }
+        }
+        ApiValue::Map(entries) => {
+            write_cbor_head(out, 5, entries.len() as u64);
+            for (key, item) in entries {
+                write_cbor_head(out, 3, key.len() as u64);
+                out.extend_from_slice(key.as_bytes());
+                write_cbor(out, item);
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Etherbone packets, as spoken by `litex_server` and `litex_client` over
+//! TCP and by LiteEth's Etherbone core over UDP. Both the Wishbone server
+//! and the sniffer parse packets and build replies with these.
+
+#[cfg(feature = "wishbone-server")]
+use super::AccessPolicy;
+use byteorder::{BigEndian, ByteOrder};
+use wishbone_bridge::{Bridge, BridgeError};
+
+use std::io::{self, BufReader, Read};
+
+/// Packet header flag asking for a probe response
+pub(crate) const FLAG_PROBE: u8 = 0x01;
+
+/// Packet header flag marking a probe response
+pub(crate) const FLAG_PROBE_RESPONSE: u8 = 0x02;
+
+/// Packet header flag promising that the packet has no reads
+pub(crate) const FLAG_NO_READS: u8 = 0x04;
+
+/// Record flag sending the replies to reads to the requester's config space
+pub(crate) const RECORD_BCA: u8 = 0x80;
+
+/// Record flag taking reads from the device's config space
+pub(crate) const RECORD_RCA: u8 = 0x40;
+
+/// Record flag asking for reads to come from a FIFO at one address
+pub(crate) const RECORD_RFF: u8 = 0x20;
+
+/// Record flag dropping the Wishbone cycle once the record is done
+pub(crate) const RECORD_CYC: u8 = 0x08;
+
+/// Record flag sending writes to the device's config space
+pub(crate) const RECORD_WCA: u8 = 0x04;
+
+/// Record flag asking for writes to go to a FIFO at one address
+pub(crate) const RECORD_WFF: u8 = 0x02;
+
+/// One Etherbone record: some writes, some reads, or both
+#[derive(Debug, Default)]
+pub(crate) struct Record {
+    pub(crate) flags: u8,
+    pub(crate) byte_enable: u8,
+
+    /// Where the first write goes
+    pub(crate) write_base: u32,
+    pub(crate) writes: Vec<u32>,
+
+    /// Where the replies to the reads are to be written
+    pub(crate) return_base: u32,
+    pub(crate) reads: Vec<u32>,
+}
+
+impl Record {
+    /// Parse the record header at the start of `data`, returning it along
+    /// with how many more bytes the rest of the record takes up.
+    fn header(data: &[u8]) -> (Record, usize, usize) {
+        let wcount = data[2] as usize;
+        let rcount = data[3] as usize;
+        let record = Record {
+            flags: data[0],
+            byte_enable: data[1],
+            ..Default::default()
+        };
+        (record, wcount, rcount)
+    }
+
+    /// Length of the body of a record with `wcount` writes and `rcount`
+    /// reads. Each half has a base address followed by one word per item.
+    fn body_len(wcount: usize, rcount: usize) -> usize {
+        let half = |count| if count > 0 { 4 + 4 * count } else { 0 };
+        half(wcount) + half(rcount)
+    }
+
+    /// Fill in the writes and reads from the body of the record.
+    fn parse_body(&mut self, body: &[u8], wcount: usize, rcount: usize) {
+        let words: Vec<u32> = body.chunks(4).map(BigEndian::read_u32).collect();
+        let mut words = words.into_iter();
+        if wcount > 0 {
+            self.write_base = words.next().unwrap_or(0);
+            self.writes = words.by_ref().take(wcount).collect();
+        }
+        if rcount > 0 {
+            self.return_base = words.next().unwrap_or(0);
+            self.reads = words.take(rcount).collect();
+        }
+    }
+
+    /// Address of the `index`th write, which all go to the same place if
+    /// they're for a FIFO.
+    pub(crate) fn write_addr(&self, index: usize) -> u32 {
+        if self.flags & RECORD_WFF != 0 {
+            self.write_base
+        } else {
+            self.write_base.wrapping_add(4 * index as u32)
+        }
+    }
+}
+
+/// A whole Etherbone packet
+#[derive(Debug, Default)]
+pub(crate) struct Packet {
+    pub(crate) header: [u8; 8],
+    pub(crate) records: Vec<Record>,
+}
+
+impl Packet {
+    pub(crate) fn flags(&self) -> u8 {
+        self.header[2] & 0x0f
+    }
+
+    pub(crate) fn has_reads(&self) -> bool {
+        self.records.iter().any(|r| !r.reads.is_empty())
+    }
+}
+
+/// Check the magic number at the start of a packet header.
+fn check_magic(header: &[u8]) -> Result<(), String> {
+    if header[0] == 0x4e && header[1] == 0x6f {
+        Ok(())
+    } else {
+        Err(format!(
+            "bad magic {:02x}{:02x}, expected 4e6f",
+            header[0], header[1]
+        ))
+    }
+}
+
+/// Split a datagram into a packet. Anything after the last whole record is
+/// reported as an error.
+pub(crate) fn parse_datagram(data: &[u8]) -> Result<Packet, String> {
+    if data.len() < 8 {
+        return Err(format!("only {} bytes, too short for a header", data.len()));
+    }
+    check_magic(data)?;
+    let mut packet = Packet::default();
+    packet.header.copy_from_slice(&data[..8]);
+    let mut offset = 8;
+    while offset < data.len() {
+        if offset + 4 > data.len() {
+            return Err(format!("{} stray bytes at the end", data.len() - offset));
+        }
+        let (mut record, wcount, rcount) = Record::header(&data[offset..]);
+        let end = offset + 4 + Record::body_len(wcount, rcount);
+        if end > data.len() {
+            return Err(format!(
+                "record {} needs {} bytes but only {} are left",
+                packet.records.len() + 1,
+                end - offset,
+                data.len() - offset
+            ));
+        }
+        record.parse_body(&data[offset + 4..end], wcount, rcount);
+        packet.records.push(record);
+        offset = end;
+    }
+    Ok(packet)
+}
+
+/// Read one packet from a stream. Packets on a stream aren't delimited, so
+/// a packet is taken to end when a new header turns up, or when everything
+/// received so far has been used up and the client is presumably waiting
+/// for a reply.
+pub(crate) fn read_stream_packet<R: Read>(
+    reader: &mut BufReader<R>,
+) -> Result<Option<Packet>, String> {
+    let mut packet = Packet::default();
+    match reader.read_exact(&mut packet.header) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    }
+    check_magic(&packet.header)?;
+    // A probe is just a header.
+    if packet.flags() & (FLAG_PROBE | FLAG_PROBE_RESPONSE) != 0 {
+        return Ok(Some(packet));
+    }
+    loop {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+        let (mut record, wcount, rcount) = Record::header(&header);
+        let mut body = vec![0; Record::body_len(wcount, rcount)];
+        reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+        record.parse_body(&body, wcount, rcount);
+        packet.records.push(record);
+
+        let buffered = reader.buffer();
+        if buffered.is_empty() || buffered.starts_with(&[0x4e, 0x6f]) {
+            return Ok(Some(packet));
+        }
+    }
+}
+
+/// Carry out a write on the bridge. Writes that don't enable every byte lane
+/// are merged with what's already there, as the bridge only writes whole words.
+pub(crate) fn forward_write(
+    bridge: &Bridge,
+    addr: u32,
+    value: u32,
+    byte_enable: u8,
+) -> Result<(), BridgeError> {
+    if byte_enable & 0xf == 0xf {
+        bridge.poke(addr, value)
+    } else {
+        let mask = (0..4)
+            .filter(|lane| byte_enable & (1 << lane) != 0)
+            .fold(0u32, |mask, lane| mask | (0xff << (8 * lane)));
+        bridge
+            .peek(addr)
+            .and_then(|old| bridge.poke(addr, (old & !mask) | (value & mask)))
+    }
+}
+
+/// Build the reply to `packet`, or `None` if it doesn't need one. Reads are
+/// answered with a write record of the values to the return address, as a
+/// device would.
+pub(crate) fn reply(packet: &Packet, answers: &[Vec<u32>]) -> Option<Vec<u8>> {
+    let mut reply = packet.header.to_vec();
+    if packet.flags() & FLAG_PROBE != 0 {
+        reply[2] = (reply[2] & 0xf0) | FLAG_PROBE_RESPONSE;
+        return Some(reply);
+    }
+    if !packet.has_reads() || packet.flags() & FLAG_NO_READS != 0 {
+        return None;
+    }
+    reply[2] &= 0xf0;
+    let mut word = [0; 4];
+    for (record, values) in packet
+        .records
+        .iter()
+        .filter(|r| !r.reads.is_empty())
+        .zip(answers)
+    {
+        let flags = if record.flags & RECORD_BCA != 0 {
+            RECORD_WCA
+        } else {
+            0
+        };
+        reply.extend_from_slice(&[flags, record.byte_enable, values.len() as u8, 0]);
+        BigEndian::write_u32(&mut word, record.return_base);
+        reply.extend_from_slice(&word);
+        for value in values {
+            BigEndian::write_u32(&mut word, *value);
+            reply.extend_from_slice(&word);
+        }
+    }
+    Some(reply)
+}
+
+/// Carry out `packet` on the bridge, as a device would, returning the
+/// answers to its reads in the order `reply()` wants them. Config space
+/// isn't backed by anything, so writes to it are dropped and reads from it
+/// are answered with zero.
+#[cfg(feature = "wishbone-server")]
+pub(crate) fn execute(packet: &Packet, bridge: &Bridge) -> Result<Vec<Vec<u32>>, BridgeError> {
+    let mut answers = vec![];
+    for record in &packet.records {
+        if record.flags & RECORD_WCA == 0 {
+            for (i, value) in record.writes.iter().enumerate() {
+                forward_write(bridge, record.write_addr(i), *value, record.byte_enable)?;
+            }
+        }
+        if record.reads.is_empty() {
+            continue;
+        }
+        let mut values = vec![];
+        for addr in &record.reads {
+            if record.flags & RECORD_RCA != 0 {
+                values.push(0);
+            } else {
+                values.push(bridge.peek(*addr)?);
+            }
+        }
+        answers.push(values);
+    }
+    Ok(answers)
+}
+
+/// Check every access in `packet` against `policy` before any of it is
+/// carried out, so that a refused packet doesn't get halfway through.
+#[cfg(feature = "wishbone-server")]
+pub(crate) fn check_access(packet: &Packet, policy: &AccessPolicy) -> Result<(), String> {
+    for record in &packet.records {
+        if record.flags & RECORD_WCA == 0 {
+            for i in 0..record.writes.len() {
+                policy.check_write(record.write_addr(i), 4)?;
+            }
+        }
+        if record.flags & RECORD_RCA == 0 {
+            for addr in &record.reads {
+                policy.check_read(*addr, 4)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 8] = [0x4e, 0x6f, 0x10, 0x44, 0, 0, 0, 0];
+
+    /// A write of 0x12345678 to 0xe0001800 followed by a read of 0xe0001804,
+    /// as `litex_client` sends them.
+    fn write_then_read() -> Vec<u8> {
+        [
+            &HEADER[..],
+            &[0x00, 0x0f, 0x01, 0x00],
+            &[0xe0, 0x00, 0x18, 0x00, 0x12, 0x34, 0x56, 0x78],
+            &[0x00, 0x0f, 0x00, 0x01],
+            &[0x00, 0x00, 0x00, 0x00, 0xe0, 0x00, 0x18, 0x04],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn datagram_parses() {
+        let packet = parse_datagram(&write_then_read()).unwrap();
+        assert_eq!(packet.records.len(), 2);
+        assert_eq!(packet.records[0].write_base, 0xe000_1800);
+        assert_eq!(packet.records[0].writes, vec![0x1234_5678]);
+        assert_eq!(packet.records[1].reads, vec![0xe000_1804]);
+        assert!(packet.has_reads());
+
+        let reply = reply(&packet, &[vec![0xcafe_f00d]]).unwrap();
+        assert_eq!(&reply[..8], &[0x4e, 0x6f, 0x10, 0x44, 0, 0, 0, 0]);
+        assert_eq!(
+            &reply[8..],
+            &[0x00, 0x0f, 0x01, 0x00, 0, 0, 0, 0, 0xca, 0xfe, 0xf0, 0x0d]
+        );
+    }
+
+    #[test]
+    fn probe_is_answered() {
+        let packet = parse_datagram(&[0x4e, 0x6f, 0x11, 0x44, 0, 0, 0, 0]).unwrap();
+        let reply = reply(&packet, &[]).unwrap();
+        assert_eq!(reply[2] & 0x0f, FLAG_PROBE_RESPONSE);
+    }
+
+    #[test]
+    fn truncated_datagram_is_rejected() {
+        let data = write_then_read();
+        assert_eq!(
+            parse_datagram(&data[..6]).unwrap_err(),
+            "only 6 bytes, too short for a header"
+        );
+        assert_eq!(
+            parse_datagram(&data[..data.len() - 2]).unwrap_err(),
+            "record 2 needs 12 bytes but only 10 are left"
+        );
+        assert_eq!(
+            parse_datagram(&data[..10]).unwrap_err(),
+            "2 stray bytes at the end"
+        );
+    }
+
+    #[test]
+    fn malformed_datagram_is_rejected() {
+        let mut data = write_then_read();
+        data[0] = 0x4f;
+        assert_eq!(
+            parse_datagram(&data).unwrap_err(),
+            "bad magic 4f6f, expected 4e6f"
+        );
+    }
+
+    #[test]
+    fn stream_packets_split_at_headers() {
+        let stream = [write_then_read(), write_then_read()].concat();
+        let mut reader = BufReader::new(&stream[..]);
+        for _ in 0..2 {
+            let packet = read_stream_packet(&mut reader).unwrap().unwrap();
+            assert_eq!(packet.records.len(), 2);
+            assert_eq!(packet.records[1].reads, vec![0xe000_1804]);
+        }
+        assert!(read_stream_packet(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_stream_packet_is_rejected() {
+        let data = write_then_read();
+        let mut reader = BufReader::new(&data[..data.len() - 2]);
+        assert!(read_stream_packet(&mut reader).is_err());
+        let mut reader = BufReader::new(&data[..4]);
+        assert!(read_stream_packet(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_stream_packet_is_rejected() {
+        let mut data = write_then_read();
+        data[1] = 0x00;
+        let mut reader = BufReader::new(&data[..]);
+        assert_eq!(
+            read_stream_packet(&mut reader).unwrap_err(),
+            "bad magic 4e00, expected 4e6f"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use super::{
+    check_golden, flash_erase_span, read_flash, shutdown, ServerError, SpiNor, LOAD_CHUNK_SIZE,
+    SECTOR_SIZE,
+};
+use crate::config::Config;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use tracing::{info, warn};
+use wishbone_bridge::Bridge;
+
+/// Erased with a single command where the range allows it
+const BLOCK_SIZE: u32 = 0x1_0000;
+
+fn progress_bar(length: u32) -> ProgressBar {
+    let pb = ProgressBar::new(length as u64);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .progress_chars("#>-"));
+    pb
+}
+
+/// Make sure `length` bytes at `offset` are all within the flash, and that
+/// the flash is there to talk to.
+fn check_range(
+    bridge: &Bridge,
+    flash: &SpiNor,
+    offset: u32,
+    length: u32,
+) -> Result<(), ServerError> {
+    let size = flash.probe(bridge)?.size.min(flash.size);
+    if offset as u64 + length as u64 > size as u64 {
+        return Err(ServerError::UnmappableAddress(format!(
+            "0x{:08x}, past the end of the {} byte flash",
+            offset as u64 + length as u64,
+            size
+        )));
+    }
+    Ok(())
+}
+
+/// Halt the CPU while `f` runs, if there's one to halt, so that it isn't
+/// fetching from the flash while it's being changed.
+fn with_cpu_halted<T, F: FnOnce() -> Result<T, ServerError>>(
+    cfg: &Config,
+    bridge: &Bridge,
+    f: F,
+) -> Result<T, ServerError> {
+    let debug = cfg
+        .register_mapping
+        .get("vexriscv_debug")
+        .copied()
+        .flatten();
+    if let Some(debug) = debug {
+        info!("Halting CPU.");
+        bridge.poke(debug, 0x00020000)?;
+    }
+    let result = f();
+    if let Some(debug) = debug {
+        bridge.poke(debug, 0x02000000)?;
+        info!("Resuming CPU.");
+    }
+    result
+}
+
+/// Read `cfg.flash_read` from the flash into a file, such as to back it up
+/// before flashing something new.
+pub fn flash_read(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let (offset, length, file_name) = match &cfg.flash_read {
+        Some((offset, length, file_name)) => (*offset, *length, file_name),
+        None => return Ok(()),
+    };
+    let flash = SpiNor::from_config(cfg)?;
+    check_range(&bridge, &flash, offset, length)?;
+    // Reads through the memory map wait while a command has them locked
+    flash.finish(&bridge)?;
+
+    info!(
+        "Reading 0x{:08x}-0x{:08x} into {}",
+        offset,
+        offset as u64 + length as u64,
+        file_name
+    );
+    let pb = progress_bar(length);
+    let mut data = Vec::with_capacity(length as usize);
+    for chunk in (offset..offset + length).step_by(LOAD_CHUNK_SIZE) {
+        if shutdown::requested() {
+            return Err(ServerError::Interrupted);
+        }
+        let chunk_length = (LOAD_CHUNK_SIZE as u32).min(offset + length - chunk);
+        // Whole words are read, and the extra bytes dropped
+        data.extend(read_flash(&bridge, &flash, chunk, (chunk_length + 3) & !3)?);
+        data.truncate((chunk - offset + chunk_length) as usize);
+        pb.set_position(data.len() as u64);
+    }
+    pb.finish_with_message("Read finished");
+    std::fs::write(file_name, &data)?;
+    info!("Wrote {} bytes to {}", data.len(), file_name);
+    Ok(())
+}
+
+/// Erase every sector that `cfg.flash_erase` touches, using block erases
+/// where they fit.
+pub fn flash_erase(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let (offset, length) = match cfg.flash_erase {
+        Some(range) => range,
+        None => return Ok(()),
+    };
+    let flash = SpiNor::from_config(cfg)?;
+    check_range(&bridge, &flash, offset, length)?;
+    let (start, end) = flash_erase_span(offset, length);
+    if start != offset || end as u64 != offset as u64 + length as u64 {
+        warn!(
+            "erasing whole sectors, so 0x{:08x}-0x{:08x} will be erased",
+            start, end
+        );
+    }
+    check_golden(cfg, start, end)?;
+
+    // Finish the sector or block in progress if we're killed
+    let _busy = shutdown::busy();
+    with_cpu_halted(cfg, &bridge, || {
+        let pb = progress_bar(end - start);
+        let mut addr = start;
+        while addr < end {
+            if shutdown::requested() {
+                warn!("stopping before the flash has been fully erased");
+                flash.finish(&bridge).ok();
+                return Err(ServerError::Interrupted);
+            }
+            let size = if addr & (BLOCK_SIZE - 1) == 0 && end - addr >= BLOCK_SIZE {
+                BLOCK_SIZE
+            } else {
+                SECTOR_SIZE
+            };
+            if flash.erase(&bridge, addr, size)? {
+                flash.finish(&bridge).ok();
+                return Err(ServerError::FlashFailed(addr));
+            }
+            addr += size;
+            pb.set_position((addr - start) as u64);
+        }
+        pb.finish_with_message("Erase finished");
+        flash.finish(&bridge)?;
+        Ok(())
+    })?;
+    info!("Erased 0x{:08x}-0x{:08x}", start, end);
+    Ok(())
+}
+
+/// Set or clear the flash's block-protect bits, as `cfg.flash_protect` says.
+pub fn flash_protect(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let protect = cfg.flash_protect.unwrap_or(true);
+    let flash = SpiNor::from_config(cfg)?;
+    let status = with_cpu_halted(cfg, &bridge, || flash.set_protection(&bridge, protect))?;
+    info!(
+        "{} the flash, status register is now 0x{:02x}",
+        if protect { "Protected" } else { "Unprotected" },
+        status
+    );
+    Ok(())
+}
+
+/// Run whichever of `--flash-read`, `--flash-erase`, and `--flash-protect`
+/// or `--flash-unprotect` were given. Unprotecting comes first, so that the
+/// erase can happen, and protecting comes last.
+pub fn flash_commands(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    if cfg.flash_protect == Some(false) {
+        flash_protect(cfg, bridge.clone())?;
+    }
+    flash_read(cfg, bridge.clone())?;
+    flash_erase(cfg, bridge.clone())?;
+    if cfg.flash_protect == Some(true) {
+        flash_protect(cfg, bridge)?;
+    }
+    Ok(())
+}
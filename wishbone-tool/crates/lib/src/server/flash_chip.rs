@@ -0,0 +1,472 @@
+use std::convert::TryInto;
+use std::fmt;
+
+/// The manufacturer, memory type, and capacity bytes that a flash chip
+/// returns for RDID (`0x9f`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JedecId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    pub capacity: u8,
+}
+
+impl JedecId {
+    /// All three bytes as one number, in the order the chip sends them
+    pub fn code(&self) -> u32 {
+        (self.manufacturer as u32) << 16 | (self.memory_type as u32) << 8 | self.capacity as u32
+    }
+}
+
+impl fmt::Display for JedecId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x} {:02x} {:02x}",
+            self.manufacturer, self.memory_type, self.capacity
+        )
+    }
+}
+
+/// A register that records whether the last erase or program failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorRegister {
+    /// Command that reads it
+    pub read: u8,
+
+    /// Bits that are set after a failure
+    pub fail_mask: u32,
+
+    /// Command that clears the failure bits, if they don't clear themselves
+    /// when the next erase or program starts
+    pub clear: Option<u8>,
+}
+
+/// What differs between the flash chips of each vendor. Everything else
+/// comes from the chip's SFDP tables where it has them, or follows the
+/// commands that nearly every chip shares.
+pub trait FlashChip: Sync {
+    /// Who makes the chips this driver handles
+    fn vendor(&self) -> &'static str;
+
+    /// Whether this driver handles the chip with the ID `id`
+    fn matches(&self, id: JedecId) -> bool;
+
+    /// Size of the flash in bytes, going by its ID. Most vendors make the
+    /// capacity byte the log2 of the size.
+    fn size_from_id(&self, id: JedecId) -> Option<u32> {
+        match id.capacity {
+            0x10..=0x1f => Some(1 << id.capacity),
+            _ => None,
+        }
+    }
+
+    /// Where the chip reports failed erases and programs, if it does. Chips
+    /// that don't are only checked by reading back what was written.
+    fn error_register(&self) -> Option<ErrorRegister> {
+        None
+    }
+
+    /// The block-protect bits of the status register. With all of them set,
+    /// none of the flash can be erased or programmed.
+    fn protect_mask(&self) -> u32 {
+        // BP0-BP2
+        0x1c
+    }
+}
+
+struct Macronix;
+
+impl FlashChip for Macronix {
+    fn vendor(&self) -> &'static str {
+        "Macronix"
+    }
+
+    fn matches(&self, id: JedecId) -> bool {
+        id.manufacturer == 0xc2
+    }
+
+    fn size_from_id(&self, id: JedecId) -> Option<u32> {
+        match id.capacity {
+            0x10..=0x1f => Some(1 << id.capacity),
+            // The MX25UM and MX66UM octal parts count from 0x20
+            0x30..=0x3f => Some(1 << (id.capacity - 0x20)),
+            _ => None,
+        }
+    }
+
+    fn error_register(&self) -> Option<ErrorRegister> {
+        // RDSCUR, with E_FAIL and P_FAIL
+        Some(ErrorRegister {
+            read: 0x2b,
+            fail_mask: 0x60,
+            clear: None,
+        })
+    }
+
+    fn protect_mask(&self) -> u32 {
+        // BP0-BP3
+        0x3c
+    }
+}
+
+struct Winbond;
+
+impl FlashChip for Winbond {
+    fn vendor(&self) -> &'static str {
+        "Winbond"
+    }
+
+    fn matches(&self, id: JedecId) -> bool {
+        id.manufacturer == 0xef
+    }
+}
+
+struct Issi;
+
+impl FlashChip for Issi {
+    fn vendor(&self) -> &'static str {
+        "ISSI"
+    }
+
+    fn matches(&self, id: JedecId) -> bool {
+        id.manufacturer == 0x9d
+    }
+
+    fn error_register(&self) -> Option<ErrorRegister> {
+        // RDERP, with PROT_E, P_ERR, and E_ERR, cleared by CLERP
+        Some(ErrorRegister {
+            read: 0x81,
+            fail_mask: 0x0e,
+            clear: Some(0x82),
+        })
+    }
+
+    fn protect_mask(&self) -> u32 {
+        // BP0-BP3
+        0x3c
+    }
+}
+
+struct Micron;
+
+impl FlashChip for Micron {
+    fn vendor(&self) -> &'static str {
+        "Micron"
+    }
+
+    fn matches(&self, id: JedecId) -> bool {
+        // Parts from before Micron bought Numonyx use Numonyx's ID
+        id.manufacturer == 0x20 || id.manufacturer == 0x2c
+    }
+
+    fn size_from_id(&self, id: JedecId) -> Option<u32> {
+        match id.capacity {
+            0x10..=0x19 => Some(1 << id.capacity),
+            // 512 Mib and up skip ahead to 0x20
+            0x20..=0x22 => Some(1 << (id.capacity - 6)),
+            _ => None,
+        }
+    }
+
+    fn error_register(&self) -> Option<ErrorRegister> {
+        // The flag status register, with the erase and program failure
+        // bits, cleared by CLFSR
+        Some(ErrorRegister {
+            read: 0x70,
+            fail_mask: 0x30,
+            clear: Some(0x50),
+        })
+    }
+
+    fn protect_mask(&self) -> u32 {
+        // BP0-BP2, with BP3 above the top/bottom bit
+        0x5c
+    }
+}
+
+/// Any chip with SFDP tables, as long as it doesn't need anything beyond
+/// the common commands
+struct Generic;
+
+impl FlashChip for Generic {
+    fn vendor(&self) -> &'static str {
+        "unknown vendor"
+    }
+
+    fn matches(&self, _id: JedecId) -> bool {
+        true
+    }
+
+    fn size_from_id(&self, _id: JedecId) -> Option<u32> {
+        None
+    }
+}
+
+/// Every driver, tried in order. `Generic` matches anything, so it has to
+/// come last.
+static DRIVERS: &[&dyn FlashChip] = &[&Macronix, &Winbond, &Issi, &Micron, &Generic];
+
+/// What the JEDEC basic flash parameter table and the 4-byte address
+/// instruction table say about a chip
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sfdp {
+    /// Size of the flash, in bytes
+    pub size: u32,
+
+    /// The size and command of each kind of erase, using 3-byte addresses,
+    /// along with which of the four SFDP erase types it is, counting from 0
+    pub erase_types: Vec<(u32, u8, usize)>,
+
+    /// The 4-byte address command for each SFDP erase type, or 0 if it has
+    /// none, if the chip has a table of them
+    pub erase_types_4b: Option<[u8; 4]>,
+}
+
+/// "SFDP", which starts the SFDP header
+pub const SFDP_SIGNATURE: u32 = 0x5044_4653;
+
+/// Parameter table IDs
+const BASIC_TABLE: u16 = 0xff00;
+const FOUR_BYTE_TABLE: u16 = 0xff84;
+
+/// The `n`th dword of `data`
+fn dword(data: &[u8], n: usize) -> Option<u32> {
+    data.get(n * 4..n * 4 + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+impl Sfdp {
+    /// Find the parameter tables listed in `header`, which holds the SFDP
+    /// header and the parameter headers after it, returning the ID, offset,
+    /// and length in bytes of each.
+    pub fn tables(header: &[u8]) -> Option<Vec<(u16, u32, u32)>> {
+        if dword(header, 0)? != SFDP_SIGNATURE {
+            return None;
+        }
+        let count = (dword(header, 1)? >> 16 & 0xff) as usize + 1;
+        (0..count)
+            .map(|i| {
+                let first = dword(header, 2 + i * 2)?;
+                let second = dword(header, 3 + i * 2)?;
+                let id = ((second >> 16) & 0xff00 | first & 0xff) as u16;
+                Some((id, second & 0x00ff_ffff, (first >> 24) * 4))
+            })
+            .collect()
+    }
+
+    /// Parse the tables found with `tables()`. `read` returns `length`
+    /// bytes of the SFDP space at `offset`.
+    pub fn parse<E, F: FnMut(u32, u32) -> Result<Vec<u8>, E>>(
+        tables: &[(u16, u32, u32)],
+        mut read: F,
+    ) -> Result<Option<Sfdp>, E> {
+        let mut sfdp = match tables.iter().find(|t| t.0 == BASIC_TABLE) {
+            Some(&(_, offset, length)) => match Self::parse_basic(&read(offset, length)?) {
+                Some(sfdp) => sfdp,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        if let Some(&(_, offset, length)) = tables.iter().find(|t| t.0 == FOUR_BYTE_TABLE) {
+            let table = read(offset, length)?;
+            if let (Some(supported), Some(opcodes)) = (dword(&table, 0), dword(&table, 1)) {
+                let mut erase_types_4b = [0; 4];
+                for (i, opcode) in erase_types_4b.iter_mut().enumerate() {
+                    if supported & (1 << (9 + i)) != 0 {
+                        *opcode = (opcodes >> (i * 8)) as u8;
+                    }
+                }
+                sfdp.erase_types_4b = Some(erase_types_4b);
+            }
+        }
+        Ok(Some(sfdp))
+    }
+
+    /// Parse the JEDEC basic flash parameter table
+    fn parse_basic(table: &[u8]) -> Option<Sfdp> {
+        let density = dword(table, 1)?;
+        let bits = if density & 0x8000_0000 == 0 {
+            density as u64 + 1
+        } else {
+            1u64.checked_shl(density & 0x7fff_ffff)?
+        };
+        let size = (bits / 8).try_into().ok()?;
+
+        let mut erase_types = vec![];
+        for n in [7, 8] {
+            if let Some(types) = dword(table, n) {
+                let halves = [types & 0xffff, types >> 16];
+                for (&half, erase_type) in halves.iter().zip((n - 7) * 2..) {
+                    let (size, opcode) = (half & 0xff, (half >> 8) as u8);
+                    if size != 0 && size < 32 {
+                        erase_types.push((1 << size, opcode, erase_type));
+                    }
+                }
+            }
+        }
+        // Tables from before JESD216 only have the 4 kiB erase, which takes
+        // the place of the first erase type
+        if erase_types.is_empty() && dword(table, 0)? & 0x3 == 0x1 {
+            erase_types.push((4096, (dword(table, 0)? >> 8) as u8, 0));
+        }
+
+        Some(Sfdp {
+            size,
+            erase_types,
+            erase_types_4b: None,
+        })
+    }
+
+    /// The 3-byte address command for erasing `size` bytes, if there is one
+    fn erase_3b(&self, size: u32) -> Option<u8> {
+        self.erase_types.iter().find(|e| e.0 == size).map(|e| e.1)
+    }
+
+    /// The 4-byte address command for erasing `size` bytes, if there is one
+    fn erase_4b(&self, size: u32) -> Option<u8> {
+        let &(_, opcode, erase_type) = self.erase_types.iter().find(|e| e.0 == size)?;
+        match &self.erase_types_4b {
+            Some(opcodes) => Some(opcodes[erase_type]).filter(|&op| op != 0),
+            // Without the table, go by the usual pairings
+            None => match opcode {
+                0x20 => Some(0x21),
+                0xd8 => Some(0xdc),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// A flash chip that's been identified, and the commands it takes. The
+/// commands all take addresses `address_bytes` long.
+#[derive(Clone)]
+pub struct Chip {
+    pub id: JedecId,
+
+    /// Size of the flash, in bytes
+    pub size: u32,
+
+    /// Either 3, or 4 for chips over 16 MiB and for `spinor`, which always
+    /// sends four
+    pub address_bytes: usize,
+
+    /// Erases a 4 kiB sector
+    pub sector_erase: u8,
+
+    /// Erases a 64 kiB block
+    pub block_erase: u8,
+
+    /// Programs up to a page
+    pub page_program: u8,
+
+    /// Whether the chip had SFDP tables
+    pub sfdp: bool,
+
+    driver: &'static dyn FlashChip,
+}
+
+impl Chip {
+    /// Pick the driver for the chip with the ID `id`, filling in what it
+    /// doesn't know from `sfdp`. Four-byte addresses are used if the chip
+    /// needs them or `four_byte` is set. Returns `None` if the chip is
+    /// unknown and has no SFDP tables to go by.
+    pub fn identify(id: JedecId, sfdp: Option<&Sfdp>, four_byte: bool) -> Option<Chip> {
+        let driver = *DRIVERS.iter().find(|d| d.matches(id))?;
+        let size = sfdp.map(|s| s.size).or_else(|| driver.size_from_id(id))?;
+        let chip = |address_bytes, erase: fn(&Sfdp, u32) -> Option<u8>, ops: [u8; 3]| Chip {
+            id,
+            size,
+            address_bytes,
+            sector_erase: sfdp.and_then(|s| erase(s, 4096)).unwrap_or(ops[0]),
+            block_erase: sfdp.and_then(|s| erase(s, 65536)).unwrap_or(ops[1]),
+            page_program: ops[2],
+            sfdp: sfdp.is_some(),
+            driver,
+        };
+        if four_byte || size > 1 << 24 {
+            Some(chip(4, Sfdp::erase_4b, [0x21, 0xdc, 0x12]))
+        } else {
+            Some(chip(3, Sfdp::erase_3b, [0x20, 0xd8, 0x02]))
+        }
+    }
+
+    pub fn vendor(&self) -> &'static str {
+        self.driver.vendor()
+    }
+
+    pub fn error_register(&self) -> Option<ErrorRegister> {
+        self.driver.error_register()
+    }
+
+    pub fn protect_mask(&self) -> u32 {
+        self.driver.protect_mask()
+    }
+}
+
+impl fmt::Debug for Chip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Chip")
+            .field("vendor", &self.vendor())
+            .field("id", &self.id)
+            .field("size", &self.size)
+            .field("address_bytes", &self.address_bytes)
+            .field("sector_erase", &self.sector_erase)
+            .field("block_erase", &self.block_erase)
+            .field("page_program", &self.page_program)
+            .field("sfdp", &self.sfdp)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dwords(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    /// A 32 MiB chip whose first erase type is unused, so that 4 kiB is
+    /// type 2 and 64 kiB is type 3
+    fn sfdp_with_gap(four_byte_table: bool) -> Sfdp {
+        let mut basic = vec![0; 9];
+        basic[1] = (256 << 20) - 1;
+        basic[7] = (0x20 << 8 | 12) << 16;
+        basic[8] = 0xd8 << 8 | 16;
+        let basic = dwords(&basic);
+        // Types 2 and 3 have 4-byte commands
+        let four_byte = dwords(&[1 << 10 | 1 << 11, 0x00dc_2100]);
+
+        let mut tables = vec![(BASIC_TABLE, 0x100, basic.len() as u32)];
+        if four_byte_table {
+            tables.push((FOUR_BYTE_TABLE, 0x200, four_byte.len() as u32));
+        }
+        Sfdp::parse::<(), _>(&tables, |offset, _| {
+            Ok(if offset == 0x100 {
+                basic.clone()
+            } else {
+                four_byte.clone()
+            })
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn erase_types_keep_their_sfdp_numbers() {
+        let sfdp = sfdp_with_gap(true);
+        assert_eq!(sfdp.size, 32 << 20);
+        assert_eq!(sfdp.erase_types, vec![(4096, 0x20, 1), (65536, 0xd8, 2)]);
+        assert_eq!(sfdp.erase_3b(4096), Some(0x20));
+        assert_eq!(sfdp.erase_4b(4096), Some(0x21));
+        assert_eq!(sfdp.erase_4b(65536), Some(0xdc));
+    }
+
+    #[test]
+    fn four_byte_erases_default_to_the_usual_pairings() {
+        let sfdp = sfdp_with_gap(false);
+        assert_eq!(sfdp.erase_4b(4096), Some(0x21));
+        assert_eq!(sfdp.erase_4b(65536), Some(0xdc));
+        assert_eq!(sfdp.erase_4b(32768), None);
+    }
+}
@@ -0,0 +1,121 @@
+use super::bitstream::{self, Bitstream, FpgaFamily};
+use super::{flash_images, read_flash, ServerError, SpiNor};
+use crate::config::Config;
+
+use tracing::{error, info};
+use wishbone_bridge::Bridge;
+
+/// Enough of the flash to hold an iCE40 multiboot header
+const ICE40_HEADER_LEN: u32 = 5 * 32;
+
+/// The top six bits of `reboot_ctrl` have to hold this before `SB_WARMBOOT`
+/// is triggered, with the image to boot in the bottom two
+const WARMBOOT_KEY: u32 = 0xac;
+
+/// Configuration registers and commands written through a Xilinx ICAP
+const ICAP_WBSTAR: u32 = 0x10;
+const ICAP_CMD: u32 = 0x04;
+const ICAP_IPROG: u32 = 0x0f;
+
+/// Write the bitstream in `cfg.load_name` to the configuration flash, at
+/// `cfg.load_addr` or the start of the flash, then reboot the FPGA into it
+/// if `cfg.fpga_reboot` is set.
+pub fn fpga_program(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let file_name = match &cfg.load_name {
+        Some(file_name) => file_name,
+        None => return Ok(()),
+    };
+    let data = std::fs::read(file_name)?;
+    let bitstream = Bitstream::parse(&data).ok_or_else(|| {
+        ServerError::BitstreamError(format!("{} doesn't look like a bitstream", file_name))
+    })?;
+    let offset = cfg.load_addr.unwrap_or(0);
+    let image = warmboot_image(cfg, &bridge, offset)?;
+
+    let cfg = Config {
+        load_addr: Some(offset),
+        // The FPGA reboot resets the CPU along with everything else
+        flash_no_reset: cfg.flash_no_reset || cfg.fpga_reboot,
+        ..cfg.clone()
+    };
+    if !flash_images(&cfg, bridge.clone())? {
+        if cfg.fpga_reboot {
+            error!("Not rebooting the FPGA, since the bitstream didn't verify");
+        }
+        return Err(ServerError::FlashFailed(offset));
+    }
+
+    if cfg.fpga_reboot {
+        reboot(&cfg, &bridge, bitstream.family, offset, image.unwrap_or(0))?;
+    }
+    Ok(())
+}
+
+/// Which of the warm boot images listed in the flash's iCE40 multiboot
+/// header is at `offset`, if there's a header. An iCE40 only boots images
+/// that the header points to, so a bitstream anywhere else is refused.
+pub(super) fn warmboot_image(
+    cfg: &Config,
+    bridge: &Bridge,
+    offset: u32,
+) -> Result<Option<usize>, ServerError> {
+    let flash = SpiNor::from_config(cfg)?;
+    flash.finish(bridge)?;
+    let header = read_flash(bridge, &flash, 0, ICE40_HEADER_LEN)?;
+    let images = match bitstream::ice40_multiboot_images(&header) {
+        Some(images) => images,
+        None => return Ok(None),
+    };
+    match images[1..].iter().position(|&addr| addr == offset) {
+        Some(image) => {
+            info!("Writing warm boot image {} at 0x{:08x}", image, offset);
+            Ok(Some(image))
+        }
+        None => Err(ServerError::BitstreamError(format!(
+            "the flash has an iCE40 multiboot header, and 0x{:08x} isn't one of its images ({})",
+            offset,
+            images[1..]
+                .iter()
+                .map(|addr| format!("0x{:08x}", addr))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+/// Have the FPGA load the bitstream at `offset` in the flash, which is
+/// warm boot image `image` on an iCE40.
+pub(super) fn reboot(
+    cfg: &Config,
+    bridge: &Bridge,
+    family: FpgaFamily,
+    offset: u32,
+    image: usize,
+) -> Result<(), ServerError> {
+    let register = |name: &str| cfg.register_mapping.get(name).copied().flatten();
+    if let Some(reboot_ctrl) = register("reboot_ctrl") {
+        info!("Warm booting image {}", image);
+        // The bridge goes away with the old gateware, so the write may
+        // never be acknowledged
+        bridge.poke(reboot_ctrl, WARMBOOT_KEY | image as u32).ok();
+        return Ok(());
+    }
+    if let (Some(addr), Some(data), Some(send)) = (
+        register("icap_addr"),
+        register("icap_data"),
+        register("icap_send"),
+    ) {
+        info!("Rebooting the FPGA from 0x{:08x} through the ICAP", offset);
+        bridge.poke(addr, ICAP_WBSTAR)?;
+        bridge.poke(data, offset)?;
+        bridge.poke(send, 1)?;
+        bridge.poke(addr, ICAP_CMD)?;
+        bridge.poke(data, ICAP_IPROG)?;
+        bridge.poke(send, 1).ok();
+        return Ok(());
+    }
+    Err(ServerError::UnmappableAddress(format!(
+        "reboot_ctrl or icap_send, to reboot the {}",
+        family.name()
+    )))
+}
@@ -0,0 +1,83 @@
+use tracing::warn;
+use wishbone_bridge::MemoryRegion;
+
+/// Regions that a user-initiated write should stay out of, because
+/// changing them would cut the session off from the device. This covers
+/// the VexRiscv debug window as well as the registers of the peripheral
+/// that the bridge itself talks through, such as the Ethernet MAC.
+#[derive(Debug, Clone, Default)]
+pub struct WriteGuard {
+    regions: Vec<MemoryRegion>,
+    force: bool,
+}
+
+impl WriteGuard {
+    /// Protect `regions`. If `force` is set, writes to them only produce a
+    /// warning rather than being refused.
+    pub fn new(regions: Vec<MemoryRegion>, force: bool) -> WriteGuard {
+        WriteGuard { regions, force }
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    /// Check a write of `length` bytes at `addr`. Returns the region that
+    /// would be hit if the write should be refused.
+    pub fn check(&self, addr: u32, length: u32) -> Result<(), &MemoryRegion> {
+        let region = match self.regions.iter().find(|r| r.overlaps(addr, length)) {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+        if !self.force {
+            return Err(region);
+        }
+        warn!(
+            "writing to 0x{:08x}, which is part of {} -- this may wedge the bridge",
+            addr, region.name
+        );
+        Ok(())
+    }
+}
+
+/// What clients of the network servers (Wishbone, HTTP and GDB) may touch,
+/// for sharing a board with people who shouldn't be able to change it.
+/// Unlike `WriteGuard`, `--force` doesn't get around this.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    read_only: bool,
+
+    /// If not empty, the only places clients may read or write
+    allowed: Vec<MemoryRegion>,
+}
+
+impl AccessPolicy {
+    pub fn new(read_only: bool, allowed: Vec<MemoryRegion>) -> AccessPolicy {
+        AccessPolicy { read_only, allowed }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Check a read of `length` bytes at `addr`, returning the reason if it
+    /// should be refused.
+    pub fn check_read(&self, addr: u32, length: u32) -> Result<(), String> {
+        if self.allowed.is_empty() || self.allowed.iter().any(|r| r.contains(addr, length)) {
+            return Ok(());
+        }
+        Err(format!(
+            "0x{:08x} is outside of the ranges given with --allow-range",
+            addr
+        ))
+    }
+
+    /// Check a write of `length` bytes at `addr`, returning the reason if it
+    /// should be refused.
+    pub fn check_write(&self, addr: u32, length: u32) -> Result<(), String> {
+        if self.read_only {
+            return Err("the server is read-only".to_owned());
+        }
+        self.check_read(addr, length)
+    }
+}
@@ -0,0 +1,73 @@
+#[cfg(feature = "gdb")]
+use wishbone_bridge::{Bridge, BridgeError};
+
+use std::time::Duration;
+#[cfg(feature = "gdb")]
+use std::time::Instant;
+
+/// A counter that firmware increments while it's making progress, such as
+/// a scratch CSR bumped from the main loop or a timer interrupt.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    /// Address of the counter
+    pub addr: u32,
+
+    /// How long the counter may stay the same before the firmware is
+    /// considered stalled
+    pub timeout: Duration,
+
+    /// Halt the CPU when a stall is detected
+    pub halt: bool,
+}
+
+/// Tracks how long a `Heartbeat` has been stuck. The clock only runs while
+/// the CPU is meant to be running, so time spent halted in the debugger
+/// doesn't count towards a stall.
+#[cfg(feature = "gdb")]
+pub(crate) struct HeartbeatMonitor {
+    heartbeat: Heartbeat,
+    last_value: Option<u32>,
+    last_change: Instant,
+    alarmed: bool,
+}
+
+#[cfg(feature = "gdb")]
+impl HeartbeatMonitor {
+    pub fn new(heartbeat: Heartbeat) -> HeartbeatMonitor {
+        HeartbeatMonitor {
+            heartbeat,
+            last_value: None,
+            last_change: Instant::now(),
+            alarmed: false,
+        }
+    }
+
+    pub fn heartbeat(&self) -> &Heartbeat {
+        &self.heartbeat
+    }
+
+    /// Forget the last value seen, e.g. because the CPU was halted.
+    pub fn reset(&mut self) {
+        self.last_value = None;
+        self.alarmed = false;
+    }
+
+    /// Sample the counter. Returns the stuck value and how long it has been
+    /// stuck the first time the timeout is exceeded, and `None` otherwise.
+    /// Once the counter moves again, another stall will be reported.
+    pub fn check(&mut self, bridge: &Bridge) -> Result<Option<(u32, Duration)>, BridgeError> {
+        let value = bridge.peek(self.heartbeat.addr)?;
+        if self.last_value != Some(value) {
+            self.last_value = Some(value);
+            self.last_change = Instant::now();
+            self.alarmed = false;
+            return Ok(None);
+        }
+        let stuck_for = self.last_change.elapsed();
+        if self.alarmed || stuck_for < self.heartbeat.timeout {
+            return Ok(None);
+        }
+        self.alarmed = true;
+        Ok(Some((value, stuck_for)))
+    }
+}
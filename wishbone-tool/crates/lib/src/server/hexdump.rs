@@ -0,0 +1,147 @@
+use super::{shutdown, ServerError};
+use crate::config::Config;
+
+use tracing::info;
+use wishbone_bridge::MappedBridge;
+
+use std::io::{self, IsTerminal, Write};
+use std::thread;
+use std::time::Duration;
+
+/// Bytes shown on each line
+const ROW_LEN: usize = 16;
+
+const HIGHLIGHT: &str = "\x1b[1;31m";
+const NORMAL: &str = "\x1b[0m";
+
+/// Format `data`, read from `addr`, as lines of hex followed by the same
+/// bytes as ASCII. Bytes that weren't read, such as read-sensitive
+/// registers, show as `--`. Where `changed` is `true`, the byte is
+/// highlighted.
+pub(crate) fn hexdump(addr: u32, data: &[Option<u8>], changed: &[bool]) -> Vec<String> {
+    data.chunks(ROW_LEN)
+        .enumerate()
+        .map(|(row, bytes)| {
+            let start = row * ROW_LEN;
+            let is_changed = |i: usize| changed.get(start + i).copied().unwrap_or(false);
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for (i, byte) in bytes.iter().enumerate() {
+                let (h, a) = match byte {
+                    Some(b) if b.is_ascii_graphic() || *b == b' ' => {
+                        (format!("{:02x}", b), *b as char)
+                    }
+                    Some(b) => (format!("{:02x}", b), '.'),
+                    None => ("--".to_owned(), ' '),
+                };
+                if is_changed(i) {
+                    hex.push_str(&format!("{}{}{} ", HIGHLIGHT, h, NORMAL));
+                    ascii.push_str(&format!("{}{}{}", HIGHLIGHT, a, NORMAL));
+                } else {
+                    hex.push_str(&format!("{} ", h));
+                    ascii.push(a);
+                }
+            }
+            // Line up the ASCII column on a short last line
+            let padding = "   ".repeat(ROW_LEN - bytes.len());
+            format!(
+                "{:08x}: {}{} |{}|",
+                addr.wrapping_add(start as u32),
+                hex,
+                padding,
+                ascii
+            )
+        })
+        .collect()
+}
+
+/// Read `cfg.burst_length` bytes from `addr` every `interval`, redrawing the
+/// hexdump in place and highlighting the bytes that changed since the last
+/// read, until interrupted.
+pub(crate) fn watch(
+    cfg: &Config,
+    bridge: &MappedBridge,
+    addr: u32,
+    interval: Duration,
+) -> Result<(), ServerError> {
+    let mut previous: Option<Vec<Option<u8>>> = None;
+    let mut stdout = io::stdout();
+    while !shutdown::requested() {
+        let data = bridge.burst_read_skip_sensitive(addr, cfg.burst_length)?;
+        let changed: Vec<bool> = match &previous {
+            Some(previous) => data.iter().zip(previous).map(|(a, b)| a != b).collect(),
+            None => vec![],
+        };
+        let lines = hexdump(addr, &data, &changed);
+        if previous.is_some() {
+            // Go back up to the top of the last dump
+            write!(stdout, "\x1b[{}A", lines.len())?;
+        }
+        for line in lines {
+            writeln!(stdout, "{}", line)?;
+        }
+        stdout.flush()?;
+        previous = Some(data);
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+/// Compare memory at `addr` with `file`, showing each line that differs as
+/// it is in the file (`-`) and in memory (`+`).
+pub(crate) fn diff(
+    cfg: &Config,
+    bridge: &MappedBridge,
+    addr: u32,
+    file: &str,
+) -> Result<(), ServerError> {
+    let expected = std::fs::read(file)?;
+    let mut live = bridge.burst_read_skip_sensitive(addr, cfg.burst_length)?;
+    let unchecked = live.len().saturating_sub(expected.len());
+    live.truncate(expected.len());
+    let expected: Vec<Option<u8>> = expected.into_iter().map(Some).take(live.len()).collect();
+
+    // There's no telling whether a byte that wasn't read has changed
+    let changed: Vec<bool> = live
+        .iter()
+        .zip(&expected)
+        .map(|(l, e)| l.is_some() && l != e)
+        .collect();
+    let color = io::stdout().is_terminal();
+    let plain = |changed: &[bool]| if color { changed.to_vec() } else { vec![] };
+
+    let mut differing = 0;
+    let expected_lines = hexdump(addr, &expected, &plain(&changed));
+    let live_lines = hexdump(addr, &live, &plain(&changed));
+    for (row, (expected_line, live_line)) in expected_lines.iter().zip(&live_lines).enumerate() {
+        let start = row * ROW_LEN;
+        let end = (start + ROW_LEN).min(changed.len());
+        let count = changed[start..end].iter().filter(|c| **c).count();
+        if count > 0 {
+            differing += count;
+            println!("-{}", expected_line);
+            println!("+{}", live_line);
+        }
+    }
+
+    if unchecked >= 4 {
+        info!(
+            "{} is only {} bytes, so the last {} bytes weren't compared",
+            file,
+            expected.len(),
+            unchecked
+        );
+    }
+    if differing == 0 {
+        info!("memory at {:08x} matches {}", addr, file);
+    } else {
+        info!(
+            "{} of {} bytes at {:08x} differ from {}",
+            differing,
+            expected.len(),
+            addr,
+            file
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,438 @@
+//! Just enough of an HTTP/1.1 server to read and write CSRs and memory
+//! with `curl`, a dashboard, or a CI script:
+//!
+//! * `GET /csr` lists every name from csr.csv along with its address
+//! * `GET /csr/NAME` reads a CSR, and `PUT /csr/NAME` writes the number in
+//!   the request body to it
+//! * `GET /mem/ADDR` reads a word, and `GET /mem/ADDR?len=N` reads `N`
+//!   bytes at once
+//! * `PUT /mem/ADDR` writes the number in the body as a word, or writes the
+//!   body as-is if it's sent as `application/octet-stream`
+//!
+//! Responses are JSON, or CBOR if the `Accept` header asks for it.
+
+use super::{check_write, ApiValue, Encoding, ServerError};
+use crate::config::{parse_u32, Config};
+
+use tracing::{debug, info};
+use wishbone_bridge::{Bridge, BridgeError, MappedBridge};
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+/// Longest request line or header that will be accepted
+const MAX_HEADER_LEN: usize = 8192;
+
+/// Largest request body, or burst read, that will be accepted
+const MAX_BODY_LEN: u32 = 1024 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    accept: Option<String>,
+    content_type: Option<String>,
+    keep_alive: bool,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn query(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A failed request, with the HTTP status to send back
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: String) -> HttpError {
+        HttpError { status, message }
+    }
+}
+
+impl From<BridgeError> for HttpError {
+    fn from(e: BridgeError) -> HttpError {
+        let status = match e {
+            BridgeError::UnmappedAddress(_) | BridgeError::AccessDenied(_) => 403,
+            _ => 502,
+        };
+        HttpError::new(status, e.to_string())
+    }
+}
+
+impl From<ServerError> for HttpError {
+    fn from(e: ServerError) -> HttpError {
+        match e {
+            ServerError::ProtectedWrite(addr, region) => HttpError::new(
+                403,
+                format!(
+                    "0x{:08x} is part of {}, and writing it may wedge the bridge",
+                    addr, region
+                ),
+            ),
+            ServerError::BridgeError(e) => e.into(),
+            e => HttpError::new(500, format!("{:?}", e)),
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        502 => "Bad Gateway",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Read a line of the request head, without its line ending. Returns
+/// `None` if the client closed the connection first.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = vec![];
+    let len = reader
+        .by_ref()
+        .take(MAX_HEADER_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request header too long",
+        ));
+    }
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Read the next request on the connection, or `None` once the client has
+/// closed it.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let request_line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) => (m.to_owned(), t.to_owned(), v.to_owned()),
+        _ => return Err(invalid("malformed request line")),
+    };
+
+    let mut request = Request {
+        method,
+        path: String::new(),
+        query: vec![],
+        accept: None,
+        content_type: None,
+        keep_alive: version != "HTTP/1.0",
+        body: vec![],
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target.as_str(), ""),
+    };
+    request.path = path.to_owned();
+    request.query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((k, v)) => (k.to_owned(), v.to_owned()),
+            None => (pair.to_owned(), String::new()),
+        })
+        .collect();
+
+    let mut content_length = 0;
+    loop {
+        let line = read_line(reader)?.ok_or_else(|| invalid("connection closed mid-request"))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => return Err(invalid("malformed header")),
+        };
+        match name.as_str() {
+            "accept" => request.accept = Some(value.to_owned()),
+            "content-type" => request.content_type = Some(value.to_ascii_lowercase()),
+            "content-length" => {
+                content_length = value
+                    .parse::<u32>()
+                    .map_err(|_| invalid("bad content-length"))?;
+                if content_length > MAX_BODY_LEN {
+                    return Err(invalid("request body too large"));
+                }
+            }
+            "connection" => match value.to_ascii_lowercase().as_str() {
+                "close" => request.keep_alive = false,
+                "keep-alive" => request.keep_alive = true,
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    request.body = vec![0; content_length as usize];
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+fn write_response<W: Write>(
+    writer: &mut W,
+    status: u16,
+    encoding: Encoding,
+    value: &ApiValue,
+    keep_alive: bool,
+) -> io::Result<()> {
+    let body = encoding.encode(value);
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        status,
+        reason(status),
+        encoding.content_type(),
+        body.len(),
+        if keep_alive { "keep-alive" } else { "close" }
+    );
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()
+}
+
+fn parse_number(what: &str, value: &str) -> Result<u32, HttpError> {
+    parse_u32(value.trim()).map_err(|_| HttpError::new(400, format!("invalid {}: {}", what, value)))
+}
+
+/// The number in a request body, for writing a single word
+fn body_value(request: &Request) -> Result<u32, HttpError> {
+    let body = String::from_utf8_lossy(&request.body);
+    parse_number("value", &body)
+}
+
+fn word_response(addr: u32, value: u32) -> Vec<(String, ApiValue)> {
+    vec![
+        ("addr".to_owned(), ApiValue::Uint(u64::from(addr))),
+        ("value".to_owned(), ApiValue::Uint(u64::from(value))),
+    ]
+}
+
+struct HttpServer {
+    cfg: Config,
+    bridge: MappedBridge,
+}
+
+impl HttpServer {
+    fn handle(&self, request: &Request) -> Result<ApiValue, HttpError> {
+        let segments: Vec<&str> = request
+            .path
+            .trim_matches('/')
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+        let result = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["csr"]) => Ok(self.list_csrs()),
+            ("GET", ["csr", name]) => self.read_csr(name),
+            ("PUT", ["csr", name]) | ("POST", ["csr", name]) => self.write_csr(name, request),
+            ("GET", ["mem", addr]) => self.read_mem(parse_number("address", addr)?, request),
+            ("PUT", ["mem", addr]) | ("POST", ["mem", addr]) => {
+                self.write_mem(parse_number("address", addr)?, request)
+            }
+            (_, ["csr"]) | (_, ["csr", _]) | (_, ["mem", _]) => Err(HttpError::new(
+                405,
+                format!("{} isn't supported on {}", request.method, request.path),
+            )),
+            _ => Err(HttpError::new(
+                404,
+                format!("no endpoint at {}", request.path),
+            )),
+        };
+        if let Some(watch) = &self.cfg.bus_errors {
+            watch.after_batch(self.bridge.bridge());
+        }
+        result
+    }
+
+    fn list_csrs(&self) -> ApiValue {
+        let mut names: Vec<(&String, &u32)> = self
+            .cfg
+            .register_mapping
+            .iter()
+            .filter_map(|(name, addr)| addr.as_ref().map(|addr| (name, addr)))
+            .collect();
+        names.sort();
+        ApiValue::Map(
+            names
+                .into_iter()
+                .map(|(name, addr)| (name.clone(), ApiValue::Uint(u64::from(*addr))))
+                .collect(),
+        )
+    }
+
+    fn csr_address(&self, name: &str) -> Result<u32, HttpError> {
+        let name = name.to_lowercase();
+        match self.cfg.register_mapping.get(&name) {
+            Some(Some(addr)) => Ok(*addr),
+            Some(None) => Err(HttpError::new(
+                404,
+                format!("{} is outside the bridge's address space", name),
+            )),
+            None => Err(HttpError::new(
+                404,
+                format!("no csr called {} in csr.csv", name),
+            )),
+        }
+    }
+
+    /// Refuse a read that `--allow-range` doesn't cover.
+    fn check_read(&self, addr: u32, length: u32) -> Result<(), HttpError> {
+        self.cfg
+            .network_access
+            .check_read(addr, length)
+            .map_err(|why| HttpError::new(403, why))
+    }
+
+    /// Refuse a write to a protected region, or one that `--read-only` or
+    /// `--allow-range` doesn't allow.
+    fn check_write(&self, addr: u32, length: u32) -> Result<(), HttpError> {
+        check_write(&self.cfg, addr, length)?;
+        self.cfg
+            .network_access
+            .check_write(addr, length)
+            .map_err(|why| HttpError::new(403, why))
+    }
+
+    fn read_csr(&self, name: &str) -> Result<ApiValue, HttpError> {
+        let addr = self.csr_address(name)?;
+        self.check_read(addr, 4)?;
+        let value = self.bridge.peek(addr)?;
+        let mut response = vec![("name".to_owned(), ApiValue::Text(name.to_lowercase()))];
+        response.extend(word_response(addr, value));
+        Ok(ApiValue::Map(response))
+    }
+
+    fn write_csr(&self, name: &str, request: &Request) -> Result<ApiValue, HttpError> {
+        let addr = self.csr_address(name)?;
+        let value = body_value(request)?;
+        self.check_write(addr, 4)?;
+        self.bridge.poke(addr, value)?;
+        let mut response = vec![("name".to_owned(), ApiValue::Text(name.to_lowercase()))];
+        response.extend(word_response(addr, value));
+        Ok(ApiValue::Map(response))
+    }
+
+    /// Read a word, or `len` bytes. Read-sensitive registers, such as UART
+    /// FIFOs, are left out of a longer read, and come back as zero.
+    fn read_mem(&self, addr: u32, request: &Request) -> Result<ApiValue, HttpError> {
+        let len = match request.query("len") {
+            Some(len) => parse_number("length", len)?,
+            None => {
+                self.check_read(addr, 4)?;
+                return Ok(ApiValue::Map(word_response(addr, self.bridge.peek(addr)?)));
+            }
+        };
+        if len > MAX_BODY_LEN {
+            return Err(HttpError::new(
+                413,
+                format!("can't read more than {} bytes at once", MAX_BODY_LEN),
+            ));
+        }
+        self.check_read(addr, len)?;
+        let data = self.bridge.burst_read_skip_sensitive(addr, len)?;
+        let skipped = data.iter().filter(|b| b.is_none()).count();
+        Ok(ApiValue::Map(vec![
+            ("addr".to_owned(), ApiValue::Uint(u64::from(addr))),
+            (
+                "data".to_owned(),
+                ApiValue::Bytes(data.into_iter().map(|b| b.unwrap_or(0)).collect()),
+            ),
+            ("skipped".to_owned(), ApiValue::Uint(skipped as u64)),
+        ]))
+    }
+
+    fn write_mem(&self, addr: u32, request: &Request) -> Result<ApiValue, HttpError> {
+        if request.content_type.as_deref() == Some("application/octet-stream") {
+            self.check_write(addr, request.body.len() as u32)?;
+            self.bridge.burst_write(addr, &request.body)?;
+            return Ok(ApiValue::Map(vec![
+                ("addr".to_owned(), ApiValue::Uint(u64::from(addr))),
+                ("len".to_owned(), ApiValue::Uint(request.body.len() as u64)),
+            ]));
+        }
+        let value = body_value(request)?;
+        self.check_write(addr, 4)?;
+        self.bridge.poke(addr, value)?;
+        Ok(ApiValue::Map(word_response(addr, value)))
+    }
+
+    fn serve(&self, connection: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        let mut writer = connection.try_clone()?;
+        let mut reader = BufReader::new(connection);
+        loop {
+            let request = match read_request(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let value =
+                        ApiValue::Map(vec![("error".to_owned(), ApiValue::Text(e.to_string()))]);
+                    return write_response(&mut writer, 400, Encoding::Json, &value, false);
+                }
+                Err(e) => return Err(e),
+            };
+            debug!("{} {} {}", peer, request.method, request.path);
+            let encoding = Encoding::from_accept(request.accept.as_deref());
+            let (status, value) = match self.handle(&request) {
+                Ok(value) => (200, value),
+                Err(e) => (
+                    e.status,
+                    ApiValue::Map(vec![("error".to_owned(), ApiValue::Text(e.message))]),
+                ),
+            };
+            write_response(&mut writer, status, encoding, &value, request.keep_alive)?;
+            if !request.keep_alive {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Serve CSR and memory access over HTTP on `--http-port`, with a thread
+/// for each connection.
+pub fn http_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let mut mapped = MappedBridge::new(bridge);
+    mapped
+        .regions(cfg.memory_regions.iter().cloned())
+        .policy(cfg.region_policy);
+    let server = Arc::new(HttpServer {
+        cfg: cfg.clone(),
+        bridge: mapped,
+    });
+
+    let listener = TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.http_port))?;
+    info!(
+        "accepting http connections on http://{}:{}/",
+        cfg.bind_addr, cfg.http_port
+    );
+    loop {
+        let (connection, peer) = listener.accept()?;
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.serve(connection, peer) {
+                debug!("http connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,55 @@
+use crate::config::{Config, InitStep};
+use crate::server::{check_write, ServerError};
+
+use tracing::info;
+use wishbone_bridge::Bridge;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Run the `[init]` steps from the config file, in order. This happens
+/// right after the bridge connects, before any servers are started.
+pub fn run_init_steps(cfg: &Config, bridge: &Bridge) -> Result<(), ServerError> {
+    for step in &cfg.init_steps {
+        if let InitStep::Write { addr, .. } = step {
+            check_write(cfg, *addr, 4)?;
+        }
+    }
+    for step in &cfg.init_steps {
+        match step {
+            InitStep::Write { name, addr, value } => {
+                info!("init: writing 0x{:08x} to {}", value, name);
+                bridge.poke(*addr, *value)?;
+            }
+            InitStep::Delay(delay) => {
+                info!("init: waiting {} ms", delay.as_millis());
+                thread::sleep(*delay);
+            }
+            InitStep::Assert {
+                name,
+                addr,
+                value,
+                mask,
+                timeout,
+            } => {
+                let start = Instant::now();
+                loop {
+                    let observed = bridge.peek(*addr)?;
+                    if observed & mask == *value {
+                        info!("init: {} is 0x{:08x}", name, observed);
+                        break;
+                    }
+                    if start.elapsed() >= *timeout {
+                        return Err(ServerError::InitAssertFailed(
+                            name.clone(),
+                            *value,
+                            observed & mask,
+                        ));
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use super::{print_json, ServerError};
+use crate::config::{Config, OutputFormat};
+
+use serde_json::json;
+use wishbone_bridge::{Bridge, RegionAccess};
+
+/// Print every CSR and memory region in the register map, in address order.
+/// Nothing is read from the target.
+pub fn list(cfg: &Config, _bridge: Bridge) -> Result<(), ServerError> {
+    if cfg.memory_regions.is_empty() {
+        return Err(ServerError::NoRegisterMap);
+    }
+    let mut regions: Vec<_> = cfg.memory_regions.iter().collect();
+    regions.sort_by(|a, b| (a.base, &a.name).cmp(&(b.base, &b.name)));
+    let access = |access: &RegionAccess| match access {
+        RegionAccess::ReadOnly => "ro",
+        RegionAccess::WriteOnly => "wo",
+        RegionAccess::ReadWrite => "rw",
+    };
+    if cfg.output == OutputFormat::Json {
+        let regions: Vec<_> = regions
+            .iter()
+            .map(|region| {
+                json!({
+                    "name": region.name,
+                    "base": region.base,
+                    "size": region.size,
+                    "access": access(&region.access),
+                })
+            })
+            .collect();
+        print_json("list", json!({ "regions": regions }));
+        return Ok(());
+    }
+    for region in regions {
+        println!(
+            "0x{:08x}  0x{:08x}  {}  {}",
+            region.base,
+            region.size,
+            access(&region.access),
+            region.name
+        );
+    }
+    Ok(())
+}
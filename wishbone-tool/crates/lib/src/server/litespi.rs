@@ -0,0 +1,132 @@
+use crate::config::Config;
+
+use wishbone_bridge::{Bridge, BridgeError};
+
+use std::iter;
+
+/// Bits of the old `spiflash` core's `bitbang` register
+const BITBANG_MOSI: u32 = 1 << 0;
+const BITBANG_CLK: u32 = 1 << 1;
+const BITBANG_CS_N: u32 = 1 << 2;
+const BITBANG_DQ_INPUT: u32 = 1 << 3;
+
+/// LiteSPI's `phyconfig`, set for eight bits at a time on one data line:
+/// `len` in bits 0-7, `width` in bits 8-11, and `mask` in bits 16-23
+const PHYCONFIG_SINGLE_BYTE: u32 = 8 | 1 << 8 | 1 << 16;
+
+/// One of LiteX's own SPI flash controllers, which shift a byte at a time
+/// rather than taking whole commands the way `spinor` does. This is how the
+/// LiteX BIOS talks to the flash, and what most LiteX boards have.
+#[derive(Clone, Debug)]
+pub enum SpiMaster {
+    /// The master interface of LiteSPI's `spiflash_core`
+    LiteSpi { cs: u32, phyconfig: u32, rxtx: u32 },
+
+    /// The bit-bang registers of the `spiflash` core that LiteSPI replaced
+    Bitbang {
+        bitbang: u32,
+        miso: u32,
+        enable: u32,
+    },
+}
+
+impl SpiMaster {
+    /// Find a LiteSPI master, or failing that a bit-bang `spiflash` core, in
+    /// csr.csv.
+    pub fn from_config(cfg: &Config) -> Option<SpiMaster> {
+        let register = |name: &str| cfg.register_mapping.get(name).copied().flatten();
+        if let (Some(cs), Some(phyconfig), Some(rxtx)) = (
+            register("spiflash_core_master_cs"),
+            register("spiflash_core_master_phyconfig"),
+            register("spiflash_core_master_rxtx"),
+        ) {
+            return Some(SpiMaster::LiteSpi {
+                cs,
+                phyconfig,
+                rxtx,
+            });
+        }
+        if let (Some(bitbang), Some(miso), Some(enable)) = (
+            register("spiflash_bitbang"),
+            register("spiflash_miso"),
+            register("spiflash_bitbang_en"),
+        ) {
+            return Some(SpiMaster::Bitbang {
+                bitbang,
+                miso,
+                enable,
+            });
+        }
+        None
+    }
+
+    /// With the flash selected, send `out`, then read `read` bytes back.
+    pub fn transfer(
+        &self,
+        bridge: &Bridge,
+        out: &[u8],
+        read: usize,
+    ) -> Result<Vec<u8>, BridgeError> {
+        let mut data = Vec::with_capacity(read);
+        match *self {
+            SpiMaster::LiteSpi {
+                cs,
+                phyconfig,
+                rxtx,
+            } => {
+                bridge.poke(phyconfig, PHYCONFIG_SINGLE_BYTE)?;
+                bridge.poke(cs, 1)?;
+                let bytes = out.iter().copied().chain(iter::repeat_n(0, read));
+                for (i, byte) in bytes.enumerate() {
+                    bridge.poke(rxtx, byte as u32)?;
+                    // A byte takes a handful of SPI clocks, which is over
+                    // long before the bridge can ask for it, so there's no
+                    // need to wait for `rx_ready`. Every byte has to be
+                    // read, or the next one won't be sent.
+                    let received = bridge.peek(rxtx)? as u8;
+                    if i >= out.len() {
+                        data.push(received);
+                    }
+                }
+                bridge.poke(cs, 0)?;
+            }
+            SpiMaster::Bitbang {
+                bitbang,
+                miso,
+                enable,
+            } => {
+                bridge.poke(enable, 1)?;
+                bridge.poke(bitbang, BITBANG_CS_N)?;
+                bridge.poke(bitbang, 0)?;
+                for byte in out {
+                    for bit in (0..8).rev() {
+                        let mosi = (*byte as u32 >> bit) & BITBANG_MOSI;
+                        bridge.poke(bitbang, mosi)?;
+                        bridge.poke(bitbang, mosi | BITBANG_CLK)?;
+                    }
+                }
+                bridge.poke(bitbang, BITBANG_DQ_INPUT)?;
+                for _ in 0..read {
+                    let mut byte = 0;
+                    for _ in 0..8 {
+                        bridge.poke(bitbang, BITBANG_CLK | BITBANG_DQ_INPUT)?;
+                        byte = byte << 1 | (bridge.peek(miso)? & 1) as u8;
+                        bridge.poke(bitbang, BITBANG_DQ_INPUT)?;
+                    }
+                    data.push(byte);
+                }
+                bridge.poke(bitbang, BITBANG_CS_N)?;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Hand the flash back to the memory-mapped interface.
+    pub fn release(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        match *self {
+            // The master only has the flash while `cs` is set
+            SpiMaster::LiteSpi { .. } => Ok(()),
+            SpiMaster::Bitbang { enable, .. } => bridge.poke(enable, 0),
+        }
+    }
+}
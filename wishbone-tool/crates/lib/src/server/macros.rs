@@ -0,0 +1,37 @@
+use crate::config::Config;
+use crate::csr_macros::{list_macros, MacroError};
+use crate::server::ServerError;
+
+use wishbone_bridge::Bridge;
+
+/// Run the macro given on the command line, printing every register it
+/// reads. If no macro name was given, list the macros that are available.
+pub fn run_macro(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let (name, args) = match &cfg.macro_call {
+        Some(call) => call,
+        None => return Ok(()),
+    };
+
+    let name = match name {
+        Some(name) => name,
+        None => {
+            if cfg.macros.is_empty() {
+                println!("No macros defined -- add a [macros] section to your --config file");
+            }
+            for line in list_macros(&cfg.macros) {
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+    };
+
+    let csr_macro = cfg
+        .macros
+        .get(name)
+        .ok_or_else(|| MacroError::UnknownMacro(name.clone()))?;
+    csr_macro.check_writes(&cfg.write_guard)?;
+    for line in csr_macro.run(&bridge, args)? {
+        println!("{}", line);
+    }
+    Ok(())
+}
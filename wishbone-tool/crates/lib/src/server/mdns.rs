@@ -0,0 +1,421 @@
+//! Advertise the GDB and Wishbone servers on the local network using
+//! multicast DNS and DNS service discovery (RFC 6762 and RFC 6763), so that
+//! clients can find them without knowing which host a board is plugged into.
+
+use crate::config::Config;
+use crate::server::ServerKind;
+
+use tracing::{debug, error, info, warn};
+use wishbone_bridge::Bridge;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// Name queried by browsers that want a list of every service type
+const SERVICES_NAME: &str = "_services._dns-sd._udp.local";
+
+/// How long others may cache records naming the host, in seconds
+const HOST_TTL: u32 = 120;
+
+/// How long others may cache the remaining records, in seconds
+const OTHER_TTL: u32 = 4500;
+
+/// Read at most this many characters of the SoC identifier
+const MAX_IDENTIFIER_LEN: u32 = 256;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Marks a record as the only one of its name and type, so that stale
+/// copies are flushed from caches
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// One service to advertise, such as a GDB server
+#[derive(Debug, Clone)]
+struct Service {
+    /// e.g. `_gdb._tcp.local`
+    service_type: String,
+
+    /// e.g. `lab-pc._gdb._tcp.local`
+    instance: String,
+    port: u16,
+    txt: Vec<String>,
+}
+
+struct Responder {
+    /// e.g. `lab-pc.local`
+    host: String,
+    addr: Ipv4Addr,
+    services: Vec<Service>,
+}
+
+/// Start advertising the servers in `cfg` via mDNS. The responder runs on
+/// its own thread for as long as the program does.
+pub fn advertise_mdns(cfg: &Config, bridge: &Bridge) -> io::Result<()> {
+    let hostname = local_hostname();
+    let name = cfg.mdns_name.clone().unwrap_or_else(|| hostname.clone());
+
+    let mut txt = vec!["txtvers=1".to_owned()];
+    if let Some(identity) = board_identity(cfg, bridge) {
+        info!("advertising board {}", identity);
+        txt.push(format!("board={}", identity));
+    }
+
+    let mut services = vec![];
+    for kind in &cfg.server_kind {
+        let (service_type, port) = match kind {
+            ServerKind::GDB if cfg.gdb_pipe => continue,
+            ServerKind::GDB => ("_gdb._tcp.local", cfg.gdb_port),
+            ServerKind::Wishbone => ("_etherbone._tcp.local", cfg.bind_port),
+            ServerKind::Http => ("_http._tcp.local", cfg.http_port),
+            _ => continue,
+        };
+        services.push(Service {
+            service_type: service_type.to_owned(),
+            instance: format!("{}.{}", name, service_type),
+            port,
+            txt: txt.clone(),
+        });
+    }
+
+    let addr = match cfg.bind_addr.parse::<Ipv4Addr>() {
+        Ok(addr) if addr.is_loopback() => {
+            warn!(
+                "servers are listening on {}, so clients found via mDNS won't be able to connect -- try --bind-addr 0.0.0.0",
+                addr
+            );
+            local_address()?
+        }
+        Ok(addr) if !addr.is_unspecified() => addr,
+        _ => local_address()?,
+    };
+
+    let responder = Responder {
+        host: format!("{}.local", hostname),
+        addr,
+        services,
+    };
+    let socket = open_socket()?;
+    info!(
+        "advertising {} on {} via mDNS",
+        responder
+            .services
+            .iter()
+            .map(|s| s.instance.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        responder.addr
+    );
+    thread::spawn(move || responder.run(socket));
+    Ok(())
+}
+
+/// Read the identification string that LiteX SoCs keep in `identifier_mem`,
+/// one character per register.
+fn board_identity(cfg: &Config, bridge: &Bridge) -> Option<String> {
+    let base = (*cfg.register_mapping.get("identifier_mem")?)?;
+    let mut identity = String::new();
+    for i in 0..MAX_IDENTIFIER_LEN {
+        let c = match bridge.peek(base + i * 4) {
+            Ok(value) => value as u8,
+            Err(e) => {
+                debug!("couldn't read identifier_mem: {}", e);
+                return None;
+            }
+        };
+        if c == 0 {
+            break;
+        }
+        identity.push(c as char);
+    }
+    Some(identity)
+}
+
+#[cfg(unix)]
+fn local_hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } == 0 {
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        if let Ok(name) = std::str::from_utf8(&buf[..len]) {
+            // Only the first label is used, and `.local` added on.
+            if let Some(name) = name.split('.').next().filter(|n| !n.is_empty()) {
+                return name.to_owned();
+            }
+        }
+    }
+    "wishbone-tool".to_owned()
+}
+
+#[cfg(not(unix))]
+fn local_hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "wishbone-tool".to_owned())
+}
+
+/// Find the address of the interface that multicast goes out on. Connecting
+/// a UDP socket doesn't send anything, but does pick a route.
+fn local_address() -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((MDNS_ADDR, MDNS_PORT))?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => Err(io::Error::other("no IPv4 address to advertise")),
+    }
+}
+
+/// Bind the mDNS port, sharing it with any other responder such as Avahi
+/// that's already running.
+#[cfg(target_os = "linux")]
+fn open_socket() -> io::Result<UdpSocket> {
+    use std::os::unix::io::AsRawFd;
+    use wishbone_bridge::sys::{self, check};
+
+    let socket: UdpSocket = sys::socket(libc::AF_INET, libc::SOCK_DGRAM, 0)?;
+    let fd = socket.as_raw_fd();
+    let one: libc::c_int = 1;
+    for option in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        check(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                *option,
+                &one as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+    }
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: MDNS_PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        sin_zero: [0; 8],
+    };
+    check(unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    })?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_socket() -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, MDNS_PORT))?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+impl Responder {
+    fn run(self, socket: UdpSocket) {
+        let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT));
+
+        // Announce twice, a second apart, as the RFC asks.
+        for _ in 0..2 {
+            let mut answers = vec![];
+            for service in &self.services {
+                self.add_service(&mut answers, service);
+                add_ptr(&mut answers, SERVICES_NAME, &service.service_type);
+            }
+            if let Err(e) = socket.send_to(&response(0, &[], &answers), group) {
+                error!("couldn't send mDNS announcement: {}", e);
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+
+        let mut buffer = [0u8; 9000];
+        loop {
+            let (len, src) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("mDNS responder stopped: {}", e);
+                    return;
+                }
+            };
+            let (id, questions) = match parse_query(&buffer[..len]) {
+                Some(query) => query,
+                None => continue,
+            };
+            let mut answers = vec![];
+            for (name, qtype) in &questions {
+                self.answer(&mut answers, name, *qtype);
+            }
+            if answers.is_empty() {
+                continue;
+            }
+            // Queries from ordinary resolvers come from some other port, and
+            // expect a unicast reply that repeats the question.
+            let result = if src.port() == MDNS_PORT {
+                socket.send_to(&response(0, &[], &answers), group)
+            } else {
+                socket.send_to(&response(id, &questions, &answers), src)
+            };
+            if let Err(e) = result {
+                debug!("couldn't send mDNS response: {}", e);
+            }
+        }
+    }
+
+    fn answer(&self, answers: &mut Vec<Vec<u8>>, name: &str, qtype: u16) {
+        if name.eq_ignore_ascii_case(SERVICES_NAME) && matches!(qtype, TYPE_PTR | TYPE_ANY) {
+            for service in &self.services {
+                add_ptr(answers, SERVICES_NAME, &service.service_type);
+            }
+        }
+        for service in &self.services {
+            if name.eq_ignore_ascii_case(&service.service_type)
+                && matches!(qtype, TYPE_PTR | TYPE_ANY)
+                || name.eq_ignore_ascii_case(&service.instance)
+                    && matches!(qtype, TYPE_SRV | TYPE_TXT | TYPE_ANY)
+            {
+                self.add_service(answers, service);
+            }
+        }
+        if name.eq_ignore_ascii_case(&self.host) && matches!(qtype, TYPE_A | TYPE_ANY) {
+            add_record(
+                answers,
+                &self.host,
+                TYPE_A,
+                true,
+                HOST_TTL,
+                &self.addr.octets(),
+            );
+        }
+    }
+
+    /// Add everything needed to connect to `service`, so that clients don't
+    /// have to ask again.
+    fn add_service(&self, answers: &mut Vec<Vec<u8>>, service: &Service) {
+        add_ptr(answers, &service.service_type, &service.instance);
+
+        let mut srv = vec![0, 0, 0, 0]; // priority and weight
+        srv.extend_from_slice(&service.port.to_be_bytes());
+        push_name(&mut srv, &self.host);
+        add_record(answers, &service.instance, TYPE_SRV, true, HOST_TTL, &srv);
+
+        let mut txt = vec![];
+        for entry in &service.txt {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            txt.push(entry.len() as u8);
+            txt.extend_from_slice(entry);
+        }
+        add_record(answers, &service.instance, TYPE_TXT, true, OTHER_TTL, &txt);
+
+        add_record(
+            answers,
+            &self.host,
+            TYPE_A,
+            true,
+            HOST_TTL,
+            &self.addr.octets(),
+        );
+    }
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+/// Encode a record and add it to `out`, unless it's already there.
+fn add_record(out: &mut Vec<Vec<u8>>, name: &str, rtype: u16, unique: bool, ttl: u32, data: &[u8]) {
+    let mut record = vec![];
+    push_name(&mut record, name);
+    record.extend_from_slice(&rtype.to_be_bytes());
+    let class = if unique {
+        CLASS_IN | CACHE_FLUSH
+    } else {
+        CLASS_IN
+    };
+    record.extend_from_slice(&class.to_be_bytes());
+    record.extend_from_slice(&ttl.to_be_bytes());
+    record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    record.extend_from_slice(data);
+    if !out.contains(&record) {
+        out.push(record);
+    }
+}
+
+/// PTR records are shared between every host offering a service, so they
+/// never flush caches.
+fn add_ptr(out: &mut Vec<Vec<u8>>, name: &str, target: &str) {
+    let mut data = vec![];
+    push_name(&mut data, target);
+    add_record(out, name, TYPE_PTR, false, OTHER_TTL, &data);
+}
+
+/// Build a response packet out of encoded records.
+fn response(id: u16, questions: &[(String, u16)], answers: &[Vec<u8>]) -> Vec<u8> {
+    let mut packet = vec![];
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&0x8400u16.to_be_bytes()); // Authoritative response
+    packet.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]); // No authority or additional records
+    for (name, qtype) in questions {
+        push_name(&mut packet, name);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for answer in answers {
+        packet.extend_from_slice(answer);
+    }
+    packet
+}
+
+/// Read a possibly-compressed name starting at `pos`, returning it along
+/// with the position just past it.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Bound the number of jumps, so a malicious packet can't loop forever.
+    for _ in 0..128 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let target = (len & 0x3f) << 8 | *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = target;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += len + 1;
+    }
+    None
+}
+
+/// Pick the questions out of a query, ignoring responses from others.
+fn parse_query(packet: &[u8]) -> Option<(u16, Vec<(String, u16)>)> {
+    let header = packet.get(..12)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let count = u16::from_be_bytes([header[4], header[5]]);
+    let mut questions = vec![];
+    let mut pos = 12;
+    for _ in 0..count {
+        let (name, next) = read_name(packet, pos)?;
+        let qtype = u16::from_be_bytes([*packet.get(next)?, *packet.get(next + 1)?]);
+        questions.push((name, qtype));
+        pos = next + 4;
+    }
+    Some((id, questions))
+}
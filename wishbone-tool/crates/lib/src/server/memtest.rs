@@ -0,0 +1,257 @@
+use super::{check_write, shutdown, ServerError};
+use crate::config::{Config, ConfigError};
+
+use tracing::{error, info};
+use wishbone_bridge::{Bridge, MappedBridge, MemoryRegion};
+
+use std::convert::TryInto;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How much to read or write in one burst
+const CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Only this many failing words are printed, as a bad chip or a loose
+/// cable can make every word fail
+const MAX_REPORTED: u64 = 16;
+
+/// The patterns `--memtest` can write
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MemTest {
+    /// A single bit set in each word, moving along by one bit from each word
+    /// to the next. There are 32 passes, so that every bit of every word is
+    /// tried.
+    WalkingOnes,
+
+    /// Each word holds its own address, then the inverse of it, which finds
+    /// address lines that are stuck or shorted together
+    AddrInAddr,
+
+    /// Pseudo-random data, which is different each run
+    Random,
+}
+
+impl MemTest {
+    pub fn from_string(item: &str) -> Result<MemTest, ConfigError> {
+        match item {
+            "walking-ones" => Ok(MemTest::WalkingOnes),
+            "addr-in-addr" => Ok(MemTest::AddrInAddr),
+            "random" => Ok(MemTest::Random),
+            unknown => Err(ConfigError::InvalidConfig(format!(
+                "unknown memory test {}",
+                unknown
+            ))),
+        }
+    }
+}
+
+/// Gives the word to write to each address during one pass of a test
+type Pattern = Box<dyn Fn(u32) -> u32>;
+
+/// Words that didn't read back as they were written
+#[derive(Default)]
+struct Failures {
+    count: u64,
+}
+
+impl Failures {
+    fn record(&mut self, addr: u32, expected: u32, observed: u32) {
+        self.count += 1;
+        if self.count <= MAX_REPORTED {
+            error!(
+                "0x{:08x}: expected 0x{:08x}, got 0x{:08x} (bits 0x{:08x} differ)",
+                addr,
+                expected,
+                observed,
+                expected ^ observed
+            );
+        } else if self.count == MAX_REPORTED + 1 {
+            error!("not showing any more failures");
+        }
+    }
+}
+
+fn test_region(cfg: &Config) -> Result<&MemoryRegion, ServerError> {
+    cfg.test_region
+        .as_ref()
+        .ok_or_else(|| ServerError::UnmappableAddress("no address given to test".to_owned()))
+}
+
+fn mapped_bridge(cfg: &Config, bridge: Bridge) -> MappedBridge {
+    let mut bridge = MappedBridge::new(bridge);
+    bridge
+        .regions(cfg.memory_regions.iter().cloned())
+        .policy(cfg.region_policy);
+    bridge
+}
+
+/// Format a transfer rate for the log
+fn rate(bytes: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return "too quick to measure".to_owned();
+    }
+    let per_sec = bytes as f64 / secs;
+    if per_sec >= 1024.0 * 1024.0 {
+        format!("{:.2} MiB/s", per_sec / (1024.0 * 1024.0))
+    } else {
+        format!("{:.2} KiB/s", per_sec / 1024.0)
+    }
+}
+
+/// Write the word `pattern(addr)` to every word of `region`, in bursts
+fn write_pattern<F: Fn(u32) -> u32>(
+    bridge: &MappedBridge,
+    region: &MemoryRegion,
+    pattern: F,
+) -> Result<Duration, ServerError> {
+    let start = Instant::now();
+    let mut done = 0;
+    while done < region.size {
+        if shutdown::requested() {
+            return Err(ServerError::Interrupted);
+        }
+        let addr = region.base + done;
+        let length = CHUNK_SIZE.min(region.size - done);
+        let data: Vec<u8> = (addr..addr + length)
+            .step_by(4)
+            .flat_map(|a| pattern(a).to_le_bytes())
+            .collect();
+        bridge.burst_write(addr, &data)?;
+        done += length;
+    }
+    Ok(start.elapsed())
+}
+
+/// Read back every word of `region`, recording each one that isn't
+/// `pattern(addr)`
+fn check_pattern<F: Fn(u32) -> u32>(
+    bridge: &MappedBridge,
+    region: &MemoryRegion,
+    pattern: F,
+    failures: &mut Failures,
+) -> Result<Duration, ServerError> {
+    let start = Instant::now();
+    let mut done = 0;
+    while done < region.size {
+        if shutdown::requested() {
+            return Err(ServerError::Interrupted);
+        }
+        let addr = region.base + done;
+        let length = CHUNK_SIZE.min(region.size - done);
+        let data = bridge.burst_read(addr, length)?;
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let word_addr = addr + i as u32 * 4;
+            let observed = u32::from_le_bytes(word.try_into().unwrap());
+            let expected = pattern(word_addr);
+            if observed != expected {
+                failures.record(word_addr, expected, observed);
+            }
+        }
+        done += length;
+    }
+    Ok(start.elapsed())
+}
+
+/// Write `cfg.fill_value` to every word of `cfg.test_region`.
+pub fn fill(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let region = test_region(cfg)?;
+    let value = cfg.fill_value.unwrap_or(0);
+    check_write(cfg, region.base, region.size)?;
+    let bridge = mapped_bridge(cfg, bridge);
+    let elapsed = write_pattern(&bridge, region, |_| value)?;
+    info!(
+        "filled {} bytes at 0x{:08x} with 0x{:08x} ({})",
+        region.size,
+        region.base,
+        value,
+        rate(region.size as u64, elapsed)
+    );
+    Ok(())
+}
+
+/// Mix `addr` into `seed`, giving the same word for the same address every
+/// time so that random data can be checked without being kept around
+fn random_word(seed: u64, addr: u32) -> u32 {
+    // splitmix64
+    let mut z = seed.wrapping_add((addr as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) as u32
+}
+
+/// Run `cfg.memtest` across `cfg.test_region`, reporting how fast it went
+/// and which words failed.
+pub fn memtest(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let region = test_region(cfg)?;
+    let test = cfg.memtest.unwrap_or(MemTest::WalkingOnes);
+    check_write(cfg, region.base, region.size)?;
+    let bridge = mapped_bridge(cfg, bridge);
+
+    let mut passes: Vec<(String, Pattern)> = vec![];
+    match test {
+        MemTest::WalkingOnes => {
+            for bit in 0..32 {
+                passes.push((
+                    format!("walking ones {}/32", bit + 1),
+                    Box::new(move |addr| 1u32.rotate_left(addr / 4 + bit)),
+                ));
+            }
+        }
+        MemTest::AddrInAddr => {
+            passes.push(("address in address".to_owned(), Box::new(|addr| addr)));
+            passes.push((
+                "inverse address in address".to_owned(),
+                Box::new(|addr| !addr),
+            ));
+        }
+        MemTest::Random => {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0);
+            info!("random seed is 0x{:016x}", seed);
+            passes.push((
+                "random".to_owned(),
+                Box::new(move |addr| random_word(seed, addr)),
+            ));
+        }
+    }
+
+    info!(
+        "testing {} bytes at 0x{:08x} ({:?}, {} passes)",
+        region.size,
+        region.base,
+        test,
+        passes.len()
+    );
+    let mut failures = Failures::default();
+    let mut written = Duration::ZERO;
+    let mut read = Duration::ZERO;
+    for (name, pattern) in &passes {
+        let before = failures.count;
+        let write_time = write_pattern(&bridge, region, pattern)?;
+        let read_time = check_pattern(&bridge, region, pattern, &mut failures)?;
+        info!(
+            "{}: writing at {}, reading at {}, {} failures",
+            name,
+            rate(region.size as u64, write_time),
+            rate(region.size as u64, read_time),
+            failures.count - before
+        );
+        written += write_time;
+        read += read_time;
+    }
+
+    let total = region.size as u64 * passes.len() as u64;
+    info!(
+        "wrote at {}, read at {}",
+        rate(total, written),
+        rate(total, read)
+    );
+    if failures.count > 0 {
+        error!("{} of {} words failed", failures.count, total / 4);
+        return Err(ServerError::MemTestFailed(failures.count));
+    }
+    info!("no errors encountered");
+    Ok(())
+}
@@ -0,0 +1,2144 @@
+use crate::config::{Config, ConfigError, OutputFormat};
+use crate::csr_macros::MacroError;
+use crate::csr_map;
+#[cfg(feature = "gdb")]
+use crate::gdb;
+#[cfg(feature = "gdb")]
+use crate::hostio::{CoreDir, HostFiles};
+use crate::image::{Image, ImageError};
+#[cfg(feature = "cpu")]
+use crate::riscv;
+#[cfg(feature = "wishbone-server")]
+use crate::wishbone;
+
+#[cfg(feature = "random-test")]
+use rand::prelude::*;
+#[cfg(feature = "gdb")]
+use tracing::debug_span;
+use tracing::{error, info, warn};
+use wishbone_bridge::{Bridge, BridgeError, MappedBridge};
+
+use std::fs::File;
+use std::io;
+#[cfg(feature = "cpu")]
+use std::io::BufWriter;
+#[cfg(any(feature = "gdb", feature = "wishbone-server"))]
+use std::net::TcpListener;
+#[cfg(feature = "gdb")]
+use std::sync::{Arc, Mutex};
+#[cfg(any(feature = "gdb", feature = "wishbone-server"))]
+use std::thread;
+#[cfg(any(feature = "gdb", feature = "wishbone-server", feature = "terminal"))]
+use std::time::Duration;
+
+mod benchmark;
+#[cfg(feature = "flash")]
+mod bitstream;
+mod board;
+mod bus_errors;
+mod dump;
+mod encoding;
+pub(crate) mod etherbone;
+#[cfg(feature = "flash")]
+mod flash;
+#[cfg(feature = "flash")]
+mod flash_chip;
+#[cfg(feature = "flash")]
+mod fpga;
+mod guard;
+mod heartbeat;
+mod hexdump;
+#[cfg(feature = "http")]
+mod http;
+mod init;
+mod list;
+#[cfg(feature = "flash")]
+mod litespi;
+mod macros;
+#[cfg(feature = "mdns")]
+mod mdns;
+mod memtest;
+mod output;
+#[cfg(feature = "terminal")]
+mod pty;
+#[cfg(feature = "repl")]
+mod repl;
+#[cfg(feature = "script")]
+mod script;
+pub mod shutdown;
+mod sniff;
+#[cfg(feature = "flash")]
+mod spinor;
+mod supervisor;
+#[cfg(feature = "update")]
+mod update;
+#[cfg(feature = "flash")]
+mod utra;
+mod vcd;
+pub use benchmark::benchmark;
+#[cfg(feature = "flash")]
+pub use bitstream::{Bitstream, FpgaFamily};
+pub use board::{board_control, BoardControl, ControlDomain};
+pub use bus_errors::{BusErrorWatch, BUS_ERRORS_CSR};
+pub use dump::{dump_regions, RegionDump};
+pub use encoding::{ApiValue, Encoding};
+#[cfg(feature = "flash")]
+pub use flash::{flash_commands, flash_erase, flash_protect, flash_read};
+#[cfg(feature = "flash")]
+pub use flash_chip::{Chip, ErrorRegister, FlashChip, JedecId};
+#[cfg(feature = "flash")]
+pub use fpga::fpga_program;
+pub use guard::{AccessPolicy, WriteGuard};
+pub use heartbeat::Heartbeat;
+#[cfg(feature = "gdb")]
+use heartbeat::HeartbeatMonitor;
+#[cfg(feature = "http")]
+pub use http::http_server;
+#[cfg(feature = "flash")]
+use indicatif::{ProgressBar, ProgressStyle};
+pub use init::run_init_steps;
+pub use list::list;
+pub use macros::run_macro;
+#[cfg(feature = "mdns")]
+pub use mdns::advertise_mdns;
+pub use memtest::{fill, memtest, MemTest};
+pub use output::{print_json, print_json_error};
+#[cfg(feature = "repl")]
+pub use repl::repl;
+#[cfg(feature = "script")]
+pub use script::run_script;
+pub use sniff::sniff;
+pub(crate) use sniff::NoDevice;
+#[cfg(feature = "flash")]
+pub use spinor::{SpiNor, PAGE_SIZE, SECTOR_SIZE};
+pub use supervisor::supervise;
+pub use vcd::{vcd_gpio, VcdCapture, VcdSignal};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ServerKind {
+    /// DevMem2 equivalent
+    MemoryAccess,
+
+    /// Wishbone bridge
+    Wishbone,
+
+    /// GDB server
+    GDB,
+
+    /// Send random data back and forth
+    RandomTest,
+
+    /// Load a file into memory
+    LoadFile,
+
+    /// Run a terminal
+    Terminal,
+
+    /// View the messible
+    Messible,
+
+    /// Flash programming
+    FlashProgram,
+
+    /// Read, erase, and change the protection of the flash
+    FlashCommands,
+
+    /// Write a bitstream to the configuration flash and reboot the FPGA
+    FpgaProgram,
+
+    /// Write the images from an update manifest to flash
+    Update,
+
+    /// Print the CSRs and memory regions from the register map
+    List,
+
+    /// Power and clock control
+    BoardControl,
+
+    /// Run a macro from the config file
+    Macro,
+
+    /// Sample GPIO inputs into a waveform
+    VcdGpio,
+
+    /// Decode and print Etherbone packets sent by other programs
+    Sniff,
+
+    /// Halt the CPU and write its registers and memory to a core file
+    CoreDump,
+
+    /// Read whole memory regions into files
+    DumpRegions,
+
+    /// Write the same word across a range of memory
+    Fill,
+
+    /// Write patterns across a range of memory and check they read back
+    MemTest,
+
+    /// Read and write CSRs and memory over HTTP
+    Http,
+
+    /// Interactive prompt for peeking and poking
+    Repl,
+
+    /// Run a bring-up script
+    Script,
+
+    /// Time peeks, pokes, and bursts over the bridge
+    Benchmark,
+
+    /// Load a program and start the CPU running it
+    Boot,
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    IoError(io::Error),
+    #[cfg(feature = "wishbone-server")]
+    WishboneError(wishbone::WishboneServerError),
+    #[cfg(feature = "gdb")]
+    GdbError(gdb::GdbServerError),
+    BridgeError(BridgeError),
+    #[cfg(feature = "cpu")]
+    RiscvCpuError(riscv::RiscvCpuError),
+    RandomValueError(
+        u32, /* counter */
+        u32, /* expected */
+        u32, /* observed */
+    ),
+    #[cfg(feature = "terminal")]
+    TerminalError(terminal::error::ErrorKind),
+
+    /// The specified address was not in mappable range
+    UnmappableAddress(String),
+    FlashError(
+        u32, // expected
+        u32, // observed
+    ),
+
+    /// The flash reported that an erase or program failed
+    FlashFailed(u32 /* offset into the flash */),
+
+    /// No driver knows the flash chip with this JEDEC ID, and it has no
+    /// SFDP tables to go by
+    UnknownFlash(u32),
+
+    /// The flash's block-protect bits didn't change, most likely because
+    /// `WP#` is holding the status register
+    ProtectFailed(u32 /* status register */),
+
+    /// Flashing would erase part of the golden image, and `--allow-golden`
+    /// wasn't given
+    GoldenImage(
+        u32, // first address that would be erased
+        u32, // address just past the last one
+    ),
+
+    /// The file being flashed isn't a bitstream for this FPGA
+    BitstreamError(String),
+
+    /// An update's files or target don't match its manifest
+    UpdateRefused(String),
+
+    /// An `assert` step in the config file didn't match
+    InitAssertFailed(
+        String, // register
+        u32,    // expected
+        u32,    // observed
+    ),
+
+    /// A macro from the config file failed
+    MacroError(MacroError),
+
+    /// A `--script` failed to parse or stopped with an error
+    ScriptError(String),
+
+    /// A write would have hit the debug interface or the bridge's own
+    /// registers, and `--force` wasn't given
+    ProtectedWrite(
+        u32,    // address
+        String, // region
+    ),
+
+    /// This server was left out when the crate was built. Contains the name
+    /// of the cargo feature that provides it.
+    NotEnabled(&'static str),
+
+    /// Stopped part way through because `wishbone-tool` is exiting
+    Interrupted,
+
+    /// The file given to `--load-name` couldn't be read
+    ImageError(ImageError),
+
+    /// This many bytes didn't read back as they were loaded with `--verify`
+    VerifyFailed(u32),
+
+    /// Some words didn't read back as they were written during `--memtest`
+    MemTestFailed(u64 /* words that failed */),
+
+    /// `list` was asked for, but no register map was loaded
+    NoRegisterMap,
+}
+
+impl std::convert::From<io::Error> for ServerError {
+    fn from(e: io::Error) -> ServerError {
+        ServerError::IoError(e)
+    }
+}
+impl std::convert::From<ImageError> for ServerError {
+    fn from(e: ImageError) -> ServerError {
+        ServerError::ImageError(e)
+    }
+}
+impl std::convert::From<MacroError> for ServerError {
+    fn from(e: MacroError) -> ServerError {
+        ServerError::MacroError(e)
+    }
+}
+#[cfg(feature = "wishbone-server")]
+impl std::convert::From<wishbone::WishboneServerError> for ServerError {
+    fn from(e: wishbone::WishboneServerError) -> ServerError {
+        ServerError::WishboneError(e)
+    }
+}
+#[cfg(feature = "gdb")]
+impl std::convert::From<gdb::GdbServerError> for ServerError {
+    fn from(e: gdb::GdbServerError) -> ServerError {
+        ServerError::GdbError(e)
+    }
+}
+impl std::convert::From<BridgeError> for ServerError {
+    fn from(e: BridgeError) -> ServerError {
+        ServerError::BridgeError(e)
+    }
+}
+#[cfg(feature = "cpu")]
+impl std::convert::From<riscv::RiscvCpuError> for ServerError {
+    fn from(e: riscv::RiscvCpuError) -> ServerError {
+        ServerError::RiscvCpuError(e)
+    }
+}
+
+#[cfg(feature = "terminal")]
+impl std::convert::From<terminal::error::ErrorKind> for ServerError {
+    fn from(e: terminal::error::ErrorKind) -> ServerError {
+        ServerError::TerminalError(e)
+    }
+}
+
+impl ServerKind {
+    pub fn from_string(item: &str) -> Result<ServerKind, ConfigError> {
+        match item {
+            "gdb" => Ok(ServerKind::GDB),
+            "wishbone" => Ok(ServerKind::Wishbone),
+            "random-test" => Ok(ServerKind::RandomTest),
+            "load-file" => Ok(ServerKind::LoadFile),
+            "terminal" => Ok(ServerKind::Terminal),
+            "messible" => Ok(ServerKind::Messible),
+            "memory-access" => Ok(ServerKind::MemoryAccess),
+            "flash-program" => Ok(ServerKind::FlashProgram),
+            "fpga-program" => Ok(ServerKind::FpgaProgram),
+            "vcd-gpio" => Ok(ServerKind::VcdGpio),
+            "sniff" => Ok(ServerKind::Sniff),
+            "core-dump" => Ok(ServerKind::CoreDump),
+            "http" => Ok(ServerKind::Http),
+            "repl" => Ok(ServerKind::Repl),
+            "benchmark" => Ok(ServerKind::Benchmark),
+            "boot" => Ok(ServerKind::Boot),
+            unknown => Err(ConfigError::UnknownServerKind(unknown.to_owned())),
+        }
+    }
+
+    /// If this server was left out when the crate was built, return the
+    /// name of the cargo feature that would provide it.
+    pub fn missing_feature(self) -> Option<&'static str> {
+        match self {
+            ServerKind::GDB if !cfg!(feature = "gdb") => Some("gdb"),
+            ServerKind::Wishbone if !cfg!(feature = "wishbone-server") => Some("wishbone-server"),
+            ServerKind::RandomTest if !cfg!(feature = "random-test") => Some("random-test"),
+            ServerKind::Terminal | ServerKind::Messible if !cfg!(feature = "terminal") => {
+                Some("terminal")
+            }
+            ServerKind::FlashProgram | ServerKind::FlashCommands | ServerKind::FpgaProgram
+                if !cfg!(feature = "flash") =>
+            {
+                Some("flash")
+            }
+            ServerKind::CoreDump | ServerKind::Boot if !cfg!(feature = "cpu") => Some("cpu"),
+            ServerKind::Update if !cfg!(feature = "update") => Some("update"),
+            ServerKind::Http if !cfg!(feature = "http") => Some("http"),
+            ServerKind::Repl if !cfg!(feature = "repl") => Some("repl"),
+            ServerKind::Script if !cfg!(feature = "script") => Some("script"),
+            _ => None,
+        }
+    }
+}
+
+/// Run the server or one-shot operation described by `kind`. Servers such as
+/// `GDB` and `Wishbone` only return on error, so run each one on its own
+/// thread if more than one is needed.
+pub fn run(kind: ServerKind, cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    if let Some(feature) = kind.missing_feature() {
+        return Err(ServerError::NotEnabled(feature));
+    }
+    match kind {
+        #[cfg(feature = "gdb")]
+        ServerKind::GDB => gdb_server(cfg, bridge),
+        #[cfg(feature = "wishbone-server")]
+        ServerKind::Wishbone => wishbone_server(cfg, bridge),
+        #[cfg(feature = "random-test")]
+        ServerKind::RandomTest => random_test(cfg, bridge),
+        ServerKind::LoadFile => load_file(cfg, bridge),
+        #[cfg(feature = "terminal")]
+        ServerKind::Terminal => terminal_client(cfg, bridge),
+        ServerKind::MemoryAccess => memory_access(cfg, bridge),
+        #[cfg(feature = "terminal")]
+        ServerKind::Messible => messible_client(cfg, bridge),
+        #[cfg(feature = "flash")]
+        ServerKind::FlashProgram => flash_program(cfg, bridge),
+        #[cfg(feature = "flash")]
+        ServerKind::FlashCommands => flash_commands(cfg, bridge),
+        #[cfg(feature = "flash")]
+        ServerKind::FpgaProgram => fpga_program(cfg, bridge),
+        #[cfg(feature = "update")]
+        ServerKind::Update => update::update(cfg, bridge),
+        ServerKind::BoardControl => board_control(cfg, bridge),
+        ServerKind::Macro => run_macro(cfg, bridge),
+        ServerKind::VcdGpio => vcd_gpio(cfg, bridge),
+        ServerKind::Sniff => sniff(cfg, bridge),
+        #[cfg(feature = "cpu")]
+        ServerKind::CoreDump => core_dump(cfg, bridge),
+        ServerKind::DumpRegions => dump_regions(cfg, bridge),
+        ServerKind::Fill => fill(cfg, bridge),
+        ServerKind::MemTest => memtest(cfg, bridge),
+        #[cfg(feature = "http")]
+        ServerKind::Http => http_server(cfg, bridge),
+        #[cfg(feature = "repl")]
+        ServerKind::Repl => repl(cfg, bridge),
+        #[cfg(feature = "script")]
+        ServerKind::Script => run_script(cfg, bridge),
+        ServerKind::Benchmark => benchmark(cfg, bridge),
+        ServerKind::List => list(cfg, bridge),
+        #[cfg(feature = "cpu")]
+        ServerKind::Boot => boot(cfg, bridge),
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("missing_feature() covers servers that weren't built"),
+    }
+}
+
+/// Poll the Messible at the address specified.
+/// Return `true` if there is still data to be read
+/// after returning.
+#[cfg(feature = "gdb")]
+fn poll_messible(
+    messible_address: Option<u32>,
+    bridge: &Bridge,
+    gdb_controller: &mut gdb::GdbController,
+) -> bool {
+    let addr = match messible_address {
+        None => return false,
+        Some(s) => s,
+    };
+
+    let mut data: Vec<u8> = vec![];
+    let max_bytes = 64;
+    while data.len() < max_bytes {
+        let status = match bridge.peek(addr + 8) {
+            Ok(b) => b,
+            Err(_) => return false,
+        };
+
+        if status & 2 == 0 {
+            break;
+        }
+
+        let b = match bridge.peek(addr + 4) {
+            Ok(b) => b as u8,
+            Err(_) => return false,
+        };
+
+        data.push(b);
+    }
+
+    let s = match std::str::from_utf8(&data) {
+        Ok(o) => o,
+        Err(_) => "[invalid string]",
+    };
+    gdb_controller.print_string(s).ok();
+
+    // Re-examine the Messible and determine if we still have data
+    match bridge.peek(addr + 8) {
+        Ok(b) => (b & 2) != 0,
+        Err(_) => false,
+    }
+}
+
+/// Poll the UART at the address specified.
+/// Return `true` if there is still data to be read
+/// after returning.
+#[cfg(feature = "terminal")]
+fn poll_uart(uart_address: u32, bridge: &Bridge) -> Result<bool, BridgeError> {
+    Ok(bridge.peek(uart_address)? == 0)
+}
+
+/// Sample the heartbeat counter of a running CPU, and raise the alarm if it
+/// has stopped advancing.
+#[cfg(feature = "gdb")]
+fn check_heartbeat(
+    heartbeat: &mut HeartbeatMonitor,
+    cpu_controller: &riscv::RiscvCpuController,
+    bridge: &Bridge,
+    gdb_controller: &mut gdb::GdbController,
+) {
+    let (value, stuck_for) = match heartbeat.check(bridge) {
+        Ok(Some(stall)) => stall,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("couldn't read heartbeat: {}", e);
+            return;
+        }
+    };
+    let addr = heartbeat.heartbeat().addr;
+    let mut alarm = format!(
+        "*** STALL: heartbeat at 0x{:08x} has been stuck at 0x{:08x} for {:.1}s while the CPU is running ***\n",
+        addr,
+        value,
+        stuck_for.as_secs_f32()
+    );
+    if heartbeat.heartbeat().halt {
+        match cpu_controller.halt_and_explain(bridge) {
+            Ok(report) => {
+                alarm.push_str("CPU halted.\n");
+                alarm.push_str(&report);
+            }
+            Err(e) => alarm.push_str(&format!("couldn't halt CPU: {}\n", e)),
+        }
+    }
+    for line in alarm.lines() {
+        error!("{}", line);
+    }
+    gdb_controller.print_string(&alarm).ok();
+}
+
+/// The GDB client that hears when the CPU stops, along with any messible
+/// output and stall alarms while it runs.
+#[cfg(feature = "gdb")]
+struct StopListener {
+    session: usize,
+    controller: gdb::GdbController,
+}
+
+/// Connect to the CPU at `debug_offset`, as `cfg` describes it.
+#[cfg(feature = "cpu")]
+fn connect_cpu(cfg: &Config, bridge: &Bridge) -> Result<riscv::RiscvCpu, ServerError> {
+    let xlen = cfg.gdb_xlen.and_then(riscv::Xlen::from_bits);
+    let interface = if cfg.debug_module {
+        riscv::DebugInterface::DebugModule
+    } else {
+        riscv::DebugInterface::VexRiscv
+    };
+    let mut cpu = riscv::RiscvCpu::new(bridge, cfg.debug_offset, xlen, interface)?;
+    cpu.set_interrupt_sources(riscv::exception::InterruptSource::from_register_mapping(
+        &cfg.register_mapping,
+    ));
+    Ok(cpu)
+}
+
+#[cfg(feature = "gdb")]
+pub fn gdb_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let cpu = Arc::new(Mutex::new(connect_cpu(cfg, &bridge)?));
+    // Don't leave the CPU halted, or stopping at breakpoints nobody is
+    // waiting for, once we've gone. Holding the CPU's lock means no packet
+    // is being handled, so a `load` into flash is between pages.
+    let release_cpu = cpu.clone();
+    let release_bridge = bridge.clone();
+    #[cfg(feature = "flash")]
+    let release_flash = SpiNor::from_config(cfg).ok();
+    let _release = shutdown::on_shutdown(move || {
+        let cpu = release_cpu.lock().unwrap();
+        #[cfg(feature = "flash")]
+        if let Some(flash) = &release_flash {
+            flash.finish(&release_bridge).ok();
+        }
+        info!("resuming the CPU");
+        if let Err(e) = cpu.release(&release_bridge) {
+            error!("couldn't resume the CPU: {:?}", e);
+        }
+    });
+    // Enable messible support, but only if we're not also running a messible or wishbone server.
+    let messible_address = if cfg.server_kind.contains(&ServerKind::Messible)
+        || cfg.server_kind.contains(&ServerKind::Wishbone)
+    {
+        None
+    } else {
+        cfg.messible_address
+    };
+    // A single thread polls the CPU for every client, and passes what it
+    // finds on to whichever one is listening.
+    let stop_listener: Arc<Mutex<Option<StopListener>>> = Arc::new(Mutex::new(None));
+    let cpu_controller = cpu.lock().unwrap().get_controller();
+    let poll_bridge = bridge.clone();
+    let poll_listener = stop_listener.clone();
+    let mut heartbeat = cfg.heartbeat.clone().map(HeartbeatMonitor::new);
+    thread::spawn(move || {
+        let mut had_error = false;
+        loop {
+            let mut do_pause = true;
+            if let Some(listener) = poll_listener.lock().unwrap().as_mut() {
+                let gdb_controller = &mut listener.controller;
+                match cpu_controller.poll(&poll_bridge, gdb_controller) {
+                    Err(e) => {
+                        if !had_error {
+                            error!("error while polling bridge: {:?}", e);
+                            had_error = true;
+                        }
+                    }
+                    Ok(running) => {
+                        had_error = false;
+                        // If there's a messible available, poll it.
+                        if running {
+                            do_pause =
+                                !poll_messible(messible_address, &poll_bridge, gdb_controller);
+                        }
+                        if let Some(heartbeat) = heartbeat.as_mut() {
+                            if running {
+                                check_heartbeat(
+                                    heartbeat,
+                                    &cpu_controller,
+                                    &poll_bridge,
+                                    gdb_controller,
+                                );
+                            } else {
+                                heartbeat.reset();
+                            }
+                        }
+                    }
+                }
+            }
+
+            if do_pause {
+                thread::park_timeout(Duration::from_millis(200));
+            }
+        }
+    });
+
+    let bus_errors = cfg.bus_errors.clone();
+    if cfg.gdb_pipe {
+        info!("talking to gdb over stdin and stdout");
+        let gdb = new_gdb_session(cfg, gdb::GdbConnection::Pipe);
+        stop_for_new_client(&cpu, &bridge, &stop_listener)?;
+        serve_gdb(gdb, 0, &cpu, &bridge, &stop_listener, &bus_errors);
+        return Ok(());
+    }
+
+    let listener = match TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.gdb_port)) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("couldn't bind to address: {:?}", e);
+            return Err(ServerError::IoError(e));
+        }
+    };
+
+    // accept connections, and serve each one on its own thread
+    info!(
+        "accepting gdb connections on {}:{}",
+        cfg.bind_addr, cfg.gdb_port
+    );
+    for session in 0.. {
+        let (connection, peer_addr) = match listener.accept() {
+            Ok(o) => o,
+            Err(e) => {
+                error!("couldn't accept connection: {:?}", e);
+                return Err(ServerError::IoError(e));
+            }
+        };
+        info!("connection from {}", peer_addr);
+
+        let gdb = new_gdb_session(cfg, connection.into());
+        if let Err(e) = stop_for_new_client(&cpu, &bridge, &stop_listener) {
+            error!("couldn't halt CPU: {:?}", e);
+            continue;
+        }
+
+        let cpu = cpu.clone();
+        let bridge = bridge.clone();
+        let stop_listener = stop_listener.clone();
+        let bus_errors = bus_errors.clone();
+        thread::spawn(move || {
+            serve_gdb(gdb, session, &cpu, &bridge, &stop_listener, &bus_errors);
+            info!("connection from {} closed", peer_addr);
+        });
+    }
+    Ok(())
+}
+
+/// Set up the GDB server for a new client, with everything `cfg` gives it.
+#[cfg(feature = "gdb")]
+fn new_gdb_session(cfg: &Config, connection: gdb::GdbConnection) -> gdb::GdbServer {
+    let mut gdb = gdb::GdbServer::new(connection).unwrap();
+    if let Some(sysroot) = &cfg.gdb_sysroot {
+        match HostFiles::new(sysroot, cfg.gdb_exec_file.clone()) {
+            Ok(host_files) => gdb.set_host_files(host_files),
+            Err(e) => error!("couldn't open gdb sysroot {}: {}", sysroot, e),
+        }
+    }
+    gdb.set_macros(cfg.macros.clone());
+    gdb.set_register_mapping(cfg.register_mapping.clone());
+    gdb.set_memory_regions(cfg.memory_regions.clone());
+    gdb.set_core_regions(cfg.core_regions.clone());
+    if let Some(core_dir) = &cfg.core_dir {
+        match CoreDir::new(core_dir) {
+            Ok(core_dir) => gdb.set_core_dir(core_dir),
+            Err(e) => error!("couldn't open core file directory {}: {}", core_dir, e),
+        }
+    }
+    gdb.set_write_guard(cfg.write_guard.clone());
+    gdb.set_access_policy(cfg.network_access.clone());
+    gdb.set_interrupt_signal(cfg.gdb_interrupt_signal);
+    #[cfg(feature = "flash")]
+    if let Ok(flash) = SpiNor::from_config(cfg) {
+        gdb.set_flash(flash);
+    }
+    gdb
+}
+
+/// Handle commands from a GDB client until it goes away.
+#[cfg(feature = "gdb")]
+fn serve_gdb(
+    mut gdb: gdb::GdbServer,
+    session: usize,
+    cpu: &Mutex<riscv::RiscvCpu>,
+    bridge: &Bridge,
+    stop_listener: &Mutex<Option<StopListener>>,
+    bus_errors: &Option<BusErrorWatch>,
+) {
+    loop {
+        let cmd = match gdb.get_command() {
+            Err(e) => {
+                error!("unable to read command from GDB client: {:?}", e);
+                break;
+            }
+            Ok(o) => o,
+        };
+
+        let _span = debug_span!("gdb_packet", command = ?cmd).entered();
+        let cpu = cpu.lock().unwrap();
+        // Only a client talking to a halted CPU can start it
+        // running, so it's the one waiting when the CPU stops again.
+        if !cpu.is_running() {
+            let mut listener = stop_listener.lock().unwrap();
+            if listener.as_ref().map(|l| l.session) != Some(session) {
+                *listener = Some(StopListener {
+                    session,
+                    controller: gdb.get_controller(),
+                });
+            }
+        }
+        if let Err(e) = gdb.process(cmd, &cpu, bridge) {
+            match e {
+                gdb::GdbServerError::ConnectionClosed => (),
+                e => error!("error in GDB server: {:?}", e),
+            }
+            break;
+        }
+        if let Some(watch) = bus_errors {
+            watch.after_batch(bridge);
+        }
+    }
+    let mut listener = stop_listener.lock().unwrap();
+    if listener.as_ref().map(|l| l.session) == Some(session) {
+        *listener = None;
+    }
+}
+
+/// Halt the CPU for a client that has just connected. If another client
+/// is waiting for the CPU to stop, the stop is reported to it as an
+/// interrupt, so that it isn't left waiting forever.
+#[cfg(feature = "gdb")]
+fn stop_for_new_client(
+    cpu: &Mutex<riscv::RiscvCpu>,
+    bridge: &Bridge,
+    stop_listener: &Mutex<Option<StopListener>>,
+) -> Result<(), riscv::RiscvCpuError> {
+    let cpu = cpu.lock().unwrap();
+    if !cpu.is_running() || stop_listener.lock().unwrap().is_none() {
+        return cpu.halt(bridge);
+    }
+    info!("another gdb is waiting for the cpu, so interrupting it");
+    cpu.interrupt(bridge)?;
+    for _ in 0..50 {
+        if !cpu.is_running() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
+
+/// How many socket errors in a row the UDP Etherbone listener puts up with
+#[cfg(feature = "wishbone-server")]
+const UDP_SOCKET_ATTEMPTS: u32 = 10;
+
+#[cfg(feature = "wishbone-server")]
+pub fn wishbone_server(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    // Enable messible support, but only if we're not also running a messible server.
+    let messible_address = if cfg.server_kind.contains(&ServerKind::Messible) {
+        None
+    } else {
+        cfg.messible_address
+    };
+
+    // If there's a messible address specified, enable printf-style debugging.
+    if let Some(addr) = messible_address {
+        let poll_bridge = bridge.clone();
+        thread::spawn(move || loop {
+            let mut data: Vec<u8> = vec![];
+            let max_bytes = 64;
+            while data.len() < max_bytes {
+                // Get the status to see if it's empty.
+                let status = match poll_bridge.peek(addr + 8) {
+                    Ok(b) => b,
+                    Err(_) => return false,
+                };
+
+                // If the messible is empty, stop filling the buffer.
+                if status & 2 == 0 {
+                    break;
+                }
+
+                // It's not empty, so grab the next character
+                let b = match poll_bridge.peek(addr + 4) {
+                    Ok(b) => b as u8,
+                    Err(_) => return false,
+                };
+
+                data.push(b);
+            }
+
+            let s = match std::str::from_utf8(&data) {
+                Ok(o) => o,
+                Err(_) => "[invalid string]",
+            };
+            print!("{}", s);
+
+            // Re-examine the Messible and determine if we still have data
+            let do_pause = match poll_bridge.peek(addr + 8) {
+                Ok(b) => (b & 2) == 0,
+                Err(_) => return false,
+            };
+
+            // If there's no more data, pause for a short time.
+            if do_pause {
+                thread::park_timeout(Duration::from_millis(200));
+            }
+        });
+    }
+
+    let mut wishbone = wishbone::WishboneServer::new(cfg).map_err(|e| {
+        error!(
+            "Unable to listen on {}:{}: {:?}",
+            cfg.bind_addr, cfg.bind_port, e
+        );
+        ServerError::WishboneError(e)
+    })?;
+    if cfg.wishbone_udp {
+        let udp = wishbone.udp(cfg).map_err(|e| {
+            error!("Unable to listen for Etherbone over UDP: {:?}", e);
+            ServerError::WishboneError(e)
+        })?;
+        let udp_bridge = bridge.clone();
+        let bus_errors = cfg.bus_errors.clone();
+        thread::spawn(move || {
+            let mut socket_errors = 0;
+            loop {
+                match udp.process(&udp_bridge) {
+                    Ok(()) => socket_errors = 0,
+                    // Waiting a little longer each time keeps a socket that
+                    // has gone bad from spinning, until it's given up on.
+                    Err(wishbone::WishboneServerError::IoError(e)) => {
+                        socket_errors += 1;
+                        error!("Etherbone over UDP failed: {}", e);
+                        if socket_errors == UDP_SOCKET_ATTEMPTS {
+                            error!("no longer listening for Etherbone over UDP");
+                            return;
+                        }
+                        thread::sleep(Duration::from_millis(50 << socket_errors.min(5)));
+                    }
+                    Err(wishbone::WishboneServerError::AccessDenied(why)) => {
+                        warn!("Refused an Etherbone datagram: {}", why)
+                    }
+                    // One bad datagram shouldn't stop the others.
+                    Err(e) => error!("Error in Wishbone server: {:?}", e),
+                }
+                if let Some(watch) = &bus_errors {
+                    watch.after_batch(&udp_bridge);
+                }
+            }
+        });
+    }
+
+    loop {
+        let mut connection = wishbone.connect().map_err(|e| {
+            error!("Unable to connect to Wishbone bridge: {:?}", e);
+            ServerError::WishboneError(e)
+        })?;
+        let peer = connection.peer();
+        info!("Wishbone client {} connected", peer);
+
+        let thread_bridge = bridge.clone();
+        let bus_errors = cfg.bus_errors.clone();
+        std::thread::spawn(move || {
+            loop {
+                match connection.process(&thread_bridge) {
+                    Ok(()) => (),
+                    // Clients such as `litex_cli` connect afresh for each command.
+                    Err(wishbone::WishboneServerError::ConnectionClosed) => break,
+                    Err(wishbone::WishboneServerError::AccessDenied(why)) => {
+                        warn!("Refused a packet from Wishbone client {}: {}", peer, why);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Error from Wishbone client {}: {:?}", peer, e);
+                        break;
+                    }
+                }
+                if let Some(watch) = &bus_errors {
+                    watch.after_batch(&thread_bridge);
+                }
+            }
+            info!("Wishbone client {} disconnected", peer);
+        });
+    }
+}
+
+#[cfg(feature = "random-test")]
+pub fn random_test(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::time::Instant;
+
+    let random_addr = match cfg.random_address {
+        Some(s) => s,
+        None => 0x1000_0000 + 8192,
+    };
+    let random_range = match cfg.random_range {
+        Some(s) => s,
+        None => 0,
+    };
+    info!(
+        "writing random values to 0x{:08x} - 0x{:08x}",
+        random_addr,
+        random_addr + random_range
+    );
+
+    // Stop at a loop boundary on Ctrl-C, so that the rates are still shown
+    let _busy = shutdown::busy();
+    let stop = AtomicBool::new(false);
+    let loops = AtomicU64::new(0);
+    let started = Instant::now();
+    let threads = cfg.random_threads.max(1);
+    let result = if threads == 1 {
+        random_loop(
+            cfg,
+            &bridge,
+            random_addr,
+            cfg.random_range,
+            "",
+            &stop,
+            &loops,
+        )
+    } else {
+        // Give each thread its own part of the range, so that one thread's
+        // writes can't be mistaken for another's corruption.
+        let slice = cfg.random_range.map(|range| (range / threads) & !3);
+        info!("running {} threads at once", threads);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|n| {
+                    let bridge = bridge.clone();
+                    let (stop, loops) = (&stop, &loops);
+                    let base = random_addr + n * slice.unwrap_or(4);
+                    scope.spawn(move || {
+                        let result = random_loop(
+                            cfg,
+                            &bridge,
+                            base,
+                            slice,
+                            &format!("thread {}: ", n),
+                            stop,
+                            loops,
+                        );
+                        if result.is_err() {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                        result
+                    })
+                })
+                .collect();
+            let results: Vec<_> = handles
+                .into_iter()
+                .map(|handle| handle.join().expect("random-test thread panicked"))
+                .collect();
+            results.into_iter().find(|r| r.is_err()).unwrap_or(Ok(()))
+        })
+    };
+
+    let loops = loops.load(Ordering::Relaxed);
+    let elapsed = started.elapsed().as_secs_f64();
+    if elapsed > 0.0 {
+        // Each loop is a poke followed by a peek
+        info!(
+            "{} loops in {:.1} s: {:.0} transactions/s",
+            loops,
+            elapsed,
+            (loops * 2) as f64 / elapsed
+        );
+    }
+    result?;
+    info!("no errors encountered");
+    Ok(())
+}
+
+/// Write random values to `base`, plus up to `range` bytes, reading each one
+/// back, until `cfg.random_loops` is reached or `stop` is set. Each loop is
+/// counted in `loops`, and `label` starts each message.
+#[cfg(feature = "random-test")]
+fn random_loop(
+    cfg: &Config,
+    bridge: &Bridge,
+    base: u32,
+    range: Option<u32>,
+    label: &str,
+    stop: &std::sync::atomic::AtomicBool,
+    loops: &std::sync::atomic::AtomicU64,
+) -> Result<(), ServerError> {
+    use std::sync::atomic::Ordering;
+
+    let mut loop_counter: u32 = 0;
+    while !stop.load(Ordering::Relaxed) && !shutdown::requested() {
+        let val = random::<u32>();
+        let extra_addr = match range {
+            Some(s) => (random::<u32>() % s) & !3,
+            None => 0,
+        };
+        bridge.poke(base + extra_addr, val)?;
+        let cmp = bridge.peek(base + extra_addr)?;
+        if cmp != val {
+            error!(
+                "{}loop {} @ 0x{:08x}: expected 0x{:08x}, got 0x{:08x}",
+                label,
+                loop_counter,
+                base + extra_addr,
+                val,
+                cmp
+            );
+            return Err(ServerError::RandomValueError(loop_counter, val, cmp));
+        }
+        if (loop_counter % 1000) == 0 {
+            info!(
+                "{}loop: {} @ 0x{:08x} (0x{:08x})",
+                label,
+                loop_counter,
+                extra_addr + base,
+                val
+            );
+        }
+        loops.fetch_add(1, Ordering::Relaxed);
+        loop_counter = loop_counter.wrapping_add(1);
+        if let Some(max_loops) = cfg.random_loops {
+            if loop_counter > max_loops {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Refuse a user-initiated write that overlaps a region in `cfg.write_guard`.
+pub(crate) fn check_write(cfg: &Config, addr: u32, length: u32) -> Result<(), ServerError> {
+    cfg.write_guard
+        .check(addr, length)
+        .map_err(|region| ServerError::ProtectedWrite(addr, region.name.clone()))
+}
+
+/// Make each of the `--set-field` changes, reading and writing each register
+/// only once, however many of its fields change.
+fn set_fields(cfg: &Config, bridge: &MappedBridge) -> Result<(), ServerError> {
+    let mut addrs: Vec<u32> = cfg.field_writes.iter().map(|w| w.addr).collect();
+    addrs.sort_unstable();
+    addrs.dedup();
+    for addr in addrs {
+        check_write(cfg, addr, 4)?;
+        let old = bridge.peek(addr)?;
+        let new = cfg
+            .field_writes
+            .iter()
+            .filter(|w| w.addr == addr)
+            .fold(old, |value, w| w.field.insert(value, w.value));
+        bridge.poke(addr, new)?;
+    }
+    Ok(())
+}
+
+pub fn memory_access(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let mut bridge = MappedBridge::new(bridge);
+    bridge
+        .regions(cfg.memory_regions.iter().cloned())
+        .policy(cfg.region_policy);
+    set_fields(cfg, &bridge)?;
+    if let Some(addr) = cfg.memory_address {
+        if let Some(value) = cfg.memory_value {
+            if cfg.burst_length == 4 {
+                check_write(cfg, addr, 4)?;
+                bridge.poke(addr, value)?;
+            }
+        } else if let Some(file_name) = &cfg.burst_source {
+            use std::io::Read;
+            info!("Loading contents of {} to 0x{:08x}", file_name, addr);
+            let mut f = File::open(file_name)?;
+            let mut data: Vec<u8> = vec![];
+            f.read_to_end(&mut data)?;
+            check_write(cfg, addr, data.len() as u32)?;
+            info!("Sending {} bytes", data.len());
+            bridge.burst_write(addr, &data)?;
+        } else if let Some(file_name) = &cfg.diff_file {
+            hexdump::diff(cfg, &bridge, addr, file_name)?;
+        } else if let Some(interval) = cfg.watch_interval {
+            hexdump::watch(cfg, &bridge, addr, interval)?;
+        } else {
+            if cfg.burst_length == 4 {
+                let val = bridge.peek(addr)?;
+                match &cfg.memory_register {
+                    _ if cfg.output == OutputFormat::Json => output::print_peek(cfg, addr, val),
+                    Some(name) => {
+                        println!("Value at {:08x} ({}): {:08x}", addr, name, val);
+                        if let Some(fields) = cfg.csr_fields.get(name) {
+                            println!("{}: {}", name, csr_map::decode_fields(fields, val));
+                        }
+                    }
+                    None => {
+                        println!("Value at {:08x}: {:08x}", addr, val);
+                        if let Some((name, fields)) =
+                            cfg.fields_at(addr).filter(|_| cfg.show_fields)
+                        {
+                            println!("{}: {}", name, csr_map::decode_fields(fields, val));
+                        }
+                    }
+                }
+            } else {
+                let array = bridge.burst_read_skip_sensitive(addr, cfg.burst_length)?;
+                if cfg.output == OutputFormat::Json {
+                    output::print_burst(addr, &array);
+                } else if cfg.hexdump {
+                    for line in hexdump::hexdump(addr, &array, &[]) {
+                        println!("{}", line);
+                    }
+                } else {
+                    use std::io::Write;
+                    if array.iter().any(|b| b.is_none()) {
+                        warn!("skipped read-sensitive registers, filling them with 0");
+                    }
+                    let array: Vec<u8> = array.iter().map(|b| b.unwrap_or(0)).collect();
+                    io::stdout().write_all(&array)?;
+                }
+            }
+        }
+    } else if cfg.field_writes.is_empty() {
+        println!("No operation and no address specified!");
+        println!(
+            "Try specifying an address such as \"0x10000000\".  See --help for more information"
+        );
+    }
+    Ok(())
+}
+
+/// Halt the CPU and write its registers, along with the memory regions
+/// picked with `--core-region`, to an ELF core file. The CPU is left
+/// running afterwards if it was running to begin with.
+#[cfg(feature = "cpu")]
+pub fn core_dump(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let cpu = connect_cpu(cfg, &bridge)?;
+    let was_running = !cpu.is_halted(&bridge)?;
+    cpu.halt(&bridge)?;
+    if cfg.core_regions.is_empty() {
+        warn!("no --core-region given, so the core file will only have registers");
+    }
+    let out = BufWriter::new(File::create(&cfg.core_file)?);
+    cpu.write_core_dump(&bridge, &cfg.core_regions, out)?;
+    info!("wrote core file {}", cfg.core_file);
+    if was_running {
+        cpu.resume(&bridge)?;
+    }
+    Ok(())
+}
+
+/// How much `load_file()` writes or reads back in one burst
+const LOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Only this many bytes that fail `--verify` are printed
+const MAX_MISMATCHES: u32 = 16;
+
+/// How long to give the SoC to come out of reset before `boot()` halts the
+/// CPU
+#[cfg(feature = "cpu")]
+const BOOT_RESET_TIME: Duration = Duration::from_millis(100);
+
+/// Write whole words to `addr`, as a burst unless `bursts` is clear. If the
+/// bridge turns out not to support bursts, `bursts` is cleared and the words
+/// are poked one at a time from then on.
+fn write_words(
+    bridge: &Bridge,
+    addr: u32,
+    data: &[u8],
+    bursts: &mut bool,
+) -> Result<(), ServerError> {
+    if *bursts {
+        match bridge.burst_write(addr, data) {
+            Err(BridgeError::ProtocolNotSupported) => *bursts = false,
+            result => return Ok(result?),
+        }
+    }
+    for (word, offset) in data.chunks_exact(4).zip((addr..).step_by(4)) {
+        bridge.poke(
+            offset,
+            u32::from_le_bytes([word[0], word[1], word[2], word[3]]),
+        )?;
+    }
+    Ok(())
+}
+
+/// Read `length` bytes of whole words from `addr`, the counterpart to
+/// `write_words()`.
+fn read_words(
+    bridge: &Bridge,
+    addr: u32,
+    length: u32,
+    bursts: &mut bool,
+) -> Result<Vec<u8>, ServerError> {
+    if *bursts {
+        match bridge.burst_read(addr, length) {
+            Err(BridgeError::ProtocolNotSupported) => *bursts = false,
+            result => return Ok(result?),
+        }
+    }
+    let mut data = Vec::with_capacity(length as usize);
+    for offset in (addr..addr + length).step_by(4) {
+        data.extend_from_slice(&bridge.peek(offset)?.to_le_bytes());
+    }
+    Ok(data)
+}
+
+pub fn load_file(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let file_name = match &cfg.load_name {
+        Some(file_name) => file_name,
+        None => {
+            println!("No filename specified!");
+            return Ok(());
+        }
+    };
+    let image = Image::load(file_name, cfg.load_addr)?;
+    if image.format.has_addresses() && cfg.load_addr.is_some() {
+        warn!(
+            "ignoring --load-address, as {} says where each part of it goes",
+            file_name
+        );
+    }
+    for segment in &image.segments {
+        check_write(cfg, segment.addr, segment.data.len() as u32)?;
+        if cfg.load_zero_bss {
+            check_write(
+                cfg,
+                segment.addr,
+                segment.data.len() as u32 + segment.zeroed,
+            )?;
+        }
+    }
+
+    // Keep the CPU from running half-loaded code
+    #[cfg(feature = "cpu")]
+    let cpu = if cfg.load_run {
+        let cpu = connect_cpu(cfg, &bridge)?;
+        cpu.halt(&bridge)?;
+        Some(cpu)
+    } else {
+        None
+    };
+
+    let mut bursts = true;
+    let mut written = 0;
+    let mut mismatches = 0;
+    for segment in &image.segments {
+        let f_len = segment.data.len() as u32;
+        let addr = segment.addr;
+        info!(
+            "Loading {} bytes from {} to address 0x{:08x}",
+            f_len, file_name, addr
+        );
+        let mut data = segment.data.clone();
+        if cfg.load_zero_bss && segment.zeroed > 0 {
+            info!(
+                "Clearing {} bytes at 0x{:08x}",
+                segment.zeroed,
+                addr + f_len
+            );
+            data.resize(data.len() + segment.zeroed as usize, 0);
+        }
+        // Pad out a partial word at the end with zeroes
+        data.resize((data.len() + 3) & !3, 0);
+
+        for (index, chunk) in data.chunks(LOAD_CHUNK_SIZE).enumerate() {
+            let offset = (index * LOAD_CHUNK_SIZE) as u32;
+            info!(
+                "write to {:08x} - {}%",
+                addr + offset,
+                offset as u64 * 100 / data.len() as u64
+            );
+            write_words(&bridge, addr + offset, chunk, &mut bursts)?;
+        }
+        written += f_len;
+
+        if cfg.load_verify {
+            info!("Reading back 0x{:08x} to verify it", addr);
+            let mut offset = 0;
+            for chunk in data.chunks(LOAD_CHUNK_SIZE) {
+                let readback = read_words(&bridge, addr + offset, chunk.len() as u32, &mut bursts)?;
+                for (i, (expected, observed)) in chunk.iter().zip(&readback).enumerate() {
+                    if expected != observed {
+                        mismatches += 1;
+                        if mismatches <= MAX_MISMATCHES {
+                            error!(
+                                "offset 0x{:x} (0x{:08x}): wrote 0x{:02x}, read back 0x{:02x}",
+                                offset as usize + i,
+                                addr + offset + i as u32,
+                                expected,
+                                observed
+                            );
+                        }
+                    }
+                }
+                offset += chunk.len() as u32;
+            }
+        }
+    }
+    info!("Done. Wrote {} bytes", written);
+    if mismatches > 0 {
+        error!("{} bytes didn't read back as they were written", mismatches);
+        return Err(ServerError::VerifyFailed(mismatches));
+    } else if cfg.load_verify {
+        info!("Verified {} bytes", written);
+    }
+
+    #[cfg(feature = "cpu")]
+    if let Some(cpu) = cpu {
+        // A raw binary starts at the beginning
+        let entry = cfg
+            .load_jump
+            .or(image.entry)
+            .or_else(|| image.segments.first().map(|s| s.addr));
+        if let Some(entry) = entry {
+            info!("Starting the CPU at 0x{:08x}", entry);
+            cpu.write_register_by_name(&bridge, "pc", entry as u64)?;
+        } else {
+            info!("Starting the CPU");
+        }
+        cpu.flush_cache(&bridge)?;
+        cpu.resume(&bridge)?;
+    }
+    Ok(())
+}
+
+/// Load a program and start the CPU running it, like `litex_term`'s serial
+/// boot but over any bridge. With `cfg.boot_reset`, the SoC is reset
+/// first, so that nothing the old program set going is still running.
+#[cfg(feature = "cpu")]
+pub fn boot(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    if cfg.boot_reset {
+        let addr = match cfg.register_mapping.get("ctrl_reset") {
+            Some(Some(addr)) => *addr,
+            _ => return Err(ServerError::UnmappableAddress("ctrl_reset".to_owned())),
+        };
+        info!("Resetting the SoC");
+        // This clears itself
+        bridge.poke(addr, 1)?;
+        std::thread::sleep(BOOT_RESET_TIME);
+    }
+    load_file(cfg, bridge)
+}
+
+/// The range of flash that programming `length` bytes at `addr` may
+/// erase, which is every sector it touches. This has to match
+/// `flash_program()`.
+#[cfg(feature = "flash")]
+fn flash_erase_span(addr: u32, length: u32) -> (u32, u32) {
+    let start = addr & !(SECTOR_SIZE - 1);
+    let end = (addr as u64 + length as u64 + SECTOR_SIZE as u64 - 1) & !(SECTOR_SIZE as u64 - 1);
+    (start, end.min(u32::MAX as u64) as u32)
+}
+
+/// Read `length` bytes at `offset` into the flash
+#[cfg(feature = "flash")]
+fn read_flash(
+    bridge: &Bridge,
+    flash: &SpiNor,
+    offset: u32,
+    length: u32,
+) -> Result<Vec<u8>, ServerError> {
+    let mut bursts = true;
+    let mut data = Vec::with_capacity(length as usize);
+    for chunk in (offset..offset + length).step_by(LOAD_CHUNK_SIZE) {
+        let chunk_length = (LOAD_CHUNK_SIZE as u32).min(offset + length - chunk);
+        data.extend(read_words(
+            bridge,
+            flash.region + chunk,
+            chunk_length,
+            &mut bursts,
+        )?);
+    }
+    Ok(data)
+}
+
+/// How much of the flash `flash_program()` had to touch
+#[cfg(feature = "flash")]
+#[derive(Default)]
+struct FlashStats {
+    unchanged: u32,
+    erased: u32,
+    programmed: u32,
+    skipped_pages: u32,
+}
+
+/// Work out everything that flashing `data` to `addr` will write, and
+/// refuse if any of it looks like it would brick the board. This is all
+/// done before the CPU is halted, so nothing has been touched if it fails.
+#[cfg(feature = "flash")]
+fn plan_flash_writes(
+    cfg: &Config,
+    addr: u32,
+    data: Vec<u8>,
+) -> Result<Vec<(u32, Vec<u8>)>, ServerError> {
+    let bitstream = Bitstream::parse(&data);
+    if let Some(bitstream) = &bitstream {
+        info!(
+            "{} is a {} bitstream{}{}",
+            cfg.load_name.as_deref().unwrap_or_default(),
+            bitstream.family.name(),
+            bitstream
+                .part
+                .as_ref()
+                .map(|p| format!(" for {}", p))
+                .unwrap_or_default(),
+            bitstream
+                .idcode
+                .map(|id| format!(" (IDCODE 0x{:08x})", id))
+                .unwrap_or_default()
+        );
+    }
+
+    if let Some(expected) = cfg.flash_idcode {
+        match &bitstream {
+            None => {
+                return Err(ServerError::BitstreamError(
+                    "file doesn't look like a bitstream".to_owned(),
+                ))
+            }
+            Some(bitstream) if !bitstream.matches_idcode(expected) => {
+                return Err(ServerError::BitstreamError(match bitstream.idcode {
+                    Some(idcode) => format!(
+                        "bitstream is for IDCODE 0x{:08x}, but the target is 0x{:08x}",
+                        idcode, expected
+                    ),
+                    None => format!(
+                        "{} bitstreams don't carry an IDCODE",
+                        bitstream.family.name()
+                    ),
+                }))
+            }
+            Some(_) => info!("bitstream IDCODE matches 0x{:08x}", expected),
+        }
+    }
+
+    let mut images = vec![(addr, data)];
+    if let Some(header_addr) = cfg.flash_multiboot {
+        match bitstream.map(|b| b.family) {
+            Some(FpgaFamily::Xilinx7) => {
+                info!(
+                    "Pointing the multiboot header at 0x{:08x} to 0x{:08x}",
+                    header_addr, addr
+                );
+                images.push((header_addr, bitstream::xilinx_multiboot_header(addr)));
+            }
+            Some(family) => {
+                return Err(ServerError::BitstreamError(format!(
+                    "multiboot headers aren't supported for {} yet",
+                    family.name()
+                )))
+            }
+            None => {
+                return Err(ServerError::BitstreamError(
+                    "a multiboot header can only point at a bitstream".to_owned(),
+                ))
+            }
+        }
+    }
+
+    for (addr, data) in &images {
+        let (start, end) = flash_erase_span(*addr, data.len() as u32);
+        check_golden(cfg, start, end)?;
+    }
+    Ok(images)
+}
+
+/// Refuse to erase the flash from `start` to `end` if it overlaps the
+/// golden image, unless `--allow-golden` was given.
+#[cfg(feature = "flash")]
+fn check_golden(cfg: &Config, start: u32, end: u32) -> Result<(), ServerError> {
+    if let Some((golden_start, golden_length)) = cfg.flash_golden {
+        if start < golden_start.saturating_add(golden_length) && end > golden_start {
+            if !cfg.allow_golden {
+                return Err(ServerError::GoldenImage(start, end));
+            }
+            warn!(
+                "overwriting the golden image at 0x{:08x}, as --allow-golden was given",
+                golden_start
+            );
+        }
+    }
+    Ok(())
+}
+
+// demo of burn performance: https://asciinema.org/a/j2HfItVBwRbdimuFMvplRA4DT
+#[cfg(feature = "flash")]
+pub fn flash_program(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    flash_images(cfg, bridge).map(|_| ())
+}
+
+/// Burn `cfg.load_name` into the flash, returning whether every image in it
+/// read back correctly.
+#[cfg(feature = "flash")]
+fn flash_images(cfg: &Config, bridge: Bridge) -> Result<bool, ServerError> {
+    let file_name = match &cfg.load_name {
+        Some(file_name) => file_name,
+        None => {
+            println!("No filename specified!");
+            return Ok(false);
+        }
+    };
+    // Gaps between the records of a hex file are left erased
+    let image = match Image::load(file_name, cfg.load_addr) {
+        Err(ImageError::NoAddress(_)) => None,
+        image => Some(image?.flatten(0xff).unwrap_or_default()),
+    };
+    match image {
+        Some((addr, data)) => {
+            info!("Burning contents of {} to 0x{:08x}", file_name, addr);
+            flash_data(cfg, bridge, addr, data)
+        }
+        None => {
+            error!("No target address specified");
+            Ok(false)
+        }
+    }
+}
+
+/// Burn `data` into the flash at `addr`, returning whether every image it
+/// turned into read back correctly.
+#[cfg(feature = "flash")]
+fn flash_data(cfg: &Config, bridge: Bridge, addr: u32, data: Vec<u8>) -> Result<bool, ServerError> {
+    let flash = SpiNor::from_config(cfg)?;
+    let register = |name: &str| cfg.register_mapping.get(name).copied().flatten();
+    // betrusted resets through `reboot`, other LiteX SoCs through `ctrl`
+    let reset_addr = register("reboot_cpu_reset").or_else(|| register("ctrl_reset"));
+    if reset_addr.is_none() && !cfg.flash_no_reset {
+        warn!("no reboot_cpu_reset or ctrl_reset in csr.csv, so the CPU won't be reset");
+    }
+    // Without a debug bridge, the CPU is left running. That's fine as long
+    // as it isn't running from the flash.
+    let vexriscv_debug_addr = register("vexriscv_debug");
+    let halt = |bridge: &Bridge| -> Result<(), BridgeError> {
+        if let Some(addr) = vexriscv_debug_addr {
+            info!("Halting CPU.");
+            bridge.poke(addr, 0x00020000)?;
+        }
+        Ok(())
+    };
+    let resume = |bridge: &Bridge| -> Result<(), BridgeError> {
+        if let Some(addr) = vexriscv_debug_addr {
+            bridge.poke(addr, 0x02000000)?;
+            info!("Resuming CPU.");
+        }
+        Ok(())
+    };
+
+    info!("{} total bytes", data.len());
+    if data.len() == 0 {
+        return Ok(true);
+    }
+
+    if addr + data.len() as u32 >= 0x0800_0000 {
+        error!("Write data out of bounds! Aborting.");
+        return Err(ServerError::UnmappableAddress(
+            (addr + data.len() as u32).to_string(),
+        ));
+    }
+    let images = plan_flash_writes(cfg, addr, data)?;
+
+    // note to those referring to this as reference code for local hardware:
+    // WIP bit must be consulted when running from the local CPU, as it runs much faster
+    // than the command state machines can finish. However, via USB we can safely assume
+    // all commands complete issuing before the next USB packet can arrive.
+
+    // Finish the page or sector in progress if we're killed, then
+    // leave the flash write-protected and the CPU running.
+    let _busy = shutdown::busy();
+    let stop = |bridge: &Bridge| {
+        warn!("stopping before the flash has been fully programmed");
+        flash.finish(bridge).ok();
+        resume(bridge).ok();
+        ServerError::Interrupted
+    };
+
+    halt(&bridge)?;
+
+    ///////// ID code check
+    let chip = flash.probe(&bridge)?;
+    if let Some((addr, data)) = images
+        .iter()
+        .find(|(addr, data)| *addr as u64 + data.len() as u64 > chip.size as u64)
+    {
+        resume(&bridge)?;
+        return Err(ServerError::UnmappableAddress(format!(
+            "0x{:08x}, past the end of the {} byte flash",
+            addr + data.len() as u32,
+            chip.size
+        )));
+    }
+
+    let image_count = images.len();
+    let mut all_passed = true;
+    for (index, (addr, data)) in images.into_iter().enumerate() {
+        // Whole sectors are erased, so work a sector at a time, with
+        // the new data laid over what's already there. Whatever
+        // else is in the first and last sectors is kept.
+        let (start, end) = flash_erase_span(addr, data.len() as u32);
+        info!(
+            "Reading 0x{:08x}-0x{:08x} to see what's changed...",
+            start, end
+        );
+        let old = read_flash(&bridge, &flash, start, end - start)?;
+        let mut new = old.clone();
+        let lead = (addr - start) as usize;
+        new[lead..lead + data.len()].copy_from_slice(&data);
+
+        let blank = [0xff; PAGE_SIZE as usize];
+        let mut stats = FlashStats::default();
+        let pb = ProgressBar::new(new.len() as u64);
+        pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .progress_chars("#>-"));
+        let sectors = old
+            .chunks(SECTOR_SIZE as usize)
+            .zip(new.chunks(SECTOR_SIZE as usize));
+        for (i, (old_sector, new_sector)) in sectors.enumerate() {
+            if shutdown::requested() {
+                return Err(stop(&bridge));
+            }
+            let sector = start + i as u32 * SECTOR_SIZE;
+            pb.inc(new_sector.len() as u64);
+            if old_sector == new_sector {
+                stats.unchanged += 1;
+                continue;
+            }
+
+            // Programming can only clear bits, so the sector only
+            // needs erasing if a bit has to be set again
+            let erase = old_sector.iter().zip(new_sector).any(|(o, n)| n & !o != 0);
+            if erase {
+                if flash.erase(&bridge, sector, SECTOR_SIZE)? {
+                    error!("E_FAIL set, erasing 0x{:08x} may have failed.", sector);
+                }
+                stats.erased += 1;
+            } else {
+                stats.programmed += 1;
+            }
+
+            let pages = old_sector
+                .chunks(PAGE_SIZE as usize)
+                .zip(new_sector.chunks(PAGE_SIZE as usize));
+            for (j, (old_page, new_page)) in pages.enumerate() {
+                let current = if erase {
+                    &blank[..new_page.len()]
+                } else {
+                    old_page
+                };
+                if new_page == current {
+                    stats.skipped_pages += 1;
+                    continue;
+                }
+                let failed = flash.program_page(
+                    &bridge,
+                    sector + j as u32 * PAGE_SIZE,
+                    new_page,
+                    cfg.careful_flashing,
+                )?;
+                if failed {
+                    error!("P_FAIL set, programming may have failed.")
+                }
+            }
+        }
+        pb.finish_with_message("Write finished");
+        info!(
+            "{} sectors unchanged, {} erased, {} programmed without erasing, {} pages skipped",
+            stats.unchanged, stats.erased, stats.programmed, stats.skipped_pages
+        );
+
+        flash.finish(&bridge)?;
+
+        /////////// verify
+        info!("Performing readback for verification...");
+        let page = read_flash(&bridge, &flash, start, end - start);
+        info!("Comparing results...");
+        let passed = match page {
+            Ok(array) => {
+                let mut error_count = 0;
+                for i in 0..array.len() {
+                    if new[i] != array[i] {
+                        error_count += 1;
+                    }
+                }
+                if error_count != 0 {
+                    info!(
+                        "{} errors found in verification, programming failed",
+                        error_count
+                    );
+                } else {
+                    info!("No errors found, programming passed");
+                }
+                error_count == 0
+            }
+            _ => {
+                error!("Low-level error occured during verification readback.");
+                false
+            }
+        };
+
+        all_passed &= passed;
+        // Don't point the multiboot header at an image that didn't
+        // make it.
+        if !passed && index + 1 < image_count {
+            error!("Skipping the remaining writes, since this one failed");
+            break;
+        }
+    }
+    resume(&bridge)?;
+
+    ////////// reset the CPU, under the presumption that code has changed and we should restart the CPU
+    if let (false, Some(reset_addr)) = (cfg.flash_no_reset, reset_addr) {
+        info!("Resetting CPU.");
+        bridge.poke(reset_addr, 1)?;
+    }
+    Ok(all_passed)
+}
+
+#[cfg(feature = "terminal")]
+use terminal::{Action, Event, KeyCode, KeyEvent, KeyModifiers, Retrieved, Terminal, Value};
+#[cfg(feature = "terminal")]
+struct IOInterface {
+    term: Terminal<std::io::Stdout>,
+    capture_mouse: bool,
+    _restore: shutdown::Cleanup,
+}
+
+/// Characters sent to the crossover UART before pausing, so that a paste
+/// arrives in pieces that the firmware has time to keep up with. This
+/// matches the depth of LiteX's UART FIFOs.
+#[cfg(feature = "terminal")]
+const PASTE_CHUNK_LEN: usize = 16;
+
+/// The UART the terminal attaches to unless `--terminal-uart` says otherwise
+pub const DEFAULT_TERMINAL_UART: &str = "uart_xover";
+
+/// One of the UARTs the terminal is attached to, along with whatever is
+/// waiting to be sent to it
+#[cfg(feature = "terminal")]
+struct TerminalUart {
+    name: String,
+    rxtx: u32,
+    txfull: u32,
+    rxempty: u32,
+
+    /// Keystrokes that haven't been sent yet. Input is only sent while the
+    /// target's FIFO has room, and reading carries on in the meantime, so a
+    /// firmware that echoes everything it's sent never gets stuck waiting
+    /// for us.
+    pending: std::collections::VecDeque<u8>,
+    next_send: std::time::Instant,
+    pty: Option<pty::Pty>,
+
+    /// Whether the next character printed needs the UART's name in front
+    at_line_start: bool,
+}
+
+#[cfg(feature = "terminal")]
+impl TerminalUart {
+    fn new(cfg: &Config, name: &str) -> Result<TerminalUart, ServerError> {
+        // Without a csr.csv, assume LiteX's usual crossover UART addresses.
+        let register = |suffix: &str, default: u32| {
+            let reg = format!("{}_{}", name, suffix);
+            match cfg.register_mapping.get(&reg) {
+                Some(Some(addr)) => Ok(*addr),
+                None if name == DEFAULT_TERMINAL_UART => Ok(default),
+                _ => Err(ServerError::UnmappableAddress(reg)),
+            }
+        };
+        let pty = if cfg.terminal_pty {
+            // Every UART after the first gets a link with its name on the end.
+            let link = cfg.terminal_pty_link.as_ref().map(|link| {
+                if cfg.terminal_uarts.first().map(String::as_str) == Some(name) {
+                    link.clone()
+                } else {
+                    format!("{}-{}", link, name)
+                }
+            });
+            let pty = pty::Pty::open(link.as_deref())?;
+            match &link {
+                Some(link) => info!(
+                    "mirroring {} on {} (linked from {})",
+                    name,
+                    pty.path().display(),
+                    link
+                ),
+                None => info!("mirroring {} on {}", name, pty.path().display()),
+            }
+            Some(pty)
+        } else {
+            None
+        };
+        Ok(TerminalUart {
+            name: name.to_owned(),
+            rxtx: register("rxtx", 0xe000_1818)?,
+            txfull: register("txfull", 0xe000_181c)?,
+            rxempty: register("rxempty", 0xe000_1820)?,
+            pending: std::collections::VecDeque::new(),
+            next_send: std::time::Instant::now(),
+            pty,
+            at_line_start: true,
+        })
+    }
+
+    /// Print whatever the target has sent, with each line labelled if
+    /// there's more than one UART.
+    fn receive(&mut self, bridge: &Bridge, label: bool) -> Result<(), ServerError> {
+        use std::io::{stdout, Write};
+
+        if !poll_uart(self.rxempty, bridge)? {
+            return Ok(());
+        }
+        let mut char_buffer = vec![];
+        let mut read_count = 0;
+        while bridge.peek(self.rxempty)? == 0 && read_count < 100 {
+            read_count += 1;
+            char_buffer.push(bridge.peek(self.rxtx)? as u8);
+        }
+        if let Some(pty) = &mut self.pty {
+            pty.write(&char_buffer)?;
+        }
+        if !label {
+            print!("{}", String::from_utf8_lossy(&char_buffer));
+        } else {
+            let mut labelled = vec![];
+            for &c in &char_buffer {
+                if self.at_line_start {
+                    labelled.extend_from_slice(format!("[{}] ", self.name).as_bytes());
+                }
+                labelled.push(c);
+                self.at_line_start = c == b'\n';
+            }
+            print!("{}", String::from_utf8_lossy(&labelled));
+        }
+        stdout().flush().ok();
+        Ok(())
+    }
+
+    /// Send some of what's pending, pausing between pieces of a paste.
+    fn send(&mut self, bridge: &Bridge, paste_delay: Duration) -> Result<(), ServerError> {
+        use std::time::Instant;
+
+        // Whatever other programs write to the pseudo-terminal is sent
+        // just like keystrokes.
+        if let Some(pty) = &mut self.pty {
+            let mut buf = [0; 256];
+            let count = pty.read(&mut buf)?;
+            self.pending.extend(&buf[..count]);
+        }
+
+        if self.pending.is_empty() || Instant::now() < self.next_send {
+            return Ok(());
+        }
+        let mut sent = 0;
+        while sent < PASTE_CHUNK_LEN && bridge.peek(self.txfull)? == 0 {
+            match self.pending.pop_front() {
+                Some(c) => bridge.poke(self.rxtx, c as u32)?,
+                None => break,
+            }
+            sent += 1;
+        }
+        if !self.pending.is_empty() {
+            self.next_send = Instant::now() + paste_delay;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "terminal")]
+pub fn terminal_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let poll_time = 10;
+    let mut uarts = cfg
+        .terminal_uarts
+        .iter()
+        .map(|name| TerminalUart::new(cfg, name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let label = uarts.len() > 1;
+    // The UART that keystrokes go to
+    let mut console = 0;
+    if label {
+        info!(
+            "typing goes to {}, press Ctrl-T to switch to the next UART",
+            uarts[console].name
+        );
+    }
+    let my_terminal = IOInterface::new(cfg.terminal_mouse);
+
+    loop {
+        for uart in uarts.iter_mut() {
+            uart.receive(&bridge, label)?;
+            uart.send(&bridge, cfg.terminal_paste_delay)?;
+        }
+
+        // Don't wait for keys while there's still input to send, and once
+        // one arrives, take everything else that's already there. A paste
+        // shows up as a flood of keys all at once.
+        let mut timeout = if uarts.iter().all(|uart| uart.pending.is_empty()) {
+            poll_time
+        } else {
+            1
+        };
+        while let Retrieved::Event(Some(event)) = my_terminal
+            .term
+            .get(Value::Event(Some(Duration::from_millis(timeout))))?
+        {
+            timeout = 0;
+            let pending = &mut uarts[console].pending;
+            match event {
+                Event::Key(KeyEvent {
+                    code: KeyCode::Esc, ..
+                }) => return Ok(()),
+                Event::Key(KeyEvent {
+                    code: KeyCode::Enter,
+                    ..
+                }) => pending.extend(b"\r\n"),
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('c'),
+                    modifiers: KeyModifiers::CONTROL,
+                }) => return Ok(()),
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('t'),
+                    modifiers: KeyModifiers::CONTROL,
+                }) if label => {
+                    console = (console + 1) % uarts.len();
+                    print!("\r\n[typing goes to {}]\r\n", uarts[console].name);
+                    for uart in uarts.iter_mut() {
+                        uart.at_line_start = true;
+                    }
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char(e),
+                    ..
+                }) => pending.extend(e.encode_utf8(&mut [0; 4]).bytes()),
+                _event => {
+                    // println!("{:?}\r", _event);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "terminal")]
+impl IOInterface {
+    pub fn new(capture_mouse: bool) -> IOInterface {
+        let term = terminal::stdout();
+        term.act(Action::EnableRawMode)
+            .expect("can't enable raw mode");
+        if capture_mouse {
+            term.act(Action::EnableMouseCapture)
+                .expect("can't capture mouse");
+        }
+        // Dropping this doesn't happen if we're killed, so undo it then too.
+        let restore = shutdown::on_shutdown(move || {
+            let term = terminal::stdout();
+            if capture_mouse {
+                term.act(Action::DisableMouseCapture).ok();
+            }
+            term.act(Action::DisableRawMode).ok();
+        });
+        IOInterface {
+            term,
+            capture_mouse,
+            _restore: restore,
+        }
+    }
+}
+#[cfg(feature = "terminal")]
+impl Drop for IOInterface {
+    fn drop(&mut self) {
+        if self.capture_mouse {
+            self.term.act(Action::DisableMouseCapture).ok();
+        }
+        self.term.act(Action::DisableRawMode).ok();
+    }
+}
+
+/// A messible to show the output of, or to send keystrokes to
+#[cfg(feature = "terminal")]
+struct MessibleStream {
+    name: String,
+    input: u32,
+    output: u32,
+    status: u32,
+
+    /// Whether the next character printed needs the messible's name in front
+    at_line_start: bool,
+}
+
+#[cfg(feature = "terminal")]
+impl MessibleStream {
+    /// Bits of the status register
+    const FULL: u32 = 1;
+    const HAVE: u32 = 2;
+
+    /// A messible whose `in`, `out`, and `status` registers follow one another
+    /// from `base`
+    fn at(name: &str, base: u32) -> MessibleStream {
+        MessibleStream {
+            name: name.to_owned(),
+            input: base,
+            output: base + 4,
+            status: base + 8,
+            at_line_start: true,
+        }
+    }
+
+    /// A messible from csr.csv, named by the prefix of its CSRs
+    fn named(cfg: &Config, name: &str) -> Result<MessibleStream, ServerError> {
+        let register = |suffix: &str| {
+            let reg = format!("{}_{}", name, suffix);
+            match cfg.register_mapping.get(&reg) {
+                Some(Some(addr)) => Ok(*addr),
+                _ => Err(ServerError::UnmappableAddress(reg)),
+            }
+        };
+        Ok(MessibleStream {
+            name: name.to_owned(),
+            input: register("in")?,
+            output: register("out")?,
+            status: register("status")?,
+            at_line_start: true,
+        })
+    }
+
+    /// Print whatever the target has put in the messible, with each line
+    /// labelled if there's more than one.
+    fn receive(&mut self, bridge: &Bridge, label: bool) -> Result<(), ServerError> {
+        use std::io::{stdout, Write};
+
+        let mut char_buffer = vec![];
+        let mut read_count = 0;
+        while bridge.peek(self.status)? & Self::HAVE != 0 && read_count < 100 {
+            read_count += 1;
+            let c = bridge.peek(self.output)? as u8;
+            if label && self.at_line_start {
+                char_buffer.extend_from_slice(format!("[{}] ", self.name).as_bytes());
+            }
+            self.at_line_start = c == b'\n';
+            char_buffer.push(c);
+        }
+        if !char_buffer.is_empty() {
+            print!("{}", String::from_utf8_lossy(&char_buffer));
+            stdout().flush().ok();
+        }
+        Ok(())
+    }
+
+    /// Send as much of `pending` as the messible has room for.
+    fn send(
+        &self,
+        bridge: &Bridge,
+        pending: &mut std::collections::VecDeque<u8>,
+    ) -> Result<(), ServerError> {
+        while !pending.is_empty() && bridge.peek(self.status)? & Self::FULL == 0 {
+            if let Some(c) = pending.pop_front() {
+                bridge.poke(self.input, c as u32)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "terminal")]
+pub fn messible_client(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let poll_time = 10;
+
+    let mut streams = if cfg.messibles.is_empty() {
+        vec![MessibleStream::at(
+            "messible",
+            cfg.messible_address.unwrap_or(0xe000_8000),
+        )]
+    } else {
+        cfg.messibles
+            .iter()
+            .map(|name| MessibleStream::named(cfg, name))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    let label = streams.len() > 1;
+    // Keystrokes go to this one, if there is one
+    let input = cfg
+        .messible_in
+        .as_ref()
+        .map(|name| MessibleStream::named(cfg, name))
+        .transpose()?;
+    let mut pending = std::collections::VecDeque::new();
+    let my_terminal = IOInterface::new(cfg.terminal_mouse);
+
+    loop {
+        for stream in streams.iter_mut() {
+            stream.receive(&bridge, label)?;
+        }
+        if let Some(input) = &input {
+            input.send(&bridge, &mut pending)?;
+        }
+
+        if let Retrieved::Event(event) = my_terminal
+            .term
+            .get(Value::Event(Some(Duration::from_millis(poll_time))))?
+        {
+            match event {
+                Some(Event::Key(KeyEvent {
+                    code: KeyCode::Esc, ..
+                })) => return Ok(()),
+                Some(Event::Key(KeyEvent {
+                    code: KeyCode::Char('c'),
+                    modifiers: KeyModifiers::CONTROL,
+                })) => return Ok(()),
+                Some(Event::Key(KeyEvent {
+                    code: KeyCode::Enter,
+                    ..
+                })) if input.is_some() => pending.push_back(b'\n'),
+                Some(Event::Key(KeyEvent {
+                    code: KeyCode::Char(e),
+                    ..
+                })) if input.is_some() => pending.extend(e.encode_utf8(&mut [0; 4]).bytes()),
+                Some(_event) => (),
+                None => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::config::Config;
+
+use serde_json::{json, Map, Value};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Print one result for `--output json`, as a line of JSON on stdout.
+/// `kind` goes in the `type` key, and the time, in seconds since the Unix
+/// epoch, in `timestamp`.
+pub fn print_json(kind: &str, mut result: Value) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs_f64())
+        .unwrap_or_default();
+    if let Value::Object(map) = &mut result {
+        map.insert("type".to_owned(), json!(kind));
+        map.insert("timestamp".to_owned(), json!(timestamp));
+    }
+    println!("{}", result);
+}
+
+/// Report an error for `--output json`, so that scripts reading stdout
+/// find out about it too.
+pub fn print_json_error(message: &str) {
+    print_json("error", json!({ "error": message }));
+}
+
+/// A peek of `addr`, along with the register's name and fields if they're
+/// known.
+pub(super) fn print_peek(cfg: &Config, addr: u32, value: u32) {
+    let mut result = json!({ "address": addr, "value": value });
+    let fields = match &cfg.memory_register {
+        Some(name) => {
+            result["register"] = json!(name);
+            cfg.csr_fields.get(name).map(|fields| fields.as_slice())
+        }
+        None => cfg.fields_at(addr).map(|(name, fields)| {
+            result["register"] = json!(name);
+            fields
+        }),
+    };
+    if let Some(fields) = fields {
+        let fields: Map<String, Value> = fields
+            .iter()
+            .map(|field| (field.name.clone(), json!(field.extract(value))))
+            .collect();
+        result["fields"] = Value::Object(fields);
+    }
+    print_json("peek", result);
+}
+
+/// A burst read from `addr`, as a string of hex digits. Read-sensitive
+/// registers that were skipped read as zero, and their addresses are listed
+/// in `skipped`.
+pub(super) fn print_burst(addr: u32, data: &[Option<u8>]) {
+    let hex: String = data
+        .iter()
+        .map(|b| format!("{:02x}", b.unwrap_or(0)))
+        .collect();
+    let skipped: Vec<u32> = data
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.is_none())
+        .map(|(offset, _)| addr + offset as u32)
+        .collect();
+    print_json(
+        "burst",
+        json!({
+            "address": addr,
+            "length": data.len(),
+            "data": hex,
+            "skipped": skipped,
+        }),
+    );
+}
@@ -0,0 +1,117 @@
+//! A pseudo-terminal mirroring the crossover UART, so that other programs
+//! can use the target's console while the terminal server is running.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The controlling side of a pseudo-terminal. Programs open `path()` as if
+/// it were a serial port.
+pub(crate) struct Pty {
+    master: File,
+    path: PathBuf,
+    link: Option<PathBuf>,
+
+    /// Held open so that the terminal keeps its settings, and reads don't
+    /// fail while nothing else has it open.
+    #[cfg(unix)]
+    _slave: File,
+}
+
+impl Pty {
+    /// Create a new raw pseudo-terminal, and if `link` is given, a symlink
+    /// to it with that name.
+    #[cfg(unix)]
+    pub fn open(link: Option<&str>) -> io::Result<Pty> {
+        use std::ffi::CStr;
+        use std::fs::OpenOptions;
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        use wishbone_bridge::sys::check;
+
+        let fd = check(unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) })?;
+        // Take ownership right away, so it's closed if anything below fails.
+        let master = unsafe { File::from_raw_fd(fd) };
+        check(unsafe { libc::grantpt(fd) })?;
+        check(unsafe { libc::unlockpt(fd) })?;
+        let name = unsafe { libc::ptsname(fd) };
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path = PathBuf::from(unsafe { CStr::from_ptr(name) }.to_string_lossy().as_ref());
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        // Pass bytes through untouched, rather than echoing them or
+        // waiting for whole lines.
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        check(unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) })?;
+        unsafe { libc::cfmakeraw(&mut termios) };
+        check(unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) })?;
+
+        let flags = check(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+        check(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+
+        let link = match link {
+            Some(link) => {
+                let link = PathBuf::from(link);
+                // Replace a link left behind by an earlier run, but nothing else.
+                if std::fs::symlink_metadata(&link).is_ok_and(|m| m.file_type().is_symlink()) {
+                    std::fs::remove_file(&link)?;
+                }
+                std::os::unix::fs::symlink(&path, &link)?;
+                Some(link)
+            }
+            None => None,
+        };
+
+        Ok(Pty {
+            master,
+            path,
+            link,
+            _slave: slave,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(_link: Option<&str>) -> io::Result<Pty> {
+        Err(io::Error::other(
+            "pseudo-terminals are only supported on Unix",
+        ))
+    }
+
+    /// The device other programs should open, e.g. `/dev/pts/3`
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read whatever other programs have written, without waiting.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.master.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            result => result,
+        }
+    }
+
+    /// Pass on output from the target. If nothing is reading it and the
+    /// kernel's buffer is full, the output is dropped, just as a real
+    /// serial port would.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.master.write_all(data) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            std::fs::remove_file(link).ok();
+        }
+    }
+}
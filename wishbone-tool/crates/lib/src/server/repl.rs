@@ -0,0 +1,228 @@
+//! An interactive prompt for poking at a board without reconnecting to it
+//! for every access. Addresses may be numbers or CSR names from csr.csv,
+//! which can be completed with Tab, and history is kept across sessions.
+
+use super::{check_write, ServerError};
+use crate::config::{parse_u32, Config};
+use crate::csr_map;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use wishbone_bridge::{Bridge, MappedBridge};
+
+use std::io;
+use std::path::PathBuf;
+
+const COMMANDS: &[&str] = &["peek", "poke", "dump", "csr", "help", "quit"];
+
+const HELP: &str = "\
+Commands:
+    peek ADDR             - Read a word
+    poke ADDR VALUE       - Write a word
+    dump ADDR LENGTH      - Show LENGTH bytes, leaving out read-sensitive registers
+    csr list [PATTERN]    - List the CSRs whose names contain PATTERN
+    help                  - Show this list
+    quit                  - Leave (or press Ctrl-D)
+ADDR may be a number or a CSR name from csr.csv. Numbers may be written in
+hex (0x8000_0000), binary (0b101), or decimal.";
+
+/// Completes command names, and CSR names after them
+struct ReplHelper {
+    names: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(' ').map_or(0, |space| space + 1);
+        let word = &line[start..pos];
+        let candidates: Box<dyn Iterator<Item = &str>> = if start == 0 {
+            Box::new(COMMANDS.iter().copied())
+        } else if line[..start].trim() == "csr" {
+            Box::new(["list"].iter().copied())
+        } else {
+            Box::new(self.names.iter().map(String::as_str))
+        };
+        let matches = candidates
+            .filter(|candidate| candidate.starts_with(word))
+            .map(|candidate| Pair {
+                display: candidate.to_owned(),
+                replacement: candidate.to_owned(),
+            })
+            .collect();
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Where history is kept between sessions
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".wishbone-tool_history"))
+}
+
+fn parse_number(value: &str) -> Result<u32, String> {
+    parse_u32(&value.replace('_', "")).map_err(|_| format!("invalid number: {}", value))
+}
+
+struct Repl<'a> {
+    cfg: &'a Config,
+    bridge: MappedBridge,
+}
+
+impl Repl<'_> {
+    /// Turn a CSR name or number into an address.
+    fn address(&self, value: &str) -> Result<u32, String> {
+        match self.cfg.register_mapping.get(&value.to_lowercase()) {
+            Some(Some(addr)) => Ok(*addr),
+            Some(None) => Err(format!("{} is outside the bridge's address space", value)),
+            None => parse_number(value).map_err(|_| format!("no csr or address called {}", value)),
+        }
+    }
+
+    /// Run one line, returning `false` once it's time to leave.
+    fn run_line(&self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => (),
+            ["quit"] | ["exit"] => return Ok(false),
+            ["help"] | ["?"] => println!("{}", HELP),
+            ["peek", addr] => {
+                let name = addr.to_lowercase();
+                let addr = self.address(addr)?;
+                let value = self.bridge.peek(addr).map_err(|e| e.to_string())?;
+                println!("{:08x}: {:08x}", addr, value);
+                let fields = match self.cfg.csr_fields.get(&name) {
+                    Some(fields) => Some((name.as_str(), fields.as_slice())),
+                    None if self.cfg.show_fields => self.cfg.fields_at(addr),
+                    None => None,
+                };
+                if let Some((name, fields)) = fields {
+                    println!("{}: {}", name, csr_map::decode_fields(fields, value));
+                }
+            }
+            ["poke", addr, value] => {
+                let addr = self.address(addr)?;
+                let value = parse_number(value)?;
+                check_write(self.cfg, addr, 4).map_err(|e| match e {
+                    ServerError::ProtectedWrite(addr, region) => format!(
+                        "0x{:08x} is part of {}, and writing it may wedge the bridge (use --force to allow this)",
+                        addr, region
+                    ),
+                    e => format!("{:?}", e),
+                })?;
+                self.bridge.poke(addr, value).map_err(|e| e.to_string())?;
+            }
+            ["dump", addr, length] => {
+                let addr = self.address(addr)?;
+                let length = parse_number(length)?;
+                let data = self
+                    .bridge
+                    .burst_read_skip_sensitive(addr, length)
+                    .map_err(|e| e.to_string())?;
+                for (row, chunk) in data.chunks(16).enumerate() {
+                    let bytes: Vec<String> = chunk
+                        .iter()
+                        .map(|b| match b {
+                            Some(b) => format!("{:02x}", b),
+                            None => "--".to_owned(),
+                        })
+                        .collect();
+                    println!(
+                        "{:08x}: {}",
+                        addr.wrapping_add(16 * row as u32),
+                        bytes.join(" ")
+                    );
+                }
+            }
+            ["csr", "list"] | ["csr", "list", _] => {
+                let pattern = words.get(2).map(|p| p.to_lowercase()).unwrap_or_default();
+                let mut names: Vec<(&String, &u32)> = self
+                    .cfg
+                    .register_mapping
+                    .iter()
+                    .filter(|(name, _)| name.contains(&pattern))
+                    .filter_map(|(name, addr)| addr.as_ref().map(|addr| (name, addr)))
+                    .collect();
+                names.sort_by_key(|(name, addr)| (**addr, *name));
+                for (name, addr) in names {
+                    println!("{:08x}  {}", addr, name);
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "unrecognized command: {} (try `help`)",
+                    line.trim()
+                ))
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Read commands from the terminal until the user quits.
+pub fn repl(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let mut mapped = MappedBridge::new(bridge);
+    mapped
+        .regions(cfg.memory_regions.iter().cloned())
+        .policy(cfg.region_policy);
+    let repl = Repl {
+        cfg,
+        bridge: mapped,
+    };
+
+    let mut names: Vec<String> = cfg.register_mapping.keys().cloned().collect();
+    names.sort();
+    let mut editor: Editor<ReplHelper, _> =
+        Editor::new().map_err(|e| ServerError::IoError(io::Error::other(e)))?;
+    editor.set_helper(Some(ReplHelper { names }));
+    let history = history_file();
+    if let Some(history) = &history {
+        // There's no history the first time around.
+        editor.load_history(history).ok();
+    }
+
+    println!("Type `help` for a list of commands.");
+    loop {
+        let line = match editor.readline("wishbone> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(ServerError::IoError(io::Error::other(e))),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str()).ok();
+        }
+        let result = repl.run_line(&line);
+        if let Some(watch) = &cfg.bus_errors {
+            watch.after_batch(repl.bridge.bridge());
+        }
+        match result {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+    }
+    if let Some(history) = &history {
+        editor.save_history(history).ok();
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+//! Bring-up sequences written in Rhai, run with `--script FILE`. Scripts
+//! get `peek`, `poke`, `burst_read`, `burst_write`, `wait_for`, `sleep`,
+//! and `csr`, and anywhere an address is wanted a CSR name from csr.csv
+//! works as well as a number.
+
+use super::{ServerError, WriteGuard};
+use crate::config::{Config, RegisterMapping};
+
+use rhai::{Blob, Dynamic, Engine, EvalAltResult};
+use tracing::info;
+use wishbone_bridge::{Bridge, MappedBridge};
+
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// What the functions given to the script have to work with
+struct Host {
+    bridge: MappedBridge,
+    register_mapping: RegisterMapping,
+    write_guard: WriteGuard,
+}
+
+impl Host {
+    fn csr(&self, name: &str) -> ScriptResult<u32> {
+        match self.register_mapping.get(&name.to_lowercase()) {
+            Some(Some(addr)) => Ok(*addr),
+            Some(None) => Err(format!("{} is outside the bridge's address space", name).into()),
+            None => Err(format!("no csr called {}", name).into()),
+        }
+    }
+
+    /// Turn a CSR name or number into an address.
+    fn address(&self, addr: &Dynamic) -> ScriptResult<u32> {
+        if let Some(name) = addr.read_lock::<rhai::ImmutableString>() {
+            return self.csr(&name);
+        }
+        match addr.as_int() {
+            Ok(addr) => to_u32(addr, "address"),
+            Err(kind) => Err(format!("expected an address or csr name, not {}", kind).into()),
+        }
+    }
+
+    fn check_write(&self, addr: u32, length: u32) -> ScriptResult<()> {
+        self.write_guard.check(addr, length).map_err(|region| {
+            format!(
+                "0x{:08x} is part of {}, and writing it may wedge the bridge (use --force to allow this)",
+                addr, region.name
+            )
+            .into()
+        })
+    }
+
+    fn peek(&self, addr: &Dynamic) -> ScriptResult<i64> {
+        let addr = self.address(addr)?;
+        let value = self.bridge.peek(addr).map_err(|e| e.to_string())?;
+        Ok(i64::from(value))
+    }
+
+    fn poke(&self, addr: &Dynamic, value: i64) -> ScriptResult<()> {
+        let addr = self.address(addr)?;
+        let value = to_u32(value, "value")?;
+        self.check_write(addr, 4)?;
+        Ok(self.bridge.poke(addr, value).map_err(|e| e.to_string())?)
+    }
+
+    fn burst_read(&self, addr: &Dynamic, length: i64) -> ScriptResult<Blob> {
+        let addr = self.address(addr)?;
+        let length = to_u32(length, "length")?;
+        Ok(self
+            .bridge
+            .burst_read(addr, length)
+            .map_err(|e| e.to_string())?)
+    }
+
+    fn burst_write(&self, addr: &Dynamic, data: Blob) -> ScriptResult<()> {
+        let addr = self.address(addr)?;
+        self.check_write(addr, data.len() as u32)?;
+        Ok(self
+            .bridge
+            .burst_write(addr, &data)
+            .map_err(|e| e.to_string())?)
+    }
+
+    /// Poll until `addr & mask == value`, returning `false` if that doesn't
+    /// happen within `timeout_ms`.
+    fn wait_for(
+        &self,
+        addr: &Dynamic,
+        mask: i64,
+        value: i64,
+        timeout_ms: i64,
+    ) -> ScriptResult<bool> {
+        let addr = self.address(addr)?;
+        let mask = to_u32(mask, "mask")?;
+        let value = to_u32(value, "value")?;
+        let timeout = Duration::from_millis(timeout_ms.max(0) as u64);
+        let start = Instant::now();
+        loop {
+            if self.bridge.peek(addr).map_err(|e| e.to_string())? & mask == value {
+                return Ok(true);
+            }
+            if start.elapsed() >= timeout {
+                return Ok(false);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+fn to_u32(value: i64, what: &str) -> ScriptResult<u32> {
+    u32::try_from(value).map_err(|_| format!("{} {} doesn't fit in 32 bits", what, value).into())
+}
+
+/// Run the file given with `--script`, stopping at the first error.
+pub fn run_script(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let path = match &cfg.script_file {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let mut mapped = MappedBridge::new(bridge);
+    mapped
+        .regions(cfg.memory_regions.iter().cloned())
+        .policy(cfg.region_policy);
+    let host = Rc::new(Host {
+        bridge: mapped,
+        register_mapping: cfg.register_mapping.clone(),
+        write_guard: cfg.write_guard.clone(),
+    });
+
+    let mut engine = Engine::new();
+    let h = host.clone();
+    engine.register_fn("peek", move |addr: Dynamic| h.peek(&addr));
+    let h = host.clone();
+    engine.register_fn("poke", move |addr: Dynamic, value: i64| {
+        h.poke(&addr, value)
+    });
+    let h = host.clone();
+    engine.register_fn("burst_read", move |addr: Dynamic, length: i64| {
+        h.burst_read(&addr, length)
+    });
+    let h = host.clone();
+    engine.register_fn("burst_write", move |addr: Dynamic, data: Blob| {
+        h.burst_write(&addr, data)
+    });
+    let h = host.clone();
+    engine.register_fn(
+        "wait_for",
+        move |addr: Dynamic, mask: i64, value: i64, timeout_ms: i64| {
+            h.wait_for(&addr, mask, value, timeout_ms)
+        },
+    );
+    let h = host.clone();
+    engine.register_fn("csr", move |name: &str| h.csr(name).map(i64::from));
+    engine.register_fn("sleep", |ms: i64| {
+        thread::sleep(Duration::from_millis(ms.max(0) as u64))
+    });
+
+    info!("running script {}", path);
+    engine
+        .run_file(PathBuf::from(path))
+        .map_err(|e| ServerError::ScriptError(format!("{}: {}", path, e)))?;
+    if let Some(watch) = &cfg.bus_errors {
+        watch.after_batch(host.bridge.bridge());
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Putting the target back the way it was when `wishbone-tool` is killed
+//! with Ctrl-C or SIGTERM, rather than leaving the CPU halted or the flash
+//! write-enabled.
+//!
+//! Servers register what needs undoing with `on_shutdown()`. Work that
+//! mustn't be cut short, such as programming a flash page, holds a `busy()`
+//! guard and checks `requested()` at points where it's safe to stop.
+
+use tracing::{info, warn};
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// How long to wait for `Busy` work to reach a point where it can stop
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+type CleanupFn = Box<dyn FnOnce() + Send>;
+
+static CLEANUPS: Mutex<Vec<(u64, CleanupFn)>> = Mutex::new(Vec::new());
+static BUSY: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+
+/// Returns `true` once a signal has asked `wishbone-tool` to exit.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Undoes something on shutdown, unless it's dropped first
+pub struct Cleanup(u64);
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        CLEANUPS.lock().unwrap().retain(|(id, _)| *id != self.0);
+    }
+}
+
+/// Run `f` on shutdown. Cleanups run in the reverse of the order they were
+/// registered in, once no `Busy` guards are left.
+pub fn on_shutdown<F: FnOnce() + Send + 'static>(f: F) -> Cleanup {
+    let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+    CLEANUPS.lock().unwrap().push((id, Box::new(f)));
+    Cleanup(id)
+}
+
+/// Held while doing something that shutdown should wait for
+pub struct Busy(());
+
+/// Make shutdown wait until the returned guard is dropped.
+pub fn busy() -> Busy {
+    *BUSY.0.lock().unwrap() += 1;
+    Busy(())
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        *BUSY.0.lock().unwrap() -= 1;
+        BUSY.1.notify_all();
+    }
+}
+
+/// Wait for the work in progress to stop, then run every cleanup.
+fn shut_down() {
+    REQUESTED.store(true, Ordering::SeqCst);
+    let busy = BUSY.0.lock().unwrap();
+    let (busy, timeout) = BUSY
+        .1
+        .wait_timeout_while(busy, BUSY_TIMEOUT, |count| *count > 0)
+        .unwrap();
+    drop(busy);
+    if timeout.timed_out() {
+        warn!("gave up waiting for work in progress to finish");
+    }
+    let cleanups = std::mem::take(&mut *CLEANUPS.lock().unwrap());
+    for (_, cleanup) in cleanups.into_iter().rev() {
+        // One cleanup going wrong shouldn't stop the others.
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(cleanup)).ok();
+    }
+}
+
+/// Clean up before exiting on SIGINT, SIGTERM, or SIGHUP. A second signal
+/// exits straight away, in case cleaning up gets stuck.
+#[cfg(unix)]
+pub fn handle_signals() -> std::io::Result<()> {
+    use signal_hook::iterator::Signals;
+    use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+
+    let signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    std::thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("cleaning up before exiting (signal again to exit now)");
+            std::thread::spawn(move || {
+                shut_down();
+                std::process::exit(128 + signal);
+            });
+        }
+        if let Some(signal) = signals.next() {
+            std::process::exit(128 + signal);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals() -> std::io::Result<()> {
+    Ok(())
+}
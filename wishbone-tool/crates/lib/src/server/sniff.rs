@@ -0,0 +1,243 @@
+use super::etherbone::{
+    forward_write, parse_datagram, read_stream_packet, reply, Packet, FLAG_NO_READS, FLAG_PROBE,
+    FLAG_PROBE_RESPONSE, RECORD_BCA, RECORD_CYC, RECORD_RCA, RECORD_RFF, RECORD_WCA, RECORD_WFF,
+};
+use crate::config::Config;
+use crate::server::ServerError;
+
+use tracing::{error, info};
+use wishbone_bridge::{Bridge, BridgeDriver, BridgeError};
+
+use std::fmt::Write as _;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::thread;
+
+/// Names of the packet header flags, for printing
+const PACKET_FLAGS: &[(u8, &str)] = &[
+    (FLAG_PROBE, "probe"),
+    (FLAG_PROBE_RESPONSE, "probe-response"),
+    (FLAG_NO_READS, "no-reads"),
+];
+
+/// Names of the record flags, for printing
+const RECORD_FLAGS: &[(u8, &str)] = &[
+    (RECORD_BCA, "bca"),
+    (RECORD_RCA, "rca"),
+    (RECORD_RFF, "rff"),
+    (RECORD_CYC, "cyc"),
+    (RECORD_WCA, "wca"),
+    (RECORD_WFF, "wff"),
+];
+
+fn flag_names(flags: u8, names: &[(u8, &str)]) -> String {
+    let set: Vec<&str> = names
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if set.is_empty() {
+        "none".to_owned()
+    } else {
+        set.join(" ")
+    }
+}
+
+/// Print `packet` and, if there's a bridge to forward to, carry it out. The
+/// returned values are the answers to the packet's reads, in order.
+fn handle_packet(source: &str, packet: &Packet, bridge: Option<&Bridge>) -> Vec<Vec<u32>> {
+    let mut out = String::new();
+    let header = &packet.header;
+    let _ = writeln!(
+        out,
+        "[{}] version {}, {}-bit addresses, {}-bit data, flags: {}, {} record{}",
+        source,
+        header[2] >> 4,
+        8 * (header[3] >> 4) as u32,
+        8 * (header[3] & 0xf) as u32,
+        flag_names(packet.flags(), PACKET_FLAGS),
+        packet.records.len(),
+        if packet.records.len() == 1 { "" } else { "s" }
+    );
+
+    let mut answers = vec![];
+    for (index, record) in packet.records.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "  record {}: {} writes, {} reads, byte enable 0x{:02x}, flags: {}",
+            index + 1,
+            record.writes.len(),
+            record.reads.len(),
+            record.byte_enable,
+            flag_names(record.flags, RECORD_FLAGS)
+        );
+
+        let config_writes = record.flags & RECORD_WCA != 0;
+        for (i, value) in record.writes.iter().enumerate() {
+            let addr = record.write_addr(i);
+            let _ = write!(out, "    write 0x{:08x} <- 0x{:08x}", addr, value);
+            match bridge {
+                _ if config_writes => out.push_str(" (config space, not forwarded)"),
+                Some(bridge) => {
+                    if let Err(e) = forward_write(bridge, addr, *value, record.byte_enable) {
+                        let _ = write!(out, " (failed: {})", e);
+                    }
+                }
+                None => (),
+            }
+            out.push('\n');
+        }
+
+        if record.reads.is_empty() {
+            continue;
+        }
+        let config_reads = record.flags & RECORD_RCA != 0;
+        let _ = writeln!(
+            out,
+            "    replies to 0x{:08x}{}",
+            record.return_base,
+            if record.flags & RECORD_BCA != 0 {
+                " in config space"
+            } else {
+                ""
+            }
+        );
+        let mut values = vec![];
+        for addr in &record.reads {
+            let _ = write!(out, "    read  0x{:08x}", addr);
+            let value = match bridge {
+                _ if config_reads => {
+                    out.push_str(" (config space, answered with 0)");
+                    0
+                }
+                Some(bridge) => match bridge.peek(*addr) {
+                    Ok(value) => {
+                        let _ = write!(out, " -> 0x{:08x}", value);
+                        value
+                    }
+                    Err(e) => {
+                        let _ = write!(out, " (failed: {})", e);
+                        0
+                    }
+                },
+                None => 0,
+            };
+            out.push('\n');
+            values.push(value);
+        }
+        answers.push(values);
+    }
+    print!("{}", out);
+    answers
+}
+
+fn sniff_udp(socket: UdpSocket, bridge: Option<Bridge>) {
+    let mut buffer = [0; 65536];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buffer) {
+            Ok(o) => o,
+            Err(e) => {
+                error!("couldn't receive from udp socket: {}", e);
+                return;
+            }
+        };
+        let source = format!("udp {}", peer);
+        let packet = match parse_datagram(&buffer[..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                println!("[{}] bad packet, {}: {}", source, e, hex(&buffer[..len]));
+                continue;
+            }
+        };
+        let answers = handle_packet(&source, &packet, bridge.as_ref());
+        if let Some(reply) = reply(&packet, &answers) {
+            if let Err(e) = socket.send_to(&reply, peer) {
+                error!("couldn't reply to {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+fn sniff_tcp(connection: TcpStream, peer: SocketAddr, bridge: Option<Bridge>) {
+    let source = format!("tcp {}", peer);
+    let mut writer = match connection.try_clone() {
+        Ok(o) => o,
+        Err(e) => {
+            error!("couldn't set up connection from {}: {}", peer, e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(connection);
+    loop {
+        let packet = match read_stream_packet(&mut reader) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => {
+                println!("[{}] closed", source);
+                return;
+            }
+            Err(e) => {
+                println!("[{}] bad packet, closing: {}", source, e);
+                return;
+            }
+        };
+        let answers = handle_packet(&source, &packet, bridge.as_ref());
+        if let Some(reply) = reply(&packet, &answers) {
+            if let Err(e) = writer.write_all(&reply) {
+                println!("[{}] couldn't reply, closing: {}", source, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Stands in for a device when the sniffer isn't forwarding packets, so
+/// that sniffing doesn't need a bridge to be plugged in.
+pub(crate) struct NoDevice;
+
+impl BridgeDriver for NoDevice {
+    fn peek(&self, _addr: u32) -> Result<u32, BridgeError> {
+        Err(BridgeError::InvalidAddress)
+    }
+
+    fn poke(&self, _addr: u32, _value: u32) -> Result<(), BridgeError> {
+        Err(BridgeError::InvalidAddress)
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Listen for Etherbone packets over both TCP and UDP, and print each one
+/// along with its records. With `--sniff-forward`, the packets are also
+/// carried out on the bridge and reads are answered with what it returns.
+/// Otherwise the bridge isn't touched, and every read is answered with zero.
+pub fn sniff(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let addr = format!("{}:{}", cfg.bind_addr, cfg.bind_port);
+    let bridge = if cfg.sniff_forward {
+        Some(bridge)
+    } else {
+        None
+    };
+    info!(
+        "sniffing etherbone packets on tcp and udp {}{}",
+        addr,
+        if bridge.is_some() {
+            ", forwarding them to the bridge"
+        } else {
+            ""
+        }
+    );
+
+    let socket = UdpSocket::bind(&addr)?;
+    let udp_bridge = bridge.clone();
+    thread::spawn(move || sniff_udp(socket, udp_bridge));
+
+    let listener = TcpListener::bind(&addr)?;
+    loop {
+        let (connection, peer) = listener.accept()?;
+        println!("[tcp {}] connected", peer);
+        let bridge = bridge.clone();
+        thread::spawn(move || sniff_tcp(connection, peer, bridge));
+    }
+}
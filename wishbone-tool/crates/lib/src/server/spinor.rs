@@ -0,0 +1,527 @@
+use super::flash_chip::{Chip, JedecId, Sfdp};
+use super::litespi::SpiMaster;
+use super::utra::spinor;
+use super::ServerError;
+use crate::config::Config;
+
+use tracing::{info, warn};
+use wishbone_bridge::{Bridge, BridgeError};
+
+use std::sync::{Arc, OnceLock};
+
+/// The smallest amount of flash that can be erased at once
+pub const SECTOR_SIZE: u32 = 4096;
+
+/// The most bytes a single page program can write. Pages start on
+/// multiples of this, and a program that runs off the end of one wraps
+/// around to its start.
+pub const PAGE_SIZE: u32 = 256;
+
+/// How much flash to assume there is when csr.csv doesn't say
+const DEFAULT_FLASH_SIZE: u32 = 0x0800_0000;
+
+/// Dummy cycles between the address and the data of an SFDP read
+const SFDP_DUMMY_CYCLES: u32 = 8;
+
+/// The SFDP header and this many parameter headers are read to find the
+/// tables, which is plenty for any chip seen so far
+const SFDP_MAX_TABLES: u32 = 8;
+
+/// What the flash is attached to
+#[derive(Clone, Debug)]
+enum Controller {
+    /// betrusted's `spinor`, which takes whole commands and runs the flash
+    /// in octal DTR mode. Contains the base address of its CSRs.
+    Spinor(u32),
+
+    /// One of LiteX's own controllers
+    Spi(SpiMaster),
+}
+
+/// A SPI NOR flash, along with the controller it's behind and the region
+/// it's mapped into.
+///
+/// Every command assumes that the CPU isn't running from flash at the same
+/// time, either because it's been halted or because it's sitting in a
+/// debugger.
+#[derive(Clone, Debug)]
+pub struct SpiNor {
+    controller: Controller,
+
+    /// Where the flash appears on the bus
+    pub region: u32,
+
+    /// Size of the flash, in bytes
+    pub size: u32,
+
+    /// The chip, once `probe()` has found out what it is. This is shared
+    /// between clones, so it's only done once.
+    chip: Arc<OnceLock<Chip>>,
+}
+
+impl SpiNor {
+    /// Find the controller and flash using csr.csv. That's either the
+    /// `spinor` CSRs or a LiteSPI or bit-bang `spiflash` core, along with
+    /// the `spiflash` memory region.
+    pub fn from_config(cfg: &Config) -> Result<SpiNor, ServerError> {
+        let controller = match cfg.register_mapping.get("spinor").copied().flatten() {
+            Some(csr) => Controller::Spinor(csr),
+            None => Controller::Spi(
+                SpiMaster::from_config(cfg)
+                    .ok_or(ServerError::UnmappableAddress("spinor".to_string()))?,
+            ),
+        };
+        let region = cfg
+            .register_mapping
+            .get("spiflash")
+            .ok_or(ServerError::UnmappableAddress("spiflash".to_string()))?
+            .unwrap();
+        let size = cfg
+            .memory_regions
+            .iter()
+            .find(|r| r.name == "spiflash")
+            .map(|r| r.size)
+            .unwrap_or(DEFAULT_FLASH_SIZE);
+        Ok(SpiNor {
+            controller,
+            region,
+            size,
+            chip: Arc::new(OnceLock::new()),
+        })
+    }
+
+    /// Whether `length` bytes starting at the bus address `addr` are all
+    /// within the flash.
+    pub fn contains(&self, addr: u32, length: u32) -> bool {
+        addr >= self.region && (addr - self.region) as u64 + length as u64 <= self.size as u64
+    }
+
+    fn register(csr: u32, register: spinor::Register) -> u32 {
+        csr + (register.offset as u32) * 4
+    }
+
+    /// Issue `command` to a `spinor` controller, with `arg` as its argument.
+    fn command(bridge: &Bridge, csr: u32, arg: u32, command: u32) -> Result<(), BridgeError> {
+        bridge.poke(Self::register(csr, spinor::CMD_ARG), arg)?;
+        bridge.poke(Self::register(csr, spinor::COMMAND), command)
+    }
+
+    /// `opcode` followed by `address`, in as many bytes as `chip` takes
+    fn with_address(chip: &Chip, opcode: u8, address: u32) -> Vec<u8> {
+        let mut bytes = vec![opcode];
+        bytes.extend_from_slice(&address.to_be_bytes()[4 - chip.address_bytes..]);
+        bytes
+    }
+
+    /// Issue `opcode`, which reads a one-byte register, and return what it
+    /// read
+    fn read_register(
+        &self,
+        bridge: &Bridge,
+        opcode: u8,
+        lock_reads: u32,
+    ) -> Result<u32, BridgeError> {
+        let csr = match &self.controller {
+            Controller::Spinor(csr) => *csr,
+            Controller::Spi(spi) => return Ok(spi.transfer(bridge, &[opcode], 1)?[0] as u32),
+        };
+        let mut spinor_csr = spinor::CSR::new(csr as *mut u32);
+        Self::command(
+            bridge,
+            csr,
+            0,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_LOCK_READS, lock_reads)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, opcode as u32)
+                | spinor_csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
+                | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, 1)
+                | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1),
+        )?;
+        bridge.peek(Self::register(csr, spinor::CMD_RBK_DATA))
+    }
+
+    /// Issue `opcode`, which takes no address and returns nothing
+    fn simple_command(&self, bridge: &Bridge, opcode: u8) -> Result<(), BridgeError> {
+        let csr = match &self.controller {
+            Controller::Spinor(csr) => *csr,
+            Controller::Spi(spi) => return spi.transfer(bridge, &[opcode], 0).map(|_| ()),
+        };
+        let mut spinor_csr = spinor::CSR::new(csr as *mut u32);
+        Self::command(
+            bridge,
+            csr,
+            0,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, opcode as u32)
+                | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1),
+        )
+    }
+
+    /// Issue `opcode`, which erases the sector or block at `address`
+    fn erase_command(
+        &self,
+        bridge: &Bridge,
+        chip: &Chip,
+        opcode: u8,
+        address: u32,
+    ) -> Result<(), BridgeError> {
+        let csr = match &self.controller {
+            Controller::Spinor(csr) => *csr,
+            Controller::Spi(spi) => {
+                let command = Self::with_address(chip, opcode, address);
+                return spi.transfer(bridge, &command, 0).map(|_| ());
+            }
+        };
+        let mut spinor_csr = spinor::CSR::new(csr as *mut u32);
+        Self::command(
+            bridge,
+            csr,
+            address,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, opcode as u32)
+                | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
+                | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1),
+        )
+    }
+
+    /// Read the status register
+    pub fn rdsr(&self, bridge: &Bridge, lock_reads: u32) -> Result<u32, BridgeError> {
+        self.read_register(bridge, 0x05, lock_reads)
+    }
+
+    /// Read `offset` words of the ID from a `spinor` controller
+    fn rdid(bridge: &Bridge, csr: u32, offset: u32) -> Result<u32, BridgeError> {
+        let mut spinor_csr = spinor::CSR::new(csr as *mut u32);
+        Self::command(
+            bridge,
+            csr,
+            0,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x9f) // RDID
+                | spinor_csr.ms(spinor::COMMAND_DUMMY_CYCLES, 4)
+                | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, offset) // 2 -> 0x3b3b8080, // 1 -> 0x8080c2c2
+                | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1),
+        )?;
+        bridge.peek(Self::register(csr, spinor::CMD_RBK_DATA))
+    }
+
+    /// Read the word of the SFDP tables at `offset`
+    fn rdsfdp(&self, bridge: &Bridge, offset: u32) -> Result<u32, BridgeError> {
+        let csr = match &self.controller {
+            Controller::Spinor(csr) => *csr,
+            Controller::Spi(spi) => {
+                // Always a 3-byte address, then a byte of dummy cycles
+                let [_, a2, a1, a0] = offset.to_be_bytes();
+                let word = spi.transfer(bridge, &[0x5a, a2, a1, a0, 0], 4)?;
+                return Ok(u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+            }
+        };
+        let mut spinor_csr = spinor::CSR::new(csr as *mut u32);
+        Self::command(
+            bridge,
+            csr,
+            offset,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x5a) // RDSFDP
+                | spinor_csr.ms(spinor::COMMAND_DUMMY_CYCLES, SFDP_DUMMY_CYCLES)
+                | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, 1)
+                | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1),
+        )?;
+        bridge.peek(Self::register(csr, spinor::CMD_RBK_DATA))
+    }
+
+    /// Write the status register
+    pub fn wrsr(&self, bridge: &Bridge, value: u8) -> Result<(), BridgeError> {
+        let csr = match &self.controller {
+            Controller::Spinor(csr) => *csr,
+            Controller::Spi(spi) => return spi.transfer(bridge, &[0x01, value], 0).map(|_| ()),
+        };
+        let mut spinor_csr = spinor::CSR::new(csr as *mut u32);
+        // `spinor` runs the flash in DTR mode, so the byte is sent twice
+        bridge.poke(
+            Self::register(csr, spinor::WDATA),
+            (value as u32) << 8 | value as u32,
+        )?;
+        Self::command(
+            bridge,
+            csr,
+            0,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, 0x01) // WRSR
+                | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, 1)
+                | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
+                | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1),
+        )
+    }
+
+    pub fn wren(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        self.simple_command(bridge, 0x06)
+    }
+
+    pub fn wrdi(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        self.simple_command(bridge, 0x04)
+    }
+
+    /// Program `page` at `address`. `spinor` takes the data from its page
+    /// buffer, at the start of the flash region.
+    fn page_program(
+        &self,
+        bridge: &Bridge,
+        chip: &Chip,
+        address: u32,
+        page: &[u8],
+    ) -> Result<(), BridgeError> {
+        let csr = match &self.controller {
+            Controller::Spinor(csr) => *csr,
+            Controller::Spi(spi) => {
+                let mut command = Self::with_address(chip, chip.page_program, address);
+                command.extend_from_slice(page);
+                return spi.transfer(bridge, &command, 0).map(|_| ());
+            }
+        };
+        bridge.burst_write(self.region, page)?;
+        let mut spinor_csr = spinor::CSR::new(csr as *mut u32);
+        Self::command(
+            bridge,
+            csr,
+            address,
+            spinor_csr.ms(spinor::COMMAND_EXEC_CMD, 1)
+                | spinor_csr.ms(spinor::COMMAND_CMD_CODE, chip.page_program as u32)
+                | spinor_csr.ms(spinor::COMMAND_HAS_ARG, 1)
+                | spinor_csr.ms(spinor::COMMAND_DATA_WORDS, page.len() as u32 / 2)
+                | spinor_csr.ms(spinor::COMMAND_LOCK_READS, 1),
+        )
+    }
+
+    /// Read the JEDEC ID
+    pub fn read_id(&self, bridge: &Bridge) -> Result<JedecId, BridgeError> {
+        let csr = match &self.controller {
+            Controller::Spinor(csr) => *csr,
+            Controller::Spi(spi) => {
+                let id = spi.transfer(bridge, &[0x9f], 3)?;
+                return Ok(JedecId {
+                    manufacturer: id[0],
+                    memory_type: id[1],
+                    capacity: id[2],
+                });
+            }
+        };
+        // `spinor` runs the flash in DTR mode, where each byte of the ID
+        // comes back twice
+        let first = Self::rdid(bridge, csr, 1)?;
+        let second = Self::rdid(bridge, csr, 2)?;
+        Ok(JedecId {
+            manufacturer: first as u8,
+            memory_type: (first >> 16) as u8,
+            capacity: (second >> 16) as u8,
+        })
+    }
+
+    /// Read `length` bytes of the SFDP tables, starting at `offset`
+    fn read_sfdp(&self, bridge: &Bridge, offset: u32, length: u32) -> Result<Vec<u8>, BridgeError> {
+        let mut data = vec![];
+        for word in (offset..offset + length).step_by(4) {
+            data.extend_from_slice(&self.rdsfdp(bridge, word)?.to_le_bytes());
+        }
+        Ok(data)
+    }
+
+    /// Find out which flash chip is attached, from its SFDP tables if it
+    /// has them and from its JEDEC ID otherwise. This is only done once,
+    /// so it's cheap to call before every erase and program.
+    pub fn probe(&self, bridge: &Bridge) -> Result<&Chip, ServerError> {
+        if let Some(chip) = self.chip.get() {
+            return Ok(chip);
+        }
+        let id = self.read_id(bridge)?;
+        let header = self.read_sfdp(bridge, 0, 8 + SFDP_MAX_TABLES * 8)?;
+        let sfdp = match Sfdp::tables(&header) {
+            Some(tables) => Sfdp::parse(&tables, |offset, length| {
+                self.read_sfdp(bridge, offset, length)
+            })?,
+            None => None,
+        };
+        // `spinor` always sends four address bytes
+        let four_byte = matches!(self.controller, Controller::Spinor(_));
+        let chip = Chip::identify(id, sfdp.as_ref(), four_byte)
+            .ok_or(ServerError::UnknownFlash(id.code()))?;
+        info!(
+            "found a {} flash chip with ID {}, {} MiB{}",
+            chip.vendor(),
+            chip.id,
+            chip.size / (1024 * 1024),
+            if chip.sfdp { "" } else { " (no SFDP)" }
+        );
+        if chip.size < self.size && self.size != DEFAULT_FLASH_SIZE {
+            warn!(
+                "the flash is only {} bytes, but csr.csv maps {} bytes of it",
+                chip.size, self.size
+            );
+        }
+        Ok(self.chip.get_or_init(|| chip))
+    }
+
+    /// Set the write enable latch, which every erase and program needs.
+    fn write_enable(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        loop {
+            self.wren(bridge)?;
+            let status = self.rdsr(bridge, 1)?;
+            if status & 0x02 != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Clear the write enable latch, if it's still set.
+    fn write_disable(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        if self.rdsr(bridge, 1)? & 0x02 != 0 {
+            self.wrdi(bridge)?;
+            loop {
+                let status = self.rdsr(bridge, 1)?;
+                if status & 0x02 == 0 {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the erase or program in progress to finish.
+    fn wait_idle(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        loop {
+            let status = self.rdsr(bridge, 1)?;
+            if status & 0x01 == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Whether the last erase or program failed, for chips that say.
+    fn failed(&self, bridge: &Bridge, chip: &Chip) -> Result<bool, BridgeError> {
+        let register = match chip.error_register() {
+            Some(register) => register,
+            None => return Ok(false),
+        };
+        if self.read_register(bridge, register.read, 1)? & register.fail_mask == 0 {
+            return Ok(false);
+        }
+        if let Some(clear) = register.clear {
+            self.simple_command(bridge, clear)?;
+        }
+        Ok(true)
+    }
+
+    /// Erase the sector (for a `size` of 4 kiB) or block (for anything
+    /// larger) at `offset` into the flash. Returns whether the flash
+    /// reported that the erase failed.
+    pub fn erase(&self, bridge: &Bridge, offset: u32, size: u32) -> Result<bool, ServerError> {
+        let chip = self.probe(bridge)?;
+        self.write_enable(bridge)?;
+        if size <= SECTOR_SIZE {
+            self.erase_command(bridge, chip, chip.sector_erase, offset)?;
+        } else {
+            self.erase_command(bridge, chip, chip.block_erase, offset)?;
+        }
+        self.wait_idle(bridge)?;
+        let failed = self.failed(bridge, chip)?;
+        self.write_disable(bridge)?;
+        Ok(failed)
+    }
+
+    /// Program `page`, which mustn't cross a page boundary and must be an
+    /// even number of bytes long, at `offset` into the flash. If `wait` is
+    /// set, wait for it to finish and return whether it failed. Otherwise
+    /// this relies on the link being slower than the flash.
+    pub fn program_page(
+        &self,
+        bridge: &Bridge,
+        offset: u32,
+        page: &[u8],
+        wait: bool,
+    ) -> Result<bool, ServerError> {
+        let chip = self.probe(bridge)?;
+        self.write_enable(bridge)?;
+        self.page_program(bridge, chip, offset, page)?;
+        if !wait {
+            return Ok(false);
+        }
+        self.wait_idle(bridge)?;
+        Ok(self.failed(bridge, chip)?)
+    }
+
+    /// Set or clear all of the block-protect bits, returning the status
+    /// register afterwards. Fails if the bits didn't change, which happens
+    /// when the status register is itself locked by the `WP#` pin.
+    pub fn set_protection(&self, bridge: &Bridge, protect: bool) -> Result<u32, ServerError> {
+        let mask = self.probe(bridge)?.protect_mask();
+        let status = self.rdsr(bridge, 1)? & 0xff;
+        let wanted = if protect {
+            status | mask
+        } else {
+            status & !mask
+        };
+        self.write_enable(bridge)?;
+        self.wrsr(bridge, wanted as u8)?;
+        self.wait_idle(bridge)?;
+        self.finish(bridge)?;
+        let status = self.rdsr(bridge, 0)? & 0xff;
+        if status & mask != wanted & mask {
+            return Err(ServerError::ProtectFailed(status));
+        }
+        Ok(status)
+    }
+
+    /// Leave the flash ready to be read again once programming is done.
+    pub fn finish(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        self.write_disable(bridge)?;
+        match &self.controller {
+            // dummy reads to clear the "read lock" bit
+            Controller::Spinor(_) => self.rdsr(bridge, 0).map(|_| ()),
+            Controller::Spi(spi) => spi.release(bridge),
+        }
+    }
+
+    /// Erase every sector that overlaps `length` bytes starting at the bus
+    /// address `addr`.
+    pub fn erase_range(&self, bridge: &Bridge, addr: u32, length: u32) -> Result<(), ServerError> {
+        let start = (addr - self.region) & !(SECTOR_SIZE - 1);
+        let end = addr - self.region + length;
+        for sector in (start..end).step_by(SECTOR_SIZE as usize) {
+            if self.erase(bridge, sector, SECTOR_SIZE)? {
+                return Err(ServerError::FlashFailed(sector));
+            }
+        }
+        Ok(())
+    }
+
+    /// Program `data` at the bus address `addr`, which must already have
+    /// been erased. Either end may be unaligned, since programming `0xff`
+    /// leaves a byte as it was.
+    pub fn program_range(
+        &self,
+        bridge: &Bridge,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), ServerError> {
+        let offset = addr - self.region;
+        let lead = (offset & 3) as usize;
+        let mut padded = vec![0xff; lead];
+        padded.extend_from_slice(data);
+        while padded.len() % 4 != 0 {
+            padded.push(0xff);
+        }
+
+        let mut written = 0;
+        let start = offset & !3;
+        while written < padded.len() {
+            let page_offset = start + written as u32;
+            let room = (PAGE_SIZE - page_offset % PAGE_SIZE) as usize;
+            let chunk = &padded[written..(written + room).min(padded.len())];
+            if self.program_page(bridge, page_offset, chunk, true)? {
+                return Err(ServerError::FlashFailed(page_offset));
+            }
+            written += chunk.len();
+        }
+        Ok(())
+    }
+}
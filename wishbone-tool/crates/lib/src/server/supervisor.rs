@@ -0,0 +1,212 @@
+//! Runs each server on its own thread, and starts it again if it panics or
+//! gives up with an error, so that a long-running session on a lab machine
+//! survives a flaky cable or a bug in one server.
+
+use super::{print_json_error, run, ServerError, ServerKind};
+use crate::config::{Config, OutputFormat};
+
+use tracing::{debug, error, info, warn};
+use wishbone_bridge::{Bridge, BridgeError, MuxBridge};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait before the first restart. This doubles with each
+/// restart in a row, up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A server that stays up this long is considered healthy again, so the
+/// next restart happens straight after `MIN_BACKOFF`.
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+impl ServerKind {
+    /// Returns `true` for servers that run until they're killed, as opposed
+    /// to one-shot operations such as loading a file, which are never
+    /// restarted.
+    pub fn runs_until_killed(self) -> bool {
+        matches!(
+            self,
+            ServerKind::GDB
+                | ServerKind::Wishbone
+                | ServerKind::Terminal
+                | ServerKind::Messible
+                | ServerKind::Sniff
+                | ServerKind::Http
+        )
+    }
+}
+
+/// How a server's thread came to an end
+enum Outcome {
+    Finished,
+    Failed(ServerError),
+    Panicked,
+}
+
+/// Errors that starting over won't fix, because the server refused to do
+/// something, or was never built in
+fn is_fatal(e: &ServerError) -> bool {
+    matches!(
+        e,
+        ServerError::ProtectedWrite(..)
+            | ServerError::GoldenImage(..)
+            | ServerError::BitstreamError(_)
+            | ServerError::UpdateRefused(_)
+            | ServerError::NotEnabled(_)
+            | ServerError::BridgeError(BridgeError::ReplayDiverged(_))
+    )
+}
+
+/// One of the servers being looked after
+struct Supervised {
+    kind: ServerKind,
+
+    /// The server's own place in the bridge's queue, which it keeps across
+    /// restarts
+    bridge: Bridge,
+    started: Instant,
+    backoff: Duration,
+}
+
+impl Supervised {
+    /// Run the server on a new thread after `delay`, first waiting for the
+    /// bridge to answer if `check_bridge` is set. The outcome is sent to
+    /// `tx` along with `index`.
+    fn start(
+        &mut self,
+        index: usize,
+        cfg: &Arc<Config>,
+        tx: &mpsc::Sender<(usize, Outcome)>,
+        delay: Duration,
+        check_bridge: bool,
+    ) {
+        let kind = self.kind;
+        let bridge = self.bridge.clone();
+        let cfg = cfg.clone();
+        let tx = tx.clone();
+        self.started = Instant::now() + delay;
+        thread::spawn(move || {
+            thread::sleep(delay);
+            if check_bridge {
+                wait_for_bridge(&cfg, &bridge);
+            }
+            let outcome = match panic::catch_unwind(AssertUnwindSafe(|| run(kind, &cfg, bridge))) {
+                Ok(Ok(())) => Outcome::Finished,
+                Ok(Err(e)) => Outcome::Failed(e),
+                Err(_) => Outcome::Panicked,
+            };
+            debug!("Exited {:?} thread", kind);
+            tx.send((index, outcome)).ok();
+        });
+    }
+}
+
+/// Read from the device until it answers. The bridges reconnect on their
+/// own, so this just waits for that to happen. `ctrl_scratch` is read if
+/// csr.csv has it, as it's there for this sort of thing, otherwise the
+/// start of the ROM.
+fn wait_for_bridge(cfg: &Config, bridge: &Bridge) {
+    let addr = match cfg.register_mapping.get("ctrl_scratch") {
+        Some(Some(addr)) => *addr,
+        _ => 0,
+    };
+    let mut reported = false;
+    loop {
+        match bridge.peek(addr) {
+            Ok(_) => break,
+            Err(e) if !reported => {
+                warn!("waiting for the bridge to come back: {}", e);
+                reported = true;
+            }
+            Err(_) => (),
+        }
+        thread::sleep(MIN_BACKOFF);
+    }
+    if reported {
+        info!("bridge is answering again");
+    }
+}
+
+/// Run each of `cfg.server_kind`, with a client of `mux` apiece, until they
+/// have all finished. Servers that run until they're killed are restarted
+/// after a panic or an error, once the bridge answers again, and with a
+/// delay that grows each time a server fails again soon after starting.
+/// Returns the first error that restarting wouldn't fix, such as a refused
+/// write, or else the first error from a server that only runs once.
+pub fn supervise(cfg: &Arc<Config>, mux: &MuxBridge) -> Result<(), ServerError> {
+    let (tx, rx) = mpsc::channel();
+    let mut servers: Vec<Supervised> = cfg
+        .server_kind
+        .iter()
+        .map(|kind| Supervised {
+            kind: *kind,
+            bridge: mux.client(&format!("{:?}", kind).to_lowercase()),
+            started: Instant::now(),
+            backoff: MIN_BACKOFF,
+        })
+        .collect();
+    for (index, server) in servers.iter_mut().enumerate() {
+        server.start(index, cfg, &tx, Duration::ZERO, false);
+    }
+
+    let mut failed = None;
+    let mut running = servers.len();
+    while running > 0 {
+        // `tx` is still held here, so this can't fail.
+        let (index, outcome) = rx.recv().unwrap();
+        let server = &mut servers[index];
+        let restartable = server.kind.runs_until_killed();
+        match outcome {
+            Outcome::Finished => {
+                running -= 1;
+                continue;
+            }
+            Outcome::Failed(e) if is_fatal(&e) => return Err(e),
+            // The first of these is reported once the others have finished
+            Outcome::Failed(e) if !restartable && failed.is_none() => {
+                failed = Some(e);
+                running -= 1;
+                continue;
+            }
+            Outcome::Failed(e) if !restartable => {
+                let message = format!("{:?} failed: {:?}", server.kind, e);
+                error!("{}", message);
+                if cfg.output == OutputFormat::Json {
+                    print_json_error(&message);
+                }
+                running -= 1;
+                continue;
+            }
+            Outcome::Panicked if !restartable => {
+                let message = format!("{:?} panicked", server.kind);
+                error!("{}", message);
+                if cfg.output == OutputFormat::Json {
+                    print_json_error(&message);
+                }
+                running -= 1;
+                continue;
+            }
+            Outcome::Failed(e) => warn!("{:?} server failed: {:?}", server.kind, e),
+            Outcome::Panicked => warn!("{:?} server panicked", server.kind),
+        }
+
+        if server.started.elapsed() >= HEALTHY_AFTER {
+            server.backoff = MIN_BACKOFF;
+        }
+        let delay = server.backoff;
+        server.backoff = (server.backoff * 2).min(MAX_BACKOFF);
+        info!(
+            "restarting {:?} server in {} s",
+            server.kind,
+            delay.as_secs()
+        );
+        // A sniffer that doesn't forward packets has no device to wait for.
+        let check_bridge = server.kind != ServerKind::Sniff || cfg.sniff_forward;
+        server.start(index, cfg, &tx, delay, check_bridge);
+    }
+    failed.map_or(Ok(()), Err)
+}
@@ -0,0 +1,189 @@
+use super::bitstream::Bitstream;
+use super::fpga::{reboot, warmboot_image};
+use super::{flash_data, ServerError};
+use crate::config::{Config, UpdateImage, UpdateManifest};
+
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use wishbone_bridge::Bridge;
+
+/// How many times to try writing each image before giving up
+const UPDATE_ATTEMPTS: u32 = 3;
+
+/// Read every file in the manifest, making sure each matches its SHA-256
+fn read_images(manifest: &UpdateManifest) -> Result<Vec<(&UpdateImage, Vec<u8>)>, ServerError> {
+    let mut images = vec![];
+    for image in &manifest.images {
+        let data = std::fs::read(&image.file)?;
+        if Sha256::digest(&data)[..] != image.sha256 {
+            return Err(ServerError::UpdateRefused(format!(
+                "{} ({}) doesn't match the SHA-256 in the manifest",
+                image.name,
+                image.file.display()
+            )));
+        }
+        images.push((image, data));
+    }
+    Ok(images)
+}
+
+/// Make sure the target's ID register, which read `id`, is one the update
+/// is meant for
+fn check_id(manifest: &UpdateManifest, id: u32) -> Result<(), ServerError> {
+    if id & manifest.id_mask != manifest.id_value & manifest.id_mask {
+        return Err(ServerError::UpdateRefused(format!(
+            "{} reads 0x{:08x}, but the update is for 0x{:08x}",
+            manifest.id_register, id, manifest.id_value
+        )));
+    }
+    Ok(())
+}
+
+/// Write every image in `cfg.update`'s manifest to flash, then reset the
+/// target. Nothing is written unless every file matches its SHA-256 and
+/// the target's ID register says the update is meant for it.
+pub fn update(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let manifest = match &cfg.update {
+        Some(manifest) => manifest,
+        None => return Ok(()),
+    };
+
+    let images = read_images(manifest)?;
+    let id = bridge.peek(manifest.id_addr)?;
+    check_id(manifest, id)?;
+    info!(
+        "{} is 0x{:08x}, as the update expects",
+        manifest.id_register, id
+    );
+
+    // Bitstreams are checked against the multiboot header before anything
+    // is written, and the first one is what the FPGA reboots into
+    let mut reboot_into = None;
+    for (image, data) in &images {
+        if let Some(bitstream) = Bitstream::parse(data) {
+            let warmboot = warmboot_image(cfg, &bridge, image.offset)?;
+            reboot_into.get_or_insert((bitstream.family, image.offset, warmboot.unwrap_or(0)));
+        }
+    }
+
+    let count = images.len();
+    for (index, (image, data)) in images.into_iter().enumerate() {
+        info!(
+            "[{}/{}] Writing {} to 0x{:08x}",
+            index + 1,
+            count,
+            image.name,
+            image.offset
+        );
+        // The IDCODE and multiboot options are about the gateware
+        let is_bitstream = Bitstream::parse(&data).is_some();
+        let image_cfg = Config {
+            load_name: Some(image.file.display().to_string()),
+            flash_no_reset: true,
+            flash_idcode: cfg.flash_idcode.filter(|_| is_bitstream),
+            flash_multiboot: cfg.flash_multiboot.filter(|_| is_bitstream),
+            ..cfg.clone()
+        };
+        let mut attempt = 1;
+        loop {
+            match flash_data(&image_cfg, bridge.clone(), image.offset, data.clone()) {
+                Ok(true) => break,
+                Ok(false) => warn!("{} didn't read back correctly", image.name),
+                // A flaky link is worth another go, but nothing else is
+                Err(ServerError::BridgeError(e)) => {
+                    warn!("writing {} failed: {}", image.name, e)
+                }
+                Err(e) => return Err(e),
+            }
+            if attempt == UPDATE_ATTEMPTS {
+                return Err(ServerError::FlashFailed(image.offset));
+            }
+            attempt += 1;
+            info!("Trying again, attempt {} of {}", attempt, UPDATE_ATTEMPTS);
+        }
+    }
+
+    if let (true, Some((family, offset, image))) = (cfg.fpga_reboot, reboot_into) {
+        return reboot(cfg, &bridge, family, offset, image);
+    }
+    let register = |name: &str| cfg.register_mapping.get(name).copied().flatten();
+    match register("reboot_cpu_reset").or_else(|| register("ctrl_reset")) {
+        Some(reset_addr) if !cfg.flash_no_reset => {
+            info!("Resetting CPU.");
+            bridge.poke(reset_addr, 1)?;
+        }
+        Some(_) => (),
+        None => warn!("no reboot_cpu_reset or ctrl_reset in csr.csv, so the CPU won't be reset"),
+    }
+    info!("Update finished");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn manifest(file: PathBuf, sha256: [u8; 32]) -> UpdateManifest {
+        UpdateManifest {
+            id_register: "ctrl_scratch".to_owned(),
+            id_addr: 0xe000_0004,
+            id_value: 0x1234_5678,
+            id_mask: 0xffff_ff00,
+            images: vec![UpdateImage {
+                name: "gateware".to_owned(),
+                file,
+                offset: 0,
+                sha256,
+            }],
+        }
+    }
+
+    /// A file to update from, unique to this test
+    fn image_file(test: &str, data: &[u8]) -> PathBuf {
+        let file =
+            std::env::temp_dir().join(format!("wishbone-tool-{}-{}.bin", test, std::process::id()));
+        std::fs::write(&file, data).unwrap();
+        file
+    }
+
+    #[test]
+    fn matching_images_are_read() {
+        let file = image_file("matching", b"abc");
+        let manifest = manifest(file.clone(), Sha256::digest(b"abc").into());
+        let images = read_images(&manifest);
+        std::fs::remove_file(file).unwrap();
+        let images = images.unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].1, b"abc");
+    }
+
+    #[test]
+    fn mismatched_digest_is_refused() {
+        let file = image_file("mismatched", b"abd");
+        let manifest = manifest(file.clone(), Sha256::digest(b"abc").into());
+        let images = read_images(&manifest);
+        std::fs::remove_file(file).unwrap();
+        match images {
+            Err(ServerError::UpdateRefused(msg)) => {
+                assert!(msg.contains("doesn't match the SHA-256"), "{}", msg)
+            }
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn id_is_compared_under_the_mask() {
+        let manifest = manifest(PathBuf::new(), [0; 32]);
+        assert!(check_id(&manifest, 0x1234_5678).is_ok());
+        assert!(check_id(&manifest, 0x1234_56ff).is_ok());
+        match check_id(&manifest, 0x1234_5778) {
+            Err(ServerError::UpdateRefused(msg)) => assert_eq!(
+                msg,
+                "ctrl_scratch reads 0x12345778, but the update is for 0x12345678"
+            ),
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::config::Config;
+use crate::server::ServerError;
+
+use tracing::{info, warn};
+use wishbone_bridge::Bridge;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+/// A register to sample, such as the `_in` CSR of a LiteX `GPIOIn`
+#[derive(Debug, Clone)]
+pub struct VcdSignal {
+    /// Name to give the signal in the waveform
+    pub name: String,
+    pub addr: u32,
+
+    /// How many of the register's low bits to keep
+    pub width: u32,
+}
+
+/// Settings for `--server vcd-gpio`, which samples registers at a fixed
+/// rate and writes them to a Value Change Dump for viewing in GTKWave or
+/// similar.
+#[derive(Debug, Clone)]
+pub struct VcdCapture {
+    pub signals: Vec<VcdSignal>,
+
+    /// Where to write the waveform
+    pub file: String,
+
+    /// Samples to take each second
+    pub rate: u32,
+
+    /// How long to sample for, or `None` to carry on until interrupted
+    pub duration: Option<Duration>,
+}
+
+/// The short code that stands for signal `index` in the body of a VCD.
+/// These are made up of the printable ASCII characters `!` to `~`.
+fn vcd_id(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+/// Writes samples to a VCD, recording only the values that changed.
+struct VcdWriter<W: Write> {
+    out: W,
+    widths: Vec<u32>,
+    last: Vec<Option<u32>>,
+}
+
+impl<W: Write> VcdWriter<W> {
+    fn new(mut out: W, signals: &[VcdSignal]) -> io::Result<VcdWriter<W>> {
+        writeln!(
+            out,
+            "$version wishbone-tool {} $end",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        // Samples are timestamped as they're taken, so they land on whole
+        // microseconds rather than on a grid.
+        writeln!(out, "$timescale 1us $end")?;
+        writeln!(out, "$scope module gpio $end")?;
+        for (index, signal) in signals.iter().enumerate() {
+            writeln!(
+                out,
+                "$var wire {} {} {} $end",
+                signal.width,
+                vcd_id(index),
+                signal.name
+            )?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        Ok(VcdWriter {
+            out,
+            widths: signals.iter().map(|s| s.width).collect(),
+            last: vec![None; signals.len()],
+        })
+    }
+
+    fn sample(&mut self, time: Duration, values: &[u32]) -> io::Result<()> {
+        let mut wrote_time = false;
+        for (index, value) in values.iter().enumerate() {
+            let width = self.widths[index];
+            let value = if width < 32 {
+                value & ((1 << width) - 1)
+            } else {
+                *value
+            };
+            if self.last[index] == Some(value) {
+                continue;
+            }
+            if !wrote_time {
+                writeln!(self.out, "#{}", time.as_micros())?;
+                wrote_time = true;
+            }
+            if width == 1 {
+                writeln!(self.out, "{}{}", value, vcd_id(index))?;
+            } else {
+                writeln!(self.out, "b{:b} {}", value, vcd_id(index))?;
+            }
+            self.last[index] = Some(value);
+        }
+        // Flush as we go, so the file is usable if we're interrupted.
+        if wrote_time {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Mark the end of the capture, so viewers show the last values for as
+    /// long as they held.
+    fn finish(&mut self, time: Duration) -> io::Result<()> {
+        writeln!(self.out, "#{}", time.as_micros())?;
+        self.out.flush()
+    }
+}
+
+/// Sample GPIO input registers into a VCD, giving a crude logic analyzer for
+/// slow signals on boards without any analyzer gateware.
+pub fn vcd_gpio(cfg: &Config, bridge: Bridge) -> Result<(), ServerError> {
+    let capture = match &cfg.vcd {
+        Some(capture) => capture,
+        None => return Ok(()),
+    };
+    let mut vcd = VcdWriter::new(
+        BufWriter::new(File::create(&capture.file)?),
+        &capture.signals,
+    )?;
+    info!(
+        "sampling {} at {} Hz into {}",
+        capture
+            .signals
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<&str>>()
+            .join(", "),
+        capture.rate,
+        capture.file
+    );
+
+    let period = Duration::from_secs(1) / capture.rate.max(1);
+    let start = Instant::now();
+    let mut next = start;
+    let mut warned = false;
+    let mut values = vec![0; capture.signals.len()];
+    loop {
+        let time = start.elapsed();
+        if let Some(duration) = capture.duration {
+            if time >= duration {
+                vcd.finish(time)?;
+                info!("finished writing {}", capture.file);
+                return Ok(());
+            }
+        }
+        for (value, signal) in values.iter_mut().zip(&capture.signals) {
+            *value = bridge.peek(signal.addr)?;
+        }
+        vcd.sample(time, &values)?;
+
+        next += period;
+        let now = Instant::now();
+        if next > now {
+            std::thread::sleep(next - now);
+        } else {
+            // Don't try to catch up, since that would bunch samples together.
+            if !warned && now - next > period {
+                warn!(
+                    "the bridge can't keep up with {} Hz, so samples will be further apart",
+                    capture.rate
+                );
+                warned = true;
+            }
+            next = now;
+        }
+    }
+}
@@ -0,0 +1,203 @@
+extern crate byteorder;
+
+use std::io;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+use super::server::{etherbone, AccessPolicy};
+use super::Config;
+use wishbone_bridge::{Bridge, BridgeError};
+
+/* The network protocol is Etherbone, streamed over TCP without any framing,
+   just as `litex_server` speaks it. That means `litex_client`, `litex_cli`
+   and `litescope_cli` can connect straight to this server.
+
+    // Packet header:
+    wb_buffer[0] = 0x4e;        // Magic byte 0
+    wb_buffer[1] = 0x6f;        // Magic byte 1
+    wb_buffer[2] = 0x10;        // Version 1, plus the probe and no-reads flags
+    wb_buffer[3] = 0x44;        // Address is 32-bits, port is 32-bits
+    wb_buffer[4] = 0;           // Padding
+    wb_buffer[5] = 0;           // Padding
+    wb_buffer[6] = 0;           // Padding
+    wb_buffer[7] = 0;           // Padding
+
+    // Record header, repeated for each record:
+    wb_buffer[8] = 0;           // Record flags (bca, rca, rff, cyc, wca, wff)
+    wb_buffer[9] = 0x0f;        // Byte enable flag
+    wb_buffer[10] = ?;          // Number of write packets
+    wb_buffer[11] = ?;          // Numer of read frames
+
+    // If there are writes, the address of the first one, then the data:
+    wb_buffer[12] = addr0;
+    ...
+    wb_buffer[16] = byte0;
+    ...
+
+    // If there are reads, the address for the reply, then each address
+    // to read, which needn't follow on from one another:
+    wb_buffer[n] = ret_addr0;
+    ...
+    wb_buffer[n + 4] = addr0;
+    ...
+
+   Reads are answered with a packet holding a write record of the values,
+   addressed to the reply address, and a probe is answered with a probe
+   response.
+
+   With `--wishbone-udp`, the same packets are also accepted as UDP
+   datagrams, one packet to a datagram, just as a LiteEth core takes them.
+
+   Each TCP client gets a thread of its own. Packets from different clients
+   don't overlap, so a read-modify-write for a partial write, or a packet
+   that reads a FIFO several times, isn't split up by another client.
+
+   With `--read-only` or `--allow-range`, a packet with any access that
+   isn't allowed is refused as a whole. A TCP client is disconnected, as
+   there's no way to answer it with an error.
+*/
+
+pub struct WishboneServer {
+    listener: TcpListener,
+
+    /// Held while a packet is carried out, by every client
+    packet_lock: Arc<Mutex<()>>,
+
+    access: AccessPolicy,
+}
+
+/// Listens for Etherbone datagrams, answering each one as it arrives
+pub struct WishboneUdpServer {
+    socket: UdpSocket,
+    packet_lock: Arc<Mutex<()>>,
+    access: AccessPolicy,
+}
+
+pub struct WishboneConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    peer: SocketAddr,
+    packet_lock: Arc<Mutex<()>>,
+    access: AccessPolicy,
+}
+
+#[derive(Debug)]
+pub enum WishboneServerError {
+    /// An error with TCP
+    IoError(io::Error),
+
+    /// There is no active connection
+    ConnectionClosed,
+
+    /// The packet wasn't valid Etherbone, such as not having the magic
+    /// bytes 0x4e 0x6f
+    BadPacket(String),
+
+    /// There was a problem with the device bridge
+    BridgeError(BridgeError),
+
+    /// The packet tried to go somewhere `--read-only` or `--allow-range`
+    /// doesn't allow
+    AccessDenied(String),
+}
+
+impl std::convert::From<io::Error> for WishboneServerError {
+    fn from(e: io::Error) -> WishboneServerError {
+        WishboneServerError::IoError(e)
+    }
+}
+
+impl std::convert::From<BridgeError> for WishboneServerError {
+    fn from(e: BridgeError) -> WishboneServerError {
+        WishboneServerError::BridgeError(e)
+    }
+}
+
+impl WishboneServer {
+    pub fn new(cfg: &Config) -> Result<WishboneServer, WishboneServerError> {
+        Ok(WishboneServer {
+            listener: TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?,
+            packet_lock: Arc::new(Mutex::new(())),
+            access: cfg.network_access.clone(),
+        })
+    }
+
+    /// Wait for the next client to connect.
+    pub fn connect(&mut self) -> Result<WishboneConnection, WishboneServerError> {
+        let (connection, peer) = self.listener.accept()?;
+        let writer = connection.try_clone()?;
+        Ok(WishboneConnection {
+            reader: BufReader::new(connection),
+            writer,
+            peer,
+            packet_lock: self.packet_lock.clone(),
+            access: self.access.clone(),
+        })
+    }
+
+    /// Listen for datagrams as well, taking turns with the TCP clients.
+    pub fn udp(&self, cfg: &Config) -> Result<WishboneUdpServer, WishboneServerError> {
+        Ok(WishboneUdpServer {
+            socket: UdpSocket::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?,
+            packet_lock: self.packet_lock.clone(),
+            access: self.access.clone(),
+        })
+    }
+}
+
+impl WishboneUdpServer {
+    /// Wait for the next datagram, carry it out, and send back the answers
+    /// to any reads in it.
+    pub fn process(&self, bridge: &Bridge) -> Result<(), WishboneServerError> {
+        let mut datagram = [0; 65536];
+        let (len, client) = self.socket.recv_from(&mut datagram)?;
+        let datagram = &datagram[..len];
+        // Probes are padded out to test how big a datagram gets through, so
+        // there are no records to parse.
+        let is_probe = len >= 8 && datagram[2] & etherbone::FLAG_PROBE != 0;
+        let packet = etherbone::parse_datagram(if is_probe { &datagram[..8] } else { datagram })
+            .map_err(WishboneServerError::BadPacket)?;
+        etherbone::check_access(&packet, &self.access)
+            .map_err(WishboneServerError::AccessDenied)?;
+        let answers = {
+            let _turn = self.packet_lock.lock().unwrap();
+            etherbone::execute(&packet, bridge)?
+        };
+        if let Some(mut reply) = etherbone::reply(&packet, &answers) {
+            // Echo all of a probe back, as LiteEth does.
+            if is_probe {
+                reply.extend_from_slice(&datagram[8..]);
+            }
+            self.socket.send_to(&reply, client)?;
+        }
+        Ok(())
+    }
+}
+
+impl WishboneConnection {
+    /// The address the client connected from
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Carry out the next packet from the client, and send back the
+    /// answers to any reads in it.
+    pub fn process(&mut self, bridge: &Bridge) -> Result<(), WishboneServerError> {
+        let packet = match etherbone::read_stream_packet(&mut self.reader) {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return Err(WishboneServerError::ConnectionClosed),
+            Err(e) => return Err(WishboneServerError::BadPacket(e)),
+        };
+        etherbone::check_access(&packet, &self.access)
+            .map_err(WishboneServerError::AccessDenied)?;
+        let answers = {
+            let _turn = self.packet_lock.lock().unwrap();
+            etherbone::execute(&packet, bridge)?
+        };
+        if let Some(reply) = etherbone::reply(&packet, &answers) {
+            self.writer.write_all(&reply)?;
+        }
+        Ok(())
+    }
+}
@@ -64,6 +64,14 @@ impl<'a> DeviceHandle<'a> {
         Ok(())
     }
 
+    /// Clears the halt/stall condition on an endpoint.
+    ///
+    /// The interface the endpoint belongs to must be claimed first.
+    pub fn clear_halt(&mut self, endpoint: u8) -> ::Result<()> {
+        try_unsafe!(libusb_clear_halt(self.handle, endpoint as c_uchar));
+        Ok(())
+    }
+
     /// Indicates whether the device has an attached kernel driver.
     ///
     /// This method is not supported on all platforms.